@@ -0,0 +1,68 @@
+use std::{
+    sync::{Arc, Barrier},
+    thread,
+    time::{Duration, Instant},
+};
+
+use kv_db::{config::MemTableConfig, memory::MemTable, Storage};
+
+/// 同时写入的线程数
+const WRITERS: usize = 64;
+/// 每个线程写入的次数
+const WRITES: usize = 20_000;
+
+/// 所有线程都写同一个 table，返回每次写入的耗时
+fn run(config: &MemTableConfig) -> Vec<Duration> {
+    let store = Arc::new(MemTable::with_config(config).unwrap());
+    let barrier = Arc::new(Barrier::new(WRITERS));
+    let writers: Vec<_> = (0..WRITERS)
+        .map(|id| {
+            let (store, barrier) = (store.clone(), barrier.clone());
+            thread::spawn(move || {
+                let mut latencies = Vec::with_capacity(WRITES);
+                barrier.wait();
+                for i in 0..WRITES {
+                    // 一半写入新的 key，一半覆盖已有的 key
+                    let key = format!("key-{}-{}", id, i % (WRITES / 2));
+                    let start = Instant::now();
+                    store.set("hot", key, (i as i64).into()).unwrap();
+                    latencies.push(start.elapsed());
+                }
+                latencies
+            })
+        })
+        .collect();
+    writers
+        .into_iter()
+        .flat_map(|writer| writer.join().unwrap())
+        .collect()
+}
+
+fn percentile(latencies: &[Duration], p: f64) -> Duration {
+    let i = ((latencies.len() as f64 * p) as usize).min(latencies.len() - 1);
+    latencies[i]
+}
+
+fn main() {
+    println!("{} writers x {} writes into one table", WRITERS, WRITES);
+    for (shards, capacity) in [(Some(2), None), (None, None), (Some(256), Some(1 << 20))] {
+        let config = MemTableConfig {
+            shards,
+            capacity,
+            ..Default::default()
+        };
+        let start = Instant::now();
+        let mut latencies = run(&config);
+        let elapsed = start.elapsed();
+        latencies.sort();
+        let shards = shards.map_or("default".into(), |s| s.to_string());
+        println!(
+            "shards {:>8}: p50 {:>10?}  p99 {:>10?}  max {:>10?}  total {:?}",
+            shards,
+            percentile(&latencies, 0.5),
+            percentile(&latencies, 0.99),
+            latencies[latencies.len() - 1],
+            elapsed,
+        );
+    }
+}
@@ -5,11 +5,11 @@ use kv_db::{
     multiplex::YamuxCtrl,
     pb::abi::CommandRequest,
     start_client_with_config, start_server_with_config,
+    transport::BoxedTransport,
 };
 use rand::seq::SliceRandom;
 use std::time::Duration;
-use tokio::{net::TcpStream, runtime::Builder, time};
-use tokio_rustls::client::TlsStream;
+use tokio::{runtime::Builder, time};
 use tokio_stream::StreamExt;
 use tracing::info;
 
@@ -28,11 +28,11 @@ async fn start_server() -> Result<()> {
 }
 
 /// 链接测试服务
-async fn connect() -> Result<YamuxCtrl<TlsStream<TcpStream>>> {
+async fn connect() -> Result<YamuxCtrl<BoxedTransport>> {
     let addr = "127.0.0.1:9999";
     let mut config: ClientConfig = toml::from_str(include_str!("../fixtures/client.conf"))?;
     config.general.addr = addr.into();
-    Ok(start_client_with_config(config).await?)
+    start_client_with_config(config).await
 }
 
 async fn start_subscribers(topic: &'static str) -> Result<()> {
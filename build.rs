@@ -1,8 +1,39 @@
 fn main() {
     prost_build::Config::new()
-        .bytes(&["."])
+        .bytes(["."])
+        .btree_map([".abi.ValueMap.entries"])
         .type_attribute(".", "#[derive(PartialOrd)]")
         .out_dir("src/pb")
         .compile_protos(&["abi.proto"], &["src/pb"])
         .unwrap();
+
+    #[cfg(feature = "grpc")]
+    compile_grpc();
+}
+
+/// 消息使用的是 prost 0.8，tonic 自带的 codec 用不了，所以手动定义 service，使用自己的 codec
+#[cfg(feature = "grpc")]
+fn compile_grpc() {
+    use tonic_build::manual::{Builder, Method, Service};
+
+    let method = |name: &str, route: &str, input: &str| {
+        Method::builder()
+            .name(name)
+            .route_name(route)
+            .input_type(format!("crate::pb::abi::{}", input))
+            .output_type("crate::pb::abi::CommandResponse")
+            .codec_path("crate::network::grpc::ProstCodec")
+    };
+    let service = Service::builder()
+        .name("KvService")
+        .package("abi")
+        .method(method("execute", "Execute", "CommandRequest").build())
+        .method(method("scan", "Scan", "Hscan").server_streaming().build())
+        .method(
+            method("subscribe", "Subscribe", "Subscribe")
+                .server_streaming()
+                .build(),
+        )
+        .build();
+    Builder::new().compile(&[service]);
 }
@@ -24,9 +24,11 @@ async fn main() -> Result<()> {
             while let Some(Ok(msg)) = service.next().await {
                 info!("Got a new command {:?}", msg);
                 // 创建一个404响应给客户端
-                let mut resp = CommandResponse::default();
-                resp.status = StatusCode::NOT_FOUND.as_u16() as _;
-                resp.message = "Not Found".into();
+                let resp = CommandResponse {
+                    status: StatusCode::NOT_FOUND.as_u16() as _,
+                    message: "Not Found".into(),
+                    ..Default::default()
+                };
                 service.send(resp).await.unwrap();
             }
             info!("Client {:?} disconnected", addr);
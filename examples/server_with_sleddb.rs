@@ -1,5 +1,3 @@
-use std::ops::Deref;
-
 use anyhow::Result;
 use async_prost::AsyncProstStream;
 use futures::{SinkExt, StreamExt};
@@ -1,22 +1,170 @@
-use crate::error::KvError;
+use crate::{error::KvError, frame::Compression};
 use serde::{Deserialize, Serialize};
-use std::fs;
+use std::{collections::HashMap, fs, str::FromStr};
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub struct ServerConfig {
     pub general: GeneralConfig,
     pub storage: StorageConfig,
+    /// MemTable 和 WalMemTable 的内存上限
+    #[serde(default)]
+    pub memtable: MemTableConfig,
+    /// 存储配额，超过配额的写入返回 507；只有 MemTable 和 WalMemTable 支持
+    #[serde(default)]
+    pub quotas: Vec<QuotaConfig>,
+    /// 启动时加载的 WASM 插件，插件导出的函数可以用 FCALL 调用
+    #[serde(default)]
+    pub plugins: Vec<PluginConfig>,
+    /// 配置之后，启动时先把这个 snapshot 导入到 storage
+    pub restore_from: Option<String>,
+    /// 配置之后，value 加密之后再写入 storage
+    pub encryption: Option<EncryptionConfig>,
+    /// 配置之后，长时间没有访问的 value 转移到对象存储，本地只留下一个占位
+    pub tiering: Option<TieringConfig>,
+    /// 配置之后，定期或者磁盘空间超过上限时在后台压缩存储
+    pub compaction: Option<CompactionConfig>,
+    /// 配置之后，每次修改生成一个新的 revision，可以读取之前的 revision 时的值
+    pub mvcc: Option<MvccConfig>,
     pub tls: ServerTlsConfig,
+    #[serde(default)]
+    pub security: SecurityConfig,
+    /// 配置之后，通过 OTLP 导出 tracing span
+    pub telemetry: Option<TelemetryConfig>,
+    /// 配置之后，本节点作为 replica 从 primary 同步数据，只处理读请求
+    pub replication: Option<ReplicationConfig>,
+    /// 拒绝所有修改数据的命令，用于维护窗口；可以通过 CONFIG SET read-only 或者重新加载配置修改。
+    /// 配置了 replication 时总是只读的
+    #[serde(default)]
+    pub read_only: bool,
+    /// 配置之后，本节点和 peers 组成 raft 集群：写命令复制到多数节点之后才返回，读命令由 leader 处理
+    pub cluster: Option<ClusterConfig>,
+    /// 配置之后，本节点不保存数据，按 table 和 key 把命令转发到后端节点
+    pub proxy: Option<ProxyConfig>,
+    /// 配置之后，节点之间通过 gossip 互相发现，并检测下线的节点
+    pub membership: Option<MembershipConfig>,
+    /// 配置之后，额外监听一个地址，redis 客户端可以通过 RESP 协议访问
+    pub resp: Option<RespConfig>,
+    /// 配置之后，额外监听一个地址，提供 REST 接口
+    pub http: Option<HttpConfig>,
+    /// 配置之后，额外监听一个地址，提供 gRPC 接口；需要开启 grpc feature
+    pub grpc: Option<GrpcConfig>,
+    /// 配置之后，额外监听一个 UDP 地址，通过 QUIC 访问；需要开启 quic feature
+    pub quic: Option<QuicConfig>,
+    /// 配置之后，额外监听一个地址，浏览器可以通过 WebSocket 访问
+    pub websocket: Option<WebSocketConfig>,
+    #[serde(default)]
+    pub notifications: NotificationConfig,
+    /// 每秒最多处理的请求数，超过的请求直接返回 429
+    #[serde(default)]
+    pub limits: LimitsConfig,
+    /// 连接的空闲超时和 TCP 选项
+    #[serde(default)]
+    pub network: NetworkConfig,
+    /// 最多同时打开的连接数，prost、QUIC、RESP 和 WebSocket 的连接一起计数；默认不限制
+    pub max_connections: Option<usize>,
+    /// 每个 IP 最多同时打开的连接数；默认不限制
+    pub max_connections_per_ip: Option<usize>,
+    /// 最多同时执行的命令数，所有连接一起计数；超过之后连接暂停读取新的请求。默认不限制
+    pub max_in_flight: Option<usize>,
+    /// 每个连接最多同时执行的命令数，同一个连接上的所有 stream 一起计数；默认不限制
+    pub max_in_flight_per_connection: Option<usize>,
+    /// 退出时等待正在执行的请求完成的最长时间（秒），默认 30 秒
+    pub grace_period: Option<u64>,
+    /// 日志级别，格式和 RUST_LOG 一样；没有配置时使用 RUST_LOG，默认 info
+    pub log_level: Option<String>,
+    /// 一个消息最大的字节数，超过的请求返回 413，默认 512MB；大的消息会拆成多个 frame 传输
+    pub max_frame_size: Option<usize>,
+    /// 客户端可以通过 HELLO 协商的压缩算法
+    #[serde(default)]
+    pub compression: CompressionConfig,
+    /// 慢查询日志的阈值和保留的条数
+    #[serde(default)]
+    pub slowlog: SlowlogConfig,
+    /// 幂等 key 的结果保留的时间和数量
+    #[serde(default)]
+    pub idempotency: IdempotencyConfig,
+    /// 配置之后，修改数据的命令写入审计日志
+    pub audit: Option<AuditConfig>,
+    /// 配置之后，所有提交的修改按顺序投递到外部的 sink
+    pub cdc: Option<CdcConfig>,
+    /// 从 Kafka topic 导入数据；需要开启 kafka feature，replica 上不导入
+    #[serde(default)]
+    pub ingest: Vec<IngestConfig>,
+    /// 从文件加载时的路径，重新加载配置时从这里读取
+    #[serde(skip)]
+    pub source: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub struct ClientConfig {
     pub general: GeneralConfig,
     pub tls: ClientTlsConfig,
+    /// 连接建立后自动发送 AUTH
+    pub auth: Option<ClientAuthConfig>,
+    /// 请求的超时时间（毫秒），服务器和客户端都会检查；默认不限制。只有使用 Pool 和 ReconnectingClient 时生效
+    pub timeout: Option<u64>,
+    /// 连接池的配置，只有使用 Pool 时生效
+    #[serde(default)]
+    pub pool: PoolConfig,
+    /// 断线重连的配置，只有使用 ReconnectingClient 时生效
+    #[serde(default)]
+    pub retry: RetryConfig,
+    /// 在多个服务器之间分配连接，只有使用 Pool 时生效
+    #[serde(default)]
+    pub balance: BalanceConfig,
+    /// 配置之后，连接建立后通过 HELLO 和服务器协商压缩算法；没有配置时使用 gzip
+    pub compression: Option<CompressionConfig>,
+    /// 收发的消息最大的字节数，超过时直接返回错误，默认 512MB
+    pub max_frame_size: Option<usize>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct PoolConfig {
+    /// 连接数，默认 4
+    pub size: Option<usize>,
+    /// 每隔多少秒检查一次空闲的连接，默认 30 秒
+    pub health_check_interval: Option<u64>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct BalanceConfig {
+    /// general.addr 之外的服务器地址，每个服务器建立 pool.size 个连接
+    #[serde(default)]
+    pub addrs: Vec<String>,
+    /// 选择服务器的策略，默认轮流使用
+    #[serde(default)]
+    pub policy: BalancePolicy,
+    /// 连接失败的服务器多少秒之内不再使用，默认 10 秒；所有的服务器都失败时仍然会尝试
+    pub eject_time: Option<u64>,
+}
+
+/// 打开新的 stream 时选择服务器的策略，只在没有被剔除的服务器中选择
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum BalancePolicy {
+    /// 轮流使用每个服务器
+    #[default]
+    RoundRobin,
+    /// 使用正在执行的请求最少的服务器
+    LeastConnections,
+    /// 按配置的顺序使用第一个可用的服务器，general.addr 是 primary
+    PrimaryWithFallback,
+}
+
+/// 重试之间按指数退避等待，实际等待的时间在 0 到退避时间之间随机选取
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct RetryConfig {
+    /// 最多重试几次，默认 3 次；0 表示不重试
+    pub max_retries: Option<u32>,
+    /// 第一次重试之前的退避时间（毫秒），之后每次翻倍，默认 100
+    pub initial_backoff: Option<u64>,
+    /// 退避时间的上限（毫秒），默认 5000
+    pub max_backoff: Option<u64>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub struct GeneralConfig {
+    /// `host:port`，或者 `unix:/var/run/kv.sock` 表示 unix socket；unix socket 不使用 TLS
     pub addr: String,
 }
 
@@ -25,28 +173,673 @@ pub struct GeneralConfig {
 pub enum StorageConfig {
     MemTable,
     SledDB(String),
+    /// 数据放在内存里，修改操作追加写入 WAL，启动时重放
+    WalMemTable(WalConfig),
+    /// 数据保存在 sled 中，前面用 MemTable 缓存最近读写的 key
+    Tiered(TieredConfig),
+    /// 日志结构的存储：数据追加写入数据文件，内存中的 keydir 记录每个 key 的位置
+    Bitcask(BitcaskConfig),
+    /// 数据保存在 LMDB 中，读通过内存映射完成，适合读多写少的场景；需要开启 lmdb feature
+    #[cfg(feature = "lmdb")]
+    Lmdb {
+        /// 数据库所在的目录
+        path: String,
+        /// 数据库文件的大小上限，例如 "64GB"，写满之后写入会失败；默认 10GB
+        map_size: Option<String>,
+    },
+    /// 数据保存在 redb 数据库文件中，每个 table 对应一个 redb table；需要开启 redb feature
+    #[cfg(feature = "redb")]
+    Redb(String),
+    /// 数据保存在 SQLite 数据库文件中，使用 WAL 模式；需要开启 sqlite feature
+    #[cfg(feature = "sqlite")]
+    Sqlite(String),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct WalConfig {
+    pub path: String,
+    #[serde(default)]
+    pub fsync: FsyncPolicy,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct BitcaskConfig {
+    /// 数据文件和 hint 文件所在的目录
+    pub path: String,
+    #[serde(default)]
+    pub fsync: FsyncPolicy,
+    /// 单个数据文件的大小上限，例如 "256MB"，写满之后换一个新的文件；默认 64MB
+    pub max_file_size: Option<String>,
+    /// 失效的数据占数据文件的比例超过这个值时合并数据文件，默认 0.5
+    pub merge_ratio: Option<f64>,
+    /// 检查是否需要合并的间隔（秒），默认 60
+    pub merge_interval: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct TieredConfig {
+    /// sled 的目录
+    pub path: String,
+    /// 缓存的内存上限，例如 "256MB"，超过之后淘汰最久没有访问的 key；默认 64MB
+    pub cache_size: Option<String>,
+    #[serde(default)]
+    pub invalidation: CacheInvalidation,
+}
+
+/// 写入 sled 之后怎么处理缓存中的 key
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum CacheInvalidation {
+    /// 知道写入之后的值时更新缓存，否则删除；刚写入的 key 马上读取也能命中缓存
+    #[default]
+    Update,
+    /// 总是删除，下次读取时再从 sled 加载；适合写多读少的 key
+    Evict,
+}
+
+/// 冷数据分层：超过 idle_days 天没有读写的 value 上传到 S3 兼容的对象存储，本地只保存 object 的名字，
+/// 读取时再从对象存储取回来放回本地
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct TieringConfig {
+    pub idle_days: u64,
+    /// 小于这个大小的 value 不转移，例如 "4KB"；默认 1KB
+    pub min_size: Option<String>,
+    /// 检查冷数据的间隔（秒），默认 3600
+    pub interval: Option<u64>,
+    pub s3: S3Config,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct S3Config {
+    /// 例如 https://s3.us-east-1.amazonaws.com，或者 MinIO 的 http://127.0.0.1:9000
+    pub endpoint: String,
+    pub bucket: String,
+    /// 默认 us-east-1
+    pub region: Option<String>,
+    /// object 名字的前缀
+    #[serde(default)]
+    pub prefix: String,
+    /// 没有配置时读取环境变量 AWS_ACCESS_KEY_ID
+    pub access_key: Option<String>,
+    /// 没有配置时读取环境变量 AWS_SECRET_ACCESS_KEY
+    pub secret_key: Option<String>,
+}
+
+/// 静态数据加密的配置：新写入的 value 使用 active 的 key 加密，每个加密的 value 都带着 key 的 id，
+/// 轮换 key 时保留旧的 key 就能继续读取之前写入的数据
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct EncryptionConfig {
+    #[serde(default)]
+    pub cipher: Cipher,
+    /// 加密新写入的 value 使用的 key 的 id
+    pub active: u32,
+    pub keys: Vec<EncryptionKey>,
+    /// 启动时把旧的 key 加密的 value 用 active 的 key 重新加密，之后就可以删掉旧的 key
+    #[serde(default)]
+    pub reencrypt: bool,
+}
+
+/// 加密使用的 key，key 和 env 二选一
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct EncryptionKey {
+    /// 写在加密的 value 中，不能重复，也不能修改
+    pub id: u32,
+    /// base64 编码的 32 字节 key
+    pub key: Option<String>,
+    /// 从这个环境变量读取 base64 编码的 key，比如由 KMS 注入，避免把 key 写在配置文件里
+    pub env: Option<String>,
+}
+
+/// 加密算法，都使用 256 位的 key
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub enum Cipher {
+    #[default]
+    #[serde(rename = "aes-256-gcm")]
+    Aes256Gcm,
+    #[serde(rename = "chacha20-poly1305")]
+    ChaCha20Poly1305,
+}
+
+/// 写入 WAL 之后什么时候把数据刷到磁盘
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum FsyncPolicy {
+    /// 每次写入都 fsync，最安全也最慢
+    Always,
+    /// 距离上次 fsync 超过一秒时才 fsync
+    #[default]
+    EverySec,
+    /// 交给操作系统决定
+    No,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct MemTableConfig {
+    /// 内存上限，例如 "512MB"；没有配置时不限制
+    pub max_memory: Option<String>,
+    /// 超过内存上限之后怎么处理写入
+    #[serde(default)]
+    pub eviction: EvictionPolicy,
+    /// 每个 table 的分片数，必须是大于 1 的 2 的幂；默认是 CPU 核数的 4 倍。写入集中在少数几个 table 时可以调大
+    pub shards: Option<usize>,
+    /// 每个 table 初始能容纳的 key 数，避免 table 变大时反复扩容；默认为 0
+    pub capacity: Option<usize>,
+}
+
+/// 超过内存上限之后的处理方式，淘汰 key 时会先清理已过期的 key
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum EvictionPolicy {
+    /// 淘汰最久没有访问过的 key
+    Lru,
+    /// 淘汰访问次数最少的 key
+    Lfu,
+    /// 随机淘汰
+    Random,
+    /// 不淘汰，拒绝会增加内存的写入
+    #[default]
+    RejectWrites,
+}
+
+impl EvictionPolicy {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Lru => "lru",
+            Self::Lfu => "lfu",
+            Self::Random => "random",
+            Self::RejectWrites => "reject-writes",
+        }
+    }
+}
+
+impl FromStr for EvictionPolicy {
+    type Err = KvError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "lru" => Ok(Self::Lru),
+            "lfu" => Ok(Self::Lfu),
+            "random" => Ok(Self::Random),
+            "reject-writes" => Ok(Self::RejectWrites),
+            _ => Err(KvError::InvalidCommand(format!(
+                "unknown eviction policy {}",
+                s
+            ))),
+        }
+    }
+}
+
+/// 一个 table 或者一个租户的所有 table 的配额，table 和 tenant 只能配置一个；
+/// 已经超过配额时仍然可以删除和缩小 value
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct QuotaConfig {
+    pub table: Option<String>,
+    /// 租户的名字，配额对租户所有的 table 一起计算
+    pub tenant: Option<String>,
+    /// key 的数量上限，不包括 sorted set；没有配置时不限制
+    pub max_keys: Option<u64>,
+    /// 占用空间的上限，例如 "64MB"，按照 key 和 value 的大小估算；没有配置时不限制
+    pub max_bytes: Option<String>,
+}
+
+/// 一个 WASM 插件模块
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct PluginConfig {
+    /// 二进制格式（.wasm）或者文本格式（.wat）的模块
+    pub path: String,
+    /// 每次调用最多消耗的 fuel，大致相当于执行的指令数；没有配置时为 10000000
+    pub fuel: Option<u64>,
+    /// 模块可以使用的内存，例如 "16MB"；没有配置时为 16MB
+    pub max_memory: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct RespConfig {
+    /// 不使用 TLS，redis-cli 可以直接连接
+    pub addr: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct HttpConfig {
+    /// 不使用 TLS，一般放在反向代理后面
+    pub addr: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct GrpcConfig {
+    /// 不使用 TLS
+    pub addr: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct WebSocketConfig {
+    /// 不使用 TLS，一般放在反向代理后面
+    pub addr: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct QuicConfig {
+    /// QUIC 自带 TLS，使用 [tls] 里的证书；重新加载配置时不会更新
+    pub addr: String,
+}
+
+/// key 被修改时自动发布到 pub/sub 的通知，类似 redis 的 notify-keyspace-events；
+/// 事件名是 set 或者 del。开启之后所有的写操作都需要获取通知的锁
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq)]
+pub struct NotificationConfig {
+    /// 发布到 `__keyspace__:{table}:{key}`，数据是事件名
+    #[serde(default)]
+    pub keyspace: bool,
+    /// 发布到 `__keyevent__:{event}`，数据是 table 和 key
+    #[serde(default)]
+    pub keyevent: bool,
+}
+
+impl NotificationConfig {
+    pub fn is_enabled(&self) -> bool {
+        self.keyspace || self.keyevent
+    }
+}
+
+/// frame 的压缩算法和阈值
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct CompressionConfig {
+    /// 支持的压缩算法，客户端按优先级排列；服务器默认支持所有的算法
+    pub codecs: Option<Vec<Compression>>,
+    /// 小于这个字节数的 frame 不压缩，默认 1436
+    pub threshold: Option<usize>,
+}
+
+impl CompressionConfig {
+    pub fn codecs(&self) -> &[Compression] {
+        self.codecs.as_deref().unwrap_or(&Compression::ALL)
+    }
+}
+
+/// 后台压缩存储（合并数据文件、重写 WAL 等）的时机，也可以通过 COMPACT 命令手动压缩
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct CompactionConfig {
+    /// 每隔多少秒压缩一次；没有配置时不定期压缩
+    pub interval: Option<u64>,
+    /// 存储占用的磁盘空间超过这个大小时压缩，例如 "10GB"
+    pub max_disk_usage: Option<String>,
+    /// 检查是否需要压缩的间隔（秒），默认 60
+    pub check_interval: Option<u64>,
+}
+
+/// 多版本：每个修改数据的命令生成一个新的 revision，HGET 可以读取某个 revision 时 key 的值。
+/// 历史只保存在内存中，重启之后从头开始；过期和 lease 删除 key 不生成 revision
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq)]
+pub struct MvccConfig {
+    /// 最多保留多少个 revision 的历史，更早的自动丢弃；默认 10000
+    pub retention: Option<u64>,
+}
+
+/// 执行时间超过阈值的命令写入日志，并且保留最近的一部分，可以通过 SLOWLOG GET 查看
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq)]
+pub struct SlowlogConfig {
+    /// 阈值（毫秒），默认 10 毫秒；0 表示记录所有的命令
+    pub threshold: Option<u64>,
+    /// 最多保留的条数，默认 128
+    pub max_len: Option<usize>,
+}
+
+/// 带幂等 key 的命令的结果在内存中保留一段时间，重试时直接返回
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq)]
+pub struct IdempotencyConfig {
+    /// 结果保留的秒数，默认 60 秒
+    pub ttl: Option<u64>,
+    /// 最多保留的结果数，超过之后丢弃最早的，默认 100000
+    pub max_keys: Option<usize>,
+}
+
+/// 审计日志：谁在什么时候用什么命令修改了哪个 table 的哪个 key，每条记录是一个 JSON 对象
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct AuditConfig {
+    /// 追加写入的文件，每行一条记录
+    pub path: Option<String>,
+    /// 发布记录的 pub/sub topic
+    pub topic: Option<String>,
+    /// 只记录这些 table 的修改，为空时记录所有的 table
+    #[serde(default)]
+    pub tables: Vec<String>,
+}
+
+/// CDC：修改先写入 dir 下的日志，再由后台任务投递到 sink；sink 确认之后才推进 dir 下持久化的游标，
+/// 所以每个修改至少投递一次
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct CdcConfig {
+    /// 存放日志和游标的目录
+    pub dir: String,
+    pub sink: CdcSinkConfig,
+    /// 只投递这些 table 的修改，为空时投递所有的 table
+    #[serde(default)]
+    pub tables: Vec<String>,
+    /// 日志什么时候 fsync，默认 everysec
+    #[serde(default)]
+    pub fsync: FsyncPolicy,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(tag = "type", content = "args")]
+pub enum CdcSinkConfig {
+    /// 追加写入文件，每个修改一行 JSON
+    File(String),
+    /// 通过客户端写入下游的 kv-sv，每个命令的修改作为一个 BATCH 执行
+    Kv(Box<ClientConfig>),
+}
+
+/// 消费一个 Kafka topic，把消息写入 table：消息的 value 是 protobuf 编码的 Kvpair，pair 中没有 value
+/// 或者消息的 value 为空（tombstone）时删除 key。消费位置和数据一起原子地写入 storage
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct IngestConfig {
+    /// bootstrap broker 的地址
+    pub brokers: Vec<String>,
+    pub topic: String,
+    pub table: String,
+    /// 消费的 partition，为空时消费 topic 所有的 partition
+    #[serde(default)]
+    pub partitions: Vec<i32>,
+    /// 没有保存消费位置时，是否从最早的消息开始消费；为 false 时只消费之后产生的消息，默认为 true
+    pub from_beginning: Option<bool>,
+}
+
+/// 令牌桶限流，允许的突发请求数等于每秒的请求数；没有配置时不限制
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq)]
+pub struct LimitsConfig {
+    /// 每个连接每秒最多处理的请求数
+    pub per_conn_qps: Option<u32>,
+    /// 每个 table 每秒最多处理的请求数，所有连接共享
+    pub per_table_qps: Option<u32>,
+}
+
+/// 没有配置时不关闭空闲的连接，使用系统默认的 TCP 选项
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq)]
+pub struct NetworkConfig {
+    /// 连接上没有执行中的命令超过这么多秒之后，服务器关闭连接；SUBSCRIBE 这类命令一直算在执行中。
+    /// 对 prost、RESP 和 WebSocket 的连接生效
+    pub idle_timeout: Option<u64>,
+    /// 连接上没有数据这么多秒之后开始发送 TCP keepalive 探测，发现 NAT 后面已经断开的连接
+    pub tcp_keepalive: Option<u64>,
+    /// 关闭 Nagle 算法，小的响应马上发出
+    #[serde(default)]
+    pub tcp_nodelay: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ReplicationConfig {
+    /// 连接 primary 使用的客户端配置
+    pub primary: ClientConfig,
+}
+
+/// raft 集群的配置；所有节点的 peers 加上自己应该是同一组节点
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ClusterConfig {
+    /// 本节点的 id，不能是 0，集群内唯一
+    pub id: u64,
+    /// 保存 raft 日志和投票的目录
+    pub dir: String,
+    /// 客户端访问本节点的地址，CLUSTER SLOTS 中返回给客户端；默认是 general.addr
+    pub advertise_addr: Option<String>,
+    /// 其它节点
+    #[serde(default)]
+    pub peers: Vec<PeerConfig>,
+    /// 多久没有收到 leader 的消息就发起选举（毫秒），实际在 1 到 2 倍之间随机选取；默认 300
+    pub election_timeout: Option<u64>,
+    /// leader 发送心跳的间隔（毫秒），默认 50
+    pub heartbeat_interval: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct PeerConfig {
+    pub id: u64,
+    /// 连接这个节点使用的客户端配置
+    pub client: ClientConfig,
+}
+
+/// 代理模式的配置：按 table 和 key 的一致性哈希选择后端节点，增加或者删除一个节点只会移动一部分 key
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ProxyConfig {
+    /// 后端节点，按地址计算在哈希环上的位置，和配置的顺序无关
+    pub backends: Vec<ClientConfig>,
+    /// 每个后端节点在哈希环上的虚拟节点数，默认 160
+    pub virtual_nodes: Option<usize>,
+}
+
+/// 节点发现的配置：从 seeds 出发，定期和随机一个节点交换各自知道的节点和心跳，
+/// 心跳长时间不增加的节点先被认为可疑，再被认为已经下线
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct MembershipConfig {
+    /// 启动时联系的节点；为空时集群模式下使用 peers，replica 使用 primary。
+    /// 之后发现的节点使用第一个 seed 的配置连接，只替换其中的地址
+    #[serde(default)]
+    pub seeds: Vec<ClientConfig>,
+    /// 其它节点访问本节点的地址，默认是 general.addr
+    pub advertise_addr: Option<String>,
+    /// gossip 的间隔（毫秒），默认 1000
+    pub interval: Option<u64>,
+    /// 多久没有收到新的心跳认为节点可疑（毫秒），再过同样的时间认为已经下线；默认 5000
+    pub failure_timeout: Option<u64>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub struct ServerTlsConfig {
+    /// 服务器证书，PEM 内容或者文件路径
     pub cert: String,
+    /// 服务器私钥，PEM 内容或者文件路径
     pub key: String,
     pub ca: Option<String>,
+    /// 检查证书文件是否更新的间隔（秒）；配置之后证书轮换不需要重启服务器
+    pub watch_interval: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct TelemetryConfig {
+    /// OTLP gRPC endpoint，例如 http://127.0.0.1:4317
+    pub endpoint: String,
+    /// 上报时使用的 service.name，默认为 kv-server
+    pub service_name: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct SecurityConfig {
+    /// 配置之后，客户端必须先 AUTH 才能发送其它命令
+    pub auth: Option<AuthConfig>,
+    /// 配置之后，按照用户的角色限制可以访问的 table 和操作
+    pub acl: Option<AclConfig>,
+    /// 签发客户端证书的 CA，PEM 内容或者文件路径；配置之后证书的 CN（没有 CN 时是 SAN）作为用户名，
+    /// 不需要再 AUTH。优先于 [tls] ca
+    pub client_ca: Option<String>,
+    /// 为 false 时没有证书的客户端也可以连接，之后再 AUTH
+    #[serde(default)]
+    pub require_client_cert: bool,
+    /// 租户：租户的用户只能访问租户自己的 table
+    #[serde(default)]
+    pub tenants: Vec<TenantConfig>,
+}
+
+/// 租户的用户访问的 table 在存储中加上 `<name>:` 的前缀，ACL 按去掉前缀之后的 table 名检查
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct TenantConfig {
+    pub name: String,
+    /// 属于这个租户的用户名，包括客户端证书中的用户名
+    pub users: Vec<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct AclConfig {
+    #[serde(default)]
+    pub roles: Vec<RoleConfig>,
+    /// <用户名，角色列表>；没有认证的连接按 default 用户处理
+    #[serde(default)]
+    pub users: HashMap<String, Vec<String>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct RoleConfig {
+    pub name: String,
+    /// 可以访问的 table，支持 `*` 和 `prefix*` 的写法
+    pub tables: Vec<String>,
+    pub verbs: Vec<Verb>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum Verb {
+    Read,
+    Write,
+    Admin,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct AuthConfig {
+    /// 不带用户名的共享密码，认证成功后的用户名为 default
+    pub password: Option<String>,
+    #[serde(default)]
+    pub users: Vec<UserConfig>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct UserConfig {
+    pub name: String,
+    /// AUTH username password
+    pub password: Option<String>,
+    /// AUTH token，不需要用户名
+    pub token: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ClientAuthConfig {
+    /// 为空时 password 可以是共享密码或者 token
+    pub username: Option<String>,
+    pub password: String,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub struct ClientTlsConfig {
     pub domain: String,
+    /// 客户端证书和私钥的 PEM 内容
     pub identity: Option<(String, String)>,
     pub ca: Option<String>,
+    /// 客户端证书的文件路径，没有配置 identity 时使用
+    pub cert_path: Option<String>,
+    /// 客户端私钥的文件路径
+    pub key_path: Option<String>,
+}
+
+impl AuthConfig {
+    /// 校验凭证，成功时返回用户名
+    pub fn verify(&self, username: &str, password: &str) -> Option<String> {
+        if username.is_empty() {
+            if self.password.as_deref() == Some(password) {
+                return Some("default".into());
+            }
+            return self
+                .users
+                .iter()
+                .find(|u| u.token.as_deref() == Some(password))
+                .map(|u| u.name.clone());
+        }
+
+        self.users
+            .iter()
+            .find(|u| u.name == username && u.password.as_deref() == Some(password))
+            .map(|u| u.name.clone())
+    }
+}
+
+/// 解析 "512MB"、"64kb" 这样的大小，单位是 1024 进制，没有单位时是字节数
+pub fn parse_size(s: &str) -> Result<usize, KvError> {
+    let s = s.trim();
+    let upper = s.to_ascii_uppercase();
+    let (number, unit) = match upper.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => upper.split_at(i),
+        None => (upper.as_str(), ""),
+    };
+    let unit: usize = match unit.trim() {
+        "" | "B" => 1,
+        "K" | "KB" => 1 << 10,
+        "M" | "MB" => 1 << 20,
+        "G" | "GB" => 1 << 30,
+        _ => return Err(KvError::InvalidCommand(format!("invalid size {}", s))),
+    };
+    number
+        .parse::<usize>()
+        .ok()
+        .and_then(|n| n.checked_mul(unit))
+        .ok_or_else(|| KvError::InvalidCommand(format!("invalid size {}", s)))
 }
 
 impl ServerConfig {
     pub fn load(path: &str) -> Result<Self, KvError> {
         let config = fs::read_to_string(path)?;
-        let config = toml::from_str(&config)?;
+        let mut config: Self = toml::from_str(&config)?;
+        config.source = Some(path.into());
         Ok(config)
     }
+
+    /// 验证客户端证书使用的 CA，以及是否要求客户端提供证书；没有配置 CA 时不验证
+    pub fn client_auth(&self) -> Result<Option<(String, bool)>, KvError> {
+        let security = &self.security;
+        match (&security.client_ca, &self.tls.ca) {
+            (Some(ca), _) => Ok(Some((load_pem(ca)?, security.require_client_cert))),
+            (None, Some(ca)) => Ok(Some((ca.clone(), true))),
+            (None, None) if security.require_client_cert => Err(KvError::InvalidCommand(
+                "require_client_cert needs client_ca".into(),
+            )),
+            (None, None) => Ok(None),
+        }
+    }
+}
+
+impl ServerTlsConfig {
+    /// 证书和私钥的 PEM 内容
+    pub fn identity(&self) -> Result<(String, String), KvError> {
+        Ok((load_pem(&self.cert)?, load_pem(&self.key)?))
+    }
+
+    /// 配置成文件路径的证书和私钥
+    pub fn files(&self) -> Vec<&str> {
+        [self.cert.as_str(), self.key.as_str()]
+            .into_iter()
+            .filter(|v| !is_pem(v))
+            .collect()
+    }
+}
+
+impl ClientTlsConfig {
+    /// 客户端证书和私钥，identity 优先于文件路径
+    pub fn identity(&self) -> Result<Option<(String, String)>, KvError> {
+        match (&self.identity, &self.cert_path, &self.key_path) {
+            (Some(identity), _, _) => Ok(Some(identity.clone())),
+            (None, Some(cert), Some(key)) => {
+                Ok(Some((fs::read_to_string(cert)?, fs::read_to_string(key)?)))
+            }
+            (None, None, None) => Ok(None),
+            _ => Err(KvError::InvalidCommand(
+                "cert_path and key_path must be set together".into(),
+            )),
+        }
+    }
+}
+
+impl ClientConfig {
+    pub fn load(path: &str) -> Result<Self, KvError> {
+        let config = fs::read_to_string(path)?;
+        Ok(toml::from_str(&config)?)
+    }
+}
+
+/// 配置的值是 PEM 内容时直接返回，否则当作文件路径读取
+pub fn load_pem(value: &str) -> Result<String, KvError> {
+    if is_pem(value) {
+        return Ok(value.into());
+    }
+    Ok(fs::read_to_string(value)?)
+}
+
+fn is_pem(value: &str) -> bool {
+    value.contains("-----BEGIN")
 }
 
 #[cfg(test)]
@@ -60,6 +853,442 @@ mod config_tests {
         assert!(result.is_ok());
     }
 
+    #[test]
+    fn telemetry_config_should_be_optional() {
+        let config: ServerConfig = toml::from_str(include_str!("../fixtures/server.conf")).unwrap();
+        assert_eq!(config.telemetry, None);
+
+        let conf = format!(
+            "{}\n[telemetry]\nendpoint = 'http://127.0.0.1:4317'\n",
+            include_str!("../fixtures/server.conf")
+        );
+        let config: ServerConfig = toml::from_str(&conf).unwrap();
+        let telemetry = config.telemetry.unwrap();
+        assert_eq!(telemetry.endpoint, "http://127.0.0.1:4317");
+        assert_eq!(telemetry.service_name, None);
+    }
+
+    #[test]
+    fn replication_config_should_be_loaded() {
+        let config: ServerConfig = toml::from_str(include_str!("../fixtures/server.conf")).unwrap();
+        assert_eq!(config.replication, None);
+
+        let conf = format!(
+            "{}\n[replication.primary.general]\naddr = '127.0.0.1:9876'\n\
+             [replication.primary.tls]\ndomain = 'kvserver.acme.inc'\n",
+            include_str!("../fixtures/server.conf")
+        );
+        let config: ServerConfig = toml::from_str(&conf).unwrap();
+        let primary = config.replication.unwrap().primary;
+        assert_eq!(primary.general.addr, "127.0.0.1:9876");
+        assert_eq!(primary.auth, None);
+    }
+
+    #[test]
+    fn cluster_config_should_be_loaded() {
+        let config: ServerConfig = toml::from_str(include_str!("../fixtures/server.conf")).unwrap();
+        assert_eq!(config.cluster, None);
+
+        let conf = format!(
+            "{}\n[cluster]\nid = 1\ndir = '/tmp/kvs-raft'\n\
+             [[cluster.peers]]\nid = 2\n\
+             [cluster.peers.client.general]\naddr = '127.0.0.1:9877'\n\
+             [cluster.peers.client.tls]\ndomain = 'kvserver.acme.inc'\n",
+            include_str!("../fixtures/server.conf")
+        );
+        let config: ServerConfig = toml::from_str(&conf).unwrap();
+        let cluster = config.cluster.unwrap();
+        assert_eq!(cluster.id, 1);
+        assert_eq!(cluster.election_timeout, None);
+        assert_eq!(cluster.peers.len(), 1);
+        assert_eq!(cluster.peers[0].id, 2);
+        assert_eq!(cluster.peers[0].client.general.addr, "127.0.0.1:9877");
+    }
+
+    #[test]
+    fn proxy_config_should_be_loaded() {
+        let config: ServerConfig = toml::from_str(include_str!("../fixtures/server.conf")).unwrap();
+        assert_eq!(config.proxy, None);
+
+        let conf = format!(
+            "{}\n[[proxy.backends]]\n[proxy.backends.general]\naddr = '127.0.0.1:9877'\n\
+             [proxy.backends.tls]\ndomain = 'kvserver.acme.inc'\n",
+            include_str!("../fixtures/server.conf")
+        );
+        let config: ServerConfig = toml::from_str(&conf).unwrap();
+        let proxy = config.proxy.unwrap();
+        assert_eq!(proxy.backends.len(), 1);
+        assert_eq!(proxy.backends[0].general.addr, "127.0.0.1:9877");
+        assert_eq!(proxy.virtual_nodes, None);
+    }
+
+    #[test]
+    fn membership_config_should_be_loaded() {
+        let config: ServerConfig = toml::from_str(include_str!("../fixtures/server.conf")).unwrap();
+        assert_eq!(config.membership, None);
+
+        let conf = format!(
+            "{}\n[membership]\ninterval = 500\n\
+             [[membership.seeds]]\n[membership.seeds.general]\naddr = '127.0.0.1:9877'\n\
+             [membership.seeds.tls]\ndomain = 'kvserver.acme.inc'\n",
+            include_str!("../fixtures/server.conf")
+        );
+        let config: ServerConfig = toml::from_str(&conf).unwrap();
+        let membership = config.membership.unwrap();
+        assert_eq!(membership.interval, Some(500));
+        assert_eq!(membership.failure_timeout, None);
+        assert_eq!(membership.seeds[0].general.addr, "127.0.0.1:9877");
+    }
+
+    #[test]
+    fn restore_from_should_be_optional() {
+        let config: ServerConfig = toml::from_str(include_str!("../fixtures/server.conf")).unwrap();
+        assert_eq!(config.restore_from, None);
+
+        let conf = format!(
+            "restore_from = 'kv.snapshot'\n{}",
+            include_str!("../fixtures/server.conf")
+        );
+        let config: ServerConfig = toml::from_str(&conf).unwrap();
+        assert_eq!(config.restore_from, Some("kv.snapshot".into()));
+    }
+
+    #[test]
+    fn wal_storage_config_should_be_loaded() {
+        let config: StorageConfig = toml::from_str(
+            r#"
+            type = "WalMemTable"
+            args = { path = "/tmp/kv.wal" }
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            config,
+            StorageConfig::WalMemTable(WalConfig {
+                path: "/tmp/kv.wal".into(),
+                fsync: FsyncPolicy::EverySec,
+            })
+        );
+
+        let config: WalConfig = toml::from_str("path = 'kv.wal'\nfsync = 'always'").unwrap();
+        assert_eq!(config.fsync, FsyncPolicy::Always);
+    }
+
+    #[test]
+    fn bitcask_storage_config_should_be_loaded() {
+        let config: StorageConfig = toml::from_str(
+            r#"
+            type = "Bitcask"
+            args = { path = "/tmp/kvserver", max_file_size = "16MB", merge_ratio = 0.3 }
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            config,
+            StorageConfig::Bitcask(BitcaskConfig {
+                path: "/tmp/kvserver".into(),
+                fsync: FsyncPolicy::EverySec,
+                max_file_size: Some("16MB".into()),
+                merge_ratio: Some(0.3),
+                merge_interval: None,
+            })
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "lmdb")]
+    fn lmdb_storage_config_should_be_loaded() {
+        let config: StorageConfig = toml::from_str(
+            r#"
+            type = "Lmdb"
+            args = { path = "/tmp/kvserver", map_size = "64GB" }
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            config,
+            StorageConfig::Lmdb {
+                path: "/tmp/kvserver".into(),
+                map_size: Some("64GB".into()),
+            }
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "redb")]
+    fn redb_storage_config_should_be_loaded() {
+        let config: StorageConfig = toml::from_str("type = 'Redb'\nargs = '/tmp/kv.redb'").unwrap();
+        assert_eq!(config, StorageConfig::Redb("/tmp/kv.redb".into()));
+    }
+
+    #[test]
+    #[cfg(feature = "sqlite")]
+    fn sqlite_storage_config_should_be_loaded() {
+        let config: StorageConfig = toml::from_str("type = 'Sqlite'\nargs = '/tmp/kv.db'").unwrap();
+        assert_eq!(config, StorageConfig::Sqlite("/tmp/kv.db".into()));
+    }
+
+    #[test]
+    fn tiered_storage_config_should_be_loaded() {
+        let config: StorageConfig = toml::from_str(
+            r#"
+            type = "Tiered"
+            args = { path = "/tmp/kvserver", cache_size = "128MB" }
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            config,
+            StorageConfig::Tiered(TieredConfig {
+                path: "/tmp/kvserver".into(),
+                cache_size: Some("128MB".into()),
+                invalidation: CacheInvalidation::Update,
+            })
+        );
+
+        let config: TieredConfig = toml::from_str("path = 'kv'\ninvalidation = 'evict'").unwrap();
+        assert_eq!(config.invalidation, CacheInvalidation::Evict);
+    }
+
+    #[test]
+    fn encryption_config_should_be_loaded() {
+        let conf = format!(
+            "{}\n[encryption]\nactive = 2\nkeys = [{{ id = 1, key = 'a2V5' }}, {{ id = 2, env = 'KV_KEY' }}]\n",
+            include_str!("../fixtures/server.conf")
+        );
+        let config: ServerConfig = toml::from_str(&conf).unwrap();
+        assert_eq!(
+            config.encryption,
+            Some(EncryptionConfig {
+                cipher: Cipher::Aes256Gcm,
+                active: 2,
+                keys: vec![
+                    EncryptionKey {
+                        id: 1,
+                        key: Some("a2V5".into()),
+                        env: None,
+                    },
+                    EncryptionKey {
+                        id: 2,
+                        key: None,
+                        env: Some("KV_KEY".into()),
+                    },
+                ],
+                reencrypt: false,
+            })
+        );
+
+        let config: EncryptionConfig =
+            toml::from_str("cipher = 'chacha20-poly1305'\nactive = 1\nkeys = []").unwrap();
+        assert_eq!(config.cipher, Cipher::ChaCha20Poly1305);
+    }
+
+    #[test]
+    fn tiering_config_should_be_loaded() {
+        let conf = format!(
+            "{}\n[tiering]\nidle_days = 30\n[tiering.s3]\nendpoint = 'http://127.0.0.1:9000'\nbucket = 'kv'\n",
+            include_str!("../fixtures/server.conf")
+        );
+        let config: ServerConfig = toml::from_str(&conf).unwrap();
+        assert_eq!(
+            config.tiering,
+            Some(TieringConfig {
+                idle_days: 30,
+                min_size: None,
+                interval: None,
+                s3: S3Config {
+                    endpoint: "http://127.0.0.1:9000".into(),
+                    bucket: "kv".into(),
+                    region: None,
+                    prefix: String::new(),
+                    access_key: None,
+                    secret_key: None,
+                },
+            })
+        );
+    }
+
+    #[test]
+    fn compaction_config_should_be_loaded() {
+        let conf = format!(
+            "{}\n[compaction]\ninterval = 86400\nmax_disk_usage = '10GB'\n",
+            include_str!("../fixtures/server.conf")
+        );
+        let config: ServerConfig = toml::from_str(&conf).unwrap();
+        assert_eq!(
+            config.compaction,
+            Some(CompactionConfig {
+                interval: Some(86400),
+                max_disk_usage: Some("10GB".into()),
+                check_interval: None,
+            })
+        );
+    }
+
+    #[test]
+    fn mvcc_config_should_be_loaded() {
+        let conf = format!(
+            "{}\n[mvcc]\nretention = 100\n",
+            include_str!("../fixtures/server.conf")
+        );
+        let config: ServerConfig = toml::from_str(&conf).unwrap();
+        assert_eq!(
+            config.mvcc,
+            Some(MvccConfig {
+                retention: Some(100)
+            })
+        );
+    }
+
+    #[test]
+    fn memtable_config_should_be_loaded() {
+        let config: ServerConfig = toml::from_str(include_str!("../fixtures/server.conf")).unwrap();
+        assert_eq!(config.memtable, MemTableConfig::default());
+
+        let conf = format!(
+            "{}\n[memtable]\nmax_memory = '512MB'\neviction = 'lfu'\nshards = 64\n",
+            include_str!("../fixtures/server.conf")
+        );
+        let config: ServerConfig = toml::from_str(&conf).unwrap();
+        let max_memory = config.memtable.max_memory.unwrap();
+        assert_eq!(parse_size(&max_memory).unwrap(), 512 << 20);
+        assert_eq!(config.memtable.eviction, EvictionPolicy::Lfu);
+        assert_eq!(config.memtable.shards, Some(64));
+        assert_eq!(config.memtable.capacity, None);
+    }
+
+    #[test]
+    fn limits_config_should_be_loaded() {
+        let config: ServerConfig = toml::from_str(include_str!("../fixtures/server.conf")).unwrap();
+        assert_eq!(config.limits, LimitsConfig::default());
+
+        let conf = format!(
+            "{}\n[limits]\nper_conn_qps = 1000\nper_table_qps = 5000\n",
+            include_str!("../fixtures/server.conf")
+        );
+        let config: ServerConfig = toml::from_str(&conf).unwrap();
+        assert_eq!(config.limits.per_conn_qps, Some(1000));
+        assert_eq!(config.limits.per_table_qps, Some(5000));
+    }
+
+    #[test]
+    fn network_config_should_be_loaded() {
+        let config: ServerConfig = toml::from_str(include_str!("../fixtures/server.conf")).unwrap();
+        assert_eq!(config.network, NetworkConfig::default());
+
+        let conf = format!(
+            "{}\n[network]\nidle_timeout = 300\ntcp_keepalive = 60\ntcp_nodelay = true\n",
+            include_str!("../fixtures/server.conf")
+        );
+        let config: ServerConfig = toml::from_str(&conf).unwrap();
+        assert_eq!(config.network.idle_timeout, Some(300));
+        assert_eq!(config.network.tcp_keepalive, Some(60));
+        assert!(config.network.tcp_nodelay);
+    }
+
+    #[test]
+    fn quota_config_should_be_loaded() {
+        let conf = format!(
+            "{}\n[[quotas]]\ntable = \"orders\"\nmax_keys = 1000\n\n[[quotas]]\ntenant = \"billing\"\nmax_bytes = \"64MB\"\n",
+            include_str!("../fixtures/server.conf")
+        );
+        let config: ServerConfig = toml::from_str(&conf).unwrap();
+        assert_eq!(
+            config.quotas,
+            [
+                QuotaConfig {
+                    table: Some("orders".into()),
+                    max_keys: Some(1000),
+                    ..Default::default()
+                },
+                QuotaConfig {
+                    tenant: Some("billing".into()),
+                    max_bytes: Some("64MB".into()),
+                    ..Default::default()
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn client_auth_should_prefer_client_ca() {
+        let mut config: ServerConfig =
+            toml::from_str(include_str!("../fixtures/server.conf")).unwrap();
+        assert_eq!(config.client_auth().unwrap(), None);
+
+        config.security.require_client_cert = true;
+        assert!(config.client_auth().is_err());
+
+        config.tls.ca = Some("tls ca".into());
+        config.security.require_client_cert = false;
+        assert_eq!(config.client_auth().unwrap(), Some(("tls ca".into(), true)));
+
+        config.security.client_ca = Some("fixtures/ca.cert".into());
+        let (ca, required) = config.client_auth().unwrap().unwrap();
+        assert!(ca.starts_with("-----BEGIN CERTIFICATE-----"));
+        assert!(!required);
+    }
+
+    #[test]
+    fn server_tls_config_should_load_files() {
+        let mut config: ServerConfig =
+            toml::from_str(include_str!("../fixtures/server.conf")).unwrap();
+        assert!(config.tls.files().is_empty());
+        let (cert, _) = config.tls.identity().unwrap();
+        assert_eq!(cert, config.tls.cert);
+
+        config.tls.cert = "fixtures/server.cert".into();
+        assert_eq!(config.tls.files(), ["fixtures/server.cert"]);
+        let (cert, _) = config.tls.identity().unwrap();
+        assert!(cert.starts_with("-----BEGIN CERTIFICATE-----"));
+    }
+
+    #[test]
+    fn compression_config_should_be_loaded() {
+        let config: ServerConfig = toml::from_str(include_str!("../fixtures/server.conf")).unwrap();
+        assert_eq!(config.compression.codecs(), Compression::ALL);
+
+        let conf = format!(
+            "{}\n[compression]\ncodecs = [\"lz4\", \"none\"]\nthreshold = 4096\n",
+            include_str!("../fixtures/client.conf")
+        );
+        let config: ClientConfig = toml::from_str(&conf).unwrap();
+        let compression = config.compression.unwrap();
+        assert_eq!(compression.codecs(), [Compression::Lz4, Compression::None]);
+        assert_eq!(compression.threshold, Some(4096));
+    }
+
+    #[test]
+    fn parse_size_should_work() {
+        assert_eq!(parse_size("1024").unwrap(), 1024);
+        assert_eq!(parse_size("64kb").unwrap(), 64 << 10);
+        assert_eq!(parse_size(" 2 GB ").unwrap(), 2 << 30);
+        assert!(parse_size("-1").is_err());
+        assert!(parse_size("1TB").is_err());
+        assert!(parse_size("MB").is_err());
+    }
+
+    #[test]
+    fn auth_config_should_verify_credentials() {
+        let config: AuthConfig = toml::from_str(
+            r#"
+            password = "secret"
+            users = [
+                { name = "alice", password = "alice-pass" },
+                { name = "svc", token = "svc-token" },
+            ]
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.verify("", "secret"), Some("default".into()));
+        assert_eq!(config.verify("alice", "alice-pass"), Some("alice".into()));
+        assert_eq!(config.verify("", "svc-token"), Some("svc".into()));
+        assert_eq!(config.verify("alice", "secret"), None);
+        assert_eq!(config.verify("svc", "svc-token"), None);
+        assert_eq!(config.verify("", "wrong"), None);
+    }
+
     #[test]
     fn client_config_should_be_loaded() {
         let result: Result<ClientConfig, toml::de::Error> =
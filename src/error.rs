@@ -9,9 +9,33 @@ pub enum KvError {
 
     #[error("Cannot parse command: `{0}`")]
     InvalidCommand(String),
-    #[error("Cannot convert value {:0} to {1}")]
+    #[error("Unauthorized: {0}")]
+    Unauthorized(String),
+    #[error("Permission denied: {0}")]
+    PermissionDenied(String),
+    #[error("Conflict: {0}")]
+    Conflict(String),
+    #[error("Out of memory: {0}")]
+    OutOfMemory(String),
+    #[error("Quota exceeded: {0}")]
+    QuotaExceeded(String),
+    #[error("Timeout: {0}")]
+    Timeout(String),
+    #[error("Too many requests: {0}")]
+    RateLimited(String),
+    #[error("Too many connections: {0}")]
+    TooManyConnections(String),
+    #[error("Not leader: {0}")]
+    NotLeader(String),
+    #[error("Moved to node {0} at {1}")]
+    Moved(u64, String),
+    #[error("Revision {0} has been compacted, the oldest available revision is {1}")]
+    Compacted(u64, u64),
+    #[error("Frame too large: {0} bytes exceeds max frame size {1}")]
+    FrameTooLarge(usize, usize),
+    #[error("Cannot convert value {0:?} to {1}")]
     ConvertError(Value, &'static str),
-    #[error("Cannot process command {0} with table: {1}, key: {2}. Error: {}")]
+    #[error("Cannot process command {0} with table: {1}, key: {2}. Error: {3}")]
     StorageError(&'static str, String, String, String),
 
     #[error("Failed to encode protobuf message")]
@@ -36,6 +60,9 @@ pub enum KvError {
 
     #[error("TomlError")]
     TomlError(#[from] toml::de::Error),
+
+    #[error("Yamux connection error")]
+    YamuxError(#[from] yamux::ConnectionError),
 }
 
 pub(crate) trait IOError<T> {
@@ -59,7 +86,7 @@ impl<T> CertError<T> for Result<T, ()> {
     fn to_error(self, key: impl Into<String>, value: impl Into<String>) -> Result<T, KvError> {
         match self {
             Ok(v) => Ok(v),
-            Err(e) => Err(KvError::CertificateParseError(key.into(), value.into())),
+            Err(_) => Err(KvError::CertificateParseError(key.into(), value.into())),
         }
     }
 }
@@ -4,59 +4,837 @@ pub mod network;
 pub mod pb;
 pub mod service;
 pub mod storage;
-
-use std::time::Duration;
+pub mod telemetry;
 
 pub use network::*;
 pub use service::*;
 pub use storage::*;
 
 use anyhow::Result;
-use config::{ClientConfig, ServerConfig};
-use network::tls::{TlsClientConnector, TlsServerAcceptor};
-use storage::{memory::MemTable, sled_db::SledDB};
+use audit::AuditLog;
+use cdc::Cdc;
+use compaction::CompactionSchedule;
+use config::{ClientConfig, NetworkConfig, ServerConfig};
+use error::KvError;
+use futures::{SinkExt, StreamExt};
+use hyper::StatusCode;
+use network::{
+    conn_limit::ConnectionLimiter,
+    frame::FrameCompression,
+    http::HttpGateway,
+    in_flight::InFlightLimiter,
+    resp::{reject as reject_resp, RespServerStream},
+    stream::ProstStream,
+    tls::TlsServerAcceptor,
+    transport::{BoxedTransport, Incoming, Listener},
+    websocket::WebSocketGateway,
+};
+use pb::abi::{value::Value, CommandRequest, CommandResponse};
+use session::Session;
+use shutdown::Shutdown;
+use socket2::{SockRef, TcpKeepalive};
+use std::{
+    collections::HashSet,
+    future::Future,
+    io,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+#[cfg(feature = "lmdb")]
+use storage::lmdb::Lmdb;
+#[cfg(feature = "redb")]
+use storage::redb_db::Redb;
+#[cfg(feature = "sqlite")]
+use storage::sqlite::Sqlite;
+use storage::{
+    bitcask::Bitcask,
+    cold::ColdStorage,
+    encrypted::{EncryptedStorage, Keyring},
+    memory::MemTable,
+    migrate::MigrateStats,
+    quota::parse_quotas,
+    s3::S3Store,
+    sled_db::SledDB,
+    tiered::Tiered,
+    wal::WalMemTable,
+};
+use tenant::Tenants;
 use tokio::{
+    io::{AsyncRead, AsyncWrite},
     net::{TcpListener, TcpStream},
+    sync::{oneshot, Notify},
     time,
 };
 use tokio_util::compat::FuturesAsyncReadCompatExt as _;
-use tracing::info;
+use tracing::{info, warn};
 
-use crate::{multiplex::YamuxCtrl, service_builder::ServiceBuilder};
+use crate::{multiplex::YamuxCtrl, plugin::Plugins, proxy::Proxy, service_builder::ServiceBuilder};
 
-pub async fn start_client_with_config(
-    config: ClientConfig,
-) -> Result<YamuxCtrl<tokio_rustls::client::TlsStream<TcpStream>>> {
-    let addr = &config.general.addr;
-    let tls = &config.tls;
-    let identity = tls
-        .identity
-        .as_ref()
-        .map(|(cert, key)| (key.as_str(), cert.as_str()));
-    let connector = TlsClientConnector::new(&tls.domain, identity, tls.ca.as_deref())?;
-    let stream = TcpStream::connect(addr).await?;
-    let stream = connector.connect(stream).await?;
-    Ok(YamuxCtrl::new_client(stream, None))
-}
-
-/// 通过配置文件创建KV Service
-pub async fn start_server_with_config(config: ServerConfig) -> Result<()> {
-    match &config.storage {
-        config::StorageConfig::MemTable => start_server(MemTable::default(), config).await?,
-        config::StorageConfig::SledDB(path) => start_server(SledDB::new(path), config).await?,
+/// 退出时等待正在执行的请求完成的默认时间（秒）
+const DEFAULT_GRACE_PERIOD: u64 = 30;
+
+/// 被拒绝的连接等待客户端发送第一个命令的最长时间
+const REJECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub async fn start_client_with_config(config: ClientConfig) -> Result<YamuxCtrl<BoxedTransport>> {
+    let stream = transport::connect(&config.general.addr, &config.tls).await?;
+    let mut ctrl = YamuxCtrl::new_client(stream, None);
+    ctrl.set_max_frame_size(config.max_frame_size);
+
+    // 认证是针对整个连接的，用一个单独的 stream 完成即可
+    if let Some(auth) = &config.auth {
+        let mut stream = ctrl.open_stream().await?;
+        let cmd =
+            CommandRequest::new_auth(auth.username.as_deref().unwrap_or_default(), &auth.password);
+        let res = stream.execute(&cmd).await?;
+        if res.status != StatusCode::OK.as_u16() as u32 {
+            return Err(KvError::Unauthorized(res.message).into());
+        }
+    }
+
+    // 压缩算法同样是针对整个连接协商的；不认识 HELLO 的旧服务器继续使用 gzip
+    if let Some(compression) = &config.compression {
+        let mut stream = ctrl.open_stream().await?;
+        let codecs = compression.codecs().iter().map(|c| c.as_str());
+        let res = stream.execute(&CommandRequest::new_hello(codecs)).await?;
+        let codec = match res.values.first().and_then(|v| v.value.as_ref()) {
+            Some(Value::String(codec)) if res.status == StatusCode::OK.as_u16() as u32 => {
+                codec.parse().ok()
+            }
+            _ => None,
+        };
+        if let Some(codec) = codec {
+            ctrl.set_compression(FrameCompression::new(codec, compression.threshold));
+        }
+    }
+
+    Ok(ctrl)
+}
+
+/// 通过配置文件创建KV Service，开始监听之后返回，服务在后台运行
+pub async fn start_server_with_config(config: ServerConfig) -> Result<ServerHandle> {
+    let handle = match &config.storage {
+        config::StorageConfig::MemTable => {
+            let store = MemTable::with_config(&config.memtable)?;
+            start_cold_tier(store, config).await?
+        }
+        config::StorageConfig::SledDB(path) => start_cold_tier(SledDB::open(path)?, config).await?,
+        config::StorageConfig::WalMemTable(wal) => {
+            let table = MemTable::with_config(&config.memtable)?;
+            let store = WalMemTable::open_with(&wal.path, wal.fsync, table)?;
+            start_cold_tier(store, config).await?
+        }
+        config::StorageConfig::Tiered(tiered) => {
+            start_cold_tier(Tiered::open(tiered)?, config).await?
+        }
+        config::StorageConfig::Bitcask(bitcask) => {
+            let store = Bitcask::open(bitcask)?;
+            store.spawn(bitcask.merge_interval);
+            start_cold_tier(store, config).await?
+        }
+        #[cfg(feature = "lmdb")]
+        config::StorageConfig::Lmdb { path, map_size } => {
+            let store = Lmdb::open_with_config(path, map_size.as_deref())?;
+            start_cold_tier(store, config).await?
+        }
+        #[cfg(feature = "redb")]
+        config::StorageConfig::Redb(path) => start_cold_tier(Redb::open(path)?, config).await?,
+        #[cfg(feature = "sqlite")]
+        config::StorageConfig::Sqlite(path) => start_cold_tier(Sqlite::open(path)?, config).await?,
+    };
+
+    Ok(handle)
+}
+
+/// 配置了 tiering 时，在存储外面加一层冷数据分层。加密在分层的外面，上传到对象存储的是密文
+async fn start_cold_tier<Store: Storage>(
+    store: Store,
+    config: ServerConfig,
+) -> Result<ServerHandle> {
+    let Some(tiering) = &config.tiering else {
+        return start_encrypted(store, config).await;
     };
+    let store = ColdStorage::open(store, tiering)?;
+    store.spawn(tiering.interval);
+    start_encrypted(store, config).await
+}
 
+/// 配置了 encryption 时，在存储外面加一层加密。离线的 backup、export 等工具直接读写内部的存储，看到的是密文
+async fn start_encrypted<Store: Storage>(
+    store: Store,
+    config: ServerConfig,
+) -> Result<ServerHandle> {
+    let Some(encryption) = &config.encryption else {
+        return start_server(store, config).await;
+    };
+    let store = EncryptedStorage::new(store, Keyring::from_config(encryption)?);
+    if encryption.reencrypt {
+        let count = store.reencrypt()?;
+        info!(
+            "Re-encrypted {} values with key {}",
+            count, encryption.active
+        );
+    }
+    start_server(store, config).await
+}
+
+/// 把配置的存储中的数据导出成 snapshot，返回导出的 key 数量。
+/// 直接打开存储，服务器运行时存储被它锁住，需要先停止服务器；MemTable 停止之后没有数据
+pub fn backup_with_config(config: &ServerConfig, path: &str) -> Result<usize, KvError> {
+    match &config.storage {
+        config::StorageConfig::MemTable => Err(memtable_is_not_persistent()),
+        config::StorageConfig::SledDB(db) => snapshot::dump(&SledDB::open(db)?, path),
+        config::StorageConfig::WalMemTable(wal) => {
+            snapshot::dump(&WalMemTable::open(&wal.path, wal.fsync)?, path)
+        }
+        config::StorageConfig::Tiered(tiered) => snapshot::dump(&SledDB::open(&tiered.path)?, path),
+        config::StorageConfig::Bitcask(bitcask) => snapshot::dump(&Bitcask::open(bitcask)?, path),
+        #[cfg(feature = "lmdb")]
+        config::StorageConfig::Lmdb { path: db, map_size } => {
+            snapshot::dump(&Lmdb::open_with_config(db, map_size.as_deref())?, path)
+        }
+        #[cfg(feature = "redb")]
+        config::StorageConfig::Redb(db) => snapshot::dump(&Redb::open(db)?, path),
+        #[cfg(feature = "sqlite")]
+        config::StorageConfig::Sqlite(db) => snapshot::dump(&Sqlite::open(db)?, path),
+    }
+}
+
+/// 把 snapshot 导入到配置的存储，返回导入的记录数；和 backup_with_config 一样需要先停止服务器
+pub fn restore_with_config(config: &ServerConfig, path: &str) -> Result<usize, KvError> {
+    match &config.storage {
+        config::StorageConfig::MemTable => Err(memtable_is_not_persistent()),
+        config::StorageConfig::SledDB(db) => {
+            let store = SledDB::open(db)?;
+            let count = snapshot::restore(&store, path)?;
+            store.flush()?;
+            Ok(count)
+        }
+        config::StorageConfig::WalMemTable(wal) => {
+            let store = WalMemTable::open(&wal.path, wal.fsync)?;
+            let count = snapshot::restore(&store, path)?;
+            store.flush()?;
+            Ok(count)
+        }
+        config::StorageConfig::Tiered(tiered) => {
+            let store = SledDB::open(&tiered.path)?;
+            let count = snapshot::restore(&store, path)?;
+            store.flush()?;
+            Ok(count)
+        }
+        config::StorageConfig::Bitcask(bitcask) => {
+            let store = Bitcask::open(bitcask)?;
+            let count = snapshot::restore(&store, path)?;
+            store.flush()?;
+            Ok(count)
+        }
+        #[cfg(feature = "lmdb")]
+        config::StorageConfig::Lmdb { path: db, map_size } => {
+            let store = Lmdb::open_with_config(db, map_size.as_deref())?;
+            let count = snapshot::restore(&store, path)?;
+            store.flush()?;
+            Ok(count)
+        }
+        #[cfg(feature = "redb")]
+        config::StorageConfig::Redb(db) => {
+            let store = Redb::open(db)?;
+            let count = snapshot::restore(&store, path)?;
+            store.flush()?;
+            Ok(count)
+        }
+        #[cfg(feature = "sqlite")]
+        config::StorageConfig::Sqlite(db) => {
+            let store = Sqlite::open(db)?;
+            let count = snapshot::restore(&store, path)?;
+            store.flush()?;
+            Ok(count)
+        }
+    }
+}
+
+/// 把配置的存储中的 kvpair 导出成 JSON lines 或者 CSV，返回导出的 key 数量；需要先停止服务器
+pub fn export_with_config(
+    config: &ServerConfig,
+    writer: impl io::Write,
+    format: export::Format,
+) -> Result<usize, KvError> {
+    match &config.storage {
+        config::StorageConfig::MemTable => Err(memtable_is_not_persistent()),
+        config::StorageConfig::SledDB(db) => export::export(&SledDB::open(db)?, writer, format),
+        config::StorageConfig::WalMemTable(wal) => {
+            let store = WalMemTable::open(&wal.path, wal.fsync)?;
+            export::export(&store, writer, format)
+        }
+        config::StorageConfig::Tiered(tiered) => {
+            export::export(&SledDB::open(&tiered.path)?, writer, format)
+        }
+        config::StorageConfig::Bitcask(bitcask) => {
+            export::export(&Bitcask::open(bitcask)?, writer, format)
+        }
+        #[cfg(feature = "lmdb")]
+        config::StorageConfig::Lmdb { path, map_size } => {
+            let store = Lmdb::open_with_config(path, map_size.as_deref())?;
+            export::export(&store, writer, format)
+        }
+        #[cfg(feature = "redb")]
+        config::StorageConfig::Redb(path) => export::export(&Redb::open(path)?, writer, format),
+        #[cfg(feature = "sqlite")]
+        config::StorageConfig::Sqlite(path) => export::export(&Sqlite::open(path)?, writer, format),
+    }
+}
+
+/// 把 export_with_config 导出的数据导入到配置的存储，返回导入的 key 数量；需要先停止服务器
+pub fn import_with_config(
+    config: &ServerConfig,
+    reader: impl io::Read,
+    format: export::Format,
+) -> Result<usize, KvError> {
+    match &config.storage {
+        config::StorageConfig::MemTable => Err(memtable_is_not_persistent()),
+        config::StorageConfig::SledDB(db) => {
+            let store = SledDB::open(db)?;
+            let count = export::import(&store, reader, format)?;
+            store.flush()?;
+            Ok(count)
+        }
+        config::StorageConfig::WalMemTable(wal) => {
+            let store = WalMemTable::open(&wal.path, wal.fsync)?;
+            let count = export::import(&store, reader, format)?;
+            store.flush()?;
+            Ok(count)
+        }
+        config::StorageConfig::Tiered(tiered) => {
+            let store = SledDB::open(&tiered.path)?;
+            let count = export::import(&store, reader, format)?;
+            store.flush()?;
+            Ok(count)
+        }
+        config::StorageConfig::Bitcask(bitcask) => {
+            let store = Bitcask::open(bitcask)?;
+            let count = export::import(&store, reader, format)?;
+            store.flush()?;
+            Ok(count)
+        }
+        #[cfg(feature = "lmdb")]
+        config::StorageConfig::Lmdb { path, map_size } => {
+            let store = Lmdb::open_with_config(path, map_size.as_deref())?;
+            let count = export::import(&store, reader, format)?;
+            store.flush()?;
+            Ok(count)
+        }
+        #[cfg(feature = "redb")]
+        config::StorageConfig::Redb(path) => {
+            let store = Redb::open(path)?;
+            let count = export::import(&store, reader, format)?;
+            store.flush()?;
+            Ok(count)
+        }
+        #[cfg(feature = "sqlite")]
+        config::StorageConfig::Sqlite(path) => {
+            let store = Sqlite::open(path)?;
+            let count = export::import(&store, reader, format)?;
+            store.flush()?;
+            Ok(count)
+        }
+    }
+}
+
+/// 把 from 配置的存储中所有的数据复制到 to 配置的存储，verify 为 true 时复制完成之后逐个 key 校验。
+/// 两个存储都需要先停止服务器
+pub fn migrate_with_config(
+    from: &ServerConfig,
+    to: &ServerConfig,
+    verify: bool,
+    progress: impl FnMut(&MigrateStats),
+) -> Result<MigrateStats, KvError> {
+    if from.storage == to.storage {
+        return Err(KvError::InvalidCommand(
+            "source and target storage are the same".into(),
+        ));
+    }
+    match &from.storage {
+        config::StorageConfig::MemTable => Err(memtable_is_not_persistent()),
+        config::StorageConfig::SledDB(db) => migrate_into(&SledDB::open(db)?, to, verify, progress),
+        config::StorageConfig::WalMemTable(wal) => {
+            let store = WalMemTable::open(&wal.path, wal.fsync)?;
+            migrate_into(&store, to, verify, progress)
+        }
+        config::StorageConfig::Tiered(tiered) => {
+            migrate_into(&SledDB::open(&tiered.path)?, to, verify, progress)
+        }
+        config::StorageConfig::Bitcask(bitcask) => {
+            migrate_into(&Bitcask::open(bitcask)?, to, verify, progress)
+        }
+        #[cfg(feature = "lmdb")]
+        config::StorageConfig::Lmdb { path, map_size } => {
+            let store = Lmdb::open_with_config(path, map_size.as_deref())?;
+            migrate_into(&store, to, verify, progress)
+        }
+        #[cfg(feature = "redb")]
+        config::StorageConfig::Redb(path) => migrate_into(&Redb::open(path)?, to, verify, progress),
+        #[cfg(feature = "sqlite")]
+        config::StorageConfig::Sqlite(path) => {
+            migrate_into(&Sqlite::open(path)?, to, verify, progress)
+        }
+    }
+}
+
+fn migrate_into(
+    from: &impl Storage,
+    to: &ServerConfig,
+    verify: bool,
+    progress: impl FnMut(&MigrateStats),
+) -> Result<MigrateStats, KvError> {
+    match &to.storage {
+        config::StorageConfig::MemTable => Err(memtable_is_not_persistent()),
+        config::StorageConfig::SledDB(db) => {
+            migrate_and_verify(from, &SledDB::open(db)?, verify, progress)
+        }
+        config::StorageConfig::WalMemTable(wal) => {
+            let store = WalMemTable::open(&wal.path, wal.fsync)?;
+            migrate_and_verify(from, &store, verify, progress)
+        }
+        config::StorageConfig::Tiered(tiered) => {
+            migrate_and_verify(from, &SledDB::open(&tiered.path)?, verify, progress)
+        }
+        config::StorageConfig::Bitcask(bitcask) => {
+            migrate_and_verify(from, &Bitcask::open(bitcask)?, verify, progress)
+        }
+        #[cfg(feature = "lmdb")]
+        config::StorageConfig::Lmdb { path, map_size } => {
+            let store = Lmdb::open_with_config(path, map_size.as_deref())?;
+            migrate_and_verify(from, &store, verify, progress)
+        }
+        #[cfg(feature = "redb")]
+        config::StorageConfig::Redb(path) => {
+            migrate_and_verify(from, &Redb::open(path)?, verify, progress)
+        }
+        #[cfg(feature = "sqlite")]
+        config::StorageConfig::Sqlite(path) => {
+            migrate_and_verify(from, &Sqlite::open(path)?, verify, progress)
+        }
+    }
+}
+
+fn migrate_and_verify(
+    from: &impl Storage,
+    to: &impl Storage,
+    verify: bool,
+    progress: impl FnMut(&MigrateStats),
+) -> Result<MigrateStats, KvError> {
+    let stats = migrate::migrate(from, to, progress)?;
+    if verify {
+        migrate::verify(from, to)?;
+    }
+    Ok(stats)
+}
+
+fn memtable_is_not_persistent() -> KvError {
+    KvError::InvalidCommand("MemTable doesn't keep any data after the server stops".into())
+}
+
+/// 不启动服务器，检查配置中的证书、内存和日志级别；返回不影响启动、但会被忽略的配置
+pub fn check_config(config: &ServerConfig) -> Result<Vec<String>, KvError> {
+    tls_acceptor(config)?;
+    MemTable::with_config(&config.memtable)?;
+    if let Some(max_memory) = &config.memtable.max_memory {
+        config::parse_size(max_memory)?;
+    }
+    if let Some(level) = &config.log_level {
+        telemetry::parse_log_level(level)?;
+    }
+    if let Some(cluster) = &config.cluster {
+        check_cluster(config, cluster)?;
+    }
+    if let Some(encryption) = &config.encryption {
+        Keyring::from_config(encryption)?;
+    }
+    if let Some(tiering) = &config.tiering {
+        if let Some(size) = &tiering.min_size {
+            config::parse_size(size)?;
+        }
+        S3Store::new(&tiering.s3)?;
+    }
+    if let Some(compaction) = &config.compaction {
+        CompactionSchedule::from_config(compaction)?;
+    }
+    if let Some(proxy) = &config.proxy {
+        if config.cluster.is_some() || config.replication.is_some() {
+            return Err(KvError::InvalidCommand(
+                "proxy can't be configured with replication or cluster".into(),
+            ));
+        }
+        Proxy::new(proxy)?;
+    }
+    Plugins::load(&config.plugins)?;
+    Tenants::try_from(config.security.tenants.as_slice())?;
+    let mut warnings = Vec::new();
+    match &config.storage {
+        config::StorageConfig::Tiered(tiered) => {
+            if let Some(size) = &tiered.cache_size {
+                config::parse_size(size)?;
+            }
+        }
+        config::StorageConfig::Bitcask(bitcask) => {
+            storage::bitcask::parse_config(bitcask)?;
+        }
+        #[cfg(feature = "lmdb")]
+        config::StorageConfig::Lmdb {
+            map_size: Some(size),
+            ..
+        } => {
+            config::parse_size(size)?;
+        }
+        _ => {}
+    }
+    let is_on_disk = !matches!(
+        config.storage,
+        config::StorageConfig::MemTable | config::StorageConfig::WalMemTable(_)
+    );
+    if is_on_disk && config.memtable.max_memory.is_some() {
+        warnings.push("max_memory is ignored, storage doesn't support memory limit".into());
+    }
+    if cfg!(not(feature = "grpc")) && config.grpc.is_some() {
+        warnings.push("gRPC listener is ignored, rebuild with the grpc feature".into());
+    }
+    if cfg!(not(feature = "quic")) && config.quic.is_some() {
+        warnings.push("QUIC listener is ignored, rebuild with the quic feature".into());
+    }
+    if cfg!(not(feature = "kafka")) && !config.ingest.is_empty() {
+        warnings.push("ingest is ignored, rebuild with the kafka feature".into());
+    } else if config.replication.is_some() && !config.ingest.is_empty() {
+        warnings.push("ingest is ignored on replica".into());
+    }
+    Ok(warnings)
+}
+
+fn check_cluster(config: &ServerConfig, cluster: &config::ClusterConfig) -> Result<(), KvError> {
+    if config.replication.is_some() {
+        return Err(KvError::InvalidCommand(
+            "replication and cluster can't be configured together".into(),
+        ));
+    }
+    let mut ids = HashSet::from([cluster.id]);
+    if cluster.id == 0 || cluster.peers.iter().any(|p| !ids.insert(p.id) || p.id == 0) {
+        return Err(KvError::InvalidCommand(
+            "cluster node ids must be unique and non-zero".into(),
+        ));
+    }
     Ok(())
 }
 
-async fn start_server<Store: Storage>(store: Store, config: ServerConfig) -> Result<()> {
-    let service = ServiceBuilder::new(store).finish();
-    let tls = &config.tls;
-    let tls = TlsServerAcceptor::new(&tls.cert, &tls.key, tls.ca.as_deref())?;
+/// 没有配置 seeds 时，集群模式下从 peers 开始发现节点，replica 从 primary 开始
+fn default_seeds(config: &ServerConfig) -> Vec<ClientConfig> {
+    match (&config.cluster, &config.replication) {
+        (Some(cluster), _) => cluster.peers.iter().map(|p| p.client.clone()).collect(),
+        (None, Some(replication)) => vec![replication.primary.clone()],
+        (None, None) => vec![],
+    }
+}
+
+/// 运行中的服务器，drop 之后服务器继续运行，调用 shutdown 才会退出
+pub struct ServerHandle {
+    shutdown: Shutdown,
+    grace_period: Duration,
+    flush: Box<dyn Fn() -> Result<(), KvError> + Send + Sync>,
+    reloader: Reloader,
+}
+
+impl ServerHandle {
+    /// 重新读取配置文件，和 CONFIG RELOAD 命令的效果一样
+    pub fn reload(&self) -> Result<(), KvError> {
+        (self.reloader)()
+    }
+
+    /// 停止接受新的连接和请求，等待正在执行的请求完成（最多 grace_period），然后把数据刷到磁盘
+    pub async fn shutdown(self) -> Result<()> {
+        info!("Shutting down, waiting for in-flight commands");
+        self.shutdown.trigger();
+        if time::timeout(self.grace_period, self.shutdown.drained())
+            .await
+            .is_err()
+        {
+            warn!(
+                "{} commands are still running after {:?}",
+                self.shutdown.in_flight(),
+                self.grace_period
+            );
+        }
+        (self.flush)()?;
+        info!("Server is shut down");
+        Ok(())
+    }
+}
+
+async fn start_server<Store: Storage>(store: Store, config: ServerConfig) -> Result<ServerHandle> {
+    if let Some(path) = &config.restore_from {
+        let count = snapshot::restore(&store, path)?;
+        info!("Restored {} records from snapshot {}", count, path);
+    }
+    match store.memory_limit() {
+        Some(memory) => memory.configure(&config.memtable)?,
+        None if config.memtable.max_memory.is_some() => {
+            warn!("max_memory is ignored, storage doesn't support memory limit")
+        }
+        None => {}
+    }
+    match store.quotas() {
+        Some(quotas) => quotas.configure(&config.quotas)?,
+        None if !config.quotas.is_empty() => {
+            warn!("quotas are ignored, storage doesn't support quotas")
+        }
+        None => {}
+    }
+    let limiter = ConnectionLimiter::from_config(&config);
+    let audit = match &config.audit {
+        Some(audit) => AuditLog::open(audit)?,
+        None => AuditLog::default(),
+    };
+    let service = ServiceBuilder::new(store)
+        .auth(config.security.auth.clone())
+        .acl(config.security.acl.clone())
+        .tenants(config.security.tenants.as_slice().try_into()?)
+        .read_only(config.read_only)
+        .replica(config.replication.is_some())
+        .notifications(config.notifications)
+        .mvcc(config.mvcc)
+        .limits(config.limits)
+        .compression(config.compression.clone())
+        .connections(limiter.clone())
+        .in_flight(InFlightLimiter::from_config(&config))
+        .slowlog(config.slowlog)
+        .idempotency(config.idempotency)
+        .audit(audit)
+        .proxy(config.proxy.as_ref().map(Proxy::new).transpose()?)
+        .plugins(Plugins::load(&config.plugins)?)
+        .cdc(config.cdc.as_ref().map(Cdc::open).transpose()?)
+        .finish();
+    service.spawn_purge_task(PURGE_INTERVAL);
+    service.spawn_cdc_task();
+    // replica 是只读的，导入的数据由 primary 复制过来
+    if config.replication.is_none() {
+        for ingest in &config.ingest {
+            service.spawn_ingest_task(ingest.clone());
+        }
+    }
+    if let Some(compaction) = &config.compaction {
+        service.spawn_compaction_task(CompactionSchedule::from_config(compaction)?);
+    }
+    match (&config.replication, &config.cluster) {
+        (Some(replication), _) => {
+            service.spawn_replication_task(replication.primary.clone());
+        }
+        // 集群模式下不支持 lease
+        (None, Some(cluster)) => {
+            let mut cluster = cluster.clone();
+            cluster
+                .advertise_addr
+                .get_or_insert_with(|| config.general.addr.clone());
+            service.start_cluster(&cluster)?
+        }
+        // replica 上关联到 lease 的 key 由 primary 删除之后同步过来
+        (None, None) => {
+            service.spawn_lease_task(lease::LEASE_INTERVAL);
+        }
+    }
+    if let Some(membership) = &config.membership {
+        let mut membership = membership.clone();
+        if membership.seeds.is_empty() {
+            membership.seeds = default_seeds(&config);
+        }
+        let addr = membership
+            .advertise_addr
+            .as_ref()
+            .unwrap_or(&config.general.addr);
+        service.start_membership(&membership, addr);
+    }
+    if let Some(resp) = &config.resp {
+        let listener = TcpListener::bind(&resp.addr).await?;
+        info!("Start RESP listening on {}", resp.addr);
+        let (service, limiter) = (service.clone(), limiter.clone());
+        tokio::spawn(serve_resp(listener, service, limiter, config.network));
+    }
+    if let Some(http) = &config.http {
+        let listener = TcpListener::bind(&http.addr).await?;
+        info!("Start HTTP listening on {}", http.addr);
+        let (service, limiter) = (service.clone(), limiter.clone());
+        tokio::spawn(serve_http(listener, service, limiter, config.network));
+    }
+    if let Some(websocket) = &config.websocket {
+        let listener = TcpListener::bind(&websocket.addr).await?;
+        info!("Start WebSocket listening on {}", websocket.addr);
+        let (service, limiter) = (service.clone(), limiter.clone());
+        tokio::spawn(serve_websocket(listener, service, limiter, config.network));
+    }
+    if let Some(grpc) = &config.grpc {
+        serve_grpc(&grpc.addr, service.clone(), limiter.clone()).await?;
+    }
+    if let Some(quic) = &config.quic {
+        serve_quic(&quic.addr, &config, service.clone(), limiter.clone())?;
+    }
+    let tls = tls_acceptor(&config)?;
     let addr = &config.general.addr;
-    let listener = TcpListener::bind(addr).await?;
+    let listener = Listener::bind(addr).await?;
     info!("Start listening on{}", addr);
+    tokio::spawn(serve_yamux(
+        listener,
+        tls.clone(),
+        service.clone(),
+        limiter,
+        config.network,
+    ));
+    tokio::spawn(watch_tls(
+        config.clone(),
+        tls.clone(),
+        service.shutdown().clone(),
+    ));
+    if let Some(level) = &config.log_level {
+        telemetry::set_log_level(level)?;
+    }
+    if let Some(size) = config.max_frame_size {
+        frame::set_max_frame_size(size);
+    }
+
+    // 只持有 weak 引用，避免 service 和 reloader 互相引用
+    let inner = Arc::downgrade(&*service);
+    let source = config.source.clone();
+    let reloader: Reloader = Arc::new(move || match (&source, inner.upgrade()) {
+        (Some(path), Some(inner)) => reload_config(path, &tls, &inner),
+        (None, _) => Err(KvError::InvalidCommand(
+            "config is not loaded from a file".into(),
+        )),
+        (_, None) => Err(KvError::Internal("server is stopped".into())),
+    });
+    service.set_reloader(reloader.clone());
+
+    let grace_period = config.grace_period.unwrap_or(DEFAULT_GRACE_PERIOD);
+    Ok(ServerHandle {
+        shutdown: service.shutdown().clone(),
+        grace_period: Duration::from_secs(grace_period),
+        flush: Box::new(move || service.store.flush()),
+        reloader,
+    })
+}
+
+/// 重新读取配置文件，应用日志级别、TLS 证书、认证和权限、存储配额；其它配置需要重启才能生效。
+/// 先检查完所有的配置再应用，配置有错误时不会只应用一部分
+fn reload_config<Store: Storage>(
+    path: &str,
+    tls: &TlsServerAcceptor,
+    service: &ServiceBuilder<Store>,
+) -> Result<(), KvError> {
+    let config = ServerConfig::load(path)?;
+    let new_tls = tls_acceptor(&config)?;
+    let limits = parse_quotas(&config.quotas)?;
+    Tenants::try_from(config.security.tenants.as_slice())?;
+    if let Some(level) = &config.log_level {
+        telemetry::set_log_level(level)?;
+    }
+    tls.reload(&new_tls);
+    service.set_security(&config.security)?;
+    if let Some(quotas) = service.store.quotas() {
+        quotas.set_limits(limits);
+    }
+    if !service.replica {
+        service.set_read_only(config.read_only)?;
+    }
+    info!("Reloaded config from {}", path);
+    Ok(())
+}
+
+/// 根据 [tls] 和 [security] 的配置创建 TLS acceptor
+fn tls_acceptor(config: &ServerConfig) -> Result<TlsServerAcceptor, KvError> {
+    let (cert, key) = config.tls.identity()?;
+    let client_auth = config.client_auth()?;
+    match &client_auth {
+        Some((ca, required)) => {
+            TlsServerAcceptor::with_client_auth(&cert, &key, Some(ca), *required)
+        }
+        None => TlsServerAcceptor::new(&cert, &key, None),
+    }
+}
+
+/// 定期检查证书文件的修改时间，变化之后重新加载证书；已经建立的连接不受影响。
+/// 配置是从文件加载的时候重新读取配置文件，以免覆盖 CONFIG RELOAD 修改过的 client_ca。
+/// 证书和私钥可能不是同时写入的，加载失败时保留原来的证书，下次检查时再重试
+async fn watch_tls(config: ServerConfig, tls: TlsServerAcceptor, shutdown: Shutdown) {
+    let Some(interval) = config.tls.watch_interval else {
+        return;
+    };
+    let files: Vec<String> = config.tls.files().into_iter().map(Into::into).collect();
+    if files.is_empty() {
+        warn!("tls.watch_interval is ignored, cert and key are not files");
+        return;
+    }
+    let modified = |files: &[String]| -> Vec<Option<SystemTime>> {
+        files
+            .iter()
+            .map(|f| std::fs::metadata(f).and_then(|m| m.modified()).ok())
+            .collect()
+    };
+    let mut last = modified(&files);
+    let mut ticker = time::interval(Duration::from_secs(interval.max(1)));
+    ticker.set_missed_tick_behavior(time::MissedTickBehavior::Delay);
+    loop {
+        tokio::select! {
+            _ = ticker.tick() => {}
+            _ = shutdown.triggered() => break,
+        }
+        let current = modified(&files);
+        if current == last {
+            continue;
+        }
+        let res = match &config.source {
+            Some(path) => ServerConfig::load(path).and_then(|c| tls_acceptor(&c)),
+            None => tls_acceptor(&config),
+        };
+        match res {
+            Ok(new_tls) => {
+                tls.reload(&new_tls);
+                last = current;
+                info!("Reloaded TLS certificate from {:?}", files);
+            }
+            Err(e) => warn!("Failed to reload TLS certificate: {:?}", e),
+        }
+    }
+}
+
+/// 接受一个连接；开始退出之后返回 None
+async fn accept<T, F>(accept: impl Fn() -> F, shutdown: &Shutdown) -> Option<T>
+where
+    F: Future<Output = io::Result<T>>,
+{
+    loop {
+        tokio::select! {
+            res = accept() => match res {
+                Ok(v) => return Some(v),
+                Err(e) => warn!("Failed to accept connection: {:?}", e),
+            },
+            _ = shutdown.triggered() => return None,
+        }
+    }
+}
+
+/// 按配置设置 TCP_NODELAY 和 keepalive
+fn configure_tcp(stream: &TcpStream, network: &NetworkConfig) -> io::Result<()> {
+    if network.tcp_nodelay {
+        stream.set_nodelay(true)?;
+    }
+    if let Some(secs) = network.tcp_keepalive {
+        let keepalive = TcpKeepalive::new().with_time(Duration::from_secs(secs));
+        SockRef::from(stream).set_tcp_keepalive(&keepalive)?;
+    }
+    Ok(())
+}
 
+/// 接受 prost 客户端的连接
+async fn serve_yamux<Store: Storage>(
+    listener: Listener,
+    tls: TlsServerAcceptor,
+    service: Service<Store>,
+    limiter: ConnectionLimiter,
+    network: NetworkConfig,
+) {
     // loop {
     //     let (tcp_stream, addr) = listener.accept().await?;
     //     info!("Clietn {:?} connected", addr);
@@ -74,22 +852,320 @@ async fn start_server<Store: Storage>(store: Store, config: ServerConfig) -> Res
     //         }
     //     });
     // }
-    loop {
-        let (tcp_stream, addr) = listener.accept().await?;
+    let shutdown = service.shutdown().clone();
+    while let Some((incoming, addr)) = accept(|| listener.accept(), &shutdown).await {
         info!("Clietn {:?} connected", addr);
         let tls = tls.clone();
-        // 使用TLS协议包装TCP
         let service = service.clone();
+        let conn = limiter.try_acquire(addr.ip());
         tokio::spawn(async move {
-            let tls_stream = tls.accept(tcp_stream).await.unwrap();
-            YamuxCtrl::new_server(tls_stream, None, move |stream| {
+            // 客户端证书的 CN/SAN 作为用户名，不需要再 AUTH
+            let (stream, identity): (BoxedTransport, _) = match incoming {
+                // 使用TLS协议包装TCP
+                Incoming::Tcp(stream) => {
+                    if let Err(e) = configure_tcp(&stream, &network) {
+                        warn!("Failed to configure TCP for {:?}: {:?}", addr, e);
+                    }
+                    match tls.accept(stream).await {
+                        Ok(stream) => {
+                            let identity = tls::client_identity(&stream);
+                            (Box::new(stream), identity)
+                        }
+                        Err(e) => {
+                            warn!("TLS handshake with {:?} failed: {:?}", addr, e);
+                            return;
+                        }
+                    }
+                }
+                // 同一台机器上的 unix socket 不需要 TLS
+                #[cfg(unix)]
+                Incoming::Unix(stream) => (Box::new(stream), None),
+            };
+            let conn = match conn {
+                Ok(conn) => conn,
+                Err(e) => {
+                    warn!("Reject client {:?}: {}", addr, e);
+                    reject_yamux(stream, e).await;
+                    return;
+                }
+            };
+            let session = identity.map(Session::authenticated).unwrap_or_default();
+            let session = Arc::new(session.with_peer(addr));
+            let _client = service.connect(&session);
+            // 连接结束时 closure 被 drop，closed 随之返回
+            let (done, closed) = oneshot::channel::<()>();
+            let watcher = session.clone();
+            let mut ctrl = YamuxCtrl::new_server(stream, None, move |stream| {
+                // 连接上所有的 stream 都结束之后才释放连接数
+                let _conn = &conn;
+                let _done = &done;
                 let service = service.clone();
+                let session = session.clone();
                 async move {
-                    let stream = ProstServerStream::new(stream.compat(), service.clone());
+                    let stream = ProstServerStream::with_session(stream.compat(), service, session);
                     stream.process().await.unwrap();
                     Ok(())
                 }
             });
+            let idle_timeout = network.idle_timeout.map(Duration::from_secs);
+            tokio::select! {
+                _ = watcher.idle(idle_timeout.unwrap_or_default()), if idle_timeout.is_some() => {
+                    info!("Close idle client {:?}", addr);
+                    let _ = ctrl.close().await;
+                }
+                _ = watcher.killed() => {
+                    info!("Client {:?} is killed", addr);
+                    let _ = ctrl.close().await;
+                }
+                _ = closed => {}
+            }
+        });
+    }
+}
+
+/// 超过连接数限制时，在客户端打开的第一个 stream 上返回错误，然后关闭连接；
+/// 客户端一直不发送命令时，最多等待 REJECT_TIMEOUT
+async fn reject_yamux<S>(stream: S, e: KvError)
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let res = CommandResponse::from(e);
+    let replied = Arc::new(Notify::new());
+    let notify = replied.clone();
+    let mut ctrl = YamuxCtrl::new_server(stream, None, move |stream| {
+        let res = res.clone();
+        let notify = notify.clone();
+        async move {
+            let mut stream =
+                ProstStream::<_, CommandRequest, CommandResponse>::new(stream.compat());
+            if let Some(Ok(_)) = stream.next().await {
+                let _ = stream.send(&res).await;
+            }
+            let _ = stream.close().await;
+            notify.notify_one();
+            Ok(())
+        }
+    });
+    let _ = time::timeout(REJECT_TIMEOUT, replied.notified()).await;
+    let _ = ctrl.close().await;
+}
+
+/// 接受 redis 客户端的连接
+async fn serve_resp<Store: Storage>(
+    listener: TcpListener,
+    service: Service<Store>,
+    limiter: ConnectionLimiter,
+    network: NetworkConfig,
+) {
+    let shutdown = service.shutdown().clone();
+    let idle_timeout = network.idle_timeout.map(Duration::from_secs);
+    while let Some((stream, addr)) = accept(|| listener.accept(), &shutdown).await {
+        info!("Redis client {:?} connected", addr);
+        if let Err(e) = configure_tcp(&stream, &network) {
+            warn!("Failed to configure TCP for {:?}: {:?}", addr, e);
+        }
+        let conn = match limiter.try_acquire(Some(addr.ip())) {
+            Ok(conn) => conn,
+            Err(e) => {
+                warn!("Reject redis client {:?}: {}", addr, e);
+                tokio::spawn(reject_resp(stream, e));
+                continue;
+            }
+        };
+        let server = RespServerStream::new(stream, service.clone())
+            .with_peer(addr)
+            .with_idle_timeout(idle_timeout);
+        tokio::spawn(async move {
+            let _conn = conn;
+            if let Err(e) = server.process().await {
+                warn!("Redis client {:?} error: {:?}", addr, e);
+            }
+        });
+    }
+}
+
+/// 接受 HTTP 客户端的连接
+async fn serve_http<Store: Storage>(
+    listener: TcpListener,
+    service: Service<Store>,
+    limiter: ConnectionLimiter,
+    network: NetworkConfig,
+) {
+    let shutdown = service.shutdown().clone();
+    let gateway = HttpGateway::new(service);
+    while let Some((stream, addr)) = accept(|| listener.accept(), &shutdown).await {
+        if let Err(e) = configure_tcp(&stream, &network) {
+            warn!("Failed to configure TCP for {:?}: {:?}", addr, e);
+        }
+        let conn = match limiter.try_acquire(Some(addr.ip())) {
+            Ok(conn) => conn,
+            Err(e) => {
+                warn!("Reject HTTP client {:?}: {}", addr, e);
+                tokio::spawn(time::timeout(
+                    REJECT_TIMEOUT,
+                    network::http::reject(stream, e),
+                ));
+                continue;
+            }
+        };
+        let gateway = gateway.clone();
+        tokio::spawn(async move {
+            let _conn = conn;
+            if let Err(e) = gateway.serve(stream).await {
+                warn!("HTTP client {:?} error: {:?}", addr, e);
+            }
+        });
+    }
+}
+
+/// 接受浏览器的 WebSocket 连接
+async fn serve_websocket<Store: Storage>(
+    listener: TcpListener,
+    service: Service<Store>,
+    limiter: ConnectionLimiter,
+    network: NetworkConfig,
+) {
+    let shutdown = service.shutdown().clone();
+    let idle_timeout = network.idle_timeout.map(Duration::from_secs);
+    let gateway = WebSocketGateway::new(service).with_idle_timeout(idle_timeout);
+    while let Some((stream, addr)) = accept(|| listener.accept(), &shutdown).await {
+        info!("WebSocket client {:?} connected", addr);
+        if let Err(e) = configure_tcp(&stream, &network) {
+            warn!("Failed to configure TCP for {:?}: {:?}", addr, e);
+        }
+        let conn = match limiter.try_acquire(Some(addr.ip())) {
+            Ok(conn) => conn,
+            Err(e) => {
+                warn!("Reject WebSocket client {:?}: {}", addr, e);
+                tokio::spawn(websocket::reject(stream, e));
+                continue;
+            }
+        };
+        let gateway = gateway.clone();
+        tokio::spawn(async move {
+            let _conn = conn;
+            if let Err(e) = gateway.serve(stream).await {
+                warn!("WebSocket client {:?} error: {:?}", addr, e);
+            }
         });
     }
 }
+
+#[cfg(feature = "grpc")]
+async fn serve_grpc<Store: Storage>(
+    addr: &str,
+    service: Service<Store>,
+    limiter: ConnectionLimiter,
+) -> Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    info!("Start gRPC listening on {}", addr);
+    let shutdown = service.shutdown().clone();
+    let server = tonic::transport::Server::builder()
+        .add_service(grpc::GrpcService::new(service).into_server())
+        .serve_with_incoming_shutdown(grpc::incoming(listener, limiter), async move {
+            shutdown.triggered().await
+        });
+    tokio::spawn(async move {
+        if let Err(e) = server.await {
+            warn!("gRPC server error: {:?}", e);
+        }
+    });
+    Ok(())
+}
+
+#[cfg(not(feature = "grpc"))]
+async fn serve_grpc<Store: Storage>(
+    addr: &str,
+    _service: Service<Store>,
+    _limiter: ConnectionLimiter,
+) -> Result<()> {
+    warn!(
+        "gRPC listener {} is ignored, rebuild with the grpc feature",
+        addr
+    );
+    Ok(())
+}
+
+/// 接受 QUIC 客户端的连接，每个双向 stream 相当于 yamux 的一个 stream
+#[cfg(feature = "quic")]
+fn serve_quic<Store: Storage>(
+    addr: &str,
+    config: &ServerConfig,
+    service: Service<Store>,
+    limiter: ConnectionLimiter,
+) -> Result<()> {
+    let endpoint = quic::server_endpoint(addr, &config.tls, config.client_auth()?)?;
+    info!("Start QUIC listening on {}", addr);
+    let shutdown = service.shutdown().clone();
+    tokio::spawn(async move {
+        loop {
+            let incoming = tokio::select! {
+                incoming = endpoint.accept() => incoming,
+                _ = shutdown.triggered() => None,
+            };
+            let Some(incoming) = incoming else {
+                break;
+            };
+            let addr = incoming.remote_address();
+            info!("QUIC client {:?} connected", addr);
+            let conn = limiter.try_acquire(Some(addr.ip()));
+            let service = service.clone();
+            tokio::spawn(async move {
+                let connection = match incoming.await {
+                    Ok(connection) => connection,
+                    Err(e) => {
+                        warn!("QUIC handshake with {:?} failed: {:?}", addr, e);
+                        return;
+                    }
+                };
+                let _conn = match conn {
+                    Ok(conn) => conn,
+                    Err(e) => {
+                        warn!("Reject QUIC client {:?}: {}", addr, e);
+                        quic::reject(connection, e).await;
+                        return;
+                    }
+                };
+                let identity = quic::peer_identity(&connection);
+                let session = identity.map(Session::authenticated).unwrap_or_default();
+                let session = Arc::new(session.with_peer(addr));
+                let _client = service.connect(&session);
+                loop {
+                    let (send, recv) = tokio::select! {
+                        stream = connection.accept_bi() => match stream {
+                            Ok(stream) => stream,
+                            Err(_) => break,
+                        },
+                        _ = session.killed() => {
+                            connection.close(0u32.into(), b"killed");
+                            break;
+                        }
+                    };
+                    let stream = quic::QuicStream::new(send, recv);
+                    let stream =
+                        ProstServerStream::with_session(stream, service.clone(), session.clone());
+                    tokio::spawn(async move {
+                        if let Err(e) = stream.process().await {
+                            warn!("QUIC client {:?} error: {:?}", addr, e);
+                        }
+                    });
+                }
+            });
+        }
+    });
+    Ok(())
+}
+
+#[cfg(not(feature = "quic"))]
+fn serve_quic<Store: Storage>(
+    addr: &str,
+    _config: &ServerConfig,
+    _service: Service<Store>,
+    _limiter: ConnectionLimiter,
+) -> Result<()> {
+    warn!(
+        "QUIC listener {} is ignored, rebuild with the quic feature",
+        addr
+    );
+    Ok(())
+}
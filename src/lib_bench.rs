@@ -0,0 +1,246 @@
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use bytes::Bytes;
+use clap::{Parser, ValueEnum};
+use kv_db::{
+    config::ClientConfig, pb::abi::CommandRequest, start_client_with_config, ProstClientStream,
+};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use tokio::task::JoinSet;
+
+/// 用不同的负载压测服务器，输出吞吐量和延迟的分位数
+#[derive(Debug, Parser)]
+#[command(name = "kv-bench")]
+struct Args {
+    /// 客户端配置文件，没有指定时使用 fixtures/client.conf
+    #[arg(short, long)]
+    config: Option<String>,
+    /// 覆盖配置文件中的服务器地址
+    #[arg(short, long)]
+    addr: Option<String>,
+    /// 总请求数
+    #[arg(short = 'n', long, default_value_t = 100_000)]
+    requests: usize,
+    /// 并发的连接数
+    #[arg(short = 'C', long, default_value_t = 50)]
+    connections: usize,
+    /// 读请求（HGET）的比例，其余是写请求（HSET）
+    #[arg(short, long, default_value_t = 0.8)]
+    read_ratio: f64,
+    /// value 的字节数
+    #[arg(short = 'd', long, default_value_t = 100)]
+    value_size: usize,
+    /// key 的数量
+    #[arg(short, long, default_value_t = 10_000)]
+    keys: usize,
+    /// key 的分布
+    #[arg(long, value_enum, default_value_t = Distribution::Uniform)]
+    distribution: Distribution,
+    /// 压测使用的 table
+    #[arg(short, long, default_value = "bench")]
+    table: String,
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum Distribution {
+    /// 每个 key 的概率相同
+    Uniform,
+    /// 少数热点 key 占了大部分请求
+    Zipf,
+    /// 按顺序依次访问
+    Sequential,
+}
+
+/// 按 Distribution 生成 key 的编号
+struct KeyGenerator {
+    distribution: Distribution,
+    keys: usize,
+    /// zipf 分布的累积概率
+    cdf: Arc<Vec<f64>>,
+    next: usize,
+}
+
+/// 一个连接的统计
+#[derive(Default)]
+struct Report {
+    latencies: Vec<Duration>,
+    errors: usize,
+}
+
+/// zipf 分布的参数，越大热点越集中
+const ZIPF_EXPONENT: f64 = 0.99;
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args = Args::parse();
+    if !(0.0..=1.0).contains(&args.read_ratio) {
+        anyhow::bail!("read ratio must be between 0 and 1");
+    }
+    if args.connections == 0 || args.keys == 0 {
+        anyhow::bail!("connections and keys must be greater than 0");
+    }
+    let mut config = match &args.config {
+        Some(path) => ClientConfig::load(path)?,
+        None => toml::from_str(include_str!("../fixtures/client.conf"))?,
+    };
+    if let Some(addr) = &args.addr {
+        config.general.addr = addr.clone();
+    }
+    let value: Bytes = vec![b'x'; args.value_size].into();
+
+    // 有读请求时先写入所有的 key，这样读请求都能命中
+    if args.read_ratio > 0.0 {
+        let start = Instant::now();
+        let mut ctrl = start_client_with_config(config.clone()).await?;
+        let mut client = ctrl.open_stream().await?;
+        for i in 0..args.keys {
+            let cmd = CommandRequest::new_hset(&args.table, key(i), value.clone());
+            client.execute(&cmd).await?;
+        }
+        println!("Preloaded {} keys in {:.2?}", args.keys, start.elapsed());
+    }
+
+    let cdf = Arc::new(match args.distribution {
+        Distribution::Zipf => zipf_cdf(args.keys),
+        _ => Vec::new(),
+    });
+    let mut tasks = JoinSet::new();
+    let start = Instant::now();
+    for id in 0..args.connections {
+        // 请求数不能整除时，前面的连接多发一个
+        let requests =
+            args.requests / args.connections + usize::from(id < args.requests % args.connections);
+        let mut ctrl = start_client_with_config(config.clone()).await?;
+        let client = ctrl.open_stream().await?;
+        let keys = KeyGenerator {
+            distribution: args.distribution,
+            keys: args.keys,
+            cdf: cdf.clone(),
+            next: id * args.keys / args.connections,
+        };
+        let (table, value, read_ratio) = (args.table.clone(), value.clone(), args.read_ratio);
+        tasks.spawn(async move {
+            // 连接在压测结束之前不能关闭
+            let _ctrl = ctrl;
+            let rng = StdRng::seed_from_u64(id as u64);
+            run(client, requests, keys, rng, table, value, read_ratio).await
+        });
+    }
+    let mut report = Report::default();
+    while let Some(res) = tasks.join_next().await {
+        let r = res??;
+        report.latencies.extend(r.latencies);
+        report.errors += r.errors;
+    }
+    let elapsed = start.elapsed();
+    print_report(&args, report, elapsed);
+    Ok(())
+}
+
+async fn run<S>(
+    mut client: ProstClientStream<S>,
+    requests: usize,
+    mut keys: KeyGenerator,
+    mut rng: StdRng,
+    table: String,
+    value: Bytes,
+    read_ratio: f64,
+) -> Result<Report>
+where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send,
+{
+    let mut report = Report {
+        latencies: Vec::with_capacity(requests),
+        errors: 0,
+    };
+    for _ in 0..requests {
+        let k = key(keys.next(&mut rng));
+        let cmd = match rng.gen_bool(read_ratio) {
+            true => CommandRequest::new_hget(&table, k),
+            false => CommandRequest::new_hset(&table, k, value.clone()),
+        };
+        let start = Instant::now();
+        let res = client.execute(&cmd).await?;
+        report.latencies.push(start.elapsed());
+        // HGET 没有找到 key 时返回 404
+        if res.status != 200 && res.status != 404 {
+            report.errors += 1;
+        }
+    }
+    Ok(report)
+}
+
+impl KeyGenerator {
+    fn next(&mut self, rng: &mut StdRng) -> usize {
+        match self.distribution {
+            Distribution::Uniform => rng.gen_range(0..self.keys),
+            Distribution::Zipf => {
+                let p: f64 = rng.gen();
+                self.cdf.partition_point(|&c| c < p).min(self.keys - 1)
+            }
+            Distribution::Sequential => {
+                let i = self.next % self.keys;
+                self.next += 1;
+                i
+            }
+        }
+    }
+}
+
+/// 第 i 个 key 的概率和 1 / (i + 1)^s 成正比
+fn zipf_cdf(keys: usize) -> Vec<f64> {
+    let weights: Vec<f64> = (1..=keys)
+        .map(|i| 1.0 / (i as f64).powf(ZIPF_EXPONENT))
+        .collect();
+    let total: f64 = weights.iter().sum();
+    let mut sum = 0.0;
+    weights
+        .into_iter()
+        .map(|w| {
+            sum += w;
+            sum / total
+        })
+        .collect()
+}
+
+fn key(i: usize) -> String {
+    format!("key:{:010}", i)
+}
+
+fn print_report(args: &Args, mut report: Report, elapsed: Duration) {
+    report.latencies.sort();
+    let latencies = &report.latencies;
+    let total = latencies.len();
+    println!(
+        "====== {:.0}% HGET / {:.0}% HSET ======",
+        args.read_ratio * 100.0,
+        (1.0 - args.read_ratio) * 100.0
+    );
+    println!("  {} requests completed in {:.2?}", total, elapsed);
+    println!("  {} parallel connections", args.connections);
+    println!("  {} bytes payload", args.value_size);
+    println!("  {} keys, {:?} distribution", args.keys, args.distribution);
+    println!("  {} errors", report.errors);
+    println!();
+    if total == 0 {
+        return;
+    }
+    println!(
+        "throughput: {:.2} requests per second",
+        total as f64 / elapsed.as_secs_f64()
+    );
+    let percentile = |p: f64| latencies[((total as f64 * p) as usize).min(total - 1)];
+    println!(
+        "latency: min {:?}  p50 {:?}  p90 {:?}  p99 {:?}  p99.9 {:?}  max {:?}",
+        latencies[0],
+        percentile(0.5),
+        percentile(0.9),
+        percentile(0.99),
+        percentile(0.999),
+        latencies[total - 1],
+    );
+}
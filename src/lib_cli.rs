@@ -0,0 +1,469 @@
+use std::{env, path::PathBuf, pin::pin, time::Duration};
+
+use anyhow::{anyhow, bail, Result};
+use clap::Parser;
+use futures::StreamExt;
+use kv_db::{
+    config::ClientConfig,
+    multiplex::YamuxCtrl,
+    pb::abi::{value, CommandRequest, CommandResponse, Value},
+    start_client_with_config,
+    transport::BoxedTransport,
+};
+use rustyline::{error::ReadlineError, DefaultEditor};
+use tokio::signal;
+
+/// 交互式的客户端
+#[derive(Debug, Parser)]
+#[command(name = "kv-cli")]
+struct Args {
+    /// 客户端配置文件，没有指定时使用 fixtures/client.conf
+    #[arg(short, long)]
+    config: Option<String>,
+    /// 覆盖配置文件中的服务器地址
+    #[arg(short, long)]
+    addr: Option<String>,
+    /// 历史记录文件，默认是 ~/.kv_cli_history
+    #[arg(long)]
+    history: Option<PathBuf>,
+}
+
+const HELP: &str = "\
+Commands:
+  ping [message]
+  hget <table> <key> [revision]      hset <table> <key> <value> [ttl]
+  hsetnx <table> <key> <value> [ttl] hsetxx <table> <key> <value> [ttl]
+  hdel <table> <key>                 hexist <table> <key>
+  hmget <table> <key>...             hmset <table> <key> <value>...
+  hgetall <table>
+  scan <table> [cursor] [count]      prefix <table> <prefix>
+  range <table> <start> [end]        incrby <table> <key> <delta>
+  expire <table> <key> <seconds>     ttl <table> <key>
+  append <table> <key> <suffix>      strlen <table> <key>
+  getrange <table> <key> <offset> [len]
+  setbit <table> <key> <offset> <0|1> getbit <table> <key> <offset>
+  bitcount <table> <key>
+  pfadd <table> <key> <member>...    pfcount <table> <key>...
+  eval <table> <script> <numkeys> [key...] [arg...]
+  evalsha <table> <sha> <numkeys> [key...] [arg...]
+  fcall <table> <function> <numkeys> [key...] [arg...]
+  lock <table> <name> <ttl_ms>       unlock <table> <name> <token>
+  tables                             dbsize
+  quota <table>
+  info                               slowlog [count]
+  client list                        client kill <id|addr>
+  select [table]                     session <timeout|stream> <value>
+  publish <topic> <value>...         subscribe <topic>
+  compact                            hcompact <revision>
+  help                               quit
+
+Values that look like integers or floats are sent as numbers; quote them to send strings.";
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args = Args::parse();
+    let mut config = match &args.config {
+        Some(path) => ClientConfig::load(path)?,
+        None => toml::from_str(include_str!("../fixtures/client.conf"))?,
+    };
+    if let Some(addr) = &args.addr {
+        config.general.addr = addr.clone();
+    }
+    let addr = config.general.addr.clone();
+    let mut ctrl = start_client_with_config(config).await?;
+
+    let history = args
+        .history
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".kv_cli_history")));
+    let mut editor = DefaultEditor::new()?;
+    if let Some(path) = &history {
+        // 第一次运行时文件还不存在
+        let _ = editor.load_history(path);
+    }
+    println!("Connected to {}, type `help` for commands", addr);
+    let prompt = format!("{}> ", addr);
+    loop {
+        let line = match editor.readline(&prompt) {
+            Ok(line) => line,
+            // Ctrl-C 只清掉当前的输入，Ctrl-D 退出
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => break,
+            Err(e) => return Err(e.into()),
+        };
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        editor.add_history_entry(line)?;
+        let args = match split(line) {
+            Ok(args) => args,
+            Err(e) => {
+                println!("(error) {}", e);
+                continue;
+            }
+        };
+        match args[0].to_ascii_lowercase().as_str() {
+            "quit" | "exit" => break,
+            "help" => println!("{}", HELP),
+            "hgetall" => {
+                if let Err(e) = hgetall(&mut ctrl, &args[1..]).await {
+                    println!("(error) {}", e);
+                }
+            }
+            "subscribe" => {
+                if let Err(e) = subscribe(&mut ctrl, &args[1..]).await {
+                    println!("(error) {}", e);
+                }
+            }
+            _ => match parse(&args) {
+                Ok(cmd) => match execute(&mut ctrl, &cmd).await {
+                    Ok(res) => print_response(&res),
+                    Err(e) => println!("(error) {}", e),
+                },
+                Err(e) => println!("(error) {}", e),
+            },
+        }
+    }
+    if let Some(path) = &history {
+        editor.save_history(path)?;
+    }
+    Ok(())
+}
+
+async fn execute(
+    ctrl: &mut YamuxCtrl<BoxedTransport>,
+    cmd: &CommandRequest,
+) -> Result<CommandResponse> {
+    let mut stream = ctrl.open_stream().await?;
+    Ok(stream.execute(cmd).await?)
+}
+
+/// 让服务器分页返回，收到一页打印一页
+async fn hgetall(ctrl: &mut YamuxCtrl<BoxedTransport>, args: &[String]) -> Result<()> {
+    let [table] = args else {
+        bail!("usage: hgetall <table>");
+    };
+    let mut stream = ctrl.open_stream().await?;
+    let pages = stream
+        .execute_paged(&CommandRequest::new_hgetall(unquote(table)))
+        .await?;
+    let mut pages = pin!(pages);
+    let mut count = 0;
+    while let Some(res) = pages.next().await {
+        let res = res?;
+        if res.status != 200 {
+            print_response(&res);
+            return Ok(());
+        }
+        for pair in &res.pairs {
+            count += 1;
+            let value = pair.value.as_ref().map(format_value).unwrap_or_default();
+            println!("{}) {} => {}", count, quote(&pair.key), value);
+        }
+    }
+    if count == 0 {
+        println!("(empty)");
+    }
+    Ok(())
+}
+
+/// 一直打印收到的消息，直到按下 Ctrl-C
+async fn subscribe(ctrl: &mut YamuxCtrl<BoxedTransport>, args: &[String]) -> Result<()> {
+    let [topic] = args else {
+        bail!("usage: subscribe <topic>");
+    };
+    let mut subscription = ctrl.subscribe(unquote(topic)).await?;
+    let id = subscription.id();
+    println!("Subscribed to {} (id {}), press Ctrl-C to stop", topic, id);
+    loop {
+        tokio::select! {
+            data = subscription.next() => match data {
+                Some(Ok(values)) => {
+                    let values: Vec<String> = values.iter().map(format_value).collect();
+                    println!("[{}] {}", topic, values.join(" "));
+                }
+                Some(Err(e)) => bail!(e),
+                None => break,
+            },
+            _ = signal::ctrl_c() => break,
+        }
+    }
+    ctrl.unsubscribe(unquote(topic), id).await?;
+    Ok(())
+}
+
+/// 把一行输入拆成参数，支持用单引号或者双引号包含空格
+fn split(line: &str) -> Result<Vec<String>> {
+    let mut args = Vec::new();
+    let mut chars = line.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+            continue;
+        }
+        let mut arg = String::new();
+        if c == '"' || c == '\'' {
+            chars.next();
+            // 保留引号，parse_value 根据它判断是不是字符串
+            arg.push('"');
+            loop {
+                match chars.next() {
+                    Some(ch) if ch == c => break,
+                    Some('\\') => arg.push(chars.next().unwrap_or('\\')),
+                    Some(ch) => arg.push(ch),
+                    None => bail!("unbalanced quotes"),
+                }
+            }
+            arg.push('"');
+        } else {
+            while let Some(&ch) = chars.peek() {
+                if ch.is_whitespace() {
+                    break;
+                }
+                arg.push(ch);
+                chars.next();
+            }
+        }
+        args.push(arg);
+    }
+    Ok(args)
+}
+
+/// 表名、key 这类参数去掉引号
+fn unquote(arg: &str) -> &str {
+    match arg.strip_prefix('"').and_then(|s| s.strip_suffix('"')) {
+        Some(s) => s,
+        None => arg,
+    }
+}
+
+fn parse_value(arg: &str) -> Value {
+    if arg.len() >= 2 && arg.starts_with('"') && arg.ends_with('"') {
+        return unquote(arg).into();
+    }
+    if let Ok(n) = arg.parse::<i64>() {
+        return n.into();
+    }
+    if let Ok(f) = arg.parse::<f64>() {
+        return f.into();
+    }
+    arg.into()
+}
+
+fn parse_number<T: std::str::FromStr>(arg: &str, name: &str) -> Result<T> {
+    unquote(arg)
+        .parse()
+        .map_err(|_| anyhow!("{} must be a number", name))
+}
+
+fn parse(args: &[String]) -> Result<CommandRequest> {
+    let name = args[0].to_ascii_lowercase();
+    let a: Vec<&str> = args[1..].iter().map(|s| s.as_str()).collect();
+    let cmd = match (name.as_str(), a.as_slice()) {
+        ("ping", []) => CommandRequest::new_ping(),
+        ("ping", [message]) => CommandRequest::new_ping_with(unquote(message).to_string()),
+        ("hget", [t, k]) => CommandRequest::new_hget(unquote(t), unquote(k)),
+        ("hget", [t, k, rev]) => {
+            CommandRequest::new_hget_at(unquote(t), unquote(k), parse_number(rev, "revision")?)
+        }
+        ("hset", [t, k, v]) => CommandRequest::new_hset(unquote(t), unquote(k), parse_value(v)),
+        ("hset", [t, k, v, ttl]) => CommandRequest::new_hset_ex(
+            unquote(t),
+            unquote(k),
+            parse_value(v),
+            parse_number(ttl, "ttl")?,
+        ),
+        ("hsetnx" | "hsetxx", [t, k, v, rest @ ..]) if rest.len() <= 1 => {
+            let ttl = match rest.first() {
+                Some(ttl) => parse_number(ttl, "ttl")?,
+                None => 0,
+            };
+            let (t, k, v) = (unquote(t), unquote(k), parse_value(v));
+            match name.as_str() {
+                "hsetnx" => CommandRequest::new_hsetnx(t, k, v, ttl),
+                _ => CommandRequest::new_hsetxx(t, k, v, ttl),
+            }
+        }
+        ("hdel", [t, k]) => CommandRequest::new_hdel(unquote(t), unquote(k)),
+        ("hexist", [t, k]) => CommandRequest::new_hexist(unquote(t), unquote(k)),
+        ("hmget", [t, keys @ ..]) if !keys.is_empty() => {
+            CommandRequest::new_hmget(unquote(t), keys.iter().map(|k| unquote(k).to_string()))
+        }
+        ("hmset", [t, pairs @ ..]) if !pairs.is_empty() && pairs.len() % 2 == 0 => {
+            let pairs = pairs.chunks(2).map(|p| (unquote(p[0]), parse_value(p[1])));
+            CommandRequest::new_hmset(unquote(t), pairs)
+        }
+        ("scan", [t, rest @ ..]) if rest.len() <= 2 => {
+            let cursor = match rest.first() {
+                Some(c) => parse_number(c, "cursor")?,
+                None => 0,
+            };
+            let count = match rest.get(1) {
+                Some(c) => parse_number(c, "count")?,
+                None => 0,
+            };
+            CommandRequest::new_hscan(unquote(t), cursor, count)
+        }
+        ("prefix", [t, p]) => CommandRequest::new_hscanprefix(unquote(t), unquote(p)),
+        ("range", [t, start]) => CommandRequest::new_hrange(unquote(t), unquote(start), ""),
+        ("range", [t, start, end]) => {
+            CommandRequest::new_hrange(unquote(t), unquote(start), unquote(end))
+        }
+        ("incrby", [t, k, delta]) => {
+            CommandRequest::new_hincrby(unquote(t), unquote(k), parse_number(delta, "delta")?)
+        }
+        ("expire", [t, k, secs]) => {
+            CommandRequest::new_hexpire(unquote(t), unquote(k), parse_number(secs, "seconds")?)
+        }
+        ("ttl", [t, k]) => CommandRequest::new_httl(unquote(t), unquote(k)),
+        ("append", [t, k, suffix]) => {
+            CommandRequest::new_happend(unquote(t), unquote(k), unquote(suffix))
+        }
+        ("strlen", [t, k]) => CommandRequest::new_hstrlen(unquote(t), unquote(k)),
+        ("setbit", [t, k, offset, bit]) => {
+            let bit = match *bit {
+                "0" => false,
+                "1" => true,
+                _ => bail!("bit must be 0 or 1"),
+            };
+            CommandRequest::new_hsetbit(
+                unquote(t),
+                unquote(k),
+                parse_number(offset, "offset")?,
+                bit,
+            )
+        }
+        ("getbit", [t, k, offset]) => {
+            CommandRequest::new_hgetbit(unquote(t), unquote(k), parse_number(offset, "offset")?)
+        }
+        ("bitcount", [t, k]) => CommandRequest::new_hbitcount(unquote(t), unquote(k)),
+        ("pfadd", [t, k, members @ ..]) if !members.is_empty() => {
+            CommandRequest::new_hpfadd(unquote(t), unquote(k), members.iter().map(|m| unquote(m)))
+        }
+        ("pfcount", [t, keys @ ..]) if !keys.is_empty() => {
+            CommandRequest::new_hpfcount(unquote(t), keys.iter().map(|k| unquote(k).to_string()))
+        }
+        (name @ ("eval" | "evalsha" | "fcall"), [t, script, numkeys, rest @ ..]) => {
+            let numkeys: usize = parse_number(numkeys, "numkeys")?;
+            if numkeys > rest.len() {
+                bail!("numkeys is greater than the number of arguments");
+            }
+            let (keys, args) = rest.split_at(numkeys);
+            let keys = keys.iter().map(|k| unquote(k).to_string());
+            let args = args.iter().map(|a| parse_value(a));
+            match name {
+                "eval" => CommandRequest::new_eval(unquote(t), unquote(script), keys, args),
+                "fcall" => CommandRequest::new_fcall(unquote(t), unquote(script), keys, args),
+                _ => CommandRequest::new_evalsha(unquote(t), unquote(script), keys, args),
+            }
+        }
+        ("getrange", [t, k, offset]) => CommandRequest::new_hgetrange(
+            unquote(t),
+            unquote(k),
+            parse_number(offset, "offset")?,
+            0,
+        ),
+        ("getrange", [t, k, offset, len]) => CommandRequest::new_hgetrange(
+            unquote(t),
+            unquote(k),
+            parse_number(offset, "offset")?,
+            parse_number(len, "len")?,
+        ),
+        ("lock", [t, n, ttl]) => {
+            let ttl = Duration::from_millis(parse_number(ttl, "ttl")?);
+            CommandRequest::new_lock(unquote(t), unquote(n), ttl)
+        }
+        ("unlock", [t, n, token]) => {
+            CommandRequest::new_unlock(unquote(t), unquote(n), parse_number(token, "token")?)
+        }
+        ("tables", []) => CommandRequest::new_tlist(),
+        ("dbsize", []) => CommandRequest::new_dbsize(),
+        ("quota", [t]) => CommandRequest::new_quota(unquote(t)),
+        ("info", []) => CommandRequest::new_info(),
+        ("compact", []) => CommandRequest::new_compact(),
+        ("hcompact", [rev]) => CommandRequest::new_hcompact(parse_number(rev, "revision")?),
+        ("slowlog", []) => CommandRequest::new_slowlog_get(0),
+        ("slowlog", [count]) => CommandRequest::new_slowlog_get(parse_number(count, "count")?),
+        ("select", []) => CommandRequest::new_select(""),
+        ("select", [t]) => CommandRequest::new_select(unquote(t)),
+        ("session", [name, value]) => CommandRequest::new_session_set(*name, unquote(value)),
+        ("client", [sub]) if sub.eq_ignore_ascii_case("list") => CommandRequest::new_client_list(),
+        ("client", [sub, target]) if sub.eq_ignore_ascii_case("kill") => match target.parse() {
+            Ok(id) => CommandRequest::new_client_kill(id),
+            Err(_) => CommandRequest::new_client_kill_peer(unquote(target)),
+        },
+        ("publish", [topic, values @ ..]) if !values.is_empty() => CommandRequest::new_publish(
+            unquote(topic),
+            values.iter().map(|v| parse_value(v)).collect(),
+        ),
+        (
+            "ping" | "hget" | "hset" | "hsetnx" | "hsetxx" | "hdel" | "hexist" | "hmget" | "hmset"
+            | "scan" | "prefix" | "range" | "incrby" | "expire" | "ttl" | "append" | "strlen"
+            | "getrange" | "setbit" | "getbit" | "bitcount" | "pfadd" | "pfcount" | "eval"
+            | "evalsha" | "lock" | "unlock" | "tables" | "dbsize" | "quota" | "info" | "compact"
+            | "hcompact" | "slowlog" | "client" | "select" | "session" | "publish",
+            _,
+        ) => bail!(
+            "wrong number of arguments for '{}', type `help` for usage",
+            name
+        ),
+        _ => bail!("unknown command '{}', type `help` for commands", name),
+    };
+    Ok(cmd)
+}
+
+fn print_response(res: &CommandResponse) {
+    if res.status != 200 {
+        println!("(error {}) {}", res.status, res.message);
+        return;
+    }
+    if res.values.is_empty() && res.pairs.is_empty() {
+        println!("OK");
+        return;
+    }
+    match res.values.as_slice() {
+        [v] if res.pairs.is_empty() => println!("{}", format_value(v)),
+        values => {
+            for (i, v) in values.iter().enumerate() {
+                println!("{}) {}", i + 1, format_value(v));
+            }
+        }
+    }
+    for (i, pair) in res.pairs.iter().enumerate() {
+        let value = pair.value.as_ref().map(format_value).unwrap_or_default();
+        println!("{}) {} => {}", i + 1, quote(&pair.key), value);
+    }
+}
+
+fn quote(data: &[u8]) -> String {
+    format!("{:?}", String::from_utf8_lossy(data))
+}
+
+fn format_value(v: &Value) -> String {
+    match &v.value {
+        None => "(nil)".into(),
+        Some(value::Value::String(s)) => format!("{:?}", s),
+        Some(value::Value::Binary(b)) => quote(b),
+        Some(value::Value::Integer(n)) => format!("(integer) {}", n),
+        Some(value::Value::Float(f)) => format!("(float) {}", f),
+        Some(value::Value::Bool(b)) => format!("(bool) {}", b),
+        Some(value::Value::Json(json)) => json.clone(),
+        Some(value::Value::List(list)) => {
+            let values: Vec<String> = list.values.iter().map(format_value).collect();
+            format!("[{}]", values.join(", "))
+        }
+        Some(value::Value::Set(set)) => {
+            let members: Vec<String> = set.members.iter().map(format_value).collect();
+            format!("{{{}}}", members.join(", "))
+        }
+        Some(value::Value::Map(map)) => {
+            let mut entries: Vec<_> = map.entries.iter().collect();
+            entries.sort_by(|a, b| a.0.cmp(b.0));
+            let entries: Vec<String> = entries
+                .into_iter()
+                .map(|(k, v)| format!("{}: {}", k, format_value(v)))
+                .collect();
+            format!("{{{}}}", entries.join(", "))
+        }
+    }
+}
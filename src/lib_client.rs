@@ -1,4 +1,5 @@
 use anyhow::Result;
+use futures::StreamExt;
 use kv_db::{
     config::ClientConfig, error::KvError, pb::abi::CommandRequest, start_client_with_config,
     ProstClientStream,
@@ -29,12 +30,15 @@ async fn main() -> Result<()> {
     let topic = "lobby1";
     start_publishing(yamux.open_stream().await?, topic)?;
     //Sub
-    let cmd = CommandRequest::new_subscribe(topic);
-    let stream = client.execute_streaming(&cmd).await?;
-    let id = stream.id;
+    let mut subscription = yamux.subscribe(topic).await?;
+    let id = subscription.id();
     //UnSub
     start_unsubscribe(yamux.open_stream().await?, topic, id)?;
 
+    while let Some(Ok(data)) = subscription.next().await {
+        info!("Got published data {:?}", data);
+    }
+
     Ok(())
 }
 
@@ -1,12 +1,167 @@
 use anyhow::Result;
-use kv_db::{config::ServerConfig, start_server_with_config};
+use clap::{Parser, Subcommand};
+use kv_db::{
+    backup_with_config, check_config, config::ServerConfig, export::Format, export_with_config,
+    import_with_config, migrate_with_config, restore_with_config, start_server_with_config,
+    telemetry, ServerHandle,
+};
+use std::{
+    fs::File,
+    io::{self, BufReader},
+};
+use tokio::signal;
+use tracing::{info, warn};
+
+/// KV 服务器，没有指定子命令时启动服务器
+#[derive(Debug, Parser)]
+#[command(name = "kv-server")]
+struct Cli {
+    /// 服务器配置文件，没有指定时使用 fixtures/server.conf
+    #[arg(short, long, global = true)]
+    config: Option<String>,
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// 启动服务器
+    Serve,
+    /// 把存储中的数据导出到 snapshot 文件，需要先停止服务器
+    Backup {
+        /// 导出的文件
+        #[arg(short, long)]
+        out: String,
+    },
+    /// 把 snapshot 文件导入到存储，需要先停止服务器
+    Restore {
+        /// 要导入的文件
+        #[arg(short, long)]
+        from: String,
+    },
+    /// 把所有 table 的 kvpair 导出成 JSON lines 或者 CSV，需要先停止服务器
+    Export {
+        /// 导出的格式：json 或者 csv
+        #[arg(short, long, default_value = "json")]
+        format: Format,
+        /// 导出的文件，没有指定时写到标准输出
+        #[arg(short, long)]
+        out: Option<String>,
+    },
+    /// 导入 export 导出的文件，需要先停止服务器
+    Import {
+        /// 文件的格式：json 或者 csv
+        #[arg(short, long, default_value = "json")]
+        format: Format,
+        /// 要导入的文件，没有指定时从标准输入读取
+        #[arg(long)]
+        from: Option<String>,
+    },
+    /// 把存储中所有的数据复制到另一个配置文件中的存储，两边都需要先停止服务器
+    Migrate {
+        /// 目标服务器的配置文件，只使用其中的存储配置
+        #[arg(long)]
+        to: String,
+        /// 复制完成之后不校验
+        #[arg(long)]
+        no_verify: bool,
+    },
+    /// 检查配置文件，不启动服务器
+    CheckConfig,
+}
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    tracing_subscriber::fmt::init();
-    let config: Result<ServerConfig, toml::de::Error> =
-        toml::from_str(include_str!("../fixtures/server.conf"));
-    start_server_with_config(config?).await
+    let cli = Cli::parse();
+    // 指定了配置文件时从文件加载，这样才能通过 SIGHUP 重新加载
+    let config: ServerConfig = match &cli.config {
+        Some(path) => ServerConfig::load(path)?,
+        None => toml::from_str(include_str!("../fixtures/server.conf"))?,
+    };
+    match cli.command.unwrap_or(Command::Serve) {
+        Command::Serve => {
+            telemetry::init(config.telemetry.as_ref())?;
+            let result = run(config).await;
+            telemetry::shutdown();
+            result?
+        }
+        Command::Backup { out } => {
+            let count = backup_with_config(&config, &out)?;
+            println!("Backed up {} keys to {}", count, out);
+        }
+        Command::Restore { from } => {
+            let count = restore_with_config(&config, &from)?;
+            println!("Restored {} records from {}", count, from);
+        }
+        Command::Export { format, out } => {
+            let count = match &out {
+                Some(path) => export_with_config(&config, File::create(path)?, format)?,
+                None => export_with_config(&config, io::stdout().lock(), format)?,
+            };
+            eprintln!("Exported {} keys", count);
+        }
+        Command::Import { format, from } => {
+            let count = match &from {
+                Some(path) => {
+                    import_with_config(&config, BufReader::new(File::open(path)?), format)?
+                }
+                None => import_with_config(&config, io::stdin().lock(), format)?,
+            };
+            println!("Imported {} keys", count);
+        }
+        Command::Migrate { to, no_verify } => {
+            let target = ServerConfig::load(&to)?;
+            let stats = migrate_with_config(&config, &target, !no_verify, |s| {
+                eprintln!("{}: {} keys copied", s.table, s.keys);
+            })?;
+            println!(
+                "Migrated {} keys in {} tables, {} sorted sets and {} indexes",
+                stats.keys, stats.tables, stats.zsets, stats.indexes
+            );
+        }
+        Command::CheckConfig => {
+            for warning in check_config(&config)? {
+                println!("warning: {}", warning);
+            }
+            println!("Config is OK");
+        }
+    }
+    Ok(())
+}
+
+async fn run(config: ServerConfig) -> Result<()> {
+    let server = start_server_with_config(config).await?;
+    wait_for_signal(&server).await?;
+    server.shutdown().await
+}
+
+/// 等待 SIGINT（Ctrl-C）或者 SIGTERM；收到 SIGHUP 时重新加载配置
+#[cfg(unix)]
+async fn wait_for_signal(server: &ServerHandle) -> Result<()> {
+    use signal::unix::{signal, SignalKind};
+
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut hangup = signal(SignalKind::hangup())?;
+    loop {
+        tokio::select! {
+            res = signal::ctrl_c() => break res?,
+            _ = terminate.recv() => break,
+            _ = hangup.recv() => {
+                if let Err(e) = server.reload() {
+                    warn!("Failed to reload config: {:?}", e);
+                }
+            }
+        }
+    }
+    info!("Got shutdown signal");
+    Ok(())
+}
+
+#[cfg(not(unix))]
+async fn wait_for_signal(_server: &ServerHandle) -> Result<()> {
+    signal::ctrl_c().await?;
+    info!("Got shutdown signal");
+    Ok(())
 }
 
 // use anyhow::Result;
@@ -0,0 +1,170 @@
+//! 同步的客户端：给命令行工具和不使用 async 的程序用，内部自带一个 tokio runtime
+
+use std::collections::VecDeque;
+
+use futures::StreamExt;
+use tokio::runtime::{self, Runtime};
+
+use super::{multiplex::ClientStream, pool::Pool, stream_result};
+use crate::{
+    config::ClientConfig,
+    error::KvError,
+    pb::abi::{CommandRequest, CommandResponse, Kvpair, Value},
+};
+
+/// 同步版本的 Pool：每个方法都在内部的 runtime 上等待对应的异步方法执行完。
+/// 不能在 async 的上下文里使用
+pub struct Client {
+    pool: Pool,
+    runtime: Runtime,
+}
+
+impl Client {
+    /// 建立连接；连接和健康检查跑在内部 runtime 的后台线程上
+    pub fn connect(config: ClientConfig) -> Result<Self, KvError> {
+        let runtime = runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .enable_all()
+            .build()?;
+        let pool = runtime.block_on(Pool::connect(config))?;
+        Ok(Self { pool, runtime })
+    }
+
+    pub fn execute(&self, cmd: &CommandRequest) -> Result<CommandResponse, KvError> {
+        self.runtime.block_on(self.pool.execute(cmd))
+    }
+
+    /// 用 HSCAN 遍历 table，每次取 count 个，count 为 0 时使用服务器默认的数量
+    pub fn scan(&self, table: impl Into<String>, count: u64) -> Scan<'_> {
+        Scan {
+            client: self,
+            table: table.into(),
+            count,
+            cursor: Some(0),
+            pairs: VecDeque::new(),
+        }
+    }
+
+    /// 订阅一个主题，返回的 Subscription 是一个阻塞的迭代器
+    pub fn subscribe(&self, topic: impl Into<String>) -> Result<Subscription<'_>, KvError> {
+        let topic = topic.into();
+        let inner = self.runtime.block_on(async {
+            let stream = self.pool.get().await?;
+            let cmd = CommandRequest::new_subscribe(&topic);
+            let result = stream.execute_streaming(&cmd).await?;
+            Ok::<_, KvError>(stream_result::Subscription::new(topic, result))
+        })?;
+        Ok(Subscription {
+            runtime: &self.runtime,
+            inner,
+        })
+    }
+
+    /// 取消订阅
+    pub fn unsubscribe(&self, topic: impl Into<String>, id: u32) -> Result<(), KvError> {
+        let res = self.execute(&CommandRequest::new_unsubscribe(&topic.into(), id))?;
+        match res.status {
+            200 => Ok(()),
+            _ => Err(KvError::Internal(res.message)),
+        }
+    }
+}
+
+/// 遍历 table 的迭代器，一页取完之后再发送下一个 HSCAN
+pub struct Scan<'a> {
+    client: &'a Client,
+    table: String,
+    count: u64,
+    /// 下一页的 cursor，None 表示已经取完
+    cursor: Option<u64>,
+    pairs: VecDeque<Kvpair>,
+}
+
+impl Iterator for Scan<'_> {
+    type Item = Result<Kvpair, KvError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.pairs.is_empty() {
+            let cursor = self.cursor.take()?;
+            let cmd = CommandRequest::new_hscan(&self.table, cursor, self.count);
+            let res = match self.client.execute(&cmd) {
+                Ok(res) if res.status == 200 => res,
+                Ok(res) => return Some(Err(KvError::Internal(res.message))),
+                Err(e) => return Some(Err(e)),
+            };
+            // 返回的 cursor 为 0 表示没有下一页了
+            let next = res.values.first().and_then(|v| v.to_integer());
+            self.cursor = next.filter(|&n| n > 0).map(|n| n as u64);
+            self.pairs = res.pairs.into();
+        }
+        self.pairs.pop_front().map(Ok)
+    }
+}
+
+/// 阻塞的订阅：每次 next 等待下一次 publish 的数据，连接断开之后结束
+pub struct Subscription<'a> {
+    runtime: &'a Runtime,
+    inner: stream_result::Subscription<ClientStream>,
+}
+
+impl Subscription<'_> {
+    pub fn topic(&self) -> &str {
+        &self.inner.topic
+    }
+
+    /// 订阅 id，取消订阅时需要用到
+    pub fn id(&self) -> u32 {
+        self.inner.id()
+    }
+}
+
+impl Iterator for Subscription<'_> {
+    type Item = Result<Vec<Value>, KvError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.runtime.block_on(self.inner.next())
+    }
+}
+
+#[cfg(test)]
+mod blocking_tests {
+    use super::*;
+    use crate::{
+        config::{ServerConfig, StorageConfig},
+        start_server_with_config,
+    };
+
+    #[test]
+    fn blocking_client_should_work() {
+        let addr = "127.0.0.1:10120";
+        let server = Runtime::new().unwrap();
+        let mut config: ServerConfig =
+            toml::from_str(include_str!("../../fixtures/server.conf")).unwrap();
+        config.general.addr = addr.into();
+        config.storage = StorageConfig::MemTable;
+        let _server = server.block_on(start_server_with_config(config)).unwrap();
+
+        let mut config: ClientConfig =
+            toml::from_str(include_str!("../../fixtures/client.conf")).unwrap();
+        config.general.addr = addr.into();
+        // 取消订阅和订阅在同一个连接上，订阅的 stream 才会马上结束
+        config.pool.size = Some(1);
+        let client = Client::connect(config).unwrap();
+
+        for i in 0..5 {
+            let cmd = CommandRequest::new_hset("t1", format!("k{}", i), i);
+            assert_eq!(client.execute(&cmd).unwrap().status, 200);
+        }
+        let mut keys: Vec<_> = client.scan("t1", 2).map(|pair| pair.unwrap().key).collect();
+        keys.sort();
+        assert_eq!(keys, ["k0", "k1", "k2", "k3", "k4"]);
+
+        let mut sub = client.subscribe("lobby").unwrap();
+        let cmd = CommandRequest::new_publish("lobby", vec!["hello".into()]);
+        assert_eq!(client.execute(&cmd).unwrap().status, 200);
+        assert_eq!(sub.next().unwrap().unwrap(), [Value::from("hello")]);
+
+        client.unsubscribe("lobby", sub.id()).unwrap();
+        assert!(sub.next().is_none());
+    }
+}
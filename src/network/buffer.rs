@@ -0,0 +1,92 @@
+use std::sync::Mutex;
+
+use bytes::BytesMut;
+
+/// 所有连接共享的读写 buffer，连接断开时放回来，新的连接不需要重新分配
+pub static CONNECTION_BUFFERS: BufferPool = BufferPool::new(16 * 1024, 1024, 64 * 1024);
+
+/// 复用的 buffer：取出时是空的，用完之后放回去，下次不需要重新分配内存
+#[derive(Debug)]
+pub struct BufferPool {
+    buffers: Mutex<Vec<BytesMut>>,
+    /// 新分配的 buffer 的容量
+    capacity: usize,
+    /// 最多缓存多少个 buffer，多出来的直接释放
+    max_buffers: usize,
+    /// 容量超过这个大小的 buffer 不放回来，避免偶尔的大消息长期占用内存
+    max_capacity: usize,
+}
+
+impl BufferPool {
+    pub const fn new(capacity: usize, max_buffers: usize, max_capacity: usize) -> Self {
+        Self {
+            buffers: Mutex::new(Vec::new()),
+            capacity,
+            max_buffers,
+            max_capacity,
+        }
+    }
+
+    pub fn get(&self) -> BytesMut {
+        let buf = self.lock().pop();
+        buf.unwrap_or_else(|| BytesMut::with_capacity(self.capacity))
+    }
+
+    /// buffer 中的数据会被清空
+    pub fn put(&self, mut buf: BytesMut) {
+        if buf.capacity() == 0 || buf.capacity() > self.max_capacity {
+            return;
+        }
+        buf.clear();
+        let mut buffers = self.lock();
+        if buffers.len() < self.max_buffers {
+            buffers.push(buf);
+        }
+    }
+
+    /// 缓存着的 buffer 数量
+    pub fn len(&self) -> usize {
+        self.lock().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Vec<BytesMut>> {
+        self.buffers.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod buffer_tests {
+    use bytes::BufMut;
+
+    use super::*;
+
+    #[test]
+    fn buffer_pool_should_reuse_buffers() {
+        let pool = BufferPool::new(64, 2, 1024);
+        let mut buf = pool.get();
+        assert!(buf.capacity() >= 64);
+        buf.put_slice(b"hello");
+        let ptr = buf.as_ptr();
+        pool.put(buf);
+        assert_eq!(pool.len(), 1);
+
+        // 取回来的是同一块内存，数据已经清空
+        let buf = pool.get();
+        assert!(buf.is_empty());
+        assert_eq!(buf.as_ptr(), ptr);
+        assert!(pool.is_empty());
+
+        // 超过上限的 buffer 不缓存
+        pool.put(BytesMut::with_capacity(4096));
+        pool.put(BytesMut::new());
+        assert!(pool.is_empty());
+        for _ in 0..3 {
+            pool.put(BytesMut::with_capacity(64));
+        }
+        assert_eq!(pool.len(), 2);
+    }
+}
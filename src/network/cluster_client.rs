@@ -0,0 +1,211 @@
+//! 感知集群拓扑的客户端：通过 CLUSTER SLOTS 获取拓扑，raft 集群中把命令直接发给 leader，
+//! 分片部署中用和代理相同的一致性哈希把命令直接发给 key 所在的后端，不需要经过代理转发。
+//! follower 返回带地址的 421 时重定向到新的 leader，不知道 leader 在哪里时稍后刷新拓扑再重试
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use hyper::StatusCode;
+use tokio::{sync::Mutex, time};
+use tracing::{debug, warn};
+
+use super::{pool::Pool, reconnect::is_idempotent};
+use crate::{
+    config::{ClientConfig, ProxyConfig},
+    error::KvError,
+    pb::abi::{value, CommandRequest, CommandResponse, Kvpair, Value},
+    proxy::{Proxy, Route},
+};
+
+/// 一个命令最多重定向或者重试几次
+const MAX_REDIRECTS: usize = 5;
+
+/// 集群还没有选出 leader 或者节点连接不上时，等多久再刷新拓扑
+const REDIRECT_BACKOFF: Duration = Duration::from_millis(200);
+
+/// 客户端看到的集群拓扑
+enum Topology {
+    /// 所有命令都发给这个节点：单机，或者 raft 集群的 leader
+    Node(String),
+    /// 分片部署，按 key 发给各个后端
+    Sharded(Proxy),
+}
+
+pub struct ClusterClient {
+    /// 连接各个节点使用的配置，只替换其中的地址
+    config: ClientConfig,
+    topology: RwLock<Arc<Topology>>,
+    /// 刷新拓扑时依次尝试的节点：配置中的节点，以及 raft 集群中的其它节点
+    nodes: RwLock<Vec<String>>,
+    /// 各个节点的连接池，第一次发送命令时建立
+    pools: Mutex<HashMap<String, Pool>>,
+}
+
+impl ClusterClient {
+    /// 从 config 中的节点获取集群拓扑
+    pub async fn connect(config: ClientConfig) -> Result<Self, KvError> {
+        let addr = config.general.addr.clone();
+        let client = Self {
+            topology: RwLock::new(Arc::new(Topology::Node(addr.clone()))),
+            nodes: RwLock::new(vec![addr]),
+            pools: Default::default(),
+            config,
+        };
+        client.refresh().await?;
+        Ok(client)
+    }
+
+    /// 重新获取拓扑，依次尝试已知的节点，直到有一个节点回复
+    pub async fn refresh(&self) -> Result<(), KvError> {
+        let nodes = self.nodes.read().unwrap().clone();
+        let mut last = None;
+        for addr in nodes {
+            match self.fetch(&addr).await {
+                Ok(topology) => {
+                    *self.topology.write().unwrap() = Arc::new(topology);
+                    return Ok(());
+                }
+                Err(e) => {
+                    debug!("Failed to fetch topology from {}: {}", addr, e);
+                    last = Some(e);
+                }
+            }
+        }
+        Err(last.unwrap_or_else(|| KvError::Internal("no node to fetch topology".into())))
+    }
+
+    /// 执行命令：按拓扑发给对应的节点，收到 421 时重定向。连接出错时刷新拓扑，
+    /// 和断线重连一样只重试幂等的命令
+    pub async fn execute(&self, cmd: &CommandRequest) -> Result<CommandResponse, KvError> {
+        let idempotent = cmd.request_data.as_ref().is_some_and(is_idempotent);
+        let mut attempt = 0;
+        loop {
+            attempt += 1;
+            let res = match self.try_execute(cmd).await {
+                Ok(res) => res,
+                Err(e) if idempotent && attempt <= MAX_REDIRECTS => {
+                    warn!("Failed to execute command: {}, refreshing topology", e);
+                    time::sleep(REDIRECT_BACKOFF).await;
+                    self.refresh().await.ok();
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+            if res.status != StatusCode::MISDIRECTED_REQUEST.as_u16() as u32
+                || attempt > MAX_REDIRECTS
+            {
+                return Ok(res);
+            }
+            match moved_to(&res) {
+                Some(addr) => {
+                    debug!("Redirected to {}", addr);
+                    *self.topology.write().unwrap() = Arc::new(Topology::Node(addr));
+                }
+                // 还没有选出 leader，或者 leader 刚刚变化
+                None => {
+                    time::sleep(REDIRECT_BACKOFF).await;
+                    self.refresh().await.ok();
+                }
+            }
+        }
+    }
+
+    fn topology(&self) -> Arc<Topology> {
+        self.topology.read().unwrap().clone()
+    }
+
+    async fn try_execute(&self, cmd: &CommandRequest) -> Result<CommandResponse, KvError> {
+        match &*self.topology() {
+            Topology::Node(addr) => self.pool(addr).await?.execute(cmd).await,
+            Topology::Sharded(proxy) => {
+                let Some(data) = &cmd.request_data else {
+                    return Err(KvError::InvalidCommand("empty command".into()));
+                };
+                let route = match proxy.route(data) {
+                    // 和数据无关的命令发给第一个后端
+                    Ok(Route::Local) => Route::Forward(0),
+                    Ok(route) => route,
+                    Err(e) => return Ok(e.into()),
+                };
+                proxy.forward(route, cmd).await
+            }
+        }
+    }
+
+    /// 从 addr 获取拓扑；raft 集群中记住所有节点，leader 出问题时从其它节点刷新
+    async fn fetch(&self, addr: &str) -> Result<Topology, KvError> {
+        let res = self
+            .pool(addr)
+            .await?
+            .execute(&CommandRequest::new_cluster_slots())
+            .await?;
+        if res.status != StatusCode::OK.as_u16() as u32 {
+            return Err(KvError::Internal(res.message));
+        }
+        let nodes: Vec<(String, &str)> = res.pairs.iter().map(node).collect();
+        let topology = match res.values.first().and_then(as_str) {
+            Some("shard") => {
+                let virtual_nodes = res.values.get(1).and_then(Value::to_integer);
+                let backends = nodes
+                    .iter()
+                    .map(|(addr, _)| self.node_config(addr))
+                    .collect();
+                Topology::Sharded(Proxy::new(&ProxyConfig {
+                    backends,
+                    virtual_nodes: virtual_nodes.map(|n| n as usize),
+                })?)
+            }
+            Some("raft") => {
+                let mut known = self.nodes.write().unwrap();
+                for (node, _) in &nodes {
+                    if !known.contains(node) {
+                        known.push(node.clone());
+                    }
+                }
+                match nodes.iter().find(|(_, role)| *role == "leader") {
+                    Some((leader, _)) => Topology::Node(leader.clone()),
+                    None => Topology::Node(addr.into()),
+                }
+            }
+            _ => Topology::Node(addr.into()),
+        };
+        Ok(topology)
+    }
+
+    async fn pool(&self, addr: &str) -> Result<Pool, KvError> {
+        let mut pools = self.pools.lock().await;
+        if let Some(pool) = pools.get(addr) {
+            return Ok(pool.clone());
+        }
+        let pool = Pool::connect(self.node_config(addr)).await?;
+        pools.insert(addr.into(), pool.clone());
+        Ok(pool)
+    }
+
+    fn node_config(&self, addr: &str) -> ClientConfig {
+        let mut config = self.config.clone();
+        config.general.addr = addr.into();
+        config
+    }
+}
+
+/// 421 中 leader 的地址
+fn moved_to(res: &CommandResponse) -> Option<String> {
+    res.values.first().and_then(as_str).map(Into::into)
+}
+
+fn as_str(v: &Value) -> Option<&str> {
+    match &v.value {
+        Some(value::Value::String(s)) => Some(s),
+        _ => None,
+    }
+}
+
+/// CLUSTER SLOTS 中的一个节点：地址和角色
+fn node(pair: &Kvpair) -> (String, &str) {
+    let role = pair.value.as_ref().and_then(as_str).unwrap_or_default();
+    (String::from_utf8_lossy(&pair.key).into_owned(), role)
+}
@@ -0,0 +1,128 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+};
+
+use crate::{config::ServerConfig, error::KvError};
+
+/// 限制同时打开的连接数，避免一个客户端耗尽服务器的文件描述符；
+/// prost、QUIC、RESP 和 WebSocket 的连接一起计数
+#[derive(Debug, Clone, Default)]
+pub struct ConnectionLimiter(Arc<Inner>);
+
+#[derive(Debug, Default)]
+struct Inner {
+    max_connections: Option<usize>,
+    max_connections_per_ip: Option<usize>,
+    /// 总连接数和每个 IP 的连接数
+    state: Mutex<(usize, HashMap<IpAddr, usize>)>,
+}
+
+/// 一个打开的连接，drop 之后连接数减一
+#[derive(Debug)]
+pub struct ConnectionGuard {
+    inner: Arc<Inner>,
+    ip: Option<IpAddr>,
+}
+
+impl ConnectionLimiter {
+    pub fn new(max_connections: Option<usize>, max_connections_per_ip: Option<usize>) -> Self {
+        Self(Arc::new(Inner {
+            max_connections,
+            max_connections_per_ip,
+            state: Default::default(),
+        }))
+    }
+
+    pub fn from_config(config: &ServerConfig) -> Self {
+        Self::new(config.max_connections, config.max_connections_per_ip)
+    }
+
+    /// 没有超过限制时记录一个新的连接；unix socket 的连接没有 IP，只计入总数
+    pub fn try_acquire(&self, ip: Option<IpAddr>) -> Result<ConnectionGuard, KvError> {
+        let mut state = self.0.state.lock().unwrap_or_else(|e| e.into_inner());
+        let (total, per_ip) = &mut *state;
+        if let Some(max) = self.0.max_connections {
+            if *total >= max {
+                return Err(KvError::TooManyConnections(format!(
+                    "server is limited to {} connections",
+                    max
+                )));
+            }
+        }
+        if let Some(ip) = ip {
+            let count = per_ip.entry(ip).or_default();
+            if let Some(max) = self.0.max_connections_per_ip {
+                if *count >= max {
+                    // 刚插入的 0 不需要保留
+                    if *count == 0 {
+                        per_ip.remove(&ip);
+                    }
+                    return Err(KvError::TooManyConnections(format!(
+                        "{} is limited to {} connections",
+                        ip, max
+                    )));
+                }
+            }
+            *count += 1;
+        }
+        *total += 1;
+        Ok(ConnectionGuard {
+            inner: self.0.clone(),
+            ip,
+        })
+    }
+
+    /// 当前的连接数
+    pub fn connections(&self) -> usize {
+        self.0.state.lock().unwrap_or_else(|e| e.into_inner()).0
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        let mut state = self.inner.state.lock().unwrap_or_else(|e| e.into_inner());
+        let (total, per_ip) = &mut *state;
+        *total -= 1;
+        let Some(ip) = self.ip else {
+            return;
+        };
+        if let Some(count) = per_ip.get_mut(&ip) {
+            *count -= 1;
+            if *count == 0 {
+                per_ip.remove(&ip);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod conn_limit_tests {
+    use super::*;
+
+    #[test]
+    fn limiter_should_limit_total_and_per_ip_connections() {
+        let limiter = ConnectionLimiter::new(Some(3), Some(2));
+        let (ip1, ip2) = (
+            Some("10.0.0.1".parse().unwrap()),
+            Some("10.0.0.2".parse().unwrap()),
+        );
+
+        let c1 = limiter.try_acquire(ip1).unwrap();
+        let _c2 = limiter.try_acquire(ip1).unwrap();
+        let res = limiter.try_acquire(ip1);
+        assert!(matches!(res, Err(KvError::TooManyConnections(m)) if m.contains("10.0.0.1")));
+        let _c3 = limiter.try_acquire(ip2).unwrap();
+        let res = limiter.try_acquire(ip2);
+        assert!(matches!(res, Err(KvError::TooManyConnections(m)) if m.contains("server")));
+        assert_eq!(limiter.connections(), 3);
+
+        // 连接关闭之后可以再建立新的连接
+        drop(c1);
+        assert_eq!(limiter.connections(), 2);
+        let _c4 = limiter.try_acquire(ip2).unwrap();
+        // 没有 IP 的连接只受总数限制
+        assert!(limiter.try_acquire(None).is_err());
+    }
+}
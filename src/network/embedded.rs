@@ -0,0 +1,177 @@
+use std::sync::Arc;
+
+use futures::{stream::BoxStream, StreamExt};
+
+use super::stream_result::{KeyWatch, StreamResult, Subscription};
+use crate::{
+    error::KvError,
+    memory::MemTable,
+    pb::abi::{CommandRequest, CommandResponse, Value},
+    session::Session,
+    Service, Storage,
+};
+
+/// 嵌入模式下的响应流
+pub type EmbeddedStream = BoxStream<'static, Result<CommandResponse, KvError>>;
+
+/// 进程内的客户端：和网络客户端的 API 一样，但直接交给 Service 执行，不经过 TCP、TLS 和 yamux。
+/// 同一个 EmbeddedClient 上执行的命令共享一个 session（AUTH、HELLO 和事务的状态）
+pub struct EmbeddedClient<Store = MemTable> {
+    service: Service<Store>,
+    session: Arc<Session>,
+}
+
+impl<Store: Storage> EmbeddedClient<Store> {
+    pub fn new(service: Service<Store>) -> Self {
+        Self {
+            service,
+            session: Default::default(),
+        }
+    }
+
+    pub fn service(&self) -> &Service<Store> {
+        &self.service
+    }
+
+    pub async fn execute(&self, cmd: &CommandRequest) -> Result<CommandResponse, KvError> {
+        let mut stream = self.execute_streaming(cmd).await;
+        match stream.next().await {
+            Some(res) => res,
+            None => Err(KvError::Internal("Didn't get any response".into())),
+        }
+    }
+
+    /// 订阅一个主题
+    pub async fn subscribe(
+        &self,
+        topic: impl Into<String>,
+    ) -> Result<Subscription<EmbeddedStream>, KvError> {
+        let topic = topic.into();
+        let cmd = CommandRequest::new_subscribe(&topic);
+        let result = StreamResult::new(self.execute_streaming(&cmd).await).await?;
+        Ok(Subscription::new(topic, result))
+    }
+
+    /// 取消订阅
+    pub async fn unsubscribe(
+        &self,
+        topic: impl Into<String>,
+        id: u32,
+    ) -> Result<CommandResponse, KvError> {
+        let cmd = CommandRequest::new_unsubscribe(&topic.into(), id);
+        self.execute(&cmd).await
+    }
+
+    /// 监听 table 中 key 的修改，prefix 为 true 时监听所有以 key 开头的 key
+    pub async fn watch(
+        &self,
+        table: impl Into<String>,
+        key: impl AsRef<[u8]>,
+        prefix: bool,
+    ) -> Result<KeyWatch<EmbeddedStream>, KvError> {
+        self.watch_from(table, key, prefix, 0).await
+    }
+
+    /// 和 watch 一样，但先重放从 revision 开始的修改；需要开启 MVCC
+    pub async fn watch_from(
+        &self,
+        table: impl Into<String>,
+        key: impl AsRef<[u8]>,
+        prefix: bool,
+        revision: u64,
+    ) -> Result<KeyWatch<EmbeddedStream>, KvError> {
+        let cmd = CommandRequest::new_hwatch_from(table, key, prefix, revision);
+        let result = StreamResult::new(self.execute_streaming(&cmd).await).await?;
+        Ok(KeyWatch::new(result))
+    }
+
+    /// 取消监听
+    pub async fn unwatch(&self, id: u32) -> Result<CommandResponse, KvError> {
+        self.execute(&CommandRequest::new_hunwatch(id)).await
+    }
+
+    /// 往主题里发布数据
+    pub async fn publish(
+        &self,
+        topic: impl Into<String>,
+        data: Vec<Value>,
+    ) -> Result<CommandResponse, KvError> {
+        let cmd = CommandRequest::new_publish(&topic.into(), data);
+        self.execute(&cmd).await
+    }
+
+    async fn execute_streaming(&self, cmd: &CommandRequest) -> EmbeddedStream {
+        let stream = self
+            .service
+            .execute_with_deadline(cmd.clone(), &self.session)
+            .await;
+        stream.map(|res| Ok(res.as_ref().clone())).boxed()
+    }
+}
+
+impl<Store> Clone for EmbeddedClient<Store> {
+    /// clone 出来的客户端使用新的 session
+    fn clone(&self) -> Self {
+        Self {
+            service: self.service.clone(),
+            session: Default::default(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod embedded_tests {
+    use std::time::Duration;
+
+    use tokio::time;
+
+    use super::*;
+    use crate::{assert_res_ok, service_builder::ServiceBuilder};
+
+    fn client() -> EmbeddedClient {
+        EmbeddedClient::new(ServiceBuilder::default().finish())
+    }
+
+    #[tokio::test]
+    async fn embedded_client_should_execute_commands() {
+        let client = client();
+        let res = client
+            .execute(&CommandRequest::new_hset("t1", "k1", "v1"))
+            .await
+            .unwrap();
+        assert_res_ok(&res, &[Value::default()], &[]);
+        let res = client
+            .execute(&CommandRequest::new_hget("t1", "k1"))
+            .await
+            .unwrap();
+        assert_res_ok(&res, &["v1".into()], &[]);
+        assert_eq!(
+            client.service().store.get("t1", "k1").unwrap(),
+            Some("v1".into())
+        );
+    }
+
+    #[tokio::test]
+    async fn embedded_client_should_subscribe_and_watch() {
+        let client = client();
+        let mut sub = client.subscribe("lobby").await.unwrap();
+        let mut watch = client.watch("t1", "k", true).await.unwrap();
+
+        client.publish("lobby", vec!["hello".into()]).await.unwrap();
+        assert_eq!(sub.next().await.unwrap().unwrap(), [Value::from("hello")]);
+
+        client
+            .execute(&CommandRequest::new_hset("t1", "k1", "v1"))
+            .await
+            .unwrap();
+        let events = watch.next().await.unwrap().unwrap();
+        assert_eq!(events[0].new_value, Some("v1".into()));
+
+        client.unsubscribe("lobby", sub.id()).await.unwrap();
+        client.unwatch(watch.id()).await.unwrap();
+        let next = time::timeout(Duration::from_secs(1), watch.next())
+            .await
+            .unwrap();
+        assert!(next.is_none());
+    }
+}
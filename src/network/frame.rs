@@ -1,23 +1,189 @@
+use super::buffer::BufferPool;
 use crate::{
     error::{IOError, KvError},
     pb::abi::{CommandRequest, CommandResponse},
 };
-use bytes::{Buf, BufMut, BytesMut};
-use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use flate2::{read::GzDecoder, write::GzEncoder};
 use prost::Message;
-use std::io::{Read, Write};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::VecDeque,
+    fmt,
+    io::{Read, Write},
+    str::FromStr,
+    sync::atomic::{AtomicUsize, Ordering},
+};
 use tokio::io::{AsyncRead, AsyncReadExt};
-use tracing::debug;
+use tracing::{debug, instrument};
 
 // 帧头4字节，Length Prefix Message
-pub const Len_Len: usize = 4;
-// 1bit压缩位
-const COMPRESSIONS_BIT: usize = 1 << 31;
-// 长度31bit，所以最大能支持2G大小的frame
-// const MAX_FRAME: usize = 2 * 1024 * 1024 * 1024;
-const MAX_FRAME: usize = 2 << 31 >> 1;
-// 如果payload达到1436字节就做压缩; MTU:1500 - Len_Len:4 - TCP:20 - IP:20 - reserved:20
-const COMPRESSION_LIMIT: usize = 1436;
+pub const LEN_LEN: usize = 4;
+// 帧头最高的 2bit 是压缩算法，以前只有 gzip 时用的是最高位，所以 gzip 是 0b10
+const CODEC_SHIFT: usize = 30;
+// 接下来的 1bit 表示后面还有属于同一个消息的 frame
+const MORE_BIT: usize = 1 << 29;
+// 剩下的 29bit 是长度
+const LEN_MASK: usize = MORE_BIT - 1;
+// 如果payload达到1436字节就做压缩; MTU:1500 - LEN_LEN:4 - TCP:20 - IP:20 - reserved:20
+pub const COMPRESSION_LIMIT: usize = 1436;
+/// 超过这个大小的消息拆成多个 frame 发送，每个 frame 单独压缩，
+/// 收发双方都不需要为整个消息分配一块连续的 frame buffer
+pub const CHUNK_SIZE: usize = 4 * 1024 * 1024;
+/// 默认的消息大小上限，多个 frame 的消息按总大小计算
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 512 * 1024 * 1024;
+
+/// 压缩之前先把消息编码到这里取出的 buffer 中，压缩完就放回去
+static ENCODE_BUFFERS: BufferPool = BufferPool::new(64 * 1024, 16, 4 * CHUNK_SIZE);
+
+/// frame 的压缩算法；每个 frame 的帧头里都带着算法，解码时不需要知道协商的结果
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    None,
+    #[default]
+    Gzip,
+    Lz4,
+    Zstd,
+}
+
+/// 编码 frame 时使用的压缩算法，payload 小于 threshold 时不压缩；
+/// 默认和协商之前的行为一样，使用 gzip
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameCompression {
+    pub codec: Compression,
+    pub threshold: usize,
+}
+
+impl Compression {
+    /// 服务器默认支持的算法，按优先级排列
+    pub const ALL: [Compression; 4] = [Self::Zstd, Self::Lz4, Self::Gzip, Self::None];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::None => "none",
+            Self::Gzip => "gzip",
+            Self::Lz4 => "lz4",
+            Self::Zstd => "zstd",
+        }
+    }
+
+    fn id(&self) -> usize {
+        match self {
+            Self::None => 0b00,
+            Self::Lz4 => 0b01,
+            Self::Gzip => 0b10,
+            Self::Zstd => 0b11,
+        }
+    }
+
+    fn from_id(id: usize) -> Self {
+        match id & 0b11 {
+            0b00 => Self::None,
+            0b01 => Self::Lz4,
+            0b10 => Self::Gzip,
+            _ => Self::Zstd,
+        }
+    }
+
+    fn compress(&self, data: &[u8], buf: &mut BytesMut) -> Result<(), KvError> {
+        match self {
+            Self::None => buf.extend_from_slice(data),
+            Self::Gzip => {
+                let mut encoder = GzEncoder::new(buf.writer(), flate2::Compression::default());
+                encoder.write_all(data).to_error()?;
+                encoder.finish().to_error()?;
+            }
+            // 直接压缩到 buf 中，格式和 compress_prepend_size 一样：前 4 个字节是小端序的原始长度
+            Self::Lz4 => {
+                let start = buf.len() + 4;
+                buf.put_u32_le(data.len() as _);
+                buf.resize(
+                    start + lz4_flex::block::get_maximum_output_size(data.len()),
+                    0,
+                );
+                let len = lz4_flex::block::compress_into(data, &mut buf[start..])
+                    .map_err(|e| KvError::Internal(format!("lz4 error: {}", e)))?;
+                buf.truncate(start + len);
+            }
+            Self::Zstd => {
+                let start = buf.len();
+                buf.resize(start + zstd::zstd_safe::compress_bound(data.len()), 0);
+                let level = zstd::DEFAULT_COMPRESSION_LEVEL;
+                let len = zstd::bulk::compress_to_buffer(data, &mut buf[start..], level)?;
+                buf.truncate(start + len);
+            }
+        }
+        Ok(())
+    }
+
+    fn decompress(&self, data: &[u8]) -> Result<Vec<u8>, KvError> {
+        match self {
+            Self::None => Ok(data.to_vec()),
+            Self::Gzip => {
+                let mut decoder = GzDecoder::new(data);
+                let mut msg_buf = Vec::with_capacity(data.len() * 2);
+                decoder.read_to_end(&mut msg_buf).to_error()?;
+                Ok(msg_buf)
+            }
+            Self::Lz4 => lz4_flex::decompress_size_prepended(data)
+                .map_err(|e| KvError::Internal(format!("lz4 error: {}", e))),
+            // 解压后的大小同样受 max_frame_size 限制
+            Self::Zstd => Ok(zstd::bulk::decompress(data, max_frame_size())?),
+        }
+    }
+}
+
+impl FromStr for Compression {
+    type Err = KvError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|c| c.as_str().eq_ignore_ascii_case(s))
+            .ok_or_else(|| KvError::InvalidCommand(format!("unknown compression {}", s)))
+    }
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FrameCompression {
+    pub fn new(codec: Compression, threshold: Option<usize>) -> Self {
+        Self {
+            codec,
+            threshold: threshold.unwrap_or(COMPRESSION_LIMIT),
+        }
+    }
+}
+
+impl Default for FrameCompression {
+    fn default() -> Self {
+        Self::new(Compression::default(), None)
+    }
+}
+
+/// 读取的 frame 超过这个大小时直接报错，避免对端让我们分配过大的内存；可以在运行时修改
+static MAX_FRAME_SIZE: AtomicUsize = AtomicUsize::new(DEFAULT_MAX_FRAME_SIZE);
+
+pub fn max_frame_size() -> usize {
+    MAX_FRAME_SIZE.load(Ordering::Relaxed)
+}
+
+pub fn set_max_frame_size(size: usize) {
+    MAX_FRAME_SIZE.store(size, Ordering::Relaxed);
+}
+
+/// 超过 max 时返回 FrameTooLarge
+pub fn check_frame_size(size: usize, max: usize) -> Result<(), KvError> {
+    match size > max {
+        true => Err(KvError::FrameTooLarge(size, max)),
+        false => Ok(()),
+    }
+}
 
 /// 实际应用产品开发的时候，可以直接使用`tokio_util::codec::LengthDelimitedCodec`，功能几乎一样的
 /// [LengthDelimitedCodec](https://docs.rs/tokio-util/0.6.8/tokio_util/codec/length_delimited/index.html)
@@ -27,104 +193,266 @@ where
 {
     // 将Message封包(encode)成Frame
     fn encode_frame(&self, buf: &mut BytesMut) -> Result<(), KvError> {
+        self.encode_frame_with(buf, FrameCompression::default())
+    }
+
+    // 使用协商好的压缩算法封包；大的消息拆成多个 frame
+    #[instrument(name = "frame_encode", skip_all)]
+    fn encode_frame_with(
+        &self,
+        buf: &mut BytesMut,
+        compression: FrameCompression,
+    ) -> Result<(), KvError> {
         // return Length of message
         let size = self.encoded_len();
-        buf.put_u32(size as _);
-        // 达到限制，压缩它
-        if size >= COMPRESSION_LIMIT {
-            // cache area
-            let mut msg_cache = Vec::with_capacity(size);
-            self.encode(&mut msg_cache)?;
-
-            // clear LPM, later compressed and merge into it
-            let payload = buf.split_off(Len_Len);
-            buf.clear();
-
-            // process compression for gzip
-            let mut encoder = GzEncoder::new(payload.writer(), Compression::default());
-            encoder.write_all(&msg_cache).to_error()?;
-
-            // done compression.
-            let payload = encoder.finish().to_error()?.into_inner();
-            debug!("Encode a frame: size {}({})", size, payload.len());
-
-            // pushed the compression length
-            buf.put_u32((payload.len() | COMPRESSIONS_BIT) as _);
-
-            // merge BytesMut into buf
-            buf.unsplit(payload);
-        } else {
+        let compressed = compression.codec != Compression::None && size >= compression.threshold;
+        if !compressed && size <= CHUNK_SIZE {
+            buf.put_u32(size as _);
             self.encode(buf)?;
+            return Ok(());
+        }
+
+        // cache area
+        let mut msg_cache = ENCODE_BUFFERS.get();
+        msg_cache.reserve(size);
+        self.encode(&mut msg_cache)?;
+        let mut chunks = msg_cache.chunks(CHUNK_SIZE).peekable();
+        while let Some(chunk) = chunks.next() {
+            let more = chunks.peek().is_some();
+            // 拆开之后的最后一个 frame 可能小于阈值，不用压缩
+            let codec = match chunk.len() >= compression.threshold {
+                true => compression.codec,
+                false => Compression::None,
+            };
+            put_frame(buf, chunk, codec, more)?;
         }
+        ENCODE_BUFFERS.put(msg_cache);
+        debug!(
+            "Encode a frame: size {}({}), {}",
+            size,
+            buf.len(),
+            compression.codec
+        );
 
         Ok(())
     }
 
-    // 将一个Frame解包(decode)成Message
+    /// 和 encode_frame_with 一样封包，但结果是按顺序发送的多段数据：不压缩时 payload 直接从编码的结果中切出来，
+    /// 不需要再复制到帧头后面。buf 必须是空的，返回的数据都引用 buf 的内存，全部释放之后 buf 可以复用这块内存
+    fn encode_frames(
+        &self,
+        buf: &mut BytesMut,
+        compression: FrameCompression,
+    ) -> Result<Vec<Bytes>, KvError> {
+        debug_assert!(buf.is_empty());
+        let size = self.encoded_len();
+        // 压缩本身就要复制一遍数据
+        if compression.codec != Compression::None && size >= compression.threshold {
+            self.encode_frame_with(buf, compression)?;
+            return Ok(vec![buf.split().freeze()]);
+        }
+
+        let count = size.div_ceil(CHUNK_SIZE).max(1);
+        buf.reserve(count * LEN_LEN + size);
+        for i in 0..count {
+            let len = (size - i * CHUNK_SIZE).min(CHUNK_SIZE);
+            let more = if i + 1 < count { MORE_BIT } else { 0 };
+            buf.put_u32((len | more) as _);
+        }
+        let headers = buf.split().freeze();
+        self.encode(buf)?;
+        let payload = buf.split().freeze();
+        let frames = (0..count)
+            .flat_map(|i| {
+                let end = ((i + 1) * CHUNK_SIZE).min(size);
+                [
+                    headers.slice(i * LEN_LEN..(i + 1) * LEN_LEN),
+                    payload.slice(i * CHUNK_SIZE..end),
+                ]
+            })
+            .collect();
+        Ok(frames)
+    }
+
+    // 将一个Frame解包(decode)成Message；buf 里可以是一个消息的多个 frame
     fn decode_frame(buf: &mut BytesMut) -> Result<Self, KvError> {
-        // get 4 byte,And get compressed bit from first bit of it
-        let header = buf.get_u32() as usize;
-        let (len, compressed) = decode_header(header);
-        debug!("Got a frame: msg len {}, compressed {}", len, compressed);
-
-        if compressed {
-            // uncompressed
-            let mut decoder = GzDecoder::new(&buf[..len]);
-            let mut msg_buf = Vec::with_capacity(len * 2);
-            decoder.read_to_end(&mut msg_buf).to_error()?;
-            buf.advance(len);
-
-            Ok(Self::decode(&msg_buf[..msg_buf.len()])?)
-        } else {
-            let msg = Self::decode(&buf[..len])?;
-            buf.advance(len);
-            Ok(msg)
+        let mut frames = Vec::new();
+        loop {
+            let header = u32::from_be_bytes(buf[..LEN_LEN].try_into().unwrap()) as usize;
+            let (len, _, more) = decode_header(header);
+            frames.push(buf.split_to(LEN_LEN + len).freeze());
+            if !more {
+                break;
+            }
+        }
+        Self::decode_frames(frames)
+    }
+
+    // 把 read_message 读到的 frame 解包成 Message；payload 不会拼成一块连续的内存
+    #[instrument(name = "frame_decode", skip_all)]
+    fn decode_frames(frames: Vec<Bytes>) -> Result<Self, KvError> {
+        let mut chunks = Chunks::default();
+        for mut frame in frames {
+            // get 4 byte, and get codec from first 2 bits of it
+            let header = frame.get_u32() as usize;
+            let (len, codec, _) = decode_header(header);
+            debug!("Got a frame: msg len {}, compression {}", len, codec);
+            match codec {
+                Compression::None => chunks.push(frame.slice(..len)),
+                codec => chunks.push(codec.decompress(&frame[..len])?.into()),
+            }
         }
+        Ok(Self::decode(chunks)?)
     }
 }
 
 impl FrameCoder for CommandRequest {}
 impl FrameCoder for CommandResponse {}
 
+/// 多个 frame 的 payload，prost 可以直接从这里解码
+#[derive(Default)]
+struct Chunks {
+    chunks: VecDeque<Bytes>,
+    remaining: usize,
+}
+
+impl Chunks {
+    fn push(&mut self, chunk: Bytes) {
+        self.remaining += chunk.len();
+        self.chunks.push_back(chunk);
+    }
+}
+
+impl Buf for Chunks {
+    fn remaining(&self) -> usize {
+        self.remaining
+    }
+
+    fn chunk(&self) -> &[u8] {
+        self.chunks.front().map(|c| &c[..]).unwrap_or_default()
+    }
+
+    /// prost 解码 bytes 字段时调用，数据在同一个 chunk 里时不复制
+    fn copy_to_bytes(&mut self, len: usize) -> Bytes {
+        match self.chunks.front() {
+            Some(front) if front.len() >= len => {
+                let bytes = front.slice(..len);
+                self.advance(len);
+                bytes
+            }
+            _ => {
+                let mut bytes = BytesMut::with_capacity(len);
+                bytes.put((&mut *self).take(len));
+                bytes.freeze()
+            }
+        }
+    }
+
+    fn advance(&mut self, mut cnt: usize) {
+        self.remaining -= cnt;
+        while cnt > 0 {
+            let front = self.chunks.front_mut().expect("advance past the end");
+            if cnt < front.len() {
+                front.advance(cnt);
+                return;
+            }
+            cnt -= front.len();
+            self.chunks.pop_front();
+        }
+        // 去掉空的 chunk，保证 chunk() 返回的是后面的数据
+        while matches!(self.chunks.front(), Some(c) if c.is_empty()) {
+            self.chunks.pop_front();
+        }
+    }
+}
+
+fn put_frame(
+    buf: &mut BytesMut,
+    data: &[u8],
+    codec: Compression,
+    more: bool,
+) -> Result<(), KvError> {
+    let more = if more { MORE_BIT } else { 0 };
+    if codec == Compression::None {
+        buf.put_u32((data.len() | more) as _);
+        buf.extend_from_slice(data);
+        return Ok(());
+    }
+
+    // 先写压缩后的数据，之后再补上帧头
+    let start = buf.len();
+    buf.put_u32(0);
+    let mut payload = buf.split_off(start + LEN_LEN);
+    codec.compress(data, &mut payload)?;
+    // pushed the compression length
+    let header = payload.len() | more | codec.id() << CODEC_SHIFT;
+    buf[start..].copy_from_slice(&(header as u32).to_be_bytes());
+    // merge BytesMut into buf
+    buf.unsplit(payload);
+    Ok(())
+}
+
 /// reading complete frame from stream
 pub async fn read_fame<S>(stream: &mut S, buf: &mut BytesMut) -> Result<(), KvError>
 where
     S: AsyncRead + Unpin + Send,
 {
-    let header = stream.read_u32().await.to_error()? as usize;
-    let (len, _compressed) = decode_header(header);
-
-    // ensure buffer sufficient of capacity
-    buf.reserve(Len_Len + len);
-    buf.put_u32(header as _);
-    unsafe {
-        buf.advance_mut(len);
+    for frame in read_message(stream, max_frame_size()).await? {
+        buf.extend_from_slice(&frame);
     }
-    // read all
-    stream.read_exact(&mut buf[Len_Len..]).await.to_error()?;
-
     Ok(())
 }
 
+/// 读取一个消息的所有 frame，每个 frame 单独一块内存；总大小超过 max 时返回 FrameTooLarge
+pub async fn read_message<S>(stream: &mut S, max: usize) -> Result<Vec<Bytes>, KvError>
+where
+    S: AsyncRead + Unpin + Send,
+{
+    let mut frames = Vec::new();
+    let mut total = 0;
+    loop {
+        // 对端在两个消息之间关闭连接时保留 UnexpectedEof，方便上层区分正常结束
+        let header = match stream.read_u32().await {
+            Ok(header) => header as usize,
+            Err(e) if frames.is_empty() => return Err(e.into()),
+            Err(e) => return Err(e).to_error(),
+        };
+        let (len, _codec, more) = decode_header(header);
+        total += len;
+        check_frame_size(total, max)?;
+
+        let mut frame = BytesMut::with_capacity(LEN_LEN + len);
+        frame.put_u32(header as _);
+        frame.resize(LEN_LEN + len, 0);
+        // read all
+        stream.read_exact(&mut frame[LEN_LEN..]).await.to_error()?;
+        frames.push(frame.freeze());
+        if !more {
+            return Ok(frames);
+        }
+    }
+}
+
 #[inline]
-fn decode_header(header: usize) -> (usize, bool) {
-    let compressed = header & COMPRESSIONS_BIT == COMPRESSIONS_BIT;
-    let len = header & !COMPRESSIONS_BIT;
-    (len, compressed)
+pub(crate) fn decode_header(header: usize) -> (usize, Compression, bool) {
+    let codec = Compression::from_id(header >> CODEC_SHIFT);
+    let more = header & MORE_BIT == MORE_BIT;
+    let len = header & LEN_MASK;
+    (len, codec, more)
 }
 
 #[cfg(test)]
 mod frame_tests {
 
     use anyhow::{Ok, Result};
-    use bytes::{Bytes, BytesMut};
+    use bytes::{Buf, Bytes, BytesMut};
 
     use crate::{
         network::frame::COMPRESSION_LIMIT,
-        pb::abi::{CommandRequest, CommandResponse, Value},
+        pb::abi::{value, CommandRequest, CommandResponse, Value},
     };
 
-    use super::FrameCoder;
+    use super::{decode_header, Compression, FrameCoder, FrameCompression, CHUNK_SIZE, LEN_LEN};
 
     #[test]
     fn command_request_encode_decode_should_work() -> Result<()> {
@@ -132,7 +460,7 @@ mod frame_tests {
         let cmd = CommandRequest::new_hset("t3", "key3", "test frame");
         cmd.encode_frame(&mut buf)?;
         let compressed = is_compressed(&buf);
-        assert_eq!(compressed, false);
+        assert!(!compressed);
 
         let cmd1 = CommandRequest::decode_frame(&mut buf)?;
         assert_eq!(cmd1, cmd);
@@ -147,7 +475,7 @@ mod frame_tests {
         let cmd: CommandResponse = value.into();
         cmd.encode_frame(&mut buf)?;
         let compressed = is_compressed(&buf);
-        assert_eq!(compressed, true);
+        assert!(compressed);
 
         let cmd1 = CommandResponse::decode_frame(&mut buf)?;
         assert_eq!(cmd1, cmd);
@@ -155,9 +483,109 @@ mod frame_tests {
         Ok(())
     }
 
+    #[test]
+    fn every_compression_should_round_trip() -> Result<()> {
+        let value: Value = Bytes::from(vec![1u8; 4096]).into();
+        let res: CommandResponse = value.into();
+        for codec in Compression::ALL {
+            let mut buf = BytesMut::new();
+            res.encode_frame_with(&mut buf, FrameCompression::new(codec, None))?;
+            let header = u32::from_be_bytes(buf[..4].try_into()?) as usize;
+            let (len, got, more) = decode_header(header);
+            assert_eq!((len + 4, got, more), (buf.len(), codec, false));
+            if codec != Compression::None {
+                assert!(len < 4096);
+            }
+            assert_eq!(CommandResponse::decode_frame(&mut buf)?, res);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn small_frames_should_skip_compression() -> Result<()> {
+        let cmd = CommandRequest::new_hset("t1", "k1", "v".repeat(200));
+        let mut buf = BytesMut::new();
+        cmd.encode_frame_with(
+            &mut buf,
+            FrameCompression::new(Compression::Zstd, Some(1024)),
+        )?;
+        assert!(!is_compressed(&buf));
+        cmd.encode_frame_with(
+            &mut buf,
+            FrameCompression::new(Compression::Zstd, Some(100)),
+        )?;
+        assert_eq!(CommandRequest::decode_frame(&mut buf)?, cmd);
+        assert!(is_compressed(&buf));
+        assert_eq!(CommandRequest::decode_frame(&mut buf)?, cmd);
+
+        Ok(())
+    }
+
+    #[test]
+    fn large_message_should_be_split_into_frames() -> Result<()> {
+        let value: Value = Bytes::from(vec![7u8; CHUNK_SIZE * 2 + 1]).into();
+        let res: CommandResponse = value.into();
+        for codec in [Compression::None, Compression::Zstd] {
+            let mut buf = BytesMut::new();
+            res.encode_frame_with(&mut buf, FrameCompression::new(codec, None))?;
+
+            let mut data = buf.clone();
+            let mut frames = vec![];
+            while !data.is_empty() {
+                let header = u32::from_be_bytes(data[..4].try_into()?) as usize;
+                let (len, _, more) = decode_header(header);
+                data.advance(LEN_LEN + len);
+                frames.push(more);
+            }
+            assert_eq!(frames, [true, true, false]);
+            assert_eq!(CommandResponse::decode_frame(&mut buf)?, res);
+            assert!(buf.is_empty());
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn encode_frames_should_not_copy_payload() -> Result<()> {
+        let value: Value = Bytes::from(vec![7u8; CHUNK_SIZE * 2 + 1]).into();
+        let res: CommandResponse = value.into();
+        for codec in [Compression::None, Compression::Lz4] {
+            let compression = FrameCompression::new(codec, None);
+            let mut expected = BytesMut::new();
+            res.encode_frame_with(&mut expected, compression)?;
+
+            // 多段数据连起来和 encode_frame_with 的结果一样
+            let mut buf = BytesMut::new();
+            let frames = res.encode_frames(&mut buf, compression)?;
+            assert_eq!(frames.concat(), expected);
+            assert!(buf.is_empty());
+        }
+
+        // 解码时 bytes 字段直接引用 frame 的内存，跨 frame 的数据才需要复制
+        let value: Value = Bytes::from(vec![7u8; CHUNK_SIZE / 2]).into();
+        let res: CommandResponse = value.into();
+        let mut buf = BytesMut::new();
+        res.encode_frame_with(&mut buf, FrameCompression::new(Compression::None, None))?;
+        let frame = buf.freeze();
+        let decoded = CommandResponse::decode_frames(vec![frame.clone()])?;
+        assert_eq!(decoded, res);
+        let Some(value::Value::Binary(data)) = &decoded.values[0].value else {
+            panic!("value should be binary");
+        };
+        assert!(frame.as_ptr_range().contains(&data.as_ptr()));
+        Ok(())
+    }
+
+    #[test]
+    fn compression_should_be_parsed() {
+        assert_eq!("LZ4".parse::<Compression>().unwrap(), Compression::Lz4);
+        assert!("brotli".parse::<Compression>().is_err());
+    }
+
     fn is_compressed(data: &[u8]) -> bool {
         if let &[v] = &data[..1] {
-            v >> 7 == 1
+            v >> 6 != 0
         } else {
             false
         }
@@ -167,11 +595,14 @@ mod frame_tests {
 #[cfg(test)]
 mod fn_test_of_read_fame {
     use bytes::BytesMut;
-    use tokio::io::AsyncRead;
 
-    use crate::{pb::abi::CommandRequest, test_utils::DummyStream};
+    use crate::{
+        error::KvError,
+        pb::abi::{CommandRequest, CommandResponse, Value},
+        test_utils::DummyStream,
+    };
 
-    use super::{read_fame, FrameCoder};
+    use super::{read_fame, read_message, FrameCoder, FrameCompression, CHUNK_SIZE};
 
     #[tokio::test]
     async fn read_frame_should_work() {
@@ -189,4 +620,23 @@ mod fn_test_of_read_fame {
 
         assert_eq!(cmd, cmd1)
     }
+
+    #[tokio::test]
+    async fn read_message_should_check_total_size() {
+        let value: Value = vec![1u8; CHUNK_SIZE + 1].as_slice().into();
+        let res: CommandResponse = value.into();
+        let mut buf = BytesMut::new();
+        let compression = FrameCompression::new(Default::default(), Some(usize::MAX));
+        res.encode_frame_with(&mut buf, compression).unwrap();
+
+        let mut stream = DummyStream { buf: buf.clone() };
+        let frames = read_message(&mut stream, CHUNK_SIZE * 2).await.unwrap();
+        assert_eq!(frames.len(), 2);
+        assert_eq!(CommandResponse::decode_frames(frames).unwrap(), res);
+
+        // 每个 frame 都没有超过限制，但是加起来超过了
+        let mut stream = DummyStream { buf };
+        let res = read_message(&mut stream, CHUNK_SIZE + 1).await;
+        assert!(matches!(res, Err(KvError::FrameTooLarge(_, max)) if max == CHUNK_SIZE + 1));
+    }
 }
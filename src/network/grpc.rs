@@ -0,0 +1,445 @@
+use std::{
+    io,
+    marker::PhantomData,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use futures::{stream, Stream, StreamExt};
+use prost::Message;
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::{TcpListener, TcpStream},
+};
+use tokio_stream::wrappers::TcpListenerStream;
+use tonic::{
+    codec::{Codec, DecodeBuf, Decoder, EncodeBuf, Encoder},
+    metadata::MetadataMap,
+    transport::server::{Connected, TcpConnectInfo},
+    Request, Response, Status,
+};
+use tracing::warn;
+
+use super::{
+    conn_limit::{ConnectionGuard, ConnectionLimiter},
+    http::basic_auth,
+};
+use crate::{
+    error::KvError,
+    pb::abi::{CommandRequest, CommandResponse, Hscan, Subscribe},
+    session::Session,
+    Service, Storage,
+};
+
+#[allow(clippy::all)]
+mod generated {
+    include!(concat!(env!("OUT_DIR"), "/abi.KvService.rs"));
+}
+
+pub use generated::{
+    kv_service_client::KvServiceClient,
+    kv_service_server::{KvService, KvServiceServer},
+};
+
+type GrpcStream = std::pin::Pin<Box<dyn Stream<Item = Result<CommandResponse, Status>> + Send>>;
+
+/// 使用 prost 0.8 编解码的 tonic codec，和自定义的 frame 格式使用同一套消息
+#[derive(Debug)]
+pub struct ProstCodec<T, U>(PhantomData<(T, U)>);
+
+impl<T, U> Default for ProstCodec<T, U> {
+    fn default() -> Self {
+        Self(PhantomData)
+    }
+}
+
+impl<T, U> Codec for ProstCodec<T, U>
+where
+    T: Message + Send + 'static,
+    U: Message + Default + Send + 'static,
+{
+    type Encode = T;
+    type Decode = U;
+    type Encoder = ProstEncoder<T>;
+    type Decoder = ProstDecoder<U>;
+
+    fn encoder(&mut self) -> Self::Encoder {
+        ProstEncoder(PhantomData)
+    }
+
+    fn decoder(&mut self) -> Self::Decoder {
+        ProstDecoder(PhantomData)
+    }
+}
+
+#[derive(Debug)]
+pub struct ProstEncoder<T>(PhantomData<T>);
+
+impl<T: Message> Encoder for ProstEncoder<T> {
+    type Item = T;
+    type Error = Status;
+
+    fn encode(&mut self, item: T, dst: &mut EncodeBuf<'_>) -> Result<(), Status> {
+        item.encode(dst)
+            .map_err(|e| Status::internal(format!("Failed to encode message: {}", e)))
+    }
+}
+
+#[derive(Debug)]
+pub struct ProstDecoder<U>(PhantomData<U>);
+
+impl<U: Message + Default> Decoder for ProstDecoder<U> {
+    type Item = U;
+    type Error = Status;
+
+    fn decode(&mut self, src: &mut DecodeBuf<'_>) -> Result<Option<U>, Status> {
+        U::decode(src)
+            .map(Some)
+            .map_err(|e| Status::invalid_argument(format!("Failed to decode message: {}", e)))
+    }
+}
+
+/// 把 Service 暴露成 gRPC service：普通命令是 unary 调用，HSCAN 和 SUBSCRIBE 是 server streaming
+pub struct GrpcService<Store> {
+    service: Service<Store>,
+}
+
+impl<Store: Storage> GrpcService<Store> {
+    pub fn new(service: Service<Store>) -> Self {
+        Self { service }
+    }
+
+    pub fn into_server(self) -> KvServiceServer<Self> {
+        KvServiceServer::new(self)
+    }
+
+    /// gRPC 请求之间没有共享的连接状态，每个请求都通过 authorization metadata 单独认证
+    async fn session(&self, metadata: &MetadataMap) -> Result<Arc<Session>, Status> {
+        let session = Arc::new(Session::new());
+        let Some(auth) = metadata.get("authorization") else {
+            return Ok(session);
+        };
+        let res = match basic_auth(auth.as_bytes()) {
+            Some((username, password)) => {
+                let cmd = CommandRequest::new_auth(username, password);
+                self.execute(cmd, &session).await
+            }
+            None => KvError::Unauthorized("invalid authorization metadata".into()).into(),
+        };
+        match res.status {
+            200 => Ok(session),
+            _ => Err(Status::unauthenticated(res.message)),
+        }
+    }
+
+    /// 只返回第一帧，所以不能让服务器流式返回
+    async fn execute(&self, mut cmd: CommandRequest, session: &Arc<Session>) -> CommandResponse {
+        cmd.stream = false;
+        let _permit = self.service.in_flight.acquire(session).await;
+        let _in_flight = self.service.shutdown().start();
+        let mut res = self.service.execute_with_deadline(cmd, session).await;
+        match res.next().await {
+            Some(res) => res.as_ref().clone(),
+            None => KvError::Internal("Didn't get any response".into()).into(),
+        }
+    }
+}
+
+/// 接受 gRPC 连接，超过连接数限制的连接直接关闭；gRPC 要先完成 HTTP/2 握手才能返回错误
+pub fn incoming(
+    listener: TcpListener,
+    limiter: ConnectionLimiter,
+) -> impl Stream<Item = io::Result<LimitedStream>> {
+    TcpListenerStream::new(listener).filter_map(move |stream| {
+        let limiter = limiter.clone();
+        async move {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => return Some(Err(e)),
+            };
+            let addr = stream.peer_addr().ok();
+            match limiter.try_acquire(addr.map(|addr| addr.ip())) {
+                Ok(conn) => Some(Ok(LimitedStream {
+                    stream,
+                    _conn: conn,
+                })),
+                Err(e) => {
+                    warn!("Reject gRPC client {:?}: {}", addr, e);
+                    None
+                }
+            }
+        }
+    })
+}
+
+/// 占用一个连接数配额的 TCP 连接，连接关闭之后释放
+pub struct LimitedStream {
+    stream: TcpStream,
+    _conn: ConnectionGuard,
+}
+
+impl Connected for LimitedStream {
+    type ConnectInfo = TcpConnectInfo;
+
+    fn connect_info(&self) -> TcpConnectInfo {
+        self.stream.connect_info()
+    }
+}
+
+impl AsyncRead for LimitedStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for LimitedStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
+
+// tonic 的接口都是返回 Status
+#[allow(clippy::result_large_err)]
+#[tonic::async_trait]
+impl<Store: Storage> KvService for GrpcService<Store> {
+    async fn execute(
+        &self,
+        request: Request<CommandRequest>,
+    ) -> Result<Response<CommandResponse>, Status> {
+        let session = self.session(request.metadata()).await?;
+        let res = self.execute(request.into_inner(), &session).await;
+        Ok(Response::new(res))
+    }
+
+    type ScanStream = GrpcStream;
+
+    /// 从 cursor 开始逐页返回，直到 table 的最后一页
+    async fn scan(&self, request: Request<Hscan>) -> Result<Response<GrpcStream>, Status> {
+        let session = self.session(request.metadata()).await?;
+        let Hscan {
+            table,
+            cursor,
+            count,
+        } = request.into_inner();
+        let service = self.service.clone();
+        let pages = stream::unfold(Some(cursor), move |cursor| {
+            let (service, session, table) = (service.clone(), session.clone(), table.clone());
+            async move {
+                let cmd = CommandRequest::new_hscan(table, cursor?, count);
+                let mut res = service.execute_with_deadline(cmd, &session).await;
+                let res = match res.next().await {
+                    Some(res) => res.as_ref().clone(),
+                    None => KvError::Internal("Didn't get any response".into()).into(),
+                };
+                let next = match res.values.first().and_then(|v| v.to_integer()) {
+                    Some(next) if res.status == 200 && next > 0 => Some(next as u64),
+                    _ => None,
+                };
+                Some((Ok(res), next))
+            }
+        });
+        Ok(Response::new(Box::pin(pages)))
+    }
+
+    type SubscribeStream = GrpcStream;
+
+    /// 和其它连接一样经过 execute_with_deadline，租户只能收到自己的 topic 和 table 上的通知；
+    /// 推送不会结束，发出第一帧之后归还配额，退出时结束
+    async fn subscribe(&self, request: Request<Subscribe>) -> Result<Response<GrpcStream>, Status> {
+        let session = self.session(request.metadata()).await?;
+        let cmd = CommandRequest::new_subscribe(&request.into_inner().topic);
+        let shutdown = self.service.shutdown().clone();
+        let in_flight = shutdown.start();
+        let permit = self.service.in_flight.acquire(&session).await;
+        let res = self.service.execute_with_deadline(cmd, &session).await;
+        let pushes = stream::unfold(
+            (res, Some(permit), in_flight),
+            move |(mut res, mut permit, in_flight)| {
+                let shutdown = shutdown.clone();
+                async move {
+                    let data = tokio::select! {
+                        biased;
+                        data = res.next() => data,
+                        _ = shutdown.triggered() => None,
+                    };
+                    permit.take();
+                    Some((Ok(data?.as_ref().clone()), (res, permit, in_flight)))
+                }
+            },
+        );
+        Ok(Response::new(Box::pin(pushes)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use base64::{engine::general_purpose::STANDARD, Engine};
+    use tokio::{io::AsyncReadExt, time};
+    use tonic::transport::{Channel, Server};
+
+    use super::*;
+    use crate::{
+        assert_res_ok,
+        config::{AuthConfig, NotificationConfig, TenantConfig},
+        pb::abi::Kvpair,
+        service_builder::ServiceBuilder,
+    };
+
+    #[tokio::test]
+    async fn grpc_service_should_work() {
+        let mut client = start_grpc_server().await;
+
+        let cmd = CommandRequest::new_hset("t1", "k1", "v1");
+        let res = client.execute(cmd).await.unwrap().into_inner();
+        assert_res_ok(&res, &[Default::default()], &[]);
+        let cmd = CommandRequest::new_hget("t1", "k1");
+        let res = client.execute(cmd).await.unwrap().into_inner();
+        assert_res_ok(&res, &["v1".into()], &[]);
+    }
+
+    #[tokio::test]
+    async fn grpc_connections_should_be_limited() {
+        let service: Service = ServiceBuilder::default().finish();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let limiter = ConnectionLimiter::new(Some(1), None);
+        tokio::spawn(
+            Server::builder()
+                .add_service(GrpcService::new(service).into_server())
+                .serve_with_incoming(incoming(listener, limiter)),
+        );
+
+        let mut client = KvServiceClient::connect(format!("http://{}", addr))
+            .await
+            .unwrap();
+        let cmd = CommandRequest::new_hget("t1", "k1");
+        assert!(client.execute(cmd).await.is_ok());
+        // 第二个连接超过了限制，直接被关闭
+        let mut other = TcpStream::connect(addr).await.unwrap();
+        let mut buf = [0; 16];
+        let n = time::timeout(Duration::from_secs(1), other.read(&mut buf)).await;
+        assert!(matches!(n, Ok(Ok(0)) | Ok(Err(_))));
+    }
+
+    #[tokio::test]
+    async fn grpc_scan_should_stream_all_pages() {
+        let mut client = start_grpc_server().await;
+        for i in 0..5i64 {
+            let cmd = CommandRequest::new_hset("t1", format!("k{}", i), i);
+            client.execute(cmd).await.unwrap();
+        }
+
+        let req = Hscan {
+            table: "t1".into(),
+            cursor: 0,
+            count: 2,
+        };
+        let pages: Vec<_> = client
+            .scan(req)
+            .await
+            .unwrap()
+            .into_inner()
+            .map(|res| res.unwrap().pairs)
+            .collect()
+            .await;
+        assert_eq!(pages.iter().map(Vec::len).collect::<Vec<_>>(), [2, 2, 1]);
+        let mut pairs: Vec<Kvpair> = pages.into_iter().flatten().collect();
+        pairs.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        assert_eq!(pairs[4], Kvpair::new("k4", 4i64.into()));
+    }
+
+    #[tokio::test]
+    async fn grpc_subscribe_should_only_push_own_tenant_events() {
+        let auth: AuthConfig = toml::from_str(
+            r#"
+            users = [{ name = "alice", password = "a" }, { name = "bob", password = "b" }]
+            "#,
+        )
+        .unwrap();
+        let tenants = [
+            TenantConfig {
+                name: "billing".into(),
+                users: vec!["alice".into()],
+            },
+            TenantConfig {
+                name: "orders".into(),
+                users: vec!["bob".into()],
+            },
+        ];
+        let notifications = NotificationConfig {
+            keyspace: false,
+            keyevent: true,
+        };
+        let service: Service = ServiceBuilder::default()
+            .auth(Some(auth))
+            .tenants(tenants[..].try_into().unwrap())
+            .notifications(notifications)
+            .finish();
+        let mut client = start_grpc_server_with(service).await;
+        let topic = "__keyevent__:set".into();
+        let req = as_user("alice", "a", Subscribe { topic });
+        let mut events = client.subscribe(req).await.unwrap().into_inner();
+        // 第一帧是订阅 id
+        assert_eq!(events.next().await.unwrap().unwrap().status, 200);
+
+        // bob 写入同名的 table，alice 收不到
+        let cmd = CommandRequest::new_hset("t1", "k1", "v1");
+        client
+            .execute(as_user("bob", "b", cmd.clone()))
+            .await
+            .unwrap();
+        client.execute(as_user("alice", "a", cmd)).await.unwrap();
+        let res = events.next().await.unwrap().unwrap();
+        assert_res_ok(&res, &["t1".into(), "k1".into()], &[]);
+        let next = time::timeout(Duration::from_millis(100), events.next()).await;
+        assert!(next.is_err());
+    }
+
+    /// 带上 basic auth 的 authorization metadata
+    fn as_user<T>(user: &str, password: &str, req: T) -> Request<T> {
+        let mut req = Request::new(req);
+        let auth = format!(
+            "Basic {}",
+            STANDARD.encode(format!("{}:{}", user, password))
+        );
+        req.metadata_mut()
+            .insert("authorization", auth.parse().unwrap());
+        req
+    }
+
+    async fn start_grpc_server() -> KvServiceClient<Channel> {
+        start_grpc_server_with(ServiceBuilder::default().finish()).await
+    }
+
+    async fn start_grpc_server_with(service: Service) -> KvServiceClient<Channel> {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(
+            Server::builder()
+                .add_service(GrpcService::new(service).into_server())
+                .serve_with_incoming(TcpListenerStream::new(listener)),
+        );
+        KvServiceClient::connect(format!("http://{}", addr))
+            .await
+            .unwrap()
+    }
+}
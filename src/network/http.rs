@@ -0,0 +1,462 @@
+use std::{convert::Infallible, sync::Arc};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use bytes::Bytes;
+use futures::StreamExt;
+use http_body_util::{BodyExt, Full};
+use hyper::{
+    body::Incoming,
+    header::{AUTHORIZATION, CONTENT_TYPE},
+    server::conn::http1,
+    service::service_fn,
+    Method, Request, Response, StatusCode,
+};
+use hyper_util::rt::TokioIo;
+use percent_encoding::percent_decode_str;
+use serde_json::json;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tracing::debug;
+
+use crate::{
+    error::KvError,
+    pb::abi::{value, CommandRequest, CommandResponse, Value},
+    session::Session,
+    Service, Storage,
+};
+
+/// HSCAN 没有指定 count 时，每页返回的数量
+const DEFAULT_SCAN_COUNT: u64 = 10;
+
+/// 把 REST 请求翻译成 CommandRequest，方便 curl、浏览器等不使用 prost 客户端的调用方
+pub struct HttpGateway<Store> {
+    service: Service<Store>,
+}
+
+impl<Store> Clone for HttpGateway<Store> {
+    fn clone(&self) -> Self {
+        Self {
+            service: self.service.clone(),
+        }
+    }
+}
+
+/// 一个 REST 请求对应的命令，响应的格式由请求的类型决定
+#[derive(Debug, PartialEq)]
+enum Route {
+    /// GET /v1/{table}/{key}
+    Get(CommandRequest),
+    /// PUT /v1/{table}/{key}?ttl=
+    Put(CommandRequest),
+    /// DELETE /v1/{table}/{key}
+    Delete(CommandRequest),
+    /// GET /v1/{table}?cursor=&count=
+    Scan(CommandRequest),
+    /// GET /healthz，进程还在处理请求就返回 200
+    Healthz,
+    /// GET /readyz，可以接收流量时返回 200，否则返回 503
+    Readyz,
+}
+
+impl<Store: Storage> HttpGateway<Store> {
+    pub fn new(service: Service<Store>) -> Self {
+        Self { service }
+    }
+
+    /// 处理一个 HTTP/1 连接，直到客户端断开；开始退出之后处理完当前的请求就关闭连接
+    pub async fn serve<S>(self, stream: S) -> Result<(), KvError>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let shutdown = self.service.shutdown().clone();
+        let service = service_fn(move |req| self.clone().handle(req));
+        let conn = http1::Builder::new().serve_connection(TokioIo::new(stream), service);
+        tokio::pin!(conn);
+        let result = tokio::select! {
+            result = conn.as_mut() => result,
+            _ = shutdown.triggered() => {
+                conn.as_mut().graceful_shutdown();
+                conn.await
+            }
+        };
+        result.map_err(|e| KvError::Internal(format!("HTTP connection error: {}", e)))
+    }
+
+    async fn handle(self, req: Request<Incoming>) -> Result<Response<Full<Bytes>>, Infallible> {
+        let _in_flight = self.service.shutdown().start();
+        let (parts, body) = req.into_parts();
+        debug!("Got http request {} {}", parts.method, parts.uri);
+        let body = match body.collect().await {
+            Ok(body) => body.to_bytes(),
+            Err(_) => return Ok(status_response(StatusCode::BAD_REQUEST)),
+        };
+        let route = match route(&parts.method, parts.uri.path(), parts.uri.query(), body) {
+            Ok(route) => route,
+            Err(status) => return Ok(status_response(status)),
+        };
+
+        // HTTP 没有连接级别的状态，每个请求都要单独认证
+        let session = Arc::new(Session::new());
+        if let Some(auth) = parts.headers.get(AUTHORIZATION) {
+            let res = match basic_auth(auth.as_bytes()) {
+                Some((username, password)) => {
+                    self.execute(CommandRequest::new_auth(username, password), &session)
+                        .await
+                }
+                None => KvError::Unauthorized("invalid authorization header".into()).into(),
+            };
+            if res.status != StatusCode::OK.as_u16() as u32 {
+                return Ok(error_response(&res));
+            }
+        }
+
+        let res = match route {
+            Route::Get(cmd) => {
+                let res = self.execute(cmd, &session).await;
+                match res.values.first() {
+                    Some(v) if is_ok(&res) => value_response(StatusCode::OK, v),
+                    _ => error_response(&res),
+                }
+            }
+            // PUT 新建时返回 201，DELETE 不存在时返回 404
+            Route::Put(cmd) => self.write(cmd, &session, StatusCode::CREATED).await,
+            Route::Delete(cmd) => self.write(cmd, &session, StatusCode::NOT_FOUND).await,
+            Route::Scan(cmd) => {
+                let res = self.execute(cmd, &session).await;
+                if is_ok(&res) {
+                    scan_response(&res)
+                } else {
+                    error_response(&res)
+                }
+            }
+            Route::Healthz => text_response(StatusCode::OK, "ok".into()),
+            Route::Readyz => match self.service.ready() {
+                Ok(()) => text_response(StatusCode::OK, "ok".into()),
+                Err(e) => text_response(StatusCode::SERVICE_UNAVAILABLE, e.to_string().into()),
+            },
+        };
+        Ok(res)
+    }
+
+    /// 之前有值时返回 200 和之前的值，否则返回 missing
+    async fn write(
+        &self,
+        cmd: CommandRequest,
+        session: &Arc<Session>,
+        missing: StatusCode,
+    ) -> Response<Full<Bytes>> {
+        let res = self.execute(cmd, session).await;
+        match res.values.first() {
+            Some(v) if is_ok(&res) && v.value.is_some() => value_response(StatusCode::OK, v),
+            Some(_) if is_ok(&res) => status_response(missing),
+            _ => error_response(&res),
+        }
+    }
+
+    /// 只返回第一帧，所以不能让服务器流式返回
+    async fn execute(&self, mut cmd: CommandRequest, session: &Arc<Session>) -> CommandResponse {
+        cmd.stream = false;
+        let _permit = self.service.in_flight.acquire(session).await;
+        let mut res = self.service.execute_with_deadline(cmd, session).await;
+        match res.next().await {
+            Some(res) => res.as_ref().clone(),
+            None => KvError::Internal("Didn't get any response".into()).into(),
+        }
+    }
+}
+
+/// 根据 method 和 path 找到对应的命令
+fn route(
+    method: &Method,
+    path: &str,
+    query: Option<&str>,
+    body: Bytes,
+) -> Result<Route, StatusCode> {
+    match (method, path) {
+        (&Method::GET, "/healthz") => return Ok(Route::Healthz),
+        (&Method::GET, "/readyz") => return Ok(Route::Readyz),
+        _ => {}
+    }
+    let path = path.strip_prefix("/v1/").ok_or(StatusCode::NOT_FOUND)?;
+    let (table, key) = match path.split_once('/') {
+        Some((table, key)) => (table, Some(key)),
+        None => (path, None),
+    };
+    let table = decode(table)?;
+    // key 可以是任意的字节，不要求解码之后是合法的 utf8
+    let key: Option<Vec<u8>> = key.map(|key| percent_decode_str(key).collect());
+    if table.is_empty() || key.as_ref().is_some_and(Vec::is_empty) {
+        return Err(StatusCode::NOT_FOUND);
+    }
+
+    match (method, key) {
+        (&Method::GET, Some(key)) => Ok(Route::Get(CommandRequest::new_hget(table, key))),
+        (&Method::PUT, Some(key)) => {
+            let value = Value::from(&body);
+            let cmd = match query_param(query, "ttl")? {
+                Some(ttl) => CommandRequest::new_hset_ex(table, key, value, ttl),
+                None => CommandRequest::new_hset(table, key, value),
+            };
+            Ok(Route::Put(cmd))
+        }
+        (&Method::DELETE, Some(key)) => Ok(Route::Delete(CommandRequest::new_hdel(table, key))),
+        (&Method::GET, None) => {
+            let cursor = query_param(query, "cursor")?.unwrap_or_default();
+            let count = query_param(query, "count")?.unwrap_or(DEFAULT_SCAN_COUNT);
+            Ok(Route::Scan(CommandRequest::new_hscan(table, cursor, count)))
+        }
+        _ => Err(StatusCode::METHOD_NOT_ALLOWED),
+    }
+}
+
+fn decode(s: &str) -> Result<String, StatusCode> {
+    percent_decode_str(s)
+        .decode_utf8()
+        .map(|s| s.into_owned())
+        .map_err(|_| StatusCode::BAD_REQUEST)
+}
+
+/// 读取 query 里的整数参数，没有这个参数时返回 None
+fn query_param(query: Option<&str>, name: &str) -> Result<Option<u64>, StatusCode> {
+    let Some(query) = query else {
+        return Ok(None);
+    };
+    for pair in query.split('&') {
+        if let Some((k, v)) = pair.split_once('=') {
+            if k == name {
+                return v.parse().map(Some).map_err(|_| StatusCode::BAD_REQUEST);
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// 解析 `Authorization: Basic base64(username:password)`
+pub(crate) fn basic_auth(header: &[u8]) -> Option<(String, String)> {
+    let encoded = header.strip_prefix(b"Basic ")?;
+    let decoded = String::from_utf8(STANDARD.decode(encoded).ok()?).ok()?;
+    let (username, password) = decoded.split_once(':')?;
+    Some((username.into(), password.into()))
+}
+
+/// 连接数超过限制时，对客户端的请求返回错误，然后关闭连接
+pub async fn reject<S>(stream: S, e: KvError)
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let res = CommandResponse::from(e);
+    let service = service_fn(move |_| {
+        let res = error_response(&res);
+        async move { Ok::<_, Infallible>(res) }
+    });
+    let conn = http1::Builder::new()
+        .keep_alive(false)
+        .serve_connection(TokioIo::new(stream), service);
+    if let Err(e) = conn.await {
+        debug!("Failed to reject HTTP client: {:?}", e);
+    }
+}
+
+fn is_ok(res: &CommandResponse) -> bool {
+    res.status == StatusCode::OK.as_u16() as u32
+}
+
+fn status_response(status: StatusCode) -> Response<Full<Bytes>> {
+    let reason = status.canonical_reason().unwrap_or_default();
+    text_response(status, reason.into())
+}
+
+fn error_response(res: &CommandResponse) -> Response<Full<Bytes>> {
+    let status =
+        StatusCode::from_u16(res.status as u16).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+    text_response(status, res.message.clone().into())
+}
+
+fn text_response(status: StatusCode, body: Bytes) -> Response<Full<Bytes>> {
+    let mut res = Response::new(Full::new(body));
+    *res.status_mut() = status;
+    res.headers_mut()
+        .insert(CONTENT_TYPE, "text/plain; charset=utf-8".parse().unwrap());
+    res
+}
+
+/// 单个 value 直接作为 body 返回，binary 使用 application/octet-stream，list/set/map/json 使用 json
+fn value_response(status: StatusCode, v: &Value) -> Response<Full<Bytes>> {
+    let body = match &v.value {
+        Some(value::Value::String(s)) => s.clone().into(),
+        Some(value::Value::Binary(b)) => b.clone(),
+        Some(value::Value::Integer(i)) => i.to_string().into(),
+        Some(value::Value::Float(f)) => f.to_string().into(),
+        Some(value::Value::Bool(b)) => b.to_string().into(),
+        Some(value::Value::List(_) | value::Value::Set(_) | value::Value::Map(_)) => {
+            to_json(Some(v)).to_string().into()
+        }
+        Some(value::Value::Json(text)) => text.clone().into(),
+        None => Bytes::new(),
+    };
+    let mut res = text_response(status, body);
+    let content_type = match &v.value {
+        Some(value::Value::Binary(_)) => "application/octet-stream",
+        Some(
+            value::Value::List(_)
+            | value::Value::Set(_)
+            | value::Value::Map(_)
+            | value::Value::Json(_),
+        ) => "application/json",
+        _ => return res,
+    };
+    res.headers_mut()
+        .insert(CONTENT_TYPE, content_type.parse().unwrap());
+    res
+}
+
+/// `{"cursor": 下一页的 cursor，0 表示没有更多数据, "pairs": [{"key": .., "value": ..}]}`，
+/// 不是合法 utf8 的 key 和 binary 的 value 一样使用 base64 编码
+fn scan_response(res: &CommandResponse) -> Response<Full<Bytes>> {
+    let cursor = res
+        .values
+        .first()
+        .and_then(Value::to_integer)
+        .unwrap_or_default();
+    let pairs: Vec<_> = res
+        .pairs
+        .iter()
+        .map(|pair| {
+            let key = to_json(Some(&Value::from(&pair.key)));
+            json!({ "key": key, "value": to_json(pair.value.as_ref()) })
+        })
+        .collect();
+    let body = json!({ "cursor": cursor, "pairs": pairs }).to_string();
+    let mut res = text_response(StatusCode::OK, body.into());
+    res.headers_mut()
+        .insert(CONTENT_TYPE, "application/json".parse().unwrap());
+    res
+}
+
+/// binary 没法直接放进 json，使用 base64 编码；list 和 set 是数组，map 是对象，json 原样嵌入
+pub(crate) fn to_json(v: Option<&Value>) -> serde_json::Value {
+    let array = |values: &[Value]| values.iter().map(|v| to_json(Some(v))).collect();
+    match v.and_then(|v| v.value.as_ref()) {
+        Some(value::Value::List(list)) => serde_json::Value::Array(array(&list.values)),
+        Some(value::Value::Set(set)) => serde_json::Value::Array(array(&set.members)),
+        Some(value::Value::Map(map)) => map
+            .entries
+            .iter()
+            .map(|(k, v)| (k.clone(), to_json(Some(v))))
+            .collect(),
+        Some(value::Value::String(s)) => json!(s),
+        Some(value::Value::Binary(b)) => json!(STANDARD.encode(b)),
+        Some(value::Value::Integer(i)) => json!(i),
+        Some(value::Value::Float(f)) => json!(f),
+        Some(value::Value::Bool(b)) => json!(b),
+        Some(value::Value::Json(text)) => {
+            serde_json::from_str(text).unwrap_or_else(|_| json!(text))
+        }
+        None => serde_json::Value::Null,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+    use crate::service_builder::ServiceBuilder;
+
+    #[test]
+    fn route_should_translate_rest_requests() {
+        let get = route(&Method::GET, "/v1/t1/a%20b", None, Bytes::new());
+        assert_eq!(get, Ok(Route::Get(CommandRequest::new_hget("t1", "a b"))));
+        let get = route(&Method::GET, "/v1/t1/%FF%00", None, Bytes::new());
+        let expected = CommandRequest::new_hget("t1", [0xff, 0]);
+        assert_eq!(get, Ok(Route::Get(expected)));
+
+        let put = route(&Method::PUT, "/v1/t1/k1", Some("ttl=10"), "v1".into());
+        let expected = CommandRequest::new_hset_ex("t1", "k1", "v1", 10);
+        assert_eq!(put, Ok(Route::Put(expected)));
+
+        let scan = route(&Method::GET, "/v1/t1", Some("cursor=20"), Bytes::new());
+        let expected = CommandRequest::new_hscan("t1", 20, DEFAULT_SCAN_COUNT);
+        assert_eq!(scan, Ok(Route::Scan(expected)));
+
+        let res = route(&Method::POST, "/v1/t1/k1", None, Bytes::new());
+        assert_eq!(res, Err(StatusCode::METHOD_NOT_ALLOWED));
+        let res = route(&Method::GET, "/v2/t1/k1", None, Bytes::new());
+        assert_eq!(res, Err(StatusCode::NOT_FOUND));
+        let res = route(&Method::GET, "/v1/t1", Some("cursor=abc"), Bytes::new());
+        assert_eq!(res, Err(StatusCode::BAD_REQUEST));
+    }
+
+    #[test]
+    fn basic_auth_should_be_parsed() {
+        let header = format!("Basic {}", STANDARD.encode("alice:secret"));
+        let expected = Some(("alice".into(), "secret".into()));
+        assert_eq!(basic_auth(header.as_bytes()), expected);
+        assert_eq!(basic_auth(b"Bearer token"), None);
+    }
+
+    #[tokio::test]
+    async fn http_gateway_should_serve_rest_requests() {
+        let service: Service = ServiceBuilder::default().finish();
+        let (mut client, server) = duplex(4096);
+        tokio::spawn(HttpGateway::new(service).serve(server));
+
+        let req = "PUT /v1/t1/k1 HTTP/1.1\r\nHost: kv\r\nContent-Length: 2\r\n\r\nv1";
+        assert!(roundtrip(&mut client, req)
+            .await
+            .starts_with("HTTP/1.1 201"));
+        let res = roundtrip(&mut client, "GET /v1/t1/k1 HTTP/1.1\r\nHost: kv\r\n\r\n").await;
+        assert!(res.starts_with("HTTP/1.1 200"));
+        assert!(res.ends_with("\r\n\r\nv1"));
+
+        let res = roundtrip(&mut client, "GET /v1/t1 HTTP/1.1\r\nHost: kv\r\n\r\n").await;
+        assert!(res.ends_with(r#"{"cursor":0,"pairs":[{"key":"k1","value":"v1"}]}"#));
+
+        let req = "DELETE /v1/t1/k1 HTTP/1.1\r\nHost: kv\r\n\r\n";
+        assert!(roundtrip(&mut client, req)
+            .await
+            .starts_with("HTTP/1.1 200"));
+        let res = roundtrip(&mut client, "GET /v1/t1/k1 HTTP/1.1\r\nHost: kv\r\n\r\n").await;
+        assert!(res.starts_with("HTTP/1.1 404"));
+    }
+
+    #[tokio::test]
+    async fn rejected_client_should_get_503() {
+        let (mut client, server) = duplex(4096);
+        let e = KvError::TooManyConnections("server is limited to 1 connections".into());
+        tokio::spawn(reject(server, e));
+        let res = roundtrip(&mut client, "GET /v1/t1/k1 HTTP/1.1\r\nHost: kv\r\n\r\n").await;
+        assert!(res.starts_with("HTTP/1.1 503"));
+        assert!(res.ends_with("server is limited to 1 connections"));
+    }
+
+    #[tokio::test]
+    async fn http_gateway_should_serve_probes() {
+        let service: Service = ServiceBuilder::default().finish();
+        let (mut client, server) = duplex(4096);
+        tokio::spawn(HttpGateway::new(service.clone()).serve(server));
+        let res = roundtrip(&mut client, "GET /healthz HTTP/1.1\r\nHost: kv\r\n\r\n").await;
+        assert!(res.starts_with("HTTP/1.1 200"));
+        let res = roundtrip(&mut client, "GET /readyz HTTP/1.1\r\nHost: kv\r\n\r\n").await;
+        assert!(res.starts_with("HTTP/1.1 200"));
+
+        // 还没有完成同步的 replica 不接收流量
+        let replica: Service = ServiceBuilder::default().replica(true).finish();
+        let (mut client, server) = duplex(4096);
+        tokio::spawn(HttpGateway::new(replica).serve(server));
+        let res = roundtrip(&mut client, "GET /readyz HTTP/1.1\r\nHost: kv\r\n\r\n").await;
+        assert!(res.starts_with("HTTP/1.1 503"));
+        assert!(res.ends_with("replica is not synced with primary"));
+        let res = roundtrip(&mut client, "GET /healthz HTTP/1.1\r\nHost: kv\r\n\r\n").await;
+        assert!(res.starts_with("HTTP/1.1 200"));
+
+        service.shutdown().trigger();
+        assert!(service.ready().is_err());
+    }
+
+    async fn roundtrip(client: &mut tokio::io::DuplexStream, req: &str) -> String {
+        client.write_all(req.as_bytes()).await.unwrap();
+        let mut buf = vec![0; 4096];
+        let n = client.read(&mut buf).await.unwrap();
+        String::from_utf8_lossy(&buf[..n]).into()
+    }
+}
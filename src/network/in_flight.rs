@@ -0,0 +1,115 @@
+use std::sync::Arc;
+
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::{
+    config::ServerConfig,
+    pb::abi::{command_request::RequestData, CommandRequest},
+    session::Session,
+};
+
+/// 限制同时在执行的命令数。拿不到配额时连接不再读取新的请求，数据留在 TCP 的缓冲区里，
+/// 客户端的写入自然就慢下来了，而不是在服务器上无限制地堆积
+#[derive(Debug, Clone, Default)]
+pub struct InFlightLimiter {
+    /// 所有连接共享
+    server: Option<Arc<Semaphore>>,
+    max_in_flight: usize,
+    /// 每个连接的配额，同一个连接上的所有 stream 共享，保存在 Session 里
+    per_connection: Option<usize>,
+}
+
+/// 一个正在执行的命令，drop 之后归还配额
+#[derive(Debug)]
+pub struct InFlightPermit {
+    _connection: Option<OwnedSemaphorePermit>,
+    _server: Option<OwnedSemaphorePermit>,
+}
+
+impl InFlightLimiter {
+    pub fn new(max_in_flight: Option<usize>, per_connection: Option<usize>) -> Self {
+        Self {
+            server: max_in_flight.map(|max| Arc::new(Semaphore::new(max))),
+            max_in_flight: max_in_flight.unwrap_or_default(),
+            per_connection,
+        }
+    }
+
+    pub fn from_config(config: &ServerConfig) -> Self {
+        Self::new(config.max_in_flight, config.max_in_flight_per_connection)
+    }
+
+    /// 等到连接和服务器都有空闲的配额。先拿连接的配额，
+    /// 这样一个连接排队的请求最多占用一个服务器的配额
+    pub async fn acquire(&self, session: &Session) -> InFlightPermit {
+        let connection = match self.per_connection {
+            Some(max) => session.in_flight(max).acquire_owned().await.ok(),
+            None => None,
+        };
+        let server = match &self.server {
+            Some(server) => server.clone().acquire_owned().await.ok(),
+            None => None,
+        };
+        InFlightPermit {
+            _connection: connection,
+            _server: server,
+        }
+    }
+
+    /// 当前在执行的命令数，没有限制时为 0
+    pub fn in_flight(&self) -> usize {
+        match &self.server {
+            Some(server) => self.max_in_flight - server.available_permits(),
+            None => 0,
+        }
+    }
+}
+
+/// 订阅这类命令的响应不会结束，发出第一个响应之后就应该归还配额
+pub fn is_long_lived(cmd: &CommandRequest) -> bool {
+    matches!(
+        cmd.request_data,
+        Some(RequestData::Subscribe(_) | RequestData::Hwatch(_) | RequestData::Replicate(_))
+    )
+}
+
+#[cfg(test)]
+mod in_flight_tests {
+    use std::time::Duration;
+
+    use tokio::time;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn limiter_should_bound_connection_and_server() {
+        let limiter = InFlightLimiter::new(Some(3), Some(2));
+        let (s1, s2) = (Session::new(), Session::new());
+
+        let p1 = limiter.acquire(&s1).await;
+        let _p2 = limiter.acquire(&s1).await;
+        assert_eq!(limiter.in_flight(), 2);
+        // 连接的配额用完了
+        let res = time::timeout(Duration::from_millis(50), limiter.acquire(&s1)).await;
+        assert!(res.is_err());
+
+        let _p3 = limiter.acquire(&s2).await;
+        // 服务器的配额用完了
+        let res = time::timeout(Duration::from_millis(50), limiter.acquire(&s2)).await;
+        assert!(res.is_err());
+
+        drop(p1);
+        let _p4 = limiter.acquire(&s1).await;
+        assert_eq!(limiter.in_flight(), 3);
+    }
+
+    #[tokio::test]
+    async fn limiter_should_not_limit_by_default() {
+        let limiter = InFlightLimiter::default();
+        let session = Session::new();
+        let permits: Vec<_> =
+            futures::future::join_all((0..100).map(|_| limiter.acquire(&session))).await;
+        assert_eq!(permits.len(), 100);
+        assert_eq!(limiter.in_flight(), 0);
+    }
+}
@@ -0,0 +1,222 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    sync::{oneshot, Mutex},
+    task::JoinHandle,
+    time,
+};
+use tracing::warn;
+
+use super::ProstClientStream;
+use crate::{
+    error::KvError,
+    pb::abi::{CommandRequest, CommandResponse, Value},
+};
+
+/// 持有一个分布式锁，后台每隔三分之一个租约续约一次。drop 之后不再续约，锁在租约到期之后自动释放；
+/// 用 unlock 可以立即释放。写入受锁保护的数据时带上 token，存储服务据此拒绝过期持有者的写入
+pub struct LockGuard<S> {
+    client: Arc<Mutex<ProstClientStream<S>>>,
+    table: String,
+    name: String,
+    token: u64,
+    lost: Arc<AtomicBool>,
+    /// drop 之后续约的任务退出
+    stop: oneshot::Sender<()>,
+    renewer: JoinHandle<()>,
+}
+
+impl<S> LockGuard<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    /// 获取锁，锁被别人持有时返回 None。client 之后只用来续约和释放锁
+    pub async fn acquire(
+        mut client: ProstClientStream<S>,
+        table: impl Into<String>,
+        name: impl Into<String>,
+        ttl: Duration,
+    ) -> Result<Option<Self>, KvError> {
+        let (table, name) = (table.into(), name.into());
+        let res = client
+            .execute(&CommandRequest::new_lock(&table, &name, ttl))
+            .await?;
+        let (locked, token) = lock_result(&res)?;
+        if !locked {
+            return Ok(None);
+        }
+
+        let client = Arc::new(Mutex::new(client));
+        let lost = Arc::new(AtomicBool::new(false));
+        let (stop, stopped) = oneshot::channel();
+        let renewer = Renewer {
+            client: client.clone(),
+            table: table.clone(),
+            name: name.clone(),
+            ttl,
+            token,
+            lost: lost.clone(),
+        };
+        let renewer = tokio::spawn(renewer.run(stopped));
+        Ok(Some(Self {
+            client,
+            table,
+            name,
+            token,
+            lost,
+            stop,
+            renewer,
+        }))
+    }
+
+    /// fencing token，每次获取锁都比之前的大
+    pub fn token(&self) -> u64 {
+        self.token
+    }
+
+    /// 续约失败（租约已经到期被别人拿走了，或者连接出错）之后返回 false
+    pub fn is_held(&self) -> bool {
+        !self.lost.load(Ordering::Acquire)
+    }
+
+    /// 停止续约并释放锁，返回是否释放了；锁已经不属于自己时返回 false
+    pub async fn unlock(self) -> Result<bool, KvError> {
+        let Self {
+            client,
+            table,
+            name,
+            token,
+            stop,
+            renewer,
+            ..
+        } = self;
+        // 等正在进行的续约结束，否则连接上会留着续约的响应
+        drop(stop);
+        let _ = renewer.await;
+        let cmd = CommandRequest::new_unlock(table, name, token);
+        let res = client.lock().await.execute(&cmd).await?;
+        check_status(&res)?;
+        Ok(res.values.first() == Some(&true.into()))
+    }
+}
+
+struct Renewer<S> {
+    client: Arc<Mutex<ProstClientStream<S>>>,
+    table: String,
+    name: String,
+    ttl: Duration,
+    token: u64,
+    lost: Arc<AtomicBool>,
+}
+
+impl<S> Renewer<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    async fn run(self, mut stopped: oneshot::Receiver<()>) {
+        let interval = (self.ttl / 3).max(Duration::from_millis(1));
+        loop {
+            tokio::select! {
+                _ = time::sleep(interval) => {}
+                _ = &mut stopped => return,
+            }
+            match self.renew().await {
+                Ok(true) => {}
+                Ok(false) => {
+                    warn!("Lock {}/{} is lost", self.table, self.name);
+                    break;
+                }
+                Err(e) => {
+                    warn!("Failed to renew lock {}/{}: {}", self.table, self.name, e);
+                    break;
+                }
+            }
+        }
+        self.lost.store(true, Ordering::Release);
+    }
+
+    async fn renew(&self) -> Result<bool, KvError> {
+        let cmd = CommandRequest::new_renew_lock(&self.table, &self.name, self.ttl, self.token);
+        let res = self.client.lock().await.execute(&cmd).await?;
+        Ok(lock_result(&res)?.0)
+    }
+}
+
+fn check_status(res: &CommandResponse) -> Result<(), KvError> {
+    match res.status {
+        200 => Ok(()),
+        _ => Err(KvError::Internal(res.message.clone())),
+    }
+}
+
+/// LOCK 返回 [是否成功, token]
+fn lock_result(res: &CommandResponse) -> Result<(bool, u64), KvError> {
+    check_status(res)?;
+    let locked = res.values.first() == Some(&true.into());
+    let token = res
+        .values
+        .get(1)
+        .and_then(Value::to_integer)
+        .unwrap_or_default();
+    Ok((locked, token as u64))
+}
+
+#[cfg(test)]
+mod lock_tests {
+    use tokio::io::DuplexStream;
+
+    use super::*;
+    use crate::{network::ProstServerStream, service::Service, service_builder::ServiceBuilder};
+
+    fn connect(service: &Service) -> ProstClientStream<DuplexStream> {
+        let (client, server) = tokio::io::duplex(4096);
+        tokio::spawn(ProstServerStream::new(server, service.clone()).process());
+        ProstClientStream::new(client)
+    }
+
+    #[tokio::test]
+    async fn lock_guard_should_renew_until_unlocked() -> anyhow::Result<()> {
+        let service: Service = ServiceBuilder::default().finish();
+        let ttl = Duration::from_millis(150);
+        let guard = LockGuard::acquire(connect(&service), "locks", "l1", ttl)
+            .await?
+            .unwrap();
+
+        // 过了几个租约，锁仍然被持有
+        time::sleep(ttl * 3).await;
+        assert!(guard.is_held());
+        let other = LockGuard::acquire(connect(&service), "locks", "l1", ttl).await?;
+        assert!(other.is_none());
+
+        assert!(guard.unlock().await?);
+        let other = LockGuard::acquire(connect(&service), "locks", "l1", ttl).await?;
+        assert!(other.unwrap().token() > 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn lock_guard_should_notice_lost_lock() -> anyhow::Result<()> {
+        let service: Service = ServiceBuilder::default().finish();
+        let ttl = Duration::from_millis(60);
+        let guard = LockGuard::acquire(connect(&service), "locks", "l1", ttl)
+            .await?
+            .unwrap();
+
+        // 锁被别人删掉了，下一次续约失败
+        let mut client = connect(&service);
+        client
+            .execute(&CommandRequest::new_hdel("locks", "l1"))
+            .await?;
+        time::sleep(ttl).await;
+        assert!(!guard.is_held());
+        assert!(!guard.unlock().await?);
+        Ok(())
+    }
+}
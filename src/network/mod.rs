@@ -1,23 +1,51 @@
-mod frame;
+pub mod blocking;
+pub mod buffer;
+pub mod cluster_client;
+pub mod conn_limit;
+pub mod embedded;
+pub mod frame;
+#[cfg(feature = "grpc")]
+pub mod grpc;
+pub mod http;
+pub mod in_flight;
+pub mod lock;
 pub mod multiplex;
+pub mod pipeline;
+pub mod pool;
+#[cfg(feature = "quic")]
+pub mod quic;
+pub mod reconnect;
+pub mod resp;
 pub mod stream;
 pub mod stream_result;
 pub mod tls;
+pub mod transport;
+pub mod typed;
+pub mod websocket;
 
-use self::{stream::ProstStream, stream_result::StreamResult};
+use self::{
+    frame::FrameCompression, in_flight::is_long_lived, stream::ProstStream,
+    stream_result::StreamResult,
+};
 use crate::{
     error::KvError,
     pb::abi::{CommandRequest, CommandResponse},
+    session::Session,
     Service, Storage,
 };
-use futures::{SinkExt, StreamExt};
-use tokio::io::{AsyncRead, AsyncWrite};
-use tracing::info;
+use futures::{SinkExt, Stream, StreamExt};
+use std::{sync::Arc, time::Duration};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    time,
+};
+use tracing::{info, info_span, Instrument};
 
 /// S: 各种协议。protocol: TPC UDP WS HTTP TLS and Customize
 pub struct ProstServerStream<S, DB> {
     stream: ProstStream<S, CommandRequest, CommandResponse>,
     service: Service<DB>,
+    session: Arc<Session>,
 }
 
 pub struct ProstClientStream<S> {
@@ -30,19 +58,82 @@ where
     D: Storage,
 {
     pub fn new(stream: S, service: Service<D>) -> Self {
+        Self::with_session(stream, service, Default::default())
+    }
+
+    /// 同一个连接上的多个 stream 共享一个 session
+    pub fn with_session(stream: S, service: Service<D>, session: Arc<Session>) -> Self {
         Self {
             stream: ProstStream::new(stream),
             service,
+            session,
         }
     }
 
     pub async fn process(mut self) -> Result<(), KvError> {
-        while let Some(Ok(cmd)) = self.stream.next().await {
-            info!("Got a new command: {:?}", cmd);
-            let mut res = self.service.execute(cmd);
+        let shutdown = self.service.shutdown().clone();
+        let session = self.session.clone();
+        let _stream = session.open_stream();
+        loop {
+            // 开始退出或者连接被 CLIENT KILL 之后不再读取新的请求
+            let cmd = tokio::select! {
+                cmd = self.stream.next() => cmd,
+                _ = shutdown.triggered() => break,
+                _ = session.killed() => break,
+            };
+            let cmd = match cmd {
+                Some(Ok(cmd)) => cmd,
+                // 请求太大时告诉客户端原因；后面的数据已经没法解析，结束这个 stream
+                Some(Err(e @ KvError::FrameTooLarge(..))) => {
+                    self.stream.send(&CommandResponse::from(e)).await?;
+                    break;
+                }
+                _ => break,
+            };
+            let _in_flight = shutdown.start();
+            // 配额用完时在这里等待，这期间不再读取这个 stream 上的请求
+            let mut permit = Some(self.service.in_flight.acquire(&self.session).await);
+            // 响应发完之前连接不算空闲
+            let _busy = self.session.busy();
+            let long_lived = is_long_lived(&cmd);
+            let id = cmd.id;
+            info!("Got a new command: {:?}", cmd.redacted());
+            let span = info_span!("request");
+            let mut res = self
+                .service
+                .execute_with_deadline(cmd, &self.session)
+                .instrument(span.clone())
+                .await;
             // let res = res.next().await.unwrap().as_ref().to_owned();
-            while let Some(data) = res.next().await {
-                self.stream.send(&data).await?;
+            loop {
+                // 已经产生的响应先发完；SUBSCRIBE 这类不会结束的流在退出时中断
+                let data = tokio::select! {
+                    biased;
+                    data = res.next().instrument(span.clone()) => data,
+                    _ = shutdown.triggered() => None,
+                    _ = session.killed() => None,
+                };
+                let Some(mut data) = data else {
+                    break;
+                };
+                // 带回请求 id，流水线的客户端用它找到对应的请求
+                if id != 0 {
+                    Arc::make_mut(&mut data).id = id;
+                }
+                // HELLO 协商之后，同一个连接上的 stream 都使用协商好的压缩算法
+                self.stream.set_compression(self.session.compression());
+                match self.stream.send(&data).instrument(span.clone()).await {
+                    // 响应太大时还没有写入任何数据，改成返回错误
+                    Err(e @ KvError::FrameTooLarge(..)) => {
+                        let mut res = CommandResponse::from(e);
+                        res.id = id;
+                        self.stream.send(&res).await?
+                    }
+                    r => r?,
+                }
+                if long_lived {
+                    permit.take();
+                }
             }
         }
 
@@ -55,13 +146,23 @@ where
     S: AsyncRead + AsyncWrite + Unpin + Send,
 {
     pub fn new(stream: S) -> Self {
-        Self {
-            stream: ProstStream::new(stream),
-        }
+        Self::with_compression(stream, FrameCompression::default())
+    }
+
+    /// 发送请求时使用 HELLO 协商好的压缩算法
+    pub fn with_compression(stream: S, compression: FrameCompression) -> Self {
+        let mut stream = ProstStream::new(stream);
+        stream.set_compression(compression);
+        Self { stream }
+    }
+
+    /// 收发的消息大小上限，没有设置时使用全局的 max_frame_size
+    pub fn set_max_frame_size(&mut self, max_frame_size: Option<usize>) {
+        self.stream.set_max_frame_size(max_frame_size);
     }
 
     pub async fn execute(&mut self, cmd: &CommandRequest) -> Result<CommandResponse, KvError> {
-        self.stream.send(&cmd).await?;
+        self.stream.send(cmd).await?;
 
         match self.stream.next().await {
             Some(v) => v,
@@ -69,6 +170,43 @@ where
         }
     }
 
+    /// 请求中带上超时时间，服务器超时之后返回 504；等待响应的时间同样不超过 timeout
+    pub async fn execute_with_timeout(
+        &mut self,
+        cmd: &CommandRequest,
+        timeout: Duration,
+    ) -> Result<CommandResponse, KvError> {
+        let cmd = cmd.clone().with_timeout(timeout);
+        match time::timeout(timeout, self.execute(&cmd)).await {
+            Ok(res) => res,
+            Err(_) => Err(KvError::Timeout(format!("no response in {:?}", timeout))),
+        }
+    }
+
+    /// 允许服务器分多帧返回（HGETALL/HSCAN），依次返回每一帧，收到 has_more 为 false 的帧之后结束。
+    /// 没有读完就丢弃时，剩下的帧还在连接上，这个连接不能再用来执行别的命令
+    pub async fn execute_paged(
+        &mut self,
+        cmd: &CommandRequest,
+    ) -> Result<impl Stream<Item = Result<CommandResponse, KvError>> + '_, KvError> {
+        self.stream.send(&cmd.clone().with_stream()).await?;
+        let pages = futures::stream::unfold(Some(&mut self.stream), |stream| async move {
+            let stream = stream?;
+            match stream.next().await {
+                Some(Ok(res)) => {
+                    let more = res.has_more;
+                    Some((Ok(res), more.then_some(stream)))
+                }
+                Some(Err(e)) => Some((Err(e), None)),
+                None => Some((
+                    Err(KvError::Internal("Didn't get any response".into())),
+                    None,
+                )),
+            }
+        });
+        Ok(pages)
+    }
+
     pub async fn execute_streaming(
         self,
         cmd: &CommandRequest,
@@ -77,14 +215,16 @@ where
         stream.send(cmd).await?;
         stream.close().await?;
 
-        Ok(StreamResult::new(stream).await?)
+        StreamResult::new(stream).await
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::{assert_res_ok, pb::abi::Value, service_builder::ServiceBuilder};
+    use crate::{
+        assert_res_ok, in_flight::InFlightLimiter, pb::abi::Value, service_builder::ServiceBuilder,
+    };
     use anyhow::Result;
     use bytes::Bytes;
     use std::net::SocketAddr;
@@ -134,6 +274,58 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn client_should_read_paged_response() -> anyhow::Result<()> {
+        let addr = start_server().await?;
+        let stream = TcpStream::connect(addr).await?;
+        let mut client = ProstClientStream::new(stream);
+        for key in ["a", "b", "c", "d", "e"] {
+            client
+                .execute(&CommandRequest::new_hset("t1", key, key))
+                .await?;
+        }
+
+        let pages: Vec<CommandResponse> = client
+            .execute_paged(&CommandRequest::new_hscan("t1", 0, 2))
+            .await?
+            .map(|res| res.unwrap())
+            .collect()
+            .await;
+        let sizes: Vec<usize> = pages.iter().map(|res| res.pairs.len()).collect();
+        assert_eq!(sizes, [2, 2, 1]);
+
+        // 读完之后连接还可以继续使用
+        let res = client.execute(&CommandRequest::new_hget("t1", "a")).await?;
+        assert_res_ok(&res, &["a".into()], &[]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn server_should_wait_for_in_flight_permit() -> anyhow::Result<()> {
+        let limiter = InFlightLimiter::new(Some(1), None);
+        let service: Service = ServiceBuilder::default()
+            .in_flight(limiter.clone())
+            .finish();
+        let (client, server) = tokio::io::duplex(4096);
+        tokio::spawn(ProstServerStream::new(server, service).process());
+        let mut client = ProstClientStream::new(client);
+
+        // 配额被占用时请求不会执行
+        let permit = limiter.acquire(&Session::new()).await;
+        let cmd = CommandRequest::new_hset("t1", "k1", "v1");
+        let res = time::timeout(Duration::from_millis(100), client.execute(&cmd)).await;
+        assert!(res.is_err());
+
+        // 归还之后继续执行
+        drop(permit);
+        let res = client.stream.next().await.unwrap()?;
+        assert_res_ok(&res, &[Value::default()], &[]);
+        assert_eq!(limiter.in_flight(), 0);
+
+        Ok(())
+    }
+
     async fn start_server() -> Result<SocketAddr> {
         let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
         let addr = listener.local_addr().unwrap();
@@ -168,8 +360,9 @@ pub mod test_utils {
             _cx: &mut Context<'_>,
             buf: &mut tokio::io::ReadBuf<'_>,
         ) -> Poll<std::io::Result<()>> {
-            let len = buf.capacity();
-            let data = self.get_mut().buf.split_to(len);
+            let this = self.get_mut();
+            let len = buf.remaining().min(this.buf.len());
+            let data = this.buf.split_to(len);
             buf.put_slice(&data);
             Poll::Ready(Ok(()))
         }
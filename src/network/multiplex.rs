@@ -4,10 +4,33 @@ use tokio::io::{AsyncRead, AsyncWrite};
 use tokio_util::compat::{Compat, FuturesAsyncReadCompatExt, TokioAsyncReadCompatExt};
 use yamux::{Config, Connection, ConnectionError, Control, Mode};
 
-use crate::ProstClientStream;
+use crate::{
+    error::KvError,
+    frame::FrameCompression,
+    pb::abi::{CommandRequest, CommandResponse, Value},
+    stream::ProstStream,
+    stream_result::{KeyWatch, StreamResult, Subscription},
+    ProstClientStream,
+};
+
+/// 客户端在 yamux stream 上收发的 ProstStream
+pub type ClientStream = ProstStream<Compat<yamux::Stream>, CommandResponse, CommandRequest>;
+
+/// 一个连接上可以打开多个 stream：yamux 跑在 TLS 或者 unix socket 上，QUIC 自带多路复用
+pub trait Multiplexer {
+    type Stream: AsyncRead + AsyncWrite + Unpin + Send;
+
+    fn open_stream(
+        &mut self,
+    ) -> impl Future<Output = Result<ProstClientStream<Self::Stream>, KvError>> + Send;
+}
 
 pub struct YamuxCtrl<S> {
     ctrl: Control,
+    /// 新打开的 stream 使用的压缩算法
+    compression: FrameCompression,
+    /// 新打开的 stream 收发的消息大小上限
+    max_frame_size: Option<usize>,
     _conn: PhantomData<S>,
 }
 
@@ -40,7 +63,9 @@ where
 
         Self {
             ctrl,
-            _conn: PhantomData::default(),
+            compression: FrameCompression::default(),
+            max_frame_size: None,
+            _conn: PhantomData,
         }
     }
 
@@ -61,13 +86,120 @@ where
         &mut self,
     ) -> Result<ProstClientStream<Compat<yamux::Stream>>, ConnectionError> {
         let stream = self.ctrl.open_stream().await?;
-        Ok(ProstClientStream::new(stream.compat()))
+        let mut stream = ProstClientStream::with_compression(stream.compat(), self.compression);
+        stream.set_max_frame_size(self.max_frame_size);
+        Ok(stream)
+    }
+
+    /// 设置 HELLO 协商好的压缩算法，之后打开的 stream 都使用它
+    pub fn set_compression(&mut self, compression: FrameCompression) {
+        self.compression = compression;
+    }
+
+    /// 之后打开的 stream 不使用全局的 max_frame_size，同一个进程里的服务器不受影响
+    pub fn set_max_frame_size(&mut self, max_frame_size: Option<usize>) {
+        self.max_frame_size = max_frame_size;
+    }
+
+    /// 关闭连接，之后打开 stream 都会失败
+    pub async fn close(&mut self) -> Result<(), ConnectionError> {
+        self.ctrl.close().await
+    }
+
+    /// 订阅一个主题；每次订阅独占一个 yamux stream
+    pub async fn subscribe(
+        &mut self,
+        topic: impl Into<String>,
+    ) -> Result<Subscription<ClientStream>, KvError> {
+        let topic = topic.into();
+        let stream = self.open_stream().await?;
+        let cmd = CommandRequest::new_subscribe(&topic);
+        let result: StreamResult<ClientStream> = stream.execute_streaming(&cmd).await?;
+        Ok(Subscription::new(topic, result))
+    }
+
+    /// 取消订阅
+    pub async fn unsubscribe(
+        &mut self,
+        topic: impl Into<String>,
+        id: u32,
+    ) -> Result<CommandResponse, KvError> {
+        let mut stream = self.open_stream().await?;
+        let cmd = CommandRequest::new_unsubscribe(&topic.into(), id);
+        stream.execute(&cmd).await
+    }
+
+    /// 监听 table 中 key 的修改，prefix 为 true 时监听所有以 key 开头的 key；
+    /// 每个 watch 独占一个 yamux stream
+    pub async fn watch(
+        &mut self,
+        table: impl Into<String>,
+        key: impl AsRef<[u8]>,
+        prefix: bool,
+    ) -> Result<KeyWatch<ClientStream>, KvError> {
+        self.watch_from(table, key, prefix, 0).await
+    }
+
+    /// 和 watch 一样，但先重放从 revision 开始的修改，断线重连之后不会丢失修改；需要服务器开启 MVCC
+    pub async fn watch_from(
+        &mut self,
+        table: impl Into<String>,
+        key: impl AsRef<[u8]>,
+        prefix: bool,
+        revision: u64,
+    ) -> Result<KeyWatch<ClientStream>, KvError> {
+        let stream = self.open_stream().await?;
+        let cmd = CommandRequest::new_hwatch_from(table, key, prefix, revision);
+        let result: StreamResult<ClientStream> = stream.execute_streaming(&cmd).await?;
+        Ok(KeyWatch::new(result))
+    }
+
+    /// 取消监听
+    pub async fn unwatch(&mut self, id: u32) -> Result<CommandResponse, KvError> {
+        let mut stream = self.open_stream().await?;
+        stream.execute(&CommandRequest::new_hunwatch(id)).await
+    }
+
+    /// 往主题里发布数据
+    pub async fn publish(
+        &mut self,
+        topic: impl Into<String>,
+        data: Vec<Value>,
+    ) -> Result<CommandResponse, KvError> {
+        let mut stream = self.open_stream().await?;
+        let cmd = CommandRequest::new_publish(&topic.into(), data);
+        stream.execute(&cmd).await
+    }
+}
+
+impl<S> Multiplexer for YamuxCtrl<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    type Stream = Compat<yamux::Stream>;
+
+    async fn open_stream(&mut self) -> Result<ProstClientStream<Self::Stream>, KvError> {
+        Ok(YamuxCtrl::open_stream(self).await?)
     }
 }
 
 #[cfg(test)]
 mod multiplex_tests {
+    use std::{net::SocketAddr, sync::Arc, time::Duration};
+
     use anyhow::Result;
+    use futures::StreamExt;
+    use tokio::{
+        net::{TcpListener, TcpStream},
+        time,
+    };
+    use tokio_util::compat::FuturesAsyncReadCompatExt;
+
+    use super::YamuxCtrl;
+    use crate::{
+        assert_res_ok, pb::abi::CommandRequest, service_builder::ServiceBuilder, session::Session,
+        ProstServerStream, Service,
+    };
 
     #[test]
     fn yamux_ctrl_client_server_should_work() -> Result<()> {
@@ -76,4 +208,92 @@ mod multiplex_tests {
 
         Ok(())
     }
+
+    #[tokio::test]
+    async fn yamux_ctrl_pub_sub_should_work() -> Result<()> {
+        let addr = start_yamux_server().await?;
+        let stream = TcpStream::connect(addr).await?;
+        let mut ctrl = YamuxCtrl::new_client(stream, None);
+
+        let mut sub = ctrl.subscribe("lobby").await?;
+        assert!(sub.id() > 0);
+
+        let res = ctrl
+            .publish("lobby", vec!["hello".into(), 1.into()])
+            .await?;
+        assert_res_ok(&res, &[], &[]);
+
+        let data = sub.next().await.unwrap()?;
+        assert_eq!(data, vec!["hello".into(), 1.into()]);
+
+        let res = ctrl.unsubscribe("lobby", sub.id()).await?;
+        assert_res_ok(&res, &[], &[]);
+
+        // 取消订阅之后 stream 会结束
+        let next = time::timeout(Duration::from_secs(1), sub.next()).await?;
+        assert!(next.is_none());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn yamux_ctrl_watch_should_work() -> Result<()> {
+        let addr = start_yamux_server().await?;
+        let stream = TcpStream::connect(addr).await?;
+        let mut ctrl = YamuxCtrl::new_client(stream, None);
+
+        let mut watch = ctrl.watch("t1", "k1", false).await?;
+        assert!(watch.id() > 0);
+
+        let mut client = ctrl.open_stream().await?;
+        client
+            .execute(&CommandRequest::new_hset("t1", "k1", "v1"))
+            .await?;
+        client
+            .execute(&CommandRequest::new_hdel("t1", "k1"))
+            .await?;
+
+        let events = watch.next().await.unwrap()?;
+        assert_eq!(events.len(), 1);
+        assert!(!events[0].deleted);
+        assert_eq!(events[0].new_value, Some("v1".into()));
+        let events = watch.next().await.unwrap()?;
+        assert!(events[0].deleted);
+        assert_eq!(events[0].old_value, Some("v1".into()));
+
+        let res = ctrl.unwatch(watch.id()).await?;
+        assert_res_ok(&res, &[], &[]);
+        let next = time::timeout(Duration::from_secs(1), watch.next()).await?;
+        assert!(next.is_none());
+
+        Ok(())
+    }
+
+    async fn start_yamux_server() -> Result<SocketAddr> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let service: Service = ServiceBuilder::default().finish();
+
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let service = service.clone();
+                // 和 serve_yamux 一样，连接上的 stream 共享一个 session
+                let session = Arc::new(Session::new());
+                YamuxCtrl::new_server(stream, None, move |stream| {
+                    let server = ProstServerStream::with_session(
+                        stream.compat(),
+                        service.clone(),
+                        session.clone(),
+                    );
+                    async move {
+                        server.process().await.unwrap();
+                        Ok(())
+                    }
+                });
+            }
+        });
+
+        Ok(addr)
+    }
 }
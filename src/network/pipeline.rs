@@ -0,0 +1,183 @@
+//! 流水线：在一个 stream 上连续发出请求，不等待前一个请求的响应；
+//! 每个请求带上递增的 id，服务器在响应中带回，收到响应之后按 id 交给对应的调用者
+
+use std::{collections::HashMap, task::Poll};
+
+use futures::{future, SinkExt, StreamExt};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    sync::{mpsc, oneshot},
+};
+use tracing::warn;
+
+use super::{in_flight::is_long_lived, stream::ProstStream, ProstClientStream};
+use crate::{
+    error::KvError,
+    pb::abi::{CommandRequest, CommandResponse},
+};
+
+/// 最多排队等待发送的请求数
+const QUEUE_SIZE: usize = 128;
+
+type Reply = oneshot::Sender<Result<CommandResponse, KvError>>;
+
+/// 流水线的客户端，可以 clone 之后在多个任务里同时执行命令
+#[derive(Clone)]
+pub struct Pipeline {
+    tx: mpsc::Sender<(CommandRequest, Reply)>,
+}
+
+enum Event {
+    Call(Option<(CommandRequest, Reply)>),
+    Flushed(Result<(), KvError>),
+    Response(Option<Result<CommandResponse, KvError>>),
+}
+
+impl<S> ProstClientStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    /// 转成流水线的客户端：后台任务负责收发，所有的 Pipeline 都 drop 并且收到所有的响应之后退出
+    pub fn pipeline(self) -> Pipeline {
+        let (tx, rx) = mpsc::channel(QUEUE_SIZE);
+        tokio::spawn(run(self.stream, rx));
+        Pipeline { tx }
+    }
+}
+
+impl Pipeline {
+    /// 发出请求，等待对应的响应；同时调用的 execute 按调用的顺序发出，不需要等待前面的响应。
+    /// SUBSCRIBE 这类不会结束的命令和分多帧返回的命令需要独占一个 stream，不能在流水线上执行
+    pub async fn execute(&self, cmd: &CommandRequest) -> Result<CommandResponse, KvError> {
+        if is_long_lived(cmd) || cmd.stream {
+            return Err(KvError::InvalidCommand(
+                "streaming command can't be pipelined".into(),
+            ));
+        }
+        let (reply, rx) = oneshot::channel();
+        self.tx
+            .send((cmd.clone(), reply))
+            .await
+            .map_err(|_| closed())?;
+        rx.await.map_err(|_| closed())?
+    }
+}
+
+/// 同时等待新的请求、写出和读取：写出的时候不停止读取，服务器不会因为响应发不出去而停止读取请求
+async fn run<S>(
+    mut stream: ProstStream<S, CommandResponse, CommandRequest>,
+    mut rx: mpsc::Receiver<(CommandRequest, Reply)>,
+) where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    let mut next_id = 0;
+    let mut pending: HashMap<u64, Reply> = HashMap::new();
+    // 所有的 Pipeline 都已经 drop
+    let mut closed = false;
+    // 有还没有写出的请求
+    let mut dirty = false;
+    while !(closed && pending.is_empty() && !dirty) {
+        let waiting = !pending.is_empty();
+        let event = future::poll_fn(|cx| {
+            if !closed {
+                if let Poll::Ready(call) = rx.poll_recv(cx) {
+                    return Poll::Ready(Event::Call(call));
+                }
+            }
+            if dirty {
+                if let Poll::Ready(res) = stream.poll_flush_unpin(cx) {
+                    return Poll::Ready(Event::Flushed(res));
+                }
+            }
+            if waiting {
+                if let Poll::Ready(res) = stream.poll_next_unpin(cx) {
+                    return Poll::Ready(Event::Response(res));
+                }
+            }
+            Poll::Pending
+        })
+        .await;
+        match event {
+            Event::Call(Some((mut cmd, reply))) => {
+                next_id += 1;
+                cmd.id = next_id;
+                // 消息太大时还没有写入任何数据，stream 还可以继续使用
+                match stream.start_send_unpin(&cmd) {
+                    Ok(()) => {
+                        pending.insert(next_id, reply);
+                        dirty = true;
+                    }
+                    Err(e) => {
+                        let _ = reply.send(Err(e));
+                    }
+                }
+            }
+            Event::Call(None) => closed = true,
+            Event::Flushed(Ok(())) => dirty = false,
+            Event::Response(Some(Ok(res))) => match pending.remove(&res.id) {
+                Some(reply) => {
+                    let _ = reply.send(Ok(res));
+                }
+                None => warn!("Got response of unknown request {}", res.id),
+            },
+            // 连接断开，还在等待的调用者收到 closed 错误
+            Event::Flushed(Err(e)) | Event::Response(Some(Err(e))) => {
+                warn!("Pipeline is broken: {:?}", e);
+                break;
+            }
+            Event::Response(None) => break,
+        }
+    }
+}
+
+fn closed() -> KvError {
+    KvError::Internal("Pipeline is closed".into())
+}
+
+#[cfg(test)]
+mod pipeline_tests {
+    use super::*;
+    use crate::{pb::abi::Value, service_builder::ServiceBuilder, ProstServerStream, Service};
+
+    fn pipeline() -> Pipeline {
+        let service: Service = ServiceBuilder::default().finish();
+        let (client, server) = tokio::io::duplex(4096);
+        tokio::spawn(ProstServerStream::new(server, service).process());
+        ProstClientStream::new(client).pipeline()
+    }
+
+    #[tokio::test]
+    async fn pipeline_should_match_responses_to_requests() {
+        let pipeline = pipeline();
+        let writes = (0..20).map(|i| {
+            let cmd = CommandRequest::new_hset("t1", format!("k{}", i), i);
+            let pipeline = pipeline.clone();
+            async move { pipeline.execute(&cmd).await }
+        });
+        for res in future::join_all(writes).await {
+            assert_eq!(res.unwrap().status, 200);
+        }
+
+        let reads = (0..20).map(|i| {
+            let cmd = CommandRequest::new_hget("t1", format!("k{}", i));
+            let pipeline = &pipeline;
+            async move { pipeline.execute(&cmd).await }
+        });
+        for (i, res) in future::join_all(reads).await.into_iter().enumerate() {
+            assert_eq!(res.unwrap().values, [Value::from(i as i64)]);
+        }
+    }
+
+    #[tokio::test]
+    async fn pipeline_should_reject_streaming_commands() {
+        let pipeline = pipeline();
+        let res = pipeline
+            .execute(&CommandRequest::new_subscribe("lobby"))
+            .await;
+        assert!(matches!(res, Err(KvError::InvalidCommand(_))));
+        let res = pipeline
+            .execute(&CommandRequest::new_hscan("t1", 0, 2).with_stream())
+            .await;
+        assert!(matches!(res, Err(KvError::InvalidCommand(_))));
+    }
+}
@@ -0,0 +1,469 @@
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex as StdMutex, Weak,
+    },
+    time::{Duration, Instant},
+};
+
+use hyper::StatusCode;
+use tokio::{sync::Mutex, task::JoinHandle, time};
+use tokio_util::compat::Compat;
+use tracing::{debug, info, warn};
+
+use crate::{
+    config::{BalancePolicy, ClientConfig},
+    error::KvError,
+    multiplex::YamuxCtrl,
+    pb::abi::{CommandRequest, CommandResponse},
+    start_client_with_config,
+    transport::BoxedTransport,
+    ProstClientStream,
+};
+
+/// 默认的连接数
+const DEFAULT_POOL_SIZE: usize = 4;
+
+/// 默认每隔多少秒检查一次空闲的连接
+const DEFAULT_HEALTH_CHECK_INTERVAL: u64 = 30;
+
+/// 默认剔除连接失败的服务器的秒数
+const DEFAULT_EJECT_TIME: u64 = 10;
+
+/// 健康检查时等待 PONG 的最长时间
+const PING_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub type ClientCtrl = YamuxCtrl<BoxedTransport>;
+
+/// 客户端连接池：和每个服务器维护多个 yamux 连接，按 balance.policy 选择服务器，轮流在它的连接上打开 stream，
+/// 多线程的客户端不会都挤在一个 TCP 连接上。断开的连接在下次使用或者健康检查时重新建立；
+/// 连接失败的服务器被剔除一段时间，健康检查重新连上之后恢复
+#[derive(Clone)]
+pub struct Pool {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    config: ClientConfig,
+    endpoints: Vec<Endpoint>,
+    policy: BalancePolicy,
+    eject_time: Duration,
+    /// 轮流使用时下一个服务器
+    next: AtomicUsize,
+}
+
+/// 一个服务器和到它的连接
+struct Endpoint {
+    /// general.addr 换成了这个服务器的地址
+    config: ClientConfig,
+    conns: Vec<Conn>,
+    /// 下一个使用的连接
+    next: AtomicUsize,
+    /// 正在执行的请求数
+    active: AtomicUsize,
+    /// 被剔除时，到这个时间之前不再使用
+    ejected_until: StdMutex<Option<Instant>>,
+}
+
+struct Conn {
+    /// None 表示连接断开之后还没有重新建立
+    ctrl: Mutex<Option<ClientCtrl>>,
+    last_used: StdMutex<Instant>,
+}
+
+/// 请求执行完之后减少服务器的 active
+struct Active<'a>(&'a AtomicUsize);
+
+impl Pool {
+    /// 建立所有的连接，并启动后台的健康检查；Pool 全部 drop 之后健康检查自动退出。
+    /// 连不上的服务器先被剔除，所有的服务器都连不上时返回错误
+    pub async fn connect(config: ClientConfig) -> Result<Self, KvError> {
+        let size = config.pool.size.unwrap_or(DEFAULT_POOL_SIZE).max(1);
+        let eject_time =
+            Duration::from_secs(config.balance.eject_time.unwrap_or(DEFAULT_EJECT_TIME));
+        let addrs = std::iter::once(&config.general.addr).chain(&config.balance.addrs);
+        let mut endpoints = Vec::new();
+        let mut last_err = None;
+        for addr in addrs {
+            let mut endpoint_config = config.clone();
+            endpoint_config.general.addr = addr.clone();
+            let endpoint = Endpoint::connect(endpoint_config, size).await;
+            match endpoint {
+                Ok(endpoint) => endpoints.push(endpoint),
+                Err((endpoint, e)) => {
+                    warn!("Failed to connect to {}: {:?}", addr, e);
+                    endpoint.eject(eject_time);
+                    endpoints.push(endpoint);
+                    last_err = Some(e);
+                }
+            }
+        }
+        if let Some(e) = last_err.filter(|_| endpoints.iter().all(|e| e.is_ejected())) {
+            return Err(e);
+        }
+        let interval = config
+            .pool
+            .health_check_interval
+            .unwrap_or(DEFAULT_HEALTH_CHECK_INTERVAL);
+        let inner = Arc::new(Inner {
+            policy: config.balance.policy,
+            config,
+            endpoints,
+            eject_time,
+            next: AtomicUsize::new(0),
+        });
+        spawn_health_check(Arc::downgrade(&inner), Duration::from_secs(interval.max(1)));
+        Ok(Self { inner })
+    }
+
+    /// 所有服务器的连接数
+    pub fn size(&self) -> usize {
+        self.inner.endpoints.iter().map(|e| e.conns.len()).sum()
+    }
+
+    /// 按策略选择服务器，轮流从它的连接上打开一个 stream，连接已经断开时先重新建立
+    pub async fn get(&self) -> Result<ProstClientStream<Compat<yamux::Stream>>, KvError> {
+        Ok(self.inner.open_stream().await?.1)
+    }
+
+    /// 在一个新的 stream 上执行命令，配置了超时时间时带上超时时间
+    pub async fn execute(&self, cmd: &CommandRequest) -> Result<CommandResponse, KvError> {
+        let (endpoint, mut stream) = self.inner.open_stream().await?;
+        let _active = Active::new(&endpoint.active);
+        match self.inner.config.timeout {
+            Some(timeout) => {
+                let timeout = Duration::from_millis(timeout);
+                stream.execute_with_timeout(cmd, timeout).await
+            }
+            None => stream.execute(cmd).await,
+        }
+    }
+}
+
+impl Inner {
+    /// 依次尝试选出的服务器，打开失败的服务器被剔除
+    async fn open_stream(
+        &self,
+    ) -> Result<(&Endpoint, ProstClientStream<Compat<yamux::Stream>>), KvError> {
+        let mut last_err = None;
+        for i in self.candidates() {
+            let endpoint = &self.endpoints[i];
+            match endpoint.open_stream().await {
+                Ok(stream) => {
+                    endpoint.restore();
+                    return Ok((endpoint, stream));
+                }
+                Err(e) => {
+                    warn!("Failed to open stream to {}: {:?}", endpoint.addr(), e);
+                    endpoint.eject(self.eject_time);
+                    last_err = Some(e);
+                }
+            }
+        }
+        Err(last_err.unwrap_or_else(|| KvError::Internal("No server configured".into())))
+    }
+
+    /// 按策略排列的服务器，被剔除的服务器放在最后，其它的都失败时再尝试
+    fn candidates(&self) -> Vec<usize> {
+        let (mut order, ejected): (Vec<usize>, Vec<usize>) =
+            (0..self.endpoints.len()).partition(|&i| !self.endpoints[i].is_ejected());
+        if self.policy != BalancePolicy::PrimaryWithFallback && !order.is_empty() {
+            let start = self.next.fetch_add(1, Ordering::Relaxed) % order.len();
+            order.rotate_left(start);
+        }
+        // 稳定排序：请求数相同的服务器仍然轮流使用
+        if self.policy == BalancePolicy::LeastConnections {
+            order.sort_by_key(|&i| self.endpoints[i].active.load(Ordering::Relaxed));
+        }
+        order.extend(ejected);
+        order
+    }
+
+    /// 检查空闲超过 idle 的连接
+    async fn check(&self, idle: Duration) {
+        for endpoint in &self.endpoints {
+            if endpoint.check(idle).await {
+                endpoint.restore();
+            } else {
+                endpoint.eject(self.eject_time);
+            }
+        }
+    }
+}
+
+impl Endpoint {
+    /// 建立 size 个连接；失败时也返回 Endpoint，连接在之后重新建立
+    async fn connect(config: ClientConfig, size: usize) -> Result<Self, (Self, KvError)> {
+        let mut conns = Vec::with_capacity(size);
+        let mut err = None;
+        for _ in 0..size {
+            let ctrl = match err {
+                None => connect(&config).await.map_err(|e| err = Some(e)).ok(),
+                Some(_) => None,
+            };
+            conns.push(Conn {
+                ctrl: Mutex::new(ctrl),
+                last_used: StdMutex::new(Instant::now()),
+            });
+        }
+        let endpoint = Self {
+            config,
+            conns,
+            next: AtomicUsize::new(0),
+            active: AtomicUsize::new(0),
+            ejected_until: StdMutex::new(None),
+        };
+        match err {
+            None => Ok(endpoint),
+            Some(e) => Err((endpoint, e)),
+        }
+    }
+
+    fn addr(&self) -> &str {
+        &self.config.general.addr
+    }
+
+    async fn open_stream(&self) -> Result<ProstClientStream<Compat<yamux::Stream>>, KvError> {
+        let i = self.next.fetch_add(1, Ordering::Relaxed) % self.conns.len();
+        self.conns[i].open_stream(&self.config).await
+    }
+
+    fn is_ejected(&self) -> bool {
+        let ejected_until = self.ejected_until.lock().unwrap();
+        ejected_until.is_some_and(|until| until > Instant::now())
+    }
+
+    fn eject(&self, time: Duration) {
+        let mut ejected_until = self.ejected_until.lock().unwrap();
+        if ejected_until.is_none() {
+            warn!("Server {} is ejected", self.addr());
+        }
+        *ejected_until = Some(Instant::now() + time);
+    }
+
+    fn restore(&self) {
+        if self.ejected_until.lock().unwrap().take().is_some() {
+            info!("Server {} is restored", self.addr());
+        }
+    }
+
+    /// 检查所有的连接，有连接重新建立失败时返回 false
+    async fn check(&self, idle: Duration) -> bool {
+        let mut healthy = true;
+        for conn in &self.conns {
+            healthy &= conn.check(&self.config, idle).await;
+        }
+        healthy
+    }
+}
+
+impl Conn {
+    async fn open_stream(
+        &self,
+        config: &ClientConfig,
+    ) -> Result<ProstClientStream<Compat<yamux::Stream>>, KvError> {
+        *self.last_used.lock().unwrap() = Instant::now();
+        let mut ctrl = self.ctrl.lock().await;
+        if let Some(ctrl) = ctrl.as_mut() {
+            match ctrl.open_stream().await {
+                Ok(stream) => return Ok(stream),
+                Err(e) => debug!("Connection is broken: {:?}, reconnecting", e),
+            }
+        }
+        *ctrl = None;
+        let ctrl = ctrl.insert(connect(config).await?);
+        Ok(ctrl.open_stream().await?)
+    }
+
+    /// 空闲的连接发送 PING 检查是否可用，不可用时重新建立，重新建立失败时返回 false；正在使用的连接跳过
+    async fn check(&self, config: &ClientConfig, idle: Duration) -> bool {
+        if self.last_used.lock().unwrap().elapsed() < idle {
+            return true;
+        }
+        let Ok(mut ctrl) = self.ctrl.try_lock() else {
+            return true;
+        };
+        if let Some(ctrl) = ctrl.as_mut() {
+            match ping(ctrl).await {
+                Ok(()) => return true,
+                Err(e) => warn!("Health check of {} failed: {:?}", config.general.addr, e),
+            }
+        }
+        *ctrl = match connect(config).await {
+            Ok(ctrl) => {
+                info!("Reconnected to {}", config.general.addr);
+                Some(ctrl)
+            }
+            Err(e) => {
+                warn!("Failed to reconnect to {}: {:?}", config.general.addr, e);
+                None
+            }
+        };
+        ctrl.is_some()
+    }
+}
+
+impl<'a> Active<'a> {
+    fn new(active: &'a AtomicUsize) -> Self {
+        active.fetch_add(1, Ordering::Relaxed);
+        Self(active)
+    }
+}
+
+impl Drop for Active<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+fn spawn_health_check(inner: Weak<Inner>, interval: Duration) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut ticker = time::interval(interval);
+        // 第一次 tick 立即返回，刚建立的连接不需要检查
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+            let Some(inner) = inner.upgrade() else {
+                break;
+            };
+            inner.check(interval).await;
+        }
+    })
+}
+
+pub(crate) async fn connect(config: &ClientConfig) -> Result<ClientCtrl, KvError> {
+    start_client_with_config(config.clone())
+        .await
+        .map_err(|e| match e.downcast::<KvError>() {
+            Ok(e) => e,
+            Err(e) => KvError::Internal(format!("Failed to connect to server: {}", e)),
+        })
+}
+
+async fn ping(ctrl: &mut ClientCtrl) -> Result<(), KvError> {
+    let mut stream = ctrl.open_stream().await?;
+    let res = time::timeout(PING_TIMEOUT, stream.execute(&CommandRequest::new_ping()))
+        .await
+        .map_err(|_| KvError::Internal("PING timed out".into()))??;
+    if res.status != StatusCode::OK.as_u16() as u32 {
+        return Err(KvError::Internal(res.message));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod pool_tests {
+    use super::*;
+    use crate::{
+        config::{ServerConfig, StorageConfig},
+        start_server_with_config,
+    };
+
+    #[tokio::test]
+    async fn pool_should_reconnect_broken_connections() {
+        let addr = "127.0.0.1:10097";
+        let mut config: ServerConfig =
+            toml::from_str(include_str!("../../fixtures/server.conf")).unwrap();
+        config.general.addr = addr.into();
+        config.storage = StorageConfig::MemTable;
+        let _server = start_server_with_config(config).await.unwrap();
+
+        let mut config: ClientConfig =
+            toml::from_str(include_str!("../../fixtures/client.conf")).unwrap();
+        config.general.addr = addr.into();
+        config.pool.size = Some(2);
+        let pool = Pool::connect(config).await.unwrap();
+        assert_eq!(pool.size(), 2);
+
+        let tasks: Vec<_> = (0..8)
+            .map(|i| {
+                let pool = pool.clone();
+                tokio::spawn(async move {
+                    let cmd = CommandRequest::new_hset("t1", format!("k{}", i), i);
+                    pool.execute(&cmd).await.unwrap().status
+                })
+            })
+            .collect();
+        for task in tasks {
+            assert_eq!(task.await.unwrap(), 200);
+        }
+
+        // 断开所有的连接：第一个在使用时重连，第二个在健康检查时重连
+        for conn in &pool.inner.endpoints[0].conns {
+            conn.ctrl
+                .lock()
+                .await
+                .as_mut()
+                .unwrap()
+                .close()
+                .await
+                .unwrap();
+        }
+        let res = pool.execute(&CommandRequest::new_hget("t1", "k1")).await;
+        assert_eq!(res.unwrap().values, [1.into()]);
+
+        pool.inner.check(Duration::ZERO).await;
+        let mut ctrl = pool.inner.endpoints[0].conns[1].ctrl.lock().await;
+        assert!(ping(ctrl.as_mut().unwrap()).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn pool_should_balance_over_servers() {
+        let addrs = ["127.0.0.1:10121", "127.0.0.1:10122"];
+        // 没有服务器监听的地址
+        let dead = "127.0.0.1:10123";
+        let mut servers = vec![];
+        for addr in addrs {
+            let mut config: ServerConfig =
+                toml::from_str(include_str!("../../fixtures/server.conf")).unwrap();
+            config.general.addr = addr.into();
+            config.storage = StorageConfig::MemTable;
+            servers.push(start_server_with_config(config).await.unwrap());
+        }
+
+        let mut config: ClientConfig =
+            toml::from_str(include_str!("../../fixtures/client.conf")).unwrap();
+        config.general.addr = addrs[0].into();
+        config.pool.size = Some(1);
+        config.balance.addrs = vec![addrs[1].into(), dead.into()];
+        let pool = Pool::connect(config.clone()).await.unwrap();
+        assert_eq!(pool.size(), 3);
+        assert!(pool.inner.endpoints[2].is_ejected());
+
+        // 轮流写到两个服务器上，连不上的服务器不会被选中
+        for i in 0..4 {
+            let cmd = CommandRequest::new_hset("t1", format!("k{}", i), i);
+            assert_eq!(pool.execute(&cmd).await.unwrap().status, 200);
+        }
+        assert_eq!(count_keys(addrs[0]).await, 2);
+        assert_eq!(count_keys(addrs[1]).await, 2);
+
+        // primary 连不上时使用后面的服务器
+        config.general.addr = dead.into();
+        config.balance.addrs = vec![addrs[1].into(), addrs[0].into()];
+        config.balance.policy = BalancePolicy::PrimaryWithFallback;
+        let pool = Pool::connect(config).await.unwrap();
+        for i in 4..8 {
+            let cmd = CommandRequest::new_hset("t1", format!("k{}", i), i);
+            assert_eq!(pool.execute(&cmd).await.unwrap().status, 200);
+        }
+        assert_eq!(count_keys(addrs[0]).await, 2);
+        assert_eq!(count_keys(addrs[1]).await, 6);
+
+        // 所有的服务器都连不上时返回错误
+        let mut config: ClientConfig =
+            toml::from_str(include_str!("../../fixtures/client.conf")).unwrap();
+        config.general.addr = dead.into();
+        assert!(Pool::connect(config).await.is_err());
+    }
+
+    async fn count_keys(addr: &str) -> usize {
+        let mut config: ClientConfig =
+            toml::from_str(include_str!("../../fixtures/client.conf")).unwrap();
+        config.general.addr = addr.into();
+        let mut ctrl = connect(&config).await.unwrap();
+        let mut stream = ctrl.open_stream().await.unwrap();
+        let res = stream.execute(&CommandRequest::new_hgetall("t1")).await;
+        res.unwrap().pairs.len()
+    }
+}
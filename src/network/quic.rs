@@ -0,0 +1,318 @@
+use std::{
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use bytes::BytesMut;
+use quinn::{
+    crypto::rustls::{QuicClientConfig, QuicServerConfig},
+    rustls::{
+        self,
+        crypto::{ring, CryptoProvider},
+        pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+        server::WebPkiClientVerifier,
+        RootCertStore,
+    },
+    Connection, Endpoint, RecvStream, SendStream,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net, time,
+};
+
+use super::{frame::FrameCoder, multiplex::Multiplexer, tls::cert_identity};
+use crate::{
+    config::{ClientConfig, ServerTlsConfig},
+    error::KvError,
+    pb::abi::CommandResponse,
+    ProstClientStream,
+};
+
+/// 和 TLS 上的 yamux 使用同一个 ALPN
+const ALPN_KV: &[u8] = b"kv";
+
+/// 被拒绝的连接等待客户端打开第一个 stream 的最长时间
+const REJECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// 一个 QUIC 双向 stream，相当于 yamux 的一个 stream
+pub struct QuicStream {
+    send: SendStream,
+    recv: RecvStream,
+}
+
+/// 客户端的 QUIC 连接，每个命令打开一个新的 stream
+pub struct QuicCtrl {
+    conn: Connection,
+    max_frame_size: Option<usize>,
+    /// endpoint drop 之后连接不能再使用
+    _endpoint: Endpoint,
+}
+
+impl QuicStream {
+    pub fn new(send: SendStream, recv: RecvStream) -> Self {
+        Self { send, recv }
+    }
+}
+
+impl AsyncRead for QuicStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.recv).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for QuicStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        AsyncWrite::poll_write(Pin::new(&mut self.send), cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.send).poll_flush(cx)
+    }
+
+    /// 结束 stream 的发送方向，对端读到 EOF
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.send).poll_shutdown(cx)
+    }
+}
+
+impl QuicCtrl {
+    /// 连接 config.general.addr；QUIC 需要配置 tls.ca 来验证服务器的证书
+    pub async fn connect(config: &ClientConfig) -> Result<Self, KvError> {
+        let tls = &config.tls;
+        let ca = tls
+            .ca
+            .as_deref()
+            .ok_or_else(|| KvError::InvalidCommand("QUIC requires tls.ca".into()))?;
+        let builder = rustls::ClientConfig::builder_with_provider(provider())
+            .with_protocol_versions(&[&rustls::version::TLS13])
+            .map_err(quic_error)?
+            .with_root_certificates(load_roots(ca)?);
+        let mut crypto = match &tls.identity()? {
+            Some((cert, key)) => builder
+                .with_client_auth_cert(load_certs(cert)?, load_key(key)?)
+                .map_err(quic_error)?,
+            None => builder.with_no_client_auth(),
+        };
+        crypto.alpn_protocols = vec![ALPN_KV.to_vec()];
+        let crypto = QuicClientConfig::try_from(crypto).map_err(quic_error)?;
+
+        let addr = resolve(&config.general.addr).await?;
+        let local: SocketAddr = match addr {
+            SocketAddr::V4(_) => ([0, 0, 0, 0], 0).into(),
+            SocketAddr::V6(_) => ([0u16; 8], 0).into(),
+        };
+        let mut endpoint = Endpoint::client(local)?;
+        endpoint.set_default_client_config(quinn::ClientConfig::new(Arc::new(crypto)));
+        let conn = endpoint
+            .connect(addr, &tls.domain)
+            .map_err(quic_error)?
+            .await
+            .map_err(quic_error)?;
+        Ok(Self {
+            conn,
+            max_frame_size: config.max_frame_size,
+            _endpoint: endpoint,
+        })
+    }
+
+    pub async fn open_stream(&mut self) -> Result<ProstClientStream<QuicStream>, KvError> {
+        let (send, recv) = self.conn.open_bi().await.map_err(quic_error)?;
+        let mut stream = ProstClientStream::new(QuicStream::new(send, recv));
+        stream.set_max_frame_size(self.max_frame_size);
+        Ok(stream)
+    }
+
+    /// 关闭连接，之后打开 stream 都会失败
+    pub fn close(&self) {
+        self.conn.close(0u32.into(), b"");
+    }
+}
+
+impl Multiplexer for QuicCtrl {
+    type Stream = QuicStream;
+
+    async fn open_stream(&mut self) -> Result<ProstClientStream<QuicStream>, KvError> {
+        QuicCtrl::open_stream(self).await
+    }
+}
+
+/// 创建服务器的 endpoint，证书和 TLS 一样；client_auth 是验证客户端证书的 CA 和是否要求客户端提供证书
+pub fn server_endpoint(
+    addr: &str,
+    tls: &ServerTlsConfig,
+    client_auth: Option<(String, bool)>,
+) -> Result<Endpoint, KvError> {
+    let addr: SocketAddr = addr
+        .parse()
+        .map_err(|_| KvError::InvalidCommand(format!("invalid QUIC address {}", addr)))?;
+    let builder = rustls::ServerConfig::builder_with_provider(provider())
+        .with_protocol_versions(&[&rustls::version::TLS13])
+        .map_err(quic_error)?;
+    let builder = match client_auth {
+        Some((ca, required)) => {
+            let verifier =
+                WebPkiClientVerifier::builder_with_provider(Arc::new(load_roots(&ca)?), provider());
+            let verifier = match required {
+                true => verifier,
+                false => verifier.allow_unauthenticated(),
+            };
+            let verifier = verifier.build().map_err(quic_error)?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+    let (cert, key) = tls.identity()?;
+    let mut crypto = builder
+        .with_single_cert(load_certs(&cert)?, load_key(&key)?)
+        .map_err(quic_error)?;
+    crypto.alpn_protocols = vec![ALPN_KV.to_vec()];
+    let crypto = QuicServerConfig::try_from(crypto).map_err(quic_error)?;
+    let config = quinn::ServerConfig::with_crypto(Arc::new(crypto));
+    Ok(Endpoint::server(config, addr)?)
+}
+
+/// 超过连接数限制时，在客户端打开的第一个 stream 上返回错误，然后关闭连接
+pub async fn reject(conn: Connection, e: KvError) {
+    let mut buf = BytesMut::new();
+    if CommandResponse::from(e).encode_frame(&mut buf).is_ok() {
+        if let Ok(Ok((mut send, _recv))) = time::timeout(REJECT_TIMEOUT, conn.accept_bi()).await {
+            if send.write_all(&buf).await.is_ok() && send.finish().is_ok() {
+                // 等客户端收到响应之后再关闭连接，否则没有发出去的数据会被丢掉
+                let _ = time::timeout(REJECT_TIMEOUT, send.stopped()).await;
+            }
+        }
+    }
+    conn.close(0u32.into(), b"too many connections");
+}
+
+/// 客户端证书对应的用户名，客户端没有提供证书时返回 None
+pub fn peer_identity(conn: &Connection) -> Option<String> {
+    let certs = conn
+        .peer_identity()?
+        .downcast::<Vec<CertificateDer<'static>>>()
+        .ok()?;
+    cert_identity(certs.first()?)
+}
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(ring::default_provider())
+}
+
+fn load_certs(cert: &str) -> Result<Vec<CertificateDer<'static>>, KvError> {
+    CertificateDer::pem_slice_iter(cert.as_bytes())
+        .collect::<Result<_, _>>()
+        .map_err(|_| KvError::CertificateParseError("server".into(), "cert".into()))
+}
+
+fn load_key(key: &str) -> Result<PrivateKeyDer<'static>, KvError> {
+    PrivateKeyDer::from_pem_slice(key.as_bytes())
+        .map_err(|_| KvError::CertificateParseError("private".into(), "key".into()))
+}
+
+fn load_roots(ca: &str) -> Result<RootCertStore, KvError> {
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(ca)? {
+        roots.add(cert).map_err(quic_error)?;
+    }
+    Ok(roots)
+}
+
+async fn resolve(addr: &str) -> Result<SocketAddr, KvError> {
+    net::lookup_host(addr)
+        .await?
+        .next()
+        .ok_or_else(|| KvError::InvalidCommand(format!("cannot resolve {}", addr)))
+}
+
+fn quic_error(e: impl std::fmt::Display) -> KvError {
+    KvError::Internal(format!("QUIC error: {}", e))
+}
+
+#[cfg(test)]
+mod quic_tests {
+    use super::*;
+    use crate::{
+        config::{QuicConfig, ServerConfig, StorageConfig},
+        pb::abi::CommandRequest,
+        start_server_with_config, ServerHandle,
+    };
+
+    async fn start_server(
+        addr: &str,
+        quic_addr: &str,
+        max_connections: Option<usize>,
+    ) -> ServerHandle {
+        let mut config: ServerConfig =
+            toml::from_str(include_str!("../../fixtures/server.conf")).unwrap();
+        config.general.addr = addr.into();
+        config.storage = StorageConfig::MemTable;
+        config.quic = Some(QuicConfig {
+            addr: quic_addr.into(),
+        });
+        config.max_connections = max_connections;
+        start_server_with_config(config).await.unwrap()
+    }
+
+    fn client_config(addr: &str) -> ClientConfig {
+        let mut config: ClientConfig =
+            toml::from_str(include_str!("../../fixtures/client.conf")).unwrap();
+        config.general.addr = addr.into();
+        config
+    }
+
+    /// 只依赖 Multiplexer，yamux 和 QUIC 都可以使用
+    async fn hset_and_hget(ctrl: &mut impl Multiplexer) -> Result<CommandResponse, KvError> {
+        let cmd = CommandRequest::new_hset("t1", "k1", "v1");
+        ctrl.open_stream().await?.execute(&cmd).await?;
+        let cmd = CommandRequest::new_hget("t1", "k1");
+        ctrl.open_stream().await?.execute(&cmd).await
+    }
+
+    #[tokio::test]
+    async fn quic_client_should_work() {
+        let _server = start_server("127.0.0.1:10101", "127.0.0.1:10102", None).await;
+
+        let mut ctrl = QuicCtrl::connect(&client_config("127.0.0.1:10102"))
+            .await
+            .unwrap();
+        let res = hset_and_hget(&mut ctrl).await.unwrap();
+        assert_eq!(res.values, ["v1".into()]);
+
+        // QUIC 和 yamux 访问的是同一个 service
+        let mut ctrl = crate::start_client_with_config(client_config("127.0.0.1:10101"))
+            .await
+            .unwrap();
+        let cmd = CommandRequest::new_hget("t1", "k1");
+        let res = ctrl.open_stream().await.unwrap().execute(&cmd).await;
+        assert_eq!(res.unwrap().values, ["v1".into()]);
+    }
+
+    #[tokio::test]
+    async fn quic_connections_over_limit_should_be_rejected() {
+        let _server = start_server("127.0.0.1:10103", "127.0.0.1:10104", Some(1)).await;
+
+        let mut ctrl = QuicCtrl::connect(&client_config("127.0.0.1:10104"))
+            .await
+            .unwrap();
+        assert!(hset_and_hget(&mut ctrl).await.is_ok());
+
+        let mut rejected = QuicCtrl::connect(&client_config("127.0.0.1:10104"))
+            .await
+            .unwrap();
+        let cmd = CommandRequest::new_ping();
+        let res = rejected.open_stream().await.unwrap().execute(&cmd).await;
+        assert_eq!(res.unwrap().status, 503);
+    }
+}
@@ -0,0 +1,228 @@
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    time::Duration,
+};
+
+use tokio::{sync::Mutex, time};
+use tokio_util::compat::Compat;
+use tracing::warn;
+
+use crate::{
+    config::ClientConfig,
+    error::KvError,
+    pb::abi::{command_request::RequestData, CommandRequest, CommandResponse},
+    pool::{connect, ClientCtrl},
+    ProstClientStream,
+};
+
+/// 默认最多重试的次数
+const DEFAULT_MAX_RETRIES: u32 = 3;
+
+/// 默认第一次重试之前的退避时间（毫秒）
+const DEFAULT_INITIAL_BACKOFF: u64 = 100;
+
+/// 默认退避时间的上限（毫秒）
+const DEFAULT_MAX_BACKOFF: u64 = 5000;
+
+/// 断线自动重连的客户端：连接断开之后重新建立连接，按指数退避重试。
+/// 命令还没有发出去时总是可以重试；已经发出去之后只重试幂等的命令和带了幂等 key 的命令，避免同一个修改执行两次
+pub struct ReconnectingClient {
+    config: ClientConfig,
+    /// None 表示连接断开之后还没有重新建立
+    ctrl: Mutex<Option<ClientCtrl>>,
+    max_retries: u32,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+    timeout: Option<Duration>,
+}
+
+impl ReconnectingClient {
+    pub async fn connect(config: ClientConfig) -> Result<Self, KvError> {
+        let ctrl = connect(&config).await?;
+        let retry = &config.retry;
+        Ok(Self {
+            max_retries: retry.max_retries.unwrap_or(DEFAULT_MAX_RETRIES),
+            initial_backoff: Duration::from_millis(
+                retry.initial_backoff.unwrap_or(DEFAULT_INITIAL_BACKOFF),
+            ),
+            max_backoff: Duration::from_millis(retry.max_backoff.unwrap_or(DEFAULT_MAX_BACKOFF)),
+            timeout: config.timeout.map(Duration::from_millis),
+            ctrl: Mutex::new(Some(ctrl)),
+            config,
+        })
+    }
+
+    /// 执行命令，连接断开时重连之后重试
+    pub async fn execute(&self, cmd: &CommandRequest) -> Result<CommandResponse, KvError> {
+        let idempotent = is_retryable(cmd);
+        let mut attempt = 0;
+        loop {
+            let result = match self.open_stream().await {
+                Ok(mut stream) => {
+                    let result = match self.timeout {
+                        Some(timeout) => stream.execute_with_timeout(cmd, timeout).await,
+                        None => stream.execute(cmd).await,
+                    };
+                    match result {
+                        Err(e) if !idempotent => return Err(e),
+                        result => result,
+                    }
+                }
+                Err(e) => Err(e),
+            };
+            match result {
+                Ok(res) => return Ok(res),
+                // 认证失败重试也不会成功，超时之后重试只会更慢
+                Err(e @ (KvError::Unauthorized(_) | KvError::Timeout(_))) => return Err(e),
+                Err(e) if attempt >= self.max_retries => return Err(e),
+                Err(e) => {
+                    let backoff = self.backoff(attempt);
+                    warn!("Request failed: {:?}, retry in {:?}", e, backoff);
+                    time::sleep(backoff).await;
+                    attempt += 1;
+                }
+            }
+        }
+    }
+
+    /// 打开一个 stream，连接已经断开时先重新建立
+    async fn open_stream(&self) -> Result<ProstClientStream<Compat<yamux::Stream>>, KvError> {
+        let mut ctrl = self.ctrl.lock().await;
+        if let Some(c) = ctrl.as_mut() {
+            match c.open_stream().await {
+                Ok(stream) => return Ok(stream),
+                Err(e) => warn!(
+                    "Connection to {} is broken: {:?}",
+                    self.config.general.addr, e
+                ),
+            }
+        }
+        *ctrl = None;
+        let ctrl = ctrl.insert(connect(&self.config).await?);
+        Ok(ctrl.open_stream().await?)
+    }
+
+    /// 第 attempt 次重试之前等待的时间：在 0 到 initial_backoff * 2^attempt 之间随机选取
+    fn backoff(&self, attempt: u32) -> Duration {
+        let backoff = self
+            .initial_backoff
+            .saturating_mul(2u32.saturating_pow(attempt))
+            .min(self.max_backoff);
+        let random = RandomState::new().build_hasher().finish();
+        let millis = backoff.as_millis() as u64;
+        Duration::from_millis(random % (millis + 1))
+    }
+}
+
+/// 发出去之后还可以重试的命令：本身幂等，或者带了幂等 key，服务器不会执行两次
+fn is_retryable(cmd: &CommandRequest) -> bool {
+    !cmd.idempotency_key.is_empty() || cmd.request_data.as_ref().is_some_and(is_idempotent)
+}
+
+/// 执行多次和执行一次效果一样的命令
+pub(crate) fn is_idempotent(cmd: &RequestData) -> bool {
+    match cmd {
+        RequestData::Hget(_)
+        | RequestData::Hgetall(_)
+        | RequestData::Hmget(_)
+        | RequestData::Hexist(_)
+        | RequestData::Hmexist(_)
+        | RequestData::Httl(_)
+        | RequestData::Hstrlen(_)
+        | RequestData::Hgetrange(_)
+        | RequestData::Hgetbit(_)
+        | RequestData::Hbitcount(_)
+        | RequestData::Hpfcount(_)
+        | RequestData::Hscan(_)
+        | RequestData::Hset(_)
+        | RequestData::Hsetbit(_)
+        | RequestData::Hpfadd(_)
+        | RequestData::Hmset(_)
+        | RequestData::Hdel(_)
+        | RequestData::Hmdel(_)
+        | RequestData::ConfigGet(_)
+        | RequestData::Ping(_) => true,
+        RequestData::Batch(v) => v
+            .commands
+            .iter()
+            .all(|cmd| cmd.request_data.as_ref().is_some_and(is_idempotent)),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod reconnect_tests {
+    use super::*;
+    use crate::{
+        config::{ServerConfig, StorageConfig},
+        start_server_with_config,
+    };
+
+    #[tokio::test]
+    async fn client_should_reconnect_after_connection_closed() {
+        let addr = "127.0.0.1:10098";
+        let mut config: ServerConfig =
+            toml::from_str(include_str!("../../fixtures/server.conf")).unwrap();
+        config.general.addr = addr.into();
+        config.storage = StorageConfig::MemTable;
+        let _server = start_server_with_config(config).await.unwrap();
+
+        let mut config: ClientConfig =
+            toml::from_str(include_str!("../../fixtures/client.conf")).unwrap();
+        config.general.addr = addr.into();
+        let client = ReconnectingClient::connect(config).await.unwrap();
+        let cmd = CommandRequest::new_hset("t1", "k1", "v1");
+        assert_eq!(client.execute(&cmd).await.unwrap().status, 200);
+
+        // 命令还没有发出去就发现连接断了，不幂等的命令也会重试
+        close(&client).await;
+        let cmd = CommandRequest::new_hincrby("t1", "k2", 1);
+        assert_eq!(client.execute(&cmd).await.unwrap().values, [1.into()]);
+        close(&client).await;
+        let cmd = CommandRequest::new_hget("t1", "k1");
+        assert_eq!(client.execute(&cmd).await.unwrap().values, ["v1".into()]);
+    }
+
+    async fn close(client: &ReconnectingClient) {
+        let mut ctrl = client.ctrl.lock().await;
+        ctrl.as_mut().unwrap().close().await.unwrap();
+    }
+
+    #[test]
+    fn backoff_should_grow_exponentially_with_cap() {
+        let client = ReconnectingClient {
+            config: toml::from_str(include_str!("../../fixtures/client.conf")).unwrap(),
+            ctrl: Mutex::new(None),
+            max_retries: 3,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_millis(500),
+            timeout: None,
+        };
+        for _ in 0..100 {
+            assert!(client.backoff(0) <= Duration::from_millis(100));
+            assert!(client.backoff(2) <= Duration::from_millis(400));
+            assert!(client.backoff(10) <= Duration::from_millis(500));
+        }
+    }
+
+    #[test]
+    fn only_idempotent_commands_should_be_retried() {
+        let idempotent = |cmd: CommandRequest| is_idempotent(&cmd.request_data.unwrap());
+        assert!(idempotent(CommandRequest::new_hget("t1", "k1")));
+        assert!(idempotent(CommandRequest::new_hset("t1", "k1", "v1")));
+        assert!(!idempotent(CommandRequest::new_hincrby("t1", "k1", 1)));
+        assert!(!idempotent(CommandRequest::new_publish("lobby", vec![])));
+        assert!(idempotent(CommandRequest::new_batch([
+            CommandRequest::new_hset("t1", "k1", "v1"),
+            CommandRequest::new_hdel("t1", "k2"),
+        ])));
+        assert!(!idempotent(CommandRequest::new_batch([
+            CommandRequest::new_hset("t1", "k1", "v1"),
+            CommandRequest::new_hincrby("t1", "k2", 1),
+        ])));
+        let cmd = CommandRequest::new_hincrby("t1", "k1", 1);
+        assert!(!is_retryable(&cmd));
+        assert!(is_retryable(&cmd.with_idempotency_key("a")));
+    }
+}
@@ -0,0 +1,607 @@
+use std::{mem, sync::Arc, time::Duration};
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use futures::{SinkExt, StreamExt};
+use hyper::StatusCode;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_util::codec::{Decoder, Encoder, Framed, FramedParts};
+use tracing::{debug, info};
+
+use crate::{
+    buffer::CONNECTION_BUFFERS,
+    error::KvError,
+    pb::abi::{value, CommandRequest, CommandResponse, Value},
+    session::Session,
+    Service, Storage,
+};
+
+/// 单个 bulk string 的上限，和 redis 一样是 512MB
+const MAX_BULK_LEN: usize = 512 * 1024 * 1024;
+
+/// 一个命令最多有多少个参数
+const MAX_ARRAY_LEN: usize = 1024 * 1024;
+
+/// RESP2/RESP3 的数据类型；Map、Boolean、Double 只在 RESP3 下原样发送，RESP2 下会降级
+#[derive(Debug, Clone, PartialEq)]
+pub enum RespFrame {
+    Simple(String),
+    Error(String),
+    Integer(i64),
+    Bulk(Bytes),
+    Null,
+    Array(Vec<RespFrame>),
+    Map(Vec<(RespFrame, RespFrame)>),
+    Boolean(bool),
+    Double(f64),
+}
+
+/// 在 tcp 上收发 RespFrame；version 是协议版本，客户端通过 HELLO 切换
+#[derive(Debug)]
+pub struct RespCodec {
+    pub version: u8,
+}
+
+impl Default for RespCodec {
+    fn default() -> Self {
+        Self { version: 2 }
+    }
+}
+
+impl Decoder for RespCodec {
+    type Item = RespFrame;
+    type Error = KvError;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        match parse(buf)? {
+            Some((frame, len)) => {
+                buf.advance(len);
+                Ok(Some(frame))
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+impl Encoder<RespFrame> for RespCodec {
+    type Error = KvError;
+
+    fn encode(&mut self, frame: RespFrame, buf: &mut BytesMut) -> Result<(), Self::Error> {
+        let resp3 = self.version >= 3;
+        match frame {
+            RespFrame::Simple(s) => put_line(buf, b'+', s.as_bytes()),
+            RespFrame::Error(s) => put_line(buf, b'-', s.as_bytes()),
+            RespFrame::Integer(i) => put_line(buf, b':', i.to_string().as_bytes()),
+            RespFrame::Bulk(b) => {
+                put_line(buf, b'$', b.len().to_string().as_bytes());
+                buf.put_slice(&b);
+                buf.put_slice(b"\r\n");
+            }
+            RespFrame::Null if resp3 => buf.put_slice(b"_\r\n"),
+            RespFrame::Null => buf.put_slice(b"$-1\r\n"),
+            RespFrame::Array(items) => {
+                put_line(buf, b'*', items.len().to_string().as_bytes());
+                for item in items {
+                    self.encode(item, buf)?;
+                }
+            }
+            RespFrame::Map(pairs) => {
+                // RESP2 没有 map，展开成 [k1, v1, k2, v2, ...]
+                match resp3 {
+                    true => put_line(buf, b'%', pairs.len().to_string().as_bytes()),
+                    false => put_line(buf, b'*', (pairs.len() * 2).to_string().as_bytes()),
+                }
+                for (k, v) in pairs {
+                    self.encode(k, buf)?;
+                    self.encode(v, buf)?;
+                }
+            }
+            RespFrame::Boolean(b) if resp3 => buf.put_slice(if b { b"#t\r\n" } else { b"#f\r\n" }),
+            RespFrame::Boolean(b) => put_line(buf, b':', if b { b"1" } else { b"0" }),
+            RespFrame::Double(f) if resp3 => put_line(buf, b',', f.to_string().as_bytes()),
+            RespFrame::Double(f) => self.encode(RespFrame::Bulk(f.to_string().into()), buf)?,
+        }
+        Ok(())
+    }
+}
+
+fn put_line(buf: &mut BytesMut, prefix: u8, line: &[u8]) {
+    buf.put_u8(prefix);
+    buf.put_slice(line);
+    buf.put_slice(b"\r\n");
+}
+
+/// 解析一个完整的 frame，返回 frame 和它占用的字节数；数据不完整时返回 None。
+/// 客户端发送的命令是 bulk string 数组，也支持 telnet 那样的 inline 命令
+fn parse(buf: &[u8]) -> Result<Option<(RespFrame, usize)>, KvError> {
+    let Some(end) = find_crlf(buf) else {
+        return Ok(None);
+    };
+    let line = buf.get(1..end).unwrap_or_default();
+    let next = end + 2;
+    let frame = match buf[0] {
+        b'+' => RespFrame::Simple(String::from_utf8_lossy(line).into()),
+        b'-' => RespFrame::Error(String::from_utf8_lossy(line).into()),
+        b':' => RespFrame::Integer(parse_int(line)?),
+        b'$' => {
+            let len = parse_int(line)?;
+            if len < 0 {
+                return Ok(Some((RespFrame::Null, next)));
+            }
+            let len = len as usize;
+            if len > MAX_BULK_LEN {
+                return Err(KvError::InvalidCommand("invalid bulk length".into()));
+            }
+            if buf.len() < next + len + 2 {
+                return Ok(None);
+            }
+            if &buf[next + len..next + len + 2] != b"\r\n" {
+                return Err(KvError::InvalidCommand("expected CRLF after bulk".into()));
+            }
+            let data = Bytes::copy_from_slice(&buf[next..next + len]);
+            return Ok(Some((RespFrame::Bulk(data), next + len + 2)));
+        }
+        b'*' => {
+            let len = parse_int(line)?;
+            if len < 0 {
+                return Ok(Some((RespFrame::Null, next)));
+            }
+            if len as usize > MAX_ARRAY_LEN {
+                return Err(KvError::InvalidCommand("invalid multibulk length".into()));
+            }
+            let mut items = Vec::with_capacity(len as usize);
+            let mut pos = next;
+            for _ in 0..len {
+                match parse(&buf[pos..])? {
+                    Some((item, used)) => {
+                        items.push(item);
+                        pos += used;
+                    }
+                    None => return Ok(None),
+                }
+            }
+            return Ok(Some((RespFrame::Array(items), pos)));
+        }
+        _ => {
+            let items = buf[..end]
+                .split(|c| c.is_ascii_whitespace())
+                .filter(|s| !s.is_empty())
+                .map(|s| RespFrame::Bulk(Bytes::copy_from_slice(s)))
+                .collect();
+            RespFrame::Array(items)
+        }
+    };
+    Ok(Some((frame, next)))
+}
+
+fn find_crlf(buf: &[u8]) -> Option<usize> {
+    buf.windows(2).position(|w| w == b"\r\n")
+}
+
+fn parse_int(line: &[u8]) -> Result<i64, KvError> {
+    std::str::from_utf8(line)
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .ok_or_else(|| KvError::InvalidCommand("invalid integer".into()))
+}
+
+/// 处理一个 redis 客户端的连接，把 redis 命令翻译成 CommandRequest 交给 Service 执行
+pub struct RespServerStream<S, Store> {
+    stream: Framed<S, RespCodec>,
+    service: Service<Store>,
+    session: Arc<Session>,
+    /// 空闲超过这个时间之后断开连接
+    idle_timeout: Option<Duration>,
+}
+
+impl<S, Store> RespServerStream<S, Store>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+    Store: Storage,
+{
+    pub fn new(stream: S, service: Service<Store>) -> Self {
+        let mut parts = FramedParts::new::<RespFrame>(stream, RespCodec::default());
+        parts.read_buf = CONNECTION_BUFFERS.get();
+        parts.write_buf = CONNECTION_BUFFERS.get();
+        Self {
+            stream: Framed::from_parts(parts),
+            service,
+            session: Arc::new(Session::new()),
+            idle_timeout: None,
+        }
+    }
+
+    /// 记录客户端的地址，在开始处理请求之前调用
+    pub fn with_peer(mut self, peer: impl ToString) -> Self {
+        self.session = Arc::new(Session::new().with_peer(peer));
+        self
+    }
+
+    /// 没有执行中的命令超过 timeout 之后断开连接
+    pub fn with_idle_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.idle_timeout = timeout;
+        self
+    }
+
+    pub async fn process(mut self) -> Result<(), KvError> {
+        let shutdown = self.service.shutdown().clone();
+        let session = self.session.clone();
+        let _client = self.service.connect(&session);
+        let _stream = session.open_stream();
+        loop {
+            // 开始退出之后不再读取新的请求
+            let frame = tokio::select! {
+                frame = self.stream.next() => frame,
+                _ = shutdown.triggered() => break,
+                _ = session.killed() => break,
+                _ = session.idle(self.idle_timeout.unwrap_or_default()),
+                    if self.idle_timeout.is_some() =>
+                {
+                    info!("Close idle redis client");
+                    break;
+                }
+            };
+            let Some(frame) = frame else {
+                break;
+            };
+            let _in_flight = shutdown.start();
+            let frame = match frame {
+                Ok(frame) => frame,
+                // 协议错误之后没办法继续解析，回复错误后断开连接
+                Err(e) => {
+                    let reply = RespFrame::Error(format!("ERR Protocol error: {}", e));
+                    self.stream.send(reply).await?;
+                    break;
+                }
+            };
+            let args = match frame {
+                RespFrame::Array(items) => items
+                    .into_iter()
+                    .map(|item| match item {
+                        RespFrame::Bulk(b) => Some(b),
+                        _ => None,
+                    })
+                    .collect::<Option<Vec<Bytes>>>(),
+                _ => None,
+            };
+            let reply = match args {
+                Some(args) if args[0].eq_ignore_ascii_case(b"QUIT") => {
+                    self.stream.send(RespFrame::Simple("OK".into())).await?;
+                    break;
+                }
+                Some(args) if args.is_empty() => continue,
+                Some(args) => {
+                    // 配额用完时在这里等待，这期间不再读取新的请求
+                    let _permit = self.service.in_flight.acquire(&self.session).await;
+                    let _busy = self.session.busy();
+                    self.handle(args).await
+                }
+                None => RespFrame::Error("ERR Protocol error: expected array of bulk".into()),
+            };
+            self.stream.send(reply).await?;
+        }
+        info!("Redis client disconnected");
+        Ok(())
+    }
+
+    async fn handle(&mut self, args: Vec<Bytes>) -> RespFrame {
+        let name = String::from_utf8_lossy(&args[0]).to_ascii_uppercase();
+        let args = &args[1..];
+        debug!("Got redis command {} with {} args", name, args.len());
+        let arity_error = || {
+            RespFrame::Error(format!(
+                "ERR wrong number of arguments for '{}' command",
+                name.to_ascii_lowercase()
+            ))
+        };
+
+        match (name.as_str(), args.len()) {
+            ("PING", 0) => RespFrame::Simple("PONG".into()),
+            ("PING", 1) => RespFrame::Bulk(args[0].clone()),
+            ("ECHO", 1) => RespFrame::Bulk(args[0].clone()),
+            ("HELLO", _) => self.hello(args).await,
+            ("AUTH", 1) => self.auth("", &args[0]).await,
+            ("AUTH", 2) => self.auth(&to_string(&args[0]), &args[1]).await,
+            ("HSET", n) if n >= 3 && n % 2 == 1 => {
+                let cmds = args[1..]
+                    .chunks(2)
+                    .map(|pair| CommandRequest::new_hset(to_string(&args[0]), &pair[0], &pair[1]));
+                // 和 redis 一样返回新增的 field 数量
+                let res = self.execute(CommandRequest::new_batch(cmds)).await;
+                count_results(res, |v| *v == Value::default())
+            }
+            ("HGET", 2) => {
+                let cmd = CommandRequest::new_hget(to_string(&args[0]), &args[1]);
+                let res = self.execute(cmd).await;
+                match res.status {
+                    200 => res.values.first().map_or(RespFrame::Null, to_frame),
+                    404 => RespFrame::Null,
+                    _ => to_error(&res),
+                }
+            }
+            ("HSTRLEN", 2) => {
+                let cmd = CommandRequest::new_hstrlen(to_string(&args[0]), &args[1]);
+                let res = self.execute(cmd).await;
+                match res.status {
+                    200 => res.values.first().map_or(RespFrame::Null, to_frame),
+                    _ => to_error(&res),
+                }
+            }
+            ("HSETNX", 3) => {
+                let cmd = CommandRequest::new_hsetnx(to_string(&args[0]), &args[1], &args[2], 0);
+                let res = self.execute(cmd).await;
+                // 和 redis 一样，设置成功返回 1，key 已经存在返回 0
+                match res.status {
+                    200 => RespFrame::Integer((res.values.first() == Some(&true.into())).into()),
+                    _ => to_error(&res),
+                }
+            }
+            ("HMSET", n) if n >= 3 && n % 2 == 1 => {
+                let pairs = args[1..].chunks(2).map(|pair| (&pair[0], &pair[1]));
+                let cmd = CommandRequest::new_hmset(to_string(&args[0]), pairs);
+                let res = self.execute(cmd).await;
+                match res.status {
+                    200 => RespFrame::Simple("OK".into()),
+                    _ => to_error(&res),
+                }
+            }
+            ("HMGET", n) if n >= 2 => {
+                let cmd = CommandRequest::new_hmget(to_string(&args[0]), &args[1..]);
+                let res = self.execute(cmd).await;
+                match res.status {
+                    // 不存在的 key 返回 Value::default()，对应 nil
+                    200 => RespFrame::Array(res.values.iter().map(to_frame).collect()),
+                    _ => to_error(&res),
+                }
+            }
+            ("HDEL", n) if n >= 2 => {
+                let cmds = args[1..]
+                    .iter()
+                    .map(|key| CommandRequest::new_hdel(to_string(&args[0]), key));
+                // 返回实际删除的 field 数量
+                let res = self.execute(CommandRequest::new_batch(cmds)).await;
+                count_results(res, |v| *v != Value::default())
+            }
+            ("HGETALL", 1) => {
+                let res = self
+                    .execute(CommandRequest::new_hgetall(to_string(&args[0])))
+                    .await;
+                match res.status {
+                    200 => RespFrame::Map(
+                        res.pairs
+                            .iter()
+                            .map(|pair| {
+                                let value = pair.value.as_ref().map_or(RespFrame::Null, to_frame);
+                                (RespFrame::Bulk(pair.key.clone()), value)
+                            })
+                            .collect(),
+                    ),
+                    _ => to_error(&res),
+                }
+            }
+            (
+                "PING" | "ECHO" | "AUTH" | "HSET" | "HSETNX" | "HGET" | "HSTRLEN" | "HMSET"
+                | "HMGET" | "HDEL" | "HGETALL",
+                _,
+            ) => arity_error(),
+            _ => RespFrame::Error(format!(
+                "ERR unknown command '{}'",
+                name.to_ascii_lowercase()
+            )),
+        }
+    }
+
+    /// HELLO [protover [AUTH username password]]
+    async fn hello(&mut self, args: &[Bytes]) -> RespFrame {
+        let version = match args.first().map(|v| parse_int(v)) {
+            None => self.stream.codec().version,
+            Some(Ok(v @ (2 | 3))) => v as u8,
+            Some(_) => {
+                return RespFrame::Error("NOPROTO unsupported protocol version".into());
+            }
+        };
+        match &args[args.len().min(1)..] {
+            [] => {}
+            [auth, user, password] if auth.eq_ignore_ascii_case(b"AUTH") => {
+                let reply = self.auth(&to_string(user), password).await;
+                if reply != RespFrame::Simple("OK".into()) {
+                    return reply;
+                }
+            }
+            _ => return RespFrame::Error("ERR syntax error in HELLO option".into()),
+        }
+
+        self.stream.codec_mut().version = version;
+        RespFrame::Map(vec![
+            (bulk("server"), bulk("kv-sv")),
+            (bulk("version"), bulk(env!("CARGO_PKG_VERSION"))),
+            (bulk("proto"), RespFrame::Integer(version as i64)),
+            (bulk("mode"), bulk("standalone")),
+        ])
+    }
+
+    async fn auth(&mut self, username: &str, password: &[u8]) -> RespFrame {
+        let cmd = CommandRequest::new_auth(username, String::from_utf8_lossy(password));
+        let res = self.execute(cmd).await;
+        match res.status {
+            200 => RespFrame::Simple("OK".into()),
+            _ => RespFrame::Error(format!("WRONGPASS {}", res.message)),
+        }
+    }
+
+    async fn execute(&self, cmd: CommandRequest) -> CommandResponse {
+        let mut res = self.service.execute_with_deadline(cmd, &self.session).await;
+        match res.next().await {
+            Some(res) => res.as_ref().clone(),
+            None => KvError::Internal("Didn't get any response".into()).into(),
+        }
+    }
+}
+
+impl<S, Store> Drop for RespServerStream<S, Store> {
+    fn drop(&mut self) {
+        CONNECTION_BUFFERS.put(mem::take(self.stream.read_buffer_mut()));
+        CONNECTION_BUFFERS.put(mem::take(self.stream.write_buffer_mut()));
+    }
+}
+
+fn to_string(b: &Bytes) -> String {
+    String::from_utf8_lossy(b).into()
+}
+
+fn bulk(s: &str) -> RespFrame {
+    RespFrame::Bulk(Bytes::copy_from_slice(s.as_bytes()))
+}
+
+/// 和 redis 一样，连接数超过限制时直接发送错误然后关闭连接
+pub async fn reject<S>(stream: S, e: KvError)
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    let mut stream = Framed::new(stream, RespCodec::default());
+    let frame = RespFrame::Error(format!("ERR {}", e));
+    if let Err(e) = stream.send(frame).await {
+        debug!("Failed to reject redis client: {:?}", e);
+    }
+}
+
+fn to_frame(v: &Value) -> RespFrame {
+    match &v.value {
+        Some(value::Value::String(s)) => RespFrame::Bulk(s.clone().into()),
+        Some(value::Value::Binary(b)) => RespFrame::Bulk(b.clone()),
+        Some(value::Value::Json(text)) => RespFrame::Bulk(text.clone().into()),
+        Some(value::Value::Integer(i)) => RespFrame::Integer(*i),
+        Some(value::Value::Float(f)) => RespFrame::Double(*f),
+        Some(value::Value::Bool(b)) => RespFrame::Boolean(*b),
+        Some(value::Value::List(list)) => {
+            RespFrame::Array(list.values.iter().map(to_frame).collect())
+        }
+        Some(value::Value::Set(set)) => {
+            RespFrame::Array(set.members.iter().map(to_frame).collect())
+        }
+        Some(value::Value::Map(map)) => RespFrame::Map(
+            map.entries
+                .iter()
+                .map(|(k, v)| (bulk(k), to_frame(v)))
+                .collect(),
+        ),
+        None => RespFrame::Null,
+    }
+}
+
+fn to_error(res: &CommandResponse) -> RespFrame {
+    // 和 redis 一样，内存不足时用 OOM 前缀
+    if res.status == StatusCode::INSUFFICIENT_STORAGE.as_u16() as u32 {
+        return RespFrame::Error(format!("OOM {}", res.message));
+    }
+    RespFrame::Error(format!("ERR {}", res.message))
+}
+
+/// batch 中第一个失败的命令作为错误返回，否则返回 values[0] 满足 f 的命令数量
+fn count_results(res: CommandResponse, f: impl Fn(&Value) -> bool) -> RespFrame {
+    if res.status != StatusCode::OK.as_u16() as u32 {
+        return to_error(&res);
+    }
+    if let Some(failed) = res.results.iter().find(|r| r.status != 200) {
+        return to_error(failed);
+    }
+    let count = res
+        .results
+        .iter()
+        .filter(|r| r.values.first().is_some_and(&f))
+        .count();
+    RespFrame::Integer(count as i64)
+}
+
+#[cfg(test)]
+mod resp_tests {
+    use super::*;
+    use crate::service_builder::ServiceBuilder;
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt, DuplexStream};
+
+    #[test]
+    fn resp_codec_should_decode_commands() {
+        let mut codec = RespCodec::default();
+        let mut buf = BytesMut::from(&b"*3\r\n$4\r\nHGET\r\n$2\r\nt1\r\n$2\r\nk"[..]);
+        // 数据不完整
+        assert_eq!(codec.decode(&mut buf).unwrap(), None);
+
+        buf.put_slice(b"1\r\nPING\r\n");
+        let frame = codec.decode(&mut buf).unwrap().unwrap();
+        assert_eq!(
+            frame,
+            RespFrame::Array(vec![bulk("HGET"), bulk("t1"), bulk("k1")])
+        );
+
+        // inline 命令
+        let frame = codec.decode(&mut buf).unwrap().unwrap();
+        assert_eq!(frame, RespFrame::Array(vec![bulk("PING")]));
+        assert!(buf.is_empty());
+
+        let mut buf = BytesMut::from(&b"$3\r\nabcd\r\n"[..]);
+        assert!(codec.decode(&mut buf).is_err());
+    }
+
+    #[test]
+    fn resp_codec_should_encode_by_version() {
+        let frame = RespFrame::Map(vec![(bulk("k"), RespFrame::Null)]);
+        let mut codec = RespCodec::default();
+        let mut buf = BytesMut::new();
+        codec.encode(frame.clone(), &mut buf).unwrap();
+        assert_eq!(&buf[..], b"*2\r\n$1\r\nk\r\n$-1\r\n");
+
+        codec.version = 3;
+        let mut buf = BytesMut::new();
+        codec.encode(frame, &mut buf).unwrap();
+        assert_eq!(&buf[..], b"%1\r\n$1\r\nk\r\n_\r\n");
+
+        let mut buf = BytesMut::new();
+        codec.encode(RespFrame::Boolean(true), &mut buf).unwrap();
+        assert_eq!(&buf[..], b"#t\r\n");
+    }
+
+    #[tokio::test]
+    async fn resp_server_should_map_hash_commands() {
+        let service: Service = ServiceBuilder::default().finish();
+        let (mut client, server) = duplex(4096);
+        tokio::spawn(RespServerStream::new(server, service).process());
+
+        roundtrip(&mut client, b"PING\r\n", b"+PONG\r\n").await;
+        roundtrip(&mut client, b"HSET t1 k1 v1 k2 v2\r\n", b":2\r\n").await;
+        roundtrip(&mut client, b"HSET t1 k1 v3\r\n", b":0\r\n").await;
+        roundtrip(&mut client, b"HGET t1 k1\r\n", b"$2\r\nv3\r\n").await;
+        roundtrip(&mut client, b"HGET t1 k3\r\n", b"$-1\r\n").await;
+        roundtrip(&mut client, b"HSETNX t1 k1 v4\r\n", b":0\r\n").await;
+        roundtrip(&mut client, b"HSETNX t1 k4 v4\r\n", b":1\r\n").await;
+        roundtrip(&mut client, b"HMSET t1 k4 v4 k5 v5\r\n", b"+OK\r\n").await;
+        let values = b"*3\r\n$2\r\nv4\r\n$-1\r\n$2\r\nv5\r\n";
+        roundtrip(&mut client, b"HMGET t1 k4 k6 k5\r\n", values).await;
+        roundtrip(&mut client, b"HDEL t1 k2 k3 k4 k5\r\n", b":3\r\n").await;
+        let all = b"*2\r\n$2\r\nk1\r\n$2\r\nv3\r\n";
+        roundtrip(&mut client, b"HGETALL t1\r\n", all).await;
+        let err = b"-ERR wrong number of arguments for 'hget' command\r\n";
+        roundtrip(&mut client, b"HGET t1\r\n", err).await;
+        roundtrip(&mut client, b"FOO\r\n", b"-ERR unknown command 'foo'\r\n").await;
+
+        // 切换到 RESP3 之后，HGETALL 返回 map
+        let hello = format!(
+            "%4\r\n$6\r\nserver\r\n$5\r\nkv-sv\r\n$7\r\nversion\r\n${}\r\n{}\r\n\
+             $5\r\nproto\r\n:3\r\n$4\r\nmode\r\n$10\r\nstandalone\r\n",
+            env!("CARGO_PKG_VERSION").len(),
+            env!("CARGO_PKG_VERSION")
+        );
+        roundtrip(&mut client, b"HELLO 3\r\n", hello.as_bytes()).await;
+        let all = b"%1\r\n$2\r\nk1\r\n$2\r\nv3\r\n";
+        roundtrip(&mut client, b"HGETALL t1\r\n", all).await;
+        roundtrip(&mut client, b"QUIT\r\n", b"+OK\r\n").await;
+    }
+
+    async fn roundtrip(client: &mut DuplexStream, cmd: &[u8], expected: &[u8]) {
+        client.write_all(cmd).await.unwrap();
+        let mut buf = vec![0; expected.len()];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(
+            String::from_utf8_lossy(&buf),
+            String::from_utf8_lossy(expected)
+        );
+    }
+}
@@ -1,23 +1,43 @@
-use super::frame::{read_fame, FrameCoder};
+use super::{
+    buffer::{BufferPool, CONNECTION_BUFFERS},
+    frame::{
+        check_frame_size, decode_header, max_frame_size, FrameCoder, FrameCompression, LEN_LEN,
+    },
+};
 use crate::error::{IOError, KvError};
-use bytes::BytesMut;
-use futures::{ready, FutureExt, Sink, Stream};
+use bytes::{Buf, Bytes, BytesMut};
+use futures::{ready, Sink, Stream};
 use std::{
+    collections::VecDeque,
+    io::{self, ErrorKind},
     marker::PhantomData,
+    mem,
     pin::Pin,
     task::{Context, Poll},
 };
 use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_util::io::poll_read_buf;
+
+/// 小于这个大小的消息编码到 wbuf 中，和其它小消息合在一起写出；大的消息的 payload 不再复制到帧头后面
+const COPY_LIMIT: usize = 64 * 1024;
 
 pub struct ProstStream<S, In, Out> {
     // inner stream
     stream: S,
     // write buffer
     wbuf: BytesMut,
-    // 写入了多少字节
-    written: usize,
-    // read buffer
+    // 等待写出的数据，按顺序写
+    pending: VecDeque<Bytes>,
+    // read buffer，可能包含还没有读完的 frame
     rbuf: BytesMut,
+    // 当前消息已经读完的 frame
+    frames: Vec<Bytes>,
+    // 发送时使用的压缩算法
+    compression: FrameCompression,
+    // 收发的消息大小上限，没有设置时使用全局的 max_frame_size
+    max_frame_size: Option<usize>,
+    // rbuf 和 wbuf 从这里取出，drop 时放回去
+    buffers: &'static BufferPool,
 
     // 幽灵类型
     _in: PhantomData<In>,
@@ -29,13 +49,42 @@ where
     S: AsyncRead + AsyncWrite + Unpin + Send,
 {
     pub fn new(stream: S) -> Self {
+        Self::with_buffers(stream, &CONNECTION_BUFFERS)
+    }
+
+    /// 读写 buffer 从 buffers 中取出，stream 结束之后放回去
+    pub fn with_buffers(stream: S, buffers: &'static BufferPool) -> Self {
         Self {
             stream,
-            wbuf: BytesMut::new(),
-            written: 0,
-            rbuf: BytesMut::new(),
-            _in: PhantomData::default(),
-            _out: PhantomData::default(),
+            wbuf: buffers.get(),
+            pending: VecDeque::new(),
+            rbuf: buffers.get(),
+            frames: Vec::new(),
+            compression: FrameCompression::default(),
+            max_frame_size: None,
+            buffers,
+            _in: PhantomData,
+            _out: PhantomData,
+        }
+    }
+
+    /// 之后发送的 frame 使用 compression 压缩；读取时按帧头里的算法解压
+    pub fn set_compression(&mut self, compression: FrameCompression) {
+        self.compression = compression;
+    }
+
+    pub fn set_max_frame_size(&mut self, max_frame_size: Option<usize>) {
+        self.max_frame_size = max_frame_size;
+    }
+
+    fn max_frame_size(&self) -> usize {
+        self.max_frame_size.unwrap_or_else(max_frame_size)
+    }
+
+    /// 把 wbuf 中的数据放到 pending 的最后，wbuf 的内存在这些数据写出去之后可以复用
+    fn stage(&mut self) {
+        if !self.wbuf.is_empty() {
+            self.pending.push_back(self.wbuf.split().freeze());
         }
     }
 }
@@ -49,23 +98,45 @@ where
 {
     type Item = Result<In, KvError>;
 
-    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
-        // 上一次调用结束后，应该情况 rbuf
-        assert!(self.rbuf.len() == 0);
-        // 把 rbuf 分离出来,摆脱 self 的引用
-        let mut rbuf = self.rbuf.split_off(0);
-        // 使用 read_frame 来获取数据 Frame
-        let fut = read_fame(&mut self.stream, &mut rbuf);
-        // 因为 poll_xxx() 方法已经是 async/await 的底层 API 实现，
-        // 所以我们在 poll_xxx() 方法中，是不能直接使用异步函数的；
-        // 需要把它看作一个 future，然后调用 future 的 poll 函数。
-        // 因为 future 是一个 trait，所以需要 Box 将其处理成一个在堆上的 trait object，
-        // 这样就可以调用 FutureExt 的 poll_unpin() 方法了。Box::pin 会生成 Pin<Box>。
-        ready!(Box::pin(fut).poll_unpin(cx))?;
-        // 把拿到的 Frame 合并回 rbuf
-        self.rbuf.unsplit(rbuf);
-        // 解析这个新拿到的这个Frame
-        Poll::Ready(Some(In::decode_frame(&mut self.rbuf)))
+    /// 读到的数据都留在 rbuf 里，poll 返回 Pending 之后下次从中断的地方继续；
+    /// 带 MORE 标记的 frame 先放到 frames 里，读完整个消息之后再解析
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let max = self.max_frame_size();
+        let this = self.get_mut();
+        loop {
+            let mut need = LEN_LEN;
+            if this.rbuf.len() >= LEN_LEN {
+                let header = u32::from_be_bytes(this.rbuf[..LEN_LEN].try_into().unwrap());
+                let (len, _, more) = decode_header(header as usize);
+                let total = this.frames.iter().map(|f| f.len() - LEN_LEN).sum::<usize>() + len;
+                check_frame_size(total, max)?;
+                need = LEN_LEN + len;
+                if this.rbuf.len() >= need {
+                    this.frames.push(this.rbuf.split_to(need).freeze());
+                    if more {
+                        continue;
+                    }
+                    // 解析这个新拿到的这个消息
+                    let frames = mem::take(&mut this.frames);
+                    return Poll::Ready(Some(In::decode_frames(frames)));
+                }
+            }
+
+            this.rbuf.reserve(need - this.rbuf.len());
+            let n = ready!(poll_read_buf(
+                Pin::new(&mut this.stream),
+                cx,
+                &mut this.rbuf
+            ))?;
+            if n == 0 {
+                // 对端在两个消息之间关闭，stream 正常结束
+                if this.rbuf.is_empty() && this.frames.is_empty() {
+                    return Poll::Ready(None);
+                }
+                let e = io::Error::from(ErrorKind::UnexpectedEof);
+                return Poll::Ready(Some(Err(e).to_error()));
+            }
+        }
     }
 }
 
@@ -85,22 +156,32 @@ where
 
     fn start_send(self: Pin<&mut Self>, item: &Out) -> Result<(), Self::Error> {
         let this = self.get_mut();
-        item.encode_frame(&mut this.wbuf)?;
+        let size = item.encoded_len();
+        check_frame_size(size, this.max_frame_size())?;
+        if size < COPY_LIMIT {
+            return item.encode_frame_with(&mut this.wbuf, this.compression);
+        }
+        this.stage();
+        let frames = item.encode_frames(&mut this.wbuf, this.compression)?;
+        this.pending.extend(frames);
         Ok(())
     }
 
     fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
         let this = self.get_mut();
+        this.stage();
         // Write to stream in a loop
-        while this.written != this.wbuf.len() {
-            let n = ready!(Pin::new(&mut this.stream).poll_write(cx, &this.wbuf[this.written..]))
-                .to_error()?;
-            this.written += n;
+        while let Some(data) = this.pending.front_mut() {
+            let n = ready!(Pin::new(&mut this.stream).poll_write(cx, data)).to_error()?;
+            if n == 0 {
+                return Poll::Ready(Err(io::Error::from(ErrorKind::WriteZero)).to_error());
+            }
+            data.advance(n);
+            if data.is_empty() {
+                this.pending.pop_front();
+            }
         }
 
-        // 使用完了，重置buf，为下次使用做准备
-        this.wbuf.clear();
-        this.written = 0;
         // 调用stream的 poll_flush确保写入
         ready!(Pin::new(&mut this.stream).poll_flush(cx)).to_error()?;
 
@@ -116,6 +197,13 @@ where
     }
 }
 
+impl<S, In, Out> Drop for ProstStream<S, In, Out> {
+    fn drop(&mut self) {
+        self.buffers.put(mem::take(&mut self.rbuf));
+        self.buffers.put(mem::take(&mut self.wbuf));
+    }
+}
+
 /// 一般来说，为异步操作而创建的数据结构，如果使用了泛型参数，那么只要内部没有自引用数据，就应该实现 Unpin。
 /// 这会给别人在使用这些的代码时带来很多方便
 impl<S, In, Out> Unpin for ProstStream<S, In, Out> where S: Unpin {}
@@ -125,13 +213,12 @@ impl<S, In, Out> Unpin for ProstStream<S, In, Out> where S: Unpin {}
 #[cfg(test)]
 mod stream_tests {
     use anyhow::Result;
-    use bytes::BytesMut;
+    use bytes::{Bytes, BytesMut};
     use futures::{SinkExt, StreamExt};
 
     use crate::{
-        pb::abi::{CommandRequest, CommandResponse},
-        stream::ProstStream,
-        test_utils::DummyStream,
+        buffer::BufferPool, error::KvError, frame::CHUNK_SIZE, pb::abi::CommandRequest,
+        stream::ProstStream, test_utils::DummyStream,
     };
 
     #[tokio::test]
@@ -141,11 +228,76 @@ mod stream_tests {
         let mut stream = ProstStream::<_, CommandRequest, CommandRequest>::new(stream);
         let cmd = CommandRequest::new_hdel("t1", "k1");
         stream.send(&cmd.to_owned()).await?;
-        if let Some(Ok(s)) = stream.next().await {
-            assert_eq!(s, cmd);
-        } else {
-            assert!(false);
+        match stream.next().await {
+            Some(Ok(s)) => assert_eq!(s, cmd),
+            _ => panic!("should get a command back"),
         }
         Ok(())
     }
+
+    #[tokio::test]
+    async fn prost_stream_should_keep_order_of_small_and_large_messages() -> Result<()> {
+        let stream = DummyStream {
+            buf: BytesMut::new(),
+        };
+        let mut stream = ProstStream::<_, CommandRequest, CommandRequest>::new(stream);
+        let cmds = [
+            CommandRequest::new_hget("t1", "k1"),
+            CommandRequest::new_hset("t1", "k1", Bytes::from(vec![1u8; CHUNK_SIZE + 1])),
+            CommandRequest::new_hdel("t1", "k1"),
+        ];
+        for cmd in &cmds {
+            stream.feed(cmd).await?;
+        }
+        stream.flush().await?;
+        for cmd in cmds {
+            assert_eq!(stream.next().await.unwrap()?, cmd);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn prost_stream_should_return_buffers_when_dropped() -> Result<()> {
+        static BUFFERS: BufferPool = BufferPool::new(1024, 4, 64 * 1024);
+        let stream = DummyStream {
+            buf: BytesMut::new(),
+        };
+        let mut stream =
+            ProstStream::<_, CommandRequest, CommandRequest>::with_buffers(stream, &BUFFERS);
+        let cmd = CommandRequest::new_hdel("t1", "k1");
+        stream.send(&cmd).await?;
+        assert_eq!(stream.next().await.unwrap()?, cmd);
+        assert!(BUFFERS.is_empty());
+        drop(stream);
+        assert_eq!(BUFFERS.len(), 2);
+
+        // 新的 stream 复用放回来的 buffer
+        let stream = DummyStream {
+            buf: BytesMut::new(),
+        };
+        let _stream =
+            ProstStream::<_, CommandRequest, CommandRequest>::with_buffers(stream, &BUFFERS);
+        assert!(BUFFERS.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn prost_stream_should_reject_large_messages() -> Result<()> {
+        let stream = DummyStream {
+            buf: BytesMut::new(),
+        };
+        let mut stream = ProstStream::<_, CommandRequest, CommandRequest>::new(stream);
+        stream.set_max_frame_size(Some(64));
+        let cmd = CommandRequest::new_hset("t1", "k1", "v".repeat(64));
+        let res = stream.send(&cmd).await;
+        assert!(matches!(res, Err(KvError::FrameTooLarge(_, 64))));
+
+        // 对端的限制更大时可以发送，但是读取时超过了自己的限制
+        stream.set_max_frame_size(Some(128));
+        stream.send(&cmd).await?;
+        stream.set_max_frame_size(Some(64));
+        let res = stream.next().await;
+        assert!(matches!(res, Some(Err(KvError::FrameTooLarge(_, 64)))));
+        Ok(())
+    }
 }
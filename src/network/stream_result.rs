@@ -1,8 +1,15 @@
-use std::ops::{Deref, DerefMut};
+use std::{
+    ops::{Deref, DerefMut},
+    pin::Pin,
+    task::{Context, Poll},
+};
 
-use futures::{Stream, StreamExt};
+use futures::{ready, Stream, StreamExt};
 
-use crate::{error::KvError, pb::abi::CommandResponse};
+use crate::{
+    error::KvError,
+    pb::abi::{CommandResponse, KeyEvent, Value},
+};
 
 pub struct StreamResult<T>
 where
@@ -55,3 +62,91 @@ where
         &mut self.inner
     }
 }
+
+/// 订阅得到的数据流，每一项是一次 publish 的数据
+pub struct Subscription<T>
+where
+    T: Stream<Item = Result<CommandResponse, KvError>> + Send,
+{
+    pub topic: String,
+    inner: StreamResult<T>,
+}
+
+impl<T> Subscription<T>
+where
+    T: Stream<Item = Result<CommandResponse, KvError>> + Send + Unpin,
+{
+    pub fn new(topic: impl Into<String>, inner: StreamResult<T>) -> Self {
+        Self {
+            topic: topic.into(),
+            inner,
+        }
+    }
+
+    /// 订阅 id，取消订阅时需要用到
+    pub fn id(&self) -> u32 {
+        self.inner.id
+    }
+}
+
+impl<T> Stream for Subscription<T>
+where
+    T: Stream<Item = Result<CommandResponse, KvError>> + Send + Unpin,
+{
+    type Item = Result<Vec<Value>, KvError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let item = ready!(self.inner.poll_next_unpin(cx));
+        Poll::Ready(item.map(|res| match res {
+            Ok(CommandResponse {
+                status: 200,
+                values,
+                ..
+            }) => Ok(values),
+            Ok(res) => Err(KvError::Internal(res.message)),
+            Err(e) => Err(e),
+        }))
+    }
+}
+
+/// HWATCH 得到的事件流，每一项是一次修改产生的事件
+pub struct KeyWatch<T>
+where
+    T: Stream<Item = Result<CommandResponse, KvError>> + Send,
+{
+    inner: StreamResult<T>,
+}
+
+impl<T> KeyWatch<T>
+where
+    T: Stream<Item = Result<CommandResponse, KvError>> + Send + Unpin,
+{
+    pub fn new(inner: StreamResult<T>) -> Self {
+        Self { inner }
+    }
+
+    /// watch id，取消监听时需要用到
+    pub fn id(&self) -> u32 {
+        self.inner.id
+    }
+}
+
+impl<T> Stream for KeyWatch<T>
+where
+    T: Stream<Item = Result<CommandResponse, KvError>> + Send + Unpin,
+{
+    type Item = Result<Vec<KeyEvent>, KvError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let item = ready!(self.inner.poll_next_unpin(cx));
+        Poll::Ready(item.map(|res| match res {
+            Ok(CommandResponse {
+                status: 200,
+                events,
+                ..
+            }) => Ok(events),
+            Ok(res) => Err(KvError::Internal(res.message)),
+            Err(e) => Err(e),
+        }))
+    }
+}
@@ -1,26 +1,28 @@
 use crate::error::{CertError, IOError, KvError};
 use anyhow::Result;
 use std::io::Cursor;
-use std::sync::Arc;
+use std::sync::{Arc, RwLock};
 use tokio::io::{AsyncRead, AsyncWrite};
 use tokio_rustls::{
     client,
     rustls::{
-        internal::pemfile, AllowAnyAuthenticatedClient, Certificate, ClientConfig, NoClientAuth,
-        PrivateKey, RootCertStore, ServerConfig,
+        internal::pemfile, AllowAnyAnonymousOrAuthenticatedClient, AllowAnyAuthenticatedClient,
+        Certificate, ClientConfig, NoClientAuth, PrivateKey, RootCertStore, ServerConfig, Session,
     },
     server,
     webpki::DNSNameRef,
     TlsAcceptor, TlsConnector,
 };
+use x509_parser::{certificate::X509Certificate, extensions::GeneralName, prelude::FromDer};
 
 /// KV Server 自己的 ALPN (Application-Layer Protocol Negotiation)
 const ALPN_KV: &str = "kv";
 
-/// 存放 TLS ServerConfig 并提供 accept 方法把底层的协议转换成 TLS
+/// 存放 TLS ServerConfig 并提供 accept 方法把底层的协议转换成 TLS；
+/// clone 出来的 acceptor 共享同一份配置，reload 之后新的连接都使用新的证书
 #[derive(Clone)]
 pub struct TlsServerAcceptor {
-    inner: Arc<ServerConfig>,
+    inner: Arc<RwLock<Arc<ServerConfig>>>,
 }
 
 /// 存放 TLS Client 并提供 connect 方法把底层协议转换成 TLS
@@ -85,6 +87,16 @@ impl TlsClientConnector {
 impl TlsServerAcceptor {
     /// 加载 server cert / CA cert, 生成 ServerConfig
     pub fn new(cert: &str, key: &str, client_ca: Option<&str>) -> Result<Self, KvError> {
+        Self::with_client_auth(cert, key, client_ca, true)
+    }
+
+    /// required 为 false 时，没有证书的客户端也可以连接；提供了证书就必须是 client_ca 签发的
+    pub fn with_client_auth(
+        cert: &str,
+        key: &str,
+        client_ca: Option<&str>,
+        required: bool,
+    ) -> Result<Self, KvError> {
         let certs = load_certs(cert)?;
         let key = load_key(key)?;
 
@@ -98,8 +110,13 @@ impl TlsServerAcceptor {
                 client_root_cert_store
                     .add_pem_file(&mut cert)
                     .to_error("server", "cert")?;
-                let client_auth = AllowAnyAuthenticatedClient::new(client_root_cert_store);
-                ServerConfig::new(client_auth)
+                if required {
+                    ServerConfig::new(AllowAnyAuthenticatedClient::new(client_root_cert_store))
+                } else {
+                    ServerConfig::new(AllowAnyAnonymousOrAuthenticatedClient::new(
+                        client_root_cert_store,
+                    ))
+                }
             }
         };
 
@@ -109,20 +126,47 @@ impl TlsServerAcceptor {
         config.set_protocols(&[protocols]);
 
         Ok(Self {
-            inner: Arc::new(config),
+            inner: Arc::new(RwLock::new(Arc::new(config))),
         })
     }
 
+    /// 换成 other 的证书；已经建立的连接不受影响
+    pub fn reload(&self, other: &TlsServerAcceptor) {
+        let config = other.inner.read().unwrap().clone();
+        *self.inner.write().unwrap() = config;
+    }
+
     // 触发 TLS 协议，把底层的 stream 转换成 TLS stream
     pub async fn accept<S>(&self, stream: S) -> Result<server::TlsStream<S>, KvError>
     where
         S: AsyncRead + AsyncWrite + Unpin + Send,
     {
-        let acceptor = TlsAcceptor::from(self.inner.clone());
-        Ok(acceptor.accept(stream).await.to_error()?)
+        let config = self.inner.read().unwrap().clone();
+        let acceptor = TlsAcceptor::from(config);
+        acceptor.accept(stream).await.to_error()
     }
 }
 
+/// 客户端证书对应的用户名，客户端没有提供证书时返回 None
+pub fn client_identity<S>(stream: &server::TlsStream<S>) -> Option<String> {
+    let certs = stream.get_ref().1.get_peer_certificates()?;
+    cert_identity(&certs.first()?.0)
+}
+
+/// 证书的 CN 作为用户名，没有 CN 时使用第一个 DNS/email SAN
+pub fn cert_identity(der: &[u8]) -> Option<String> {
+    let (_, cert) = X509Certificate::from_der(der).ok()?;
+    let subject = cert.subject();
+    if let Some(cn) = subject.iter_common_name().next() {
+        return cn.as_str().ok().map(Into::into);
+    }
+    let san = cert.subject_alternative_name().ok()??;
+    san.value.general_names.iter().find_map(|name| match name {
+        GeneralName::DNSName(name) | GeneralName::RFC822Name(name) => Some(name.to_string()),
+        _ => None,
+    })
+}
+
 fn load_certs(cert: &str) -> Result<Vec<Certificate>, KvError> {
     let mut cert = Cursor::new(cert);
     pemfile::certs(&mut cert).map_err(|_| KvError::ConvertError("server".into(), "cert"))
@@ -193,7 +237,7 @@ mod tests {
         let client_identity = Some((CLIENT_CERT, CLIENT_KEY));
         let ca = Some(CA_CERT);
 
-        let addr = start_server(ca.clone()).await?;
+        let addr = start_server(ca).await?;
 
         let connector = TlsClientConnector::new("kvserver.acme.inc", client_identity, ca)?;
         let stream = TcpStream::connect(addr).await?;
@@ -206,6 +250,45 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn client_cert_should_be_optional_when_not_required() -> Result<()> {
+        let acceptor =
+            TlsServerAcceptor::with_client_auth(SERVER_CERT, SERVER_KEY, Some(CA_CERT), false)?;
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        tokio::spawn(async move {
+            for _ in 0..2 {
+                let (stream, _) = listener.accept().await.unwrap();
+                let mut stream = acceptor.accept(stream).await.unwrap();
+                let identity = client_identity(&stream).unwrap_or_default();
+                stream
+                    .write_all(format!("{:<17}", identity).as_bytes())
+                    .await
+                    .unwrap();
+            }
+        });
+
+        let mut buf = [0; 17];
+        for (identity, expected) in [
+            (Some((CLIENT_CERT, CLIENT_KEY)), "awesome-device-id"),
+            (None, ""),
+        ] {
+            let connector = TlsClientConnector::new("kvserver.acme.inc", identity, Some(CA_CERT))?;
+            let mut stream = connector.connect(TcpStream::connect(addr).await?).await?;
+            stream.read_exact(&mut buf).await?;
+            assert_eq!(std::str::from_utf8(&buf)?.trim_end(), expected);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn cert_identity_should_return_common_name() {
+        let certs = load_certs(CLIENT_CERT).unwrap();
+        let identity = cert_identity(&certs[0].0);
+        assert_eq!(identity.as_deref(), Some("awesome-device-id"));
+    }
+
     #[tokio::test]
     async fn tls_with_bad_domain_should_not_work() -> Result<()> {
         let addr = start_server(None).await?;
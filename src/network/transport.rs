@@ -0,0 +1,140 @@
+use std::{
+    fmt, io,
+    net::{IpAddr, SocketAddr},
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::{TcpListener, TcpStream},
+};
+
+#[cfg(unix)]
+use tokio::net::{UnixListener, UnixStream};
+
+use crate::{config::ClientTlsConfig, error::KvError, tls::TlsClientConnector};
+
+/// unix socket 地址的前缀，例如 `unix:/var/run/kv.sock`
+const UNIX_PREFIX: &str = "unix:";
+
+/// 客户端和服务器之间的字节流，上面再跑 yamux：TCP 上的 TLS，或者同一台机器上的 unix socket
+pub trait Transport: AsyncRead + AsyncWrite + Unpin + Send + 'static {}
+
+impl<T> Transport for T where T: AsyncRead + AsyncWrite + Unpin + Send + 'static {}
+
+pub type BoxedTransport = Box<dyn Transport>;
+
+/// 监听 TCP 或者 unix socket
+pub enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+/// 刚接受的连接，TCP 连接还需要完成 TLS 握手
+pub enum Incoming {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+/// 连接的对端
+#[derive(Debug, Clone, Copy)]
+pub enum Peer {
+    Tcp(SocketAddr),
+    Unix,
+}
+
+impl Listener {
+    /// `unix:` 开头时监听 unix socket，会先删掉上次没有清理的 socket 文件
+    pub async fn bind(addr: &str) -> Result<Self, KvError> {
+        match unix_path(addr) {
+            #[cfg(unix)]
+            Some(path) => {
+                if let Err(e) = std::fs::remove_file(path) {
+                    if e.kind() != io::ErrorKind::NotFound {
+                        return Err(e.into());
+                    }
+                }
+                Ok(Self::Unix(UnixListener::bind(path)?))
+            }
+            #[cfg(not(unix))]
+            Some(_) => Err(unsupported()),
+            None => Ok(Self::Tcp(TcpListener::bind(addr).await?)),
+        }
+    }
+
+    pub async fn accept(&self) -> io::Result<(Incoming, Peer)> {
+        match self {
+            Self::Tcp(listener) => {
+                let (stream, addr) = listener.accept().await?;
+                stream.set_nodelay(true)?;
+                Ok((Incoming::Tcp(stream), Peer::Tcp(addr)))
+            }
+            #[cfg(unix)]
+            Self::Unix(listener) => {
+                let (stream, _) = listener.accept().await?;
+                Ok((Incoming::Unix(stream), Peer::Unix))
+            }
+        }
+    }
+}
+
+impl fmt::Display for Peer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Tcp(addr) => write!(f, "{}", addr),
+            Self::Unix => write!(f, "unix"),
+        }
+    }
+}
+
+impl Peer {
+    /// unix socket 的对端没有 IP
+    pub fn ip(&self) -> Option<IpAddr> {
+        match self {
+            Self::Tcp(addr) => Some(addr.ip()),
+            Self::Unix => None,
+        }
+    }
+}
+
+/// 连接服务器；unix socket 不使用 TLS
+pub async fn connect(addr: &str, tls: &ClientTlsConfig) -> Result<BoxedTransport, KvError> {
+    match unix_path(addr) {
+        #[cfg(unix)]
+        Some(path) => Ok(Box::new(UnixStream::connect(path).await?)),
+        #[cfg(not(unix))]
+        Some(_) => Err(unsupported()),
+        None => {
+            let identity = tls.identity()?;
+            let identity = identity
+                .as_ref()
+                .map(|(cert, key)| (cert.as_str(), key.as_str()));
+            let connector = TlsClientConnector::new(&tls.domain, identity, tls.ca.as_deref())?;
+            let stream = TcpStream::connect(addr).await?;
+            stream.set_nodelay(true)?;
+            Ok(Box::new(connector.connect(stream).await?))
+        }
+    }
+}
+
+/// `unix:` 开头的地址返回 socket 文件的路径
+pub fn unix_path(addr: &str) -> Option<&str> {
+    addr.strip_prefix(UNIX_PREFIX)
+}
+
+#[cfg(not(unix))]
+fn unsupported() -> KvError {
+    KvError::InvalidCommand("unix socket is not supported on this platform".into())
+}
+
+#[cfg(test)]
+mod transport_tests {
+    use super::*;
+
+    #[test]
+    fn unix_path_should_work() {
+        assert_eq!(unix_path("unix:/var/run/kv.sock"), Some("/var/run/kv.sock"));
+        assert_eq!(unix_path("127.0.0.1:9876"), None);
+    }
+}
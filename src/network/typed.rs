@@ -0,0 +1,195 @@
+use std::future::Future;
+
+use bytes::Bytes;
+use serde::{de::DeserializeOwned, Serialize};
+
+use super::{embedded::EmbeddedClient, pool::Pool, reconnect::ReconnectingClient};
+use crate::{
+    error::KvError,
+    pb::abi::{value, CommandRequest, CommandResponse, Value},
+    Storage,
+};
+
+/// 把 Rust 类型编码成 Value 中的二进制；需要 bincode、MessagePack 等格式时可以自己实现
+pub trait Codec {
+    fn encode<T: Serialize>(value: &T) -> Result<Vec<u8>, KvError>;
+
+    fn decode<T: DeserializeOwned>(data: &[u8]) -> Result<T, KvError>;
+}
+
+/// 编码成 JSON 文本
+pub struct Json;
+
+impl Codec for Json {
+    fn encode<T: Serialize>(value: &T) -> Result<Vec<u8>, KvError> {
+        serde_json::to_vec(value).map_err(|e| KvError::Internal(format!("Failed to encode: {}", e)))
+    }
+
+    fn decode<T: DeserializeOwned>(data: &[u8]) -> Result<T, KvError> {
+        serde_json::from_slice(data)
+            .map_err(|e| KvError::Internal(format!("Failed to decode: {}", e)))
+    }
+}
+
+/// 直接读写 Rust 类型：set_typed 把 value 编码成二进制保存，get_typed 读出来再解码，默认使用 JSON
+pub trait TypedClient: Sync {
+    fn execute(
+        &self,
+        cmd: &CommandRequest,
+    ) -> impl Future<Output = Result<CommandResponse, KvError>> + Send;
+
+    fn set_typed<T: Serialize>(
+        &self,
+        table: impl Into<String>,
+        key: impl AsRef<[u8]>,
+        value: &T,
+    ) -> impl Future<Output = Result<(), KvError>> + Send {
+        self.set_typed_with::<Json, T>(table, key, value)
+    }
+
+    fn get_typed<T: DeserializeOwned>(
+        &self,
+        table: impl Into<String>,
+        key: impl AsRef<[u8]>,
+    ) -> impl Future<Output = Result<Option<T>, KvError>> + Send {
+        self.get_typed_with::<Json, T>(table, key)
+    }
+
+    /// 用 C 编码之后保存
+    fn set_typed_with<C: Codec, T: Serialize>(
+        &self,
+        table: impl Into<String>,
+        key: impl AsRef<[u8]>,
+        value: &T,
+    ) -> impl Future<Output = Result<(), KvError>> + Send {
+        let cmd =
+            C::encode(value).map(|data| CommandRequest::new_hset(table, key, Bytes::from(data)));
+        async move {
+            let res = self.execute(&cmd?).await?;
+            match res.status {
+                200 => Ok(()),
+                _ => Err(KvError::Internal(res.message)),
+            }
+        }
+    }
+
+    /// 读出来之后用 C 解码，key 不存在时返回 None
+    fn get_typed_with<C: Codec, T: DeserializeOwned>(
+        &self,
+        table: impl Into<String>,
+        key: impl AsRef<[u8]>,
+    ) -> impl Future<Output = Result<Option<T>, KvError>> + Send {
+        let cmd = CommandRequest::new_hget(table, key);
+        async move {
+            let res = self.execute(&cmd).await?;
+            match res.status {
+                200 => {}
+                404 => return Ok(None),
+                _ => return Err(KvError::Internal(res.message)),
+            }
+            match res.values.into_iter().next() {
+                Some(Value {
+                    value: Some(value::Value::Binary(data)),
+                }) => C::decode(&data).map(Some),
+                Some(Value {
+                    value: Some(value::Value::String(s) | value::Value::Json(s)),
+                }) => C::decode(s.as_bytes()).map(Some),
+                Some(v) => Err(KvError::ConvertError(v, "Binary")),
+                None => Err(KvError::Internal("Didn't get any value".into())),
+            }
+        }
+    }
+}
+
+impl TypedClient for Pool {
+    fn execute(
+        &self,
+        cmd: &CommandRequest,
+    ) -> impl Future<Output = Result<CommandResponse, KvError>> + Send {
+        Pool::execute(self, cmd)
+    }
+}
+
+impl TypedClient for ReconnectingClient {
+    fn execute(
+        &self,
+        cmd: &CommandRequest,
+    ) -> impl Future<Output = Result<CommandResponse, KvError>> + Send {
+        ReconnectingClient::execute(self, cmd)
+    }
+}
+
+impl<Store: Storage> TypedClient for EmbeddedClient<Store> {
+    fn execute(
+        &self,
+        cmd: &CommandRequest,
+    ) -> impl Future<Output = Result<CommandResponse, KvError>> + Send {
+        EmbeddedClient::execute(self, cmd)
+    }
+}
+
+#[cfg(test)]
+mod typed_tests {
+    use serde::Deserialize;
+
+    use super::*;
+    use crate::service_builder::ServiceBuilder;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct User {
+        name: String,
+        age: u32,
+    }
+
+    /// 倒过来的 JSON，测试自定义的 Codec
+    struct Reversed;
+
+    impl Codec for Reversed {
+        fn encode<T: Serialize>(value: &T) -> Result<Vec<u8>, KvError> {
+            let mut data = Json::encode(value)?;
+            data.reverse();
+            Ok(data)
+        }
+
+        fn decode<T: DeserializeOwned>(data: &[u8]) -> Result<T, KvError> {
+            let mut data = data.to_vec();
+            data.reverse();
+            Json::decode(&data)
+        }
+    }
+
+    #[tokio::test]
+    async fn typed_values_should_round_trip() {
+        let client = EmbeddedClient::new(ServiceBuilder::default().finish());
+        let user = User {
+            name: "alice".into(),
+            age: 30,
+        };
+        client.set_typed("users", "u1", &user).await.unwrap();
+        let value = client.service().store.get("users", "u1").unwrap().unwrap();
+        assert_eq!(
+            value,
+            Value::from(Bytes::from(Json::encode(&user).unwrap()))
+        );
+        assert_eq!(client.get_typed("users", "u1").await.unwrap(), Some(user));
+        assert_eq!(client.get_typed::<User>("users", "u2").await.unwrap(), None);
+
+        client
+            .set_typed_with::<Reversed, _>("users", "u3", &vec![1, 2, 3])
+            .await
+            .unwrap();
+        let numbers: Option<Vec<i32>> = client
+            .get_typed_with::<Reversed, _>("users", "u3")
+            .await
+            .unwrap();
+        assert_eq!(numbers, Some(vec![1, 2, 3]));
+
+        // 解码失败和类型不对都返回错误
+        assert!(client.get_typed::<User>("users", "u3").await.is_err());
+        client
+            .execute(&CommandRequest::new_hset("users", "u4", 1))
+            .await
+            .unwrap();
+        assert!(client.get_typed::<User>("users", "u4").await.is_err());
+    }
+}
@@ -0,0 +1,243 @@
+use std::{sync::Arc, time::Duration};
+
+use futures::{SinkExt, StreamExt};
+use prost::Message as _;
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    sync::mpsc,
+};
+use tokio_tungstenite::{accept_async, tungstenite::Message};
+use tracing::debug;
+
+use crate::{
+    error::KvError,
+    in_flight::is_long_lived,
+    pb::abi::{CommandRequest, CommandResponse},
+    session::Session,
+    Service, Storage,
+};
+
+/// 等待发送给客户端的消息数，超过之后推送的任务等待
+const SEND_BUFFER: usize = 128;
+
+/// 浏览器通过 WebSocket 访问：每个 binary 消息是一个 CommandRequest 或者 CommandResponse。
+/// 命令按收到的顺序执行，响应和 SUBSCRIBE/HWATCH 的推送可能交错，通过 id 对应到请求
+pub struct WebSocketGateway<Store> {
+    service: Service<Store>,
+    /// 空闲超过这个时间之后断开连接
+    idle_timeout: Option<Duration>,
+}
+
+impl<Store> Clone for WebSocketGateway<Store> {
+    fn clone(&self) -> Self {
+        Self {
+            service: self.service.clone(),
+            idle_timeout: self.idle_timeout,
+        }
+    }
+}
+
+impl<Store: Storage> WebSocketGateway<Store> {
+    pub fn new(service: Service<Store>) -> Self {
+        Self {
+            service,
+            idle_timeout: None,
+        }
+    }
+
+    /// 没有执行中的命令超过 timeout 之后断开连接
+    pub fn with_idle_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.idle_timeout = timeout;
+        self
+    }
+
+    /// 完成 WebSocket 握手，然后处理请求直到客户端断开；开始退出之后不再读取新的请求
+    pub async fn serve<S>(self, stream: S) -> Result<(), KvError>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let ws = accept_async(stream).await.map_err(ws_error)?;
+        let (mut sink, mut source) = ws.split();
+        let (tx, mut rx) = mpsc::channel::<Message>(SEND_BUFFER);
+        let writer = tokio::spawn(async move {
+            while let Some(msg) = rx.recv().await {
+                if let Err(e) = sink.send(msg).await {
+                    debug!("Failed to send WebSocket message: {:?}", e);
+                    break;
+                }
+            }
+            let _ = sink.close().await;
+        });
+
+        let shutdown = self.service.shutdown().clone();
+        let session = Arc::new(Session::new());
+        let _client = self.service.connect(&session);
+        let _stream = session.open_stream();
+        loop {
+            let msg = tokio::select! {
+                msg = source.next() => msg,
+                _ = shutdown.triggered() => break,
+                _ = session.killed() => break,
+                _ = session.idle(self.idle_timeout.unwrap_or_default()),
+                    if self.idle_timeout.is_some() =>
+                {
+                    debug!("Close idle WebSocket client");
+                    break;
+                }
+            };
+            let cmd = match msg {
+                Some(Ok(Message::Binary(data))) => CommandRequest::decode(data),
+                Some(Ok(Message::Close(_))) | None => break,
+                // ping/pong 由 tungstenite 自动处理
+                Some(Ok(Message::Ping(_) | Message::Pong(_) | Message::Frame(_))) => continue,
+                Some(Ok(Message::Text(_))) => {
+                    let e = KvError::InvalidCommand("only binary messages are supported".into());
+                    send(&tx, &CommandResponse::from(e)).await;
+                    continue;
+                }
+                Some(Err(e)) => {
+                    debug!("WebSocket error: {:?}", e);
+                    break;
+                }
+            };
+            let cmd = match cmd {
+                Ok(cmd) => cmd,
+                Err(e) => {
+                    send(&tx, &CommandResponse::from(KvError::from(e))).await;
+                    continue;
+                }
+            };
+            let id = cmd.id;
+            let in_flight = shutdown.start();
+            // 配额用完时在这里等待，这期间不再读取新的消息
+            let mut permit = Some(self.service.in_flight.acquire(&session).await);
+            let busy = session.busy();
+            let long_lived = is_long_lived(&cmd);
+            let mut res = self.service.execute_with_deadline(cmd, &session).await;
+            let (tx, shutdown, session) = (tx.clone(), shutdown.clone(), session.clone());
+            // 推送不会结束，放到单独的任务里转发，不阻塞之后的请求
+            tokio::spawn(async move {
+                let (_in_flight, _busy) = (in_flight, busy);
+                loop {
+                    let data = tokio::select! {
+                        biased;
+                        data = res.next() => data,
+                        _ = shutdown.triggered() => None,
+                        _ = session.killed() => None,
+                    };
+                    let Some(data) = data else {
+                        break;
+                    };
+                    let mut data = data.as_ref().clone();
+                    data.id = id;
+                    if !send(&tx, &data).await {
+                        break;
+                    }
+                    if long_lived {
+                        permit.take();
+                    }
+                }
+            });
+        }
+        drop(tx);
+        let _ = writer.await;
+        Ok(())
+    }
+}
+
+/// 连接数超过限制时，完成握手之后发送错误然后关闭连接
+pub async fn reject<S>(stream: S, e: KvError)
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    match accept_async(stream).await {
+        Ok(mut ws) => {
+            let data = CommandResponse::from(e).encode_to_vec();
+            let _ = ws.send(Message::Binary(data.into())).await;
+            let _ = ws.close(None).await;
+        }
+        Err(e) => debug!("Failed to reject WebSocket client: {:?}", e),
+    }
+}
+
+/// 发送失败说明连接已经断开
+async fn send(tx: &mpsc::Sender<Message>, res: &CommandResponse) -> bool {
+    let data = res.encode_to_vec();
+    tx.send(Message::Binary(data.into())).await.is_ok()
+}
+
+fn ws_error(e: impl std::fmt::Display) -> KvError {
+    KvError::Internal(format!("WebSocket error: {}", e))
+}
+
+#[cfg(test)]
+mod websocket_tests {
+    use std::time::Duration;
+
+    use tokio::{
+        net::{TcpListener, TcpStream},
+        time,
+    };
+    use tokio_tungstenite::{client_async, WebSocketStream};
+
+    use super::*;
+    use crate::service_builder::ServiceBuilder;
+
+    #[tokio::test]
+    async fn websocket_should_execute_commands_and_push_subscriptions() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let service: Service = ServiceBuilder::default().finish();
+        let gateway = WebSocketGateway::new(service);
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            gateway.serve(stream).await.unwrap();
+        });
+
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let (mut ws, _) = client_async(format!("ws://{}/", addr), stream)
+            .await
+            .unwrap();
+        ws.send(request(1, CommandRequest::new_hset("t1", "k1", "v1")))
+            .await
+            .unwrap();
+        let res = next_response(&mut ws).await;
+        assert_eq!((res.id, res.status), (1, 200));
+
+        // 订阅之后，发布的数据作为推送发回来，id 是 SUBSCRIBE 的 id
+        ws.send(request(2, CommandRequest::new_subscribe("lobby")))
+            .await
+            .unwrap();
+        let res = next_response(&mut ws).await;
+        assert_eq!(res.id, 2);
+        ws.send(request(
+            3,
+            CommandRequest::new_publish("lobby", vec!["hi".into()]),
+        ))
+        .await
+        .unwrap();
+        let mut pushed = vec![];
+        for _ in 0..2 {
+            let res = next_response(&mut ws).await;
+            pushed.push((res.id, res.values));
+        }
+        pushed.sort_by_key(|(id, _)| *id);
+        assert_eq!(pushed, [(2, vec!["hi".into()]), (3, vec![])]);
+
+        ws.send(Message::Text("HGET t1 k1".into())).await.unwrap();
+        assert_eq!(next_response(&mut ws).await.status, 400);
+    }
+
+    fn request(id: u64, mut cmd: CommandRequest) -> Message {
+        cmd.id = id;
+        Message::Binary(cmd.encode_to_vec().into())
+    }
+
+    async fn next_response(ws: &mut WebSocketStream<TcpStream>) -> CommandResponse {
+        let msg = time::timeout(Duration::from_secs(1), ws.next()).await;
+        match msg.unwrap().unwrap().unwrap() {
+            Message::Binary(data) => CommandResponse::decode(data).unwrap(),
+            msg => panic!("unexpected message {:?}", msg),
+        }
+    }
+}
@@ -3,9 +3,23 @@
 #[allow(clippy::derive_partial_eq_without_eq)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct CommandRequest {
+    /// 超时时间（毫秒），0 表示不限制；服务器从收到请求开始计时，超时之后返回 504；
+    /// 超时之前还没有开始执行的命令不会再执行，已经开始执行的写命令仍然可能生效
+    #[prost(uint64, tag = "100")]
+    pub timeout: u64,
+    /// 客户端设置的请求 id，服务器在响应中原样带回；WebSocket 上和流水线的客户端用来区分不同请求的响应
+    #[prost(uint64, tag = "101")]
+    pub id: u64,
+    /// 客户端可以接收多帧的响应；HGETALL/HSCAN 会分批返回，不需要在服务器上一次读出整个 table
+    #[prost(bool, tag = "102")]
+    pub stream: bool,
+    /// 修改数据的命令可以带上一个唯一的幂等 key：服务器在一段时间内记住结果，
+    /// 同一个用户用同一个 key 重试时直接返回第一次执行的结果，不会再执行一次
+    #[prost(string, tag = "103")]
+    pub idempotency_key: ::prost::alloc::string::String,
     #[prost(
         oneof = "command_request::RequestData",
-        tags = "1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12"
+        tags = "1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27, 28, 29, 30, 31, 32, 33, 34, 35, 36, 37, 38, 39, 40, 41, 42, 43, 44, 45, 46, 47, 48, 49, 50, 51, 52, 53, 54, 55, 56, 57, 58, 59, 60, 61, 62, 63, 64, 65, 66, 67, 68, 69, 70, 71, 72, 73, 74, 75, 76, 77, 78, 79, 80, 81, 82, 83, 84"
     )]
     pub request_data: ::core::option::Option<command_request::RequestData>,
 }
@@ -40,6 +54,177 @@ pub mod command_request {
         Unsubscribe(super::Unsubscribe),
         #[prost(message, tag = "12")]
         Publish(super::Publish),
+        /// ttl
+        #[prost(message, tag = "13")]
+        Hexpire(super::Hexpire),
+        #[prost(message, tag = "14")]
+        Httl(super::Httl),
+        /// 一次发送多个命令
+        #[prost(message, tag = "15")]
+        Batch(super::Batch),
+        #[prost(message, tag = "16")]
+        Hscan(super::Hscan),
+        #[prost(message, tag = "17")]
+        Auth(super::Auth),
+        #[prost(message, tag = "18")]
+        Snapshot(super::Snapshot),
+        #[prost(message, tag = "19")]
+        Replicate(super::Replicate),
+        #[prost(message, tag = "20")]
+        Hcas(super::Hcas),
+        #[prost(message, tag = "21")]
+        Hincrby(super::Hincrby),
+        /// 事务
+        #[prost(message, tag = "22")]
+        Multi(super::Multi),
+        #[prost(message, tag = "23")]
+        Exec(super::Exec),
+        #[prost(message, tag = "24")]
+        Discard(super::Discard),
+        #[prost(message, tag = "25")]
+        Watch(super::Watch),
+        #[prost(message, tag = "26")]
+        Unwatch(super::Unwatch),
+        /// 监听 key 的修改
+        #[prost(message, tag = "27")]
+        Hwatch(super::Hwatch),
+        #[prost(message, tag = "28")]
+        Hunwatch(super::Hunwatch),
+        #[prost(message, tag = "29")]
+        ConfigReload(super::ConfigReload),
+        #[prost(message, tag = "30")]
+        ConfigGet(super::ConfigGet),
+        #[prost(message, tag = "31")]
+        ConfigSet(super::ConfigSet),
+        #[prost(message, tag = "32")]
+        Ping(super::Ping),
+        #[prost(message, tag = "33")]
+        Hello(super::Hello),
+        /// list/set/map 类型的 value
+        #[prost(message, tag = "34")]
+        Lpush(super::Lpush),
+        #[prost(message, tag = "35")]
+        Lrange(super::Lrange),
+        #[prost(message, tag = "36")]
+        Sadd(super::Sadd),
+        #[prost(message, tag = "37")]
+        Smembers(super::Smembers),
+        #[prost(message, tag = "38")]
+        MapGet(super::MapGet),
+        #[prost(message, tag = "39")]
+        MapSet(super::MapSet),
+        /// sorted set
+        #[prost(message, tag = "40")]
+        Zadd(super::Zadd),
+        #[prost(message, tag = "41")]
+        Zrange(super::Zrange),
+        #[prost(message, tag = "42")]
+        Zrangebyscore(super::Zrangebyscore),
+        /// JSON 文档
+        #[prost(message, tag = "43")]
+        Hjsonget(super::Hjsonget),
+        #[prost(message, tag = "44")]
+        Hjsonset(super::Hjsonset),
+        /// 二级索引
+        #[prost(message, tag = "45")]
+        CreateIndex(super::CreateIndex),
+        #[prost(message, tag = "46")]
+        Query(super::Query),
+        #[prost(message, tag = "47")]
+        Hrange(super::Hrange),
+        #[prost(message, tag = "48")]
+        Hscanprefix(super::Hscanprefix),
+        /// table 管理
+        #[prost(message, tag = "49")]
+        Tlist(super::Tlist),
+        #[prost(message, tag = "50")]
+        Tdrop(super::Tdrop),
+        #[prost(message, tag = "51")]
+        Ttruncate(super::Ttruncate),
+        /// 统计信息
+        #[prost(message, tag = "52")]
+        Dbsize(super::Dbsize),
+        #[prost(message, tag = "53")]
+        Hstats(super::Hstats),
+        #[prost(message, tag = "54")]
+        Info(super::Info),
+        #[prost(message, tag = "55")]
+        SlowlogGet(super::SlowlogGet),
+        /// 分布式锁
+        #[prost(message, tag = "56")]
+        Lock(super::Lock),
+        #[prost(message, tag = "57")]
+        Unlock(super::Unlock),
+        /// lease
+        #[prost(message, tag = "58")]
+        LeaseGrant(super::LeaseGrant),
+        #[prost(message, tag = "59")]
+        LeaseKeepAlive(super::LeaseKeepAlive),
+        #[prost(message, tag = "60")]
+        LeaseRevoke(super::LeaseRevoke),
+        /// 集群模式下节点之间的 raft 消息
+        #[prost(message, tag = "61")]
+        RaftVote(super::RaftVote),
+        #[prost(message, tag = "62")]
+        RaftAppend(super::RaftAppend),
+        /// 查询集群拓扑，客户端据此把命令直接发给 key 所在的节点
+        #[prost(message, tag = "63")]
+        ClusterSlots(super::ClusterSlots),
+        /// 节点之间交换成员列表
+        #[prost(message, tag = "64")]
+        Gossip(super::Gossip),
+        /// 查看本节点知道的所有节点
+        #[prost(message, tag = "65")]
+        Members(super::Members),
+        /// 压缩存储占用的磁盘空间
+        #[prost(message, tag = "66")]
+        Compact(super::Compact),
+        /// 丢弃 MVCC 历史中更早的 revision
+        #[prost(message, tag = "67")]
+        Hcompact(super::Hcompact),
+        /// 嵌入 kv-sv 的程序注册的命令
+        #[prost(message, tag = "68")]
+        Custom(super::Custom),
+        /// 查看和断开客户端的连接
+        #[prost(message, tag = "69")]
+        ClientList(super::ClientList),
+        #[prost(message, tag = "70")]
+        ClientKill(super::ClientKill),
+        /// 连接级别的设置
+        #[prost(message, tag = "71")]
+        Select(super::Select),
+        #[prost(message, tag = "72")]
+        SessionSet(super::SessionSet),
+        /// 存储配额和使用量
+        #[prost(message, tag = "73")]
+        Quota(super::Quota),
+        /// 字符串操作
+        #[prost(message, tag = "74")]
+        Happend(super::Happend),
+        #[prost(message, tag = "75")]
+        Hstrlen(super::Hstrlen),
+        #[prost(message, tag = "76")]
+        Hgetrange(super::Hgetrange),
+        /// bitmap 操作
+        #[prost(message, tag = "77")]
+        Hsetbit(super::Hsetbit),
+        #[prost(message, tag = "78")]
+        Hgetbit(super::Hgetbit),
+        #[prost(message, tag = "79")]
+        Hbitcount(super::Hbitcount),
+        /// HyperLogLog 基数估计
+        #[prost(message, tag = "80")]
+        Hpfadd(super::Hpfadd),
+        #[prost(message, tag = "81")]
+        Hpfcount(super::Hpfcount),
+        /// 服务器端脚本
+        #[prost(message, tag = "82")]
+        Eval(super::Eval),
+        #[prost(message, tag = "83")]
+        Evalsha(super::Evalsha),
+        /// 调用 WASM 插件中的函数
+        #[prost(message, tag = "84")]
+        Fcall(super::Fcall),
     }
 }
 /// 服务器的响应
@@ -59,6 +244,21 @@ pub struct CommandResponse {
     /// 成功返回的 kv pairs
     #[prost(message, repeated, tag = "4")]
     pub pairs: ::prost::alloc::vec::Vec<Kvpair>,
+    /// batch 命令中每个子命令的结果，顺序和请求一致
+    #[prost(message, repeated, tag = "5")]
+    pub results: ::prost::alloc::vec::Vec<CommandResponse>,
+    /// 复制时 primary 推送给 replica 的修改记录
+    #[prost(message, repeated, tag = "6")]
+    pub records: ::prost::alloc::vec::Vec<WalRecord>,
+    /// HWATCH 推送的 key 修改事件
+    #[prost(message, repeated, tag = "7")]
+    pub events: ::prost::alloc::vec::Vec<KeyEvent>,
+    /// 对应请求的 id
+    #[prost(uint64, tag = "8")]
+    pub id: u64,
+    /// 流式返回时，后面还有这个请求的响应
+    #[prost(bool, tag = "9")]
+    pub has_more: bool,
 }
 /// 从 table 中获取一个 key，返回 value
 #[derive(PartialOrd)]
@@ -67,8 +267,11 @@ pub struct CommandResponse {
 pub struct Hget {
     #[prost(string, tag = "1")]
     pub table: ::prost::alloc::string::String,
-    #[prost(string, tag = "2")]
-    pub key: ::prost::alloc::string::String,
+    #[prost(bytes = "bytes", tag = "2")]
+    pub key: ::prost::bytes::Bytes,
+    /// 大于 0 时读取这个 revision 时的值，需要开启 MVCC
+    #[prost(uint64, tag = "3")]
+    pub at_revision: u64,
 }
 /// 从 table 中获取所有的 Kvpair
 #[derive(PartialOrd)]
@@ -78,6 +281,41 @@ pub struct Hgetall {
     #[prost(string, tag = "1")]
     pub table: ::prost::alloc::string::String,
 }
+/// 分页遍历 table；cursor 从 0 开始，返回的 values\[0\] 是下一次的 cursor，为 0 时表示遍历结束
+#[derive(PartialOrd)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hscan {
+    #[prost(string, tag = "1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(uint64, tag = "2")]
+    pub cursor: u64,
+    /// 每页最多返回多少个 kvpair，0 表示使用默认值
+    #[prost(uint64, tag = "3")]
+    pub count: u64,
+}
+/// 按 key 的字节序返回 key 在 [start, end) 之间的 kvpair；end 为空表示一直到 table 的最后
+#[derive(PartialOrd)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hrange {
+    #[prost(string, tag = "1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(bytes = "bytes", tag = "2")]
+    pub start: ::prost::bytes::Bytes,
+    #[prost(bytes = "bytes", tag = "3")]
+    pub end: ::prost::bytes::Bytes,
+}
+/// 返回 table 中所有以 prefix 开头的 kvpair，适合 `user:42:name` 这样分层命名的 key
+#[derive(PartialOrd)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hscanprefix {
+    #[prost(string, tag = "1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(bytes = "bytes", tag = "2")]
+    pub prefix: ::prost::bytes::Bytes,
+}
 /// 从 table 中获取一组 key，返回它们的 value
 #[derive(PartialOrd)]
 #[allow(clippy::derive_partial_eq_without_eq)]
@@ -85,15 +323,15 @@ pub struct Hgetall {
 pub struct Hmget {
     #[prost(string, tag = "1")]
     pub table: ::prost::alloc::string::String,
-    #[prost(string, repeated, tag = "2")]
-    pub keys: ::prost::alloc::vec::Vec<::prost::alloc::string::String>,
+    #[prost(bytes = "bytes", repeated, tag = "2")]
+    pub keys: ::prost::alloc::vec::Vec<::prost::bytes::Bytes>,
 }
 /// 返回的值
 #[derive(PartialOrd)]
 #[allow(clippy::derive_partial_eq_without_eq)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Value {
-    #[prost(oneof = "value::Value", tags = "1, 2, 3, 4, 5")]
+    #[prost(oneof = "value::Value", tags = "1, 2, 3, 4, 5, 6, 7, 8, 9")]
     pub value: ::core::option::Option<value::Value>,
 }
 /// Nested message and enum types in `Value`.
@@ -112,15 +350,48 @@ pub mod value {
         Float(f64),
         #[prost(bool, tag = "5")]
         Bool(bool),
+        #[prost(message, tag = "6")]
+        List(super::ValueList),
+        #[prost(message, tag = "7")]
+        Set(super::ValueSet),
+        #[prost(message, tag = "8")]
+        Map(super::ValueMap),
+        /// JSON 文档的文本
+        #[prost(string, tag = "9")]
+        Json(::prost::alloc::string::String),
     }
 }
-/// 返回的 kvpair
+/// 有序的一组 value
+#[derive(PartialOrd)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ValueList {
+    #[prost(message, repeated, tag = "1")]
+    pub values: ::prost::alloc::vec::Vec<Value>,
+}
+/// 不重复的一组 value，按加入的顺序排列
+#[derive(PartialOrd)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ValueSet {
+    #[prost(message, repeated, tag = "1")]
+    pub members: ::prost::alloc::vec::Vec<Value>,
+}
+/// field 到 value 的映射，value 也可以是 map，这样可以嵌套
+#[derive(PartialOrd)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ValueMap {
+    #[prost(btree_map = "string, message", tag = "1")]
+    pub entries: ::prost::alloc::collections::BTreeMap<::prost::alloc::string::String, Value>,
+}
+/// 返回的 kvpair；key 可以是任意的字节，和 string 的编码相同，老的客户端不受影响
 #[derive(PartialOrd)]
 #[allow(clippy::derive_partial_eq_without_eq)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Kvpair {
-    #[prost(string, tag = "1")]
-    pub key: ::prost::alloc::string::String,
+    #[prost(bytes = "bytes", tag = "1")]
+    pub key: ::prost::bytes::Bytes,
     #[prost(message, optional, tag = "2")]
     pub value: ::core::option::Option<Value>,
 }
@@ -134,6 +405,23 @@ pub struct Hset {
     pub table: ::prost::alloc::string::String,
     #[prost(message, optional, tag = "2")]
     pub pair: ::core::option::Option<Kvpair>,
+    /// 存活时间（秒），0 表示永不过期
+    #[prost(uint64, tag = "3")]
+    pub ttl: u64,
+    /// 只在 key 不存在时设置（SETNX）
+    #[prost(bool, tag = "4")]
+    pub nx: bool,
+    /// 只在 key 已经存在时设置（SETXX）；nx 和 xx 不能同时为 true。
+    /// 设置了其中一个时返回 \[是否设置成功, 之前的值\]
+    #[prost(bool, tag = "5")]
+    pub xx: bool,
+    /// 把 key 关联到这个 lease，lease 过期或者被撤销时 key 被删除；lease 不存在时返回 404。
+    /// 不能和 ttl、nx、xx 一起使用
+    #[prost(uint64, tag = "6")]
+    pub lease: u64,
+    /// lease 所属的租户，由服务器按用户填写
+    #[prost(string, tag = "7")]
+    pub lease_namespace: ::prost::alloc::string::String,
 }
 /// 往 table 中存一组 kvpair，
 /// 如果 table 不存在就创建这个 table
@@ -153,8 +441,8 @@ pub struct Hmset {
 pub struct Hdel {
     #[prost(string, tag = "1")]
     pub table: ::prost::alloc::string::String,
-    #[prost(string, tag = "2")]
-    pub key: ::prost::alloc::string::String,
+    #[prost(bytes = "bytes", tag = "2")]
+    pub key: ::prost::bytes::Bytes,
 }
 /// 从 table 中删除一组 key，返回它们之前的值
 #[derive(PartialOrd)]
@@ -163,8 +451,8 @@ pub struct Hdel {
 pub struct Hmdel {
     #[prost(string, tag = "1")]
     pub table: ::prost::alloc::string::String,
-    #[prost(string, repeated, tag = "2")]
-    pub keys: ::prost::alloc::vec::Vec<::prost::alloc::string::String>,
+    #[prost(bytes = "bytes", repeated, tag = "2")]
+    pub keys: ::prost::alloc::vec::Vec<::prost::bytes::Bytes>,
 }
 /// 查看 key 是否存在
 #[derive(PartialOrd)]
@@ -173,8 +461,8 @@ pub struct Hmdel {
 pub struct Hexist {
     #[prost(string, tag = "1")]
     pub table: ::prost::alloc::string::String,
-    #[prost(string, tag = "2")]
-    pub key: ::prost::alloc::string::String,
+    #[prost(bytes = "bytes", tag = "2")]
+    pub key: ::prost::bytes::Bytes,
 }
 /// 查看一组 key 是否存在
 #[derive(PartialOrd)]
@@ -183,8 +471,8 @@ pub struct Hexist {
 pub struct Hmexist {
     #[prost(string, tag = "1")]
     pub table: ::prost::alloc::string::String,
-    #[prost(string, repeated, tag = "2")]
-    pub keys: ::prost::alloc::vec::Vec<::prost::alloc::string::String>,
+    #[prost(bytes = "bytes", repeated, tag = "2")]
+    pub keys: ::prost::alloc::vec::Vec<::prost::bytes::Bytes>,
 }
 /// 订阅某个主题；订阅成功，第一次返回id
 #[derive(PartialOrd)]
@@ -214,3 +502,882 @@ pub struct Publish {
     #[prost(message, repeated, tag = "2")]
     pub data: ::prost::alloc::vec::Vec<Value>,
 }
+/// 给 table 中的 key 设置存活时间（秒）
+#[derive(PartialOrd)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hexpire {
+    #[prost(string, tag = "1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(bytes = "bytes", tag = "2")]
+    pub key: ::prost::bytes::Bytes,
+    #[prost(uint64, tag = "3")]
+    pub ttl: u64,
+}
+/// 查看 key 剩余的存活时间（秒）；-1 表示永不过期，-2 表示 key 不存在
+#[derive(PartialOrd)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Httl {
+    #[prost(string, tag = "1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(bytes = "bytes", tag = "2")]
+    pub key: ::prost::bytes::Bytes,
+}
+/// key 当前的值等于 expected 时才写入 value，expected 为空表示 key 必须不存在；
+/// 返回的 values\[0\] 表示是否写入成功，values\[1\] 是 key 当前的值
+#[derive(PartialOrd)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hcas {
+    #[prost(string, tag = "1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(bytes = "bytes", tag = "2")]
+    pub key: ::prost::bytes::Bytes,
+    #[prost(message, optional, tag = "3")]
+    pub expected: ::core::option::Option<Value>,
+    #[prost(message, optional, tag = "4")]
+    pub value: ::core::option::Option<Value>,
+}
+/// 把 key 的值加上 delta，key 不存在时从 0 开始，返回新的值；HDECRBY 就是 delta 取负数
+#[derive(PartialOrd)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hincrby {
+    #[prost(string, tag = "1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(bytes = "bytes", tag = "2")]
+    pub key: ::prost::bytes::Bytes,
+    #[prost(int64, tag = "3")]
+    pub delta: i64,
+}
+/// 把 suffix 追加到 key 的值后面，key 不存在时值就是 suffix，返回追加之后的字节数；
+/// 值和 suffix 都必须是 string 或者 binary，有一个是 binary 时结果是 binary。不会改变 key 的过期时间
+#[derive(PartialOrd)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Happend {
+    #[prost(string, tag = "1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(bytes = "bytes", tag = "2")]
+    pub key: ::prost::bytes::Bytes,
+    #[prost(message, optional, tag = "3")]
+    pub suffix: ::core::option::Option<Value>,
+}
+/// 返回 key 的值的字节数，key 不存在时返回 0；值必须是 string 或者 binary
+#[derive(PartialOrd)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hstrlen {
+    #[prost(string, tag = "1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(bytes = "bytes", tag = "2")]
+    pub key: ::prost::bytes::Bytes,
+}
+/// 返回 key 的值从 offset 开始的 len 个字节，offset 为负数时从末尾开始数，len 为 0 时读到末尾；
+/// 超出范围的部分被忽略，key 不存在时返回空字符串。string 切开之后不是合法的 UTF-8 时返回 binary
+#[derive(PartialOrd)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hgetrange {
+    #[prost(string, tag = "1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(bytes = "bytes", tag = "2")]
+    pub key: ::prost::bytes::Bytes,
+    #[prost(int64, tag = "3")]
+    pub offset: i64,
+    #[prost(uint64, tag = "4")]
+    pub len: u64,
+}
+/// 把 key 的值当成 bitmap，设置 offset 处的 bit，返回原来的 bit。和 redis 一样，offset 0 是第一个字节的最高位；
+/// 值不够长时用 0 补齐，key 不存在时从空的 bitmap 开始。值必须是 string 或者 binary，修改之后是 binary
+#[derive(PartialOrd)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hsetbit {
+    #[prost(string, tag = "1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(bytes = "bytes", tag = "2")]
+    pub key: ::prost::bytes::Bytes,
+    #[prost(uint64, tag = "3")]
+    pub offset: u64,
+    #[prost(bool, tag = "4")]
+    pub bit: bool,
+}
+/// 返回 offset 处的 bit，超出值的长度或者 key 不存在时返回 false
+#[derive(PartialOrd)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hgetbit {
+    #[prost(string, tag = "1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(bytes = "bytes", tag = "2")]
+    pub key: ::prost::bytes::Bytes,
+    #[prost(uint64, tag = "3")]
+    pub offset: u64,
+}
+/// 在服务器上统计 bitmap 中为 1 的 bit 数，key 不存在时返回 0
+#[derive(PartialOrd)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hbitcount {
+    #[prost(string, tag = "1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(bytes = "bytes", tag = "2")]
+    pub key: ::prost::bytes::Bytes,
+}
+/// 把 members 加入 key 对应的 HyperLogLog，key 不存在时创建一个空的。HyperLogLog 固定占用 16KB 左右，
+/// 存成 binary，估计值的标准误差约为 0.81%。有寄存器变化或者新建了 key 时返回 1，否则返回 0
+#[derive(PartialOrd)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hpfadd {
+    #[prost(string, tag = "1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(bytes = "bytes", tag = "2")]
+    pub key: ::prost::bytes::Bytes,
+    #[prost(message, repeated, tag = "3")]
+    pub members: ::prost::alloc::vec::Vec<Value>,
+}
+/// 估计 keys 对应的 HyperLogLog 中不同 member 的个数，多个 key 时返回并集的估计值；不存在的 key 当成空的
+#[derive(PartialOrd)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hpfcount {
+    #[prost(string, tag = "1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(bytes = "bytes", repeated, tag = "2")]
+    pub keys: ::prost::alloc::vec::Vec<::prost::bytes::Bytes>,
+}
+/// 在 table 上原子地执行一段 Rhai 脚本，返回脚本最后一个表达式的值。脚本只能通过 get(key)、set(key, value)
+/// 和 del(key) 访问 keys 中声明的 key，KEYS 和 ARGV 是 keys 和 args 组成的数组。脚本读过的 key 被并发修改时
+/// 会重新执行，出错时不修改任何数据。执行过的脚本按 SHA1 缓存，之后可以用 EVALSHA 执行
+#[derive(PartialOrd)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Eval {
+    #[prost(string, tag = "1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag = "2")]
+    pub script: ::prost::alloc::string::String,
+    #[prost(bytes = "bytes", repeated, tag = "3")]
+    pub keys: ::prost::alloc::vec::Vec<::prost::bytes::Bytes>,
+    #[prost(message, repeated, tag = "4")]
+    pub args: ::prost::alloc::vec::Vec<Value>,
+}
+/// 执行缓存的脚本，sha 是脚本的 SHA1；脚本不在缓存中时返回 404，客户端需要用 EVAL 重新提交
+#[derive(PartialOrd)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Evalsha {
+    #[prost(string, tag = "1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag = "2")]
+    pub sha: ::prost::alloc::string::String,
+    #[prost(bytes = "bytes", repeated, tag = "3")]
+    pub keys: ::prost::alloc::vec::Vec<::prost::bytes::Bytes>,
+    #[prost(message, repeated, tag = "4")]
+    pub args: ::prost::alloc::vec::Vec<Value>,
+}
+/// 调用插件模块导出的函数，返回函数的结果。函数可以读 table 中任意的 key，只能修改 keys 中声明的 key；
+/// 函数不存在时返回 404
+#[derive(PartialOrd)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Fcall {
+    #[prost(string, tag = "1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag = "2")]
+    pub function: ::prost::alloc::string::String,
+    #[prost(bytes = "bytes", repeated, tag = "3")]
+    pub keys: ::prost::alloc::vec::Vec<::prost::bytes::Bytes>,
+    #[prost(message, repeated, tag = "4")]
+    pub args: ::prost::alloc::vec::Vec<Value>,
+}
+/// 一组命令，按顺序执行，每个命令的结果放在 CommandResponse.results 中
+#[derive(PartialOrd)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Batch {
+    #[prost(message, repeated, tag = "1")]
+    pub commands: ::prost::alloc::vec::Vec<CommandRequest>,
+}
+/// 认证；username 为空时 password 可以是共享密码或者 token
+#[derive(PartialOrd)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Auth {
+    #[prost(string, tag = "1")]
+    pub username: ::prost::alloc::string::String,
+    #[prost(string, tag = "2")]
+    pub password: ::prost::alloc::string::String,
+}
+/// 列出所有有数据的 table
+#[derive(PartialOrd)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Tlist {}
+/// 删除 table 中所有的 key 和 sorted set，以及 table 上的索引，返回删除的 key 数量
+#[derive(PartialOrd)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Tdrop {
+    #[prost(string, tag = "1")]
+    pub table: ::prost::alloc::string::String,
+}
+/// 删除 table 中所有的 key 和 sorted set，保留 table 上的索引，返回删除的 key 数量
+#[derive(PartialOrd)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Ttruncate {
+    #[prost(string, tag = "1")]
+    pub table: ::prost::alloc::string::String,
+}
+/// 所有 table 的 key 数量之和，不包括 sorted set
+#[derive(PartialOrd)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Dbsize {
+    /// 只统计名字以 namespace 开头的 table；租户的用户由服务器填写为租户的前缀
+    #[prost(string, tag = "1")]
+    pub namespace: ::prost::alloc::string::String,
+}
+/// table 的统计信息，每个 table 返回一个 kvpair：key 是 table 名，value 是包含 keys、bytes、hits
+/// 和 misses 的 map；hits 和 misses 是服务器启动以来 HGET/HMGET 找到和没找到 key 的次数。
+/// table 为空时返回所有的 table
+#[derive(PartialOrd)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hstats {
+    #[prost(string, tag = "1")]
+    pub table: ::prost::alloc::string::String,
+}
+/// 服务器的运行状态，每一项是一个 kvpair：版本、运行时间、连接数、处理过的命令数、存储引擎、
+/// 复制的角色和内存使用量
+#[derive(PartialOrd)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Info {}
+/// 最近的慢查询，最新的在前面；每一项是包含 id、timestamp、duration_us、command、table、key
+/// 和 peer 的 map。count 为 0 时返回保留的全部
+#[derive(PartialOrd)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct SlowlogGet {
+    #[prost(uint32, tag = "1")]
+    pub count: u32,
+}
+/// 获取 table 中名为 name 的锁：锁没有被持有时把它设置成一个新的 fencing token，租约时间是 ttl_ms，
+/// 返回 \[是否获取成功, token\]，获取失败时 token 是当前持有者的 token。
+/// token 不为 0 时是续约：锁仍然属于这个 token 时把租约延长到 ttl_ms 之后，返回 \[是否续约成功, token\]。
+/// fencing token 单调递增，存储服务可以拒绝比已经见过的 token 更小的写入
+#[derive(PartialOrd)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Lock {
+    #[prost(string, tag = "1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag = "2")]
+    pub name: ::prost::alloc::string::String,
+    #[prost(uint64, tag = "3")]
+    pub ttl_ms: u64,
+    #[prost(uint64, tag = "4")]
+    pub token: u64,
+}
+/// 释放锁，只有 token 和当前持有者一致时才删除，返回 \[是否释放\]
+#[derive(PartialOrd)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Unlock {
+    #[prost(string, tag = "1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag = "2")]
+    pub name: ::prost::alloc::string::String,
+    #[prost(uint64, tag = "3")]
+    pub token: u64,
+}
+/// 创建一个存活 ttl 秒的 lease，id 为 0 时由服务器分配，返回 \[id, ttl\]；id 已经存在时返回 409
+#[derive(PartialOrd)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct LeaseGrant {
+    #[prost(uint64, tag = "1")]
+    pub ttl: u64,
+    #[prost(uint64, tag = "2")]
+    pub id: u64,
+    /// lease 所属的租户，由服务器按用户填写；不同租户的 lease 互相看不到
+    #[prost(string, tag = "3")]
+    pub namespace: ::prost::alloc::string::String,
+}
+/// 把 lease 的存活时间重新设置为创建时的 ttl，返回 \[ttl\]；lease 不存在时返回 404
+#[derive(PartialOrd)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct LeaseKeepAlive {
+    #[prost(uint64, tag = "1")]
+    pub id: u64,
+    #[prost(string, tag = "2")]
+    pub namespace: ::prost::alloc::string::String,
+}
+/// 撤销 lease，同时删除关联的 key，返回删除的 key 的数量；lease 不存在时返回 404
+#[derive(PartialOrd)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct LeaseRevoke {
+    #[prost(uint64, tag = "1")]
+    pub id: u64,
+    #[prost(string, tag = "2")]
+    pub namespace: ::prost::alloc::string::String,
+}
+/// candidate 请求投票，返回 \[term, 是否投票\]
+#[derive(PartialOrd)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct RaftVote {
+    #[prost(uint64, tag = "1")]
+    pub term: u64,
+    #[prost(uint64, tag = "2")]
+    pub candidate: u64,
+    #[prost(uint64, tag = "3")]
+    pub last_log_index: u64,
+    #[prost(uint64, tag = "4")]
+    pub last_log_term: u64,
+}
+/// leader 追加日志，entries 为空时是心跳。返回 \[term, 是否成功, index\]：
+/// 成功时 index 是和 leader 一致的最后一条日志，失败时是 leader 下一次应该从哪里开始发送
+#[derive(PartialOrd)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct RaftAppend {
+    #[prost(uint64, tag = "1")]
+    pub term: u64,
+    #[prost(uint64, tag = "2")]
+    pub leader: u64,
+    #[prost(uint64, tag = "3")]
+    pub prev_log_index: u64,
+    #[prost(uint64, tag = "4")]
+    pub prev_log_term: u64,
+    #[prost(message, repeated, tag = "5")]
+    pub entries: ::prost::alloc::vec::Vec<RaftEntry>,
+    #[prost(uint64, tag = "6")]
+    pub leader_commit: u64,
+}
+/// raft 日志中的一条记录，按 length delimited 的格式追加写入文件；command 为空时是 leader 上任时写入的空操作
+#[derive(PartialOrd)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct RaftEntry {
+    #[prost(uint64, tag = "1")]
+    pub term: u64,
+    #[prost(message, optional, tag = "2")]
+    pub command: ::core::option::Option<CommandRequest>,
+}
+/// raft 需要持久化的任期和投票，0 表示还没有投票
+#[derive(PartialOrd)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct RaftHardState {
+    #[prost(uint64, tag = "1")]
+    pub term: u64,
+    #[prost(uint64, tag = "2")]
+    pub voted_for: u64,
+}
+/// 返回集群拓扑：values 是 \[模式, 虚拟节点数\]，模式是 standalone、raft 或者 shard；
+/// pairs 是各个节点的地址和角色。raft 模式下角色是 leader、follower 或者 candidate，
+/// shard 模式下是 shard，客户端用同样的一致性哈希算出 key 所在的节点
+#[derive(PartialOrd)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ClusterSlots {}
+/// 发送方知道的节点，包括发送方自己。返回接收方知道的节点：pairs 是地址 -> \[generation, heartbeat\]
+#[derive(PartialOrd)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Gossip {
+    #[prost(message, repeated, tag = "1")]
+    pub members: ::prost::alloc::vec::Vec<Member>,
+}
+/// generation 是节点启动时的时间戳，重启之后变大；heartbeat 由节点自己定期增加
+#[derive(PartialOrd)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Member {
+    #[prost(string, tag = "1")]
+    pub addr: ::prost::alloc::string::String,
+    #[prost(uint64, tag = "2")]
+    pub generation: u64,
+    #[prost(uint64, tag = "3")]
+    pub heartbeat: u64,
+}
+/// 返回 pairs：节点地址 -> 状态，状态是 alive、suspect 或者 dead
+#[derive(PartialOrd)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Members {}
+/// 压缩存储（合并数据文件、重写 WAL 等），返回回收的字节数；数据不在磁盘上的存储返回 0
+#[derive(PartialOrd)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Compact {}
+/// 嵌入 kv-sv 的程序注册的命令，编码和 google.protobuf.Any 相同：
+/// type_url 选择处理命令的 handler，value 是 handler 自己定义的参数
+#[derive(PartialOrd)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Custom {
+    #[prost(string, tag = "1")]
+    pub type_url: ::prost::alloc::string::String,
+    #[prost(bytes = "bytes", tag = "2")]
+    pub value: ::prost::bytes::Bytes,
+}
+/// 连接到本节点的客户端，每一项是包含 id、peer、user、connected_at（毫秒时间戳）、last_command
+/// 和 streams 的 map，按 id 排序
+#[derive(PartialOrd)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ClientList {}
+/// 设置连接的默认 table，之后 table 为空的命令使用这个 table；table 为空时取消默认 table。
+/// 同一个连接上的所有 stream 共享，使用 pool 的客户端需要在每个连接上设置
+#[derive(PartialOrd)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Select {
+    #[prost(string, tag = "1")]
+    pub table: ::prost::alloc::string::String,
+}
+/// 设置连接级别的选项：timeout 是没有设置超时时间的命令使用的超时（毫秒），0 表示不限制；
+/// stream 为 on 时 HGETALL/HSCAN 的结果分多帧返回
+#[derive(PartialOrd)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct SessionSet {
+    #[prost(string, tag = "1")]
+    pub name: ::prost::alloc::string::String,
+    #[prost(string, tag = "2")]
+    pub value: ::prost::alloc::string::String,
+}
+/// table 的配额和使用量，每个配额返回一个 kvpair：key 是 table 名，租户的配额是 `<tenant>:*`；
+/// value 是包含 keys、bytes、max_keys 和 max_bytes 的 map，max_keys 和 max_bytes 为 0 表示不限制
+#[derive(PartialOrd)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Quota {
+    #[prost(string, tag = "1")]
+    pub table: ::prost::alloc::string::String,
+}
+/// 断开 id 对应的连接，id 为 0 时断开地址是 peer 的所有连接，返回断开的连接数
+#[derive(PartialOrd)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ClientKill {
+    #[prost(uint64, tag = "1")]
+    pub id: u64,
+    #[prost(string, tag = "2")]
+    pub peer: ::prost::alloc::string::String,
+}
+/// 丢弃 MVCC 历史中不晚于 revision 的修改，之后不能再读取 revision 之前的值；返回丢弃的修改数
+#[derive(PartialOrd)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hcompact {
+    #[prost(uint64, tag = "1")]
+    pub revision: u64,
+}
+/// 把所有 table 的数据导出到服务器上的 path，返回导出的 key 数量
+#[derive(PartialOrd)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Snapshot {
+    #[prost(string, tag = "1")]
+    pub path: ::prost::alloc::string::String,
+}
+/// replica 向 primary 请求同步数据；primary 先返回 replica id，然后推送全量数据，之后持续推送修改
+#[derive(PartialOrd)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Replicate {}
+/// 开始一个事务，之后的命令排队，直到 EXEC 时一起执行
+#[derive(PartialOrd)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Multi {}
+/// 执行排队的命令，修改原子生效；results 是每个命令的结果
+#[derive(PartialOrd)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Exec {}
+/// 丢弃排队的命令，结束事务
+#[derive(PartialOrd)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Discard {}
+/// 监视 key，EXEC 时这些 key 被修改过的话事务不会执行，返回 409
+#[derive(PartialOrd)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Watch {
+    #[prost(string, tag = "1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(bytes = "bytes", repeated, tag = "2")]
+    pub keys: ::prost::alloc::vec::Vec<::prost::bytes::Bytes>,
+}
+/// 取消所有的监视；EXEC 和 DISCARD 之后也会自动取消
+#[derive(PartialOrd)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Unwatch {}
+/// 监听 table 中 key 的修改，prefix 为 true 时监听所有以 key 开头的 key；
+/// 先返回 watch id，之后每次修改推送一个带 events 的 CommandResponse
+#[derive(PartialOrd)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hwatch {
+    #[prost(string, tag = "1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(bytes = "bytes", tag = "2")]
+    pub key: ::prost::bytes::Bytes,
+    #[prost(bool, tag = "3")]
+    pub prefix: bool,
+    /// 大于 0 时先重放从这个 revision 开始（包括这个 revision）的修改，需要开启 MVCC
+    #[prost(uint64, tag = "4")]
+    pub from_revision: u64,
+}
+/// 取消 HWATCH
+#[derive(PartialOrd)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hunwatch {
+    #[prost(uint32, tag = "1")]
+    pub id: u32,
+}
+/// key 的一次修改：deleted 为 true 时是 DELETE，否则是 PUT
+#[derive(PartialOrd)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct KeyEvent {
+    #[prost(string, tag = "1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(bytes = "bytes", tag = "2")]
+    pub key: ::prost::bytes::Bytes,
+    #[prost(bool, tag = "3")]
+    pub deleted: bool,
+    /// 修改之前的值，key 之前不存在时为空
+    #[prost(message, optional, tag = "4")]
+    pub old_value: ::core::option::Option<Value>,
+    /// 修改之后的值，DELETE 时为空
+    #[prost(message, optional, tag = "5")]
+    pub new_value: ::core::option::Option<Value>,
+    /// 开启 MVCC 时是这次修改的 revision，同一个命令的修改共用一个 revision；否则为 0
+    #[prost(uint64, tag = "6")]
+    pub revision: u64,
+}
+/// 重新读取配置文件，应用其中可以在运行时修改的部分（日志级别、TLS 证书、认证和权限），
+/// 已有的连接不会断开
+#[derive(PartialOrd)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ConfigReload {}
+/// 查看运行时参数，pattern 支持 `*` 和 `prefix*`；pairs 中每个参数一项，value 是字符串
+#[derive(PartialOrd)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ConfigGet {
+    #[prost(string, tag = "1")]
+    pub pattern: ::prost::alloc::string::String,
+}
+/// 修改运行时参数，只在当前进程中生效，不会写回配置文件
+#[derive(PartialOrd)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ConfigSet {
+    #[prost(string, tag = "1")]
+    pub name: ::prost::alloc::string::String,
+    #[prost(string, tag = "2")]
+    pub value: ::prost::alloc::string::String,
+}
+/// 检查连接是否可用，返回 PONG，带了 message 时原样返回 message；不需要认证
+#[derive(PartialOrd)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Ping {
+    #[prost(bytes = "bytes", tag = "1")]
+    pub message: ::prost::bytes::Bytes,
+}
+/// 连接建立后协商 frame 的压缩算法：compressions 是客户端支持的算法，按优先级排列；
+/// 服务器选择第一个自己也支持的，放在 values 里返回，之后双方都用它压缩
+#[derive(PartialOrd)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hello {
+    #[prost(string, repeated, tag = "1")]
+    pub compressions: ::prost::alloc::vec::Vec<::prost::alloc::string::String>,
+}
+/// 把 values 依次插入到 list 的头部，key 不存在时创建一个空的 list；返回 list 的长度
+#[derive(PartialOrd)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Lpush {
+    #[prost(string, tag = "1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(bytes = "bytes", tag = "2")]
+    pub key: ::prost::bytes::Bytes,
+    #[prost(message, repeated, tag = "3")]
+    pub values: ::prost::alloc::vec::Vec<Value>,
+}
+/// 返回 list 中 \[start, stop\] 之间的元素，负数表示从尾部开始数，-1 是最后一个元素
+#[derive(PartialOrd)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Lrange {
+    #[prost(string, tag = "1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(bytes = "bytes", tag = "2")]
+    pub key: ::prost::bytes::Bytes,
+    #[prost(int64, tag = "3")]
+    pub start: i64,
+    #[prost(int64, tag = "4")]
+    pub stop: i64,
+}
+/// 把 members 加入 set，已经存在的会被忽略，key 不存在时创建一个空的 set；返回新加入的数量
+#[derive(PartialOrd)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Sadd {
+    #[prost(string, tag = "1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(bytes = "bytes", tag = "2")]
+    pub key: ::prost::bytes::Bytes,
+    #[prost(message, repeated, tag = "3")]
+    pub members: ::prost::alloc::vec::Vec<Value>,
+}
+/// 返回 set 中所有的 member
+#[derive(PartialOrd)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Smembers {
+    #[prost(string, tag = "1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(bytes = "bytes", tag = "2")]
+    pub key: ::prost::bytes::Bytes,
+}
+/// 读取 map 中 path 指向的 value，path 的每一项是一层 map 的 field；path 为空时返回整个 map
+#[derive(PartialOrd)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct MapGet {
+    #[prost(string, tag = "1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(bytes = "bytes", tag = "2")]
+    pub key: ::prost::bytes::Bytes,
+    #[prost(string, repeated, tag = "3")]
+    pub path: ::prost::alloc::vec::Vec<::prost::alloc::string::String>,
+}
+/// 把 map 中 path 指向的 field 设置为 value，中间不存在的 map 会被创建；返回之前的值
+#[derive(PartialOrd)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct MapSet {
+    #[prost(string, tag = "1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(bytes = "bytes", tag = "2")]
+    pub key: ::prost::bytes::Bytes,
+    #[prost(string, repeated, tag = "3")]
+    pub path: ::prost::alloc::vec::Vec<::prost::alloc::string::String>,
+    #[prost(message, optional, tag = "4")]
+    pub value: ::core::option::Option<Value>,
+}
+/// sorted set 中的一个 member
+#[derive(PartialOrd)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ScoredMember {
+    #[prost(bytes = "bytes", tag = "1")]
+    pub member: ::prost::bytes::Bytes,
+    #[prost(double, tag = "2")]
+    pub score: f64,
+}
+/// 把 members 加入 sorted set，已经存在的 member 更新 score；返回新加入的数量。
+/// sorted set 和普通的 value 分开存放，HGET/HDEL 等命令访问不到
+#[derive(PartialOrd)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Zadd {
+    #[prost(string, tag = "1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(bytes = "bytes", tag = "2")]
+    pub key: ::prost::bytes::Bytes,
+    #[prost(message, repeated, tag = "3")]
+    pub members: ::prost::alloc::vec::Vec<ScoredMember>,
+}
+/// 按 score 从小到大排列，返回排名在 \[start, stop\] 之间的 member，负数表示从尾部开始数；
+/// pairs 中 key 是 member，value 是 score
+#[derive(PartialOrd)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Zrange {
+    #[prost(string, tag = "1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(bytes = "bytes", tag = "2")]
+    pub key: ::prost::bytes::Bytes,
+    #[prost(int64, tag = "3")]
+    pub start: i64,
+    #[prost(int64, tag = "4")]
+    pub stop: i64,
+}
+/// 返回 score 在 \[min, max\] 之间的 member，按 score 从小到大排列；pairs 的格式和 ZRANGE 一样
+#[derive(PartialOrd)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Zrangebyscore {
+    #[prost(string, tag = "1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(bytes = "bytes", tag = "2")]
+    pub key: ::prost::bytes::Bytes,
+    #[prost(double, tag = "3")]
+    pub min: f64,
+    #[prost(double, tag = "4")]
+    pub max: f64,
+}
+/// 读取 JSON 文档中 path 指向的部分，返回 json 类型的 value。
+/// path 是 JSONPath 的一个子集：`$`、`.field`、`\['field'\]` 和 `\[index\]`，index 可以是负数
+#[derive(PartialOrd)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hjsonget {
+    #[prost(string, tag = "1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(bytes = "bytes", tag = "2")]
+    pub key: ::prost::bytes::Bytes,
+    #[prost(string, tag = "3")]
+    pub path: ::prost::alloc::string::String,
+}
+/// 把 JSON 文档中 path 指向的部分设置为 value（JSON 文本），返回之前的值；
+/// path 的上一层必须存在，key 不存在时 path 只能是 `$`
+#[derive(PartialOrd)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hjsonset {
+    #[prost(string, tag = "1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(bytes = "bytes", tag = "2")]
+    pub key: ::prost::bytes::Bytes,
+    #[prost(string, tag = "3")]
+    pub path: ::prost::alloc::string::String,
+    #[prost(string, tag = "4")]
+    pub value: ::prost::alloc::string::String,
+}
+/// 索引的定义：field 为空时索引整个 value，否则索引 map 或者 JSON 文档中 field 指向的部分，
+/// 没有这个 field 的 key 不会被索引
+#[derive(PartialOrd)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct IndexSpec {
+    #[prost(string, tag = "1")]
+    pub name: ::prost::alloc::string::String,
+    #[prost(string, repeated, tag = "2")]
+    pub field: ::prost::alloc::vec::Vec<::prost::alloc::string::String>,
+}
+/// 在 table 上建立索引，已有的数据也会加入索引；之后每次修改都会更新索引。
+/// 返回是否新建了索引，同名的索引已经存在时返回 false
+#[derive(PartialOrd)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct CreateIndex {
+    #[prost(string, tag = "1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(message, optional, tag = "2")]
+    pub index: ::core::option::Option<IndexSpec>,
+}
+/// 通过索引查找被索引的部分等于 value 的 key，返回这些 key 的 kvpair。
+/// string、integer、float 和 bool 按文本比较，例如 30 和 "30" 是相等的
+#[derive(PartialOrd)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Query {
+    #[prost(string, tag = "1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag = "2")]
+    pub index: ::prost::alloc::string::String,
+    #[prost(message, optional, tag = "3")]
+    pub value: ::core::option::Option<Value>,
+}
+/// WAL 中的一条修改记录，按 length delimited 的格式追加写入文件
+#[derive(PartialOrd)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct WalRecord {
+    #[prost(string, tag = "1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(bytes = "bytes", tag = "2")]
+    pub key: ::prost::bytes::Bytes,
+    #[prost(oneof = "wal_record::Op", tags = "3, 4, 5, 6, 7, 8, 9, 10")]
+    pub op: ::core::option::Option<wal_record::Op>,
+}
+/// Nested message and enum types in `WalRecord`.
+pub mod wal_record {
+    #[derive(PartialOrd)]
+    #[allow(clippy::derive_partial_eq_without_eq)]
+    #[derive(Clone, PartialEq, ::prost::Oneof)]
+    pub enum Op {
+        /// 写入的 value
+        #[prost(message, tag = "3")]
+        Set(super::Value),
+        /// 删除 key
+        #[prost(bool, tag = "4")]
+        Del(bool),
+        /// key 过期时刻的毫秒时间戳
+        #[prost(uint64, tag = "5")]
+        Expire(u64),
+        /// 需要原子生效的一组记录，table 和 key 为空
+        #[prost(message, tag = "6")]
+        Batch(super::WalBatch),
+        /// 加入 sorted set 的 member
+        #[prost(message, tag = "7")]
+        Zadd(super::ScoredMembers),
+        /// 在 table 上建立的索引，key 为空
+        #[prost(message, tag = "8")]
+        CreateIndex(super::IndexSpec),
+        /// 清空 table，key 为空
+        #[prost(bool, tag = "9")]
+        TruncateTable(bool),
+        /// 删除 table 以及 table 上的索引，key 为空
+        #[prost(bool, tag = "10")]
+        DropTable(bool),
+    }
+}
+#[derive(PartialOrd)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ScoredMembers {
+    #[prost(message, repeated, tag = "1")]
+    pub members: ::prost::alloc::vec::Vec<ScoredMember>,
+}
+#[derive(PartialOrd)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct WalBatch {
+    #[prost(message, repeated, tag = "1")]
+    pub records: ::prost::alloc::vec::Vec<WalRecord>,
+}
+/// bitcask 的 hint 文件中的一条记录，对应合并生成的数据文件中的一条记录，
+/// 启动时读取 hint 文件就能建立 keydir，不需要读取整个数据文件
+#[derive(PartialOrd)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct HintRecord {
+    #[prost(string, tag = "1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(bytes = "bytes", tag = "2")]
+    pub key: ::prost::bytes::Bytes,
+    /// 记录在数据文件中的位置和长度
+    #[prost(uint64, tag = "3")]
+    pub offset: u64,
+    #[prost(uint64, tag = "4")]
+    pub len: u64,
+    /// 记录是 SET 时 key 过期时刻的毫秒时间戳，0 表示没有过期时间
+    #[prost(uint64, tag = "5")]
+    pub expire: u64,
+    /// 记录是 SET：只需要加入 keydir；其它记录（ZADD、建立索引）启动时需要从数据文件读出来重放
+    #[prost(bool, tag = "6")]
+    pub set: bool,
+}
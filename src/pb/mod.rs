@@ -3,98 +3,1195 @@ pub mod abi;
 use abi::{command_request::RequestData, *};
 use bytes::Bytes;
 use hyper::StatusCode;
+use std::{borrow::Cow, time::Duration};
 
 use crate::error::KvError;
 
 impl CommandRequest {
     pub fn new_hset(
         table: impl Into<String>,
-        key: impl Into<String>,
+        key: impl AsRef<[u8]>,
         value: impl Into<Value>,
     ) -> Self {
         RequestData::Hset(Hset {
             table: table.into(),
             pair: Some(Kvpair::new(key, value.into())),
+            ttl: 0,
+            ..Default::default()
+        })
+        .into()
+    }
+
+    /// 设置 key 的同时指定存活时间（秒）
+    pub fn new_hset_ex(
+        table: impl Into<String>,
+        key: impl AsRef<[u8]>,
+        value: impl Into<Value>,
+        ttl_secs: u64,
+    ) -> Self {
+        RequestData::Hset(Hset {
+            table: table.into(),
+            pair: Some(Kvpair::new(key, value.into())),
+            ttl: ttl_secs,
+            ..Default::default()
+        })
+        .into()
+    }
+
+    /// 只在 key 不存在时设置，ttl_secs 为 0 表示永不过期
+    pub fn new_hsetnx(
+        table: impl Into<String>,
+        key: impl AsRef<[u8]>,
+        value: impl Into<Value>,
+        ttl_secs: u64,
+    ) -> Self {
+        RequestData::Hset(Hset {
+            table: table.into(),
+            pair: Some(Kvpair::new(key, value.into())),
+            ttl: ttl_secs,
+            nx: true,
+            ..Default::default()
+        })
+        .into()
+    }
+
+    /// 只在 key 已经存在时设置，ttl_secs 为 0 表示永不过期
+    pub fn new_hsetxx(
+        table: impl Into<String>,
+        key: impl AsRef<[u8]>,
+        value: impl Into<Value>,
+        ttl_secs: u64,
+    ) -> Self {
+        RequestData::Hset(Hset {
+            table: table.into(),
+            pair: Some(Kvpair::new(key, value.into())),
+            ttl: ttl_secs,
+            xx: true,
+            ..Default::default()
+        })
+        .into()
+    }
+
+    /// 设置 key 的同时把它关联到 lease
+    pub fn new_hset_with_lease(
+        table: impl Into<String>,
+        key: impl AsRef<[u8]>,
+        value: impl Into<Value>,
+        lease: u64,
+    ) -> Self {
+        RequestData::Hset(Hset {
+            table: table.into(),
+            pair: Some(Kvpair::new(key, value.into())),
+            lease,
+            ..Default::default()
+        })
+        .into()
+    }
+
+    pub fn new_hexpire(table: impl Into<String>, key: impl AsRef<[u8]>, ttl_secs: u64) -> Self {
+        RequestData::Hexpire(Hexpire {
+            table: table.into(),
+            key: to_key(key),
+            ttl: ttl_secs,
+        })
+        .into()
+    }
+
+    pub fn new_httl(table: impl Into<String>, key: impl AsRef<[u8]>) -> Self {
+        RequestData::Httl(Httl {
+            table: table.into(),
+            key: to_key(key),
+        })
+        .into()
+    }
+
+    /// expected 为 None 表示 key 必须不存在
+    pub fn new_hcas(
+        table: impl Into<String>,
+        key: impl AsRef<[u8]>,
+        expected: Option<Value>,
+        value: impl Into<Value>,
+    ) -> Self {
+        RequestData::Hcas(Hcas {
+            table: table.into(),
+            key: to_key(key),
+            expected,
+            value: Some(value.into()),
+        })
+        .into()
+    }
+
+    pub fn new_hincrby(table: impl Into<String>, key: impl AsRef<[u8]>, delta: i64) -> Self {
+        RequestData::Hincrby(Hincrby {
+            table: table.into(),
+            key: to_key(key),
+            delta,
+        })
+        .into()
+    }
+
+    pub fn new_happend(
+        table: impl Into<String>,
+        key: impl AsRef<[u8]>,
+        suffix: impl Into<Value>,
+    ) -> Self {
+        RequestData::Happend(Happend {
+            table: table.into(),
+            key: to_key(key),
+            suffix: Some(suffix.into()),
+        })
+        .into()
+    }
+
+    pub fn new_hstrlen(table: impl Into<String>, key: impl AsRef<[u8]>) -> Self {
+        RequestData::Hstrlen(Hstrlen {
+            table: table.into(),
+            key: to_key(key),
+        })
+        .into()
+    }
+
+    /// offset 为负数时从末尾开始数，len 为 0 时读到末尾
+    pub fn new_hgetrange(
+        table: impl Into<String>,
+        key: impl AsRef<[u8]>,
+        offset: i64,
+        len: u64,
+    ) -> Self {
+        RequestData::Hgetrange(Hgetrange {
+            table: table.into(),
+            key: to_key(key),
+            offset,
+            len,
+        })
+        .into()
+    }
+
+    pub fn new_hsetbit(
+        table: impl Into<String>,
+        key: impl AsRef<[u8]>,
+        offset: u64,
+        bit: bool,
+    ) -> Self {
+        RequestData::Hsetbit(Hsetbit {
+            table: table.into(),
+            key: to_key(key),
+            offset,
+            bit,
+        })
+        .into()
+    }
+
+    pub fn new_hgetbit(table: impl Into<String>, key: impl AsRef<[u8]>, offset: u64) -> Self {
+        RequestData::Hgetbit(Hgetbit {
+            table: table.into(),
+            key: to_key(key),
+            offset,
+        })
+        .into()
+    }
+
+    pub fn new_hbitcount(table: impl Into<String>, key: impl AsRef<[u8]>) -> Self {
+        RequestData::Hbitcount(Hbitcount {
+            table: table.into(),
+            key: to_key(key),
+        })
+        .into()
+    }
+
+    pub fn new_hpfadd(
+        table: impl Into<String>,
+        key: impl AsRef<[u8]>,
+        members: impl IntoIterator<Item = impl Into<Value>>,
+    ) -> Self {
+        RequestData::Hpfadd(Hpfadd {
+            table: table.into(),
+            key: to_key(key),
+            members: members.into_iter().map(Into::into).collect(),
+        })
+        .into()
+    }
+
+    pub fn new_hpfcount(
+        table: impl Into<String>,
+        keys: impl IntoIterator<Item = impl AsRef<[u8]>>,
+    ) -> Self {
+        RequestData::Hpfcount(Hpfcount {
+            table: table.into(),
+            keys: keys.into_iter().map(to_key).collect(),
+        })
+        .into()
+    }
+
+    pub fn new_eval(
+        table: impl Into<String>,
+        script: impl Into<String>,
+        keys: impl IntoIterator<Item = impl AsRef<[u8]>>,
+        args: impl IntoIterator<Item = impl Into<Value>>,
+    ) -> Self {
+        RequestData::Eval(Eval {
+            table: table.into(),
+            script: script.into(),
+            keys: keys.into_iter().map(to_key).collect(),
+            args: args.into_iter().map(Into::into).collect(),
+        })
+        .into()
+    }
+
+    pub fn new_evalsha(
+        table: impl Into<String>,
+        sha: impl Into<String>,
+        keys: impl IntoIterator<Item = impl AsRef<[u8]>>,
+        args: impl IntoIterator<Item = impl Into<Value>>,
+    ) -> Self {
+        RequestData::Evalsha(Evalsha {
+            table: table.into(),
+            sha: sha.into(),
+            keys: keys.into_iter().map(to_key).collect(),
+            args: args.into_iter().map(Into::into).collect(),
+        })
+        .into()
+    }
+
+    pub fn new_fcall(
+        table: impl Into<String>,
+        function: impl Into<String>,
+        keys: impl IntoIterator<Item = impl AsRef<[u8]>>,
+        args: impl IntoIterator<Item = impl Into<Value>>,
+    ) -> Self {
+        RequestData::Fcall(Fcall {
+            table: table.into(),
+            function: function.into(),
+            keys: keys.into_iter().map(to_key).collect(),
+            args: args.into_iter().map(Into::into).collect(),
+        })
+        .into()
+    }
+
+    pub fn new_hdecrby(table: impl Into<String>, key: impl AsRef<[u8]>, delta: i64) -> Self {
+        Self::new_hincrby(table, key, delta.saturating_neg())
+    }
+
+    pub fn new_hget(table: impl Into<String>, key: impl AsRef<[u8]>) -> Self {
+        RequestData::Hget(Hget {
+            table: table.into(),
+            key: to_key(key),
+            at_revision: 0,
+        })
+        .into()
+    }
+
+    pub fn new_hget_at(table: impl Into<String>, key: impl AsRef<[u8]>, revision: u64) -> Self {
+        RequestData::Hget(Hget {
+            table: table.into(),
+            key: to_key(key),
+            at_revision: revision,
+        })
+        .into()
+    }
+
+    pub fn new_hdel(table: impl Into<String>, key: impl AsRef<[u8]>) -> Self {
+        RequestData::Hdel(Hdel {
+            table: table.into(),
+            key: to_key(key),
+        })
+        .into()
+    }
+
+    pub fn new_hgetall(table: impl Into<String>) -> Self {
+        RequestData::Hgetall(Hgetall {
+            table: table.into(),
+        })
+        .into()
+    }
+
+    pub fn new_hmget(
+        table: impl Into<String>,
+        keys: impl IntoIterator<Item = impl AsRef<[u8]>>,
+    ) -> Self {
+        RequestData::Hmget(Hmget {
+            table: table.into(),
+            keys: keys.into_iter().map(to_key).collect(),
+        })
+        .into()
+    }
+
+    pub fn new_hmset(
+        table: impl Into<String>,
+        pairs: impl IntoIterator<Item = (impl AsRef<[u8]>, impl Into<Value>)>,
+    ) -> Self {
+        RequestData::Hmset(Hmset {
+            table: table.into(),
+            pairs: pairs
+                .into_iter()
+                .map(|(key, value)| Kvpair::new(key, value.into()))
+                .collect(),
+        })
+        .into()
+    }
+
+    pub fn new_hscan(table: impl Into<String>, cursor: u64, count: u64) -> Self {
+        RequestData::Hscan(Hscan {
+            table: table.into(),
+            cursor,
+            count,
+        })
+        .into()
+    }
+
+    pub fn new_hexist(table: impl Into<String>, key: impl AsRef<[u8]>) -> Self {
+        RequestData::Hexist(Hexist {
+            table: table.into(),
+            key: to_key(key),
+        })
+        .into()
+    }
+
+    pub fn new_hmdel(
+        table: impl Into<String>,
+        keys: impl IntoIterator<Item = impl AsRef<[u8]>>,
+    ) -> Self {
+        RequestData::Hmdel(Hmdel {
+            table: table.into(),
+            keys: keys.into_iter().map(to_key).collect(),
+        })
+        .into()
+    }
+
+    pub fn new_hmexist(
+        table: impl Into<String>,
+        keys: impl IntoIterator<Item = impl AsRef<[u8]>>,
+    ) -> Self {
+        RequestData::Hmexist(Hmexist {
+            table: table.into(),
+            keys: keys.into_iter().map(to_key).collect(),
+        })
+        .into()
+    }
+
+    pub fn new_publish(topic: &str, data: Vec<Value>) -> Self {
+        RequestData::Publish(Publish {
+            topic: topic.into(),
+            data,
+        })
+        .into()
+    }
+
+    pub fn new_subscribe(topic: &str) -> Self {
+        RequestData::Subscribe(Subscribe {
+            topic: topic.into(),
+        })
+        .into()
+    }
+
+    pub fn new_unsubscribe(topic: &str, id: u32) -> Self {
+        RequestData::Unsubscribe(Unsubscribe {
+            topic: topic.into(),
+            id,
+        })
+        .into()
+    }
+
+    pub fn new_auth(username: impl Into<String>, password: impl Into<String>) -> Self {
+        RequestData::Auth(Auth {
+            username: username.into(),
+            password: password.into(),
+        })
+        .into()
+    }
+
+    pub fn new_snapshot(path: impl Into<String>) -> Self {
+        RequestData::Snapshot(Snapshot { path: path.into() }).into()
+    }
+
+    pub fn new_replicate() -> Self {
+        RequestData::Replicate(Replicate {}).into()
+    }
+
+    pub fn new_multi() -> Self {
+        RequestData::Multi(Multi {}).into()
+    }
+
+    pub fn new_exec() -> Self {
+        RequestData::Exec(Exec {}).into()
+    }
+
+    pub fn new_discard() -> Self {
+        RequestData::Discard(Discard {}).into()
+    }
+
+    pub fn new_watch(
+        table: impl Into<String>,
+        keys: impl IntoIterator<Item = impl AsRef<[u8]>>,
+    ) -> Self {
+        RequestData::Watch(Watch {
+            table: table.into(),
+            keys: keys.into_iter().map(to_key).collect(),
+        })
+        .into()
+    }
+
+    pub fn new_unwatch() -> Self {
+        RequestData::Unwatch(Unwatch {}).into()
+    }
+
+    /// 把一组命令打包成一个 batch，只需要一次往返
+    pub fn new_hwatch(table: impl Into<String>, key: impl AsRef<[u8]>, prefix: bool) -> Self {
+        Self::new_hwatch_from(table, key, prefix, 0)
+    }
+
+    pub fn new_hwatch_from(
+        table: impl Into<String>,
+        key: impl AsRef<[u8]>,
+        prefix: bool,
+        revision: u64,
+    ) -> Self {
+        RequestData::Hwatch(Hwatch {
+            table: table.into(),
+            key: to_key(key),
+            prefix,
+            from_revision: revision,
+        })
+        .into()
+    }
+
+    pub fn new_hunwatch(id: u32) -> Self {
+        RequestData::Hunwatch(Hunwatch { id }).into()
+    }
+
+    pub fn new_config_reload() -> Self {
+        RequestData::ConfigReload(ConfigReload {}).into()
+    }
+
+    pub fn new_config_get(pattern: impl Into<String>) -> Self {
+        RequestData::ConfigGet(ConfigGet {
+            pattern: pattern.into(),
+        })
+        .into()
+    }
+
+    pub fn new_config_set(name: impl Into<String>, value: impl Into<String>) -> Self {
+        RequestData::ConfigSet(ConfigSet {
+            name: name.into(),
+            value: value.into(),
+        })
+        .into()
+    }
+
+    /// 设置超时时间，服务器超过这个时间还没有执行完时返回 504
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout.as_millis() as u64;
+        self
+    }
+
+    /// 设置幂等 key，重试时服务器返回第一次执行的结果
+    pub fn with_idempotency_key(mut self, key: impl Into<String>) -> Self {
+        self.idempotency_key = key.into();
+        self
+    }
+
+    /// 允许服务器分多帧返回结果，除了最后一帧，其它帧的 has_more 都为 true
+    pub fn with_stream(mut self) -> Self {
+        self.stream = true;
+        self
+    }
+
+    pub fn new_ping() -> Self {
+        Self::new_ping_with("")
+    }
+
+    pub fn new_ping_with(message: impl Into<Bytes>) -> Self {
+        RequestData::Ping(Ping {
+            message: message.into(),
+        })
+        .into()
+    }
+
+    pub fn new_hello(compressions: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        RequestData::Hello(Hello {
+            compressions: compressions.into_iter().map(Into::into).collect(),
+        })
+        .into()
+    }
+
+    /// values 依次插入到头部，所以在 list 中的顺序和参数相反
+    pub fn new_lpush(
+        table: impl Into<String>,
+        key: impl AsRef<[u8]>,
+        values: impl IntoIterator<Item = impl Into<Value>>,
+    ) -> Self {
+        RequestData::Lpush(Lpush {
+            table: table.into(),
+            key: to_key(key),
+            values: values.into_iter().map(Into::into).collect(),
+        })
+        .into()
+    }
+
+    pub fn new_lrange(
+        table: impl Into<String>,
+        key: impl AsRef<[u8]>,
+        start: i64,
+        stop: i64,
+    ) -> Self {
+        RequestData::Lrange(Lrange {
+            table: table.into(),
+            key: to_key(key),
+            start,
+            stop,
+        })
+        .into()
+    }
+
+    pub fn new_sadd(
+        table: impl Into<String>,
+        key: impl AsRef<[u8]>,
+        members: impl IntoIterator<Item = impl Into<Value>>,
+    ) -> Self {
+        RequestData::Sadd(Sadd {
+            table: table.into(),
+            key: to_key(key),
+            members: members.into_iter().map(Into::into).collect(),
+        })
+        .into()
+    }
+
+    pub fn new_smembers(table: impl Into<String>, key: impl AsRef<[u8]>) -> Self {
+        RequestData::Smembers(Smembers {
+            table: table.into(),
+            key: to_key(key),
+        })
+        .into()
+    }
+
+    pub fn new_map_get(
+        table: impl Into<String>,
+        key: impl AsRef<[u8]>,
+        path: impl IntoIterator<Item = impl Into<String>>,
+    ) -> Self {
+        RequestData::MapGet(MapGet {
+            table: table.into(),
+            key: to_key(key),
+            path: path.into_iter().map(Into::into).collect(),
+        })
+        .into()
+    }
+
+    pub fn new_map_set(
+        table: impl Into<String>,
+        key: impl AsRef<[u8]>,
+        path: impl IntoIterator<Item = impl Into<String>>,
+        value: impl Into<Value>,
+    ) -> Self {
+        RequestData::MapSet(MapSet {
+            table: table.into(),
+            key: to_key(key),
+            path: path.into_iter().map(Into::into).collect(),
+            value: Some(value.into()),
+        })
+        .into()
+    }
+
+    /// members 是 (member, score)
+    pub fn new_zadd(
+        table: impl Into<String>,
+        key: impl AsRef<[u8]>,
+        members: impl IntoIterator<Item = (impl AsRef<[u8]>, f64)>,
+    ) -> Self {
+        RequestData::Zadd(Zadd {
+            table: table.into(),
+            key: to_key(key),
+            members: members.into_iter().map(Into::into).collect(),
+        })
+        .into()
+    }
+
+    pub fn new_zrange(
+        table: impl Into<String>,
+        key: impl AsRef<[u8]>,
+        start: i64,
+        stop: i64,
+    ) -> Self {
+        RequestData::Zrange(Zrange {
+            table: table.into(),
+            key: to_key(key),
+            start,
+            stop,
+        })
+        .into()
+    }
+
+    pub fn new_zrangebyscore(
+        table: impl Into<String>,
+        key: impl AsRef<[u8]>,
+        min: f64,
+        max: f64,
+    ) -> Self {
+        RequestData::Zrangebyscore(Zrangebyscore {
+            table: table.into(),
+            key: to_key(key),
+            min,
+            max,
+        })
+        .into()
+    }
+
+    pub fn new_hjsonget(
+        table: impl Into<String>,
+        key: impl AsRef<[u8]>,
+        path: impl Into<String>,
+    ) -> Self {
+        RequestData::Hjsonget(Hjsonget {
+            table: table.into(),
+            key: to_key(key),
+            path: path.into(),
         })
         .into()
     }
 
-    pub fn new_hget(table: impl Into<String>, key: impl Into<String>) -> Self {
-        RequestData::Hget(Hget {
+    /// value 是 JSON 文本
+    pub fn new_hjsonset(
+        table: impl Into<String>,
+        key: impl AsRef<[u8]>,
+        path: impl Into<String>,
+        value: impl Into<String>,
+    ) -> Self {
+        RequestData::Hjsonset(Hjsonset {
             table: table.into(),
-            key: key.into(),
+            key: to_key(key),
+            path: path.into(),
+            value: value.into(),
         })
         .into()
     }
 
-    pub fn new_hdel(table: impl Into<String>, key: impl Into<String>) -> Self {
-        RequestData::Hdel(Hdel {
+    /// field 为空时索引整个 value
+    pub fn new_create_index(
+        table: impl Into<String>,
+        name: impl Into<String>,
+        field: impl IntoIterator<Item = impl Into<String>>,
+    ) -> Self {
+        RequestData::CreateIndex(CreateIndex {
             table: table.into(),
-            key: key.into(),
+            index: Some(IndexSpec {
+                name: name.into(),
+                field: field.into_iter().map(Into::into).collect(),
+            }),
         })
         .into()
     }
 
-    pub fn new_hgetall(table: impl Into<String>) -> Self {
-        RequestData::Hgetall(Hgetall {
+    pub fn new_query(
+        table: impl Into<String>,
+        index: impl Into<String>,
+        value: impl Into<Value>,
+    ) -> Self {
+        RequestData::Query(Query {
             table: table.into(),
+            index: index.into(),
+            value: Some(value.into()),
         })
         .into()
     }
 
-    pub fn new_hmget(table: impl Into<String>, key: impl Into<String>) -> Self {
-        RequestData::Hmget(Hmget {
+    /// end 为空表示一直到 table 的最后
+    pub fn new_hrange(
+        table: impl Into<String>,
+        start: impl AsRef<[u8]>,
+        end: impl AsRef<[u8]>,
+    ) -> Self {
+        RequestData::Hrange(Hrange {
             table: table.into(),
-            keys: vec![key.into()],
+            start: to_key(start),
+            end: to_key(end),
         })
         .into()
     }
 
-    pub fn new_publish(topic: &str, data: Vec<Value>) -> Self {
-        RequestData::Publish(Publish {
-            topic: topic.into(),
-            data,
+    pub fn new_hscanprefix(table: impl Into<String>, prefix: impl AsRef<[u8]>) -> Self {
+        RequestData::Hscanprefix(Hscanprefix {
+            table: table.into(),
+            prefix: to_key(prefix),
         })
         .into()
     }
 
-    pub fn new_subscribe(topic: &str) -> Self {
-        RequestData::Subscribe(Subscribe {
-            topic: topic.into(),
+    pub fn new_tlist() -> Self {
+        RequestData::Tlist(Tlist {}).into()
+    }
+
+    pub fn new_tdrop(table: impl Into<String>) -> Self {
+        RequestData::Tdrop(Tdrop {
+            table: table.into(),
         })
         .into()
     }
 
-    pub fn new_unsubscribe(topic: &str, id: u32) -> Self {
-        RequestData::Unsubscribe(Unsubscribe {
-            topic: topic.into(),
+    pub fn new_ttruncate(table: impl Into<String>) -> Self {
+        RequestData::Ttruncate(Ttruncate {
+            table: table.into(),
+        })
+        .into()
+    }
+
+    pub fn new_dbsize() -> Self {
+        RequestData::Dbsize(Dbsize::default()).into()
+    }
+
+    /// table 为空时返回所有 table 的统计信息
+    pub fn new_hstats(table: impl Into<String>) -> Self {
+        RequestData::Hstats(Hstats {
+            table: table.into(),
+        })
+        .into()
+    }
+
+    pub fn new_quota(table: impl Into<String>) -> Self {
+        RequestData::Quota(Quota {
+            table: table.into(),
+        })
+        .into()
+    }
+
+    pub fn new_info() -> Self {
+        RequestData::Info(Info {}).into()
+    }
+
+    pub fn new_client_list() -> Self {
+        RequestData::ClientList(ClientList {}).into()
+    }
+
+    /// table 为空时取消默认 table
+    pub fn new_select(table: impl Into<String>) -> Self {
+        RequestData::Select(Select {
+            table: table.into(),
+        })
+        .into()
+    }
+
+    pub fn new_session_set(name: impl Into<String>, value: impl Into<String>) -> Self {
+        RequestData::SessionSet(SessionSet {
+            name: name.into(),
+            value: value.into(),
+        })
+        .into()
+    }
+
+    /// 断开 id 对应的连接
+    pub fn new_client_kill(id: u64) -> Self {
+        RequestData::ClientKill(ClientKill {
+            id,
+            peer: String::new(),
+        })
+        .into()
+    }
+
+    /// 断开地址是 peer 的所有连接
+    pub fn new_client_kill_peer(peer: impl Into<String>) -> Self {
+        RequestData::ClientKill(ClientKill {
+            id: 0,
+            peer: peer.into(),
+        })
+        .into()
+    }
+
+    /// count 为 0 时返回全部
+    pub fn new_slowlog_get(count: u32) -> Self {
+        RequestData::SlowlogGet(SlowlogGet { count }).into()
+    }
+
+    /// 获取锁，租约时间 ttl 按毫秒计算
+    pub fn new_lock(table: impl Into<String>, name: impl Into<String>, ttl: Duration) -> Self {
+        Self::new_renew_lock(table, name, ttl, 0)
+    }
+
+    /// 锁仍然属于 token 时把租约延长到 ttl 之后
+    pub fn new_renew_lock(
+        table: impl Into<String>,
+        name: impl Into<String>,
+        ttl: Duration,
+        token: u64,
+    ) -> Self {
+        RequestData::Lock(Lock {
+            table: table.into(),
+            name: name.into(),
+            ttl_ms: ttl.as_millis() as u64,
+            token,
+        })
+        .into()
+    }
+
+    /// 创建一个 lease，id 由服务器分配
+    pub fn new_lease_grant(ttl_secs: u64) -> Self {
+        Self::new_lease_grant_with_id(0, ttl_secs)
+    }
+
+    pub fn new_lease_grant_with_id(id: u64, ttl_secs: u64) -> Self {
+        RequestData::LeaseGrant(LeaseGrant {
+            ttl: ttl_secs,
+            id,
+            ..Default::default()
+        })
+        .into()
+    }
+
+    pub fn new_lease_keep_alive(id: u64) -> Self {
+        RequestData::LeaseKeepAlive(LeaseKeepAlive {
+            id,
+            ..Default::default()
+        })
+        .into()
+    }
+
+    pub fn new_lease_revoke(id: u64) -> Self {
+        RequestData::LeaseRevoke(LeaseRevoke {
             id,
+            ..Default::default()
+        })
+        .into()
+    }
+
+    pub fn new_cluster_slots() -> Self {
+        RequestData::ClusterSlots(ClusterSlots {}).into()
+    }
+
+    pub fn new_gossip(members: Vec<Member>) -> Self {
+        RequestData::Gossip(Gossip { members }).into()
+    }
+
+    pub fn new_members() -> Self {
+        RequestData::Members(Members {}).into()
+    }
+
+    pub fn new_compact() -> Self {
+        RequestData::Compact(Compact {}).into()
+    }
+
+    pub fn new_hcompact(revision: u64) -> Self {
+        RequestData::Hcompact(Hcompact { revision }).into()
+    }
+
+    pub fn new_custom(type_url: impl Into<String>, value: impl Into<Bytes>) -> Self {
+        RequestData::Custom(Custom {
+            type_url: type_url.into(),
+            value: value.into(),
+        })
+        .into()
+    }
+
+    /// 参数是 protobuf 消息的自定义命令
+    pub fn new_custom_message(type_url: impl Into<String>, msg: &impl prost::Message) -> Self {
+        Self::new_custom(type_url, msg.encode_to_vec())
+    }
+
+    pub fn new_unlock(table: impl Into<String>, name: impl Into<String>, token: u64) -> Self {
+        RequestData::Unlock(Unlock {
+            table: table.into(),
+            name: name.into(),
+            token,
+        })
+        .into()
+    }
+
+    pub fn new_batch(commands: impl IntoIterator<Item = CommandRequest>) -> Self {
+        RequestData::Batch(Batch {
+            commands: commands.into_iter().collect(),
         })
         .into()
     }
+
+    /// 写日志用的命令：AUTH 的密码替换成 ***，BATCH 中的 AUTH 也一样；其它命令不复制
+    pub fn redacted(&self) -> Cow<'_, CommandRequest> {
+        if !self.request_data.as_ref().is_some_and(has_secret) {
+            return Cow::Borrowed(self);
+        }
+        let mut cmd = self.clone();
+        redact(&mut cmd);
+        Cow::Owned(cmd)
+    }
+}
+
+fn has_secret(data: &RequestData) -> bool {
+    match data {
+        RequestData::Auth(_) => true,
+        RequestData::Batch(batch) => batch
+            .commands
+            .iter()
+            .any(|cmd| cmd.request_data.as_ref().is_some_and(has_secret)),
+        _ => false,
+    }
+}
+
+fn redact(cmd: &mut CommandRequest) {
+    match &mut cmd.request_data {
+        Some(RequestData::Auth(auth)) => auth.password = "***".into(),
+        Some(RequestData::Batch(batch)) => batch.commands.iter_mut().for_each(redact),
+        _ => {}
+    }
+}
+
+impl RequestData {
+    /// 命令的名字，比如 HGET；用于日志和 CLIENT LIST
+    pub fn name(&self) -> &'static str {
+        match self {
+            RequestData::Hget(_) => "HGET",
+            RequestData::Hgetall(_) => "HGETALL",
+            RequestData::Hmget(_) => "HMGET",
+            RequestData::Hset(_) => "HSET",
+            RequestData::Hmset(_) => "HMSET",
+            RequestData::Hdel(_) => "HDEL",
+            RequestData::Hmdel(_) => "HMDEL",
+            RequestData::Hexist(_) => "HEXIST",
+            RequestData::Hmexist(_) => "HMEXIST",
+            RequestData::Subscribe(_) => "SUBSCRIBE",
+            RequestData::Unsubscribe(_) => "UNSUBSCRIBE",
+            RequestData::Publish(_) => "PUBLISH",
+            RequestData::Hexpire(_) => "HEXPIRE",
+            RequestData::Httl(_) => "HTTL",
+            RequestData::Batch(_) => "BATCH",
+            RequestData::Hscan(_) => "HSCAN",
+            RequestData::Auth(_) => "AUTH",
+            RequestData::Snapshot(_) => "SNAPSHOT",
+            RequestData::Replicate(_) => "REPLICATE",
+            RequestData::Hcas(_) => "HCAS",
+            RequestData::Hincrby(_) => "HINCRBY",
+            RequestData::Happend(_) => "HAPPEND",
+            RequestData::Hstrlen(_) => "HSTRLEN",
+            RequestData::Hgetrange(_) => "HGETRANGE",
+            RequestData::Hsetbit(_) => "HSETBIT",
+            RequestData::Hgetbit(_) => "HGETBIT",
+            RequestData::Hbitcount(_) => "HBITCOUNT",
+            RequestData::Hpfadd(_) => "HPFADD",
+            RequestData::Hpfcount(_) => "HPFCOUNT",
+            RequestData::Eval(_) => "EVAL",
+            RequestData::Evalsha(_) => "EVALSHA",
+            RequestData::Fcall(_) => "FCALL",
+            RequestData::Multi(_) => "MULTI",
+            RequestData::Exec(_) => "EXEC",
+            RequestData::Discard(_) => "DISCARD",
+            RequestData::Watch(_) => "WATCH",
+            RequestData::Unwatch(_) => "UNWATCH",
+            RequestData::Hwatch(_) => "HWATCH",
+            RequestData::Hunwatch(_) => "HUNWATCH",
+            RequestData::ConfigReload(_) => "CONFIGRELOAD",
+            RequestData::ConfigGet(_) => "CONFIGGET",
+            RequestData::ConfigSet(_) => "CONFIGSET",
+            RequestData::Ping(_) => "PING",
+            RequestData::Hello(_) => "HELLO",
+            RequestData::Lpush(_) => "LPUSH",
+            RequestData::Lrange(_) => "LRANGE",
+            RequestData::Sadd(_) => "SADD",
+            RequestData::Smembers(_) => "SMEMBERS",
+            RequestData::MapGet(_) => "MAPGET",
+            RequestData::MapSet(_) => "MAPSET",
+            RequestData::Zadd(_) => "ZADD",
+            RequestData::Zrange(_) => "ZRANGE",
+            RequestData::Zrangebyscore(_) => "ZRANGEBYSCORE",
+            RequestData::Hjsonget(_) => "HJSONGET",
+            RequestData::Hjsonset(_) => "HJSONSET",
+            RequestData::CreateIndex(_) => "CREATEINDEX",
+            RequestData::Query(_) => "QUERY",
+            RequestData::Hrange(_) => "HRANGE",
+            RequestData::Hscanprefix(_) => "HSCANPREFIX",
+            RequestData::Tlist(_) => "TLIST",
+            RequestData::Tdrop(_) => "TDROP",
+            RequestData::Ttruncate(_) => "TTRUNCATE",
+            RequestData::Dbsize(_) => "DBSIZE",
+            RequestData::Hstats(_) => "HSTATS",
+            RequestData::Info(_) => "INFO",
+            RequestData::SlowlogGet(_) => "SLOWLOGGET",
+            RequestData::Lock(_) => "LOCK",
+            RequestData::Unlock(_) => "UNLOCK",
+            RequestData::LeaseGrant(_) => "LEASEGRANT",
+            RequestData::LeaseKeepAlive(_) => "LEASEKEEPALIVE",
+            RequestData::LeaseRevoke(_) => "LEASEREVOKE",
+            RequestData::RaftVote(_) => "RAFTVOTE",
+            RequestData::RaftAppend(_) => "RAFTAPPEND",
+            RequestData::ClusterSlots(_) => "CLUSTERSLOTS",
+            RequestData::Gossip(_) => "GOSSIP",
+            RequestData::Members(_) => "MEMBERS",
+            RequestData::Compact(_) => "COMPACT",
+            RequestData::Hcompact(_) => "HCOMPACT",
+            RequestData::Custom(_) => "CUSTOM",
+            RequestData::ClientList(_) => "CLIENTLIST",
+            RequestData::ClientKill(_) => "CLIENTKILL",
+            RequestData::Select(_) => "SELECT",
+            RequestData::SessionSet(_) => "SESSIONSET",
+            RequestData::Quota(_) => "QUOTA",
+        }
+    }
+
+    /// 命令访问的 table，没有 table 的命令返回 None；BATCH 中的命令需要分别处理
+    pub fn table_mut(&mut self) -> Option<&mut String> {
+        match self {
+            RequestData::Hget(Hget { table, .. })
+            | RequestData::Hgetall(Hgetall { table, .. })
+            | RequestData::Hmget(Hmget { table, .. })
+            | RequestData::Hset(Hset { table, .. })
+            | RequestData::Hmset(Hmset { table, .. })
+            | RequestData::Hdel(Hdel { table, .. })
+            | RequestData::Hmdel(Hmdel { table, .. })
+            | RequestData::Hexist(Hexist { table, .. })
+            | RequestData::Hmexist(Hmexist { table, .. })
+            | RequestData::Hexpire(Hexpire { table, .. })
+            | RequestData::Httl(Httl { table, .. })
+            | RequestData::Hscan(Hscan { table, .. })
+            | RequestData::Hcas(Hcas { table, .. })
+            | RequestData::Hincrby(Hincrby { table, .. })
+            | RequestData::Happend(Happend { table, .. })
+            | RequestData::Hstrlen(Hstrlen { table, .. })
+            | RequestData::Hgetrange(Hgetrange { table, .. })
+            | RequestData::Hsetbit(Hsetbit { table, .. })
+            | RequestData::Hgetbit(Hgetbit { table, .. })
+            | RequestData::Hbitcount(Hbitcount { table, .. })
+            | RequestData::Hpfadd(Hpfadd { table, .. })
+            | RequestData::Hpfcount(Hpfcount { table, .. })
+            | RequestData::Eval(Eval { table, .. })
+            | RequestData::Evalsha(Evalsha { table, .. })
+            | RequestData::Fcall(Fcall { table, .. })
+            | RequestData::Watch(Watch { table, .. })
+            | RequestData::Hwatch(Hwatch { table, .. })
+            | RequestData::Lpush(Lpush { table, .. })
+            | RequestData::Lrange(Lrange { table, .. })
+            | RequestData::Sadd(Sadd { table, .. })
+            | RequestData::Smembers(Smembers { table, .. })
+            | RequestData::MapGet(MapGet { table, .. })
+            | RequestData::MapSet(MapSet { table, .. })
+            | RequestData::Zadd(Zadd { table, .. })
+            | RequestData::Zrange(Zrange { table, .. })
+            | RequestData::Zrangebyscore(Zrangebyscore { table, .. })
+            | RequestData::Hjsonget(Hjsonget { table, .. })
+            | RequestData::Hjsonset(Hjsonset { table, .. })
+            | RequestData::CreateIndex(CreateIndex { table, .. })
+            | RequestData::Query(Query { table, .. })
+            | RequestData::Hrange(Hrange { table, .. })
+            | RequestData::Hscanprefix(Hscanprefix { table, .. })
+            | RequestData::Tdrop(Tdrop { table, .. })
+            | RequestData::Ttruncate(Ttruncate { table, .. })
+            | RequestData::Hstats(Hstats { table, .. })
+            | RequestData::Quota(Quota { table, .. })
+            | RequestData::Lock(Lock { table, .. })
+            | RequestData::Unlock(Unlock { table, .. }) => Some(table),
+            _ => None,
+        }
+    }
 }
 
 impl CommandResponse {
     pub fn ok() -> Self {
-        let mut cmd = CommandResponse::default();
-        cmd.status = StatusCode::OK.as_u16() as _;
-        cmd
+        Self {
+            status: StatusCode::OK.as_u16() as _,
+            ..Default::default()
+        }
     }
 }
 
 impl Kvpair {
-    pub fn new(key: impl Into<String>, value: Value) -> Self {
+    pub fn new(key: impl AsRef<[u8]>, value: Value) -> Self {
         Self {
-            key: key.into(),
+            key: to_key(key),
             value: Some(value),
         }
     }
 }
 
+impl WalRecord {
+    pub fn new_set(table: impl Into<String>, key: impl AsRef<[u8]>, value: Value) -> Self {
+        Self {
+            table: table.into(),
+            key: to_key(key),
+            op: Some(wal_record::Op::Set(value)),
+        }
+    }
+
+    pub fn new_del(table: impl Into<String>, key: impl AsRef<[u8]>) -> Self {
+        Self {
+            table: table.into(),
+            key: to_key(key),
+            op: Some(wal_record::Op::Del(true)),
+        }
+    }
+
+    /// deadline 是 key 过期时刻的毫秒时间戳
+    pub fn new_expire(table: impl Into<String>, key: impl AsRef<[u8]>, deadline: u64) -> Self {
+        Self {
+            table: table.into(),
+            key: to_key(key),
+            op: Some(wal_record::Op::Expire(deadline)),
+        }
+    }
+
+    pub fn new_zadd(
+        table: impl Into<String>,
+        key: impl AsRef<[u8]>,
+        members: Vec<ScoredMember>,
+    ) -> Self {
+        Self {
+            table: table.into(),
+            key: to_key(key),
+            op: Some(wal_record::Op::Zadd(ScoredMembers { members })),
+        }
+    }
+
+    pub fn new_create_index(table: impl Into<String>, spec: IndexSpec) -> Self {
+        Self {
+            table: table.into(),
+            op: Some(wal_record::Op::CreateIndex(spec)),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_truncate_table(table: impl Into<String>) -> Self {
+        Self {
+            table: table.into(),
+            op: Some(wal_record::Op::TruncateTable(true)),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_drop_table(table: impl Into<String>) -> Self {
+        Self {
+            table: table.into(),
+            op: Some(wal_record::Op::DropTable(true)),
+            ..Default::default()
+        }
+    }
+
+    /// 一组需要原子生效的记录
+    pub fn new_batch(records: Vec<WalRecord>) -> Self {
+        Self {
+            op: Some(wal_record::Op::Batch(WalBatch { records })),
+            ..Default::default()
+        }
+    }
+}
+
+impl<K: AsRef<[u8]>> From<(K, f64)> for ScoredMember {
+    fn from((member, score): (K, f64)) -> Self {
+        Self {
+            member: to_key(member),
+            score,
+        }
+    }
+}
+
+/// ZRANGE 的结果中 key 是 member，value 是 score
+impl From<ScoredMember> for Kvpair {
+    fn from(value: ScoredMember) -> Self {
+        Kvpair::new(value.member, value.score.into())
+    }
+}
+
+/// key 可以是任意的字节，字符串、`Vec<u8>` 和 `Bytes` 都可以直接传进来
+pub fn to_key(key: impl AsRef<[u8]>) -> Bytes {
+    Bytes::copy_from_slice(key.as_ref())
+}
+
 impl From<RequestData> for CommandRequest {
     fn from(value: RequestData) -> Self {
         Self {
             request_data: Some(value),
+            ..Default::default()
         }
     }
 }
@@ -142,13 +1239,33 @@ impl From<KvError> for CommandResponse {
         let mut result = Self {
             status: StatusCode::INTERNAL_SERVER_ERROR.as_u16() as _,
             message: value.to_string(),
-            values: vec![],
-            pairs: vec![],
+            ..Default::default()
         };
 
         match value {
             KvError::NotFound(_) => result.status = StatusCode::NOT_FOUND.as_u16() as _,
             KvError::InvalidCommand(_) => result.status = StatusCode::BAD_REQUEST.as_u16() as _,
+            KvError::Unauthorized(_) => result.status = StatusCode::UNAUTHORIZED.as_u16() as _,
+            KvError::PermissionDenied(_) => result.status = StatusCode::FORBIDDEN.as_u16() as _,
+            KvError::Conflict(_) => result.status = StatusCode::CONFLICT.as_u16() as _,
+            KvError::Timeout(_) => result.status = StatusCode::GATEWAY_TIMEOUT.as_u16() as _,
+            KvError::NotLeader(_) => result.status = StatusCode::MISDIRECTED_REQUEST.as_u16() as _,
+            // 和 redis 的 MOVED 一样带上 leader 的地址，客户端直接重定向过去
+            KvError::Moved(_, addr) => {
+                result.status = StatusCode::MISDIRECTED_REQUEST.as_u16() as _;
+                result.values = vec![addr.into()];
+            }
+            KvError::RateLimited(_) => result.status = StatusCode::TOO_MANY_REQUESTS.as_u16() as _,
+            KvError::TooManyConnections(_) => {
+                result.status = StatusCode::SERVICE_UNAVAILABLE.as_u16() as _
+            }
+            KvError::OutOfMemory(_) | KvError::QuotaExceeded(_) => {
+                result.status = StatusCode::INSUFFICIENT_STORAGE.as_u16() as _
+            }
+            KvError::Compacted(..) => result.status = StatusCode::GONE.as_u16() as _,
+            KvError::FrameTooLarge(..) => {
+                result.status = StatusCode::PAYLOAD_TOO_LARGE.as_u16() as _
+            }
             _ => {}
         }
 
@@ -156,8 +1273,8 @@ impl From<KvError> for CommandResponse {
     }
 }
 
-impl From<(String, Value)> for Kvpair {
-    fn from(kv: (String, Value)) -> Self {
+impl From<(Bytes, Value)> for Kvpair {
+    fn from(kv: (Bytes, Value)) -> Self {
         Kvpair {
             key: kv.0,
             value: Some(kv.1),
@@ -165,6 +1282,36 @@ impl From<(String, Value)> for Kvpair {
     }
 }
 
+impl From<Vec<CommandResponse>> for CommandResponse {
+    fn from(results: Vec<CommandResponse>) -> Self {
+        Self {
+            status: StatusCode::OK.as_u16() as _,
+            results,
+            ..Default::default()
+        }
+    }
+}
+
+impl From<Vec<WalRecord>> for CommandResponse {
+    fn from(records: Vec<WalRecord>) -> Self {
+        Self {
+            status: StatusCode::OK.as_u16() as _,
+            records,
+            ..Default::default()
+        }
+    }
+}
+
+impl From<Vec<KeyEvent>> for CommandResponse {
+    fn from(events: Vec<KeyEvent>) -> Self {
+        Self {
+            status: StatusCode::OK.as_u16() as _,
+            events,
+            ..Default::default()
+        }
+    }
+}
+
 impl From<Vec<Kvpair>> for CommandResponse {
     fn from(value: Vec<Kvpair>) -> Self {
         Self {
@@ -193,6 +1340,58 @@ impl From<i64> for Value {
     }
 }
 
+impl Value {
+    /// 把 value 当成整数：Integer 直接返回，String 和 Binary 尝试按十进制解析
+    pub fn to_integer(&self) -> Option<i64> {
+        match &self.value {
+            Some(value::Value::Integer(i)) => Some(*i),
+            Some(value::Value::String(s)) => s.parse().ok(),
+            Some(value::Value::Binary(b)) => std::str::from_utf8(b).ok()?.parse().ok(),
+            _ => None,
+        }
+    }
+}
+
+impl From<f64> for Value {
+    fn from(value: f64) -> Self {
+        Self {
+            value: Some(value::Value::Float(value)),
+        }
+    }
+}
+
+impl From<serde_json::Value> for Value {
+    fn from(value: serde_json::Value) -> Self {
+        Self {
+            value: Some(value::Value::Json(value.to_string())),
+        }
+    }
+}
+
+impl From<ValueList> for Value {
+    fn from(value: ValueList) -> Self {
+        Self {
+            value: Some(value::Value::List(value)),
+        }
+    }
+}
+
+impl From<ValueSet> for Value {
+    fn from(value: ValueSet) -> Self {
+        Self {
+            value: Some(value::Value::Set(value)),
+        }
+    }
+}
+
+impl From<ValueMap> for Value {
+    fn from(value: ValueMap) -> Self {
+        Self {
+            value: Some(value::Value::Map(value)),
+        }
+    }
+}
+
 impl TryFrom<&Value> for i64 {
     type Error = KvError;
 
@@ -204,6 +1403,14 @@ impl TryFrom<&Value> for i64 {
     }
 }
 
+impl From<bool> for Value {
+    fn from(value: bool) -> Self {
+        Self {
+            value: Some(value::Value::Bool(value)),
+        }
+    }
+}
+
 impl From<String> for Value {
     fn from(value: String) -> Self {
         Self {
@@ -229,6 +1436,16 @@ impl From<&[u8]> for Value {
     }
 }
 
+impl From<&Bytes> for Value {
+    /// 合法的 utf8 当成 String，否则当成 Binary
+    fn from(value: &Bytes) -> Self {
+        match std::str::from_utf8(value) {
+            Ok(s) => s.into(),
+            Err(_) => value.clone().into(),
+        }
+    }
+}
+
 impl From<Bytes> for Value {
     fn from(value: Bytes) -> Self {
         Self {
@@ -253,7 +1470,7 @@ impl TryFrom<&CommandResponse> for i64 {
                 "CommandResponse",
             ));
         }
-        match value.values.get(0) {
+        match value.values.first() {
             Some(v) => v.try_into(),
             None => Err(KvError::ConvertError(
                 value.values[0].clone(),
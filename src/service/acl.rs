@@ -0,0 +1,277 @@
+use std::collections::HashMap;
+
+use super::lease::LEASE_TABLE;
+use crate::{
+    config::{AclConfig, RoleConfig, Verb},
+    error::KvError,
+    pb::abi::command_request::RequestData,
+};
+
+/// 一个命令需要的权限；table 为 None 时表示这个命令不针对某个 table
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Access<'a> {
+    pub verb: Verb,
+    pub table: Option<&'a str>,
+}
+
+impl<'a> Access<'a> {
+    fn read(table: &'a str) -> Self {
+        Self {
+            verb: Verb::Read,
+            table: Some(table),
+        }
+    }
+
+    fn write(table: &'a str) -> Self {
+        Self {
+            verb: Verb::Write,
+            table: Some(table),
+        }
+    }
+
+    fn global(verb: Verb) -> Self {
+        Self { verb, table: None }
+    }
+}
+
+/// 计算执行一个命令需要哪些权限
+pub fn required_access(cmd: &RequestData) -> Vec<Access<'_>> {
+    match cmd {
+        RequestData::Hget(v) => vec![Access::read(&v.table)],
+        RequestData::Hgetall(v) => vec![Access::read(&v.table)],
+        RequestData::Hmget(v) => vec![Access::read(&v.table)],
+        RequestData::Hexist(v) => vec![Access::read(&v.table)],
+        RequestData::Hmexist(v) => vec![Access::read(&v.table)],
+        RequestData::Httl(v) => vec![Access::read(&v.table)],
+        RequestData::Hstrlen(v) => vec![Access::read(&v.table)],
+        RequestData::Hgetrange(v) => vec![Access::read(&v.table)],
+        RequestData::Hgetbit(v) => vec![Access::read(&v.table)],
+        RequestData::Hbitcount(v) => vec![Access::read(&v.table)],
+        RequestData::Hpfcount(v) => vec![Access::read(&v.table)],
+        RequestData::Hscan(v) => vec![Access::read(&v.table)],
+        RequestData::Hrange(v) => vec![Access::read(&v.table)],
+        RequestData::Hscanprefix(v) => vec![Access::read(&v.table)],
+        RequestData::Lrange(v) => vec![Access::read(&v.table)],
+        RequestData::Smembers(v) => vec![Access::read(&v.table)],
+        RequestData::MapGet(v) => vec![Access::read(&v.table)],
+        RequestData::Zrange(v) => vec![Access::read(&v.table)],
+        RequestData::Zrangebyscore(v) => vec![Access::read(&v.table)],
+        RequestData::Hjsonget(v) => vec![Access::read(&v.table)],
+        RequestData::Query(v) => vec![Access::read(&v.table)],
+        RequestData::Hset(v) if v.lease != 0 => {
+            vec![Access::write(&v.table), Access::write(LEASE_TABLE)]
+        }
+        RequestData::Hset(v) => vec![Access::write(&v.table)],
+        RequestData::Hcas(v) => vec![Access::write(&v.table)],
+        RequestData::Hincrby(v) => vec![Access::write(&v.table)],
+        RequestData::Happend(v) => vec![Access::write(&v.table)],
+        RequestData::Hsetbit(v) => vec![Access::write(&v.table)],
+        RequestData::Hpfadd(v) => vec![Access::write(&v.table)],
+        RequestData::Eval(v) => vec![Access::write(&v.table)],
+        RequestData::Evalsha(v) => vec![Access::write(&v.table)],
+        RequestData::Fcall(v) => vec![Access::write(&v.table)],
+        RequestData::Hmset(v) => vec![Access::write(&v.table)],
+        RequestData::Hdel(v) => vec![Access::write(&v.table)],
+        RequestData::Hmdel(v) => vec![Access::write(&v.table)],
+        RequestData::Hexpire(v) => vec![Access::write(&v.table)],
+        RequestData::Lpush(v) => vec![Access::write(&v.table)],
+        RequestData::Sadd(v) => vec![Access::write(&v.table)],
+        RequestData::MapSet(v) => vec![Access::write(&v.table)],
+        RequestData::Zadd(v) => vec![Access::write(&v.table)],
+        RequestData::Hjsonset(v) => vec![Access::write(&v.table)],
+        RequestData::Lock(v) => vec![Access::write(&v.table)],
+        RequestData::Unlock(v) => vec![Access::write(&v.table)],
+        RequestData::LeaseGrant(_)
+        | RequestData::LeaseKeepAlive(_)
+        | RequestData::LeaseRevoke(_) => vec![Access::write(LEASE_TABLE)],
+        RequestData::CreateIndex(v) => vec![Access::write(&v.table)],
+        RequestData::Tdrop(v) => vec![Access::write(&v.table)],
+        RequestData::Ttruncate(v) => vec![Access::write(&v.table)],
+        RequestData::Tlist(_) | RequestData::Dbsize(_) => vec![Access::global(Verb::Read)],
+        RequestData::Hstats(v) => match v.table.is_empty() {
+            true => vec![Access::global(Verb::Read)],
+            false => vec![Access::read(&v.table)],
+        },
+        RequestData::Quota(v) => vec![Access::read(&v.table)],
+        RequestData::Watch(v) => vec![Access::read(&v.table)],
+        RequestData::Hwatch(v) => vec![Access::read(&v.table)],
+        RequestData::Subscribe(_) | RequestData::Unsubscribe(_) | RequestData::ClusterSlots(_) => {
+            vec![Access::global(Verb::Read)]
+        }
+        // 不知道自定义命令会访问哪些 table，需要全局的写权限
+        RequestData::Publish(_) | RequestData::Custom(_) => vec![Access::global(Verb::Write)],
+        RequestData::Snapshot(_)
+        | RequestData::Info(_)
+        | RequestData::SlowlogGet(_)
+        | RequestData::ClientList(_)
+        | RequestData::ClientKill(_)
+        | RequestData::Replicate(_)
+        | RequestData::ConfigReload(_)
+        | RequestData::ConfigGet(_)
+        | RequestData::ConfigSet(_)
+        | RequestData::RaftVote(_)
+        | RequestData::RaftAppend(_)
+        | RequestData::Gossip(_)
+        | RequestData::Members(_)
+        | RequestData::Compact(_)
+        | RequestData::Hcompact(_) => {
+            vec![Access::global(Verb::Admin)]
+        }
+        RequestData::Batch(v) => v
+            .commands
+            .iter()
+            .filter_map(|cmd| cmd.request_data.as_ref())
+            .flat_map(required_access)
+            .collect(),
+        // 排队的命令在排队时单独检查
+        RequestData::Auth(_)
+        | RequestData::Multi(_)
+        | RequestData::Exec(_)
+        | RequestData::Discard(_)
+        | RequestData::Unwatch(_)
+        | RequestData::Hunwatch(_)
+        | RequestData::Ping(_)
+        | RequestData::Select(_)
+        | RequestData::SessionSet(_)
+        | RequestData::Hello(_) => vec![],
+    }
+}
+
+/// 基于角色的访问控制
+#[derive(Debug, Default)]
+pub struct Acl {
+    roles: HashMap<String, RoleConfig>,
+    users: HashMap<String, Vec<String>>,
+}
+
+impl Acl {
+    /// user 为 None 时按 default 用户检查；租户的用户按去掉 namespace 前缀之后的 table 名检查
+    pub fn check(
+        &self,
+        user: Option<&str>,
+        namespace: Option<&str>,
+        cmd: &RequestData,
+    ) -> Result<(), KvError> {
+        let user = user.unwrap_or("default");
+        let roles: Vec<&RoleConfig> = self
+            .users
+            .get(user)
+            .map(|names| names.iter().filter_map(|n| self.roles.get(n)).collect())
+            .unwrap_or_default();
+
+        for access in required_access(cmd) {
+            let table = access
+                .table
+                .map(|t| namespace.and_then(|ns| t.strip_prefix(ns)).unwrap_or(t));
+            let access = Access { table, ..access };
+            if !roles.iter().any(|role| role_allows(role, &access)) {
+                return Err(KvError::PermissionDenied(match access.table {
+                    Some(table) => format!("{} cannot {:?} table {}", user, access.verb, table),
+                    None => format!("{} cannot {:?}", user, access.verb),
+                }));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl From<AclConfig> for Acl {
+    fn from(config: AclConfig) -> Self {
+        Self {
+            roles: config
+                .roles
+                .into_iter()
+                .map(|r| (r.name.clone(), r))
+                .collect(),
+            users: config.users,
+        }
+    }
+}
+
+fn role_allows(role: &RoleConfig, access: &Access) -> bool {
+    if !role.verbs.contains(&access.verb) {
+        return false;
+    }
+    match access.table {
+        Some(table) => role
+            .tables
+            .iter()
+            .any(|pattern| pattern_matches(pattern, table)),
+        None => true,
+    }
+}
+
+/// 支持 `*` 和 `prefix*` 的写法
+pub(crate) fn pattern_matches(pattern: &str, name: &str) -> bool {
+    match pattern.strip_suffix('*') {
+        Some(prefix) => name.starts_with(prefix),
+        None => pattern == name,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pb::abi::CommandRequest;
+
+    fn acl() -> Acl {
+        let config: AclConfig = toml::from_str(
+            r#"
+            [[roles]]
+            name = "reader"
+            tables = ["*"]
+            verbs = ["read"]
+
+            [[roles]]
+            name = "logs-writer"
+            tables = ["logs:*"]
+            verbs = ["read", "write"]
+
+            [users]
+            default = ["reader"]
+            alice = ["reader", "logs-writer"]
+            "#,
+        )
+        .unwrap();
+        config.into()
+    }
+
+    fn check(acl: &Acl, user: Option<&str>, cmd: CommandRequest) -> Result<(), KvError> {
+        acl.check(user, None, cmd.request_data.as_ref().unwrap())
+    }
+
+    #[test]
+    fn acl_should_allow_by_role() {
+        let acl = acl();
+        assert!(check(&acl, None, CommandRequest::new_hget("t1", "k1")).is_ok());
+        assert!(check(&acl, None, CommandRequest::new_hset("t1", "k1", "v1")).is_err());
+        assert!(check(
+            &acl,
+            Some("alice"),
+            CommandRequest::new_hset("logs:a", "k", "v")
+        )
+        .is_ok());
+        assert!(check(
+            &acl,
+            Some("alice"),
+            CommandRequest::new_hset("t1", "k", "v")
+        )
+        .is_err());
+        // 不在 users 里的用户什么都不能做
+        assert!(check(&acl, Some("bob"), CommandRequest::new_hget("t1", "k1")).is_err());
+    }
+
+    #[test]
+    fn acl_should_check_every_command_in_batch() {
+        let acl = acl();
+        let cmd = CommandRequest::new_batch([
+            CommandRequest::new_hset("logs:a", "k", "v"),
+            CommandRequest::new_hdel("t1", "k"),
+        ]);
+        let err = check(&acl, Some("alice"), cmd).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Permission denied: alice cannot Write table t1"
+        );
+    }
+}
@@ -0,0 +1,193 @@
+use std::{
+    collections::HashSet,
+    fs::{File, OpenOptions},
+    io::Write,
+    sync::{Arc, Mutex},
+};
+
+use hyper::StatusCode;
+use serde_json::json;
+use tracing::warn;
+
+use super::{
+    replication::written_keys,
+    session::Session,
+    slowlog::command_name,
+    topic::{BroadCaster, Topic},
+};
+use crate::{
+    config::AuditConfig,
+    error::KvError,
+    pb::abi::{command_request::RequestData, CommandResponse, Value},
+    storage::now_millis,
+};
+
+/// 修改数据的审计日志，写到文件或者发布到 pub/sub；没有配置时什么都不做
+#[derive(Debug, Default)]
+pub struct AuditLog {
+    file: Option<Mutex<File>>,
+    topic: Option<String>,
+    /// 为空时记录所有的 table
+    tables: HashSet<String>,
+}
+
+impl AuditLog {
+    /// 以追加的方式打开配置的文件
+    pub fn open(config: &AuditConfig) -> Result<Self, KvError> {
+        let file = match &config.path {
+            Some(path) => {
+                let file = OpenOptions::new().create(true).append(true).open(path)?;
+                Some(Mutex::new(file))
+            }
+            None => None,
+        };
+        Ok(Self {
+            file,
+            topic: config.topic.clone(),
+            tables: config.tables.iter().cloned().collect(),
+        })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.file.is_some() || self.topic.is_some()
+    }
+
+    /// 命令执行成功之后调用，每个被修改的 key 记录一条；BATCH 中的命令按各自的结果分别记录
+    pub fn record(
+        &self,
+        cmd: &RequestData,
+        res: &CommandResponse,
+        session: &Session,
+        broadcaster: &Arc<BroadCaster>,
+    ) {
+        if !self.is_enabled() {
+            return;
+        }
+        if let RequestData::Batch(batch) = cmd {
+            batch
+                .commands
+                .iter()
+                .zip(&res.results)
+                .filter_map(|(cmd, res)| Some((cmd.request_data.as_ref()?, res)))
+                .for_each(|(cmd, res)| self.record(cmd, res, session, broadcaster));
+            return;
+        }
+        if res.status != StatusCode::OK.as_u16() as u32 {
+            return;
+        }
+        // HCAS 没有替换时也返回 200
+        if matches!(cmd, RequestData::Hcas(_)) && res.values.first() != Some(&true.into()) {
+            return;
+        }
+        let timestamp = now_millis();
+        let command = command_name(cmd);
+        for (table, key) in modified_keys(cmd) {
+            if !self.tables.is_empty() && !self.tables.contains(table) {
+                continue;
+            }
+            let entry = json!({
+                "timestamp": timestamp,
+                "user": session.user(),
+                "peer": session.peer(),
+                "command": command,
+                "table": table,
+                "key": String::from_utf8_lossy(key),
+            });
+            self.write(entry, broadcaster);
+        }
+    }
+
+    fn write(&self, entry: serde_json::Value, broadcaster: &Arc<BroadCaster>) {
+        if let Some(file) = &self.file {
+            let mut file = file.lock().unwrap_or_else(|e| e.into_inner());
+            if let Err(e) = writeln!(file, "{}", entry) {
+                warn!("Failed to write audit log: {}", e);
+            }
+        }
+        if let Some(topic) = &self.topic {
+            let data = Arc::new(Value::from(entry).into());
+            broadcaster.clone().publish(topic, data);
+        }
+    }
+}
+
+/// 命令修改了哪些 key；修改整个 table 的命令 key 为空
+fn modified_keys(cmd: &RequestData) -> Vec<(&str, &[u8])> {
+    match cmd {
+        RequestData::Zadd(v) => vec![(&v.table, &v.key[..])],
+        RequestData::CreateIndex(v) => vec![(&v.table, &[])],
+        RequestData::Ttruncate(v) => vec![(&v.table, &[])],
+        RequestData::Tdrop(v) => vec![(&v.table, &[])],
+        _ => written_keys(cmd),
+    }
+}
+
+#[cfg(test)]
+mod audit_tests {
+    use std::fs;
+
+    use futures::StreamExt;
+
+    use super::*;
+    use crate::{pb::abi::CommandRequest, service_builder::ServiceBuilder, Service};
+
+    #[tokio::test]
+    async fn audit_log_should_record_mutations() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("audit.log");
+        let config = AuditConfig {
+            path: Some(path.to_string_lossy().into()),
+            topic: Some("audit".into()),
+            tables: vec!["t1".into()],
+        };
+        let service: Service = ServiceBuilder::default()
+            .audit(AuditLog::open(&config).unwrap())
+            .finish();
+        let mut rx = service.broadcaster.clone().subscript("audit");
+        let session = Session::authenticated("alice").with_peer("127.0.0.1:4000");
+        for cmd in [
+            CommandRequest::new_hset("t1", "k1", "v1"),
+            // 读命令、其它 table 和执行失败的命令都不记录
+            CommandRequest::new_hget("t1", "k1"),
+            CommandRequest::new_hset("t2", "k1", "v1"),
+            CommandRequest::new_hcas("t1", "k1", Some("v0".into()), "v2"),
+            CommandRequest::new_batch([
+                CommandRequest::new_hdel("t1", "k1"),
+                CommandRequest::new_hset("t2", "k2", "v2"),
+            ]),
+            // 事务中的修改也要记录
+            CommandRequest::new_multi(),
+            CommandRequest::new_hset("t1", "k3", "v3"),
+            CommandRequest::new_exec(),
+        ] {
+            service.execute_with(cmd, &session).next().await.unwrap();
+        }
+
+        let entries: Vec<serde_json::Value> = fs::read_to_string(&path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries[0]["user"], "alice");
+        assert_eq!(entries[0]["peer"], "127.0.0.1:4000");
+        assert_eq!(entries[0]["command"], "HSET");
+        assert_eq!(entries[0]["table"], "t1");
+        assert_eq!(entries[0]["key"], "k1");
+        assert!(entries[0]["timestamp"].as_u64().unwrap() > 0);
+        assert_eq!(entries[1]["command"], "HDEL");
+        assert_eq!(entries[2]["command"], "HSET");
+        assert_eq!(entries[2]["key"], "k3");
+
+        // 第一个消息是订阅 id
+        rx.recv().await.unwrap();
+        let res = rx.recv().await.unwrap();
+        assert_eq!(res.values[0], Value::from(entries[0].clone()));
+    }
+
+    #[test]
+    fn audit_log_should_be_disabled_by_default() {
+        let audit = AuditLog::open(&AuditConfig::default()).unwrap();
+        assert!(!audit.is_enabled());
+    }
+}
@@ -0,0 +1,790 @@
+//! CDC（change data capture）：每个提交的修改按顺序追加到本地的日志，后台任务再把日志投递到外部的 sink。
+//!
+//! sink 确认之后才推进持久化的游标，投递失败或者重启之后从游标继续，所以每个修改至少投递一次。
+//! 记录的是 key 修改之后的状态而不是命令本身，重复投递是幂等的
+
+use std::{
+    collections::HashSet,
+    fmt::Write as _,
+    fs::{self, File, OpenOptions},
+    io::{Read, Seek, SeekFrom, Write},
+    path::PathBuf,
+    sync::{mpsc, Arc, Mutex, MutexGuard},
+    thread,
+    time::{Duration, Instant},
+};
+
+use prost::Message;
+use serde_json::json;
+use tokio::{sync::Notify, task::JoinHandle, time};
+use tracing::warn;
+
+use super::Service;
+use crate::{
+    config::{CdcConfig, CdcSinkConfig, FsyncPolicy},
+    error::KvError,
+    http::to_json,
+    pb::abi::{
+        command_request::RequestData, wal_record::Op, CommandRequest, CommandResponse, CreateIndex,
+        WalRecord, Zadd,
+    },
+    reconnect::ReconnectingClient,
+    storage::now_millis,
+    Storage,
+};
+
+/// 还没有投递的修改
+const LOG_FILE: &str = "cdc.log";
+/// 已经投递的修改在日志中的位置
+const CURSOR_FILE: &str = "cdc.cursor";
+
+/// 每次最多投递多少个命令的修改
+const SHIP_BATCH_SIZE: usize = 256;
+
+/// 每次从日志中读取的字节数，单个命令的修改超过这个大小时按需要读取更多
+const READ_CHUNK_SIZE: u64 = 4 * 1024 * 1024;
+
+/// 投递失败之后，重试之前等待的时间
+const RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// 没有新的修改时，最长等待多久检查一次 Service 是否已经 drop
+const IDLE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// EverySec 策略下两次 fsync 之间的间隔
+const FSYNC_INTERVAL: Duration = Duration::from_secs(1);
+
+/// varint 编码的长度最多占 10 个字节
+const MAX_VARINT_LEN: usize = 10;
+
+/// 写入日志并投递修改；日志中每个命令的修改是一个 length delimited 的 batch WalRecord
+#[derive(Debug)]
+pub struct Cdc {
+    dir: PathBuf,
+    log: Arc<Mutex<LogWriter>>,
+    /// 交给写日志的线程，按发送的顺序写入
+    writer: mpsc::Sender<Append>,
+    sink: CdcSinkConfig,
+    /// 为空时投递所有的 table
+    tables: HashSet<String>,
+    /// 有新的修改写入日志
+    appended: Arc<Notify>,
+}
+
+/// 等待写入日志的一个命令的修改
+#[derive(Debug)]
+struct Append {
+    data: Vec<u8>,
+    done: mpsc::SyncSender<Result<(), String>>,
+}
+
+/// Cdc::append 返回，等待修改写入日志
+#[must_use]
+pub(super) struct Appended(Option<mpsc::Receiver<Result<(), String>>>);
+
+impl Appended {
+    /// 写入日志并按 fsync 策略刷盘之后返回
+    pub(super) fn wait(self) -> Result<(), KvError> {
+        let Some(done) = self.0 else {
+            return Ok(());
+        };
+        match done.recv() {
+            Ok(result) => result.map_err(KvError::Internal),
+            Err(_) => Err(KvError::Internal("CDC log writer has stopped".into())),
+        }
+    }
+}
+
+impl Cdc {
+    /// 打开 dir 下的日志，上次没有投递完的修改会在启动投递任务之后继续投递
+    pub fn open(config: &CdcConfig) -> Result<Self, KvError> {
+        let dir = PathBuf::from(&config.dir);
+        fs::create_dir_all(&dir)?;
+        let log = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(dir.join(LOG_FILE))?;
+        let log = Arc::new(Mutex::new(LogWriter {
+            file: log,
+            fsync: config.fsync,
+            last_sync: Instant::now(),
+            dirty: false,
+        }));
+        let appended = Arc::new(Notify::new());
+        let (writer, rx) = mpsc::channel();
+        let cdc = Self {
+            dir,
+            log: log.clone(),
+            writer,
+            sink: config.sink.clone(),
+            tables: config.tables.iter().cloned().collect(),
+            appended: appended.clone(),
+        };
+        cdc.recover()?;
+        // Cdc 被丢弃之后 channel 关闭，线程退出
+        thread::spawn(move || run_writer(&log, rx, &appended));
+        Ok(cdc)
+    }
+
+    fn lock(&self) -> MutexGuard<'_, LogWriter> {
+        lock(&self.log)
+    }
+
+    /// 崩溃时日志末尾可能留下写了一半的记录，之后追加的记录接在它后面就都读不出来了，
+    /// 所以打开时截断到最后一个完整的记录
+    fn recover(&self) -> Result<(), KvError> {
+        let len = fs::metadata(self.dir.join(LOG_FILE))?.len();
+        let mut offset = self.cursor()?;
+        if offset > len {
+            offset = 0;
+        }
+        loop {
+            let (_, next) = self.read_log(offset)?;
+            if next == offset {
+                break;
+            }
+            offset = next;
+        }
+        if offset < len {
+            warn!(
+                "Truncated incomplete CDC record at offset {} ({} bytes)",
+                offset,
+                len - offset
+            );
+            self.lock().file.set_len(offset)?;
+        }
+        Ok(())
+    }
+
+    /// 把一个命令的修改交给写日志的线程；在 Replicator 的锁里调用，日志中的顺序就是修改提交的顺序。
+    /// 写入和 fsync 在线程中进行，释放锁之后再调用 Appended::wait 等待结果
+    pub(super) fn append(&self, records: &[WalRecord]) -> Appended {
+        let records: Vec<_> = records
+            .iter()
+            .filter(|r| self.tables.is_empty() || self.tables.contains(&r.table))
+            .cloned()
+            .collect();
+        if records.is_empty() {
+            return Appended(None);
+        }
+        let data = WalRecord::new_batch(records).encode_length_delimited_to_vec();
+        let (done, rx) = mpsc::sync_channel(1);
+        // 线程只在 channel 关闭之后退出，发送不会失败；万一失败 wait 会返回错误
+        let _ = self.writer.send(Append { data, done });
+        Appended(Some(rx))
+    }
+
+    /// EverySec 策略下把距离上次 fsync 超过一秒、还没有 fsync 的修改刷到磁盘
+    fn flush(&self) -> Result<(), KvError> {
+        let mut log = self.lock();
+        if log.dirty && log.last_sync.elapsed() >= FSYNC_INTERVAL {
+            log.sync()?;
+        }
+        Ok(())
+    }
+
+    /// 已经投递的修改在日志中的位置，没有游标文件时为 0
+    fn cursor(&self) -> Result<u64, KvError> {
+        match fs::read_to_string(self.dir.join(CURSOR_FILE)) {
+            Ok(s) => s
+                .trim()
+                .parse()
+                .map_err(|_| KvError::Internal(format!("Invalid CDC cursor: {}", s))),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(0),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// 先写临时文件再改名，崩溃时游标要么是旧的要么是新的
+    fn save_cursor(&self, cursor: u64) -> Result<(), KvError> {
+        let tmp = self.dir.join(format!("{}.tmp", CURSOR_FILE));
+        let mut file = File::create(&tmp)?;
+        file.write_all(cursor.to_string().as_bytes())?;
+        file.sync_all()?;
+        fs::rename(tmp, self.dir.join(CURSOR_FILE))?;
+        Ok(())
+    }
+
+    /// 从 offset 开始读出最多 SHIP_BATCH_SIZE 个命令的修改，返回修改和读到的位置；
+    /// 写到一半的记录留到下次读取，损坏的记录跳过
+    fn read_log(&self, offset: u64) -> Result<(Vec<WalRecord>, u64), KvError> {
+        let mut limit = READ_CHUNK_SIZE;
+        loop {
+            let mut file = File::open(self.dir.join(LOG_FILE))?;
+            file.seek(SeekFrom::Start(offset))?;
+            let mut data = Vec::new();
+            file.take(limit).read_to_end(&mut data)?;
+
+            let mut buf = &data[..];
+            let mut batches = Vec::new();
+            while batches.len() < SHIP_BATCH_SIZE && !buf.is_empty() {
+                match next_entry(buf)? {
+                    Entry::Batch(batch, size) => {
+                        batches.push(batch);
+                        buf = &buf[size..];
+                    }
+                    Entry::Corrupt(size) => {
+                        let at = offset + (data.len() - buf.len()) as u64;
+                        warn!(
+                            "Skipped corrupt CDC record at offset {} ({} bytes)",
+                            at, size
+                        );
+                        buf = &buf[size..];
+                    }
+                    Entry::Incomplete => break,
+                }
+            }
+            let read = (data.len() - buf.len()) as u64;
+            if read == 0 && data.len() as u64 == limit {
+                limit *= 2;
+                continue;
+            }
+            return Ok((batches, offset + read));
+        }
+    }
+
+    /// 所有的修改都投递完之后清空日志；先把游标写成 0，崩溃时最多重复投递
+    fn truncate(&self, cursor: u64) -> Result<(), KvError> {
+        let log = self.lock();
+        if cursor == 0 || log.file.metadata()?.len() != cursor {
+            return Ok(());
+        }
+        self.save_cursor(0)?;
+        log.file.set_len(0)?;
+        Ok(())
+    }
+
+    /// 投递一批修改，返回日志中是否可能还有没有投递的修改
+    async fn ship(&self, sink: &mut Option<Sink>) -> Result<bool, KvError> {
+        let mut cursor = self.cursor()?;
+        let len = fs::metadata(self.dir.join(LOG_FILE))?.len();
+        if cursor > len {
+            warn!("CDC cursor {} is beyond the log ({} bytes)", cursor, len);
+            cursor = 0;
+        }
+        let (batches, next) = self.read_log(cursor)?;
+        if batches.is_empty() && next > cursor {
+            // 读到的都是损坏的记录
+            self.save_cursor(next)?;
+            return Ok(true);
+        }
+        if batches.is_empty() {
+            self.truncate(cursor)?;
+            return Ok(false);
+        }
+        let sink = match sink {
+            Some(sink) => sink,
+            None => sink.insert(Sink::open(&self.sink).await?),
+        };
+        sink.deliver(&batches).await?;
+        self.save_cursor(next)?;
+        Ok(true)
+    }
+}
+
+impl<Store: Storage> Service<Store> {
+    /// 启动后台任务，把 CDC 日志中的修改投递到 sink；没有配置 CDC 时返回 None。
+    /// Service 全部 drop 之后任务自动退出
+    pub fn spawn_cdc_task(&self) -> Option<JoinHandle<()>> {
+        let cdc = Arc::downgrade(self.cdc.as_ref()?);
+        Some(tokio::spawn(async move {
+            let mut sink = None;
+            while let Some(cdc) = cdc.upgrade() {
+                if let Err(e) = cdc.flush() {
+                    warn!("Failed to sync CDC log: {:?}", e);
+                }
+                match cdc.ship(&mut sink).await {
+                    Ok(true) => {}
+                    Ok(false) => {
+                        let _ = time::timeout(IDLE_INTERVAL, cdc.appended.notified()).await;
+                    }
+                    Err(e) => {
+                        warn!("Failed to ship CDC records: {:?}", e);
+                        sink = None;
+                        time::sleep(RETRY_INTERVAL).await;
+                    }
+                }
+            }
+        }))
+    }
+}
+
+fn lock(log: &Mutex<LogWriter>) -> MutexGuard<'_, LogWriter> {
+    log.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// 写日志的线程：一次写入已经在排队的所有修改，共用一次 fsync
+fn run_writer(log: &Mutex<LogWriter>, rx: mpsc::Receiver<Append>, appended: &Notify) {
+    while let Ok(first) = rx.recv() {
+        let mut pending = vec![first];
+        pending.extend(rx.try_iter());
+        let data = pending
+            .iter()
+            .flat_map(|a| &a.data)
+            .copied()
+            .collect::<Vec<_>>();
+        let result = lock(log).append(&data).map_err(|e| e.to_string());
+        if result.is_ok() {
+            appended.notify_one();
+        }
+        for append in pending {
+            let _ = append.done.send(result.clone());
+        }
+    }
+}
+
+#[derive(Debug)]
+struct LogWriter {
+    file: File,
+    fsync: FsyncPolicy,
+    last_sync: Instant,
+    /// 有写入之后还没有 fsync 的数据
+    dirty: bool,
+}
+
+impl LogWriter {
+    /// 写入或者 fsync 失败时截断到写入之前的长度，不在日志中留下写了一半的记录
+    fn append(&mut self, data: &[u8]) -> Result<(), KvError> {
+        let len = self.file.metadata()?.len();
+        let result = self
+            .file
+            .write_all(data)
+            .map_err(KvError::from)
+            .and_then(|_| {
+                self.dirty = true;
+                match self.fsync {
+                    FsyncPolicy::Always => self.sync(),
+                    FsyncPolicy::EverySec if self.last_sync.elapsed() >= FSYNC_INTERVAL => {
+                        self.sync()
+                    }
+                    _ => Ok(()),
+                }
+            });
+        if let Err(e) = &result {
+            warn!("Failed to write CDC log: {:?}", e);
+            if let Err(e) = self.file.set_len(len) {
+                warn!("Failed to truncate CDC log to {} bytes: {}", len, e);
+            }
+        }
+        result
+    }
+
+    fn sync(&mut self) -> Result<(), KvError> {
+        self.file.sync_data()?;
+        self.last_sync = Instant::now();
+        self.dirty = false;
+        Ok(())
+    }
+}
+
+/// 日志中的一条记录
+enum Entry {
+    /// 一个命令的修改和记录占的字节数
+    Batch(WalRecord, usize),
+    /// 长度完整但是解码失败的记录占的字节数
+    Corrupt(usize),
+    /// 还没有写完的记录
+    Incomplete,
+}
+
+/// 解析 buf 开头的记录；长度本身无法解码时日志已经损坏，无法找到下一条记录
+fn next_entry(buf: &[u8]) -> Result<Entry, KvError> {
+    let mut rest = buf;
+    let len = match prost::encoding::decode_varint(&mut rest) {
+        Ok(len) => len as usize,
+        Err(_) if buf.len() < MAX_VARINT_LEN => return Ok(Entry::Incomplete),
+        Err(e) => return Err(KvError::Internal(format!("Corrupt CDC log: {}", e))),
+    };
+    if rest.len() < len {
+        return Ok(Entry::Incomplete);
+    }
+    let size = buf.len() - rest.len() + len;
+    match WalRecord::decode(&rest[..len]) {
+        Ok(batch) => Ok(Entry::Batch(batch, size)),
+        Err(_) => Ok(Entry::Corrupt(size)),
+    }
+}
+
+/// 投递的目的地
+enum Sink {
+    File(File),
+    Kv(Box<ReconnectingClient>),
+}
+
+impl Sink {
+    async fn open(config: &CdcSinkConfig) -> Result<Self, KvError> {
+        match config {
+            CdcSinkConfig::File(path) => {
+                let file = OpenOptions::new().create(true).append(true).open(path)?;
+                Ok(Self::File(file))
+            }
+            CdcSinkConfig::Kv(config) => {
+                let client = ReconnectingClient::connect((**config).clone()).await?;
+                Ok(Self::Kv(Box::new(client)))
+            }
+        }
+    }
+
+    /// 返回 Ok 表示 sink 已经持久化了这些修改
+    async fn deliver(&mut self, batches: &[WalRecord]) -> Result<(), KvError> {
+        match self {
+            Self::File(file) => {
+                let mut lines = String::new();
+                for entry in batches.iter().flat_map(changes).filter_map(to_entry) {
+                    let _ = writeln!(lines, "{}", entry);
+                }
+                file.write_all(lines.as_bytes())?;
+                file.sync_data()?;
+            }
+            Self::Kv(client) => {
+                for batch in batches {
+                    let cmds = changes(batch).iter().filter_map(to_command);
+                    let res = client.execute(&CommandRequest::new_batch(cmds)).await?;
+                    check(&res)?;
+                    res.results.iter().try_for_each(check)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// 下游出错时重试；下游拒绝了修改（4xx）时重试也不会成功，跳过这个修改
+fn check(res: &CommandResponse) -> Result<(), KvError> {
+    match res.status {
+        500.. => Err(KvError::Internal(format!(
+            "CDC sink failed: {} {}",
+            res.status, res.message
+        ))),
+        400..=499 if res.status != 404 => {
+            warn!("CDC sink rejected change: {} {}", res.status, res.message);
+            Ok(())
+        }
+        _ => Ok(()),
+    }
+}
+
+/// 一个命令的修改
+fn changes(batch: &WalRecord) -> &[WalRecord] {
+    match &batch.op {
+        Some(Op::Batch(batch)) => &batch.records,
+        _ => std::slice::from_ref(batch),
+    }
+}
+
+/// 写入文件的一行：table、key、修改的类型和数据；expire 的数据是过期时刻的毫秒时间戳
+fn to_entry(record: &WalRecord) -> Option<serde_json::Value> {
+    let (op, data) = match record.op.as_ref()? {
+        Op::Set(value) => ("set", to_json(Some(value))),
+        Op::Del(_) => ("del", serde_json::Value::Null),
+        Op::Expire(deadline) => ("expire", json!(deadline)),
+        Op::Zadd(zadd) => (
+            "zadd",
+            zadd.members
+                .iter()
+                .map(|m| {
+                    (
+                        String::from_utf8_lossy(&m.member).into_owned(),
+                        json!(m.score),
+                    )
+                })
+                .collect(),
+        ),
+        Op::CreateIndex(spec) => (
+            "create_index",
+            json!({ "name": spec.name, "field": spec.field }),
+        ),
+        Op::TruncateTable(_) => ("truncate_table", serde_json::Value::Null),
+        Op::DropTable(_) => ("drop_table", serde_json::Value::Null),
+        Op::Batch(_) => return None,
+    };
+    Some(json!({
+        "table": record.table,
+        "key": String::from_utf8_lossy(&record.key),
+        "op": op,
+        "data": data,
+    }))
+}
+
+/// 在下游 kv-sv 上产生同样修改的命令；已经过期的 key 直接删除
+fn to_command(record: &WalRecord) -> Option<CommandRequest> {
+    let (table, key) = (record.table.clone(), &record.key);
+    let cmd = match record.op.as_ref()? {
+        Op::Set(value) => CommandRequest::new_hset(table, key, value.clone()),
+        Op::Del(_) => CommandRequest::new_hdel(table, key),
+        Op::Expire(deadline) => match deadline.checked_sub(now_millis()) {
+            Some(ttl) if ttl > 0 => CommandRequest::new_hexpire(table, key, ttl.div_ceil(1000)),
+            _ => CommandRequest::new_hdel(table, key),
+        },
+        Op::Zadd(zadd) => RequestData::Zadd(Zadd {
+            table,
+            key: key.clone(),
+            members: zadd.members.clone(),
+        })
+        .into(),
+        Op::CreateIndex(spec) => RequestData::CreateIndex(CreateIndex {
+            table,
+            index: Some(spec.clone()),
+        })
+        .into(),
+        Op::TruncateTable(_) => CommandRequest::new_ttruncate(table),
+        Op::DropTable(_) => CommandRequest::new_tdrop(table),
+        Op::Batch(_) => return None,
+    };
+    Some(cmd)
+}
+
+#[cfg(test)]
+mod cdc_tests {
+    use futures::StreamExt;
+
+    use super::*;
+    use crate::{service_builder::ServiceBuilder, session::Session};
+
+    fn config(dir: &tempfile::TempDir, sink: impl Into<String>) -> CdcConfig {
+        CdcConfig {
+            dir: dir.path().join("cdc").to_string_lossy().into(),
+            sink: CdcSinkConfig::File(sink.into()),
+            tables: vec!["t1".into()],
+            fsync: FsyncPolicy::Always,
+        }
+    }
+
+    fn read_entries(path: &std::path::Path) -> Vec<serde_json::Value> {
+        fs::read_to_string(path)
+            .unwrap_or_default()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn cdc_should_ship_mutations_to_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("changes.jsonl");
+        let config = config(&dir, path.to_string_lossy());
+        let service: Service = ServiceBuilder::default()
+            .cdc(Some(Cdc::open(&config).unwrap()))
+            .finish();
+        let session = Session::default();
+        for cmd in [
+            CommandRequest::new_hset("t1", "k1", "v1"),
+            // 读命令和其它 table 的修改不投递
+            CommandRequest::new_hget("t1", "k1"),
+            CommandRequest::new_hset("t2", "k1", "v1"),
+            CommandRequest::new_hset_ex("t1", "k2", 2, 60),
+            CommandRequest::new_hdel("t1", "k1"),
+            CommandRequest::new_zadd("t1", "z", [("a", 1.0)]),
+        ] {
+            service.execute_with(cmd, &session).next().await.unwrap();
+        }
+        let task = service.spawn_cdc_task().unwrap();
+
+        let mut entries = read_entries(&path);
+        for _ in 0..100 {
+            if entries.len() >= 5 {
+                break;
+            }
+            time::sleep(Duration::from_millis(10)).await;
+            entries = read_entries(&path);
+        }
+        assert_eq!(entries.len(), 5);
+        assert_eq!(
+            entries[0],
+            json!({ "table": "t1", "key": "k1", "op": "set", "data": "v1" })
+        );
+        assert_eq!(entries[1]["op"], "set");
+        assert_eq!(entries[1]["data"], 2);
+        assert_eq!(entries[2]["op"], "expire");
+        assert!(entries[2]["data"].as_u64().unwrap() > now_millis());
+        assert_eq!(entries[3]["op"], "del");
+        assert_eq!(entries[4]["data"], json!({ "a": 1.0 }));
+
+        // 投递完之后清空日志
+        let cdc = service.cdc.clone().unwrap();
+        for _ in 0..100 {
+            if cdc.cursor().unwrap() == 0 {
+                break;
+            }
+            time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(cdc.cursor().unwrap(), 0);
+        assert_eq!(fs::metadata(cdc.dir.join(LOG_FILE)).unwrap().len(), 0);
+
+        drop((service, cdc));
+        time::timeout(Duration::from_secs(3), task)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn cdc_should_redeliver_from_cursor() {
+        let dir = tempfile::tempdir().unwrap();
+        // sink 所在的目录不存在，投递失败
+        let path = dir.path().join("sink").join("changes.jsonl");
+        let config = config(&dir, path.to_string_lossy());
+        let cdc = Cdc::open(&config).unwrap();
+        cdc.append(&[WalRecord::new_set("t1", "k1", "v1".into())])
+            .wait()
+            .unwrap();
+        cdc.append(&[WalRecord::new_del("t1", "k2")])
+            .wait()
+            .unwrap();
+        assert!(cdc.ship(&mut None).await.is_err());
+        assert_eq!(cdc.cursor().unwrap(), 0);
+
+        // 重启之后从游标继续
+        drop(cdc);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        let cdc = Cdc::open(&config).unwrap();
+        let mut sink = None;
+        assert!(cdc.ship(&mut sink).await.unwrap());
+        assert!(cdc.cursor().unwrap() > 0);
+        cdc.append(&[WalRecord::new_set("t1", "k3", "v3".into())])
+            .wait()
+            .unwrap();
+        assert!(cdc.ship(&mut sink).await.unwrap());
+        assert!(!cdc.ship(&mut sink).await.unwrap());
+
+        let entries = read_entries(&path);
+        let keys: Vec<_> = entries.iter().map(|e| e["key"].clone()).collect();
+        assert_eq!(keys, ["k1", "k2", "k3"]);
+        assert_eq!(cdc.cursor().unwrap(), 0);
+    }
+
+    fn write_log(config: &CdcConfig, data: &[u8]) {
+        let path = PathBuf::from(&config.dir).join(LOG_FILE);
+        let mut file = OpenOptions::new().append(true).open(path).unwrap();
+        file.write_all(data).unwrap();
+    }
+
+    #[tokio::test]
+    async fn cdc_should_skip_corrupt_records() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("changes.jsonl");
+        let config = config(&dir, path.to_string_lossy());
+        let cdc = Cdc::open(&config).unwrap();
+        cdc.append(&[WalRecord::new_set("t1", "k1", "v1".into())])
+            .wait()
+            .unwrap();
+        // 长度完整但是内容无法解码
+        write_log(&config, &[3, 0xff, 0xff, 0xff]);
+        cdc.append(&[WalRecord::new_set("t1", "k2", "v2".into())])
+            .wait()
+            .unwrap();
+
+        let mut sink = None;
+        while cdc.ship(&mut sink).await.unwrap() {}
+        let entries = read_entries(&path);
+        let keys: Vec<_> = entries.iter().map(|e| e["key"].clone()).collect();
+        assert_eq!(keys, ["k1", "k2"]);
+        assert_eq!(cdc.cursor().unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn cdc_should_drop_incomplete_tail_on_open() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("changes.jsonl");
+        let config = config(&dir, path.to_string_lossy());
+        let cdc = Cdc::open(&config).unwrap();
+        cdc.append(&[WalRecord::new_set("t1", "k1", "v1".into())])
+            .wait()
+            .unwrap();
+        let len = fs::metadata(cdc.dir.join(LOG_FILE)).unwrap().len();
+        // 崩溃时只写了一部分的记录
+        write_log(&config, &[100, 1, 2, 3]);
+        drop(cdc);
+
+        let cdc = Cdc::open(&config).unwrap();
+        assert_eq!(fs::metadata(cdc.dir.join(LOG_FILE)).unwrap().len(), len);
+        cdc.append(&[WalRecord::new_set("t1", "k2", "v2".into())])
+            .wait()
+            .unwrap();
+        let mut sink = None;
+        while cdc.ship(&mut sink).await.unwrap() {}
+        let entries = read_entries(&path);
+        let keys: Vec<_> = entries.iter().map(|e| e["key"].clone()).collect();
+        assert_eq!(keys, ["k1", "k2"]);
+    }
+
+    #[test]
+    fn cdc_append_should_return_error_on_write_failure() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = config(&dir, "changes.jsonl");
+        let cdc = Cdc::open(&config).unwrap();
+        cdc.append(&[WalRecord::new_set("t1", "k1", "v1".into())])
+            .wait()
+            .unwrap();
+        let len = fs::metadata(cdc.dir.join(LOG_FILE)).unwrap().len();
+
+        // 只读打开的文件无法写入
+        cdc.lock().file = File::open(cdc.dir.join(LOG_FILE)).unwrap();
+        assert!(cdc
+            .append(&[WalRecord::new_set("t1", "k2", "v2".into())])
+            .wait()
+            .is_err());
+        assert_eq!(fs::metadata(cdc.dir.join(LOG_FILE)).unwrap().len(), len);
+        // 不需要投递的修改不写入日志
+        assert!(cdc
+            .append(&[WalRecord::new_set("t2", "k2", "v2".into())])
+            .wait()
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn cdc_appends_should_be_written_in_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("changes.jsonl");
+        let config = config(&dir, path.to_string_lossy());
+        let cdc = Cdc::open(&config).unwrap();
+        // 先排队再等待，写日志的线程可能一次写入多个
+        let pending: Vec<_> = (0..10)
+            .map(|i| cdc.append(&[WalRecord::new_del("t1", format!("k{}", i))]))
+            .collect();
+        for appended in pending {
+            appended.wait().unwrap();
+        }
+
+        let mut sink = None;
+        while cdc.ship(&mut sink).await.unwrap() {}
+        let entries = read_entries(&path);
+        let keys: Vec<_> = entries.iter().map(|e| e["key"].clone()).collect();
+        let expected: Vec<_> = (0..10).map(|i| json!(format!("k{}", i))).collect();
+        assert_eq!(keys, expected);
+    }
+
+    #[test]
+    fn next_entry_should_tell_incomplete_from_corrupt() {
+        let data = WalRecord::new_del("t1", "k1").encode_length_delimited_to_vec();
+        assert!(matches!(next_entry(&data), Ok(Entry::Batch(_, n)) if n == data.len()));
+        assert!(matches!(
+            next_entry(&data[..data.len() - 1]),
+            Ok(Entry::Incomplete)
+        ));
+        assert!(matches!(next_entry(&[0x80]), Ok(Entry::Incomplete)));
+        assert!(matches!(
+            next_entry(&[2, 0xff, 0xff, 0]),
+            Ok(Entry::Corrupt(3))
+        ));
+        // 长度本身已经损坏
+        assert!(next_entry(&[0xff; MAX_VARINT_LEN + 1]).is_err());
+    }
+
+    #[test]
+    fn records_should_be_converted_to_commands() {
+        let expire = WalRecord::new_expire("t1", "k1", now_millis() + 1500);
+        assert_eq!(
+            to_command(&expire),
+            Some(CommandRequest::new_hexpire("t1", "k1", 2))
+        );
+        let expired = WalRecord::new_expire("t1", "k1", now_millis() - 1);
+        assert_eq!(
+            to_command(&expired),
+            Some(CommandRequest::new_hdel("t1", "k1"))
+        );
+        assert_eq!(
+            to_command(&WalRecord::new_truncate_table("t1")),
+            Some(CommandRequest::new_ttruncate("t1"))
+        );
+    }
+}
@@ -0,0 +1,174 @@
+use std::{
+    collections::BTreeMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, Weak,
+    },
+};
+
+use super::session::Session;
+use crate::{
+    pb::abi::{CommandResponse, Value, ValueMap},
+    storage::now_millis,
+};
+
+type Clients = Arc<Mutex<BTreeMap<u64, Client>>>;
+
+/// 所有连接到本节点的客户端，CLIENT LIST 和 CLIENT KILL 使用；
+/// prost、QUIC、RESP 和 WebSocket 的连接建立之后注册，断开之后自动移除
+#[derive(Debug, Default)]
+pub struct ClientRegistry {
+    next_id: AtomicU64,
+    clients: Clients,
+}
+
+#[derive(Debug)]
+struct Client {
+    session: Weak<Session>,
+    /// 建立连接的毫秒时间戳
+    connected_at: u64,
+}
+
+/// 连接断开时 drop，从注册表中移除
+#[derive(Debug)]
+pub struct ClientGuard {
+    clients: Clients,
+    id: u64,
+}
+
+impl Drop for ClientGuard {
+    fn drop(&mut self) {
+        let mut clients = self.clients.lock().unwrap_or_else(|e| e.into_inner());
+        clients.remove(&self.id);
+    }
+}
+
+impl ClientGuard {
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+}
+
+impl ClientRegistry {
+    /// 注册一个连接，id 从 1 开始递增
+    pub fn register(&self, session: &Arc<Session>) -> ClientGuard {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+        let client = Client {
+            session: Arc::downgrade(session),
+            connected_at: now_millis(),
+        };
+        let mut clients = self.clients.lock().unwrap_or_else(|e| e.into_inner());
+        clients.insert(id, client);
+        ClientGuard {
+            clients: self.clients.clone(),
+            id,
+        }
+    }
+
+    /// 处理 CLIENT LIST
+    pub fn list(&self) -> CommandResponse {
+        let clients = self.clients.lock().unwrap_or_else(|e| e.into_inner());
+        let values: Vec<Value> = clients
+            .iter()
+            .filter_map(|(&id, client)| {
+                let session = client.session.upgrade()?;
+                let entries = [
+                    ("id", (id as i64).into()),
+                    ("peer", session.peer().unwrap_or_default().into()),
+                    ("user", session.user().unwrap_or_default().into()),
+                    ("connected_at", (client.connected_at as i64).into()),
+                    (
+                        "last_command",
+                        session.last_command().unwrap_or_default().into(),
+                    ),
+                    ("streams", (session.streams() as i64).into()),
+                ]
+                .into_iter()
+                .map(|(name, v)| (name.to_string(), v))
+                .collect();
+                Some(ValueMap { entries }.into())
+            })
+            .collect();
+        values.into()
+    }
+
+    /// 处理 CLIENT KILL：id 不为 0 时按 id 断开，否则断开地址是 peer 的连接；返回断开的连接数
+    pub fn kill(&self, id: u64, peer: &str) -> CommandResponse {
+        let clients = self.clients.lock().unwrap_or_else(|e| e.into_inner());
+        let killed = clients
+            .iter()
+            .filter(|(&client_id, _)| id == 0 || client_id == id)
+            .filter_map(|(_, client)| client.session.upgrade())
+            .filter(|session| id != 0 || session.peer() == Some(peer))
+            .inspect(|session| session.kill())
+            .count();
+        (killed as i64).into()
+    }
+}
+
+#[cfg(test)]
+mod clients_tests {
+    use futures::StreamExt;
+
+    use super::*;
+    use crate::{
+        pb::abi::{value, CommandRequest},
+        service_builder::ServiceBuilder,
+        Service,
+    };
+
+    #[tokio::test]
+    async fn client_list_and_kill_should_work() {
+        let service: Service = ServiceBuilder::default().finish();
+        let first = Arc::new(Session::new().with_peer("127.0.0.1:5000"));
+        let second = Arc::new(Session::new().with_peer("127.0.0.1:5001"));
+        let guard = service.clients.register(&first);
+        let _second = service.clients.register(&second);
+        let _stream = first.open_stream();
+        service
+            .execute_with(CommandRequest::new_hget("t1", "k1"), &first)
+            .next()
+            .await
+            .unwrap();
+
+        let res = service
+            .execute(CommandRequest::new_client_list())
+            .next()
+            .await
+            .unwrap();
+        assert_eq!(res.values.len(), 2);
+        let Some(value::Value::Map(map)) = &res.values[0].value else {
+            panic!("expect map");
+        };
+        let get = |name: &str| map.entries.get(name).cloned().unwrap();
+        assert_eq!(get("id"), (guard.id() as i64).into());
+        assert_eq!(get("peer"), "127.0.0.1:5000".into());
+        assert_eq!(get("last_command"), "HGET".into());
+        assert_eq!(get("streams"), 1.into());
+
+        let res = service
+            .execute(CommandRequest::new_client_kill_peer("127.0.0.1:5001"))
+            .next()
+            .await
+            .unwrap();
+        assert_eq!(res.values, [1.into()]);
+        second.killed().await;
+
+        let res = service
+            .execute(CommandRequest::new_client_kill(guard.id()))
+            .next()
+            .await
+            .unwrap();
+        assert_eq!(res.values, [1.into()]);
+        first.killed().await;
+
+        // 断开之后从列表中移除
+        drop(guard);
+        let res = service
+            .execute(CommandRequest::new_client_list())
+            .next()
+            .await
+            .unwrap();
+        assert_eq!(res.values.len(), 1);
+    }
+}
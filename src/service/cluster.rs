@@ -0,0 +1,1013 @@
+//! raft 集群模式：写命令作为日志复制到多数节点，提交之后每个节点按顺序应用到自己的存储；
+//! 读命令由 leader 确认自己仍然是 leader、并且应用了已经提交的日志之后在本地执行，读到的数据是线性一致的。
+//! follower 收到读写命令时返回 421，带上 leader 的地址
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex as StdMutex, Weak},
+    time::{Duration, Instant},
+};
+
+use futures::future::{join_all, BoxFuture};
+use tokio::{
+    sync::{oneshot, watch, Mutex, Notify},
+    time,
+};
+use tokio_util::compat::Compat;
+use tracing::{debug, info, warn};
+
+pub use super::raft::Role;
+
+use super::{
+    acl::required_access,
+    dispatch,
+    raft::{Raft, RaftLog},
+    session::Session,
+    slowlog::command_name,
+    Service,
+};
+use crate::{
+    config::{ClientConfig, ClusterConfig, PeerConfig, Verb},
+    error::KvError,
+    network::pool::{connect, ClientCtrl},
+    pb::abi::{
+        command_request::RequestData, CommandRequest, CommandResponse, Kvpair, RaftAppend,
+        RaftVote, Value,
+    },
+    ProstClientStream, Storage,
+};
+
+/// 默认的选举超时（毫秒）
+const DEFAULT_ELECTION_TIMEOUT: u64 = 300;
+
+/// 默认的心跳间隔（毫秒）
+const DEFAULT_HEARTBEAT_INTERVAL: u64 = 50;
+
+/// 命令没有设置超时时间时，最多等待多久提交
+const PROPOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// 检查是否要发起选举的间隔
+const TICK_INTERVAL: Duration = Duration::from_millis(10);
+
+/// 把提交的命令应用到本节点的存储，返回命令的结果
+pub(super) type Apply = Box<dyn Fn(CommandRequest) -> CommandResponse + Send + Sync>;
+
+/// 给其它节点发送 raft 消息
+pub trait Transport: Send + Sync + 'static {
+    fn call(
+        &self,
+        peer: u64,
+        cmd: CommandRequest,
+    ) -> BoxFuture<'_, Result<CommandResponse, KvError>>;
+}
+
+/// 通过网络连接其它节点，连接在第一次使用或者断开之后建立
+pub struct PeerTransport {
+    peers: HashMap<u64, Peer>,
+}
+
+struct Peer {
+    config: ClientConfig,
+    /// None 表示还没有连接，或者连接断开之后还没有重新建立
+    ctrl: Mutex<Option<ClientCtrl>>,
+}
+
+impl PeerTransport {
+    pub fn new(peers: &[PeerConfig]) -> Self {
+        let peers = peers
+            .iter()
+            .map(|p| {
+                let peer = Peer {
+                    config: p.client.clone(),
+                    ctrl: Mutex::new(None),
+                };
+                (p.id, peer)
+            })
+            .collect();
+        Self { peers }
+    }
+}
+
+impl Transport for PeerTransport {
+    fn call(
+        &self,
+        peer: u64,
+        cmd: CommandRequest,
+    ) -> BoxFuture<'_, Result<CommandResponse, KvError>> {
+        Box::pin(async move {
+            let peer = self
+                .peers
+                .get(&peer)
+                .ok_or_else(|| KvError::Internal(format!("unknown node {}", peer)))?;
+            peer.open_stream().await?.execute(&cmd).await
+        })
+    }
+}
+
+impl Peer {
+    async fn open_stream(&self) -> Result<ProstClientStream<Compat<yamux::Stream>>, KvError> {
+        let mut ctrl = self.ctrl.lock().await;
+        if let Some(ctrl) = ctrl.as_mut() {
+            match ctrl.open_stream().await {
+                Ok(stream) => return Ok(stream),
+                Err(e) => debug!("Connection is broken: {:?}, reconnecting", e),
+            }
+        }
+        *ctrl = None;
+        let ctrl = ctrl.insert(connect(&self.config).await?);
+        Ok(ctrl.open_stream().await?)
+    }
+}
+
+/// INFO 中显示的集群状态
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClusterStatus {
+    pub role: Role,
+    pub term: u64,
+    pub leader: Option<u64>,
+    pub commit_index: u64,
+    pub last_applied: u64,
+}
+
+/// 本节点在 raft 集群中的状态，以及和其它节点通信的后台任务
+pub struct Cluster {
+    raft: StdMutex<Raft>,
+    peers: Vec<u64>,
+    /// 各个 peer 的地址，follower 返回 421 时告诉客户端 leader 在哪里
+    addrs: HashMap<u64, String>,
+    /// 本节点给客户端的地址
+    addr: Option<String>,
+    transport: Box<dyn Transport>,
+    apply: Apply,
+    /// 等待日志应用的命令：index -> (任期, 结果)
+    waiters: StdMutex<HashMap<u64, (u64, oneshot::Sender<CommandResponse>)>>,
+    /// 有新的日志时通知各个 peer 的复制任务
+    appended: watch::Sender<()>,
+    /// commit index 前进时通知应用日志的任务
+    committed: Arc<Notify>,
+    /// 最后应用的日志
+    applied: watch::Sender<u64>,
+    heartbeat: Duration,
+    rpc_timeout: Duration,
+}
+
+impl Cluster {
+    pub(super) fn new(
+        config: &ClusterConfig,
+        transport: Box<dyn Transport>,
+        apply: Apply,
+    ) -> Result<Arc<Self>, KvError> {
+        let election = Duration::from_millis(
+            config
+                .election_timeout
+                .unwrap_or(DEFAULT_ELECTION_TIMEOUT)
+                .max(1),
+        );
+        let heartbeat = Duration::from_millis(
+            config
+                .heartbeat_interval
+                .unwrap_or(DEFAULT_HEARTBEAT_INTERVAL)
+                .max(1),
+        );
+        let peers: Vec<u64> = config.peers.iter().map(|p| p.id).collect();
+        let log = RaftLog::open(&config.dir)?;
+        let raft = Raft::new(config.id, peers.clone(), election, log);
+        let addrs = config
+            .peers
+            .iter()
+            .map(|p| (p.id, p.client.general.addr.clone()))
+            .collect();
+        Ok(Arc::new(Self {
+            raft: StdMutex::new(raft),
+            peers,
+            addrs,
+            addr: config.advertise_addr.clone(),
+            transport,
+            apply,
+            waiters: Default::default(),
+            appended: watch::channel(()).0,
+            committed: Default::default(),
+            applied: watch::channel(0).0,
+            heartbeat,
+            // 一次请求等不到回复时，不能耽误下一次选举
+            rpc_timeout: election / 2,
+        }))
+    }
+
+    /// 启动选举、复制日志和应用日志的后台任务；Cluster drop 之后任务自动退出
+    pub(super) fn spawn(self: &Arc<Self>) {
+        let cluster = Arc::downgrade(self);
+        tokio::spawn(async move {
+            let mut ticker = time::interval(TICK_INTERVAL);
+            loop {
+                ticker.tick().await;
+                let Some(cluster) = cluster.upgrade() else {
+                    break;
+                };
+                cluster.tick();
+            }
+        });
+
+        for &peer in &self.peers {
+            let (cluster, mut appended) = (Arc::downgrade(self), self.appended.subscribe());
+            let heartbeat = self.heartbeat;
+            tokio::spawn(async move {
+                loop {
+                    tokio::select! {
+                        _ = time::sleep(heartbeat) => {}
+                        res = appended.changed() => if res.is_err() { break },
+                    }
+                    let Some(cluster) = cluster.upgrade() else {
+                        break;
+                    };
+                    // 落后的 peer 一批接一批地发送，直到追上为止
+                    while cluster.replicate(peer).await.is_ok() {
+                        if !cluster.raft().lags(peer) {
+                            break;
+                        }
+                    }
+                }
+            });
+        }
+
+        let (cluster, committed) = (Arc::downgrade(self), self.committed.clone());
+        let heartbeat = self.heartbeat;
+        tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    _ = time::sleep(heartbeat) => {}
+                    _ = committed.notified() => {}
+                }
+                let Some(cluster) = cluster.upgrade() else {
+                    break;
+                };
+                cluster.apply_committed();
+            }
+        });
+    }
+
+    fn raft(&self) -> std::sync::MutexGuard<'_, Raft> {
+        self.raft.lock().unwrap()
+    }
+
+    pub fn status(&self) -> ClusterStatus {
+        let raft = self.raft();
+        ClusterStatus {
+            role: raft.role(),
+            term: raft.term(),
+            leader: raft.leader(),
+            commit_index: raft.commit_index(),
+            last_applied: raft.last_applied(),
+        }
+    }
+
+    fn tick(self: &Arc<Self>) {
+        let vote = match self.raft().tick(Instant::now()) {
+            Ok(vote) => vote,
+            Err(e) => {
+                warn!("Failed to start election: {:?}", e);
+                return;
+            }
+        };
+        match vote {
+            Some(vote) => self.campaign(vote),
+            // 只有一个节点时直接成为 leader
+            None if self.peers.is_empty() => self.committed.notify_one(),
+            None => {}
+        }
+    }
+
+    /// 向所有 peer 请求投票
+    fn campaign(self: &Arc<Self>, vote: RaftVote) {
+        for &peer in &self.peers {
+            let (cluster, vote) = (self.clone(), vote.clone());
+            tokio::spawn(async move {
+                let term = vote.term;
+                let values = match cluster.call(peer, RequestData::RaftVote(vote).into()).await {
+                    Ok(values) => values,
+                    Err(e) => {
+                        debug!("Failed to request vote from node {}: {:?}", peer, e);
+                        return;
+                    }
+                };
+                let (resp_term, granted, _) = parse_reply(&values);
+                let res = cluster
+                    .raft()
+                    .handle_vote_response(peer, term, resp_term, granted);
+                match res {
+                    // 立即发送心跳，其它节点不用等到选举超时
+                    Ok(true) => cluster.appended.send_replace(()),
+                    Ok(false) => {}
+                    Err(e) => warn!("Failed to handle vote: {:?}", e),
+                }
+            });
+        }
+    }
+
+    /// leader 给 peer 发送一次 AppendEntries，返回 peer 是否认可本节点是当前任期的 leader
+    async fn replicate(&self, peer: u64) -> Result<bool, KvError> {
+        let req = {
+            let raft = self.raft();
+            if !raft.is_leader() {
+                return Ok(false);
+            }
+            raft.append_request(peer)
+        };
+        let term = req.term;
+        let values = self.call(peer, RequestData::RaftAppend(req).into()).await?;
+        let (resp_term, success, index) = parse_reply(&values);
+        let committed = self
+            .raft()
+            .handle_append_response(peer, term, resp_term, success, index)?;
+        if committed {
+            self.committed.notify_one();
+        }
+        Ok(resp_term == term)
+    }
+
+    /// 把已经提交的日志依次应用到存储，唤醒等待结果的命令
+    fn apply_committed(&self) {
+        loop {
+            let Some((index, entry)) = self.raft().next_to_apply() else {
+                break;
+            };
+            let res = match entry.command {
+                Some(cmd) => (self.apply)(cmd),
+                None => CommandResponse::ok(),
+            };
+            self.raft().set_applied(index);
+            if let Some((term, tx)) = self.waiters.lock().unwrap().remove(&index) {
+                // 这个位置上提交的是另一个 leader 写下的日志，原来的命令被丢掉了
+                let res = match term == entry.term {
+                    true => res,
+                    false => dropped().into(),
+                };
+                let _ = tx.send(res);
+            }
+            self.applied.send_replace(index);
+        }
+    }
+
+    /// 在 leader 上把命令写入日志，等它提交并应用之后返回结果
+    pub(super) async fn write(
+        &self,
+        cmd: CommandRequest,
+        timeout: Duration,
+    ) -> Result<CommandResponse, KvError> {
+        let (index, term, rx) = {
+            let mut raft = self.raft();
+            if !raft.is_leader() {
+                return Err(self.not_leader(raft.leader()));
+            }
+            let (index, term) = raft.propose(cmd)?;
+            let (tx, rx) = oneshot::channel();
+            let mut waiters = self.waiters.lock().unwrap();
+            if let Some((_, old)) = waiters.insert(index, (term, tx)) {
+                let _ = old.send(dropped().into());
+            }
+            if raft.commit_index() >= index {
+                self.committed.notify_one();
+            }
+            (index, term, rx)
+        };
+        self.appended.send_replace(());
+        match time::timeout(timeout, rx).await {
+            Ok(Ok(res)) => Ok(res),
+            Ok(Err(_)) => Err(dropped()),
+            Err(_) => {
+                let mut waiters = self.waiters.lock().unwrap();
+                if waiters.get(&index).is_some_and(|(t, _)| *t == term) {
+                    waiters.remove(&index);
+                }
+                Err(KvError::Timeout(format!(
+                    "command isn't committed in {:?}",
+                    timeout
+                )))
+            }
+        }
+    }
+
+    /// 确认自己仍然是 leader，并且应用了确认时已经提交的所有日志；之后在本地读到的数据是线性一致的
+    pub(super) async fn read_barrier(&self, timeout: Duration) -> Result<(), KvError> {
+        let deadline = time::Instant::now() + timeout;
+        let mut applied = self.applied.subscribe();
+        let (index, term) = loop {
+            {
+                let raft = self.raft();
+                if !raft.is_leader() {
+                    return Err(self.not_leader(raft.leader()));
+                }
+                if let Some(index) = raft.read_index() {
+                    break (index, raft.term());
+                }
+            }
+            // 刚上任的 leader 要等空操作提交之后，才知道之前的日志提交到了哪里
+            if time::timeout_at(deadline, applied.changed()).await.is_err() {
+                return Err(not_ready(timeout));
+            }
+        };
+
+        let acks = join_all(self.peers.iter().map(|&peer| self.replicate(peer)))
+            .await
+            .into_iter()
+            .filter(|res| matches!(res, Ok(true)))
+            .count();
+        {
+            let raft = self.raft();
+            if raft.term() != term || !raft.is_leader() {
+                return Err(self.not_leader(raft.leader()));
+            }
+            // 可能已经有了新的 leader，只是本节点还不知道
+            if acks + 1 < raft.quorum() {
+                return Err(self.not_leader(None));
+            }
+        }
+
+        while *applied.borrow_and_update() < index {
+            if time::timeout_at(deadline, applied.changed()).await.is_err() {
+                return Err(not_ready(timeout));
+            }
+        }
+        Ok(())
+    }
+
+    /// 处理其它节点的投票请求
+    pub(super) fn handle_vote(&self, vote: &RaftVote) -> CommandResponse {
+        match self.raft().handle_vote(vote) {
+            Ok((term, granted)) => vec![Value::from(term as i64), granted.into()].into(),
+            Err(e) => e.into(),
+        }
+    }
+
+    /// 处理 leader 发来的日志和心跳
+    pub(super) fn handle_append(&self, append: &RaftAppend) -> CommandResponse {
+        let res = self.raft().handle_append(append);
+        match res {
+            Ok((term, success, index)) => {
+                if success {
+                    self.committed.notify_one();
+                }
+                vec![
+                    Value::from(term as i64),
+                    success.into(),
+                    (index as i64).into(),
+                ]
+                .into()
+            }
+            Err(e) => e.into(),
+        }
+    }
+
+    async fn call(&self, peer: u64, cmd: CommandRequest) -> Result<Vec<Value>, KvError> {
+        let res = time::timeout(self.rpc_timeout, self.transport.call(peer, cmd))
+            .await
+            .map_err(|_| {
+                KvError::Timeout(format!(
+                    "node {} didn't respond in {:?}",
+                    peer, self.rpc_timeout
+                ))
+            })??;
+        match res.status {
+            200 => Ok(res.values),
+            _ => Err(KvError::Internal(res.message)),
+        }
+    }
+
+    /// 各个节点的地址和角色；只知道 leader 是谁，不知道其它 peer 是 follower 还是 candidate
+    pub fn topology(&self) -> Vec<(String, &'static str)> {
+        let (role, leader) = {
+            let raft = self.raft();
+            (raft.role(), raft.leader())
+        };
+        let mut nodes: Vec<_> = self
+            .addr
+            .iter()
+            .map(|addr| (addr.clone(), role.as_str()))
+            .collect();
+        for peer in &self.peers {
+            if let Some(addr) = self.addrs.get(peer) {
+                let role = match leader == Some(*peer) {
+                    true => Role::Leader.as_str(),
+                    false => Role::Follower.as_str(),
+                };
+                nodes.push((addr.clone(), role));
+            }
+        }
+        nodes
+    }
+
+    fn not_leader(&self, leader: Option<u64>) -> KvError {
+        match leader.map(|id| (id, self.addrs.get(&id))) {
+            Some((id, Some(addr))) => KvError::Moved(id, addr.clone()),
+            Some((id, None)) => KvError::NotLeader(format!("leader is node {}", id)),
+            None => KvError::NotLeader("leader is not elected yet".into()),
+        }
+    }
+}
+
+fn dropped() -> KvError {
+    KvError::NotLeader("leadership changed before the command was committed".into())
+}
+
+fn not_ready(timeout: Duration) -> KvError {
+    KvError::Timeout(format!("leader isn't ready in {:?}", timeout))
+}
+
+/// 投票和 AppendEntries 的回复：[term, 是否成功, index]
+fn parse_reply(values: &[Value]) -> (u64, bool, u64) {
+    let integer = |i: usize| {
+        values
+            .get(i)
+            .and_then(Value::to_integer)
+            .unwrap_or_default() as u64
+    };
+    (integer(0), values.get(1) == Some(&true.into()), integer(2))
+}
+
+/// 集群模式下命令怎么执行
+#[derive(Debug, PartialEq)]
+enum Route {
+    /// 和集群无关，直接在本节点执行
+    Local,
+    /// 由 leader 确认线性一致之后在本地执行
+    Read,
+    /// 复制到多数节点之后应用
+    Write,
+    /// 结果和时间或者节点有关，或者应用日志时无法执行，集群模式下不支持
+    Unsupported,
+}
+
+fn route(cmd: &RequestData) -> Route {
+    match cmd {
+        RequestData::Multi(_)
+        | RequestData::Exec(_)
+        | RequestData::Discard(_)
+        | RequestData::Watch(_)
+        | RequestData::Lock(_)
+        | RequestData::Unlock(_)
+        | RequestData::LeaseGrant(_)
+        | RequestData::LeaseKeepAlive(_)
+        | RequestData::LeaseRevoke(_) => Route::Unsupported,
+        RequestData::Hset(v) if v.lease != 0 => Route::Unsupported,
+        // 插件由各个节点自己加载，应用 raft 日志时只能执行存储的命令
+        RequestData::Fcall(_) => Route::Unsupported,
+        RequestData::Tlist(_) | RequestData::Dbsize(_) => Route::Read,
+        RequestData::Batch(v) => v
+            .commands
+            .iter()
+            .filter_map(|cmd| cmd.request_data.as_ref())
+            .map(route)
+            .fold(Route::Local, |acc, r| match (acc, r) {
+                (Route::Unsupported, _) | (_, Route::Unsupported) => Route::Unsupported,
+                (Route::Write, _) | (_, Route::Write) => Route::Write,
+                (Route::Read, _) | (_, Route::Read) => Route::Read,
+                _ => Route::Local,
+            }),
+        _ => {
+            let access = required_access(cmd);
+            let data: Vec<_> = access.iter().filter(|a| a.table.is_some()).collect();
+            match data.iter().any(|a| a.verb == Verb::Write) {
+                true => Route::Write,
+                false if data.is_empty() => Route::Local,
+                false => Route::Read,
+            }
+        }
+    }
+}
+
+impl<Store: Storage> Service<Store> {
+    /// 以集群模式运行：先清空存储，由 raft 日志重新构建；之后读写命令都经过 raft
+    pub fn start_cluster(&self, config: &ClusterConfig) -> Result<(), KvError> {
+        self.start_cluster_with(config, Box::new(PeerTransport::new(&config.peers)))
+    }
+
+    pub fn start_cluster_with(
+        &self,
+        config: &ClusterConfig,
+        transport: Box<dyn Transport>,
+    ) -> Result<(), KvError> {
+        for table in self.store.tables()? {
+            self.store.drop_table(table)?;
+        }
+        let cluster = Cluster::new(config, transport, self.cluster_apply())?;
+        cluster.spawn();
+        info!(
+            "Node {} joined cluster with {} peers",
+            config.id,
+            config.peers.len()
+        );
+        *self.cluster.write().unwrap() = Some(cluster);
+        Ok(())
+    }
+
+    pub fn cluster(&self) -> Option<Arc<Cluster>> {
+        self.cluster.read().unwrap().clone()
+    }
+
+    /// 提交的命令在每个节点上都像普通命令一样执行：更新 keyspace 通知、replica 和统计
+    fn cluster_apply(&self) -> Apply {
+        let inner: Weak<_> = Arc::downgrade(&self.inner);
+        let (keyspace, replicator, stats) = (
+            self.keyspace.clone(),
+            self.replicator.clone(),
+            self.stats.clone(),
+        );
+        Box::new(move |cmd| {
+            let (Some(inner), Some(data)) = (inner.upgrade(), &cmd.request_data) else {
+                return CommandResponse::ok();
+            };
+            let mut res =
+                keyspace.track(data, &inner.store, || dispatch(cmd.clone(), &inner.store));
+            if let Err(e) = replicator.record(data, &inner.store) {
+                res = e.into();
+            }
+            stats.record(data, &res);
+            res
+        })
+    }
+
+    /// 集群模式下执行命令：写命令返回应用之后的结果；读命令确认线性一致之后返回 None，接着在本地执行
+    pub(super) async fn execute_in_cluster(
+        &self,
+        cluster: &Cluster,
+        cmd: &CommandRequest,
+        session: &Session,
+    ) -> Option<CommandResponse> {
+        let data = cmd.request_data.as_ref()?;
+        if session.in_transaction() {
+            return None;
+        }
+        let timeout = match cmd.timeout {
+            0 => PROPOSE_TIMEOUT,
+            timeout => Duration::from_millis(timeout),
+        };
+        match route(data) {
+            Route::Local => None,
+            Route::Read => cluster.read_barrier(timeout).await.err().map(Into::into),
+            Route::Unsupported => Some(
+                KvError::InvalidCommand(format!(
+                    "{} is not supported in cluster mode",
+                    command_name(data)
+                ))
+                .into(),
+            ),
+            Route::Write => {
+                self.stats.command();
+                if let Err(e) = self.authorize(cmd, session) {
+                    return Some(e.into());
+                }
+                let entry = CommandRequest {
+                    request_data: Some(data.clone()),
+                    idempotency_key: cmd.idempotency_key.clone(),
+                    ..Default::default()
+                };
+                let res = self
+                    .idempotency
+                    .execute_async(cmd, session.user(), || async {
+                        cluster
+                            .write(entry, timeout)
+                            .await
+                            .unwrap_or_else(Into::into)
+                    })
+                    .await;
+                self.audit.record(data, &res, session, &self.broadcaster);
+                Some(res)
+            }
+        }
+    }
+
+    pub(super) fn raft_vote(&self, vote: &RaftVote) -> CommandResponse {
+        match self.cluster() {
+            Some(cluster) => cluster.handle_vote(vote),
+            None => not_clustered().into(),
+        }
+    }
+
+    pub(super) fn raft_append(&self, append: &RaftAppend) -> CommandResponse {
+        match self.cluster() {
+            Some(cluster) => cluster.handle_append(append),
+            None => not_clustered().into(),
+        }
+    }
+}
+
+impl<Store: Storage> Service<Store> {
+    /// CLUSTER SLOTS：代理模式下返回各个后端，集群模式下返回 raft 的各个节点
+    pub(super) fn cluster_slots(&self) -> CommandResponse {
+        let (mode, virtual_nodes, nodes) = match (&self.proxy, self.cluster()) {
+            (Some(proxy), _) => {
+                let nodes = proxy.addrs().map(|addr| (addr.to_string(), "shard"));
+                ("shard", proxy.virtual_nodes(), nodes.collect())
+            }
+            (None, Some(cluster)) => ("raft", 0, cluster.topology()),
+            (None, None) => ("standalone", 0, vec![]),
+        };
+        CommandResponse {
+            values: vec![mode.into(), (virtual_nodes as i64).into()],
+            pairs: nodes
+                .into_iter()
+                .map(|(addr, role)| Kvpair::new(addr, role.into()))
+                .collect(),
+            ..CommandResponse::ok()
+        }
+    }
+}
+
+fn not_clustered() -> KvError {
+    KvError::InvalidCommand("cluster mode is not enabled".into())
+}
+
+#[cfg(test)]
+mod cluster_tests {
+    use std::collections::HashSet;
+    use std::sync::RwLock;
+
+    use futures::StreamExt;
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::service_builder::ServiceBuilder;
+
+    /// 进程内的网络，down 中的节点收发的消息都会失败
+    #[derive(Default)]
+    struct Network {
+        nodes: RwLock<HashMap<u64, Service>>,
+        down: RwLock<HashSet<u64>>,
+    }
+
+    impl Network {
+        fn is_down(&self, id: u64) -> bool {
+            self.down.read().unwrap().contains(&id)
+        }
+    }
+
+    struct LocalTransport {
+        id: u64,
+        network: Arc<Network>,
+    }
+
+    impl Transport for LocalTransport {
+        fn call(
+            &self,
+            peer: u64,
+            cmd: CommandRequest,
+        ) -> BoxFuture<'_, Result<CommandResponse, KvError>> {
+            Box::pin(async move {
+                if self.network.is_down(self.id) || self.network.is_down(peer) {
+                    return Err(KvError::Internal("node is down".into()));
+                }
+                let service = self.network.nodes.read().unwrap()[&peer].clone();
+                let session = Arc::new(Session::default());
+                let res = service.execute_with_deadline(cmd, &session).await;
+                Ok((*res.into_future().await.0.unwrap()).clone())
+            })
+        }
+    }
+
+    fn addr(id: u64) -> String {
+        format!("10.0.0.{}:9876", id)
+    }
+
+    fn cluster_config(id: u64, ids: &[u64], dir: &TempDir) -> ClusterConfig {
+        let client = ClientConfig::load("fixtures/client.conf").unwrap();
+        ClusterConfig {
+            id,
+            dir: dir.path().join(id.to_string()).to_string_lossy().into(),
+            advertise_addr: Some(addr(id)),
+            peers: ids
+                .iter()
+                .filter(|&&p| p != id)
+                .map(|&p| {
+                    let mut client = client.clone();
+                    client.general.addr = addr(p);
+                    PeerConfig { id: p, client }
+                })
+                .collect(),
+            election_timeout: Some(100),
+            heartbeat_interval: Some(20),
+        }
+    }
+
+    fn start_cluster(ids: &[u64], dir: &TempDir) -> Arc<Network> {
+        let network = Arc::new(Network::default());
+        for &id in ids {
+            let service: Service = ServiceBuilder::default().finish();
+            let transport = LocalTransport {
+                id,
+                network: network.clone(),
+            };
+            service
+                .start_cluster_with(&cluster_config(id, ids, dir), Box::new(transport))
+                .unwrap();
+            network.nodes.write().unwrap().insert(id, service);
+        }
+        network
+    }
+
+    /// 等待 down 以外的节点选出 leader
+    async fn wait_for_leader(network: &Network) -> u64 {
+        for _ in 0..300 {
+            let down = network.down.read().unwrap().clone();
+            let leader = network
+                .nodes
+                .read()
+                .unwrap()
+                .iter()
+                .filter(|(id, _)| !down.contains(id))
+                .find(|(_, s)| s.cluster().unwrap().status().role == Role::Leader)
+                .map(|(id, _)| *id);
+            if let Some(leader) = leader {
+                return leader;
+            }
+            time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("no leader is elected");
+    }
+
+    async fn execute(network: &Network, id: u64, cmd: CommandRequest) -> CommandResponse {
+        let service = network.nodes.read().unwrap()[&id].clone();
+        let session = Arc::new(Session::default());
+        let res = service.execute_with_deadline(cmd, &session).await;
+        (*res.into_future().await.0.unwrap()).clone()
+    }
+
+    /// 等待节点的存储中出现 key
+    async fn wait_for_key(network: &Network, id: u64, key: &str, value: &str) {
+        let service = network.nodes.read().unwrap()[&id].clone();
+        for _ in 0..300 {
+            if service.store.get("t1", key).unwrap() == Some(value.into()) {
+                return;
+            }
+            time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("{} is not replicated to node {}", key, id);
+    }
+
+    #[tokio::test]
+    async fn cluster_should_replicate_writes_to_all_nodes() {
+        let dir = tempfile::tempdir().unwrap();
+        let network = start_cluster(&[1, 2, 3], &dir);
+        let leader = wait_for_leader(&network).await;
+
+        let res = execute(&network, leader, CommandRequest::new_hset("t1", "k1", "v1")).await;
+        assert_eq!(res.status, 200);
+        let res = execute(&network, leader, CommandRequest::new_hget("t1", "k1")).await;
+        assert_eq!(res.values, vec!["v1".into()]);
+        for id in [1, 2, 3] {
+            wait_for_key(&network, id, "k1", "v1").await;
+        }
+
+        // follower 不处理读写命令，告诉客户端 leader 是谁
+        let follower = [1, 2, 3].into_iter().find(|&id| id != leader).unwrap();
+        for cmd in [
+            CommandRequest::new_hset("t1", "k2", "v2"),
+            CommandRequest::new_hget("t1", "k1"),
+        ] {
+            let res = execute(&network, follower, cmd).await;
+            assert_eq!(res.status, 421);
+            assert!(res.message.contains(&format!("node {}", leader)));
+            assert_eq!(res.values, vec![addr(leader).into()]);
+        }
+        // PING 之类的命令在本地执行
+        let res = execute(&network, follower, CommandRequest::new_ping()).await;
+        assert_eq!(res.status, 200);
+    }
+
+    #[tokio::test]
+    async fn cluster_slots_should_return_nodes_and_leader() {
+        let dir = tempfile::tempdir().unwrap();
+        let network = start_cluster(&[1, 2, 3], &dir);
+        let leader = wait_for_leader(&network).await;
+        let res = execute(&network, leader, CommandRequest::new_cluster_slots()).await;
+        assert_eq!(res.values, vec!["raft".into(), 0.into()]);
+        let mut nodes = res.pairs;
+        nodes.sort_by(|a, b| a.key.cmp(&b.key));
+        let expected: Vec<_> = [1, 2, 3]
+            .into_iter()
+            .map(|id| match id == leader {
+                true => Kvpair::new(addr(id), "leader".into()),
+                false => Kvpair::new(addr(id), "follower".into()),
+            })
+            .collect();
+        assert_eq!(nodes, expected);
+
+        let service: Service = ServiceBuilder::default().finish();
+        let res = service.execute(CommandRequest::new_cluster_slots());
+        let res = res.into_future().await.0.unwrap();
+        assert_eq!(res.values, vec!["standalone".into(), 0.into()]);
+        assert!(res.pairs.is_empty());
+    }
+
+    #[tokio::test]
+    async fn cluster_should_elect_new_leader_when_leader_is_down() {
+        let dir = tempfile::tempdir().unwrap();
+        let network = start_cluster(&[1, 2, 3], &dir);
+        let old = wait_for_leader(&network).await;
+        let res = execute(&network, old, CommandRequest::new_hset("t1", "k1", "v1")).await;
+        assert_eq!(res.status, 200);
+
+        network.down.write().unwrap().insert(old);
+        // 和其它节点断开之后，原来的 leader 不能确认自己还是 leader，不再处理读命令
+        let res = execute(&network, old, CommandRequest::new_hget("t1", "k1")).await;
+        assert_eq!(res.status, 421);
+
+        let leader = wait_for_leader(&network).await;
+        assert_ne!(leader, old);
+        let res = execute(&network, leader, CommandRequest::new_hset("t1", "k2", "v2")).await;
+        assert_eq!(res.status, 200);
+        let res = execute(&network, leader, CommandRequest::new_hget("t1", "k1")).await;
+        assert_eq!(res.values, vec!["v1".into()]);
+
+        // 恢复之后，原来的 leader 追上新的日志
+        network.down.write().unwrap().clear();
+        wait_for_key(&network, old, "k2", "v2").await;
+        assert_ne!(
+            network.nodes.read().unwrap()[&old]
+                .cluster()
+                .unwrap()
+                .status()
+                .role,
+            Role::Leader
+        );
+    }
+
+    #[tokio::test]
+    async fn cluster_should_rebuild_state_from_log() {
+        let dir = tempfile::tempdir().unwrap();
+        let network = start_cluster(&[1], &dir);
+        wait_for_leader(&network).await;
+        let res = execute(&network, 1, CommandRequest::new_hincrby("t1", "counter", 2)).await;
+        assert_eq!(res.status, 200);
+        let res = execute(&network, 1, CommandRequest::new_hincrby("t1", "counter", 3)).await;
+        assert_eq!(res.values, vec![5.into()]);
+        network.nodes.write().unwrap().clear();
+
+        // 重启之后日志重新应用一遍，结果和之前一样
+        let network = start_cluster(&[1], &dir);
+        wait_for_leader(&network).await;
+        let res = execute(&network, 1, CommandRequest::new_hget("t1", "counter")).await;
+        assert_eq!(res.values, vec![5.into()]);
+    }
+
+    #[tokio::test]
+    async fn cluster_writes_should_be_idempotent() {
+        let dir = tempfile::tempdir().unwrap();
+        let network = start_cluster(&[1], &dir);
+        wait_for_leader(&network).await;
+        let cmd = CommandRequest::new_hincrby("t1", "counter", 1).with_idempotency_key("a");
+        for _ in 0..2 {
+            let res = execute(&network, 1, cmd.clone()).await;
+            assert_eq!(res.values, vec![1.into()]);
+        }
+        let res = execute(&network, 1, CommandRequest::new_hget("t1", "counter")).await;
+        assert_eq!(res.values, vec![1.into()]);
+    }
+
+    #[tokio::test]
+    async fn cluster_should_reject_unsupported_commands() {
+        let dir = tempfile::tempdir().unwrap();
+        let network = start_cluster(&[1], &dir);
+        wait_for_leader(&network).await;
+        for cmd in [
+            CommandRequest::new_multi(),
+            CommandRequest::new_lock("locks", "l1", Duration::from_secs(1)),
+            CommandRequest::new_lease_grant(10),
+            CommandRequest::new_fcall("t1", "incr", ["k1"], [Value::from(1)]),
+            CommandRequest::new_batch([
+                CommandRequest::new_hset("t1", "k1", "v1"),
+                CommandRequest::new_fcall("t1", "incr", ["k1"], [Value::from(1)]),
+            ]),
+        ] {
+            let res = execute(&network, 1, cmd).await;
+            assert_eq!(res.status, 400);
+            assert!(res.message.contains("cluster mode"));
+        }
+    }
+
+    #[test]
+    fn route_should_classify_commands() {
+        let route_of = |cmd: CommandRequest| route(&cmd.request_data.unwrap());
+        assert_eq!(
+            route_of(CommandRequest::new_hset("t1", "k1", "v1")),
+            Route::Write
+        );
+        assert_eq!(route_of(CommandRequest::new_hget("t1", "k1")), Route::Read);
+        assert_eq!(route_of(CommandRequest::new_tlist()), Route::Read);
+        assert_eq!(route_of(CommandRequest::new_ping()), Route::Local);
+        assert_eq!(route_of(CommandRequest::new_info()), Route::Local);
+        let batch = CommandRequest::new_batch(vec![
+            CommandRequest::new_hget("t1", "k1"),
+            CommandRequest::new_hset("t1", "k1", "v1"),
+        ]);
+        assert_eq!(route_of(batch), Route::Write);
+        assert_eq!(
+            route_of(CommandRequest::new_fcall(
+                "t1",
+                "f",
+                ["k1"],
+                [Value::from(1)]
+            )),
+            Route::Unsupported
+        );
+    }
+}
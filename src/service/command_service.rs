@@ -1,24 +1,58 @@
 use crate::{
     error::KvError,
-    pb::abi::{CommandResponse, Hget, Hgetall, Hset, Value},
-    Storage,
+    pb::abi::{
+        value, CommandResponse, CreateIndex, Dbsize, Happend, Hbitcount, Hcas, Hdel, Hexist,
+        Hexpire, Hget, Hgetall, Hgetbit, Hgetrange, Hincrby, Hjsonget, Hjsonset, Hmdel, Hmexist,
+        Hmget, Hmset, Hpfadd, Hpfcount, Hrange, Hscan, Hscanprefix, Hset, Hsetbit, Hstrlen, Httl,
+        Kvpair, Lpush, Lrange, MapGet, MapSet, Query, Sadd, Smembers, Snapshot, Tdrop, Tlist,
+        Ttruncate, Value, ValueList, ValueMap, ValueSet, Zadd, Zrange, Zrangebyscore,
+    },
+    snapshot,
+    zset::{check_score, rank_range},
+    SetCondition, Storage,
 };
+use bytes::Bytes;
+use prost::Message;
+use std::{ops::Bound, time::Duration};
+use tracing::instrument;
+
+use super::{hyperloglog::HyperLogLog, json_path, lease};
+
+/// HSCAN 没有指定 count 时，每页返回的数量
+pub(super) const DEFAULT_SCAN_COUNT: u64 = 10;
+
+/// HSETBIT 最大的 offset，和 redis 一样 bitmap 最大 512MB
+const MAX_BIT_OFFSET: u64 = (1 << 32) - 1;
 
 pub trait CommandService {
     fn execute(self, store: &impl Storage) -> CommandResponse;
 }
 
 impl CommandService for Hget {
+    #[instrument(name = "storage_hget", skip_all, fields(table = %self.table))]
     fn execute(self, store: &impl crate::Storage) -> CommandResponse {
+        // 读取历史需要 keyspace，只能单独执行，不能放在事务和批量命令中
+        if self.at_revision > 0 {
+            return KvError::InvalidCommand(
+                "HGET with at_revision can't be used in transactions or batches".into(),
+            )
+            .into();
+        }
         match store.get(&self.table, &self.key) {
             Ok(Some(v)) => v.into(),
-            Ok(None) => KvError::NotFound(format!("{}:{}", self.table, self.key)).into(),
+            Ok(None) => KvError::NotFound(format!(
+                "{}:{}",
+                self.table,
+                String::from_utf8_lossy(&self.key)
+            ))
+            .into(),
             Err(e) => e.into(),
         }
     }
 }
 
 impl CommandService for Hgetall {
+    #[instrument(name = "storage_hgetall", skip_all, fields(table = %self.table))]
     fn execute(self, store: &impl crate::Storage) -> CommandResponse {
         match store.get_all(&self.table) {
             Ok(v) => v.into(),
@@ -27,15 +61,819 @@ impl CommandService for Hgetall {
     }
 }
 
+impl CommandService for Hrange {
+    #[instrument(name = "storage_hrange", skip_all, fields(table = %self.table))]
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        let end = match self.end.is_empty() {
+            true => Bound::Unbounded,
+            false => Bound::Excluded(self.end),
+        };
+        match store.range(&self.table, (Bound::Included(self.start), end)) {
+            Ok(pairs) => pairs.into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Hscanprefix {
+    #[instrument(name = "storage_hscanprefix", skip_all, fields(table = %self.table))]
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        match store.scan_prefix(&self.table, &self.prefix) {
+            Ok(iter) => iter.collect::<Vec<_>>().into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
 impl CommandService for Hset {
+    #[instrument(name = "storage_hset", skip_all, fields(table = %self.table))]
     fn execute(self, store: &impl crate::Storage) -> CommandResponse {
-        match self.pair {
-            Some(pair) => match store.set(&self.table, pair.key, pair.value.unwrap_or_default()) {
-                Ok(Some(v)) => v.into(),
-                Ok(None) => Value::default().into(),
+        let condition = match (self.nx, self.xx) {
+            (false, false) => None,
+            (true, false) => Some(SetCondition::IfAbsent),
+            (false, true) => Some(SetCondition::IfExists),
+            (true, true) => {
+                return KvError::InvalidCommand("nx and xx can't be used together".into()).into()
+            }
+        };
+        if self.lease != 0 {
+            if self.ttl != 0 || condition.is_some() {
+                let msg = "lease can't be used with ttl, nx or xx";
+                return KvError::InvalidCommand(msg.into()).into();
+            }
+            let Some(pair) = self.pair else {
+                return KvError::InvalidCommand("Hset has no pair".into()).into();
+            };
+            let value = pair.value.unwrap_or_default();
+            let (namespace, id) = (&self.lease_namespace, self.lease);
+            return match lease::attach(store, namespace, id, &self.table, &pair.key, value) {
+                Ok(old) => old.unwrap_or_default().into(),
                 Err(e) => e.into(),
+            };
+        }
+        let Some(pair) = self.pair else {
+            return KvError::InvalidCommand("HSET requires a key/value pair".into()).into();
+        };
+        // 不带 lease 的写入让 key 脱离之前关联的 lease
+        let owner = match lease::owner(store, &self.table, &pair.key) {
+            Ok(owner) => owner,
+            Err(e) => return e.into(),
+        };
+        match condition {
+            // 只有设置成功时才设置存活时间
+            Some(condition) => {
+                let value = pair.value.unwrap_or_default();
+                let res = store
+                    .set_if(&self.table, &pair.key, value, condition)
+                    .and_then(|(set, old)| {
+                        if set && self.ttl > 0 {
+                            let ttl = Duration::from_secs(self.ttl);
+                            store.expire(&self.table, &pair.key, ttl)?;
+                        }
+                        if set {
+                            lease::detach(store, &self.table, &pair.key, owner)?;
+                        }
+                        Ok((set, old))
+                    });
+                match res {
+                    Ok((set, old)) => vec![set.into(), old.unwrap_or_default()].into(),
+                    Err(e) => e.into(),
+                }
+            }
+            None => {
+                let old = store.set(&self.table, &pair.key, pair.value.unwrap_or_default());
+                let old = match (old, self.ttl) {
+                    (Ok(v), 0) => Ok(v),
+                    (Ok(v), ttl) => store
+                        .expire(&self.table, &pair.key, Duration::from_secs(ttl))
+                        .map(|_| v),
+                    (Err(e), _) => Err(e),
+                };
+                let old = old
+                    .and_then(|v| lease::detach(store, &self.table, &pair.key, owner).map(|_| v));
+                match old {
+                    Ok(Some(v)) => v.into(),
+                    Ok(None) => Value::default().into(),
+                    Err(e) => e.into(),
+                }
+            }
+        }
+    }
+}
+
+impl CommandService for Hcas {
+    #[instrument(name = "storage_hcas", skip_all, fields(table = %self.table))]
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        let value = self.value.unwrap_or_default();
+        match store.compare_and_swap(&self.table, &self.key, self.expected, value) {
+            Ok((swapped, current)) => vec![swapped.into(), current.unwrap_or_default()].into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Hincrby {
+    #[instrument(name = "storage_hincrby", skip_all, fields(table = %self.table))]
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        match store.incr(&self.table, &self.key, self.delta) {
+            Ok(v) => v.into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Happend {
+    #[instrument(name = "storage_happend", skip_all, fields(table = %self.table))]
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        let suffix = self.suffix.unwrap_or_default();
+        let Some(tail) = string_bytes(&suffix) else {
+            return KvError::InvalidCommand("suffix must be a string or binary".into()).into();
+        };
+        let res = store.update(&self.table, &self.key, |current| {
+            let value = match current {
+                None => suffix.clone(),
+                Some(current) => {
+                    let head =
+                        string_bytes(current).ok_or_else(|| wrong_type(&self.key, "string"))?;
+                    match (&current.value, &suffix.value) {
+                        (Some(value::Value::String(s)), Some(value::Value::String(t))) => {
+                            format!("{}{}", s, t).into()
+                        }
+                        _ => Bytes::from([head, tail].concat()).into(),
+                    }
+                }
+            };
+            let len = string_bytes(&value).map_or(0, |v| v.len()) as i64;
+            Ok((value, len))
+        });
+        match res {
+            Ok(len) => len.into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Hstrlen {
+    #[instrument(name = "storage_hstrlen", skip_all, fields(table = %self.table))]
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        match store.get(&self.table, &self.key) {
+            Ok(None) => 0.into(),
+            Ok(Some(v)) => match string_bytes(&v) {
+                Some(v) => (v.len() as i64).into(),
+                None => wrong_type(&self.key, "string").into(),
             },
-            None => todo!(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Hgetrange {
+    #[instrument(name = "storage_hgetrange", skip_all, fields(table = %self.table))]
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        let value = match store.get(&self.table, &self.key) {
+            Ok(None) => return Value::from("").into(),
+            Ok(Some(v)) => v,
+            Err(e) => return e.into(),
+        };
+        let Some(data) = string_bytes(&value) else {
+            return wrong_type(&self.key, "string").into();
+        };
+        let start = match self.offset {
+            offset if offset < 0 => data.len().saturating_sub(offset.unsigned_abs() as usize),
+            offset => (offset as usize).min(data.len()),
+        };
+        let end = match self.len {
+            0 => data.len(),
+            len => start.saturating_add(len as usize).min(data.len()),
+        };
+        let data = &data[start..end];
+        match (&value.value, std::str::from_utf8(data)) {
+            (Some(value::Value::String(_)), Ok(s)) => Value::from(s).into(),
+            _ => Value::from(Bytes::copy_from_slice(data)).into(),
+        }
+    }
+}
+
+impl CommandService for Hsetbit {
+    #[instrument(name = "storage_hsetbit", skip_all, fields(table = %self.table))]
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        if self.offset > MAX_BIT_OFFSET {
+            return KvError::InvalidCommand(format!(
+                "bit offset {} is out of range, max is {}",
+                self.offset, MAX_BIT_OFFSET
+            ))
+            .into();
+        }
+        let (index, mask) = ((self.offset / 8) as usize, 0x80u8 >> (self.offset % 8));
+        let res = store.update(&self.table, &self.key, |current| {
+            let mut bitmap = match current {
+                Some(v) => string_bytes(v)
+                    .ok_or_else(|| wrong_type(&self.key, "string"))?
+                    .to_vec(),
+                None => vec![],
+            };
+            if bitmap.len() <= index {
+                bitmap.resize(index + 1, 0);
+            }
+            let old = bitmap[index] & mask != 0;
+            match self.bit {
+                true => bitmap[index] |= mask,
+                false => bitmap[index] &= !mask,
+            }
+            Ok((Bytes::from(bitmap).into(), old))
+        });
+        match res {
+            Ok(old) => Value::from(old).into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Hgetbit {
+    #[instrument(name = "storage_hgetbit", skip_all, fields(table = %self.table))]
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        let value = match store.get(&self.table, &self.key) {
+            Ok(None) => return Value::from(false).into(),
+            Ok(Some(v)) => v,
+            Err(e) => return e.into(),
+        };
+        let Some(bitmap) = string_bytes(&value) else {
+            return wrong_type(&self.key, "string").into();
+        };
+        let bit = usize::try_from(self.offset / 8)
+            .ok()
+            .and_then(|index| bitmap.get(index))
+            .is_some_and(|byte| byte & (0x80 >> (self.offset % 8)) != 0);
+        Value::from(bit).into()
+    }
+}
+
+impl CommandService for Hbitcount {
+    #[instrument(name = "storage_hbitcount", skip_all, fields(table = %self.table))]
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        match store.get(&self.table, &self.key) {
+            Ok(None) => 0.into(),
+            Ok(Some(v)) => match string_bytes(&v) {
+                Some(bitmap) => {
+                    let count: u64 = bitmap.iter().map(|b| b.count_ones() as u64).sum();
+                    (count as i64).into()
+                }
+                None => wrong_type(&self.key, "string").into(),
+            },
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Hpfadd {
+    #[instrument(name = "storage_hpfadd", skip_all, fields(table = %self.table))]
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        let res = store.update(&self.table, &self.key, |current| {
+            let (mut hll, mut changed) = match current {
+                Some(v) => (
+                    HyperLogLog::from_value(v)
+                        .ok_or_else(|| wrong_type(&self.key, "hyperloglog"))?,
+                    false,
+                ),
+                None => (HyperLogLog::default(), true),
+            };
+            for member in &self.members {
+                changed |= match string_bytes(member) {
+                    Some(data) => hll.add(data),
+                    None => hll.add(&member.encode_to_vec()),
+                };
+            }
+            Ok((hll.into(), changed))
+        });
+        match res {
+            Ok(changed) => (changed as i64).into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Hpfcount {
+    #[instrument(name = "storage_hpfcount", skip_all, fields(table = %self.table))]
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        let mut union = HyperLogLog::default();
+        for key in &self.keys {
+            match store.get(&self.table, key) {
+                Ok(None) => {}
+                Ok(Some(v)) => match HyperLogLog::from_value(&v) {
+                    Some(hll) => union.merge(&hll),
+                    None => return wrong_type(key, "hyperloglog").into(),
+                },
+                Err(e) => return e.into(),
+            }
+        }
+        (union.count() as i64).into()
+    }
+}
+
+impl CommandService for Hscan {
+    #[instrument(name = "storage_hscan", skip_all, fields(table = %self.table))]
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        let count = match self.count {
+            0 => DEFAULT_SCAN_COUNT,
+            n => n,
+        };
+        // 多取一个，用来判断后面是否还有数据
+        let page: Result<Vec<Kvpair>, KvError> = store.get_iter(&self.table).map(|iter| {
+            iter.skip(self.cursor as usize)
+                .take(count as usize + 1)
+                .collect()
+        });
+        match page {
+            Ok(mut pairs) => {
+                let next = if pairs.len() as u64 > count {
+                    pairs.truncate(count as usize);
+                    self.cursor + count
+                } else {
+                    0
+                };
+                let mut res: CommandResponse = pairs.into();
+                res.values = vec![(next as i64).into()];
+                res
+            }
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Hmget {
+    #[instrument(name = "storage_hmget", skip_all, fields(table = %self.table))]
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        // 不存在的 key 返回 Value::default()，保证结果顺序和请求的 keys 一致
+        match store.mget(&self.table, &self.keys) {
+            Ok(values) => values
+                .into_iter()
+                .map(Option::unwrap_or_default)
+                .collect::<Vec<_>>()
+                .into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Hmset {
+    #[instrument(name = "storage_hmset", skip_all, fields(table = %self.table))]
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        match store.mset(&self.table, self.pairs) {
+            Ok(()) => CommandResponse::ok(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Hdel {
+    #[instrument(name = "storage_hdel", skip_all, fields(table = %self.table))]
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        let old = lease::owner(store, &self.table, &self.key).and_then(|owner| {
+            let old = store.del(&self.table, &self.key)?;
+            lease::detach(store, &self.table, &self.key, owner)?;
+            Ok(old)
+        });
+        match old {
+            Ok(Some(v)) => v.into(),
+            Ok(None) => Value::default().into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Hexist {
+    #[instrument(name = "storage_hexist", skip_all, fields(table = %self.table))]
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        match store.contains(&self.table, &self.key) {
+            Ok(v) => Value::from(v).into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Hmdel {
+    #[instrument(name = "storage_hmdel", skip_all, fields(table = %self.table))]
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        // 和 HMGET 一样按请求的顺序返回被删除的值，不存在的 key 返回 Value::default()
+        let values: Result<Vec<_>, KvError> = self
+            .keys
+            .iter()
+            .map(|key| {
+                let owner = lease::owner(store, &self.table, key)?;
+                let old = store.del(&self.table, key)?;
+                lease::detach(store, &self.table, key, owner)?;
+                Ok(old)
+            })
+            .collect();
+        match values {
+            Ok(values) => values
+                .into_iter()
+                .map(Option::unwrap_or_default)
+                .collect::<Vec<_>>()
+                .into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Hmexist {
+    #[instrument(name = "storage_hmexist", skip_all, fields(table = %self.table))]
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        let values: Result<Vec<_>, _> = self
+            .keys
+            .iter()
+            .map(|key| store.contains(&self.table, key).map(Value::from))
+            .collect();
+        match values {
+            Ok(values) => values.into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Hexpire {
+    #[instrument(name = "storage_hexpire", skip_all, fields(table = %self.table))]
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        match store.expire(&self.table, &self.key, Duration::from_secs(self.ttl)) {
+            Ok(v) => Value::from(v).into(),
+            Err(e) => e.into(),
         }
     }
 }
+
+impl CommandService for Httl {
+    #[instrument(name = "storage_httl", skip_all, fields(table = %self.table))]
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        // 和 redis 一样：-2 表示 key 不存在，-1 表示 key 永不过期
+        let ttl = match store.contains(&self.table, &self.key) {
+            Ok(false) => Ok(-2),
+            Ok(true) => store
+                .ttl(&self.table, &self.key)
+                .map(|ttl| ttl.map_or(-1, |d| d.as_secs() as i64)),
+            Err(e) => Err(e),
+        };
+        match ttl {
+            Ok(v) => v.into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Snapshot {
+    #[instrument(name = "storage_snapshot", skip_all, fields(path = %self.path))]
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        match snapshot::dump(store, &self.path) {
+            Ok(n) => Value::from(n as i64).into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Tlist {
+    #[instrument(name = "storage_tlist", skip_all)]
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        match store.tables() {
+            Ok(mut tables) => {
+                tables.sort();
+                tables
+                    .into_iter()
+                    .map(Value::from)
+                    .collect::<Vec<_>>()
+                    .into()
+            }
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Dbsize {
+    #[instrument(name = "storage_dbsize", skip_all)]
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        match store.stats() {
+            Ok(stats) => (stats
+                .iter()
+                .filter(|(table, _)| table.starts_with(&self.namespace))
+                .map(|(_, s)| s.keys)
+                .sum::<u64>() as i64)
+                .into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Tdrop {
+    #[instrument(name = "storage_tdrop", skip_all, fields(table = %self.table))]
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        match store.drop_table(&self.table) {
+            Ok(n) => Value::from(n as i64).into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Ttruncate {
+    #[instrument(name = "storage_ttruncate", skip_all, fields(table = %self.table))]
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        match store.truncate_table(&self.table) {
+            Ok(n) => Value::from(n as i64).into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Lpush {
+    #[instrument(name = "storage_lpush", skip_all, fields(table = %self.table))]
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        let res = store.update(&self.table, &self.key, |current| {
+            let mut list = match current.map(|v| &v.value) {
+                None => ValueList::default(),
+                Some(Some(value::Value::List(list))) => list.clone(),
+                Some(_) => return Err(wrong_type(&self.key, "list")),
+            };
+            for v in &self.values {
+                list.values.insert(0, v.clone());
+            }
+            let len = list.values.len() as i64;
+            Ok((list.into(), len))
+        });
+        match res {
+            Ok(len) => len.into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Lrange {
+    #[instrument(name = "storage_lrange", skip_all, fields(table = %self.table))]
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        // 和 redis 一样，key 不存在时返回空的 list
+        let values = match store.get(&self.table, &self.key) {
+            Ok(None) => vec![],
+            Ok(Some(Value {
+                value: Some(value::Value::List(list)),
+            })) => {
+                let (start, end) = rank_range(list.values.len(), self.start, self.stop);
+                list.values[start..end].to_vec()
+            }
+            Ok(Some(_)) => return wrong_type(&self.key, "list").into(),
+            Err(e) => return e.into(),
+        };
+        values.into()
+    }
+}
+
+impl CommandService for Sadd {
+    #[instrument(name = "storage_sadd", skip_all, fields(table = %self.table))]
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        let res = store.update(&self.table, &self.key, |current| {
+            let mut set = match current.map(|v| &v.value) {
+                None => ValueSet::default(),
+                Some(Some(value::Value::Set(set))) => set.clone(),
+                Some(_) => return Err(wrong_type(&self.key, "set")),
+            };
+            let mut added = 0;
+            for member in &self.members {
+                if !set.members.contains(member) {
+                    set.members.push(member.clone());
+                    added += 1;
+                }
+            }
+            Ok((set.into(), added))
+        });
+        match res {
+            Ok(added) => added.into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Smembers {
+    #[instrument(name = "storage_smembers", skip_all, fields(table = %self.table))]
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        match store.get(&self.table, &self.key) {
+            Ok(None) => Vec::<Value>::new().into(),
+            Ok(Some(Value {
+                value: Some(value::Value::Set(set)),
+            })) => set.members.into(),
+            Ok(Some(_)) => wrong_type(&self.key, "set").into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for MapGet {
+    #[instrument(name = "storage_map_get", skip_all, fields(table = %self.table))]
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        let not_found = || {
+            let key = String::from_utf8_lossy(&self.key);
+            KvError::NotFound(format!("{}:{}/{}", self.table, key, self.path.join("/")))
+        };
+        let mut current = match store.get(&self.table, &self.key) {
+            Ok(Some(v)) => v,
+            Ok(None) => return not_found().into(),
+            Err(e) => return e.into(),
+        };
+        if !matches!(current.value, Some(value::Value::Map(_))) {
+            return wrong_type(&self.key, "map").into();
+        }
+        for field in &self.path {
+            current = match current.value {
+                Some(value::Value::Map(mut map)) => match map.entries.remove(field) {
+                    Some(v) => v,
+                    None => return not_found().into(),
+                },
+                _ => return wrong_type(&self.key, "map").into(),
+            };
+        }
+        current.into()
+    }
+}
+
+impl CommandService for MapSet {
+    #[instrument(name = "storage_map_set", skip_all, fields(table = %self.table))]
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        let Some((last, parents)) = self.path.split_last() else {
+            return KvError::InvalidCommand("path of MAPSET is empty".into()).into();
+        };
+        let value = self.value.clone().unwrap_or_default();
+        let res = store.update(&self.table, &self.key, |current| {
+            let mut root = match current {
+                None => ValueMap::default().into(),
+                Some(v) => v.clone(),
+            };
+            // 沿着 path 找到最后一层 map，中间不存在的 field 创建成空的 map
+            let mut map = as_map(&mut root).ok_or_else(|| wrong_type(&self.key, "map"))?;
+            for field in parents {
+                let next = map
+                    .entries
+                    .entry(field.clone())
+                    .or_insert_with(|| ValueMap::default().into());
+                map = as_map(next).ok_or_else(|| wrong_type(&self.key, "map"))?;
+            }
+            let old = map.entries.insert(last.clone(), value.clone());
+            Ok((root, old.unwrap_or_default()))
+        });
+        match res {
+            Ok(old) => old.into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Zadd {
+    #[instrument(name = "storage_zadd", skip_all, fields(table = %self.table))]
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        match store.zadd(&self.table, &self.key, self.members) {
+            Ok(added) => (added as i64).into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Zrange {
+    #[instrument(name = "storage_zrange", skip_all, fields(table = %self.table))]
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        match store.zrange(&self.table, &self.key, self.start, self.stop) {
+            Ok(members) => members
+                .into_iter()
+                .map(Kvpair::from)
+                .collect::<Vec<_>>()
+                .into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Zrangebyscore {
+    #[instrument(name = "storage_zrangebyscore", skip_all, fields(table = %self.table))]
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        if let Err(e) = check_score(self.min).and_then(|_| check_score(self.max)) {
+            return e.into();
+        }
+        match store.zrange_by_score(&self.table, &self.key, self.min, self.max) {
+            Ok(members) => members
+                .into_iter()
+                .map(Kvpair::from)
+                .collect::<Vec<_>>()
+                .into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Hjsonget {
+    #[instrument(name = "storage_hjsonget", skip_all, fields(table = %self.table))]
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        let path = match json_path::parse(&self.path) {
+            Ok(path) => path,
+            Err(e) => return e.into(),
+        };
+        let not_found = || {
+            let key = String::from_utf8_lossy(&self.key);
+            KvError::NotFound(format!("{}:{} {}", self.table, key, self.path))
+        };
+        let doc = match store.get(&self.table, &self.key) {
+            Ok(Some(v)) => v,
+            Ok(None) => return not_found().into(),
+            Err(e) => return e.into(),
+        };
+        let doc = match as_json(&self.key, &doc) {
+            Ok(doc) => doc,
+            Err(e) => return e.into(),
+        };
+        match json_path::get(&doc, &path) {
+            Some(v) => Value::from(v.clone()).into(),
+            None => not_found().into(),
+        }
+    }
+}
+
+impl CommandService for Hjsonset {
+    #[instrument(name = "storage_hjsonset", skip_all, fields(table = %self.table))]
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        let path = match json_path::parse(&self.path) {
+            Ok(path) => path,
+            Err(e) => return e.into(),
+        };
+        let value: serde_json::Value = match serde_json::from_str(&self.value) {
+            Ok(value) => value,
+            Err(e) => return KvError::InvalidCommand(format!("invalid JSON: {}", e)).into(),
+        };
+        let not_found = || {
+            let key = String::from_utf8_lossy(&self.key);
+            KvError::NotFound(format!("{}:{} {}", self.table, key, self.path))
+        };
+        let res = store.update(&self.table, &self.key, |current| {
+            let mut doc = match current {
+                Some(v) => as_json(&self.key, v)?,
+                // 新的文档只能整个设置
+                None if path.is_empty() => serde_json::Value::Null,
+                None => return Err(not_found()),
+            };
+            let old = json_path::set(&mut doc, &path, value.clone()).ok_or_else(not_found)?;
+            let old = match current {
+                Some(_) => old.map(Value::from).unwrap_or_default(),
+                None => Value::default(),
+            };
+            Ok((doc.into(), old))
+        });
+        match res {
+            Ok(old) => old.into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for CreateIndex {
+    #[instrument(name = "storage_create_index", skip_all, fields(table = %self.table))]
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        let Some(spec) = self.index else {
+            return KvError::InvalidCommand("CREATEINDEX without index".into()).into();
+        };
+        match store.create_index(&self.table, spec) {
+            Ok(created) => Value::from(created).into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Query {
+    #[instrument(name = "storage_query", skip_all, fields(table = %self.table))]
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        let value = self.value.unwrap_or_default();
+        match store.query(&self.table, &self.index, &value) {
+            Ok(pairs) => pairs.into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+/// 解析保存的 JSON 文档，不是 JSON 的 value 返回错误
+fn as_json(key: &[u8], v: &Value) -> Result<serde_json::Value, KvError> {
+    match &v.value {
+        Some(value::Value::Json(text)) => serde_json::from_str(text)
+            .map_err(|e| KvError::Internal(format!("broken JSON document: {}", e))),
+        _ => Err(wrong_type(key, "JSON document")),
+    }
+}
+
+fn as_map(v: &mut Value) -> Option<&mut ValueMap> {
+    match &mut v.value {
+        Some(value::Value::Map(map)) => Some(map),
+        _ => None,
+    }
+}
+
+/// string 和 binary 的内容，其它类型返回 None
+fn string_bytes(value: &Value) -> Option<&[u8]> {
+    match &value.value {
+        Some(value::Value::String(s)) => Some(s.as_bytes()),
+        Some(value::Value::Binary(b)) => Some(b),
+        _ => None,
+    }
+}
+
+fn wrong_type(key: &[u8], kind: &str) -> KvError {
+    let key = String::from_utf8_lossy(key);
+    KvError::InvalidCommand(format!("value of {} is not a {}", key, kind))
+}
@@ -0,0 +1,199 @@
+//! 压缩存储：COMPACT 命令手动压缩；配置了 compaction 时后台定期检查，
+//! 距离上次压缩超过了配置的间隔，或者存储占用的磁盘空间超过了上限时压缩
+
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use tokio::{task::JoinHandle, time};
+use tracing::{info, warn};
+
+use super::Service;
+use crate::{
+    config::{parse_size, CompactionConfig},
+    error::KvError,
+    pb::abi::{CommandResponse, Value},
+    Storage,
+};
+
+/// 默认每分钟检查一次是否需要压缩
+const DEFAULT_CHECK_INTERVAL: u64 = 60;
+
+/// 后台压缩的时机
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CompactionSchedule {
+    /// 距离上次压缩超过这个间隔时压缩
+    pub interval: Option<Duration>,
+    /// 存储占用的磁盘空间超过这个字节数时压缩
+    pub max_disk_usage: Option<u64>,
+    pub check_interval: Duration,
+}
+
+impl CompactionSchedule {
+    pub fn from_config(config: &CompactionConfig) -> Result<Self, KvError> {
+        let max_disk_usage = match &config.max_disk_usage {
+            Some(size) => Some(parse_size(size)? as u64),
+            None => None,
+        };
+        let check_interval = config.check_interval.unwrap_or(DEFAULT_CHECK_INTERVAL);
+        if check_interval == 0 {
+            return Err(KvError::InvalidCommand(
+                "compaction check_interval must be greater than 0".into(),
+            ));
+        }
+        Ok(Self {
+            interval: config.interval.map(Duration::from_secs),
+            max_disk_usage,
+            check_interval: Duration::from_secs(check_interval),
+        })
+    }
+}
+
+impl<Store: Storage> Service<Store> {
+    /// COMPACT：压缩存储，返回回收的字节数
+    pub(super) fn compact(&self) -> CommandResponse {
+        match self.store.compact() {
+            Ok(reclaimed) => {
+                info!("Compacted storage, {} bytes reclaimed", reclaimed);
+                Value::from(reclaimed as i64).into()
+            }
+            Err(e) => e.into(),
+        }
+    }
+
+    /// 启动后台任务，按 schedule 压缩存储；Service 全部 drop 之后任务自动退出
+    pub fn spawn_compaction_task(&self, schedule: CompactionSchedule) -> JoinHandle<()> {
+        let inner = Arc::downgrade(&self.inner);
+        tokio::spawn(async move {
+            let mut ticker = time::interval(schedule.check_interval);
+            // 第一次 tick 立即返回，跳过它
+            ticker.tick().await;
+            let mut last = Instant::now();
+            // 压缩之后仍然超过上限时，等磁盘空间再变化之后才重新压缩，避免反复压缩
+            let mut compacted_usage = None;
+            loop {
+                ticker.tick().await;
+                let Some(inner) = inner.upgrade() else {
+                    break;
+                };
+                let usage = inner.store.disk_usage().unwrap_or_else(|e| {
+                    warn!("Failed to get disk usage of storage: {:?}", e);
+                    None
+                });
+                let due = schedule.interval.is_some_and(|i| last.elapsed() >= i);
+                let full = match (schedule.max_disk_usage, usage) {
+                    (Some(max), Some(used)) => used > max && Some(used) != compacted_usage,
+                    _ => false,
+                };
+                if !due && !full {
+                    continue;
+                }
+                last = Instant::now();
+                // 压缩要读写整个存储，放到阻塞线程上执行
+                let res = tokio::task::spawn_blocking(move || {
+                    let reclaimed = inner.store.compact()?;
+                    Ok::<_, KvError>((reclaimed, inner.store.disk_usage()?))
+                })
+                .await;
+                match res {
+                    Ok(Ok((reclaimed, usage))) => {
+                        info!("Compacted storage, {} bytes reclaimed", reclaimed);
+                        compacted_usage = usage;
+                    }
+                    Ok(Err(e)) => warn!("Failed to compact storage: {:?}", e),
+                    Err(e) => warn!("Compaction task panicked: {:?}", e),
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod compaction_tests {
+    use futures::StreamExt;
+    use tempfile::tempdir;
+
+    use super::*;
+    use crate::{
+        config::FsyncPolicy, pb::abi::CommandRequest, service_builder::ServiceBuilder,
+        storage::wal::WalMemTable,
+    };
+
+    fn wal_service(path: &std::path::Path) -> Service<WalMemTable> {
+        let store = WalMemTable::open(path.join("kv.wal"), FsyncPolicy::No).unwrap();
+        ServiceBuilder::new(store).finish()
+    }
+
+    async fn overwrite(service: &Service<WalMemTable>, times: i64) {
+        for i in 0..times {
+            let cmd = CommandRequest::new_hset("t1", "k1", i);
+            service.execute(cmd).next().await.unwrap();
+        }
+    }
+
+    #[test]
+    fn schedule_should_be_parsed_from_config() {
+        let config = CompactionConfig {
+            interval: Some(3600),
+            max_disk_usage: Some("1KB".into()),
+            check_interval: None,
+        };
+        let schedule = CompactionSchedule::from_config(&config).unwrap();
+        assert_eq!(schedule.interval, Some(Duration::from_secs(3600)));
+        assert_eq!(schedule.max_disk_usage, Some(1024));
+        assert_eq!(schedule.check_interval, Duration::from_secs(60));
+
+        let config = CompactionConfig {
+            check_interval: Some(0),
+            ..Default::default()
+        };
+        assert!(CompactionSchedule::from_config(&config).is_err());
+    }
+
+    #[tokio::test]
+    async fn compact_command_should_report_reclaimed_bytes() {
+        let dir = tempdir().unwrap();
+        let service = wal_service(dir.path());
+        overwrite(&service, 100).await;
+
+        let res = service.execute(CommandRequest::new_compact());
+        let res = res.into_future().await.0.unwrap();
+        assert_eq!(res.status, 200);
+        let reclaimed = res.values[0].to_integer().unwrap();
+        assert!(reclaimed > 0);
+
+        // 没有可以回收的空间了
+        let res = service.execute(CommandRequest::new_compact());
+        let res = res.into_future().await.0.unwrap();
+        assert_eq!(res.values, [Value::from(0)]);
+
+        // 不在磁盘上的存储什么都不做
+        let service: Service = ServiceBuilder::default().finish();
+        let res = service.execute(CommandRequest::new_compact());
+        let res = res.into_future().await.0.unwrap();
+        assert_eq!(res.values, [Value::from(0)]);
+    }
+
+    #[tokio::test]
+    async fn compaction_task_should_compact_when_disk_usage_is_over_limit() {
+        let dir = tempdir().unwrap();
+        let service = wal_service(dir.path());
+        overwrite(&service, 100).await;
+        let before = service.store.disk_usage().unwrap().unwrap();
+
+        let schedule = CompactionSchedule {
+            interval: None,
+            max_disk_usage: Some(before / 2),
+            check_interval: Duration::from_millis(10),
+        };
+        let handle = service.spawn_compaction_task(schedule);
+        time::sleep(Duration::from_millis(100)).await;
+        assert!(service.store.disk_usage().unwrap().unwrap() < before / 2);
+
+        // service 被 drop 之后，后台任务会退出
+        drop(service);
+        time::sleep(Duration::from_millis(50)).await;
+        assert!(handle.is_finished());
+    }
+}
@@ -0,0 +1,219 @@
+use super::{acl::pattern_matches, Service};
+use crate::{
+    config::parse_size,
+    error::KvError,
+    frame::{max_frame_size, set_max_frame_size},
+    pb::abi::{CommandResponse, Kvpair},
+    storage::eviction::MemoryLimit,
+    telemetry, Storage,
+};
+
+/// CONFIG GET/SET 可以查看和修改的参数
+const PARAMETERS: &[&str] = &[
+    "log-level",
+    "max-frame-size",
+    "max-memory",
+    "eviction-policy",
+    "read-only",
+];
+
+impl<Store: Storage> Service<Store> {
+    /// 处理 CONFIG GET：返回名字匹配 pattern 的参数
+    pub(super) fn config_get(&self, pattern: &str) -> CommandResponse {
+        let pattern = pattern.to_ascii_lowercase();
+        let pairs: Vec<Kvpair> = PARAMETERS
+            .iter()
+            .filter(|name| pattern_matches(&pattern, name))
+            .filter_map(|name| Some(Kvpair::new(*name, self.parameter(name)?.into())))
+            .collect();
+        pairs.into()
+    }
+
+    /// 处理 CONFIG SET：值不合法时不做任何修改
+    pub(super) fn config_set(&self, name: &str, value: &str) -> Result<(), KvError> {
+        match name.to_ascii_lowercase().as_str() {
+            "log-level" => telemetry::set_log_level(value),
+            "max-frame-size" => match parse_size(value)? {
+                0 => Err(KvError::InvalidCommand(
+                    "max-frame-size must be positive".into(),
+                )),
+                size => {
+                    set_max_frame_size(size);
+                    Ok(())
+                }
+            },
+            "max-memory" => {
+                self.memory_limit()?.set_max_memory(parse_size(value)?);
+                Ok(())
+            }
+            "eviction-policy" => {
+                self.memory_limit()?.set_policy(value.parse()?);
+                Ok(())
+            }
+            "read-only" => self.set_read_only(parse_bool(value)?),
+            _ => Err(KvError::InvalidCommand(format!(
+                "unknown config parameter {}",
+                name
+            ))),
+        }
+    }
+
+    /// 参数当前的值，store 不支持的参数返回 None
+    fn parameter(&self, name: &str) -> Option<String> {
+        match name {
+            "log-level" => Some(telemetry::log_level().unwrap_or_default()),
+            "max-frame-size" => Some(max_frame_size().to_string()),
+            "max-memory" => Some(self.store.memory_limit()?.max_memory().to_string()),
+            "eviction-policy" => Some(self.store.memory_limit()?.policy().as_str().into()),
+            "read-only" => Some(if self.is_read_only() { "yes" } else { "no" }.into()),
+            _ => None,
+        }
+    }
+
+    fn memory_limit(&self) -> Result<&MemoryLimit, KvError> {
+        self.store
+            .memory_limit()
+            .ok_or_else(|| KvError::InvalidCommand("storage doesn't support memory limit".into()))
+    }
+}
+
+/// 和 redis 一样接受 yes/no，也接受 true/false
+fn parse_bool(value: &str) -> Result<bool, KvError> {
+    match value.to_ascii_lowercase().as_str() {
+        "yes" | "true" => Ok(true),
+        "no" | "false" => Ok(false),
+        _ => Err(KvError::InvalidCommand(format!(
+            "invalid boolean {}, expected yes or no",
+            value
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod config_service_tests {
+    use futures::StreamExt;
+
+    use crate::{
+        assert_res_error, frame::DEFAULT_MAX_FRAME_SIZE, pb::abi::CommandRequest,
+        service_builder::ServiceBuilder, Service,
+    };
+
+    #[tokio::test]
+    async fn config_get_and_set_should_work() {
+        let service: Service = ServiceBuilder::default().finish();
+        let execute = |cmd: CommandRequest| {
+            let service = service.clone();
+            async move { service.execute(cmd).next().await.unwrap() }
+        };
+
+        let res = execute(CommandRequest::new_config_get("*")).await;
+        let names: Vec<&str> = res
+            .pairs
+            .iter()
+            .map(|p| std::str::from_utf8(&p.key).unwrap())
+            .collect();
+        assert_eq!(
+            names,
+            [
+                "log-level",
+                "max-frame-size",
+                "max-memory",
+                "eviction-policy",
+                "read-only"
+            ]
+        );
+
+        // 比默认值大，不影响同时运行的其它测试
+        let size = (DEFAULT_MAX_FRAME_SIZE * 2).to_string();
+        let res = execute(CommandRequest::new_config_set("MAX-FRAME-SIZE", &size)).await;
+        assert_eq!(res.status, 200);
+        let res = execute(CommandRequest::new_config_get("max-frame*")).await;
+        assert_eq!(res.pairs[0].value, Some(size.as_str().into()));
+        let default = DEFAULT_MAX_FRAME_SIZE.to_string();
+        execute(CommandRequest::new_config_set("max-frame-size", default)).await;
+
+        let res = execute(CommandRequest::new_config_set("max-frame-size", "-1")).await;
+        assert_res_error(&res, 400, "invalid size");
+        let res = execute(CommandRequest::new_config_set("max-frame-size", "0")).await;
+        assert_res_error(&res, 400, "must be positive");
+        let res = execute(CommandRequest::new_config_set("no-such-thing", "1")).await;
+        assert_res_error(&res, 400, "unknown config parameter");
+    }
+
+    #[tokio::test]
+    async fn config_set_max_memory_should_evict_keys() {
+        let service: Service = ServiceBuilder::default().finish();
+        let execute = |cmd: CommandRequest| {
+            let service = service.clone();
+            async move { service.execute(cmd).next().await.unwrap() }
+        };
+
+        execute(CommandRequest::new_config_set("max-memory", "1kb")).await;
+        for i in 0..10 {
+            let value = "v".repeat(100);
+            execute(CommandRequest::new_hset("t1", format!("k{}", i), value)).await;
+        }
+        // 默认拒绝写入
+        let res = execute(CommandRequest::new_hset("t1", "k10", "v")).await;
+        assert_res_error(&res, 507, "Out of memory");
+
+        execute(CommandRequest::new_config_set("eviction-policy", "LRU")).await;
+        let res = execute(CommandRequest::new_config_get("eviction-policy")).await;
+        assert_eq!(res.pairs[0].value, Some("lru".into()));
+        let res = execute(CommandRequest::new_hset("t1", "k10", "v")).await;
+        assert_eq!(res.status, 200);
+        let res = execute(CommandRequest::new_hget("t1", "k0")).await;
+        assert_eq!(res.status, 404);
+
+        let res = execute(CommandRequest::new_config_set("eviction-policy", "ttl")).await;
+        assert_res_error(&res, 400, "unknown eviction policy");
+    }
+
+    #[tokio::test]
+    async fn config_set_read_only_should_reject_writes() {
+        let service: Service = ServiceBuilder::default().finish();
+        let execute = |cmd: CommandRequest| {
+            let service = service.clone();
+            async move { service.execute(cmd).next().await.unwrap() }
+        };
+
+        let res = execute(CommandRequest::new_config_set("read-only", "maybe")).await;
+        assert_res_error(&res, 400, "invalid boolean");
+        execute(CommandRequest::new_config_set("read-only", "yes")).await;
+        let res = execute(CommandRequest::new_config_get("read-only")).await;
+        assert_eq!(res.pairs[0].value, Some("yes".into()));
+        let res = execute(CommandRequest::new_hset("t1", "k1", "v1")).await;
+        assert_res_error(&res, 403, "read-only mode");
+        // batch 里的写命令同样被拒绝，读命令不受影响
+        let batch = CommandRequest::new_batch([
+            CommandRequest::new_hget("t1", "k1"),
+            CommandRequest::new_hdel("t1", "k1"),
+        ]);
+        assert_eq!(execute(batch).await.status, 403);
+        assert_eq!(
+            execute(CommandRequest::new_hget("t1", "k1")).await.status,
+            404
+        );
+
+        execute(CommandRequest::new_config_set("read-only", "no")).await;
+        let res = execute(CommandRequest::new_hset("t1", "k1", "v1")).await;
+        assert_eq!(res.status, 200);
+    }
+
+    #[tokio::test]
+    async fn replica_should_stay_read_only() {
+        let service: Service = ServiceBuilder::default().replica(true).finish();
+        let res = service
+            .execute(CommandRequest::new_config_set("read-only", "no"))
+            .next()
+            .await
+            .unwrap();
+        assert_res_error(&res, 400, "always read-only");
+        let res = service
+            .execute(CommandRequest::new_hset("t1", "k1", "v1"))
+            .next()
+            .await
+            .unwrap();
+        assert_res_error(&res, 403, "replica is read-only");
+    }
+}
@@ -0,0 +1,268 @@
+//! 事件 hook：嵌入 kv-sv 的程序可以在连接建立和断开、收到命令、命令执行完成和出错时得到通知，
+//! 用来记录日志、把修改同步到别的系统或者让缓存失效。
+//!
+//! 同步的 hook 在处理命令的线程上直接调用，应该尽快返回；异步的 hook 返回的 future 放到 tokio 上执行，
+//! 不阻塞命令，也不保证执行的顺序
+
+use std::{
+    future::Future,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use futures::{future::BoxFuture, FutureExt, StreamExt};
+use tracing::debug;
+
+use super::{clients::ClientGuard, session::Session, topic_service::StreamingResponse, Service};
+use crate::{
+    pb::abi::{CommandRequest, CommandResponse},
+    Storage,
+};
+
+/// 触发 hook 的事件
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+    /// 客户端建立了连接
+    Connect { peer: Option<String> },
+    /// 收到命令，还没有执行
+    Command {
+        peer: Option<String>,
+        user: Option<String>,
+        request: CommandRequest,
+    },
+    /// 命令执行完成；流式的命令在第一个响应返回时触发，elapsed 是从收到命令到得到第一个响应的时间
+    Executed {
+        peer: Option<String>,
+        user: Option<String>,
+        request: CommandRequest,
+        response: CommandResponse,
+        elapsed: Duration,
+    },
+    /// 命令执行出错，状态码不是 2xx；在 Executed 之后触发
+    Error {
+        peer: Option<String>,
+        user: Option<String>,
+        request: CommandRequest,
+        response: CommandResponse,
+    },
+    /// 客户端断开了连接
+    Disconnect { peer: Option<String> },
+}
+
+/// 一个同步或者异步的 hook
+#[derive(Clone)]
+pub enum Hook {
+    Sync(Arc<dyn Fn(&Event) + Send + Sync>),
+    Async(Arc<dyn Fn(Event) -> BoxFuture<'static, ()> + Send + Sync>),
+}
+
+impl Hook {
+    pub fn new(f: impl Fn(&Event) + Send + Sync + 'static) -> Self {
+        Self::Sync(Arc::new(f))
+    }
+
+    pub fn new_async<F, Fut>(f: F) -> Self
+    where
+        F: Fn(Event) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        Self::Async(Arc::new(move |event| f(event).boxed()))
+    }
+
+    fn call(&self, event: &Event) {
+        match self {
+            Self::Sync(f) => f(event),
+            // 没有 tokio runtime 时（比如在同步的测试中直接调用 Service）无法执行异步的 hook
+            Self::Async(f) => match tokio::runtime::Handle::try_current() {
+                Ok(runtime) => {
+                    runtime.spawn(f(event.clone()));
+                }
+                Err(_) => debug!("No tokio runtime, skip async hook for {:?}", event),
+            },
+        }
+    }
+}
+
+impl<F> From<F> for Hook
+where
+    F: Fn(&Event) + Send + Sync + 'static,
+{
+    fn from(f: F) -> Self {
+        Self::new(f)
+    }
+}
+
+/// 按事件注册的 hook
+#[derive(Clone, Default)]
+pub struct Hooks {
+    pub(super) connect: Vec<Hook>,
+    pub(super) command: Vec<Hook>,
+    pub(super) executed: Vec<Hook>,
+    pub(super) error: Vec<Hook>,
+    pub(super) disconnect: Vec<Hook>,
+}
+
+/// 只有注册了 hook 时才构造事件，没有 hook 时不需要复制命令和响应
+fn fire(hooks: &[Hook], event: impl FnOnce() -> Event) {
+    if hooks.is_empty() {
+        return;
+    }
+    let event = event();
+    for hook in hooks {
+        hook.call(&event);
+    }
+}
+
+/// 连接断开时 drop：从 CLIENT LIST 中移除，然后触发 Disconnect
+pub struct ConnectionGuard {
+    _client: ClientGuard,
+    hooks: Vec<Hook>,
+    peer: Option<String>,
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        let peer = self.peer.take();
+        fire(&self.hooks, || Event::Disconnect { peer });
+    }
+}
+
+impl<Store: Storage> Service<Store> {
+    /// 注册一个新的连接并触发 Connect；连接断开时 drop 返回的 guard
+    pub fn connect(&self, session: &Arc<Session>) -> ConnectionGuard {
+        let client = self.clients.register(session);
+        let peer = session.peer().map(ToOwned::to_owned);
+        fire(&self.hooks.connect, || Event::Connect {
+            peer: peer.clone(),
+        });
+        ConnectionGuard {
+            _client: client,
+            hooks: self.hooks.disconnect.clone(),
+            peer,
+        }
+    }
+
+    /// 触发 Command，然后在第一个响应返回时触发 Executed 和 Error
+    pub(super) fn execute_hooked(
+        &self,
+        cmd: CommandRequest,
+        session: &Session,
+    ) -> StreamingResponse {
+        let hooks = &self.hooks;
+        let peer = session.peer().map(ToOwned::to_owned);
+        let user = session.user();
+        fire(&hooks.command, || Event::Command {
+            peer: peer.clone(),
+            user: user.clone(),
+            request: cmd.clone(),
+        });
+        if hooks.executed.is_empty() && hooks.error.is_empty() {
+            return self.execute_middlewares(cmd, session);
+        }
+
+        let start = Instant::now();
+        let request = cmd.clone();
+        let (executed, error) = (hooks.executed.clone(), hooks.error.clone());
+        let mut first = true;
+        let res = self.execute_middlewares(cmd, session);
+        Box::pin(res.inspect(move |response| {
+            if !std::mem::take(&mut first) {
+                return;
+            }
+            fire(&executed, || Event::Executed {
+                peer: peer.clone(),
+                user: user.clone(),
+                request: request.clone(),
+                response: (**response).clone(),
+                elapsed: start.elapsed(),
+            });
+            if !(200..300).contains(&response.status) {
+                fire(&error, || Event::Error {
+                    peer: peer.clone(),
+                    user: user.clone(),
+                    request: request.clone(),
+                    response: (**response).clone(),
+                });
+            }
+        }))
+    }
+}
+
+#[cfg(test)]
+mod hooks_tests {
+    use std::sync::Mutex;
+
+    use tokio::sync::mpsc;
+
+    use super::*;
+    use crate::{memory::MemTable, service_builder::ServiceBuilder};
+
+    #[tokio::test]
+    async fn hooks_should_be_called() {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let recorded = events.clone();
+        let service: Service = ServiceBuilder::new(MemTable::new())
+            .on_connect(|e: &Event| assert!(matches!(e, Event::Connect { .. })))
+            .on_command(move |e: &Event| recorded.lock().unwrap().push(e.clone()))
+            .on_error(Hook::new_async(move |e| {
+                let tx = tx.clone();
+                async move { tx.send(e).unwrap() }
+            }))
+            .finish();
+
+        let session = Arc::new(Session::default().with_peer("127.0.0.1:1234"));
+        let guard = service.connect(&session);
+        service
+            .execute_with(CommandRequest::new_hset("t1", "k1", "v1"), &session)
+            .next()
+            .await;
+        service
+            .execute_with(CommandRequest::new_hincrby("t1", "k1", 1), &session)
+            .next()
+            .await;
+        drop(guard);
+
+        let events = events.lock().unwrap().clone();
+        assert_eq!(events.len(), 2);
+        assert!(matches!(
+            &events[0],
+            Event::Command { peer: Some(peer), .. } if peer == "127.0.0.1:1234"
+        ));
+        // 只有出错的 HINCRBY 触发 Error
+        match rx.recv().await.unwrap() {
+            Event::Error {
+                request, response, ..
+            } => {
+                assert_eq!(request, CommandRequest::new_hincrby("t1", "k1", 1));
+                assert_eq!(response.status, 400);
+            }
+            e => panic!("unexpected event {:?}", e),
+        }
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn executed_hook_should_see_responses() {
+        let responses = Arc::new(Mutex::new(Vec::new()));
+        let disconnected = Arc::new(Mutex::new(false));
+        let (recorded, flag) = (responses.clone(), disconnected.clone());
+        let service: Service = ServiceBuilder::new(MemTable::new())
+            .on_executed(move |e: &Event| {
+                if let Event::Executed { response, .. } = e {
+                    recorded.lock().unwrap().push(response.status);
+                }
+            })
+            .on_disconnect(move |_: &Event| *flag.lock().unwrap() = true)
+            .finish();
+
+        let session = Arc::new(Session::default());
+        let guard = service.connect(&session);
+        let cmd = CommandRequest::new_hget("t1", "none");
+        service.execute_with(cmd, &session).next().await;
+        assert_eq!(*responses.lock().unwrap(), [404]);
+        assert!(!*disconnected.lock().unwrap());
+        drop(guard);
+        assert!(*disconnected.lock().unwrap());
+    }
+}
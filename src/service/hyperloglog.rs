@@ -0,0 +1,125 @@
+use bytes::Bytes;
+
+use super::proxy::hash;
+use crate::pb::abi::{value, Value};
+
+/// 编码的开头，用来区分 HyperLogLog 和普通的 binary
+const MAGIC: &[u8; 4] = b"HYLL";
+/// 用 hash 的低 14 位选择寄存器，标准误差约为 1.04 / sqrt(16384) = 0.81%
+const PRECISION: u32 = 14;
+const REGISTERS: usize = 1 << PRECISION;
+
+/// 每个寄存器记录落到它上面的 hash 中（去掉低 PRECISION 位之后）末尾 0 的最大个数加一。
+/// 编码成 MAGIC 加上每个寄存器一个字节，一共 16KB 左右，存成 Value::Binary
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct HyperLogLog {
+    registers: Vec<u8>,
+}
+
+impl Default for HyperLogLog {
+    fn default() -> Self {
+        Self {
+            registers: vec![0; REGISTERS],
+        }
+    }
+}
+
+impl HyperLogLog {
+    /// 不是 HyperLogLog 编码的 value 返回 None
+    pub fn from_value(value: &Value) -> Option<Self> {
+        match &value.value {
+            Some(value::Value::Binary(data))
+                if data.len() == MAGIC.len() + REGISTERS && data.starts_with(MAGIC) =>
+            {
+                Some(Self {
+                    registers: data[MAGIC.len()..].to_vec(),
+                })
+            }
+            _ => None,
+        }
+    }
+
+    /// 加入一个 member，返回是否有寄存器变化
+    pub fn add(&mut self, member: &[u8]) -> bool {
+        let h = hash(member);
+        let index = (h & (REGISTERS as u64 - 1)) as usize;
+        // 最高位补一个 1，rank 最大为 64 - PRECISION + 1
+        let rest = (h >> PRECISION) | (1 << (64 - PRECISION));
+        let rank = rest.trailing_zeros() as u8 + 1;
+        if rank > self.registers[index] {
+            self.registers[index] = rank;
+            return true;
+        }
+        false
+    }
+
+    /// 合并另一个 HyperLogLog，之后的估计值是两者并集的基数
+    pub fn merge(&mut self, other: &Self) {
+        self.registers
+            .iter_mut()
+            .zip(&other.registers)
+            .for_each(|(a, b)| *a = (*a).max(*b));
+    }
+
+    /// 估计加入过的不同 member 的个数；基数较小时用线性计数修正
+    pub fn count(&self) -> u64 {
+        let m = REGISTERS as f64;
+        let alpha = 0.7213 / (1.0 + 1.079 / m);
+        let sum: f64 = self.registers.iter().map(|&r| 2f64.powi(-(r as i32))).sum();
+        let estimate = alpha * m * m / sum;
+        let zeros = self.registers.iter().filter(|&&r| r == 0).count();
+        if estimate <= 2.5 * m && zeros > 0 {
+            return (m * (m / zeros as f64).ln()).round() as u64;
+        }
+        estimate.round() as u64
+    }
+}
+
+impl From<HyperLogLog> for Value {
+    fn from(hll: HyperLogLog) -> Self {
+        let mut data = Vec::with_capacity(MAGIC.len() + REGISTERS);
+        data.extend_from_slice(MAGIC);
+        data.extend_from_slice(&hll.registers);
+        Bytes::from(data).into()
+    }
+}
+
+#[cfg(test)]
+mod hyperloglog_tests {
+    use super::*;
+
+    #[test]
+    fn hyperloglog_should_estimate_cardinality() {
+        let mut hll = HyperLogLog::default();
+        assert_eq!(hll.count(), 0);
+        assert!(hll.add(b"a"));
+        assert!(!hll.add(b"a"));
+        assert_eq!(hll.count(), 1);
+
+        for n in [1_000u64, 100_000] {
+            let mut hll = HyperLogLog::default();
+            (0..n).for_each(|i| {
+                hll.add(format!("user-{}", i).as_bytes());
+            });
+            let error = (hll.count() as f64 - n as f64).abs() / n as f64;
+            assert!(error < 0.03, "n = {}, count = {}", n, hll.count());
+        }
+    }
+
+    #[test]
+    fn hyperloglog_should_merge_and_encode() {
+        let (mut a, mut b) = (HyperLogLog::default(), HyperLogLog::default());
+        (0..1000).for_each(|i| {
+            a.add(format!("a-{}", i).as_bytes());
+            b.add(format!("b-{}", i).as_bytes());
+            b.add(format!("a-{}", i).as_bytes());
+        });
+        a.merge(&b);
+        let error = (a.count() as f64 - 2000.0).abs() / 2000.0;
+        assert!(error < 0.03);
+
+        let value: Value = a.clone().into();
+        assert_eq!(HyperLogLog::from_value(&value), Some(a));
+        assert_eq!(HyperLogLog::from_value(&"HYLL".into()), None);
+    }
+}
@@ -0,0 +1,298 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    future::Future,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use tracing::debug;
+
+use super::acl::required_access;
+use crate::{
+    config::{IdempotencyConfig, Verb},
+    error::KvError,
+    pb::abi::{command_request::RequestData, CommandRequest, CommandResponse},
+};
+
+/// 默认保留 60 秒
+const DEFAULT_TTL: Duration = Duration::from_secs(60);
+
+/// 默认最多保留的结果数
+const DEFAULT_MAX_KEYS: usize = 100000;
+
+/// 带幂等 key 的修改命令的结果：同一个用户用同一个 key 重试时返回第一次执行的结果。
+/// 结果只保存在内存中，超过 ttl 或者数量超过 max_keys 之后丢弃
+#[derive(Debug)]
+pub struct IdempotencyCache {
+    ttl: Duration,
+    max_keys: usize,
+    state: Mutex<State>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    /// <(user, 幂等 key), 结果>
+    entries: HashMap<(String, String), Entry>,
+    /// 按执行完的顺序排列的 key，用来丢弃过期的和最早的结果
+    order: VecDeque<(Instant, (String, String))>,
+}
+
+#[derive(Debug)]
+enum Entry {
+    /// 第一次执行还没有结束
+    Pending,
+    Done(Instant, CommandResponse),
+}
+
+impl Default for IdempotencyCache {
+    fn default() -> Self {
+        Self::new(IdempotencyConfig::default())
+    }
+}
+
+impl IdempotencyCache {
+    pub fn new(config: IdempotencyConfig) -> Self {
+        Self {
+            ttl: config.ttl.map_or(DEFAULT_TTL, Duration::from_secs),
+            max_keys: config.max_keys.unwrap_or(DEFAULT_MAX_KEYS),
+            state: Default::default(),
+        }
+    }
+
+    /// 执行命令 f；命令带了幂等 key 并且会修改数据时，重试直接返回之前的结果，
+    /// 第一次执行还没有结束时返回 409
+    pub fn execute(
+        &self,
+        cmd: &CommandRequest,
+        user: Option<String>,
+        f: impl FnOnce() -> CommandResponse,
+    ) -> CommandResponse {
+        match self.begin(cmd, user) {
+            Begin::Run(pending) => pending.finish(f()),
+            Begin::Replay(res) => res,
+        }
+    }
+
+    /// 和 execute 一样，f 是异步的；f 被取消时不保存结果，重试会重新执行
+    pub async fn execute_async<F>(
+        &self,
+        cmd: &CommandRequest,
+        user: Option<String>,
+        f: impl FnOnce() -> F,
+    ) -> CommandResponse
+    where
+        F: Future<Output = CommandResponse>,
+    {
+        match self.begin(cmd, user) {
+            Begin::Run(pending) => pending.finish(f().await),
+            Begin::Replay(res) => res,
+        }
+    }
+
+    fn begin(&self, cmd: &CommandRequest, user: Option<String>) -> Begin<'_> {
+        if cmd.idempotency_key.is_empty() || !is_write(cmd) {
+            return Begin::Run(Pending {
+                cache: self,
+                id: None,
+            });
+        }
+        let id = (user.unwrap_or_default(), cmd.idempotency_key.clone());
+        let mut state = self.state.lock().unwrap();
+        state.purge(Instant::now());
+        match state.entries.get(&id) {
+            Some(Entry::Done(_, res)) => {
+                debug!("Replay response of idempotency key {}", id.1);
+                Begin::Replay(res.clone())
+            }
+            Some(Entry::Pending) => Begin::Replay(
+                KvError::Conflict(format!(
+                    "request with idempotency key {} is in progress",
+                    id.1
+                ))
+                .into(),
+            ),
+            None => {
+                state.entries.insert(id.clone(), Entry::Pending);
+                Begin::Run(Pending {
+                    cache: self,
+                    id: Some(id),
+                })
+            }
+        }
+    }
+}
+
+enum Begin<'a> {
+    /// 需要执行命令
+    Run(Pending<'a>),
+    /// 之前的结果，或者第一次执行还没有结束时的 409
+    Replay(CommandResponse),
+}
+
+/// 正在执行的命令；没有保存结果就被 drop（命令 panic 或者被取消）时删掉 Pending，
+/// 否则之后的重试会一直返回 409
+struct Pending<'a> {
+    cache: &'a IdempotencyCache,
+    id: Option<(String, String)>,
+}
+
+impl Pending<'_> {
+    fn finish(mut self, res: CommandResponse) -> CommandResponse {
+        let Some(id) = self.id.take() else {
+            return res;
+        };
+        let expire = Instant::now() + self.cache.ttl;
+        let mut state = self.cache.state.lock().unwrap();
+        state
+            .entries
+            .insert(id.clone(), Entry::Done(expire, res.clone()));
+        state.order.push_back((expire, id));
+        while state.order.len() > self.cache.max_keys {
+            state.pop_front();
+        }
+        res
+    }
+}
+
+impl Drop for Pending<'_> {
+    fn drop(&mut self) {
+        if let Some(id) = self.id.take() {
+            let mut state = self.cache.state.lock().unwrap_or_else(|e| e.into_inner());
+            if matches!(state.entries.get(&id), Some(Entry::Pending)) {
+                state.entries.remove(&id);
+            }
+        }
+    }
+}
+
+impl State {
+    fn purge(&mut self, now: Instant) {
+        while self.order.front().is_some_and(|(expire, _)| *expire <= now) {
+            self.pop_front();
+        }
+    }
+
+    fn pop_front(&mut self) {
+        if let Some((expire, id)) = self.order.pop_front() {
+            // key 过期之后可能又被用过一次，只删除这一次的结果
+            if matches!(self.entries.get(&id), Some(Entry::Done(e, _)) if *e == expire) {
+                self.entries.remove(&id);
+            }
+        }
+    }
+}
+
+fn is_write(cmd: &CommandRequest) -> bool {
+    cmd.request_data.as_ref().is_some_and(|data| {
+        // 自定义命令可能修改任何 table
+        matches!(data, RequestData::Custom(_))
+            || required_access(data)
+                .iter()
+                .any(|a| a.verb == Verb::Write && a.table.is_some())
+    })
+}
+
+#[cfg(test)]
+mod idempotency_tests {
+    use std::cell::Cell;
+
+    use futures::StreamExt;
+
+    use super::*;
+    use crate::{pb::abi::Value, service_builder::ServiceBuilder, Service};
+
+    fn run(cache: &IdempotencyCache, cmd: &CommandRequest, user: &str, n: &Cell<i64>) -> i64 {
+        let res = cache.execute(cmd, Some(user.into()), || {
+            n.set(n.get() + 1);
+            Value::from(n.get()).into()
+        });
+        res.values[0].to_integer().unwrap()
+    }
+
+    #[test]
+    fn retry_should_return_first_response() {
+        let cache = IdempotencyCache::default();
+        let n = Cell::new(0);
+        let cmd = CommandRequest::new_hset("t1", "k1", "v1").with_idempotency_key("a");
+        assert_eq!(run(&cache, &cmd, "alice", &n), 1);
+        assert_eq!(run(&cache, &cmd, "alice", &n), 1);
+        // 不同的 key 和不同的用户都会执行
+        let other = CommandRequest::new_hset("t1", "k1", "v1").with_idempotency_key("b");
+        assert_eq!(run(&cache, &other, "alice", &n), 2);
+        assert_eq!(run(&cache, &cmd, "bob", &n), 3);
+        // 没有幂等 key 的命令和只读的命令每次都会执行
+        let cmd = CommandRequest::new_hset("t1", "k1", "v1");
+        assert_eq!(run(&cache, &cmd, "alice", &n), 4);
+        let cmd = CommandRequest::new_hget("t1", "k1").with_idempotency_key("a");
+        assert_eq!(run(&cache, &cmd, "alice", &n), 5);
+    }
+
+    #[tokio::test]
+    async fn service_should_not_execute_retried_command_twice() {
+        let service: Service = ServiceBuilder::default().finish();
+        let cmd = CommandRequest::new_hincrby("t1", "k1", 1).with_idempotency_key("a");
+        for _ in 0..2 {
+            let res = service.execute(cmd.clone()).next().await.unwrap();
+            assert_eq!(res.values, [Value::from(1)]);
+        }
+        let res = service.execute(CommandRequest::new_hget("t1", "k1"));
+        assert_eq!(res.into_future().await.0.unwrap().values, [Value::from(1)]);
+    }
+
+    #[test]
+    fn pending_request_should_conflict() {
+        let cache = IdempotencyCache::default();
+        let cmd = CommandRequest::new_hset("t1", "k1", "v1").with_idempotency_key("a");
+        let res = cache.execute(&cmd, None, || {
+            cache.execute(&cmd, None, CommandResponse::ok)
+        });
+        assert_eq!(res.status, 409);
+    }
+
+    #[test]
+    fn panicked_request_should_not_block_retries() {
+        let cache = IdempotencyCache::default();
+        let cmd = CommandRequest::new_hset("t1", "k1", "v1").with_idempotency_key("a");
+        let res = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            cache.execute(&cmd, None, || panic!("command failed"))
+        }));
+        assert!(res.is_err());
+        let n = Cell::new(0);
+        assert_eq!(run(&cache, &cmd, "", &n), 1);
+    }
+
+    #[tokio::test]
+    async fn cancelled_request_should_not_block_retries() {
+        let cache = IdempotencyCache::default();
+        let cmd = CommandRequest::new_hset("t1", "k1", "v1").with_idempotency_key("a");
+        let pending = cache.execute_async(&cmd, None, futures::future::pending);
+        assert!(futures::poll!(Box::pin(pending)).is_pending());
+        let res = cache
+            .execute_async(&cmd, None, || async { CommandResponse::ok() })
+            .await;
+        assert_eq!(res.status, 200);
+        let res = cache.execute(&cmd, None, || unreachable!());
+        assert_eq!(res.status, 200);
+    }
+
+    #[test]
+    fn responses_should_expire_and_be_bounded() {
+        let cache = IdempotencyCache::new(IdempotencyConfig {
+            ttl: Some(0),
+            max_keys: None,
+        });
+        let n = Cell::new(0);
+        let cmd = CommandRequest::new_hset("t1", "k1", "v1").with_idempotency_key("a");
+        assert_eq!(run(&cache, &cmd, "alice", &n), 1);
+        assert_eq!(run(&cache, &cmd, "alice", &n), 2);
+
+        let cache = IdempotencyCache::new(IdempotencyConfig {
+            ttl: None,
+            max_keys: Some(1),
+        });
+        let other = CommandRequest::new_hset("t1", "k1", "v1").with_idempotency_key("b");
+        assert_eq!(run(&cache, &cmd, "alice", &n), 3);
+        assert_eq!(run(&cache, &other, "alice", &n), 4);
+        assert_eq!(run(&cache, &cmd, "alice", &n), 5);
+    }
+}
@@ -0,0 +1,310 @@
+//! 从 Kafka 导入数据，把 kv-sv 当作由事件流维护的物化视图。
+//!
+//! 每一批消息的修改和消费位置在同一个 apply_batch 中原子地写入 storage，
+//! 重启或者重连之后从保存的位置继续消费，每条消息正好生效一次
+
+use prost::Message as _;
+use tokio::task::JoinHandle;
+use tracing::warn;
+
+use super::{replication::Replicator, Service};
+use crate::{
+    config::IngestConfig,
+    error::KvError,
+    pb::abi::{value, wal_record::Op, CommandRequest, Kvpair, Value, WalRecord},
+    Storage,
+};
+
+/// 消费位置，<topic/partition, 下一个要消费的 offset>；和数据一起复制到 replica，切换之后可以继续消费
+pub const INGEST_OFFSET_TABLE: &str = "__ingest_offsets__";
+
+/// topic 中的一条消息；value 为 None 是 tombstone
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Message {
+    pub offset: i64,
+    pub key: Option<Vec<u8>>,
+    pub value: Option<Vec<u8>>,
+}
+
+/// 消费位置在 INGEST_OFFSET_TABLE 中的 key
+pub fn source_of(topic: &str, partition: i32) -> String {
+    format!("{}/{}", topic, partition)
+}
+
+/// 保存的消费位置，没有消费过时返回 None
+pub fn offset_of(store: &impl Storage, source: &str) -> Result<Option<i64>, KvError> {
+    match store.get(INGEST_OFFSET_TABLE, source)? {
+        Some(Value {
+            value: Some(value::Value::Integer(offset)),
+        }) => Ok(Some(offset)),
+        _ => Ok(None),
+    }
+}
+
+/// 消息对应的修改；无法解析的消息跳过
+fn to_record(table: &str, message: &Message) -> Option<WalRecord> {
+    let Some(data) = &message.value else {
+        return Some(WalRecord::new_del(table, message.key.as_ref()?));
+    };
+    match Kvpair::decode(&data[..]) {
+        Ok(Kvpair {
+            key,
+            value: Some(value),
+        }) => Some(WalRecord::new_set(table, key, value)),
+        Ok(pair) => Some(WalRecord::new_del(table, pair.key)),
+        Err(e) => {
+            warn!("Skip invalid message at offset {}: {}", message.offset, e);
+            None
+        }
+    }
+}
+
+/// 把一批消息写入 table，同时把消费位置推进到最后一条消息之后；返回新的消费位置，没有消息时返回 None
+pub fn ingest(
+    store: &impl Storage,
+    replicator: &Replicator,
+    table: &str,
+    source: &str,
+    messages: &[Message],
+) -> Result<Option<i64>, KvError> {
+    let Some(last) = messages.last() else {
+        return Ok(None);
+    };
+    let next = last.offset + 1;
+    let mut records: Vec<_> = messages
+        .iter()
+        .filter_map(|message| to_record(table, message))
+        .collect();
+    records.push(WalRecord::new_set(INGEST_OFFSET_TABLE, source, next.into()));
+
+    // 修改过的 key 推送给 replica 和 CDC
+    let cmd = CommandRequest::new_batch(records.iter().map(|r| match &r.op {
+        Some(Op::Set(value)) => CommandRequest::new_hset(&r.table, &r.key, value.clone()),
+        _ => CommandRequest::new_hdel(&r.table, &r.key),
+    }));
+    store.apply_batch(records, &[])?;
+    if let Some(data) = &cmd.request_data {
+        replicator.record(data, store)?;
+    }
+    Ok(Some(next))
+}
+
+#[cfg(feature = "kafka")]
+mod kafka {
+    use std::{sync::Weak, time::Duration};
+
+    use rskafka::client::{
+        partition::{OffsetAt, UnknownTopicHandling},
+        ClientBuilder,
+    };
+    use tokio::time;
+    use tracing::{info, warn};
+
+    use super::{ingest, offset_of, source_of, Message};
+    use crate::{
+        config::IngestConfig, error::KvError, replication::Replicator,
+        service_builder::ServiceBuilder, Storage,
+    };
+
+    /// 每次 fetch 最多返回的字节数
+    const FETCH_MAX_BYTES: i32 = 1024 * 1024;
+
+    /// 没有新消息时 broker 最多等待的时间（毫秒）
+    const FETCH_MAX_WAIT_MS: i32 = 500;
+
+    /// 和 broker 断开之后，重连之前等待的时间
+    pub(super) const RECONNECT_INTERVAL: Duration = Duration::from_secs(1);
+
+    fn kafka_error(e: impl std::fmt::Display) -> KvError {
+        KvError::Internal(format!("Kafka error: {}", e))
+    }
+
+    /// 配置的 partition，没有配置时为 topic 所有的 partition
+    pub(super) async fn partitions_of(config: &IngestConfig) -> Result<Vec<i32>, KvError> {
+        if !config.partitions.is_empty() {
+            return Ok(config.partitions.clone());
+        }
+        let client = ClientBuilder::new(config.brokers.clone())
+            .build()
+            .await
+            .map_err(kafka_error)?;
+        let topics = client.list_topics().await.map_err(kafka_error)?;
+        match topics.into_iter().find(|t| t.name == config.topic) {
+            Some(topic) => Ok(topic.partitions.into_iter().collect()),
+            None => Err(KvError::NotFound(format!("topic {}", config.topic))),
+        }
+    }
+
+    /// 消费一个 partition，出错之后重连；Service 全部 drop 之后返回
+    pub(super) async fn consume_partition<Store: Storage>(
+        config: &IngestConfig,
+        partition: i32,
+        inner: &Weak<ServiceBuilder<Store>>,
+        replicator: &Replicator,
+    ) {
+        let source = source_of(&config.topic, partition);
+        loop {
+            match consume(config, partition, &source, inner, replicator).await {
+                Ok(()) => return,
+                Err(e) => warn!("Ingesting {} failed: {:?}", source, e),
+            }
+            if inner.strong_count() == 0 {
+                return;
+            }
+            time::sleep(RECONNECT_INTERVAL).await;
+        }
+    }
+
+    async fn consume<Store: Storage>(
+        config: &IngestConfig,
+        partition: i32,
+        source: &str,
+        inner: &Weak<ServiceBuilder<Store>>,
+        replicator: &Replicator,
+    ) -> Result<(), KvError> {
+        let client = ClientBuilder::new(config.brokers.clone())
+            .build()
+            .await
+            .map_err(kafka_error)?;
+        let client = client
+            .partition_client(&config.topic, partition, UnknownTopicHandling::Retry)
+            .await
+            .map_err(kafka_error)?;
+        let stored = match inner.upgrade() {
+            Some(inner) => offset_of(&inner.store, source)?,
+            None => return Ok(()),
+        };
+        let mut offset = match stored {
+            Some(offset) => offset,
+            None if config.from_beginning.unwrap_or(true) => client
+                .get_offset(OffsetAt::Earliest)
+                .await
+                .map_err(kafka_error)?,
+            None => client
+                .get_offset(OffsetAt::Latest)
+                .await
+                .map_err(kafka_error)?,
+        };
+        info!(
+            "Ingesting {} into table {} from offset {}",
+            source, config.table, offset
+        );
+
+        loop {
+            let (records, _) = client
+                .fetch_records(offset, 1..FETCH_MAX_BYTES, FETCH_MAX_WAIT_MS)
+                .await
+                .map_err(kafka_error)?;
+            // 压缩的消息按整批返回，可能包含 offset 之前的消息
+            let messages: Vec<_> = records
+                .into_iter()
+                .filter(|r| r.offset >= offset)
+                .map(|r| Message {
+                    offset: r.offset,
+                    key: r.record.key,
+                    value: r.record.value,
+                })
+                .collect();
+            let Some(inner) = inner.upgrade() else {
+                return Ok(());
+            };
+            if let Some(next) = ingest(&inner.store, replicator, &config.table, source, &messages)?
+            {
+                offset = next;
+            }
+        }
+    }
+}
+
+impl<Store: Storage> Service<Store> {
+    /// 启动后台任务消费 topic 的每个 partition；断开之后重连，并从保存的位置继续。
+    /// Service 全部 drop 之后任务自动退出
+    #[cfg(feature = "kafka")]
+    pub fn spawn_ingest_task(&self, config: IngestConfig) -> JoinHandle<()> {
+        let inner = std::sync::Arc::downgrade(&self.inner);
+        let replicator = self.replicator.clone();
+        tokio::spawn(async move {
+            let partitions = loop {
+                match kafka::partitions_of(&config).await {
+                    Ok(partitions) => break partitions,
+                    Err(e) => warn!("Failed to get partitions of {}: {:?}", config.topic, e),
+                }
+                if inner.strong_count() == 0 {
+                    return;
+                }
+                tokio::time::sleep(kafka::RECONNECT_INTERVAL).await;
+            };
+            let tasks = partitions
+                .into_iter()
+                .map(|partition| kafka::consume_partition(&config, partition, &inner, &replicator));
+            futures::future::join_all(tasks).await;
+        })
+    }
+
+    #[cfg(not(feature = "kafka"))]
+    pub fn spawn_ingest_task(&self, config: IngestConfig) -> JoinHandle<()> {
+        warn!(
+            "Ingesting topic {} is ignored, rebuild with the kafka feature",
+            config.topic
+        );
+        tokio::spawn(async {})
+    }
+}
+
+#[cfg(test)]
+mod ingest_tests {
+    use super::*;
+    use crate::memory::MemTable;
+
+    fn message(offset: i64, key: &str, value: Option<Vec<u8>>) -> Message {
+        Message {
+            offset,
+            key: Some(key.into()),
+            value,
+        }
+    }
+
+    #[test]
+    fn ingest_should_apply_messages_and_offset() {
+        let store = MemTable::new();
+        let replicator = Replicator::default();
+        let source = source_of("users", 0);
+        let set = |key: &str, value: &str| Kvpair::new(key, value.into()).encode_to_vec();
+        let messages = [
+            message(10, "k1", Some(set("k1", "v1"))),
+            message(11, "k2", Some(set("k2", "v2"))),
+            message(12, "k3", Some(set("k3", "v3"))),
+            // pair 中没有 value 时删除 key
+            message(
+                13,
+                "",
+                Some(
+                    Kvpair {
+                        key: "k1".into(),
+                        value: None,
+                    }
+                    .encode_to_vec(),
+                ),
+            ),
+            // tombstone 按消息的 key 删除
+            message(14, "k2", None),
+            // 无法解析的消息跳过，但是消费位置照样推进
+            message(15, "k4", Some(vec![0xff, 0xff])),
+        ];
+        assert_eq!(offset_of(&store, &source).unwrap(), None);
+        let next = ingest(&store, &replicator, "users", &source, &messages).unwrap();
+        assert_eq!(next, Some(16));
+        assert_eq!(offset_of(&store, &source).unwrap(), Some(16));
+
+        assert_eq!(store.get("users", "k1").unwrap(), None);
+        assert_eq!(store.get("users", "k2").unwrap(), None);
+        assert_eq!(store.get("users", "k3").unwrap(), Some("v3".into()));
+        assert_eq!(store.get("users", "k4").unwrap(), None);
+
+        assert_eq!(
+            ingest(&store, &replicator, "users", &source, &[]).unwrap(),
+            None
+        );
+        assert_eq!(offset_of(&store, &source).unwrap(), Some(16));
+    }
+}
@@ -0,0 +1,139 @@
+use serde_json::Value as Json;
+
+use crate::error::KvError;
+
+/// JSONPath 中的一段：object 的 field，或者 array 的下标（负数从尾部开始数）
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Segment {
+    Field(String),
+    Index(i64),
+}
+
+/// 解析 JSONPath 的一个子集：以 `$` 开头，后面跟着 `.field`、`['field']`、`["field"]` 或者 `[index]`
+pub(crate) fn parse(path: &str) -> Result<Vec<Segment>, KvError> {
+    let invalid = || KvError::InvalidCommand(format!("invalid JSON path {}", path));
+    let mut rest = path.strip_prefix('$').ok_or_else(invalid)?;
+    let mut segments = Vec::new();
+    while !rest.is_empty() {
+        if let Some(s) = rest.strip_prefix('.') {
+            let end = s.find(['.', '[']).unwrap_or(s.len());
+            if end == 0 {
+                return Err(invalid());
+            }
+            segments.push(Segment::Field(s[..end].to_string()));
+            rest = &s[end..];
+        } else if let Some(s) = rest.strip_prefix('[') {
+            let (segment, s) = match s.chars().next() {
+                Some(quote @ ('\'' | '"')) => {
+                    let s = &s[1..];
+                    let end = s.find(quote).ok_or_else(invalid)?;
+                    (Segment::Field(s[..end].to_string()), &s[end + 1..])
+                }
+                _ => {
+                    let end = s.find(']').ok_or_else(invalid)?;
+                    let index = s[..end].trim().parse().map_err(|_| invalid())?;
+                    (Segment::Index(index), &s[end..])
+                }
+            };
+            rest = s.strip_prefix(']').ok_or_else(invalid)?;
+            segments.push(segment);
+        } else {
+            return Err(invalid());
+        }
+    }
+    Ok(segments)
+}
+
+/// path 指向的部分，不存在或者类型不匹配时返回 None
+pub(crate) fn get<'a>(doc: &'a Json, path: &[Segment]) -> Option<&'a Json> {
+    path.iter().try_fold(doc, |v, segment| match (v, segment) {
+        (Json::Object(map), Segment::Field(field)) => map.get(field),
+        (Json::Array(array), Segment::Index(i)) => array.get(resolve(array.len(), *i)?),
+        _ => None,
+    })
+}
+
+fn get_mut<'a>(doc: &'a mut Json, path: &[Segment]) -> Option<&'a mut Json> {
+    path.iter().try_fold(doc, |v, segment| match (v, segment) {
+        (Json::Object(map), Segment::Field(field)) => map.get_mut(field),
+        (Json::Array(array), Segment::Index(i)) => {
+            let i = resolve(array.len(), *i)?;
+            array.get_mut(i)
+        }
+        _ => None,
+    })
+}
+
+/// 把 path 指向的部分设置为 value：object 可以加入新的 field，array 只能替换已有的元素。
+/// 返回之前的值（新加入的 field 为 None）；上一层不存在或者类型不匹配时返回 None
+pub(crate) fn set(doc: &mut Json, path: &[Segment], value: Json) -> Option<Option<Json>> {
+    let Some((last, parents)) = path.split_last() else {
+        return Some(Some(std::mem::replace(doc, value)));
+    };
+    match (get_mut(doc, parents)?, last) {
+        (Json::Object(map), Segment::Field(field)) => Some(map.insert(field.clone(), value)),
+        (Json::Array(array), Segment::Index(i)) => {
+            let i = resolve(array.len(), *i)?;
+            Some(Some(std::mem::replace(&mut array[i], value)))
+        }
+        _ => None,
+    }
+}
+
+fn resolve(len: usize, index: i64) -> Option<usize> {
+    let index = match index < 0 {
+        true => len as i64 + index,
+        false => index,
+    };
+    (0..len as i64).contains(&index).then_some(index as usize)
+}
+
+#[cfg(test)]
+mod json_path_tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn parse_should_work() {
+        assert_eq!(parse("$").unwrap(), []);
+        let path = parse("$.a['b c'][\"d\"][-1][0]").unwrap();
+        assert_eq!(
+            path,
+            [
+                Segment::Field("a".into()),
+                Segment::Field("b c".into()),
+                Segment::Field("d".into()),
+                Segment::Index(-1),
+                Segment::Index(0),
+            ]
+        );
+        for path in ["", "a", "$.", "$..a", "$[x]", "$['a]", "$[0", "$a"] {
+            assert!(parse(path).is_err(), "{}", path);
+        }
+    }
+
+    #[test]
+    fn get_and_set_should_work() {
+        let mut doc = json!({ "name": "alice", "tags": ["a", "b"] });
+        let path = |p: &str| parse(p).unwrap();
+        assert_eq!(get(&doc, &path("$.tags[-1]")), Some(&json!("b")));
+        assert_eq!(get(&doc, &path("$.tags[2]")), None);
+        assert_eq!(get(&doc, &path("$.name.first")), None);
+
+        let old = set(&mut doc, &path("$.tags[0]"), json!("c"));
+        assert_eq!(old, Some(Some(json!("a"))));
+        assert_eq!(set(&mut doc, &path("$.age"), json!(30)), Some(None));
+        // 上一层不存在，或者 array 的下标越界
+        assert_eq!(set(&mut doc, &path("$.address.city"), json!("x")), None);
+        assert_eq!(set(&mut doc, &path("$.tags[5]"), json!("x")), None);
+        assert_eq!(
+            doc,
+            json!({ "name": "alice", "tags": ["c", "b"], "age": 30 })
+        );
+
+        let old = set(&mut doc, &path("$"), json!([1]));
+        assert_eq!(old.unwrap().unwrap()["name"], "alice");
+        assert_eq!(doc, json!([1]));
+    }
+}
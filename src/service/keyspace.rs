@@ -0,0 +1,422 @@
+use std::sync::{
+    atomic::{AtomicU32, Ordering},
+    Arc, Mutex,
+};
+
+use bytes::Bytes;
+use dashmap::DashMap;
+use futures::{stream, StreamExt};
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tokio_stream::wrappers::ReceiverStream;
+use tracing::{debug, warn};
+
+use super::{
+    mvcc::History,
+    replication::written_keys,
+    topic::{BroadCaster, Topic},
+    topic_service::StreamingResponse,
+};
+use crate::{
+    config::{MvccConfig, NotificationConfig},
+    error::KvError,
+    pb::{
+        abi::{command_request::RequestData, CommandResponse, Hwatch, KeyEvent, Value},
+        to_key,
+    },
+    Storage,
+};
+
+/// 每个 watcher 还没有发出去的事件的上限，超过之后 watcher 会被删除
+const WATCH_CAPACITY: usize = 128;
+
+/// keyspace 通知的 topic 前缀，后面是 `{table}:{key}`
+pub const KEYSPACE_TOPIC: &str = "__keyspace__:";
+
+/// keyevent 通知的 topic 前缀，后面是事件名
+pub const KEYEVENT_TOPIC: &str = "__keyevent__:";
+
+/// 下一个 watch id
+static NEXT_ID: AtomicU32 = AtomicU32::new(1);
+
+/// key 的修改通知：命令修改了被监听的 key 时，把修改前后的值推送给 watcher，
+/// 开启了 keyspace 通知时还会发布到 pub/sub，开启了 MVCC 时还会记录到历史中
+#[derive(Default)]
+pub struct Keyspace {
+    /// 修改被监听的 key 时持有，这样读到的旧值和新值之间没有其它修改，推送的顺序也和修改的顺序一致
+    lock: Mutex<()>,
+    /// <watch id, watcher>
+    watchers: DashMap<u32, Watcher>,
+    notifications: NotificationConfig,
+    broadcaster: Arc<BroadCaster>,
+    history: Option<History>,
+}
+
+struct Watcher {
+    table: String,
+    key: Bytes,
+    prefix: bool,
+    sender: mpsc::Sender<Arc<CommandResponse>>,
+}
+
+impl Watcher {
+    fn matches(&self, table: &str, key: &[u8]) -> bool {
+        self.table == table
+            && match self.prefix {
+                true => key.starts_with(&self.key),
+                false => key == &self.key[..],
+            }
+    }
+}
+
+/// 修改之前 key 的状态
+struct Before<'a> {
+    table: &'a str,
+    key: &'a [u8],
+    version: u64,
+    value: Option<Value>,
+}
+
+impl Keyspace {
+    pub fn new(
+        notifications: NotificationConfig,
+        mvcc: Option<MvccConfig>,
+        broadcaster: Arc<BroadCaster>,
+    ) -> Self {
+        Self {
+            notifications,
+            broadcaster,
+            history: mvcc.map(History::new),
+            ..Default::default()
+        }
+    }
+
+    /// 没有开启 MVCC 时为 None
+    pub fn history(&self) -> Option<&History> {
+        self.history.as_ref()
+    }
+
+    /// 读取 key 在 revision 时的值；revision 之后没有修改过的 key 读取当前的值
+    pub fn get_at(
+        &self,
+        table: &str,
+        key: &[u8],
+        revision: u64,
+        store: &impl Storage,
+    ) -> Result<Option<Value>, KvError> {
+        let Some(history) = &self.history else {
+            return Err(KvError::InvalidCommand("MVCC is not enabled".into()));
+        };
+        // 持有锁时没有正在执行的修改，历史和存储中的值是一致的
+        let _guard = self.lock.lock().unwrap();
+        match history.get(table, key, revision)? {
+            Some(value) => Ok(value),
+            None => store.get(table, key),
+        }
+    }
+
+    /// 处理 HWATCH：先返回 watch id，设置了 from_revision 时重放历史中匹配的修改，之后推送匹配的 key 的修改。
+    /// 同时返回 watch id，调用者记录在 session 中，HUNWATCH 时检查
+    pub fn watch(&self, cmd: Hwatch) -> Result<(u32, StreamingResponse), KvError> {
+        let (tx, rx) = mpsc::channel(WATCH_CAPACITY);
+        let watcher = Watcher {
+            table: cmd.table,
+            key: cmd.key,
+            prefix: cmd.prefix,
+            sender: tx,
+        };
+        // 持有锁时没有正在执行的修改，重放的修改和之后推送的修改之间不会重复也不会遗漏
+        let _guard = self.lock.lock().unwrap();
+        let replay = match (cmd.from_revision, &self.history) {
+            (0, _) => vec![],
+            (revision, Some(history)) => {
+                history.since(revision, |e| watcher.matches(&e.table, &e.key))?
+            }
+            (_, None) => return Err(KvError::InvalidCommand("MVCC is not enabled".into())),
+        };
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        self.watchers.insert(id, watcher);
+        debug!("Watcher {} is added", id);
+        let head = stream::once(async move { Arc::new((id as i64).into()) });
+        let replay = stream::iter(replay.into_iter().map(|events| Arc::new(events.into())));
+        Ok((
+            id,
+            Box::pin(head.chain(replay).chain(ReceiverStream::new(rx))),
+        ))
+    }
+
+    /// 处理 HUNWATCH：删除 watcher，它的事件流随之结束
+    pub fn unwatch(&self, id: u32) -> Result<(), KvError> {
+        match self.watchers.remove(&id) {
+            Some(_) => Ok(()),
+            None => Err(KvError::NotFound(format!("watcher {}", id))),
+        }
+    }
+
+    /// 执行会修改 key 的命令 f，执行之后把被监听的 key 的修改推送出去
+    pub fn track<T>(&self, cmd: &RequestData, store: &impl Storage, f: impl FnOnce() -> T) -> T {
+        let all = self.notifications.is_enabled() || self.history.is_some();
+        if !all && self.watchers.is_empty() {
+            return f();
+        }
+        let keys: Vec<(&str, &[u8])> = written_keys(cmd)
+            .into_iter()
+            .filter(|&(table, key)| all || self.watchers.iter().any(|w| w.matches(table, key)))
+            .collect();
+        if keys.is_empty() {
+            return f();
+        }
+
+        let _guard = self.lock.lock().unwrap();
+        let before: Vec<Before> = keys
+            .into_iter()
+            .map(|(table, key)| Before {
+                table,
+                key,
+                version: store.version(table, key).unwrap_or_default(),
+                value: store.get(table, key).ok().flatten(),
+            })
+            .collect();
+        let result = f();
+        let mut events = Vec::new();
+        for b in before {
+            // 同一个命令可能多次修改同一个 key，只推送一次
+            if events
+                .iter()
+                .any(|e: &KeyEvent| e.table == b.table && e.key == b.key)
+            {
+                continue;
+            }
+            if store.version(b.table, b.key).unwrap_or_default() == b.version {
+                continue;
+            }
+            let value = store.get(b.table, b.key).ok().flatten();
+            if b.value.is_none() && value.is_none() {
+                continue;
+            }
+            events.push(KeyEvent {
+                table: b.table.into(),
+                key: to_key(b.key),
+                deleted: value.is_none(),
+                old_value: b.value,
+                new_value: value,
+                revision: 0,
+            });
+        }
+        if let Some(history) = &self.history {
+            history.record(&mut events);
+        }
+        self.notify(&events);
+        self.publish(events);
+        result
+    }
+
+    /// 把事件发布到 `__keyspace__:{table}:{key}` 和 `__keyevent__:{event}`
+    fn notify(&self, events: &[KeyEvent]) {
+        for e in events {
+            let event = if e.deleted { "del" } else { "set" };
+            if self.notifications.keyspace {
+                let key = String::from_utf8_lossy(&e.key);
+                let topic = format!("{}{}:{}", KEYSPACE_TOPIC, e.table, key);
+                let data = Arc::new(Value::from(event).into());
+                self.broadcaster.clone().publish(topic, data);
+            }
+            if self.notifications.keyevent {
+                let topic = format!("{}{}", KEYEVENT_TOPIC, event);
+                let data: Vec<Value> = vec![e.table.as_str().into(), (&e.key).into()];
+                self.broadcaster
+                    .clone()
+                    .publish(topic, Arc::new(data.into()));
+            }
+        }
+    }
+
+    /// 把事件按 watcher 分组推送；发送不出去的 watcher（已断开或者太慢）会被删除
+    fn publish(&self, events: Vec<KeyEvent>) {
+        let mut closed = vec![];
+        for watcher in self.watchers.iter() {
+            let events: Vec<KeyEvent> = events
+                .iter()
+                .filter(|e| watcher.matches(&e.table, &e.key))
+                .cloned()
+                .collect();
+            if events.is_empty() {
+                continue;
+            }
+            match watcher.sender.try_send(Arc::new(events.into())) {
+                Ok(()) => {}
+                Err(TrySendError::Full(_)) => {
+                    warn!("Watcher {} is too slow, dropped", watcher.key());
+                    closed.push(*watcher.key());
+                }
+                Err(TrySendError::Closed(_)) => closed.push(*watcher.key()),
+            }
+        }
+        for id in closed {
+            self.watchers.remove(&id);
+            debug!("Watcher {} is removed", id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod keyspace_tests {
+    use super::*;
+    use crate::{memory::MemTable, pb::abi::CommandRequest};
+
+    #[tokio::test]
+    async fn watch_should_receive_put_and_delete_events() {
+        let keyspace = Keyspace::default();
+        let store = MemTable::new();
+        let (_, mut stream) = keyspace
+            .watch(Hwatch {
+                table: "t1".into(),
+                key: "user:".into(),
+                prefix: true,
+                from_revision: 0,
+            })
+            .unwrap();
+        let id: i64 = stream.next().await.unwrap().as_ref().try_into().unwrap();
+
+        let write = |cmd: CommandRequest| {
+            let data = cmd.request_data.unwrap();
+            keyspace.track(&data, &store, || match &data {
+                RequestData::Hset(v) => {
+                    let pair = v.pair.clone().unwrap();
+                    store.set(&v.table, pair.key, pair.value.unwrap()).unwrap();
+                }
+                RequestData::Hdel(v) => {
+                    store.del(&v.table, &v.key).unwrap();
+                }
+                _ => unreachable!(),
+            })
+        };
+        write(CommandRequest::new_hset("t1", "user:1", "v1"));
+        // 不匹配的 key 和不存在的 key 的删除不会推送
+        write(CommandRequest::new_hset("t1", "other", "v1"));
+        write(CommandRequest::new_hset("t2", "user:1", "v1"));
+        write(CommandRequest::new_hdel("t1", "user:2"));
+        write(CommandRequest::new_hset("t1", "user:1", "v2"));
+        write(CommandRequest::new_hdel("t1", "user:1"));
+
+        let events: Vec<KeyEvent> = stream
+            .by_ref()
+            .take(3)
+            .flat_map(|res| stream::iter(res.events.clone()))
+            .collect()
+            .await;
+        let kinds: Vec<_> = events
+            .iter()
+            .map(|e| {
+                (
+                    std::str::from_utf8(&e.key).unwrap(),
+                    e.deleted,
+                    e.old_value.clone(),
+                    e.new_value.clone(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            kinds,
+            [
+                ("user:1", false, None, Some("v1".into())),
+                ("user:1", false, Some("v1".into()), Some("v2".into())),
+                ("user:1", true, Some("v2".into()), None),
+            ]
+        );
+
+        // 取消之后事件流结束
+        keyspace.unwatch(id as u32).unwrap();
+        assert!(stream.next().await.is_none());
+        assert!(keyspace.unwatch(id as u32).is_err());
+    }
+
+    #[tokio::test]
+    async fn watch_should_replay_events_from_revision() {
+        let mvcc = MvccConfig { retention: Some(3) };
+        let keyspace = Keyspace::new(Default::default(), Some(mvcc), Default::default());
+        let store = MemTable::new();
+        let set = |key: &str, value: &str| {
+            let data = CommandRequest::new_hset("t1", key, value)
+                .request_data
+                .unwrap();
+            keyspace
+                .track(&data, &store, || store.set("t1", key, value.into()))
+                .unwrap();
+        };
+        set("user:1", "v1");
+        set("other", "v1");
+        set("user:2", "v1");
+        set("user:1", "v2");
+
+        let watch = |from_revision| {
+            keyspace.watch(Hwatch {
+                table: "t1".into(),
+                key: "user:".into(),
+                prefix: true,
+                from_revision,
+            })
+        };
+        // 第一个 revision 已经被丢弃了
+        assert!(matches!(watch(1), Err(KvError::Compacted(1, 2))));
+        let (_, mut stream) = watch(2).unwrap();
+        stream.next().await.unwrap();
+        set("user:3", "v1");
+
+        let events: Vec<(u64, Bytes)> = stream
+            .take(3)
+            .flat_map(|res| stream::iter(res.events.clone()))
+            .map(|e| (e.revision, e.key))
+            .collect()
+            .await;
+        assert_eq!(
+            events,
+            [
+                (3, Bytes::from("user:2")),
+                (4, Bytes::from("user:1")),
+                (5, Bytes::from("user:3")),
+            ]
+        );
+
+        // 没有开启 MVCC 时不能重放
+        let keyspace = Keyspace::default();
+        let res = keyspace.watch(Hwatch {
+            from_revision: 1,
+            ..Default::default()
+        });
+        assert!(matches!(res, Err(KvError::InvalidCommand(_))));
+    }
+
+    #[tokio::test]
+    async fn keyspace_notifications_should_be_published() {
+        let broadcaster: Arc<BroadCaster> = Default::default();
+        let notifications = NotificationConfig {
+            keyspace: true,
+            keyevent: true,
+        };
+        let keyspace = Keyspace::new(notifications, None, broadcaster.clone());
+        let store = MemTable::new();
+        let mut key_rx = broadcaster.clone().subscript("__keyspace__:t1:k1");
+        let mut event_rx = broadcaster.clone().subscript("__keyevent__:del");
+        // 第一条是 subscription id
+        key_rx.recv().await.unwrap();
+        event_rx.recv().await.unwrap();
+
+        let data = CommandRequest::new_hset("t1", "k1", "v1")
+            .request_data
+            .unwrap();
+        keyspace
+            .track(&data, &store, || store.set("t1", "k1", "v1".into()))
+            .unwrap();
+        let data = CommandRequest::new_hdel("t1", "k1").request_data.unwrap();
+        keyspace
+            .track(&data, &store, || store.del("t1", "k1"))
+            .unwrap();
+
+        let res = key_rx.recv().await.unwrap();
+        assert_eq!(res.values, [Value::from("set")]);
+        let res = key_rx.recv().await.unwrap();
+        assert_eq!(res.values, [Value::from("del")]);
+        let res = event_rx.recv().await.unwrap();
+        assert_eq!(res.values, [Value::from("t1"), Value::from("k1")]);
+    }
+}
@@ -0,0 +1,547 @@
+use std::{collections::BTreeMap, sync::Arc, time::Duration};
+
+use bytes::Bytes;
+use tokio::{task::JoinHandle, time};
+use tracing::{debug, warn};
+
+use super::{lock_service::next_token, replication::Replicator, transaction::version_of, Service};
+use crate::{
+    error::KvError,
+    pb::abi::{
+        command_request::RequestData, value, CommandResponse, Hmdel, LeaseGrant, LeaseKeepAlive,
+        LeaseRevoke, Value, ValueList, ValueSet, WalRecord,
+    },
+    storage::{now_millis, KeyVersion},
+    Storage,
+};
+
+/// lease 的 ttl（秒），<id, ttl>；key 的过期时间就是 lease 的到期时间
+pub const LEASE_TABLE: &str = "__leases__";
+
+/// 关联到 lease 的 key，<id, Set<[table, key]>>；lease 过期之后由后台任务删除这些 key
+pub const LEASE_KEYS_TABLE: &str = "__lease_keys__";
+
+/// key 当前关联的 lease，<[table 的长度][table][key], lease>；和 etcd 一样一个 key 只属于一个 lease，
+/// 不带 lease 写入或者删除 key 时通过它解除关联
+pub const KEY_LEASE_TABLE: &str = "__key_leases__";
+
+/// 后台检查过期 lease 的默认间隔
+pub const LEASE_INTERVAL: Duration = Duration::from_millis(500);
+
+/// lease 被并发修改时最多重试的次数
+const MAX_RETRIES: usize = 16;
+
+/// lease 在 LEASE_TABLE 和 LEASE_KEYS_TABLE 中的 key；租户的 lease 加上租户的前缀，
+/// 不同租户的同一个 id 是不同的 lease
+fn lease_key(namespace: &str, id: u64) -> String {
+    format!("{}{}", namespace, id)
+}
+
+/// key 在 KEY_LEASE_TABLE 中的 key；table 前面加上长度，不同的 table 和 key 不会拼出同一个 key
+fn owner_key(table: &str, key: &[u8]) -> Vec<u8> {
+    let mut owner = (table.len() as u32).to_be_bytes().to_vec();
+    owner.extend_from_slice(table.as_bytes());
+    owner.extend_from_slice(key);
+    owner
+}
+
+/// key 在 LEASE_KEYS_TABLE 中的表示：[table, key]
+fn member(table: &str, key: &[u8]) -> Value {
+    ValueList {
+        values: vec![table.into(), Bytes::copy_from_slice(key).into()],
+    }
+    .into()
+}
+
+fn conflict(id: u64) -> KvError {
+    KvError::Conflict(format!("too many concurrent operations on lease {}", id))
+}
+
+fn not_found(id: u64) -> KvError {
+    KvError::NotFound(format!("lease {}", id))
+}
+
+/// 创建 lease，id 为 0 时分配一个新的 id，返回 lease 的 id
+pub(super) fn grant(
+    store: &impl Storage,
+    namespace: &str,
+    id: u64,
+    ttl: u64,
+) -> Result<u64, KvError> {
+    if ttl == 0 {
+        return Err(KvError::InvalidCommand(
+            "lease ttl must be greater than 0".into(),
+        ));
+    }
+    let id = match id {
+        0 => next_token(),
+        id => id,
+    };
+    let key = lease_key(namespace, id);
+    for _ in 0..MAX_RETRIES {
+        let watched = [
+            version_of(store, LEASE_TABLE, key.as_bytes())?,
+            version_of(store, LEASE_KEYS_TABLE, key.as_bytes())?,
+        ];
+        // 过期的 lease 关联的 key 还没有删除时也不能重新创建，否则这些 key 就归到新的 lease 下了
+        if store.contains(LEASE_TABLE, &key)? || store.contains(LEASE_KEYS_TABLE, &key)? {
+            return Err(KvError::Conflict(format!("lease {} already exists", id)));
+        }
+        let records = vec![
+            WalRecord::new_set(LEASE_TABLE, &key, (ttl as i64).into()),
+            WalRecord::new_expire(LEASE_TABLE, &key, now_millis() + ttl * 1000),
+        ];
+        if store.apply_batch(records, &watched)? {
+            return Ok(id);
+        }
+    }
+    Err(conflict(id))
+}
+
+/// 把 lease 的到期时间重新设置为创建时的 ttl 之后，返回 ttl
+pub(super) fn keep_alive(store: &impl Storage, namespace: &str, id: u64) -> Result<u64, KvError> {
+    let key = lease_key(namespace, id);
+    for _ in 0..MAX_RETRIES {
+        let watched = version_of(store, LEASE_TABLE, key.as_bytes())?;
+        let ttl = match store.get(LEASE_TABLE, &key)? {
+            Some(v) => v.to_integer().unwrap_or_default() as u64,
+            None => return Err(not_found(id)),
+        };
+        let records = vec![WalRecord::new_expire(
+            LEASE_TABLE,
+            &key,
+            now_millis() + ttl * 1000,
+        )];
+        if store.apply_batch(records, &[watched])? {
+            return Ok(ttl);
+        }
+    }
+    Err(conflict(id))
+}
+
+/// 设置 key 并把它关联到 lease，返回 key 之前的值
+pub(super) fn attach(
+    store: &impl Storage,
+    namespace: &str,
+    id: u64,
+    table: &str,
+    key: &[u8],
+    value: Value,
+) -> Result<Option<Value>, KvError> {
+    let lease = lease_key(namespace, id);
+    let (owner, member) = (owner_key(table, key), member(table, key));
+    for _ in 0..MAX_RETRIES {
+        let mut watched = vec![
+            version_of(store, LEASE_TABLE, lease.as_bytes())?,
+            version_of(store, LEASE_KEYS_TABLE, lease.as_bytes())?,
+            version_of(store, table, key)?,
+            version_of(store, KEY_LEASE_TABLE, &owner)?,
+        ];
+        if !store.contains(LEASE_TABLE, &lease)? {
+            return Err(not_found(id));
+        }
+        let mut keys = attached(store, &lease)?;
+        if !keys.members.contains(&member) {
+            keys.members.push(member.clone());
+        }
+        let old = store.get(table, key)?;
+        let mut records = vec![
+            WalRecord::new_set(table, key, value.clone()),
+            WalRecord::new_set(LEASE_KEYS_TABLE, &lease, keys.into()),
+            WalRecord::new_set(KEY_LEASE_TABLE, &owner, lease.as_str().into()),
+        ];
+        // 之前关联到了别的 lease 时从那个 lease 中移除
+        if let Some(previous) = lease_of(store, &owner)?.filter(|previous| *previous != lease) {
+            watched.push(version_of(store, LEASE_KEYS_TABLE, previous.as_bytes())?);
+            records.push(remove_member(store, &previous, &member)?);
+        }
+        if store.apply_batch(records, &watched)? {
+            return Ok(old);
+        }
+    }
+    Err(conflict(id))
+}
+
+/// 写入或者删除 key 之前读取它关联的 lease，写入之后交给 detach 解除关联
+pub(super) struct Owner {
+    watched: KeyVersion,
+    lease: Option<String>,
+}
+
+/// key 关联的 lease，没有关联时 detach 什么都不做
+pub(super) fn owner(store: &impl Storage, table: &str, key: &[u8]) -> Result<Owner, KvError> {
+    let owner = owner_key(table, key);
+    let watched = version_of(store, KEY_LEASE_TABLE, &owner)?;
+    let lease = lease_of(store, &owner)?;
+    Ok(Owner { watched, lease })
+}
+
+/// 不带 lease 写入或者删除 key 之后解除它和之前的 lease 的关联，之后撤销这个 lease 时不再删除它；
+/// 这期间 key 又被关联到了 lease 时什么都不做
+pub(super) fn detach(
+    store: &impl Storage,
+    table: &str,
+    key: &[u8],
+    owner: Owner,
+) -> Result<(), KvError> {
+    let Some(lease) = owner.lease else {
+        return Ok(());
+    };
+    let member = member(table, key);
+    for _ in 0..MAX_RETRIES {
+        if version_of(store, KEY_LEASE_TABLE, &owner.watched.key)? != owner.watched {
+            return Ok(());
+        }
+        let watched = [
+            owner.watched.clone(),
+            version_of(store, LEASE_KEYS_TABLE, lease.as_bytes())?,
+        ];
+        let records = vec![
+            WalRecord::new_del(KEY_LEASE_TABLE, &owner.watched.key),
+            remove_member(store, &lease, &member)?,
+        ];
+        if store.apply_batch(records, &watched)? {
+            return Ok(());
+        }
+    }
+    Err(KvError::Conflict(format!(
+        "too many concurrent operations on lease {}",
+        lease
+    )))
+}
+
+/// 从 lease 关联的 key 中移除 member，一个都不剩时删除整个记录
+fn remove_member(store: &impl Storage, lease: &str, member: &Value) -> Result<WalRecord, KvError> {
+    let mut keys = attached(store, lease)?;
+    keys.members.retain(|m| m != member);
+    Ok(match keys.members.is_empty() {
+        true => WalRecord::new_del(LEASE_KEYS_TABLE, lease),
+        false => WalRecord::new_set(LEASE_KEYS_TABLE, lease, keys.into()),
+    })
+}
+
+/// 删除 lease 和关联的 key，返回删除的 key
+pub(super) fn revoke(
+    store: &impl Storage,
+    namespace: &str,
+    id: u64,
+) -> Result<Vec<(String, Bytes)>, KvError> {
+    match revoke_key(store, &lease_key(namespace, id)) {
+        Ok(Some(keys)) => Ok(keys),
+        Ok(None) => Err(not_found(id)),
+        Err(KvError::Conflict(_)) => Err(conflict(id)),
+        Err(e) => Err(e),
+    }
+}
+
+/// 按 lease 的 key 撤销 lease，lease 不存在时返回 None
+fn revoke_key(store: &impl Storage, lease: &str) -> Result<Option<Vec<(String, Bytes)>>, KvError> {
+    for _ in 0..MAX_RETRIES {
+        let watched = [
+            version_of(store, LEASE_TABLE, lease.as_bytes())?,
+            version_of(store, LEASE_KEYS_TABLE, lease.as_bytes())?,
+        ];
+        let exists = store.contains(LEASE_TABLE, lease)?;
+        if !exists && !store.contains(LEASE_KEYS_TABLE, lease)? {
+            return Ok(None);
+        }
+        let keys: Vec<(String, Bytes)> = attached(store, lease)?
+            .members
+            .iter()
+            .filter_map(decode_member)
+            .collect();
+        let mut records = vec![
+            WalRecord::new_del(LEASE_TABLE, lease),
+            WalRecord::new_del(LEASE_KEYS_TABLE, lease),
+        ];
+        for (table, key) in &keys {
+            records.push(WalRecord::new_del(table, key));
+            records.push(WalRecord::new_del(KEY_LEASE_TABLE, owner_key(table, key)));
+        }
+        if store.apply_batch(records, &watched)? {
+            return Ok(Some(keys));
+        }
+    }
+    Err(KvError::Conflict(format!(
+        "too many concurrent operations on lease {}",
+        lease
+    )))
+}
+
+/// 撤销所有租户已经过期的 lease，返回删除的 key
+pub(super) fn revoke_expired(store: &impl Storage) -> Result<Vec<(String, Bytes)>, KvError> {
+    let leases: Vec<String> = store
+        .get_iter(LEASE_KEYS_TABLE)?
+        .filter_map(|pair| String::from_utf8(pair.key.to_vec()).ok())
+        .collect();
+    let mut deleted = Vec::new();
+    for lease in leases {
+        if store.contains(LEASE_TABLE, &lease)? {
+            continue;
+        }
+        // 返回 None 说明同时被 LEASE REVOKE 撤销了
+        if let Some(keys) = revoke_key(store, &lease)? {
+            deleted.extend(keys);
+        }
+    }
+    Ok(deleted)
+}
+
+fn lease_of(store: &impl Storage, owner: &[u8]) -> Result<Option<String>, KvError> {
+    match store.get(KEY_LEASE_TABLE, owner)? {
+        Some(Value {
+            value: Some(value::Value::String(lease)),
+        }) => Ok(Some(lease)),
+        _ => Ok(None),
+    }
+}
+
+fn attached(store: &impl Storage, lease: &str) -> Result<ValueSet, KvError> {
+    match store.get(LEASE_KEYS_TABLE, lease)? {
+        Some(Value {
+            value: Some(value::Value::Set(set)),
+        }) => Ok(set),
+        _ => Ok(ValueSet::default()),
+    }
+}
+
+/// [table, key] 转换回 (table, key)
+fn decode_member(member: &Value) -> Option<(String, Bytes)> {
+    let Some(value::Value::List(list)) = &member.value else {
+        return None;
+    };
+    match list.values.as_slice() {
+        [Value {
+            value: Some(value::Value::String(table)),
+        }, Value {
+            value: Some(value::Value::Binary(key)),
+        }] => Some((table.clone(), key.clone())),
+        _ => None,
+    }
+}
+
+/// 把删除的 key 推送给 replica；lease 本身不复制，replica 上的 key 由 primary 删除
+fn replicate_deleted(replicator: &Replicator, store: &impl Storage, keys: Vec<(String, Bytes)>) {
+    let mut tables: BTreeMap<String, Vec<Bytes>> = BTreeMap::new();
+    for (table, key) in keys {
+        tables.entry(table).or_default().push(key);
+    }
+    for (table, keys) in tables {
+        if let Err(e) = replicator.record(&RequestData::Hmdel(Hmdel { table, keys }), store) {
+            warn!("Failed to record keys deleted with leases: {:?}", e);
+        }
+    }
+}
+
+impl<Store: Storage> Service<Store> {
+    pub(super) fn lease_grant(&self, v: &LeaseGrant) -> CommandResponse {
+        match grant(&self.store, &v.namespace, v.id, v.ttl) {
+            Ok(id) => vec![Value::from(id as i64), (v.ttl as i64).into()].into(),
+            Err(e) => e.into(),
+        }
+    }
+
+    pub(super) fn lease_keep_alive(&self, v: &LeaseKeepAlive) -> CommandResponse {
+        match keep_alive(&self.store, &v.namespace, v.id) {
+            Ok(ttl) => Value::from(ttl as i64).into(),
+            Err(e) => e.into(),
+        }
+    }
+
+    pub(super) fn lease_revoke(&self, v: &LeaseRevoke) -> CommandResponse {
+        match revoke(&self.store, &v.namespace, v.id) {
+            Ok(keys) => {
+                let deleted = keys.len() as i64;
+                replicate_deleted(&self.replicator, &self.store, keys);
+                Value::from(deleted).into()
+            }
+            Err(e) => e.into(),
+        }
+    }
+
+    /// 启动后台任务，每隔 interval 撤销一次已过期的 lease；Service 全部 drop 之后任务自动退出。
+    /// replica 不需要启动，key 由 primary 删除之后同步过来
+    pub fn spawn_lease_task(&self, interval: Duration) -> JoinHandle<()> {
+        let inner = Arc::downgrade(&self.inner);
+        let replicator = self.replicator.clone();
+        tokio::spawn(async move {
+            let mut ticker = time::interval(interval);
+            loop {
+                ticker.tick().await;
+                let Some(inner) = inner.upgrade() else {
+                    break;
+                };
+                match revoke_expired(&inner.store) {
+                    Ok(keys) if keys.is_empty() => {}
+                    Ok(keys) => {
+                        debug!("Deleted {} keys of expired leases", keys.len());
+                        replicate_deleted(&replicator, &inner.store, keys);
+                    }
+                    Err(e) => warn!("Failed to revoke expired leases: {:?}", e),
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod lease_tests {
+    use futures::StreamExt;
+
+    use super::*;
+    use crate::{
+        assert_res_error, assert_res_ok, memory::MemTable, pb::abi::CommandRequest,
+        service_builder::ServiceBuilder, session::Session, sled_db::SledDB,
+    };
+    use tempfile::tempdir;
+
+    async fn execute(service: &Service, cmd: CommandRequest) -> Arc<CommandResponse> {
+        service
+            .execute_with(cmd, &Session::new())
+            .next()
+            .await
+            .unwrap()
+    }
+
+    fn lease_should_work(store: impl Storage) {
+        let id = grant(&store, "", 0, 10).unwrap();
+        assert!(matches!(
+            grant(&store, "", id, 10),
+            Err(KvError::Conflict(_))
+        ));
+        attach(&store, "", id, "t1", b"k1", "v1".into()).unwrap();
+        attach(&store, "", id, "t2", b"k2", "v2".into()).unwrap();
+        let old = attach(&store, "", id, "t1", b"k1", "v3".into()).unwrap();
+        assert_eq!(old, Some("v1".into()));
+        assert!(store.ttl(LEASE_TABLE, lease_key("", id)).unwrap().is_some());
+        assert_eq!(keep_alive(&store, "", id).unwrap(), 10);
+
+        let mut keys = revoke(&store, "", id).unwrap();
+        keys.sort();
+        assert_eq!(
+            keys,
+            [("t1".into(), "k1".into()), ("t2".into(), "k2".into())]
+        );
+        assert!(!store.contains("t1", "k1").unwrap());
+        assert!(!store.contains("t2", "k2").unwrap());
+        assert!(matches!(revoke(&store, "", id), Err(KvError::NotFound(_))));
+        assert!(matches!(
+            keep_alive(&store, "", id),
+            Err(KvError::NotFound(_))
+        ));
+        let res = attach(&store, "", id, "t1", b"k1", "v1".into());
+        assert!(matches!(res, Err(KvError::NotFound(_))));
+    }
+
+    #[test]
+    fn memtable_lease_should_work() {
+        lease_should_work(MemTable::new());
+    }
+
+    #[test]
+    fn sled_lease_should_work() {
+        let dir = tempdir().unwrap();
+        lease_should_work(SledDB::new(dir.path()));
+    }
+
+    #[test]
+    fn leases_should_be_isolated_by_namespace() {
+        let store = MemTable::new();
+        grant(&store, "", 7, 10).unwrap();
+        grant(&store, "billing:", 7, 10).unwrap();
+        attach(&store, "billing:", 7, "billing:t1", b"k1", "v1".into()).unwrap();
+
+        // 别的租户看不到这个 lease，也不能撤销它
+        assert!(matches!(
+            keep_alive(&store, "orders:", 7),
+            Err(KvError::NotFound(_))
+        ));
+        assert!(matches!(
+            revoke(&store, "orders:", 7),
+            Err(KvError::NotFound(_))
+        ));
+        assert!(revoke(&store, "", 7).unwrap().is_empty());
+        assert!(store.contains("billing:t1", "k1").unwrap());
+
+        let keys = revoke(&store, "billing:", 7).unwrap();
+        assert_eq!(keys, [("billing:t1".into(), "k1".into())]);
+        assert!(!store.contains("billing:t1", "k1").unwrap());
+    }
+
+    #[test]
+    fn revoke_expired_should_cover_all_namespaces() {
+        let store = MemTable::new();
+        grant(&store, "billing:", 7, 10).unwrap();
+        attach(&store, "billing:", 7, "billing:t1", b"k1", "v1".into()).unwrap();
+        assert!(revoke_expired(&store).unwrap().is_empty());
+
+        store.del(LEASE_TABLE, lease_key("billing:", 7)).unwrap();
+        let keys = revoke_expired(&store).unwrap();
+        assert_eq!(keys, [("billing:t1".into(), "k1".into())]);
+        assert!(!store.contains("billing:t1", "k1").unwrap());
+    }
+
+    #[tokio::test]
+    async fn writes_without_lease_should_detach_keys() {
+        let service: Service = ServiceBuilder::default().finish();
+        for id in [7, 8] {
+            execute(&service, CommandRequest::new_lease_grant_with_id(id, 10)).await;
+        }
+        for key in ["k1", "k2", "k3"] {
+            let cmd = CommandRequest::new_hset_with_lease("t1", key, "v1", 7);
+            execute(&service, cmd).await;
+        }
+
+        // 普通的 HSET 覆盖、删除之后重新写入、关联到别的 lease，撤销原来的 lease 时都不再删除
+        execute(&service, CommandRequest::new_hset("t1", "k1", "v2")).await;
+        execute(&service, CommandRequest::new_hdel("t1", "k2")).await;
+        execute(&service, CommandRequest::new_hset("t1", "k2", "v2")).await;
+        let cmd = CommandRequest::new_hset_with_lease("t1", "k3", "v2", 8);
+        execute(&service, cmd).await;
+        let res = execute(&service, CommandRequest::new_lease_revoke(7)).await;
+        assert_res_ok(&res, &[0.into()], &[]);
+        for key in ["k1", "k2", "k3"] {
+            assert_eq!(service.store.get("t1", key).unwrap(), Some("v2".into()));
+        }
+
+        let res = execute(&service, CommandRequest::new_lease_revoke(8)).await;
+        assert_res_ok(&res, &[1.into()], &[]);
+        assert!(!service.store.contains("t1", "k3").unwrap());
+        assert!(service.store.get_all(KEY_LEASE_TABLE).unwrap().is_empty());
+    }
+
+    #[test]
+    fn grant_should_reject_zero_ttl() {
+        let res = grant(&MemTable::new(), "", 0, 0);
+        assert!(matches!(res, Err(KvError::InvalidCommand(_))));
+    }
+
+    #[tokio::test]
+    async fn expired_lease_should_delete_keys() {
+        let service: Service = ServiceBuilder::default().finish();
+        let res = execute(&service, CommandRequest::new_lease_grant_with_id(7, 1)).await;
+        assert_res_ok(&res, &[7.into(), 1.into()], &[]);
+        let cmd = CommandRequest::new_hset_with_lease("t1", "k1", "v1", 7);
+        execute(&service, cmd).await;
+        // ttl 和 lease 不能同时使用
+        let mut cmd = CommandRequest::new_hset_with_lease("t1", "k2", "v2", 7);
+        if let Some(RequestData::Hset(v)) = &mut cmd.request_data {
+            v.ttl = 10;
+        }
+        let res = execute(&service, cmd).await;
+        assert_res_error(&res, 400, "lease");
+
+        let handle = service.spawn_lease_task(Duration::from_millis(50));
+        time::sleep(Duration::from_millis(500)).await;
+        assert!(service.store.contains("t1", "k1").unwrap());
+        let res = execute(&service, CommandRequest::new_lease_keep_alive(7)).await;
+        assert_res_ok(&res, &[1.into()], &[]);
+
+        time::sleep(Duration::from_millis(1200)).await;
+        assert!(!service.store.contains("t1", "k1").unwrap());
+        assert!(!service.store.contains(LEASE_KEYS_TABLE, "7").unwrap());
+        let res = execute(&service, CommandRequest::new_lease_revoke(7)).await;
+        assert_res_error(&res, 404, "lease 7");
+        handle.abort();
+    }
+}
@@ -0,0 +1,197 @@
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use tracing::instrument;
+
+use super::{command_service::CommandService, transaction::version_of};
+use crate::{
+    error::KvError,
+    pb::abi::{CommandResponse, Lock, Unlock, Value, WalRecord},
+    storage::now_millis,
+    Storage,
+};
+
+/// 锁被并发修改时最多重试的次数
+const MAX_RETRIES: usize = 16;
+
+/// 上一次分配的 fencing token
+static LAST_TOKEN: AtomicU64 = AtomicU64::new(0);
+
+/// 分配一个新的 fencing token。用微秒时间戳而不是从 1 开始计数，
+/// 这样服务器重启之后分配的 token 仍然比之前的大
+pub(super) fn next_token() -> u64 {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_micros() as u64;
+    let next = |last: u64| now.max(last + 1);
+    let last = LAST_TOKEN
+        .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |last| Some(next(last)))
+        .unwrap_or_default();
+    next(last)
+}
+
+/// 当前持有锁的 token，锁不存在时为 None；值不是整数（被别的命令覆盖了）时当成 0，谁都不能释放
+fn holder_of(store: &impl Storage, table: &str, name: &str) -> Result<Option<u64>, KvError> {
+    Ok(store
+        .get(table, name)?
+        .map(|v| v.to_integer().unwrap_or_default() as u64))
+}
+
+impl CommandService for Lock {
+    #[instrument(name = "storage_lock", skip_all, fields(table = %self.table))]
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        if self.ttl_ms == 0 {
+            return KvError::InvalidCommand("lock ttl must be greater than 0".into()).into();
+        }
+        match self.acquire(store) {
+            Ok((locked, token)) => vec![Value::from(locked), (token as i64).into()].into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl Lock {
+    /// 返回 [是否成功, token]；续约时锁已经不存在了，token 为 0
+    fn acquire(&self, store: &impl Storage) -> Result<(bool, u64), KvError> {
+        for _ in 0..MAX_RETRIES {
+            // 版本号要在读取之前获取，读取时锁刚好过期被删除也会让版本号变化
+            let watched = version_of(store, &self.table, self.name.as_bytes())?;
+            let (token, mut records) =
+                match (holder_of(store, &self.table, &self.name)?, self.token) {
+                    (None, 0) => {
+                        let token = next_token();
+                        let value = (token as i64).into();
+                        (
+                            token,
+                            vec![WalRecord::new_set(&self.table, &self.name, value)],
+                        )
+                    }
+                    (None, _) => return Ok((false, 0)),
+                    (Some(holder), token) if token != 0 && holder == token => (token, vec![]),
+                    (Some(holder), _) => return Ok((false, holder)),
+                };
+            let deadline = now_millis() + self.ttl_ms;
+            records.push(WalRecord::new_expire(&self.table, &self.name, deadline));
+            if store.apply_batch(records, &[watched])? {
+                return Ok((true, token));
+            }
+        }
+        Err(KvError::Conflict(format!(
+            "too many concurrent operations on lock {}/{}",
+            self.table, self.name
+        )))
+    }
+}
+
+impl CommandService for Unlock {
+    #[instrument(name = "storage_unlock", skip_all, fields(table = %self.table))]
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        match self.release(store) {
+            Ok(released) => vec![Value::from(released)].into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl Unlock {
+    fn release(&self, store: &impl Storage) -> Result<bool, KvError> {
+        if self.token == 0 {
+            return Ok(false);
+        }
+        for _ in 0..MAX_RETRIES {
+            let watched = version_of(store, &self.table, self.name.as_bytes())?;
+            if holder_of(store, &self.table, &self.name)? != Some(self.token) {
+                return Ok(false);
+            }
+            let records = vec![WalRecord::new_del(&self.table, &self.name)];
+            if store.apply_batch(records, &[watched])? {
+                return Ok(true);
+            }
+        }
+        Err(KvError::Conflict(format!(
+            "too many concurrent operations on lock {}/{}",
+            self.table, self.name
+        )))
+    }
+}
+
+#[cfg(test)]
+mod lock_tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::{memory::MemTable, pb::abi::CommandRequest, service::dispatch, sled_db::SledDB};
+    use tempfile::tempdir;
+
+    fn lock(store: &impl Storage, cmd: CommandRequest) -> (bool, u64) {
+        let res = dispatch(cmd, store);
+        assert_eq!(res.status, 200, "{}", res.message);
+        let locked = res.values[0] == true.into();
+        (locked, res.values[1].to_integer().unwrap() as u64)
+    }
+
+    fn unlock(store: &impl Storage, token: u64) -> bool {
+        let res = dispatch(CommandRequest::new_unlock("locks", "l1", token), store);
+        res.values[0] == true.into()
+    }
+
+    fn lock_should_work(store: impl Storage) {
+        let ttl = Duration::from_millis(100);
+        let (locked, token) = lock(&store, CommandRequest::new_lock("locks", "l1", ttl));
+        assert!(locked);
+
+        // 被持有时获取失败，返回持有者的 token
+        let res = lock(&store, CommandRequest::new_lock("locks", "l1", ttl));
+        assert_eq!(res, (false, token));
+        // 只有持有者能续约和释放
+        let renew = CommandRequest::new_renew_lock("locks", "l1", ttl, token + 1);
+        assert_eq!(lock(&store, renew), (false, token));
+        assert!(!unlock(&store, token + 1));
+        let renew = CommandRequest::new_renew_lock("locks", "l1", ttl * 10, token);
+        assert_eq!(lock(&store, renew), (true, token));
+        assert!(store.ttl("locks", "l1").unwrap().unwrap() > ttl);
+
+        assert!(unlock(&store, token));
+        assert!(!unlock(&store, token));
+        // 锁已经不存在，不能续约
+        let renew = CommandRequest::new_renew_lock("locks", "l1", ttl, token);
+        assert_eq!(lock(&store, renew), (false, 0));
+
+        // 租约到期之后别人可以获取，token 比之前的大
+        let (locked, t1) = lock(&store, CommandRequest::new_lock("locks", "l1", ttl));
+        assert!(locked && t1 > token);
+        std::thread::sleep(ttl + Duration::from_millis(50));
+        let (locked, t2) = lock(&store, CommandRequest::new_lock("locks", "l1", ttl));
+        assert!(locked && t2 > t1);
+        assert!(!unlock(&store, t1));
+    }
+
+    #[test]
+    fn memtable_lock_should_work() {
+        lock_should_work(MemTable::new());
+    }
+
+    #[test]
+    fn sled_lock_should_work() {
+        let dir = tempdir().unwrap();
+        lock_should_work(SledDB::new(dir.path()));
+    }
+
+    #[test]
+    fn lock_should_reject_zero_ttl() {
+        let store = MemTable::new();
+        let cmd = CommandRequest::new_lock("locks", "l1", Duration::ZERO);
+        let res = dispatch(cmd, &store);
+        assert_eq!(res.status, 400);
+        assert_eq!(store.get("locks", "l1").unwrap(), None::<Value>);
+    }
+
+    #[test]
+    fn next_token_should_increase() {
+        let tokens: Vec<u64> = (0..1000).map(|_| next_token()).collect();
+        assert!(tokens.windows(2).all(|w| w[0] < w[1]));
+    }
+}
@@ -0,0 +1,392 @@
+//! 节点发现：每个节点定期增加自己的心跳，随机选一个节点交换各自知道的节点和心跳，
+//! 新的节点和心跳就这样传播到整个集群。一个节点的心跳长时间不增加，说明它已经连不上任何节点，
+//! 先被标记为 suspect，再被标记为 dead；它重新上线之后心跳继续增加，又变回 alive
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex as StdMutex, Weak},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use rand::seq::SliceRandom;
+use tokio::{sync::Mutex, time};
+use tracing::{debug, info};
+
+use super::{shutdown::Shutdown, Service};
+use crate::{
+    config::{ClientConfig, MembershipConfig},
+    error::KvError,
+    network::pool::Pool,
+    pb::abi::{value, CommandRequest, CommandResponse, Gossip, Kvpair, Member, Value, ValueList},
+    Storage,
+};
+
+/// 默认的 gossip 间隔（毫秒）
+const DEFAULT_INTERVAL: u64 = 1000;
+
+/// 默认多久没有新的心跳认为节点可疑（毫秒）
+const DEFAULT_FAILURE_TIMEOUT: u64 = 5000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemberState {
+    Alive,
+    /// 一段时间没有新的心跳，可能已经下线，也可能只是消息还没有传过来
+    Suspect,
+    Dead,
+}
+
+impl MemberState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MemberState::Alive => "alive",
+            MemberState::Suspect => "suspect",
+            MemberState::Dead => "dead",
+        }
+    }
+}
+
+/// 知道的一个节点
+struct Peer {
+    generation: u64,
+    heartbeat: u64,
+    /// 最后一次收到新心跳的时刻
+    updated: Instant,
+}
+
+/// 本节点知道的所有节点，以及定期 gossip 的后台任务
+pub struct Membership {
+    addr: String,
+    generation: u64,
+    heartbeat: StdMutex<u64>,
+    peers: StdMutex<HashMap<String, Peer>>,
+    seeds: Vec<String>,
+    /// 连接其它节点使用的配置，没有 seed 时为 None，只被动地回复其它节点
+    client: Option<ClientConfig>,
+    pools: Mutex<HashMap<String, Pool>>,
+    interval: Duration,
+    failure_timeout: Duration,
+}
+
+impl Membership {
+    /// addr 是其它节点访问本节点的地址
+    pub fn new(config: &MembershipConfig, addr: impl Into<String>) -> Self {
+        // 用启动时刻作为 generation，重启之后的心跳从 0 开始也不会被认为是旧的
+        let generation = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+        Self {
+            addr: addr.into(),
+            generation,
+            heartbeat: StdMutex::new(0),
+            peers: Default::default(),
+            seeds: config
+                .seeds
+                .iter()
+                .map(|seed| seed.general.addr.clone())
+                .collect(),
+            client: config.seeds.first().cloned(),
+            pools: Default::default(),
+            interval: Duration::from_millis(config.interval.unwrap_or(DEFAULT_INTERVAL).max(1)),
+            failure_timeout: Duration::from_millis(
+                config
+                    .failure_timeout
+                    .unwrap_or(DEFAULT_FAILURE_TIMEOUT)
+                    .max(1),
+            ),
+        }
+    }
+
+    /// 每隔 interval 增加一次心跳，和一个节点交换成员列表；Membership drop 或者服务器退出之后任务结束
+    pub(super) fn spawn(self: &Arc<Self>, shutdown: Shutdown) {
+        let membership: Weak<Self> = Arc::downgrade(self);
+        let interval = self.interval;
+        tokio::spawn(async move {
+            let mut ticker = time::interval(interval);
+            loop {
+                ticker.tick().await;
+                let Some(membership) = membership.upgrade() else {
+                    break;
+                };
+                if shutdown.is_triggered() {
+                    break;
+                }
+                *membership.heartbeat.lock().unwrap() += 1;
+                let Some(target) = membership.target(Instant::now()) else {
+                    continue;
+                };
+                match time::timeout(interval, membership.gossip(&target)).await {
+                    Ok(Ok(())) => {}
+                    Ok(Err(e)) => debug!("Failed to gossip with {}: {}", target, e),
+                    Err(_) => debug!("Gossip with {} timed out", target),
+                }
+            }
+        });
+    }
+
+    /// 节点的状态，按地址排序，包括本节点
+    pub fn members(&self, now: Instant) -> Vec<(String, MemberState)> {
+        let mut members: Vec<_> = self
+            .peers
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(addr, peer)| (addr.clone(), self.state(peer, now)))
+            .collect();
+        members.push((self.addr.clone(), MemberState::Alive));
+        members.sort_by(|a, b| a.0.cmp(&b.0));
+        members
+    }
+
+    /// 合并其它节点发来的成员列表：generation 更大，或者 generation 相同而心跳更大时更新
+    pub fn merge(&self, members: &[Member], now: Instant) {
+        let mut peers = self.peers.lock().unwrap();
+        for member in members.iter().filter(|m| m.addr != self.addr) {
+            match peers.get_mut(&member.addr) {
+                Some(peer)
+                    if (member.generation, member.heartbeat)
+                        > (peer.generation, peer.heartbeat) =>
+                {
+                    if self.state(peer, now) == MemberState::Dead {
+                        info!("Node {} is alive again", member.addr);
+                    }
+                    peer.generation = member.generation;
+                    peer.heartbeat = member.heartbeat;
+                    peer.updated = now;
+                }
+                Some(_) => {}
+                None => {
+                    info!("Discovered node {}", member.addr);
+                    peers.insert(
+                        member.addr.clone(),
+                        Peer {
+                            generation: member.generation,
+                            heartbeat: member.heartbeat,
+                            updated: now,
+                        },
+                    );
+                }
+            }
+        }
+    }
+
+    /// 发给其它节点的成员列表：本节点和还没有下线的节点，下线的节点不再传播
+    pub fn digest(&self, now: Instant) -> Vec<Member> {
+        let mut members = vec![Member {
+            addr: self.addr.clone(),
+            generation: self.generation,
+            heartbeat: *self.heartbeat.lock().unwrap(),
+        }];
+        let peers = self.peers.lock().unwrap();
+        for (addr, peer) in peers.iter() {
+            if self.state(peer, now) != MemberState::Dead {
+                members.push(Member {
+                    addr: addr.clone(),
+                    generation: peer.generation,
+                    heartbeat: peer.heartbeat,
+                });
+            }
+        }
+        members
+    }
+
+    /// 处理其它节点发来的 GOSSIP，返回本节点知道的节点
+    pub(super) fn handle_gossip(&self, gossip: &Gossip) -> CommandResponse {
+        let now = Instant::now();
+        self.merge(&gossip.members, now);
+        self.digest(now)
+            .into_iter()
+            .map(|m| {
+                let values = vec![(m.generation as i64).into(), (m.heartbeat as i64).into()];
+                Kvpair::new(m.addr, ValueList { values }.into())
+            })
+            .collect::<Vec<_>>()
+            .into()
+    }
+
+    fn state(&self, peer: &Peer, now: Instant) -> MemberState {
+        match now.saturating_duration_since(peer.updated) {
+            elapsed if elapsed < self.failure_timeout => MemberState::Alive,
+            elapsed if elapsed < self.failure_timeout * 2 => MemberState::Suspect,
+            _ => MemberState::Dead,
+        }
+    }
+
+    /// 这一轮 gossip 的对象：随机一个没有下线的节点，还不知道这样的节点时随机一个 seed
+    fn target(&self, now: Instant) -> Option<String> {
+        let peers = self.peers.lock().unwrap();
+        let alive: Vec<_> = peers
+            .iter()
+            .filter(|(_, peer)| self.state(peer, now) != MemberState::Dead)
+            .map(|(addr, _)| addr)
+            .collect();
+        let mut rng = rand::thread_rng();
+        match alive.choose(&mut rng) {
+            Some(addr) => Some(addr.to_string()),
+            None => self.seeds.choose(&mut rng).cloned(),
+        }
+    }
+
+    async fn gossip(&self, addr: &str) -> Result<(), KvError> {
+        let cmd = CommandRequest::new_gossip(self.digest(Instant::now()));
+        let res = self.pool(addr).await?.execute(&cmd).await?;
+        if res.status != 200 {
+            return Err(KvError::Internal(res.message));
+        }
+        let members: Vec<_> = res.pairs.iter().filter_map(parse_member).collect();
+        self.merge(&members, Instant::now());
+        Ok(())
+    }
+
+    async fn pool(&self, addr: &str) -> Result<Pool, KvError> {
+        let Some(client) = &self.client else {
+            return Err(KvError::InvalidCommand("no seed to connect nodes".into()));
+        };
+        let mut pools = self.pools.lock().await;
+        if let Some(pool) = pools.get(addr) {
+            return Ok(pool.clone());
+        }
+        let mut config = client.clone();
+        config.general.addr = addr.into();
+        let pool = Pool::connect(config).await?;
+        pools.insert(addr.into(), pool.clone());
+        Ok(pool)
+    }
+}
+
+/// GOSSIP 回复中的一个节点：地址 -> [generation, heartbeat]
+fn parse_member(pair: &Kvpair) -> Option<Member> {
+    let values = match pair.value.as_ref()?.value.as_ref()? {
+        value::Value::List(list) => &list.values,
+        _ => return None,
+    };
+    let integer = |i: usize| values.get(i).and_then(Value::to_integer).map(|n| n as u64);
+    Some(Member {
+        addr: String::from_utf8_lossy(&pair.key).into_owned(),
+        generation: integer(0)?,
+        heartbeat: integer(1)?,
+    })
+}
+
+impl<Store: Storage> Service<Store> {
+    /// 启动节点发现，addr 是其它节点访问本节点的地址
+    pub fn start_membership(&self, config: &MembershipConfig, addr: &str) {
+        let membership = Arc::new(Membership::new(config, addr));
+        membership.spawn(self.shutdown.clone());
+        info!(
+            "Node {} started gossip with {} seeds",
+            addr,
+            config.seeds.len()
+        );
+        *self.membership.write().unwrap() = Some(membership);
+    }
+
+    pub fn membership(&self) -> Option<Arc<Membership>> {
+        self.membership.read().unwrap().clone()
+    }
+
+    pub(super) fn gossip(&self, gossip: &Gossip) -> CommandResponse {
+        match self.membership() {
+            Some(membership) => membership.handle_gossip(gossip),
+            None => membership_disabled().into(),
+        }
+    }
+
+    /// MEMBERS：各个节点的地址和状态
+    pub(super) fn members(&self) -> CommandResponse {
+        let Some(membership) = self.membership() else {
+            return membership_disabled().into();
+        };
+        membership
+            .members(Instant::now())
+            .into_iter()
+            .map(|(addr, state)| Kvpair::new(addr, state.as_str().into()))
+            .collect::<Vec<_>>()
+            .into()
+    }
+}
+
+fn membership_disabled() -> KvError {
+    KvError::InvalidCommand("membership is not enabled".into())
+}
+
+#[cfg(test)]
+mod membership_tests {
+    use futures::StreamExt;
+
+    use super::*;
+    use crate::service_builder::ServiceBuilder;
+
+    fn config() -> MembershipConfig {
+        MembershipConfig {
+            seeds: vec![],
+            advertise_addr: None,
+            interval: Some(100),
+            failure_timeout: Some(1000),
+        }
+    }
+
+    fn member(addr: &str, generation: u64, heartbeat: u64) -> Member {
+        Member {
+            addr: addr.into(),
+            generation,
+            heartbeat,
+        }
+    }
+
+    #[test]
+    fn members_should_be_suspected_then_dead_without_heartbeat() {
+        let membership = Membership::new(&config(), "10.0.0.1:9876");
+        let now = Instant::now();
+        membership.merge(&[member("10.0.0.2:9876", 1, 1)], now);
+        let state = |at: Instant| membership.members(at)[1].1;
+        assert_eq!(state(now), MemberState::Alive);
+        assert_eq!(
+            state(now + Duration::from_millis(1500)),
+            MemberState::Suspect
+        );
+        assert_eq!(state(now + Duration::from_millis(2500)), MemberState::Dead);
+
+        // 旧的心跳不算
+        let later = now + Duration::from_millis(1500);
+        membership.merge(&[member("10.0.0.2:9876", 1, 1)], later);
+        assert_eq!(state(later), MemberState::Suspect);
+        membership.merge(&[member("10.0.0.2:9876", 1, 2)], later);
+        assert_eq!(state(later), MemberState::Alive);
+
+        // 下线的节点不再传播，重启之后 generation 更大，心跳从头开始也会被接受
+        let dead = now + Duration::from_millis(5000);
+        assert_eq!(state(dead), MemberState::Dead);
+        assert_eq!(membership.digest(dead).len(), 1);
+        membership.merge(&[member("10.0.0.2:9876", 2, 0)], dead);
+        assert_eq!(state(dead), MemberState::Alive);
+        assert_eq!(membership.digest(dead).len(), 2);
+    }
+
+    #[tokio::test]
+    async fn gossip_should_exchange_members() {
+        let service: Service = ServiceBuilder::default().finish();
+        let res = service.execute(CommandRequest::new_members());
+        assert_eq!(res.into_future().await.0.unwrap().status, 400);
+
+        service.start_membership(&config(), "10.0.0.1:9876");
+        let cmd = CommandRequest::new_gossip(vec![
+            member("10.0.0.2:9876", 1, 5),
+            member("10.0.0.3:9876", 1, 3),
+        ]);
+        let res = service.execute(cmd);
+        let res = res.into_future().await.0.unwrap();
+        let addrs: Vec<_> = res.pairs.iter().map(|p| p.key.clone()).collect();
+        assert_eq!(addrs.len(), 3);
+        let members: Vec<_> = res.pairs.iter().filter_map(parse_member).collect();
+        assert!(members.contains(&member("10.0.0.2:9876", 1, 5)));
+
+        let res = service.execute(CommandRequest::new_members());
+        let res = res.into_future().await.0.unwrap();
+        let expected: Vec<_> = ["10.0.0.1:9876", "10.0.0.2:9876", "10.0.0.3:9876"]
+            .into_iter()
+            .map(|addr| Kvpair::new(addr, "alive".into()))
+            .collect();
+        assert_eq!(res.pairs, expected);
+    }
+}
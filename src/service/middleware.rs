@@ -0,0 +1,162 @@
+//! 中间件：嵌入 kv-sv 的程序可以在命令执行前后插入自己的逻辑，比如认证、校验、统计和改写，
+//! 不需要修改 service 模块
+
+use std::sync::Arc;
+
+use futures::StreamExt;
+
+use super::{session::Session, topic_service::StreamingResponse, Service};
+use crate::{
+    error::KvError,
+    pb::abi::{CommandRequest, CommandResponse},
+    Storage,
+};
+
+/// 命令执行前后调用的中间件。before 按注册的顺序调用，after 按相反的顺序调用；
+/// 某个中间件的 before 返回错误时，命令和后面的中间件都不会执行，错误作为响应交给前面的中间件的 after
+pub trait Middleware: Send + Sync + 'static {
+    /// 命令执行之前调用，可以修改命令；返回错误时不执行命令
+    fn before(&self, _cmd: &mut CommandRequest, _session: &Session) -> Result<(), KvError> {
+        Ok(())
+    }
+
+    /// 命令执行之后调用，可以修改响应；流式的响应每一帧调用一次
+    fn after(&self, _cmd: &CommandRequest, _res: &mut CommandResponse) {}
+}
+
+/// 只需要在执行之前检查或者改写命令时，可以直接用一个函数作为中间件
+impl<F> Middleware for F
+where
+    F: Fn(&mut CommandRequest, &Session) -> Result<(), KvError> + Send + Sync + 'static,
+{
+    fn before(&self, cmd: &mut CommandRequest, session: &Session) -> Result<(), KvError> {
+        self(cmd, session)
+    }
+}
+
+impl<Store: Storage> Service<Store> {
+    /// 依次调用中间件的 before，执行命令之后依次调用 after
+    pub(super) fn execute_middlewares(
+        &self,
+        mut cmd: CommandRequest,
+        session: &Session,
+    ) -> StreamingResponse {
+        if self.middlewares.is_empty() {
+            return self.execute_command(cmd, session);
+        }
+        let mut passed = 0;
+        let mut rejected = None;
+        for m in &self.middlewares {
+            if let Err(e) = m.before(&mut cmd, session) {
+                rejected = Some(e);
+                break;
+            }
+            passed += 1;
+        }
+        let res = match rejected {
+            Some(e) => Box::pin(futures::stream::once(async move { Arc::new(e.into()) })),
+            None => self.execute_command(cmd.clone(), session),
+        };
+        let middlewares = self.middlewares[..passed].to_vec();
+        if middlewares.is_empty() {
+            return res;
+        }
+        Box::pin(res.map(move |res| {
+            let mut res = Arc::unwrap_or_clone(res);
+            for m in middlewares.iter().rev() {
+                m.after(&cmd, &mut res);
+            }
+            Arc::new(res)
+        }))
+    }
+}
+
+#[cfg(test)]
+mod middleware_tests {
+    use std::sync::Mutex;
+
+    use hyper::StatusCode;
+
+    use super::*;
+    use crate::{
+        pb::abi::{command_request::RequestData, Value},
+        service_builder::ServiceBuilder,
+    };
+
+    /// 记录调用的顺序
+    struct Trace(&'static str, Arc<Mutex<Vec<String>>>);
+
+    impl Middleware for Trace {
+        fn before(&self, _cmd: &mut CommandRequest, _session: &Session) -> Result<(), KvError> {
+            self.1.lock().unwrap().push(format!("before {}", self.0));
+            Ok(())
+        }
+
+        fn after(&self, _cmd: &CommandRequest, res: &mut CommandResponse) {
+            self.1.lock().unwrap().push(format!("after {}", self.0));
+            res.message.push_str(self.0);
+        }
+    }
+
+    /// 不允许写 secret table
+    fn deny_secret(cmd: &mut CommandRequest, _session: &Session) -> Result<(), KvError> {
+        match &cmd.request_data {
+            Some(RequestData::Hset(v)) if v.table == "secret" => {
+                Err(KvError::PermissionDenied("secret is read-only".into()))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// 所有的 key 都加上前缀
+    fn prefix_keys(cmd: &mut CommandRequest, _session: &Session) -> Result<(), KvError> {
+        match &mut cmd.request_data {
+            Some(RequestData::Hset(v)) => {
+                if let Some(pair) = v.pair.as_mut() {
+                    pair.key = format!("app:{}", String::from_utf8_lossy(&pair.key)).into();
+                }
+            }
+            Some(RequestData::Hget(v)) => {
+                v.key = format!("app:{}", String::from_utf8_lossy(&v.key)).into()
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn middlewares_should_run_around_command() {
+        let trace = Arc::new(Mutex::new(vec![]));
+        let service: Service = ServiceBuilder::default()
+            .middleware(Trace("a", trace.clone()))
+            .middleware(deny_secret)
+            .middleware(Trace("b", trace.clone()))
+            .middleware(prefix_keys)
+            .finish();
+
+        let res = service.execute(CommandRequest::new_hset("t1", "k1", "v1"));
+        let res = res.into_future().await.0.unwrap();
+        assert_eq!(res.message, "ba");
+        assert_eq!(
+            *trace.lock().unwrap(),
+            ["before a", "before b", "after b", "after a"]
+        );
+        assert_eq!(
+            service.store.get("t1", "app:k1").unwrap(),
+            Some("v1".into())
+        );
+        let res = service.execute(CommandRequest::new_hget("t1", "k1"));
+        assert_eq!(
+            res.into_future().await.0.unwrap().values,
+            [Value::from("v1")]
+        );
+
+        // 被拒绝的命令不会执行，只有前面的中间件看到错误
+        trace.lock().unwrap().clear();
+        let res = service.execute(CommandRequest::new_hset("secret", "k1", "v1"));
+        let res = res.into_future().await.0.unwrap();
+        assert_eq!(res.status, StatusCode::FORBIDDEN.as_u16() as u32);
+        assert_eq!(*trace.lock().unwrap(), ["before a", "after a"]);
+        assert!(service.store.get("secret", "app:k1").unwrap().is_none());
+    }
+}
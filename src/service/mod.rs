@@ -1,36 +1,397 @@
+pub mod acl;
+pub mod audit;
+pub mod cdc;
+pub mod clients;
+pub mod cluster;
 mod command_service;
+pub mod compaction;
+mod config_service;
+pub mod hooks;
+mod hyperloglog;
+pub mod idempotency;
+pub mod ingest;
+mod json_path;
+pub mod keyspace;
+pub mod lease;
+mod lock_service;
+pub mod membership;
+pub mod middleware;
+pub mod mvcc;
 pub mod notify;
+mod pages;
+pub mod plugin;
+pub mod proxy;
+mod raft;
+pub mod rate_limit;
+pub mod registry;
+pub mod replication;
+mod script_service;
 pub mod service_builder;
+pub mod session;
+pub mod shutdown;
+pub mod slowlog;
+pub mod stats;
+pub mod tenant;
 pub mod topic;
 pub mod topic_service;
+mod transaction;
 
 use crate::{
+    config::Verb,
     error::KvError,
+    frame::{Compression, FrameCompression},
     memory::MemTable,
-    pb::abi::{command_request::RequestData, CommandRequest, CommandResponse},
+    pb::abi::{
+        command_request::RequestData, Auth, Batch, CommandRequest, CommandResponse, Hello, Value,
+    },
     Storage,
 };
+use cluster::Cluster;
 use command_service::*;
-use futures::{stream, Stream};
-use std::{ops::Deref, sync::Arc};
+use futures::{future, stream, Stream, StreamExt};
+use keyspace::Keyspace;
+use membership::Membership;
+use replication::Replicator;
+use session::Session;
+use shutdown::Shutdown;
+use stats::Stats;
+use std::{
+    ops::Deref,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, RwLock,
+    },
+    time::{Duration, Instant},
+};
+use tokio::{
+    task::{self, JoinHandle},
+    time,
+};
 use topic_service::*;
-use tracing::info;
+use tracing::{debug, info, instrument, warn, Span};
+
+/// 后台清理过期 key 的默认间隔
+pub const PURGE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// 重新加载配置，由启动服务器的地方提供
+pub type Reloader = Arc<dyn Fn() -> Result<(), KvError> + Send + Sync>;
 
 /// 可以跨线程，可以调用 execute 来执行某个 CommandRequest 命令，返回 CommandResponse。
 pub struct Service<Store = MemTable> {
     inner: Arc<ServiceBuilder<Store>>,
     broadcaster: Arc<BroadCaster>,
+    replicator: Arc<Replicator>,
+    keyspace: Arc<Keyspace>,
+    stats: Arc<Stats>,
+    shutdown: Shutdown,
+    reloader: Arc<RwLock<Option<Reloader>>>,
+    cluster: Arc<RwLock<Option<Arc<Cluster>>>>,
+    membership: Arc<RwLock<Option<Arc<Membership>>>>,
 }
 
 impl<Store: Storage> Service<Store> {
     pub fn execute(&self, cmd: CommandRequest) -> impl Stream<Item = Arc<CommandResponse>> + Send {
-        info!("God request: {:?}", &cmd);
+        self.execute_with(cmd, &Session::default())
+    }
+
+    /// 在某个连接的 session 下执行命令；请求设置了超时时间，或者存储引擎会阻塞线程时放到阻塞线程上执行，
+    /// 超时之后返回 504，到期时还没有开始执行的命令不再执行
+    pub async fn execute_with_deadline(
+        &self,
+        mut cmd: CommandRequest,
+        session: &Arc<Session>,
+    ) -> StreamingResponse {
+        // SELECT 的 table、SESSION SET 的选项和租户的前缀在路由到集群和后端之前生效
+        session.apply_options(&mut cmd);
+        let namespace = self
+            .tenants
+            .read()
+            .unwrap()
+            .namespace(session.user().as_deref());
+        if let (Some(namespace), Some(data)) = (&namespace, cmd.request_data.as_mut()) {
+            tenant::prefix_tables(data, namespace);
+            if tenant::lists_tables(data) {
+                let namespace = namespace.clone();
+                let res = self.execute_routed(cmd, session).await;
+                return Box::pin(
+                    res.map(move |res| Arc::new(tenant::strip_tables(&res, &namespace))),
+                );
+            }
+            if tenant::subscribes_keyevents(data) {
+                let namespace = namespace.clone();
+                let res = self.execute_routed(cmd, session).await;
+                return Box::pin(res.filter_map(move |res| {
+                    future::ready(tenant::strip_keyevent(&res, &namespace).map(Arc::new))
+                }));
+            }
+        }
+        self.execute_routed(cmd, session).await
+    }
+
+    /// 转发到集群的 leader 或者后端节点，或者在本地执行
+    async fn execute_routed(
+        &self,
+        cmd: CommandRequest,
+        session: &Arc<Session>,
+    ) -> StreamingResponse {
+        if let Some(cluster) = self.cluster() {
+            if let Some(res) = self.execute_in_cluster(&cluster, &cmd, session).await {
+                return Box::pin(stream::once(async move { Arc::new(res) }));
+            }
+        }
+        if let Some(proxy) = &self.proxy {
+            if let Some(res) = self.execute_in_proxy(proxy, &cmd, session).await {
+                return Box::pin(stream::once(async move { Arc::new(res) }));
+            }
+        }
+        if cmd.timeout == 0 {
+            return match self.store.is_blocking() {
+                true => self.execute_blocking(cmd, session).await,
+                false => Box::pin(self.execute_with(cmd, session)),
+            };
+        }
+        let timeout = Duration::from_millis(cmd.timeout);
+        let deadline = Instant::now() + timeout;
+        let (service, session) = (self.clone(), session.clone());
+        let span = Span::current();
+        // 阻塞线程上的命令没法中途取消：超时的时候还没开始执行就不再执行，已经开始的会继续执行完
+        let started = Arc::new(AtomicBool::new(false));
+        let task = task::spawn_blocking({
+            let started = started.clone();
+            move || {
+                if Instant::now() >= deadline || started.swap(true, Ordering::AcqRel) {
+                    return None;
+                }
+                let res = span.in_scope(|| service.execute_with(cmd, &session));
+                Some(Box::pin(res) as StreamingResponse)
+            }
+        });
+        let e = match time::timeout_at(deadline.into(), task).await {
+            Ok(Ok(Some(res))) => return res,
+            Ok(Err(e)) => KvError::Internal(format!("Failed to execute command: {}", e)),
+            _ if started.swap(true, Ordering::AcqRel) => KvError::Timeout(format!(
+                "command didn't finish in {:?}, it's still running and its writes may be applied",
+                timeout
+            )),
+            _ => KvError::Timeout(format!(
+                "command didn't start in {:?}, it wasn't executed",
+                timeout
+            )),
+        };
+        Box::pin(stream::once(async move { Arc::new(e.into()) }))
+    }
+
+    /// 在阻塞线程上执行命令，不占用 tokio 的工作线程
+    async fn execute_blocking(
+        &self,
+        cmd: CommandRequest,
+        session: &Arc<Session>,
+    ) -> StreamingResponse {
+        let (service, session) = (self.clone(), session.clone());
+        let span = Span::current();
+        let task = task::spawn_blocking(move || {
+            let res = span.in_scope(|| service.execute_with(cmd, &session));
+            Box::pin(res) as StreamingResponse
+        });
+        match task.await {
+            Ok(res) => res,
+            Err(e) => {
+                let e = KvError::Internal(format!("Failed to execute command: {}", e));
+                Box::pin(stream::once(async move { Arc::new(e.into()) }))
+            }
+        }
+    }
+
+    /// 在某个连接的 session 下执行命令，执行前后调用注册的 hook 和中间件
+    #[instrument(name = "service_execute", skip_all)]
+    pub fn execute_with(
+        &self,
+        cmd: CommandRequest,
+        session: &Session,
+    ) -> impl Stream<Item = Arc<CommandResponse>> + Send {
+        self.execute_hooked(cmd, session)
+    }
+
+    fn execute_command(&self, cmd: CommandRequest, session: &Session) -> StreamingResponse {
+        let start = Instant::now();
+        info!("God request: {:?}", cmd.redacted());
         self.on_received.notify(&cmd);
-        let mut resp = dispatch(cmd.clone(), &self.store);
+        self.stats.command();
+        if let Some(data) = &cmd.request_data {
+            session.set_last_command(data.name());
+        }
+        let mut resp = match &cmd.request_data {
+            Some(RequestData::Ping(ping)) => match ping.message.is_empty() {
+                true => Value::from("PONG").into(),
+                false => Value::from(ping.message.clone()).into(),
+            },
+            Some(RequestData::Hello(hello)) => self.hello(hello, session),
+            Some(RequestData::Multi(_)) => self.multi(&cmd, session),
+            Some(RequestData::Exec(_)) => self.exec(&cmd, session),
+            Some(RequestData::Discard(_)) => self.discard(session),
+            Some(RequestData::Auth(auth)) => self.authenticate(auth, session),
+            Some(RequestData::Select(v)) => match self.authorize(&cmd, session) {
+                Ok(()) => {
+                    session.select(&v.table);
+                    CommandResponse::ok()
+                }
+                Err(e) => e.into(),
+            },
+            Some(RequestData::SessionSet(v)) => {
+                let res = self.authorize(&cmd, session);
+                match res.and_then(|_| session.set_option(&v.name, &v.value)) {
+                    Ok(()) => CommandResponse::ok(),
+                    Err(e) => e.into(),
+                }
+            }
+            Some(_) if session.in_transaction() => self.queue(cmd.clone(), session),
+            Some(RequestData::Watch(_)) => self.watch(&cmd, session),
+            Some(RequestData::Unwatch(_)) => {
+                session.unwatch();
+                CommandResponse::ok()
+            }
+            Some(RequestData::Replicate(_)) => match self.authorize(&cmd, session) {
+                Ok(()) => return self.replicator.replicate(&self.store),
+                Err(e) => e.into(),
+            },
+            Some(RequestData::Hwatch(watch)) => match self.authorize(&cmd, session) {
+                Ok(()) => match self.keyspace.watch(watch.clone()) {
+                    Ok((id, stream)) => {
+                        session.add_watcher(id);
+                        return stream;
+                    }
+                    Err(e) => e.into(),
+                },
+                Err(e) => e.into(),
+            },
+            Some(RequestData::Hunwatch(v)) => match self.authorize(&cmd, session) {
+                Ok(()) if !session.remove_watcher(v.id) => {
+                    KvError::NotFound(format!("watcher {}", v.id)).into()
+                }
+                Ok(()) => match self.keyspace.unwatch(v.id) {
+                    Ok(()) => CommandResponse::ok(),
+                    Err(e) => e.into(),
+                },
+                Err(e) => e.into(),
+            },
+            Some(RequestData::ConfigReload(_)) => {
+                match self.authorize(&cmd, session).and_then(|_| self.reload()) {
+                    Ok(()) => CommandResponse::ok(),
+                    Err(e) => e.into(),
+                }
+            }
+            Some(RequestData::Info(_)) => match self.authorize(&cmd, session) {
+                Ok(()) => self.info(),
+                Err(e) => e.into(),
+            },
+            Some(RequestData::SlowlogGet(v)) => match self.authorize(&cmd, session) {
+                Ok(()) => self.slowlog.get(v.count as usize),
+                Err(e) => e.into(),
+            },
+            Some(RequestData::ClientList(_)) => match self.authorize(&cmd, session) {
+                Ok(()) => self.clients.list(),
+                Err(e) => e.into(),
+            },
+            Some(RequestData::ClientKill(v)) => match self.authorize(&cmd, session) {
+                Ok(()) => self.clients.kill(v.id, &v.peer),
+                Err(e) => e.into(),
+            },
+            Some(RequestData::Hstats(v)) => match self.authorize(&cmd, session) {
+                Ok(()) => self.table_stats(&v.table),
+                Err(e) => e.into(),
+            },
+            Some(RequestData::Quota(v)) => match self.authorize(&cmd, session) {
+                Ok(()) => self.quota(&v.table),
+                Err(e) => e.into(),
+            },
+            Some(RequestData::LeaseGrant(v)) => match self.authorize(&cmd, session) {
+                Ok(()) => self.lease_grant(v),
+                Err(e) => e.into(),
+            },
+            Some(RequestData::LeaseKeepAlive(v)) => match self.authorize(&cmd, session) {
+                Ok(()) => self.lease_keep_alive(v),
+                Err(e) => e.into(),
+            },
+            Some(RequestData::LeaseRevoke(v)) => match self.authorize(&cmd, session) {
+                Ok(()) => self.lease_revoke(v),
+                Err(e) => e.into(),
+            },
+            Some(RequestData::RaftVote(v)) => match self.authorize(&cmd, session) {
+                Ok(()) => self.raft_vote(v),
+                Err(e) => e.into(),
+            },
+            Some(RequestData::RaftAppend(v)) => match self.authorize(&cmd, session) {
+                Ok(()) => self.raft_append(v),
+                Err(e) => e.into(),
+            },
+            Some(RequestData::ClusterSlots(_)) => match self.authorize(&cmd, session) {
+                Ok(()) => self.cluster_slots(),
+                Err(e) => e.into(),
+            },
+            Some(RequestData::Gossip(v)) => match self.authorize(&cmd, session) {
+                Ok(()) => self.gossip(v),
+                Err(e) => e.into(),
+            },
+            Some(RequestData::Members(_)) => match self.authorize(&cmd, session) {
+                Ok(()) => self.members(),
+                Err(e) => e.into(),
+            },
+            Some(RequestData::Compact(_)) => match self.authorize(&cmd, session) {
+                Ok(()) => self.compact(),
+                Err(e) => e.into(),
+            },
+            Some(RequestData::Hcompact(v)) => match self.authorize(&cmd, session) {
+                Ok(()) => self.hcompact(v.revision),
+                Err(e) => e.into(),
+            },
+            Some(RequestData::Hget(v)) if v.at_revision > 0 => {
+                match self.authorize(&cmd, session) {
+                    Ok(()) => self.hget_at(v),
+                    Err(e) => e.into(),
+                }
+            }
+            Some(RequestData::ConfigGet(v)) => match self.authorize(&cmd, session) {
+                Ok(()) => self.config_get(&v.pattern),
+                Err(e) => e.into(),
+            },
+            Some(RequestData::ConfigSet(v)) => {
+                let res = self.authorize(&cmd, session);
+                match res.and_then(|_| self.config_set(&v.name, &v.value)) {
+                    Ok(()) => CommandResponse::ok(),
+                    Err(e) => e.into(),
+                }
+            }
+            Some(data @ (RequestData::Hgetall(_) | RequestData::Hscan(_))) if cmd.stream => {
+                match self.authorize(&cmd, session) {
+                    Ok(()) => return self.stream_pages(data),
+                    Err(e) => e.into(),
+                }
+            }
+            Some(data) => match self.authorize(&cmd, session) {
+                Ok(()) => self.idempotency.execute(&cmd, session.user(), || {
+                    let mut resp = self.keyspace.track(data, &self.store, || match data {
+                        RequestData::Fcall(v) => self.fcall(v),
+                        RequestData::Custom(v) => self.custom(v, session),
+                        _ => dispatch(cmd.clone(), &self.store),
+                    });
+                    if let Err(e) = self.replicator.record(data, &self.store) {
+                        resp = e.into();
+                    }
+                    self.stats.record(data, &resp);
+                    self.audit.record(data, &resp, session, &self.broadcaster);
+                    resp
+                }),
+                Err(e) => e.into(),
+            },
+            None => dispatch(cmd.clone(), &self.store),
+        };
         if resp == CommandResponse::default() {
             dispatch_stream(cmd, self.broadcaster.clone())
         } else {
             info!("Executed response: {:?}", resp);
+            if let Some(data) = &cmd.request_data {
+                self.slowlog.record(data, session, start.elapsed());
+            }
             self.on_executed.notify(&resp);
             self.on_before_send.notify(&mut resp);
 
@@ -40,6 +401,116 @@ impl<Store: Storage> Service<Store> {
             Box::pin(stream::once(async { Arc::new(resp) }))
         }
     }
+
+    /// 检查 session 是否可以执行这个命令，以及有没有超过限流的配额
+    fn authorize(&self, cmd: &CommandRequest, session: &Session) -> Result<(), KvError> {
+        if self.auth.read().unwrap().is_some() && !session.is_authenticated() {
+            return Err(KvError::Unauthorized("AUTH is required".into()));
+        }
+        if let (Some(acl), Some(data)) = (&*self.acl.read().unwrap(), &cmd.request_data) {
+            let user = session.user();
+            let namespace = self.tenants.read().unwrap().namespace(user.as_deref());
+            acl.check(user.as_deref(), namespace.as_deref(), data)?;
+        }
+        if let (true, Some(data)) = (self.is_read_only(), &cmd.request_data) {
+            let writes = acl::required_access(data)
+                .iter()
+                .any(|a| a.verb == Verb::Write && a.table.is_some());
+            if writes {
+                let msg = match self.replica {
+                    true => "replica is read-only",
+                    false => "server is in read-only mode",
+                };
+                return Err(KvError::PermissionDenied(msg.into()));
+            }
+        }
+        if let Some(data) = &cmd.request_data {
+            self.limiter.acquire(data, session)?;
+        }
+        Ok(())
+    }
+
+    fn authenticate(&self, auth: &Auth, session: &Session) -> CommandResponse {
+        let user = match &*self.auth.read().unwrap() {
+            Some(config) => config.verify(&auth.username, &auth.password),
+            // 没有开启认证时，AUTH 总是成功
+            None => Some("default".into()),
+        };
+        match user {
+            Some(user) => {
+                session.set_user(user);
+                CommandResponse::ok()
+            }
+            None => KvError::Unauthorized("invalid username or password".into()).into(),
+        }
+    }
+
+    /// 选择客户端列出的第一个服务器也支持的压缩算法，都不支持时不压缩
+    fn hello(&self, hello: &Hello, session: &Session) -> CommandResponse {
+        let supported = self.compression.codecs();
+        let codec = hello
+            .compressions
+            .iter()
+            .filter_map(|c| c.parse::<Compression>().ok())
+            .find(|c| supported.contains(c))
+            .unwrap_or(Compression::None);
+        session.set_compression(FrameCompression::new(codec, self.compression.threshold));
+        Value::from(codec.as_str()).into()
+    }
+
+    /// 设置 CONFIG RELOAD 时执行的操作
+    pub fn set_reloader(&self, reloader: Reloader) {
+        *self.reloader.write().unwrap() = Some(reloader);
+    }
+
+    /// 重新加载配置
+    pub fn reload(&self) -> Result<(), KvError> {
+        let reloader = self.reloader.read().unwrap().clone();
+        match reloader {
+            Some(reload) => reload(),
+            None => Err(KvError::InvalidCommand(
+                "config reload is not supported".into(),
+            )),
+        }
+    }
+
+    /// 优雅退出的信号，所有的 listener 和连接共享
+    pub fn shutdown(&self) -> &Shutdown {
+        &self.shutdown
+    }
+
+    /// 是否可以接收流量：没有开始退出，存储可以读取，replica 已经完成了全量同步
+    pub fn ready(&self) -> Result<(), KvError> {
+        if self.shutdown.is_triggered() {
+            return Err(KvError::Internal("server is shutting down".into()));
+        }
+        self.store.tables()?;
+        if self.replica && !self.replicator.synced() {
+            return Err(KvError::Internal(
+                "replica is not synced with primary".into(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// 启动后台任务，每隔 interval 清理一次已过期的 key；Service 全部 drop 之后任务自动退出
+    pub fn spawn_purge_task(&self, interval: Duration) -> JoinHandle<()> {
+        let inner = Arc::downgrade(&self.inner);
+        tokio::spawn(async move {
+            let mut ticker = time::interval(interval);
+            loop {
+                ticker.tick().await;
+                let Some(inner) = inner.upgrade() else {
+                    break;
+                };
+                match inner.store.purge_expired() {
+                    Ok(0) => {}
+                    Ok(n) => debug!("Purged {} expired keys", n),
+                    Err(e) => warn!("Failed to purge expired keys: {:?}", e),
+                }
+            }
+        })
+    }
 }
 
 impl<Store> Clone for Service<Store> {
@@ -47,6 +518,13 @@ impl<Store> Clone for Service<Store> {
         Service {
             inner: self.inner.clone(),
             broadcaster: self.broadcaster.clone(),
+            replicator: self.replicator.clone(),
+            keyspace: self.keyspace.clone(),
+            stats: self.stats.clone(),
+            shutdown: self.shutdown.clone(),
+            reloader: self.reloader.clone(),
+            cluster: self.cluster.clone(),
+            membership: self.membership.clone(),
         }
     }
 }
@@ -59,46 +537,133 @@ impl<Store> Deref for Service<Store> {
     }
 }
 
-/// 从 Request 中得到 Response，目前处理所有 HGET/HSET/HGETALL
+/// 在 store 上执行命令得到 Response；流式命令返回空的 CommandResponse，交给 dispatch_stream 处理
+#[instrument(name = "command_dispatch", skip_all)]
 fn dispatch(cmd: CommandRequest, store: &impl Storage) -> CommandResponse {
     match cmd.request_data {
         Some(RequestData::Hget(cmd)) => cmd.execute(store),
         Some(RequestData::Hgetall(cmd)) => cmd.execute(store),
+        Some(RequestData::Hscan(cmd)) => cmd.execute(store),
+        Some(RequestData::Hrange(cmd)) => cmd.execute(store),
+        Some(RequestData::Hscanprefix(cmd)) => cmd.execute(store),
+        Some(RequestData::Tlist(cmd)) => cmd.execute(store),
+        Some(RequestData::Tdrop(cmd)) => cmd.execute(store),
+        Some(RequestData::Ttruncate(cmd)) => cmd.execute(store),
+        Some(RequestData::Dbsize(cmd)) => cmd.execute(store),
+        Some(RequestData::Hmget(cmd)) => cmd.execute(store),
+        Some(RequestData::Hmset(cmd)) => cmd.execute(store),
         Some(RequestData::Hset(cmd)) => cmd.execute(store),
+        Some(RequestData::Hcas(cmd)) => cmd.execute(store),
+        Some(RequestData::Hincrby(cmd)) => cmd.execute(store),
+        Some(RequestData::Happend(cmd)) => cmd.execute(store),
+        Some(RequestData::Hstrlen(cmd)) => cmd.execute(store),
+        Some(RequestData::Hgetrange(cmd)) => cmd.execute(store),
+        Some(RequestData::Hsetbit(cmd)) => cmd.execute(store),
+        Some(RequestData::Hgetbit(cmd)) => cmd.execute(store),
+        Some(RequestData::Hbitcount(cmd)) => cmd.execute(store),
+        Some(RequestData::Hpfadd(cmd)) => cmd.execute(store),
+        Some(RequestData::Hpfcount(cmd)) => cmd.execute(store),
+        Some(RequestData::Eval(cmd)) => cmd.execute(store),
+        Some(RequestData::Evalsha(cmd)) => cmd.execute(store),
+        Some(RequestData::Hdel(cmd)) => cmd.execute(store),
+        Some(RequestData::Hexist(cmd)) => cmd.execute(store),
+        Some(RequestData::Hmdel(cmd)) => cmd.execute(store),
+        Some(RequestData::Hmexist(cmd)) => cmd.execute(store),
+        Some(RequestData::Hexpire(cmd)) => cmd.execute(store),
+        Some(RequestData::Httl(cmd)) => cmd.execute(store),
+        Some(RequestData::Snapshot(cmd)) => cmd.execute(store),
+        Some(RequestData::Lpush(cmd)) => cmd.execute(store),
+        Some(RequestData::Lrange(cmd)) => cmd.execute(store),
+        Some(RequestData::Sadd(cmd)) => cmd.execute(store),
+        Some(RequestData::Smembers(cmd)) => cmd.execute(store),
+        Some(RequestData::MapGet(cmd)) => cmd.execute(store),
+        Some(RequestData::MapSet(cmd)) => cmd.execute(store),
+        Some(RequestData::Zadd(cmd)) => cmd.execute(store),
+        Some(RequestData::Zrange(cmd)) => cmd.execute(store),
+        Some(RequestData::Zrangebyscore(cmd)) => cmd.execute(store),
+        Some(RequestData::Hjsonget(cmd)) => cmd.execute(store),
+        Some(RequestData::Hjsonset(cmd)) => cmd.execute(store),
+        Some(RequestData::CreateIndex(cmd)) => cmd.execute(store),
+        Some(RequestData::Query(cmd)) => cmd.execute(store),
+        Some(RequestData::Lock(cmd)) => cmd.execute(store),
+        Some(RequestData::Unlock(cmd)) => cmd.execute(store),
+        Some(RequestData::Batch(cmd)) => dispatch_batch(cmd, store),
+        // 插件由 Service 加载，batch 和事务中不能调用
+        Some(RequestData::Fcall(_)) => {
+            KvError::InvalidCommand("FCALL is not supported in batch".into()).into()
+        }
         None => KvError::InvalidCommand("Request has no data".into()).into(),
-        // 没有做任何处理，尝试让之后的 dispatch_stream 处理
+        // 没有做任何处理，交给 dispatch_stream；它也不支持的命令返回 400
         _ => CommandResponse::default(),
-        // _ => KvError::Internal("Not implemented yet".into()).into(),
     }
 }
 
-// fn dispatch_stream(
-//     cmd: CommandRequest,
-//     topic: impl Topic,
-// ) -> impl Stream<Item = Arc<CommandResponse>> + Send {
-//     match cmd.request_data {
-//         Some(RequestData::Subscribe(cmd)) => cmd.execute(topic),
-//         Some(RequestData::Unsubscribe(cmd)) => cmd.execute(topic),
-//         Some(RequestData::Publish(cmd)) => cmd.execute(topic),
-//         _ => unreachable!(), // 走到这里说明代码逻辑有问题，尽早改了才是
-//     }
-// }
+/// 按顺序执行 batch 里的每个命令；连续的只写 key 的命令（HSET、HMSET、HDEL、HMDEL）原子地一起提交。
+/// batch 里不支持 pub/sub 这类流式命令和由 Service 处理的命令，也不能嵌套 batch
+fn dispatch_batch(batch: Batch, store: &impl Storage) -> CommandResponse {
+    let mut results = Vec::with_capacity(batch.commands.len());
+    let mut writes = Vec::new();
+    for cmd in batch.commands {
+        if cmd
+            .request_data
+            .as_ref()
+            .is_some_and(transaction::is_plain_write)
+        {
+            writes.extend(cmd.request_data);
+            continue;
+        }
+        if !writes.is_empty() {
+            results.extend(transaction::apply_writes(store, &writes));
+            writes.clear();
+        }
+        let res = match &cmd.request_data {
+            Some(RequestData::Batch(_)) => {
+                KvError::InvalidCommand("Nested batch is not supported".into()).into()
+            }
+            Some(data) => {
+                let name = data.name();
+                match dispatch(cmd, store) {
+                    resp if resp == CommandResponse::default() => {
+                        KvError::InvalidCommand(format!("{} is not supported in batch", name))
+                            .into()
+                    }
+                    resp => resp,
+                }
+            }
+            None => dispatch(cmd, store),
+        };
+        results.push(res);
+    }
+    if !writes.is_empty() {
+        results.extend(transaction::apply_writes(store, &writes));
+    }
+    results.into()
+}
 
 fn dispatch_stream(cmd: CommandRequest, topic: impl Topic) -> StreamingResponse {
     match cmd.request_data {
         Some(RequestData::Subscribe(cmd)) => Box::pin(cmd.execute(topic)),
         Some(RequestData::Unsubscribe(cmd)) => Box::pin(cmd.execute(topic)),
         Some(RequestData::Publish(cmd)) => Box::pin(cmd.execute(topic)),
-        _ => unreachable!(), // 走到这里说明代码逻辑有问题，尽早改了才是
+        // 前面的 dispatch 没有处理，也不是流式命令
+        _ => {
+            let resp: CommandResponse =
+                KvError::InvalidCommand("unsupported command".into()).into();
+            Box::pin(stream::once(async { Arc::new(resp) }))
+        }
     }
 }
 
 #[cfg(test)]
 mod service_tests {
+    use serde_json::json;
+
     use super::*;
     use crate::{
         memory::MemTable,
-        pb::abi::{command_request::RequestData, CommandRequest, CommandResponse, Kvpair, Value},
+        pb::abi::{
+            command_request::RequestData, CommandRequest, CommandResponse, Kvpair, Value, ValueMap,
+        },
         Storage,
     };
 
@@ -113,6 +678,142 @@ mod service_tests {
         assert_res_ok(res, &["world".into()], &[]);
     }
 
+    #[test]
+    fn hcas_should_work() {
+        let store = MemTable::new();
+        let res = dispatch(CommandRequest::new_hcas("t1", "k1", None, "v1"), &store);
+        assert_res_ok(res, &[true.into(), "v1".into()], &[]);
+
+        let cmd = CommandRequest::new_hcas("t1", "k1", Some("v0".into()), "v2");
+        let res = dispatch(cmd, &store);
+        assert_res_ok(res, &[false.into(), "v1".into()], &[]);
+
+        let cmd = CommandRequest::new_hcas("t1", "k1", Some("v1".into()), "v2");
+        let res = dispatch(cmd, &store);
+        assert_res_ok(res, &[true.into(), "v2".into()], &[]);
+    }
+
+    #[test]
+    fn hincrby_and_hdecrby_should_work() {
+        let store = MemTable::new();
+        let res = dispatch(CommandRequest::new_hincrby("score", "u1", 10), &store);
+        assert_res_ok(res, &[10.into()], &[]);
+        let res = dispatch(CommandRequest::new_hdecrby("score", "u1", 3), &store);
+        assert_res_ok(res, &[7.into()], &[]);
+
+        dispatch(CommandRequest::new_hset("score", "u2", "abc"), &store);
+        let res = dispatch(CommandRequest::new_hincrby("score", "u2", 1), &store);
+        assert_eq!(res.status, 400);
+    }
+
+    #[test]
+    fn happend_and_hgetrange_should_work() {
+        let store = MemTable::new();
+        let res = dispatch(CommandRequest::new_happend("log", "l1", "hello"), &store);
+        assert_res_ok(res, &[5.into()], &[]);
+        let res = dispatch(CommandRequest::new_happend("log", "l1", " world"), &store);
+        assert_res_ok(res, &[11.into()], &[]);
+        assert_eq!(store.get("log", "l1").unwrap(), Some("hello world".into()));
+        let res = dispatch(CommandRequest::new_hstrlen("log", "l1"), &store);
+        assert_res_ok(res, &[11.into()], &[]);
+        let res = dispatch(CommandRequest::new_hstrlen("log", "l2"), &store);
+        assert_res_ok(res, &[0.into()], &[]);
+
+        let res = dispatch(CommandRequest::new_hgetrange("log", "l1", 6, 0), &store);
+        assert_res_ok(res, &["world".into()], &[]);
+        let res = dispatch(CommandRequest::new_hgetrange("log", "l1", -5, 3), &store);
+        assert_res_ok(res, &["wor".into()], &[]);
+        let res = dispatch(CommandRequest::new_hgetrange("log", "l1", 20, 3), &store);
+        assert_res_ok(res, &["".into()], &[]);
+
+        // 追加 binary 之后结果是 binary，切开的 UTF-8 也返回 binary
+        let res = dispatch(
+            CommandRequest::new_happend("log", "l1", b"\xe4\xbd"),
+            &store,
+        );
+        assert_res_ok(res, &[13.into()], &[]);
+        let res = dispatch(CommandRequest::new_hgetrange("log", "l1", -3, 0), &store);
+        assert_res_ok(res, &[b"d\xe4\xbd".into()], &[]);
+
+        // 不是 string 的 value 不能追加
+        dispatch(CommandRequest::new_hset("log", "n1", 10), &store);
+        let res = dispatch(CommandRequest::new_happend("log", "n1", "a"), &store);
+        assert_eq!(res.status, 400);
+        let res = dispatch(CommandRequest::new_hstrlen("log", "n1"), &store);
+        assert_eq!(res.status, 400);
+        let res = dispatch(CommandRequest::new_happend("log", "l1", 1), &store);
+        assert_eq!(res.status, 400);
+    }
+
+    #[test]
+    fn bitmap_commands_should_work() {
+        let store = MemTable::new();
+        let res = dispatch(CommandRequest::new_hsetbit("flags", "u1", 7, true), &store);
+        assert_res_ok(res, &[false.into()], &[]);
+        let res = dispatch(CommandRequest::new_hsetbit("flags", "u1", 7, true), &store);
+        assert_res_ok(res, &[true.into()], &[]);
+        dispatch(CommandRequest::new_hsetbit("flags", "u1", 17, true), &store);
+        // 和 redis 一样，offset 0 是第一个字节的最高位，不够长时用 0 补齐
+        assert_eq!(
+            store.get("flags", "u1").unwrap(),
+            Some(bytes::Bytes::from_static(&[0x01, 0x00, 0x40]).into())
+        );
+
+        let res = dispatch(CommandRequest::new_hgetbit("flags", "u1", 17), &store);
+        assert_res_ok(res, &[true.into()], &[]);
+        let res = dispatch(CommandRequest::new_hgetbit("flags", "u1", 1000), &store);
+        assert_res_ok(res, &[false.into()], &[]);
+        let res = dispatch(CommandRequest::new_hbitcount("flags", "u1"), &store);
+        assert_res_ok(res, &[2.into()], &[]);
+
+        let res = dispatch(CommandRequest::new_hsetbit("flags", "u1", 7, false), &store);
+        assert_res_ok(res, &[true.into()], &[]);
+        let res = dispatch(CommandRequest::new_hbitcount("flags", "u1"), &store);
+        assert_res_ok(res, &[1.into()], &[]);
+
+        // string 也可以当成 bitmap，"a" 是 0b01100001
+        dispatch(CommandRequest::new_hset("flags", "s1", "a"), &store);
+        let res = dispatch(CommandRequest::new_hbitcount("flags", "s1"), &store);
+        assert_res_ok(res, &[3.into()], &[]);
+        let res = dispatch(CommandRequest::new_hbitcount("flags", "none"), &store);
+        assert_res_ok(res, &[0.into()], &[]);
+
+        dispatch(CommandRequest::new_hset("flags", "n1", 10), &store);
+        let res = dispatch(CommandRequest::new_hsetbit("flags", "n1", 0, true), &store);
+        assert_eq!(res.status, 400);
+        let res = dispatch(
+            CommandRequest::new_hsetbit("flags", "u2", 1 << 32, true),
+            &store,
+        );
+        assert_eq!(res.status, 400);
+    }
+
+    #[test]
+    fn hyperloglog_commands_should_work() {
+        let store = MemTable::new();
+        let cmd = CommandRequest::new_hpfadd("visitors", "day1", ["u1", "u2", "u3"]);
+        assert_res_ok(dispatch(cmd, &store), &[1.into()], &[]);
+        // 已经加入过的 member 不会改变寄存器
+        let cmd = CommandRequest::new_hpfadd("visitors", "day1", ["u2"]);
+        assert_res_ok(dispatch(cmd, &store), &[0.into()], &[]);
+        let cmd = CommandRequest::new_hpfadd("visitors", "day2", ["u3", "u4"]);
+        assert_res_ok(dispatch(cmd, &store), &[1.into()], &[]);
+        // 没有 member 时也会创建 key
+        let cmd = CommandRequest::new_hpfadd("visitors", "day3", Vec::<Value>::new());
+        assert_res_ok(dispatch(cmd, &store), &[1.into()], &[]);
+
+        let res = dispatch(CommandRequest::new_hpfcount("visitors", ["day1"]), &store);
+        assert_res_ok(res, &[3.into()], &[]);
+        let cmd = CommandRequest::new_hpfcount("visitors", ["day1", "day2", "day3", "none"]);
+        assert_res_ok(dispatch(cmd, &store), &[4.into()], &[]);
+
+        dispatch(CommandRequest::new_hset("visitors", "s1", "hello"), &store);
+        let cmd = CommandRequest::new_hpfadd("visitors", "s1", ["u1"]);
+        assert_eq!(dispatch(cmd, &store).status, 400);
+        let res = dispatch(CommandRequest::new_hpfcount("visitors", ["s1"]), &store);
+        assert_eq!(res.status, 400);
+    }
+
     #[test]
     fn hget_should_work() {
         let store = MemTable::new();
@@ -123,6 +824,129 @@ mod service_tests {
         assert_res_ok(res, &[10.into()], &[]);
     }
 
+    #[test]
+    fn hscan_should_work() {
+        let store = MemTable::new();
+        for i in 0..5 {
+            dispatch(
+                CommandRequest::new_hset("score", format!("u{}", i), i),
+                &store,
+            );
+        }
+
+        let mut cursor = 0;
+        let mut pairs = vec![];
+        loop {
+            let res = dispatch(CommandRequest::new_hscan("score", cursor, 2), &store);
+            assert!(res.pairs.len() <= 2);
+            pairs.extend(res.pairs.clone());
+            let next: i64 = (&res).try_into().unwrap();
+            cursor = next as u64;
+            if cursor == 0 {
+                break;
+            }
+        }
+        pairs.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        let expected: Vec<Kvpair> = (0..5)
+            .map(|i| Kvpair::new(format!("u{}", i), i.into()))
+            .collect();
+        assert_eq!(pairs, expected);
+    }
+
+    #[test]
+    fn hmget_should_work() {
+        let store = MemTable::new();
+        dispatch(CommandRequest::new_hset("score", "u1", 10), &store);
+        dispatch(CommandRequest::new_hset("score", "u3", 11), &store);
+        let cmd = CommandRequest::new_hmget("score", ["u1", "u2", "u3"]);
+        let res = dispatch(cmd, &store);
+        assert_res_ok(res, &[10.into(), Value::default(), 11.into()], &[]);
+    }
+
+    #[test]
+    fn hset_with_condition_should_work() {
+        let store = MemTable::new();
+        let res = dispatch(CommandRequest::new_hsetxx("t1", "k1", "v1", 0), &store);
+        assert_res_ok(res, &[false.into(), Value::default()], &[]);
+        let res = dispatch(CommandRequest::new_hsetnx("t1", "k1", "v1", 100), &store);
+        assert_res_ok(res, &[true.into(), Value::default()], &[]);
+        let res = dispatch(CommandRequest::new_hsetnx("t1", "k1", "v2", 0), &store);
+        assert_res_ok(res, &[false.into(), "v1".into()], &[]);
+        // 只有设置成功时才修改存活时间
+        let res = dispatch(CommandRequest::new_httl("t1", "k1"), &store);
+        assert!(res.values[0].to_integer().unwrap() > 90);
+        let res = dispatch(CommandRequest::new_hsetxx("t1", "k1", "v3", 0), &store);
+        assert_res_ok(res, &[true.into(), "v1".into()], &[]);
+        let res = dispatch(CommandRequest::new_httl("t1", "k1"), &store);
+        assert_eq!(res.values, [(-1).into()]);
+
+        let mut cmd = CommandRequest::new_hsetnx("t1", "k1", "v4", 0);
+        if let Some(RequestData::Hset(v)) = &mut cmd.request_data {
+            v.xx = true;
+        }
+        assert_eq!(dispatch(cmd, &store).status, 400);
+
+        // 没有 pair 的 HSET 返回错误，不会 panic
+        let cmd: CommandRequest = RequestData::Hset(Default::default()).into();
+        let res = dispatch(cmd, &store);
+        assert_eq!(res.status, 400);
+        assert!(res.message.contains("HSET requires a key/value pair"));
+    }
+
+    #[test]
+    fn hmset_should_work() {
+        let store = MemTable::new();
+        dispatch(CommandRequest::new_hset_ex("score", "u1", 1, 100), &store);
+        let cmd = CommandRequest::new_hmset("score", [("u1", 10), ("u2", 20)]);
+        assert_res_ok(dispatch(cmd, &store), &[], &[]);
+        let cmd = CommandRequest::new_hmget("score", ["u1", "u2"]);
+        assert_res_ok(dispatch(cmd, &store), &[10.into(), 20.into()], &[]);
+        // 和 HSET 一样清掉过期时间
+        let res = dispatch(CommandRequest::new_httl("score", "u1"), &store);
+        assert_eq!(res.values, [(-1).into()]);
+    }
+
+    #[test]
+    fn hdel_should_work() {
+        let store = MemTable::new();
+        dispatch(CommandRequest::new_hset("t1", "k1", "v1"), &store);
+        let res = dispatch(CommandRequest::new_hdel("t1", "k1"), &store);
+        assert_res_ok(res, &["v1".into()], &[]);
+
+        // 删除不存在的 key 返回空值
+        let res = dispatch(CommandRequest::new_hdel("t1", "k1"), &store);
+        assert_res_ok(res, &[Value::default()], &[]);
+    }
+
+    #[test]
+    fn hexist_should_work() {
+        let store = MemTable::new();
+        dispatch(CommandRequest::new_hset("t1", "k1", "v1"), &store);
+        let res = dispatch(CommandRequest::new_hexist("t1", "k1"), &store);
+        assert_res_ok(res, &[true.into()], &[]);
+        let res = dispatch(CommandRequest::new_hexist("t1", "k2"), &store);
+        assert_res_ok(res, &[false.into()], &[]);
+    }
+
+    #[test]
+    fn hmdel_should_work() {
+        let store = MemTable::new();
+        dispatch(CommandRequest::new_hset("t1", "k1", "v1"), &store);
+        dispatch(CommandRequest::new_hset("t1", "k3", "v3"), &store);
+        let res = dispatch(CommandRequest::new_hmdel("t1", ["k1", "k2", "k3"]), &store);
+        assert_res_ok(res, &["v1".into(), Value::default(), "v3".into()], &[]);
+        assert_eq!(store.get("t1", "k1").unwrap(), None);
+        assert_eq!(store.get("t1", "k3").unwrap(), None);
+    }
+
+    #[test]
+    fn hmexist_should_work() {
+        let store = MemTable::new();
+        dispatch(CommandRequest::new_hset("t1", "k1", "v1"), &store);
+        let res = dispatch(CommandRequest::new_hmexist("t1", ["k1", "k2"]), &store);
+        assert_res_ok(res, &[true.into(), false.into()], &[]);
+    }
+
     #[test]
     fn hgetall_should_work() {
         let store = MemTable::new();
@@ -137,6 +961,343 @@ mod service_tests {
         }
     }
 
+    #[test]
+    fn hset_ex_and_httl_should_work() {
+        let store = MemTable::new();
+        let cmd = CommandRequest::new_hset_ex("t1", "k1", "v1", 100);
+        dispatch(cmd, &store);
+        let res = dispatch(CommandRequest::new_httl("t1", "k1"), &store);
+        let ttl: i64 = (&res).try_into().unwrap();
+        assert!(ttl > 90 && ttl <= 100);
+
+        // key 不存在返回 -2，没有过期时间返回 -1
+        let res = dispatch(CommandRequest::new_httl("t1", "k2"), &store);
+        assert_res_ok(res, &[(-2).into()], &[]);
+        dispatch(CommandRequest::new_hset("t1", "k2", "v2"), &store);
+        let res = dispatch(CommandRequest::new_httl("t1", "k2"), &store);
+        assert_res_ok(res, &[(-1).into()], &[]);
+    }
+
+    #[test]
+    fn hexpire_should_work() {
+        let store = MemTable::new();
+        let res = dispatch(CommandRequest::new_hexpire("t1", "k1", 10), &store);
+        assert_res_ok(res, &[false.into()], &[]);
+
+        dispatch(CommandRequest::new_hset("t1", "k1", "v1"), &store);
+        let res = dispatch(CommandRequest::new_hexpire("t1", "k1", 0), &store);
+        assert_res_ok(res, &[true.into()], &[]);
+
+        // 过期的 key 不会返回给客户端
+        let res = dispatch(CommandRequest::new_hget("t1", "k1"), &store);
+        assert_eq!(res.status, 404);
+    }
+
+    #[test]
+    fn snapshot_should_work() {
+        let store = MemTable::new();
+        dispatch(CommandRequest::new_hset("t1", "k1", "v1"), &store);
+        dispatch(CommandRequest::new_hset("t2", "k1", "v2"), &store);
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("kv.snapshot");
+        let res = dispatch(CommandRequest::new_snapshot(path.to_str().unwrap()), &store);
+        assert_res_ok(res, &[2.into()], &[]);
+
+        let restored = MemTable::new();
+        crate::snapshot::restore(&restored, &path).unwrap();
+        assert_eq!(restored.get("t2", "k1").unwrap(), Some("v2".into()));
+    }
+
+    #[test]
+    fn lpush_and_lrange_should_work() {
+        let store = MemTable::new();
+        let res = dispatch(CommandRequest::new_lpush("t1", "l1", ["a", "b"]), &store);
+        assert_res_ok(res, &[2.into()], &[]);
+        let res = dispatch(CommandRequest::new_lpush("t1", "l1", ["c"]), &store);
+        assert_res_ok(res, &[3.into()], &[]);
+
+        let res = dispatch(CommandRequest::new_lrange("t1", "l1", 0, -1), &store);
+        assert_res_ok(res, &["c".into(), "b".into(), "a".into()], &[]);
+        let res = dispatch(CommandRequest::new_lrange("t1", "l1", -2, 10), &store);
+        assert_res_ok(res, &["b".into(), "a".into()], &[]);
+        let res = dispatch(CommandRequest::new_lrange("t1", "l1", 2, 1), &store);
+        assert_res_ok(res, &[], &[]);
+        // key 不存在时是空的 list
+        let res = dispatch(CommandRequest::new_lrange("t1", "l2", 0, -1), &store);
+        assert_res_ok(res, &[], &[]);
+
+        // 不是 list 的 value 不能当成 list 使用，原来的值保持不变
+        dispatch(CommandRequest::new_hset("t1", "s1", "v1"), &store);
+        let res = dispatch(CommandRequest::new_lpush("t1", "s1", ["a"]), &store);
+        assert_eq!(res.status, 400);
+        assert_eq!(store.get("t1", "s1").unwrap(), Some("v1".into()));
+        let res = dispatch(CommandRequest::new_lrange("t1", "s1", 0, -1), &store);
+        assert_eq!(res.status, 400);
+    }
+
+    #[test]
+    fn sadd_and_smembers_should_work() {
+        let store = MemTable::new();
+        let res = dispatch(
+            CommandRequest::new_sadd("t1", "s1", ["a", "b", "a"]),
+            &store,
+        );
+        assert_res_ok(res, &[2.into()], &[]);
+        let res = dispatch(CommandRequest::new_sadd("t1", "s1", ["b", "c"]), &store);
+        assert_res_ok(res, &[1.into()], &[]);
+
+        let res = dispatch(CommandRequest::new_smembers("t1", "s1"), &store);
+        assert_res_ok(res, &["a".into(), "b".into(), "c".into()], &[]);
+        let res = dispatch(CommandRequest::new_smembers("t1", "s2"), &store);
+        assert_res_ok(res, &[], &[]);
+
+        dispatch(CommandRequest::new_lpush("t1", "l1", ["a"]), &store);
+        let res = dispatch(CommandRequest::new_sadd("t1", "l1", ["a"]), &store);
+        assert_eq!(res.status, 400);
+    }
+
+    #[test]
+    fn map_get_and_map_set_should_work() {
+        let store = MemTable::new();
+        let cmd = CommandRequest::new_map_set("t1", "u1", ["name"], "alice");
+        assert_res_ok(dispatch(cmd, &store), &[Value::default()], &[]);
+        // 中间不存在的 map 会被创建
+        let cmd = CommandRequest::new_map_set("t1", "u1", ["address", "city"], "hangzhou");
+        assert_res_ok(dispatch(cmd, &store), &[Value::default()], &[]);
+        let cmd = CommandRequest::new_map_set("t1", "u1", ["address", "city"], "beijing");
+        assert_res_ok(dispatch(cmd, &store), &["hangzhou".into()], &[]);
+
+        let cmd = CommandRequest::new_map_get("t1", "u1", ["address", "city"]);
+        assert_res_ok(dispatch(cmd, &store), &["beijing".into()], &[]);
+        let cmd = CommandRequest::new_map_get("t1", "u1", ["address"]);
+        let address = ValueMap {
+            entries: [("city".to_string(), "beijing".into())].into(),
+        };
+        assert_res_ok(dispatch(cmd, &store), &[address.clone().into()], &[]);
+        let cmd = CommandRequest::new_map_get("t1", "u1", Vec::<String>::new());
+        let user = ValueMap {
+            entries: [
+                ("address".to_string(), address.into()),
+                ("name".to_string(), "alice".into()),
+            ]
+            .into(),
+        };
+        assert_res_ok(dispatch(cmd, &store), &[user.into()], &[]);
+
+        let cmd = CommandRequest::new_map_get("t1", "u1", ["age"]);
+        assert_eq!(dispatch(cmd, &store).status, 404);
+        // name 不是 map，不能再往下走
+        let cmd = CommandRequest::new_map_set("t1", "u1", ["name", "first"], "a");
+        assert_eq!(dispatch(cmd, &store).status, 400);
+        let cmd = CommandRequest::new_map_set("t1", "u1", Vec::<String>::new(), "a");
+        assert_eq!(dispatch(cmd, &store).status, 400);
+    }
+
+    #[test]
+    fn zadd_and_zrange_should_work() {
+        let store = MemTable::new();
+        let cmd = CommandRequest::new_zadd("t1", "z1", [("alice", 90.0), ("bob", 75.5)]);
+        assert_res_ok(dispatch(cmd, &store), &[2.into()], &[]);
+        let cmd = CommandRequest::new_zadd("t1", "z1", [("bob", 95.0), ("carol", 80.0)]);
+        assert_res_ok(dispatch(cmd, &store), &[1.into()], &[]);
+
+        // assert_res_ok 会给 pairs 排序，这里要检查的正是顺序
+        let pairs = vec![
+            Kvpair::new("carol", 80.0.into()),
+            Kvpair::new("alice", 90.0.into()),
+            Kvpair::new("bob", 95.0.into()),
+        ];
+        let res = dispatch(CommandRequest::new_zrange("t1", "z1", 0, -1), &store);
+        assert_eq!(res.pairs, pairs);
+        let res = dispatch(CommandRequest::new_zrange("t1", "z1", -1, -1), &store);
+        assert_eq!(res.pairs, pairs[2..]);
+        let cmd = CommandRequest::new_zrangebyscore("t1", "z1", 85.0, f64::INFINITY);
+        assert_eq!(dispatch(cmd, &store).pairs, pairs[1..]);
+        // key 不存在时是空的 sorted set
+        let res = dispatch(CommandRequest::new_zrange("t1", "z2", 0, -1), &store);
+        assert_res_ok(res, &[], &[]);
+
+        let cmd = CommandRequest::new_zadd("t1", "z1", [("dave", f64::NAN)]);
+        assert_eq!(dispatch(cmd, &store).status, 400);
+        let cmd = CommandRequest::new_zrangebyscore("t1", "z1", f64::NAN, 100.0);
+        assert_eq!(dispatch(cmd, &store).status, 400);
+    }
+
+    #[test]
+    fn hjsonget_and_hjsonset_should_work() {
+        let store = MemTable::new();
+        let doc = r#"{"name": "alice", "tags": ["a", "b"]}"#;
+        let cmd = CommandRequest::new_hjsonset("t1", "u1", "$", doc);
+        assert_res_ok(dispatch(cmd, &store), &[Value::default()], &[]);
+
+        // 只修改一个 field，返回之前的值
+        let cmd = CommandRequest::new_hjsonset("t1", "u1", "$.tags[1]", r#""c""#);
+        assert_res_ok(dispatch(cmd, &store), &[json!("b").into()], &[]);
+        let cmd = CommandRequest::new_hjsonset("t1", "u1", "$['age']", "30");
+        assert_res_ok(dispatch(cmd, &store), &[Value::default()], &[]);
+
+        let cmd = CommandRequest::new_hjsonget("t1", "u1", "$.tags");
+        assert_res_ok(dispatch(cmd, &store), &[json!(["a", "c"]).into()], &[]);
+        let cmd = CommandRequest::new_hjsonget("t1", "u1", "$");
+        let doc = json!({ "name": "alice", "tags": ["a", "c"], "age": 30 });
+        assert_res_ok(dispatch(cmd, &store), &[doc.into()], &[]);
+
+        let cmd = CommandRequest::new_hjsonget("t1", "u1", "$.address");
+        assert_eq!(dispatch(cmd, &store).status, 404);
+        // 上一层不存在，或者 key 不存在时只能设置整个文档
+        let cmd = CommandRequest::new_hjsonset("t1", "u1", "$.address.city", r#""x""#);
+        assert_eq!(dispatch(cmd, &store).status, 404);
+        let cmd = CommandRequest::new_hjsonset("t1", "u2", "$.name", r#""x""#);
+        assert_eq!(dispatch(cmd, &store).status, 404);
+        let cmd = CommandRequest::new_hjsonset("t1", "u1", "$.name", "not json");
+        assert_eq!(dispatch(cmd, &store).status, 400);
+        let cmd = CommandRequest::new_hjsonget("t1", "u1", "name");
+        assert_eq!(dispatch(cmd, &store).status, 400);
+
+        dispatch(CommandRequest::new_hset("t1", "k1", "v1"), &store);
+        let cmd = CommandRequest::new_hjsonget("t1", "k1", "$");
+        assert_eq!(dispatch(cmd, &store).status, 400);
+    }
+
+    #[test]
+    fn create_index_and_query_should_work() {
+        let store = MemTable::new();
+        let cmd = CommandRequest::new_map_set("t1", "u1", ["city"], "hangzhou");
+        dispatch(cmd, &store);
+        let cmd = CommandRequest::new_hjsonset("t1", "u2", "$", r#"{"city": "hangzhou"}"#);
+        dispatch(cmd, &store);
+        dispatch(CommandRequest::new_hset("t1", "k1", "hangzhou"), &store);
+
+        let cmd = CommandRequest::new_create_index("t1", "by_city", ["city"]);
+        assert_res_ok(dispatch(cmd.clone(), &store), &[true.into()], &[]);
+        assert_res_ok(dispatch(cmd, &store), &[false.into()], &[]);
+
+        // map 和 JSON 文档都可以按 field 索引，没有这个 field 的 value 不会被索引
+        let res = dispatch(
+            CommandRequest::new_query("t1", "by_city", "hangzhou"),
+            &store,
+        );
+        let keys: Vec<_> = res.pairs.iter().map(|p| p.key.clone()).collect();
+        assert_eq!(keys, ["u1", "u2"]);
+        let res = dispatch(
+            CommandRequest::new_query("t1", "by_city", "beijing"),
+            &store,
+        );
+        assert_res_ok(res, &[], &[]);
+
+        let res = dispatch(CommandRequest::new_query("t1", "by_name", "alice"), &store);
+        assert_eq!(res.status, 404);
+        let cmd = CommandRequest::new_create_index("t1", "", Vec::<String>::new());
+        assert_eq!(dispatch(cmd, &store).status, 400);
+    }
+
+    #[test]
+    fn hrange_should_work() {
+        let store = MemTable::new();
+        for key in ["user:3", "user:1", "order:1", "user:2", "user;"] {
+            dispatch(CommandRequest::new_hset("t1", key, key), &store);
+        }
+        // 按 key 的顺序返回，不包含 end
+        let res = dispatch(CommandRequest::new_hrange("t1", "user:1", "user:3"), &store);
+        let keys: Vec<_> = res.pairs.iter().map(|p| p.key.clone()).collect();
+        assert_eq!(keys, ["user:1", "user:2"]);
+        let res = dispatch(CommandRequest::new_hrange("t1", "user:", ""), &store);
+        let keys: Vec<_> = res.pairs.iter().map(|p| p.key.clone()).collect();
+        assert_eq!(keys, ["user:1", "user:2", "user:3", "user;"]);
+        let res = dispatch(CommandRequest::new_hrange("t1", "user:3", "user:1"), &store);
+        assert_res_ok(res, &[], &[]);
+    }
+
+    #[test]
+    fn hscanprefix_should_work() {
+        let store = MemTable::new();
+        for key in ["user:1:name", "user:1:age", "user:10:name", "order:1"] {
+            dispatch(CommandRequest::new_hset("t1", key, key), &store);
+        }
+        let res = dispatch(CommandRequest::new_hscanprefix("t1", "user:1:"), &store);
+        let pairs = &[
+            Kvpair::new("user:1:age", "user:1:age".into()),
+            Kvpair::new("user:1:name", "user:1:name".into()),
+        ];
+        assert_res_ok(res, &[], pairs);
+        let res = dispatch(CommandRequest::new_hscanprefix("t1", "product:"), &store);
+        assert_res_ok(res, &[], &[]);
+    }
+
+    #[test]
+    fn table_management_should_work() {
+        let store = MemTable::new();
+        dispatch(CommandRequest::new_hset("t2", "k1", "v1"), &store);
+        dispatch(CommandRequest::new_hset("t1", "k1", "v1"), &store);
+        dispatch(CommandRequest::new_hset("t1", "k2", "v2"), &store);
+        let res = dispatch(CommandRequest::new_tlist(), &store);
+        assert_res_ok(res, &["t1".into(), "t2".into()], &[]);
+
+        let res = dispatch(CommandRequest::new_ttruncate("t1"), &store);
+        assert_res_ok(res, &[2.into()], &[]);
+        let res = dispatch(CommandRequest::new_hgetall("t1"), &store);
+        assert_res_ok(res, &[], &[]);
+
+        let res = dispatch(CommandRequest::new_tdrop("t2"), &store);
+        assert_res_ok(res, &[1.into()], &[]);
+        let res = dispatch(CommandRequest::new_tlist(), &store);
+        assert_res_ok(res, &[], &[]);
+    }
+
+    #[test]
+    fn batch_should_work() {
+        let store = MemTable::new();
+        let cmd = CommandRequest::new_batch([
+            CommandRequest::new_hset("t1", "k1", "v1"),
+            CommandRequest::new_hset("t1", "k1", "v2"),
+            CommandRequest::new_hget("t1", "k1"),
+            CommandRequest::new_hget("t1", "k2"),
+            CommandRequest::new_subscribe("lobby"),
+            CommandRequest::new_batch([]),
+        ]);
+        let res = dispatch(cmd, &store);
+        assert_eq!(res.status, 200);
+        assert_eq!(res.results.len(), 6);
+
+        let mut results = res.results.into_iter();
+        assert_res_ok(results.next().unwrap(), &[Value::default()], &[]);
+        assert_res_ok(results.next().unwrap(), &["v1".into()], &[]);
+        assert_res_ok(results.next().unwrap(), &["v2".into()], &[]);
+        assert_eq!(results.next().unwrap().status, 404);
+        let res = results.next().unwrap();
+        assert_eq!(res.status, 400);
+        assert!(res.message.contains("SUBSCRIBE is not supported in batch"));
+        assert_eq!(results.next().unwrap().status, 400);
+    }
+
+    #[test]
+    fn batch_writes_should_be_applied_atomically() {
+        let store = MemTable::new();
+        store.set("t1", "k2", "v0".into()).unwrap();
+        let cmd = CommandRequest::new_batch([
+            CommandRequest::new_hset("t1", "k1", "v1"),
+            CommandRequest::new_hmset("t1", [("k1", "v2")]),
+            CommandRequest::new_hdel("t1", "k2"),
+            CommandRequest::new_hmdel("t1", ["k1", "k2"]),
+            CommandRequest::new_hset("t1", "k3", "v3"),
+        ]);
+        let res = dispatch(cmd, &store);
+        let values: Vec<_> = res.results.iter().map(|r| r.values.clone()).collect();
+        assert_eq!(
+            values,
+            vec![
+                vec![Value::default()],
+                vec![],
+                vec!["v0".into()],
+                vec!["v2".into(), Value::default()],
+                vec![Value::default()],
+            ]
+        );
+        assert_eq!(store.get("t1", "k1").unwrap(), None);
+        assert_eq!(store.get("t1", "k3").unwrap(), Some("v3".into()));
+    }
+
     // 测试成功返回的结果
     fn assert_res_ok(mut res: CommandResponse, values: &[Value], pairs: &[Kvpair]) {
         res.pairs.sort_by(|a, b| a.partial_cmp(b).unwrap());
@@ -145,38 +1306,37 @@ mod service_tests {
         assert_eq!(res.values, values);
         assert_eq!(res.pairs, pairs);
     }
-
-    // 测试失败返回的结果
-    fn assert_res_error(res: CommandResponse, code: u32, msg: &str) {
-        assert_eq!(res.status, code);
-        assert!(res.message.contains(msg));
-        assert_eq!(res.values, &[]);
-        assert_eq!(res.pairs, &[]);
-    }
-
-    fn dispatch(mut cmd: CommandRequest, store: &impl Storage) -> CommandResponse {
-        match cmd.request_data.unwrap() {
-            RequestData::Hget(v) => v.execute(store),
-            RequestData::Hgetall(v) => v.execute(store),
-            RequestData::Hset(v) => v.execute(store),
-            _ => todo!(),
-        }
-    }
 }
 
 #[cfg(test)]
 mod service_tests_2 {
-    use std::thread;
+    use std::{
+        sync::{Arc, Mutex},
+        thread,
+        time::Duration,
+    };
 
     use futures::StreamExt;
+    use tokio::time;
 
     use crate::{
-        assert_res_ok,
-        pb::abi::{CommandRequest, Value},
-        Service,
+        assert_res_error, assert_res_ok,
+        config::{AclConfig, AuthConfig, CompressionConfig, NotificationConfig, TenantConfig},
+        frame::{Compression, FrameCompression},
+        pb::abi::{command_request::RequestData, CommandRequest, Value},
+        sled_db::SledDB,
+        Service, Storage,
     };
 
-    use super::service_builder::ServiceBuilder;
+    use super::{service_builder::ServiceBuilder, session::Session, StreamingResponse};
+
+    #[tokio::test]
+    async fn dispatch_stream_should_reject_unsupported_command() {
+        let topic = Arc::new(super::topic::BroadCaster::default());
+        let mut res = super::dispatch_stream(CommandRequest::new_hget("t1", "k1"), topic);
+        let res = res.next().await.unwrap();
+        assert_res_error(&res, 400, "unsupported command");
+    }
 
     #[tokio::test]
     async fn service_should_works() {
@@ -199,10 +1359,421 @@ mod service_tests_2 {
         let res = res.next().await.unwrap();
         assert_res_ok(&res, &["v1".into()], &[]);
     }
+
+    #[tokio::test]
+    async fn auth_should_be_required_when_configured() {
+        let auth = AuthConfig {
+            password: Some("secret".into()),
+            users: vec![],
+        };
+        let service: Service = ServiceBuilder::default().auth(Some(auth)).finish();
+        let session = Session::new();
+
+        // 没有认证之前只能发 AUTH
+        let cmd = CommandRequest::new_hget("t1", "k1");
+        let res = service.execute_with(cmd.clone(), &session).next().await;
+        assert_res_error(&res.unwrap(), 401, "AUTH is required");
+
+        let auth = CommandRequest::new_auth("", "wrong");
+        let res = service.execute_with(auth, &session).next().await;
+        assert_res_error(&res.unwrap(), 401, "invalid username or password");
+
+        let auth = CommandRequest::new_auth("", "secret");
+        let res = service.execute_with(auth, &session).next().await;
+        assert_res_ok(&res.unwrap(), &[], &[]);
+        assert_eq!(session.user(), Some("default".into()));
+
+        let res = service.execute_with(cmd, &session).next().await;
+        assert_eq!(res.unwrap().status, 404);
+    }
+
+    #[tokio::test]
+    async fn session_commands_should_require_auth() {
+        let auth = AuthConfig {
+            password: Some("secret".into()),
+            users: vec![],
+        };
+        let service: Service = ServiceBuilder::default().auth(Some(auth)).finish();
+        let session = Session::new();
+        let execute = |cmd: CommandRequest| {
+            let res = service.execute_with(cmd, &session);
+            async move { res.into_future().await.0.unwrap() }
+        };
+
+        for cmd in [
+            CommandRequest::new_select("t1"),
+            CommandRequest::new_session_set("timeout", "10"),
+            CommandRequest::new_multi(),
+            CommandRequest::new_exec(),
+        ] {
+            let res = execute(cmd).await;
+            assert_res_error(&res, 401, "AUTH is required");
+        }
+        assert_eq!(session.selected(), None);
+
+        // 事务中的 AUTH 直接执行，不会排队
+        execute(CommandRequest::new_auth("", "secret")).await;
+        let res = execute(CommandRequest::new_multi()).await;
+        assert_res_ok(&res, &[], &[]);
+        let res = execute(CommandRequest::new_auth("", "secret")).await;
+        assert_res_ok(&res, &[], &[]);
+        let res = execute(CommandRequest::new_exec()).await;
+        assert!(res.results.is_empty());
+    }
+
+    #[tokio::test]
+    async fn hunwatch_should_only_cancel_own_watchers() {
+        let auth = AuthConfig {
+            password: Some("secret".into()),
+            users: vec![],
+        };
+        let service: Service = ServiceBuilder::default().auth(Some(auth)).finish();
+        let owner = Session::authenticated("default");
+        let mut stream =
+            service.execute_with(CommandRequest::new_hwatch("t1", "k1", false), &owner);
+        let id: i64 = stream.next().await.unwrap().as_ref().try_into().unwrap();
+        let unwatch = CommandRequest::new_hunwatch(id as u32);
+
+        let res = service
+            .execute_with(unwatch.clone(), &Session::new())
+            .next()
+            .await;
+        assert_res_error(&res.unwrap(), 401, "AUTH is required");
+        let other = Session::authenticated("default");
+        let res = service.execute_with(unwatch.clone(), &other).next().await;
+        assert_res_error(&res.unwrap(), 404, "watcher");
+
+        let res = service.execute_with(unwatch, &owner).next().await;
+        assert_res_ok(&res.unwrap(), &[], &[]);
+        assert!(stream.next().await.is_none());
+    }
+
+    #[test]
+    fn logged_auth_should_not_contain_password() {
+        let auth = CommandRequest::new_auth("alice", "secret");
+        let batch = CommandRequest::new_batch([auth.clone(), CommandRequest::new_hget("t1", "k1")]);
+        for cmd in [auth, batch] {
+            let logged = format!("{:?}", cmd.redacted());
+            assert!(logged.contains("alice"));
+            assert!(!logged.contains("secret"));
+        }
+        let hget = CommandRequest::new_hget("t1", "k1");
+        assert!(matches!(hget.redacted(), std::borrow::Cow::Borrowed(_)));
+    }
+
+    #[tokio::test]
+    async fn acl_should_be_enforced_before_dispatch() {
+        let acl: AclConfig = toml::from_str(
+            r#"
+            roles = [{ name = "reader", tables = ["*"], verbs = ["read"] }]
+            users = { alice = ["reader"] }
+            "#,
+        )
+        .unwrap();
+        let service: Service = ServiceBuilder::default().acl(Some(acl)).finish();
+        let session = Session::authenticated("alice");
+
+        let cmd = CommandRequest::new_hset("t1", "k1", "v1");
+        let res = service.execute_with(cmd, &session).next().await;
+        assert_res_error(&res.unwrap(), 403, "alice cannot Write table t1");
+        assert!(service.store.get("t1", "k1").unwrap().is_none());
+
+        let cmd = CommandRequest::new_hget("t1", "k1");
+        let res = service.execute_with(cmd, &session).next().await;
+        assert_eq!(res.unwrap().status, 404);
+    }
+
+    #[tokio::test]
+    async fn tenant_tables_should_be_namespaced() {
+        let acl: AclConfig = toml::from_str(
+            r#"
+            roles = [{ name = "orders", tables = ["orders*"], verbs = ["read", "write"] }]
+            users = { alice = ["orders"] }
+            "#,
+        )
+        .unwrap();
+        let tenants = [TenantConfig {
+            name: "billing".into(),
+            users: vec!["alice".into()],
+        }];
+        let service: Service = ServiceBuilder::default()
+            .acl(Some(acl))
+            .tenants(tenants[..].try_into().unwrap())
+            .finish();
+        let alice = Arc::new(Session::authenticated("alice"));
+
+        // ACL 按租户内的 table 名检查
+        let cmd = CommandRequest::new_hset("orders", "k1", "v1");
+        let mut res = service.execute_with_deadline(cmd, &alice).await;
+        assert_res_ok(&res.next().await.unwrap(), &[Value::default()], &[]);
+        let cmd = CommandRequest::new_hset("billing:orders", "k1", "v1");
+        let mut res = service.execute_with_deadline(cmd, &alice).await;
+        assert_res_error(&res.next().await.unwrap(), 403, "cannot Write");
+        assert!(service.store.get("billing:orders", "k1").unwrap().is_some());
+        assert!(service.store.get("orders", "k1").unwrap().is_none());
+
+        service.store.set("payroll", "k1", "v1".into()).unwrap();
+        let mut res = service
+            .execute_with_deadline(CommandRequest::new_tlist(), &alice)
+            .await;
+        assert_res_ok(&res.next().await.unwrap(), &["orders".into()], &[]);
+    }
+
+    fn tenant_service() -> Service {
+        let tenants = [
+            TenantConfig {
+                name: "billing".into(),
+                users: vec!["alice".into()],
+            },
+            TenantConfig {
+                name: "orders".into(),
+                users: vec!["bob".into()],
+            },
+        ];
+        let notifications = NotificationConfig {
+            keyspace: true,
+            keyevent: true,
+        };
+        ServiceBuilder::default()
+            .tenants(tenants[..].try_into().unwrap())
+            .notifications(notifications)
+            .finish()
+    }
+
+    async fn execute_as(
+        service: &Service,
+        cmd: CommandRequest,
+        session: &Arc<Session>,
+    ) -> super::CommandResponse {
+        let mut res = service.execute_with_deadline(cmd, session).await;
+        res.next().await.unwrap().as_ref().clone()
+    }
+
+    #[tokio::test]
+    async fn tenant_dbsize_should_be_namespaced() {
+        let service = tenant_service();
+        let alice = Arc::new(Session::authenticated("alice"));
+        service.store.set("payroll", "k1", "v1".into()).unwrap();
+        service.store.set("billing:t1", "k1", "v1".into()).unwrap();
+        service.store.set("billing:t2", "k1", "v1".into()).unwrap();
+
+        let res = execute_as(&service, CommandRequest::new_dbsize(), &alice).await;
+        assert_res_ok(&res, &[2.into()], &[]);
+        let res = execute_as(
+            &service,
+            CommandRequest::new_dbsize(),
+            &Arc::new(Session::new()),
+        )
+        .await;
+        assert_res_ok(&res, &[3.into()], &[]);
+    }
+
+    #[tokio::test]
+    async fn tenant_topics_should_be_namespaced() {
+        let service = tenant_service();
+        let alice = Arc::new(Session::authenticated("alice"));
+        let admin = Arc::new(Session::new());
+        let next = |mut stream: StreamingResponse| async move {
+            let res = time::timeout(Duration::from_millis(100), stream.next()).await;
+            (res.ok().flatten(), stream)
+        };
+
+        let cmd = CommandRequest::new_subscribe("lobby");
+        let (_, lobby) = next(service.execute_with_deadline(cmd, &alice).await).await;
+        let cmd = CommandRequest::new_subscribe("billing:lobby");
+        let (_, raw) = next(service.execute_with_deadline(cmd, &admin).await).await;
+        let cmd = CommandRequest::new_subscribe("__keyspace__:t1:k1");
+        let (_, keyspace) = next(service.execute_with_deadline(cmd, &alice).await).await;
+        let cmd = CommandRequest::new_subscribe("__keyevent__:set");
+        let (_, keyevent) = next(service.execute_with_deadline(cmd, &alice).await).await;
+
+        // 别人发布的同名 topic 收不到，租户自己发布的在租户的 topic 上
+        let cmd = CommandRequest::new_publish("lobby", vec!["admin".into()]);
+        execute_as(&service, cmd, &admin).await;
+        let cmd = CommandRequest::new_publish("lobby", vec!["alice".into()]);
+        execute_as(&service, cmd, &alice).await;
+        let (res, _) = next(lobby).await;
+        assert_res_ok(&res.unwrap(), &["alice".into()], &[]);
+        let (res, _) = next(raw).await;
+        assert_res_ok(&res.unwrap(), &["alice".into()], &[]);
+
+        // 只收到租户自己的 table 上的通知，table 名去掉了前缀
+        let cmd = CommandRequest::new_hset("t1", "k1", "v1");
+        execute_as(&service, cmd.clone(), &admin).await;
+        execute_as(&service, cmd, &alice).await;
+        let (res, keyspace) = next(keyspace).await;
+        assert_res_ok(&res.unwrap(), &["set".into()], &[]);
+        assert!(next(keyspace).await.0.is_none());
+        let (res, keyevent) = next(keyevent).await;
+        assert_res_ok(&res.unwrap(), &["t1".into(), "k1".into()], &[]);
+        assert!(next(keyevent).await.0.is_none());
+    }
+
+    #[tokio::test]
+    async fn tenant_leases_should_be_namespaced() {
+        let service = tenant_service();
+        let alice = Arc::new(Session::authenticated("alice"));
+        let bob = Arc::new(Session::authenticated("bob"));
+        let admin = Arc::new(Session::new());
+
+        let res = execute_as(
+            &service,
+            CommandRequest::new_lease_grant_with_id(7, 10),
+            &alice,
+        )
+        .await;
+        assert_res_ok(&res, &[7.into(), 10.into()], &[]);
+        let cmd = CommandRequest::new_hset_with_lease("t1", "k1", "v1", 7);
+        execute_as(&service, cmd, &alice).await;
+        // 其它租户和不属于租户的用户都不能撤销 alice 的 lease，也不能把 key 关联上去
+        let cmd = CommandRequest::new_hset_with_lease("t1", "k1", "v1", 7);
+        let res = execute_as(&service, cmd, &bob).await;
+        assert_res_error(&res, 404, "lease 7");
+        let res = execute_as(&service, CommandRequest::new_lease_revoke(7), &bob).await;
+        assert_res_error(&res, 404, "lease 7");
+        let res = execute_as(&service, CommandRequest::new_lease_revoke(7), &admin).await;
+        assert_res_error(&res, 404, "lease 7");
+        assert!(service.store.contains("billing:t1", "k1").unwrap());
+
+        let res = execute_as(&service, CommandRequest::new_lease_revoke(7), &alice).await;
+        assert_res_ok(&res, &[1.into()], &[]);
+        assert!(!service.store.contains("billing:t1", "k1").unwrap());
+    }
+
+    #[tokio::test]
+    async fn ping_should_echo_message() {
+        let service: Service = ServiceBuilder::default().finish();
+        let res = service
+            .execute(CommandRequest::new_ping())
+            .next()
+            .await
+            .unwrap();
+        assert_res_ok(&res, &["PONG".into()], &[]);
+        let cmd = CommandRequest::new_ping_with("hello");
+        let res = service.execute(cmd).next().await.unwrap();
+        assert_res_ok(&res, &[bytes::Bytes::from("hello").into()], &[]);
+    }
+
+    #[tokio::test]
+    async fn hello_should_negotiate_compression() {
+        let compression: CompressionConfig = toml::from_str(
+            r#"
+            codecs = ["gzip", "lz4"]
+            threshold = 100
+            "#,
+        )
+        .unwrap();
+        let service: Service = ServiceBuilder::default().compression(compression).finish();
+        let session = Session::new();
+        assert_eq!(session.compression(), FrameCompression::default());
+
+        // 按客户端的顺序选择服务器也支持的算法
+        let cmd = CommandRequest::new_hello(["zstd", "lz4", "gzip"]);
+        let res = service.execute_with(cmd, &session).next().await;
+        assert_res_ok(&res.unwrap(), &["lz4".into()], &[]);
+        let expected = FrameCompression::new(Compression::Lz4, Some(100));
+        assert_eq!(session.compression(), expected);
+
+        let cmd = CommandRequest::new_hello(["zstd", "brotli"]);
+        let res = service.execute_with(cmd, &session).next().await;
+        assert_res_ok(&res.unwrap(), &["none".into()], &[]);
+        assert_eq!(session.compression().codec, Compression::None);
+    }
+
+    #[tokio::test]
+    async fn command_should_time_out_past_deadline() {
+        let service: Service = ServiceBuilder::default()
+            .fn_received(|cmd| {
+                if matches!(&cmd.request_data, Some(RequestData::Hset(v)) if v.table == "slow") {
+                    thread::sleep(Duration::from_millis(100));
+                }
+            })
+            .finish();
+        let session = Arc::new(Session::new());
+
+        let cmd = CommandRequest::new_hset("slow", "k1", "v1");
+        let cmd = cmd.with_timeout(Duration::from_millis(10));
+        let mut res = service.execute_with_deadline(cmd, &session).await;
+        assert_res_error(&res.next().await.unwrap(), 504, "may be applied");
+        // 已经开始执行的写命令超时之后仍然会生效
+        time::sleep(Duration::from_millis(200)).await;
+        let res = service
+            .execute_with(CommandRequest::new_hget("slow", "k1"), &session)
+            .next()
+            .await;
+        assert_res_ok(&res.unwrap(), &["v1".into()], &[]);
+
+        let cmd = CommandRequest::new_hget("t1", "k1").with_timeout(Duration::from_secs(1));
+        let mut res = service.execute_with_deadline(cmd, &session).await;
+        assert_eq!(res.next().await.unwrap().status, 404);
+    }
+
+    #[tokio::test]
+    async fn select_should_set_default_table_and_timeout() {
+        let service: Service = ServiceBuilder::default()
+            .fn_received(|cmd| {
+                if matches!(&cmd.request_data, Some(RequestData::Hset(v)) if v.table == "slow") {
+                    thread::sleep(Duration::from_millis(100));
+                }
+            })
+            .finish();
+        let session = Arc::new(Session::new());
+
+        for cmd in [
+            CommandRequest::new_select("slow"),
+            CommandRequest::new_session_set("timeout", "10"),
+        ] {
+            let mut res = service.execute_with_deadline(cmd, &session).await;
+            assert_res_ok(&res.next().await.unwrap(), &[], &[]);
+        }
+        let cmd = CommandRequest::new_hset("", "k1", "v1");
+        let mut res = service.execute_with_deadline(cmd, &session).await;
+        assert_res_error(&res.next().await.unwrap(), 504, "didn't finish");
+
+        let cmd = CommandRequest::new_session_set("colour", "red");
+        let mut res = service.execute_with_deadline(cmd, &session).await;
+        assert_res_error(&res.next().await.unwrap(), 400, "unknown session option");
+    }
+
+    #[tokio::test]
+    async fn blocking_store_should_execute_off_runtime_thread() {
+        static THREAD: Mutex<Option<thread::ThreadId>> = Mutex::new(None);
+        let dir = tempfile::tempdir().unwrap();
+        let service = ServiceBuilder::new(SledDB::new(dir.path()))
+            .fn_received(|_| *THREAD.lock().unwrap() = Some(thread::current().id()))
+            .finish();
+        let session = Arc::new(Session::new());
+
+        let cmd = CommandRequest::new_hset("t1", "k1", "v1");
+        let mut res = service.execute_with_deadline(cmd, &session).await;
+        assert_res_ok(&res.next().await.unwrap(), &[Value::default()], &[]);
+        assert_ne!(*THREAD.lock().unwrap(), Some(thread::current().id()));
+    }
+
+    #[tokio::test]
+    async fn purge_task_should_remove_expired_keys() {
+        let service: Service = ServiceBuilder::default().finish();
+        let handle = service.spawn_purge_task(Duration::from_millis(10));
+
+        let mut res = service.execute(CommandRequest::new_hset("t1", "k1", "v1"));
+        res.next().await.unwrap();
+        let mut res = service.execute(CommandRequest::new_hexpire("t1", "k1", 0));
+        res.next().await.unwrap();
+
+        // 后台任务已经清理过了，再手动清理时没有可以清理的 key
+        time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(service.store.purge_expired().unwrap(), 0);
+        assert!(!handle.is_finished());
+
+        // service 被 drop 之后，后台任务会退出
+        drop(service);
+        time::sleep(Duration::from_millis(20)).await;
+        assert!(handle.is_finished());
+    }
 }
 
 #[cfg(test)]
-use crate::pb::abi::{Kvpair, Value};
+use crate::pb::abi::Kvpair;
 
 use self::{
     notify::{Notify, NotifyMut},
@@ -223,7 +1794,6 @@ pub fn assert_res_ok(res: &CommandResponse, values: &[Value], pairs: &[Kvpair])
 // 测试失败返回的结果
 #[cfg(test)]
 pub fn assert_res_error(res: &CommandResponse, code: u32, msg: &str) {
-    println!("【 res.message 】==> {:?}", res.message);
     assert_eq!(res.status, code);
     assert!(res.message.contains(msg));
     assert_eq!(res.values, &[]);
@@ -0,0 +1,305 @@
+//! MVCC：每个修改数据的命令生成一个新的 revision，在内存中保留最近的修改，
+//! HGET 可以读取 key 在某个 revision 时的值，HCOMPACT 丢弃更早的历史
+
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    sync::{Arc, Mutex},
+};
+
+use bytes::Bytes;
+
+use super::Service;
+use crate::{
+    config::MvccConfig,
+    error::KvError,
+    pb::abi::{CommandResponse, Hget, KeyEvent, Value},
+    Storage,
+};
+
+/// 默认保留最近 10000 个 revision
+const DEFAULT_RETENTION: u64 = 10000;
+
+/// 修改的历史
+pub struct History {
+    retention: u64,
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    /// 当前的 revision，还没有修改时为 0
+    revision: u64,
+    /// 不晚于这个 revision 的修改已经丢弃
+    compacted: u64,
+    /// 按 revision 排列的修改
+    events: VecDeque<Arc<KeyEvent>>,
+    /// <(table, key), <revision, 修改>>
+    keys: HashMap<(String, Bytes), BTreeMap<u64, Arc<KeyEvent>>>,
+}
+
+impl History {
+    pub fn new(config: MvccConfig) -> Self {
+        Self {
+            retention: config.retention.unwrap_or(DEFAULT_RETENTION),
+            state: Default::default(),
+        }
+    }
+
+    /// 当前的 revision
+    pub fn revision(&self) -> u64 {
+        self.state.lock().unwrap().revision
+    }
+
+    /// 还可以读取的最早的 revision
+    pub fn oldest(&self) -> u64 {
+        self.state.lock().unwrap().oldest()
+    }
+
+    /// 记录一个命令的修改：所有修改共用一个新的 revision，并设置到 events 中
+    pub(super) fn record(&self, events: &mut [KeyEvent]) {
+        if events.is_empty() {
+            return;
+        }
+        let mut state = self.state.lock().unwrap();
+        state.revision += 1;
+        let revision = state.revision;
+        for event in events.iter_mut() {
+            event.revision = revision;
+            let event = Arc::new(event.clone());
+            state
+                .keys
+                .entry((event.table.clone(), event.key.clone()))
+                .or_default()
+                .insert(revision, event.clone());
+            state.events.push_back(event);
+        }
+        if revision > self.retention {
+            state.compact(revision - self.retention);
+        }
+    }
+
+    /// key 在 revision 时的值；revision 之后 key 没有修改过时返回 None，这时 key 的值就是当前的值
+    pub(super) fn get(
+        &self,
+        table: &str,
+        key: &[u8],
+        revision: u64,
+    ) -> Result<Option<Option<Value>>, KvError> {
+        let state = self.state.lock().unwrap();
+        state.check(revision)?;
+        let id = (table.to_owned(), Bytes::copy_from_slice(key));
+        let Some(revisions) = state.keys.get(&id) else {
+            return Ok(None);
+        };
+        // revision 之前的最后一次修改之后的值，也就是 revision 之后的第一次修改之前的值
+        match revisions.range(..=revision).next_back() {
+            Some((_, event)) => Ok(Some(event.new_value.clone())),
+            None => Ok(revisions.values().next().map(|e| e.old_value.clone())),
+        }
+    }
+
+    /// 从 revision 开始（包括 revision）满足 filter 的修改，同一个 revision 的修改放在一组
+    pub(super) fn since(
+        &self,
+        revision: u64,
+        filter: impl Fn(&KeyEvent) -> bool,
+    ) -> Result<Vec<Vec<KeyEvent>>, KvError> {
+        let state = self.state.lock().unwrap();
+        if revision <= state.compacted {
+            return Err(KvError::Compacted(revision, state.compacted + 1));
+        }
+        let start = state.events.partition_point(|e| e.revision < revision);
+        let mut groups: Vec<Vec<KeyEvent>> = vec![];
+        for event in state.events.range(start..).filter(|e| filter(e)) {
+            match groups.last_mut() {
+                Some(group) if group[0].revision == event.revision => {
+                    group.push(event.as_ref().clone())
+                }
+                _ => groups.push(vec![event.as_ref().clone()]),
+            }
+        }
+        Ok(groups)
+    }
+
+    /// 丢弃不晚于 revision 的修改，返回丢弃的修改数
+    pub fn compact(&self, revision: u64) -> Result<usize, KvError> {
+        let mut state = self.state.lock().unwrap();
+        if revision > state.revision {
+            return Err(KvError::InvalidCommand(format!(
+                "revision {} is newer than the current revision {}",
+                revision, state.revision
+            )));
+        }
+        Ok(state.compact(revision))
+    }
+}
+
+impl State {
+    fn oldest(&self) -> u64 {
+        self.compacted.max(1)
+    }
+
+    fn check(&self, revision: u64) -> Result<(), KvError> {
+        if revision > self.revision {
+            return Err(KvError::InvalidCommand(format!(
+                "revision {} is newer than the current revision {}",
+                revision, self.revision
+            )));
+        }
+        if revision < self.oldest() {
+            return Err(KvError::Compacted(revision, self.oldest()));
+        }
+        Ok(())
+    }
+
+    fn compact(&mut self, revision: u64) -> usize {
+        if revision <= self.compacted {
+            return 0;
+        }
+        self.compacted = revision;
+        let mut count = 0;
+        while let Some(event) = self.events.front() {
+            if event.revision > revision {
+                break;
+            }
+            let id = (event.table.clone(), event.key.clone());
+            if let Some(revisions) = self.keys.get_mut(&id) {
+                revisions.remove(&event.revision);
+                if revisions.is_empty() {
+                    self.keys.remove(&id);
+                }
+            }
+            self.events.pop_front();
+            count += 1;
+        }
+        count
+    }
+}
+
+impl<Store: Storage> Service<Store> {
+    /// HGET 带 at_revision：读取 key 在这个 revision 时的值
+    pub(super) fn hget_at(&self, cmd: &Hget) -> CommandResponse {
+        let res = self
+            .keyspace
+            .get_at(&cmd.table, &cmd.key, cmd.at_revision, &self.store);
+        match res {
+            Ok(Some(v)) => v.into(),
+            Ok(None) => KvError::NotFound(format!(
+                "{}:{}@{}",
+                cmd.table,
+                String::from_utf8_lossy(&cmd.key),
+                cmd.at_revision
+            ))
+            .into(),
+            Err(e) => e.into(),
+        }
+    }
+
+    /// HCOMPACT：丢弃不晚于 revision 的修改
+    pub(super) fn hcompact(&self, revision: u64) -> CommandResponse {
+        let Some(history) = self.keyspace.history() else {
+            return KvError::InvalidCommand("MVCC is not enabled".into()).into();
+        };
+        match history.compact(revision) {
+            Ok(n) => Value::from(n as i64).into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod mvcc_tests {
+    use futures::StreamExt;
+    use hyper::StatusCode;
+
+    use super::*;
+    use crate::{pb::abi::CommandRequest, service_builder::ServiceBuilder};
+
+    fn mvcc_service(retention: Option<u64>) -> Service {
+        ServiceBuilder::default()
+            .mvcc(Some(MvccConfig { retention }))
+            .finish()
+    }
+
+    async fn execute(service: &Service, cmd: CommandRequest) -> CommandResponse {
+        let res = service.execute(cmd).next().await.unwrap();
+        res.as_ref().clone()
+    }
+
+    #[tokio::test]
+    async fn hget_should_read_value_at_revision() {
+        let service = mvcc_service(None);
+        // 开启 MVCC 之前就存在的 key
+        service.store.set("t1", "k0", "old".into()).unwrap();
+        execute(&service, CommandRequest::new_hset("t1", "k1", "v1")).await;
+        execute(&service, CommandRequest::new_hset("t1", "k0", "new")).await;
+        execute(&service, CommandRequest::new_hset("t1", "k1", "v2")).await;
+        execute(&service, CommandRequest::new_hdel("t1", "k1")).await;
+        // 没有修改数据的命令不生成 revision
+        execute(&service, CommandRequest::new_hdel("t1", "k1")).await;
+        execute(&service, CommandRequest::new_hget("t1", "k1")).await;
+        assert_eq!(service.keyspace.history().unwrap().revision(), 4);
+
+        let get =
+            |key, revision| execute(&service, CommandRequest::new_hget_at("t1", key, revision));
+        assert_eq!(get("k1", 1).await.values, [Value::from("v1")]);
+        assert_eq!(get("k1", 2).await.values, [Value::from("v1")]);
+        assert_eq!(get("k1", 3).await.values, [Value::from("v2")]);
+        assert_eq!(
+            get("k1", 4).await.status,
+            StatusCode::NOT_FOUND.as_u16() as u32
+        );
+        assert_eq!(get("k0", 1).await.values, [Value::from("old")]);
+        assert_eq!(get("k0", 4).await.values, [Value::from("new")]);
+        // 一直没有修改过的 key 读取当前的值
+        service.store.set("t1", "k2", "v".into()).unwrap();
+        assert_eq!(get("k2", 1).await.values, [Value::from("v")]);
+        // 还没有到达的 revision
+        assert_eq!(
+            get("k1", 5).await.status,
+            StatusCode::BAD_REQUEST.as_u16() as u32
+        );
+    }
+
+    #[tokio::test]
+    async fn hcompact_should_discard_old_revisions() {
+        let service = mvcc_service(None);
+        for i in 0..5 {
+            execute(&service, CommandRequest::new_hset("t1", "k1", i)).await;
+        }
+        let res = execute(&service, CommandRequest::new_hcompact(3)).await;
+        assert_eq!(res.values, [Value::from(3)]);
+        let res = execute(&service, CommandRequest::new_hcompact(10)).await;
+        assert_eq!(res.status, StatusCode::BAD_REQUEST.as_u16() as u32);
+
+        let res = execute(&service, CommandRequest::new_hget_at("t1", "k1", 2)).await;
+        assert_eq!(res.status, StatusCode::GONE.as_u16() as u32);
+        // 压缩到的 revision 本身还可以读取
+        let res = execute(&service, CommandRequest::new_hget_at("t1", "k1", 3)).await;
+        assert_eq!(res.values, [Value::from(2)]);
+        let res = execute(&service, CommandRequest::new_hget_at("t1", "k1", 4)).await;
+        assert_eq!(res.values, [Value::from(3)]);
+    }
+
+    #[tokio::test]
+    async fn history_should_keep_retention_revisions() {
+        let service = mvcc_service(Some(2));
+        for i in 0..5 {
+            execute(&service, CommandRequest::new_hset("t1", "k1", i)).await;
+        }
+        let history = service.keyspace.history().unwrap();
+        assert_eq!((history.oldest(), history.revision()), (3, 5));
+        let res = execute(&service, CommandRequest::new_hget_at("t1", "k1", 3)).await;
+        assert_eq!(res.values, [Value::from(2)]);
+    }
+
+    #[tokio::test]
+    async fn mvcc_commands_should_fail_when_disabled() {
+        let service: Service = ServiceBuilder::default().finish();
+        execute(&service, CommandRequest::new_hset("t1", "k1", "v1")).await;
+        let res = execute(&service, CommandRequest::new_hget_at("t1", "k1", 1)).await;
+        assert_eq!(res.status, StatusCode::BAD_REQUEST.as_u16() as u32);
+        let res = execute(&service, CommandRequest::new_hcompact(1)).await;
+        assert_eq!(res.status, StatusCode::BAD_REQUEST.as_u16() as u32);
+    }
+}
@@ -0,0 +1,190 @@
+use std::sync::Arc;
+
+use bytes::Bytes;
+use futures::stream;
+use tokio::task;
+
+use super::{command_service::DEFAULT_SCAN_COUNT, topic_service::StreamingResponse, Service};
+use crate::{
+    error::KvError,
+    pb::abi::{command_request::RequestData, CommandResponse, Kvpair},
+    Storage,
+};
+
+/// 流式返回 HGETALL 时每一帧最多包含的 kvpair 数量
+const PAGE_SIZE: usize = 1000;
+
+/// 按 key 的字节序逐页读取一个 table，读到的最后一个 key 就是下一页的起点
+struct Pages<Store> {
+    service: Service<Store>,
+    table: String,
+    page_size: usize,
+    /// 上一页的最后一个 key
+    last: Option<Bytes>,
+    /// 开始之前还要跳过多少个 kvpair
+    skip: u64,
+    /// 已经读过的 kvpair 数量，包括跳过的，也就是下一页的 cursor
+    position: u64,
+    /// HSCAN 在每一帧的 values[0] 中返回下一页的 cursor
+    with_cursor: bool,
+}
+
+impl<Store: Storage> Service<Store> {
+    /// 流式执行 HGETALL/HSCAN：读一页发一页，服务器上同时只保留一页数据。
+    /// 除了最后一帧，其它帧的 has_more 都为 true；出错时返回错误之后结束
+    pub(super) fn stream_pages(&self, cmd: &RequestData) -> StreamingResponse {
+        let mut pages = Pages {
+            service: self.clone(),
+            table: String::new(),
+            page_size: PAGE_SIZE,
+            last: None,
+            skip: 0,
+            position: 0,
+            with_cursor: false,
+        };
+        match cmd {
+            RequestData::Hgetall(v) => pages.table = v.table.clone(),
+            RequestData::Hscan(v) => {
+                pages.table = v.table.clone();
+                pages.page_size = match v.count {
+                    0 => DEFAULT_SCAN_COUNT,
+                    n => n,
+                } as usize;
+                (pages.skip, pages.position) = (v.cursor, v.cursor);
+                pages.with_cursor = true;
+            }
+            _ => unreachable!(), // 只有 HGETALL 和 HSCAN 支持流式返回
+        }
+        Box::pin(stream::unfold(Some(pages), |pages| async move {
+            let mut pages = pages?;
+            match pages.next().await {
+                Ok(res) => {
+                    let more = res.has_more;
+                    Some((Arc::new(res), more.then_some(pages)))
+                }
+                Err(e) => Some((Arc::new(e.into()), None)),
+            }
+        }))
+    }
+}
+
+impl<Store: Storage> Pages<Store> {
+    async fn next(&mut self) -> Result<CommandResponse, KvError> {
+        while self.skip > 0 {
+            let pairs = self.read(self.page_size.min(self.skip as usize)).await?;
+            let Some(last) = pairs.last() else {
+                break;
+            };
+            self.last = Some(last.key.clone());
+            self.skip -= pairs.len() as u64;
+        }
+        // 多读一个，用来判断后面是否还有数据
+        let mut pairs = self.read(self.page_size + 1).await?;
+        let has_more = pairs.len() > self.page_size;
+        pairs.truncate(self.page_size);
+        if let Some(last) = pairs.last() {
+            self.last = Some(last.key.clone());
+        }
+        self.position += pairs.len() as u64;
+
+        let mut res: CommandResponse = pairs.into();
+        if self.with_cursor {
+            let next = if has_more { self.position } else { 0 };
+            res.values = vec![(next as i64).into()];
+        }
+        res.has_more = has_more;
+        Ok(res)
+    }
+
+    /// 读取上一页之后的 limit 个 kvpair；会阻塞的存储引擎在阻塞线程上读取
+    async fn read(&self, limit: usize) -> Result<Vec<Kvpair>, KvError> {
+        let (service, table, start) = (self.service.clone(), self.table.clone(), self.last.clone());
+        if !service.store.is_blocking() {
+            return service.store.scan_after(table, start, limit);
+        }
+        task::spawn_blocking(move || service.store.scan_after(table, start, limit))
+            .await
+            .map_err(|e| KvError::Internal(format!("Failed to read page: {}", e)))?
+    }
+}
+
+#[cfg(test)]
+mod pages_tests {
+    use futures::StreamExt;
+
+    use super::*;
+    use crate::{
+        pb::abi::{CommandRequest, Value},
+        service_builder::ServiceBuilder,
+        sled_db::SledDB,
+    };
+
+    async fn collect<Store: Storage>(
+        service: &Service<Store>,
+        cmd: CommandRequest,
+    ) -> Vec<Arc<CommandResponse>> {
+        service.execute(cmd.with_stream()).collect().await
+    }
+
+    fn keys(res: &CommandResponse) -> Vec<Bytes> {
+        res.pairs.iter().map(|p| p.key.clone()).collect()
+    }
+
+    #[tokio::test]
+    async fn hgetall_should_stream_pages() {
+        let service: Service = ServiceBuilder::default().finish();
+        let total = PAGE_SIZE * 2 + 10;
+        for i in 0..total {
+            service
+                .store
+                .set("t1", format!("key-{:05}", i), "v".into())
+                .unwrap();
+        }
+
+        let frames = collect(&service, CommandRequest::new_hgetall("t1")).await;
+        let sizes: Vec<usize> = frames.iter().map(|res| res.pairs.len()).collect();
+        assert_eq!(sizes, [PAGE_SIZE, PAGE_SIZE, 10]);
+        let more: Vec<bool> = frames.iter().map(|res| res.has_more).collect();
+        assert_eq!(more, [true, true, false]);
+        // 按 key 的顺序返回，没有重复
+        let all: Vec<Bytes> = frames.iter().flat_map(|res| keys(res)).collect();
+        let expected: Vec<Bytes> = (0..total)
+            .map(|i| Bytes::from(format!("key-{:05}", i)))
+            .collect();
+        assert_eq!(all, expected);
+
+        // 空的 table 返回一帧
+        let frames = collect(&service, CommandRequest::new_hgetall("t2")).await;
+        assert_eq!(frames.len(), 1);
+        assert!(frames[0].pairs.is_empty() && !frames[0].has_more);
+
+        // 没有设置 stream 时仍然一次返回
+        let frames: Vec<_> = service
+            .execute(CommandRequest::new_hgetall("t1"))
+            .collect()
+            .await;
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0].pairs.len(), total);
+    }
+
+    #[tokio::test]
+    async fn hscan_should_stream_pages_from_cursor() {
+        let dir = tempfile::tempdir().unwrap();
+        let service: Service<SledDB> = ServiceBuilder::new(SledDB::new(dir.path())).finish();
+        for key in ["a", "b", "c", "d", "e", "f"] {
+            service.store.set("t1", key, key.into()).unwrap();
+        }
+
+        let frames = collect(&service, CommandRequest::new_hscan("t1", 1, 2)).await;
+        let pages: Vec<Vec<Bytes>> = frames.iter().map(|res| keys(res)).collect();
+        assert_eq!(pages, [vec!["b", "c"], vec!["d", "e"], vec!["f"]]);
+        let cursors: Vec<Value> = frames.iter().map(|res| res.values[0].clone()).collect();
+        assert_eq!(cursors, [3.into(), 5.into(), 0.into()]);
+        assert!(frames[1].has_more && !frames[2].has_more);
+
+        // cursor 超过 table 的大小
+        let frames = collect(&service, CommandRequest::new_hscan("t1", 10, 2)).await;
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0].values, [0.into()]);
+    }
+}
@@ -0,0 +1,415 @@
+//! WASM 插件：启动时加载配置的模块，模块导出的函数可以用 FCALL 调用，不需要重新编译服务器。
+//!
+//! 模块需要导出 memory 和 `alloc(len: i32) -> i32`，服务器用 alloc 在模块的内存中分配空间来传递数据。
+//! 签名为 `(ptr: i32, len: i32) -> i64` 的导出函数都可以调用：参数是 protobuf 编码的 ValueList，
+//! 返回值把结果的地址和长度打包成 `ptr << 32 | len`，结果也是 protobuf 编码的 ValueList。
+//! 模块可以从 "kv" 导入下面的函数读写 FCALL 指定的 table，地址和长度的含义和上面一样：
+//! - `get(key_ptr, key_len) -> i64`：返回 protobuf 编码的 Value，key 不存在时返回 -1
+//! - `set(key_ptr, key_len, value_ptr, value_len) -> i32`：value 是 protobuf 编码的 Value，返回 0
+//! - `del(key_ptr, key_len) -> i32`：key 存在时返回 1，否则返回 0
+//! - `scan(prefix_ptr, prefix_len) -> i64`：返回 protobuf 编码的 CommandResponse，pairs 是以 prefix 开头的 kv pair
+//!
+//! 函数可以读 table 中任意的 key，但只能修改 FCALL 中声明的 key，这样修改和其它命令一样会产生 keyspace 通知、
+//! 复制到 replica。每次修改立即生效，函数出错时已经做的修改不会回滚
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs,
+    sync::Arc,
+};
+
+use bytes::Bytes;
+use prost::Message;
+use tracing::info;
+use wasmi::{
+    AsContext, AsContextMut, Caller, Config, Engine, Linker, Memory, Module, StoreLimits,
+    StoreLimitsBuilder, TypedFunc, ValType,
+};
+
+use super::{service_builder::ServiceBuilder, Service};
+use crate::{
+    config::{parse_size, PluginConfig},
+    error::KvError,
+    pb::abi::{CommandResponse, Fcall, Value, ValueList},
+    Storage,
+};
+
+/// 没有配置时每次调用最多消耗的 fuel
+const DEFAULT_FUEL: u64 = 10_000_000;
+/// 没有配置时模块可以使用的内存
+const DEFAULT_MAX_MEMORY: usize = 16 * 1024 * 1024;
+
+/// 加载的模块，<函数名, 导出这个函数的模块>
+pub struct Plugins {
+    engine: Engine,
+    functions: BTreeMap<String, Function>,
+}
+
+struct Function {
+    module: Module,
+    fuel: u64,
+    max_memory: usize,
+}
+
+/// 一次调用中模块可以访问的数据
+struct Host<Store> {
+    service: Arc<ServiceBuilder<Store>>,
+    table: String,
+    /// 允许修改的 key
+    keys: BTreeSet<Bytes>,
+    limits: StoreLimits,
+}
+
+impl Default for Plugins {
+    fn default() -> Self {
+        let mut config = Config::default();
+        config.consume_fuel(true);
+        Self {
+            engine: Engine::new(&config),
+            functions: BTreeMap::new(),
+        }
+    }
+}
+
+impl Plugins {
+    pub fn load(config: &[PluginConfig]) -> Result<Self, KvError> {
+        let mut plugins = Self::default();
+        for plugin in config {
+            let wasm = fs::read(&plugin.path)?;
+            let max_memory = match &plugin.max_memory {
+                Some(size) => parse_size(size)?,
+                None => DEFAULT_MAX_MEMORY,
+            };
+            let fuel = plugin.fuel.unwrap_or(DEFAULT_FUEL);
+            let names = plugins.add(&wasm, fuel, max_memory).map_err(|e| {
+                KvError::InvalidCommand(format!("failed to load plugin {}: {}", plugin.path, e))
+            })?;
+            info!("Loaded plugin {} with functions {:?}", plugin.path, names);
+        }
+        Ok(plugins)
+    }
+
+    /// 加载一个模块，返回它导出的可以调用的函数；wasm 可以是二进制格式或者文本格式。
+    /// 函数名和已经加载的模块重复时不加载这个模块
+    pub fn add(
+        &mut self,
+        wasm: &[u8],
+        fuel: u64,
+        max_memory: usize,
+    ) -> Result<Vec<String>, KvError> {
+        let module = Module::new(&self.engine, wasm)
+            .map_err(|e| KvError::InvalidCommand(format!("invalid module: {}", e)))?;
+        let names: Vec<String> = module
+            .exports()
+            .filter(|export| export.name() != "alloc")
+            .filter(|export| {
+                export.ty().func().is_some_and(|ty| {
+                    ty.params() == [ValType::I32, ValType::I32] && ty.results() == [ValType::I64]
+                })
+            })
+            .map(|export| export.name().to_owned())
+            .collect();
+        if let Some(name) = names.iter().find(|name| self.functions.contains_key(*name)) {
+            return Err(KvError::InvalidCommand(format!(
+                "function {} is already defined",
+                name
+            )));
+        }
+        for name in &names {
+            let function = Function {
+                module: module.clone(),
+                fuel,
+                max_memory,
+            };
+            self.functions.insert(name.clone(), function);
+        }
+        Ok(names)
+    }
+}
+
+impl<Store: Storage> Service<Store> {
+    /// 处理 FCALL：每次调用都在新的实例上执行，调用之间不共享模块的内存
+    pub(super) fn fcall(&self, cmd: &Fcall) -> CommandResponse {
+        match self.call_function(cmd) {
+            Ok(values) => values.into(),
+            Err(e) => e.into(),
+        }
+    }
+
+    fn call_function(&self, cmd: &Fcall) -> Result<Vec<Value>, KvError> {
+        let plugins = &self.plugins;
+        let function = plugins
+            .functions
+            .get(&cmd.function)
+            .ok_or_else(|| KvError::NotFound(format!("function {}", cmd.function)))?;
+        let failed = |e: wasmi::Error| {
+            KvError::InvalidCommand(format!("function {} failed: {}", cmd.function, e))
+        };
+
+        let host = Host {
+            service: self.inner.clone(),
+            table: cmd.table.clone(),
+            keys: cmd.keys.iter().cloned().collect(),
+            limits: StoreLimitsBuilder::new()
+                .memory_size(function.max_memory)
+                .build(),
+        };
+        let mut store = wasmi::Store::new(&plugins.engine, host);
+        store.limiter(|host| &mut host.limits);
+        store.set_fuel(function.fuel).map_err(failed)?;
+        let instance = linker(&plugins.engine)
+            .and_then(|linker| linker.instantiate_and_start(&mut store, &function.module))
+            .map_err(failed)?;
+        let (memory, alloc) = match (
+            instance.get_memory(&store, "memory"),
+            instance.get_typed_func::<i32, i32>(&store, "alloc"),
+        ) {
+            (Some(memory), Ok(alloc)) => (memory, alloc),
+            _ => return Err(failed(missing_exports())),
+        };
+        let func = instance
+            .get_typed_func::<(i32, i32), i64>(&store, &cmd.function)
+            .map_err(failed)?;
+
+        let args = ValueList {
+            values: cmd.args.clone(),
+        };
+        let args = write(&mut store, memory, alloc, &args.encode_to_vec()).map_err(failed)?;
+        let (ptr, len) = unpack(args);
+        let result = func
+            .call(&mut store, (ptr as i32, len as i32))
+            .map_err(failed)?;
+        let (ptr, len) = unpack(result);
+        let result = read(&store, memory, ptr as i32, len as i32).map_err(failed)?;
+        let result = ValueList::decode(&result[..]).map_err(|e| {
+            KvError::InvalidCommand(format!("invalid result of {}: {}", cmd.function, e))
+        })?;
+        Ok(result.values)
+    }
+}
+
+/// 模块可以导入的 "kv" 函数
+fn linker<Store: Storage>(engine: &Engine) -> Result<Linker<Host<Store>>, wasmi::Error> {
+    let mut linker = Linker::new(engine);
+    linker.func_wrap(
+        "kv",
+        "get",
+        |mut caller: Caller<'_, Host<Store>>, ptr: i32, len: i32| -> Result<i64, wasmi::Error> {
+            let (memory, alloc) = exports(&caller)?;
+            let key = read(&caller, memory, ptr, len)?;
+            let host = caller.data();
+            match host
+                .service
+                .store
+                .get(&host.table, &key)
+                .map_err(host_error)?
+            {
+                Some(value) => write(&mut caller, memory, alloc, &value.encode_to_vec()),
+                None => Ok(-1),
+            }
+        },
+    )?;
+    linker.func_wrap(
+        "kv",
+        "set",
+        |caller: Caller<'_, Host<Store>>,
+         key_ptr: i32,
+         key_len: i32,
+         value_ptr: i32,
+         value_len: i32|
+         -> Result<i32, wasmi::Error> {
+            let (memory, _) = exports(&caller)?;
+            let key = writable_key(&caller, memory, key_ptr, key_len)?;
+            let value = read(&caller, memory, value_ptr, value_len)?;
+            let value = Value::decode(&value[..]).map_err(host_error)?;
+            let host = caller.data();
+            host.service
+                .store
+                .set(&host.table, key, value)
+                .map_err(host_error)?;
+            Ok(0)
+        },
+    )?;
+    linker.func_wrap(
+        "kv",
+        "del",
+        |caller: Caller<'_, Host<Store>>, ptr: i32, len: i32| -> Result<i32, wasmi::Error> {
+            let (memory, _) = exports(&caller)?;
+            let key = writable_key(&caller, memory, ptr, len)?;
+            let host = caller.data();
+            let old = host
+                .service
+                .store
+                .del(&host.table, key)
+                .map_err(host_error)?;
+            Ok(old.is_some() as i32)
+        },
+    )?;
+    linker.func_wrap(
+        "kv",
+        "scan",
+        |mut caller: Caller<'_, Host<Store>>, ptr: i32, len: i32| -> Result<i64, wasmi::Error> {
+            let (memory, alloc) = exports(&caller)?;
+            let prefix = read(&caller, memory, ptr, len)?;
+            let host = caller.data();
+            let pairs: Vec<_> = host
+                .service
+                .store
+                .scan_prefix(&host.table, prefix)
+                .map_err(host_error)?
+                .collect();
+            let resp = CommandResponse::from(pairs);
+            write(&mut caller, memory, alloc, &resp.encode_to_vec())
+        },
+    )?;
+    Ok(linker)
+}
+
+fn exports<Store>(
+    caller: &Caller<'_, Host<Store>>,
+) -> Result<(Memory, TypedFunc<i32, i32>), wasmi::Error> {
+    let memory = caller.get_export("memory").and_then(|e| e.into_memory());
+    let alloc = caller.get_export("alloc").and_then(|e| e.into_func());
+    match (memory, alloc) {
+        (Some(memory), Some(alloc)) => Ok((memory, alloc.typed(caller)?)),
+        _ => Err(missing_exports()),
+    }
+}
+
+/// 只有 FCALL 中声明的 key 可以修改
+fn writable_key<Store>(
+    caller: &Caller<'_, Host<Store>>,
+    memory: Memory,
+    ptr: i32,
+    len: i32,
+) -> Result<Bytes, wasmi::Error> {
+    let key = Bytes::from(read(caller, memory, ptr, len)?);
+    match caller.data().keys.contains(&key) {
+        true => Ok(key),
+        false => Err(wasmi::Error::new(format!(
+            "key {} is not declared in FCALL",
+            String::from_utf8_lossy(&key)
+        ))),
+    }
+}
+
+fn read(ctx: impl AsContext, memory: Memory, ptr: i32, len: i32) -> Result<Vec<u8>, wasmi::Error> {
+    let mut data = vec![0; len as u32 as usize];
+    memory.read(ctx, ptr as u32 as usize, &mut data)?;
+    Ok(data)
+}
+
+/// 用模块的 alloc 分配空间，写入 data，返回打包之后的地址和长度
+fn write(
+    mut ctx: impl AsContextMut,
+    memory: Memory,
+    alloc: TypedFunc<i32, i32>,
+    data: &[u8],
+) -> Result<i64, wasmi::Error> {
+    let ptr = alloc.call(&mut ctx, data.len() as i32)?;
+    memory.write(&mut ctx, ptr as u32 as usize, data)?;
+    Ok(((ptr as u32 as i64) << 32) | data.len() as i64)
+}
+
+fn unpack(packed: i64) -> (u32, u32) {
+    ((packed >> 32) as u32, packed as u32)
+}
+
+fn missing_exports() -> wasmi::Error {
+    wasmi::Error::new("module must export memory and alloc")
+}
+
+fn host_error(e: impl ToString) -> wasmi::Error {
+    wasmi::Error::new(e.to_string())
+}
+
+#[cfg(test)]
+mod plugin_tests {
+    use futures::StreamExt;
+
+    use super::*;
+    use crate::{memory::MemTable, pb::abi::CommandRequest};
+
+    const MODULE: &str = r#"
+        (module
+          (import "kv" "get" (func $get (param i32 i32) (result i64)))
+          (import "kv" "set" (func $set (param i32 i32 i32 i32) (result i32)))
+          (memory (export "memory") 1)
+          (global $next (mut i32) (i32.const 1024))
+          (data (i32.const 0) "srcdst")
+          (func (export "alloc") (param $len i32) (result i32)
+            (local $ptr i32)
+            (local.set $ptr (global.get $next))
+            (global.set $next (i32.add (global.get $next) (local.get $len)))
+            (local.get $ptr))
+          ;; 原样返回参数
+          (func (export "echo") (param $ptr i32) (param $len i32) (result i64)
+            (i64.or
+              (i64.shl (i64.extend_i32_u (local.get $ptr)) (i64.const 32))
+              (i64.extend_i32_u (local.get $len))))
+          ;; 把 src 的值复制到 dst
+          (func (export "copy") (param i32 i32) (result i64)
+            (local $v i64)
+            (local.set $v (call $get (i32.const 0) (i32.const 3)))
+            (if (i64.ge_s (local.get $v) (i64.const 0))
+              (then
+                (drop (call $set (i32.const 3) (i32.const 3)
+                  (i32.wrap_i64 (i64.shr_u (local.get $v) (i64.const 32)))
+                  (i32.wrap_i64 (local.get $v))))))
+            (i64.const 0))
+          (func (export "spin") (param i32 i32) (result i64)
+            (loop $l (br $l))
+            (i64.const 0)))
+    "#;
+
+    fn service() -> Service {
+        let mut plugins = Plugins::default();
+        let names = plugins.add(MODULE.as_bytes(), 100_000, 1 << 20).unwrap();
+        assert_eq!(names, ["copy", "echo", "spin"]);
+        ServiceBuilder::new(MemTable::new())
+            .plugins(plugins)
+            .finish()
+    }
+
+    async fn fcall(service: &Service, function: &str, keys: &[&str]) -> Arc<CommandResponse> {
+        let cmd =
+            CommandRequest::new_fcall("t1", function, keys.to_vec(), [Value::from(1), "a".into()]);
+        service.execute(cmd).next().await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn fcall_should_call_plugin_functions() {
+        let service = service();
+        let res = fcall(&service, "echo", &[]).await;
+        assert_eq!(res.values, vec![1.into(), "a".into()]);
+
+        service.store.set("t1", "src", "hello".into()).unwrap();
+        let res = fcall(&service, "copy", &["dst"]).await;
+        assert_eq!(res.status, 200);
+        assert_eq!(
+            service.store.get("t1", "dst").unwrap(),
+            Some("hello".into())
+        );
+    }
+
+    #[tokio::test]
+    async fn fcall_should_be_sandboxed() {
+        let service = service();
+        service.store.set("t1", "src", "hello".into()).unwrap();
+        // 没有声明的 key 不能修改
+        let res = fcall(&service, "copy", &[]).await;
+        assert_eq!(res.status, 400);
+        assert_eq!(service.store.get("t1", "dst").unwrap(), None);
+
+        // fuel 用完之后停止执行
+        let res = fcall(&service, "spin", &[]).await;
+        assert_eq!(res.status, 400);
+        let res = fcall(&service, "alloc", &[]).await;
+        assert_eq!(res.status, 404);
+
+        let mut plugins = Plugins::default();
+        plugins.add(MODULE.as_bytes(), 100, 1 << 20).unwrap();
+        assert!(plugins.add(MODULE.as_bytes(), 100, 1 << 20).is_err());
+        assert!(plugins.add(b"(module", 100, 1 << 20).is_err());
+    }
+}
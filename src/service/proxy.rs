@@ -0,0 +1,466 @@
+//! 代理模式：本节点不保存数据，按 table 和 key 的一致性哈希把命令转发到后端节点；
+//! 遍历整个 table 的命令发给所有后端，再把结果合并起来。客户端只需要连接代理
+
+use std::{collections::BTreeMap, time::Duration};
+
+use futures::future::join_all;
+use tokio::sync::OnceCell;
+
+use super::{session::Session, slowlog::command_name, transaction::touched_keys, Service};
+use crate::{
+    config::{ClientConfig, ProxyConfig},
+    error::KvError,
+    network::pool::Pool,
+    pb::abi::{command_request::RequestData, value, CommandRequest, CommandResponse, Hscan, Value},
+    Storage,
+};
+
+/// 每个后端节点默认的虚拟节点数
+const DEFAULT_VIRTUAL_NODES: usize = 160;
+
+/// HSCAN 的 cursor 高 16 位是后端的序号，低 48 位是后端自己的 cursor
+const CURSOR_SHIFT: u32 = 48;
+const CURSOR_MASK: u64 = (1 << CURSOR_SHIFT) - 1;
+
+/// 命令在代理上怎么执行
+#[derive(Debug, PartialEq)]
+pub(crate) enum Route {
+    /// 和数据无关，在代理本地执行
+    Local,
+    /// 转发给 key 所在的后端
+    Forward(usize),
+    /// 发给所有后端，合并结果
+    Broadcast,
+    /// 依次遍历每个后端
+    Scan,
+}
+
+/// 代理到一组后端节点，连接按需建立
+pub struct Proxy {
+    backends: Vec<Backend>,
+    /// 哈希环：虚拟节点的位置 -> 后端的序号
+    ring: BTreeMap<u64, usize>,
+    virtual_nodes: usize,
+}
+
+struct Backend {
+    config: ClientConfig,
+    /// 第一次使用时建立连接，后端暂时不可用时不影响代理启动
+    pool: OnceCell<Pool>,
+}
+
+impl Proxy {
+    pub fn new(config: &ProxyConfig) -> Result<Self, KvError> {
+        if config.backends.is_empty() {
+            return Err(KvError::InvalidCommand(
+                "proxy needs at least one backend".into(),
+            ));
+        }
+        let virtual_nodes = config.virtual_nodes.unwrap_or(DEFAULT_VIRTUAL_NODES).max(1);
+        let mut ring = BTreeMap::new();
+        for (i, backend) in config.backends.iter().enumerate() {
+            for v in 0..virtual_nodes {
+                let point = format!("{}#{}", backend.general.addr, v);
+                ring.insert(hash(point.as_bytes()), i);
+            }
+        }
+        let backends = config
+            .backends
+            .iter()
+            .map(|config| Backend {
+                config: config.clone(),
+                pool: OnceCell::new(),
+            })
+            .collect();
+        Ok(Self {
+            backends,
+            ring,
+            virtual_nodes,
+        })
+    }
+
+    /// 每个后端的虚拟节点数，和后端地址一起就能还原出哈希环
+    pub fn virtual_nodes(&self) -> usize {
+        self.virtual_nodes
+    }
+
+    /// 各个后端的地址，和配置中的顺序一致
+    pub fn addrs(&self) -> impl Iterator<Item = &str> {
+        self.backends.iter().map(|b| b.config.general.addr.as_str())
+    }
+
+    /// key 所在的后端：哈希环上顺时针方向的第一个虚拟节点
+    pub fn backend_of(&self, table: &str, key: &[u8]) -> usize {
+        let mut data = Vec::with_capacity(table.len() + 1 + key.len());
+        data.extend_from_slice(table.as_bytes());
+        data.push(0);
+        data.extend_from_slice(key);
+        let point = hash(&data);
+        match self.ring.range(point..).next() {
+            Some((_, &i)) => i,
+            None => *self.ring.values().next().unwrap(),
+        }
+    }
+
+    pub(crate) fn route(&self, cmd: &RequestData) -> Result<Route, KvError> {
+        let route = match cmd {
+            RequestData::Ping(_)
+            | RequestData::Hello(_)
+            | RequestData::Auth(_)
+            | RequestData::Info(_)
+            | RequestData::SlowlogGet(_)
+            | RequestData::ClientList(_)
+            | RequestData::ClientKill(_)
+            | RequestData::Select(_)
+            | RequestData::SessionSet(_)
+            | RequestData::ConfigGet(_)
+            | RequestData::ConfigSet(_)
+            | RequestData::ConfigReload(_)
+            | RequestData::ClusterSlots(_)
+            | RequestData::Gossip(_)
+            | RequestData::Members(_)
+            | RequestData::Subscribe(_)
+            | RequestData::Unsubscribe(_)
+            | RequestData::Publish(_) => Route::Local,
+            RequestData::Hgetall(_)
+            | RequestData::Hrange(_)
+            | RequestData::Hscanprefix(_)
+            | RequestData::Query(_)
+            | RequestData::Tlist(_)
+            | RequestData::Dbsize(_)
+            | RequestData::Tdrop(_)
+            | RequestData::Ttruncate(_)
+            | RequestData::CreateIndex(_)
+            | RequestData::Compact(_) => Route::Broadcast,
+            RequestData::Hscan(_) => Route::Scan,
+            _ => {
+                let Some(keys) = keys_of(cmd) else {
+                    return Err(KvError::InvalidCommand(format!(
+                        "{} is not supported in proxy mode",
+                        command_name(cmd)
+                    )));
+                };
+                let mut backends = keys.iter().map(|(table, key)| self.backend_of(table, key));
+                let first = backends.next().unwrap_or_default();
+                if backends.any(|i| i != first) {
+                    return Err(KvError::InvalidCommand(format!(
+                        "keys of {} belong to different backends",
+                        command_name(cmd)
+                    )));
+                }
+                Route::Forward(first)
+            }
+        };
+        Ok(route)
+    }
+
+    /// 按 route 转发命令；只转发 request_data 和超时时间
+    pub(crate) async fn forward(
+        &self,
+        route: Route,
+        cmd: &CommandRequest,
+    ) -> Result<CommandResponse, KvError> {
+        let Some(data) = &cmd.request_data else {
+            return Err(KvError::InvalidCommand("empty command".into()));
+        };
+        let forwarded = CommandRequest {
+            request_data: Some(data.clone()),
+            timeout: cmd.timeout,
+            ..Default::default()
+        };
+        match route {
+            Route::Local => Err(KvError::Internal("command should run locally".into())),
+            Route::Forward(i) => self.backends[i].execute(&forwarded).await,
+            Route::Broadcast => {
+                let results = join_all(self.backends.iter().map(|b| b.execute(&forwarded))).await;
+                let responses = results.into_iter().collect::<Result<Vec<_>, _>>()?;
+                Ok(merge(data, responses))
+            }
+            Route::Scan => match data {
+                RequestData::Hscan(scan) => self.scan(scan, cmd.timeout).await,
+                _ => Err(KvError::Internal("only HSCAN can be scanned".into())),
+            },
+        }
+    }
+
+    /// 从 cursor 指向的后端取一页，这个后端遍历完之后 cursor 指向下一个后端
+    async fn scan(&self, scan: &Hscan, timeout: u64) -> Result<CommandResponse, KvError> {
+        let shard = (scan.cursor >> CURSOR_SHIFT) as usize;
+        let Some(backend) = self.backends.get(shard) else {
+            return Err(KvError::InvalidCommand(format!(
+                "invalid cursor {}",
+                scan.cursor
+            )));
+        };
+        let cmd = CommandRequest {
+            request_data: Some(RequestData::Hscan(Hscan {
+                cursor: scan.cursor & CURSOR_MASK,
+                ..scan.clone()
+            })),
+            timeout,
+            ..Default::default()
+        };
+        let mut res = backend.execute(&cmd).await?;
+        if res.status != 200 {
+            return Ok(res);
+        }
+        let next = res
+            .values
+            .first()
+            .and_then(Value::to_integer)
+            .unwrap_or_default() as u64;
+        let next = match next {
+            0 if shard + 1 < self.backends.len() => ((shard + 1) as u64) << CURSOR_SHIFT,
+            0 => 0,
+            next => ((shard as u64) << CURSOR_SHIFT) | next,
+        };
+        res.values = vec![(next as i64).into()];
+        Ok(res)
+    }
+}
+
+impl Backend {
+    async fn execute(&self, cmd: &CommandRequest) -> Result<CommandResponse, KvError> {
+        let pool = self
+            .pool
+            .get_or_try_init(|| Pool::connect(self.config.clone()))
+            .await?;
+        match cmd.timeout {
+            0 => pool.execute(cmd).await,
+            timeout => {
+                let timeout = Duration::from_millis(timeout);
+                pool.get().await?.execute_with_timeout(cmd, timeout).await
+            }
+        }
+    }
+}
+
+/// 命令访问的所有 key；访问整个 table 或者和 key 无关的命令返回 None
+fn keys_of(cmd: &RequestData) -> Option<Vec<(&str, &[u8])>> {
+    let keys = match cmd {
+        RequestData::Hmdel(v) => v.keys.iter().map(|k| (v.table.as_str(), &k[..])).collect(),
+        RequestData::Hmexist(v) => v.keys.iter().map(|k| (v.table.as_str(), &k[..])).collect(),
+        RequestData::Zadd(v) => vec![(v.table.as_str(), &v.key[..])],
+        RequestData::Zrange(v) => vec![(v.table.as_str(), &v.key[..])],
+        RequestData::Zrangebyscore(v) => vec![(v.table.as_str(), &v.key[..])],
+        RequestData::Multi(_) | RequestData::Watch(_) => return None,
+        _ => touched_keys(cmd)?
+            .into_iter()
+            .map(|(table, key)| key.map(|key| (table, key)))
+            .collect::<Option<_>>()?,
+    };
+    Some(keys)
+}
+
+/// 合并各个后端的结果；有一个后端出错时返回这个错误
+fn merge(cmd: &RequestData, responses: Vec<CommandResponse>) -> CommandResponse {
+    if let Some(res) = responses.iter().find(|res| res.status != 200) {
+        return res.clone();
+    }
+    match cmd {
+        RequestData::Tlist(_) => {
+            let mut tables: Vec<String> = responses
+                .into_iter()
+                .flat_map(|res| res.values)
+                .filter_map(|v| match v.value {
+                    Some(value::Value::String(table)) => Some(table),
+                    _ => None,
+                })
+                .collect();
+            tables.sort();
+            tables.dedup();
+            tables
+                .into_iter()
+                .map(Value::from)
+                .collect::<Vec<_>>()
+                .into()
+        }
+        RequestData::Dbsize(_)
+        | RequestData::Tdrop(_)
+        | RequestData::Ttruncate(_)
+        | RequestData::Compact(_) => {
+            let sum: i64 = responses
+                .iter()
+                .filter_map(|res| res.values.first().and_then(Value::to_integer))
+                .sum();
+            Value::from(sum).into()
+        }
+        RequestData::CreateIndex(_) => {
+            let created = responses
+                .iter()
+                .any(|res| res.values.first() == Some(&true.into()));
+            Value::from(created).into()
+        }
+        _ => {
+            let mut pairs: Vec<_> = responses.into_iter().flat_map(|res| res.pairs).collect();
+            pairs.sort_by(|a, b| a.key.cmp(&b.key));
+            pairs.into()
+        }
+    }
+}
+
+/// FNV-1a 之后再打散一次，相近的字符串也能均匀地分布在哈希环上；不依赖 Rust 版本，重启之后位置不变
+pub(super) fn hash(data: &[u8]) -> u64 {
+    let mut h: u64 = 0xcbf29ce484222325;
+    for b in data {
+        h ^= *b as u64;
+        h = h.wrapping_mul(0x100000001b3);
+    }
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xff51afd7ed558ccd);
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xc4ceb9fe1a85ec53);
+    h ^ (h >> 33)
+}
+
+impl<Store: Storage> Service<Store> {
+    /// 代理模式下转发命令；在代理本地执行的命令返回 None
+    pub(super) async fn execute_in_proxy(
+        &self,
+        proxy: &Proxy,
+        cmd: &CommandRequest,
+        session: &Session,
+    ) -> Option<CommandResponse> {
+        let data = cmd.request_data.as_ref()?;
+        let route = match proxy.route(data) {
+            Ok(Route::Local) => return None,
+            Ok(route) => route,
+            Err(e) => return Some(e.into()),
+        };
+        self.stats.command();
+        if let Err(e) = self.authorize(cmd, session) {
+            return Some(e.into());
+        }
+        let res = proxy.forward(route, cmd).await.unwrap_or_else(Into::into);
+        self.audit.record(data, &res, session, &self.broadcaster);
+        Some(res)
+    }
+}
+
+#[cfg(test)]
+mod proxy_tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::pb::abi::Kvpair;
+
+    fn proxy(addrs: &[&str]) -> Proxy {
+        let client = ClientConfig::load("fixtures/client.conf").unwrap();
+        let backends = addrs
+            .iter()
+            .map(|addr| {
+                let mut config = client.clone();
+                config.general.addr = addr.to_string();
+                config
+            })
+            .collect();
+        Proxy::new(&ProxyConfig {
+            backends,
+            virtual_nodes: None,
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn keys_should_be_spread_over_backends() {
+        let addrs = ["10.0.0.1:9876", "10.0.0.2:9876", "10.0.0.3:9876"];
+        let proxy3 = proxy(&addrs);
+        let keys: Vec<String> = (0..3000).map(|i| format!("key{}", i)).collect();
+        let mut counts: HashMap<usize, usize> = HashMap::new();
+        for key in &keys {
+            *counts
+                .entry(proxy3.backend_of("t1", key.as_bytes()))
+                .or_default() += 1;
+        }
+        assert_eq!(counts.len(), 3);
+        assert!(
+            counts.values().all(|&n| n > 600 && n < 1400),
+            "{:?}",
+            counts
+        );
+
+        // 增加一个后端，只有一部分 key 移动到新的后端，其它 key 不动
+        let proxy4 = proxy(&[addrs[0], addrs[1], addrs[2], "10.0.0.4:9876"]);
+        let moved = keys
+            .iter()
+            .filter(|key| {
+                let before = proxy3.backend_of("t1", key.as_bytes());
+                let after = proxy4.backend_of("t1", key.as_bytes());
+                assert!(after == before || after == 3);
+                after != before
+            })
+            .count();
+        assert!(moved > 300 && moved < 1200, "{} keys moved", moved);
+
+        // 后端的顺序不影响 key 的位置
+        let reversed = proxy(&[addrs[2], addrs[1], addrs[0]]);
+        for key in keys.iter().take(100) {
+            let i = proxy3.backend_of("t1", key.as_bytes());
+            assert_eq!(
+                addrs[i],
+                addrs[2 - reversed.backend_of("t1", key.as_bytes())]
+            );
+        }
+    }
+
+    #[test]
+    fn commands_should_be_routed_by_key() {
+        let proxy = proxy(&["10.0.0.1:9876", "10.0.0.2:9876"]);
+        let route = |cmd: CommandRequest| proxy.route(&cmd.request_data.unwrap());
+        let i = proxy.backend_of("t1", b"k1");
+        assert_eq!(
+            route(CommandRequest::new_hget("t1", "k1")).unwrap(),
+            Route::Forward(i)
+        );
+        assert_eq!(
+            route(CommandRequest::new_hset("t1", "k1", "v1")).unwrap(),
+            Route::Forward(i)
+        );
+        assert_eq!(
+            route(CommandRequest::new_hgetall("t1")).unwrap(),
+            Route::Broadcast
+        );
+        assert_eq!(route(CommandRequest::new_ping()).unwrap(), Route::Local);
+
+        // 找一个不在同一个后端的 key
+        let other = (0..)
+            .map(|n| format!("k{}", n))
+            .find(|k| proxy.backend_of("t1", k.as_bytes()) != i)
+            .unwrap();
+        let cmd = CommandRequest::new_hmget("t1", vec!["k1".to_string(), other]);
+        assert!(route(cmd).is_err());
+        assert!(route(CommandRequest::new_multi()).is_err());
+    }
+
+    #[test]
+    fn broadcast_results_should_be_merged() {
+        let tlist = CommandRequest::new_tlist().request_data.unwrap();
+        let responses = vec![
+            vec![Value::from("t1"), "t2".into()].into(),
+            vec![Value::from("t2"), "t3".into()].into(),
+        ];
+        let res = merge(&tlist, responses);
+        assert_eq!(res.values, vec!["t1".into(), "t2".into(), "t3".into()]);
+
+        let dbsize = CommandRequest::new_dbsize().request_data.unwrap();
+        let res = merge(&dbsize, vec![Value::from(2).into(), Value::from(3).into()]);
+        assert_eq!(res.values, vec![5.into()]);
+
+        let hgetall = CommandRequest::new_hgetall("t1").request_data.unwrap();
+        let responses = vec![
+            vec![Kvpair::new("k3", "v3".into())].into(),
+            vec![
+                Kvpair::new("k1", "v1".into()),
+                Kvpair::new("k2", "v2".into()),
+            ]
+            .into(),
+        ];
+        let res = merge(&hgetall, responses);
+        let keys: Vec<_> = res.pairs.iter().map(|p| p.key.clone()).collect();
+        assert_eq!(keys, vec!["k1", "k2", "k3"]);
+
+        let error: CommandResponse = KvError::NotFound("t1".into()).into();
+        let res = merge(&hgetall, vec![CommandResponse::ok(), error.clone()]);
+        assert_eq!(res, error);
+    }
+}
@@ -0,0 +1,682 @@
+//! raft 的选举、日志复制和提交，不涉及网络和定时器；消息的收发和后台任务在 cluster.rs 中。
+//! 目前不支持日志压缩、快照和成员变更
+
+use std::{
+    collections::{HashMap, HashSet},
+    fs::{self, File, OpenOptions},
+    io::{ErrorKind, Write},
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use prost::Message;
+use rand::Rng;
+use tracing::{info, warn};
+
+use crate::{
+    error::KvError,
+    pb::abi::{CommandRequest, RaftAppend, RaftEntry, RaftHardState, RaftVote},
+};
+
+const STATE_FILE: &str = "raft.state";
+const LOG_FILE: &str = "raft.log";
+
+/// 一次 AppendEntries 最多发送的日志条数
+const MAX_ENTRIES: usize = 256;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Follower,
+    Candidate,
+    Leader,
+}
+
+impl Role {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::Follower => "follower",
+            Role::Candidate => "candidate",
+            Role::Leader => "leader",
+        }
+    }
+}
+
+/// 持久化的任期、投票和日志；回复其它节点之前先写到磁盘
+pub(super) struct RaftLog {
+    dir: PathBuf,
+    state: RaftHardState,
+    /// 第 i 个元素是 index 为 i + 1 的日志
+    entries: Vec<RaftEntry>,
+    file: File,
+}
+
+impl RaftLog {
+    /// 读取 dir 中的任期、投票和日志，目录不存在时创建
+    pub fn open(dir: impl AsRef<Path>) -> Result<Self, KvError> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+        let state = match fs::read(dir.join(STATE_FILE)) {
+            Ok(data) => RaftHardState::decode(&data[..])?,
+            Err(e) if e.kind() == ErrorKind::NotFound => RaftHardState::default(),
+            Err(e) => return Err(e.into()),
+        };
+
+        let path = dir.join(LOG_FILE);
+        let data = match fs::read(&path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == ErrorKind::NotFound => vec![],
+            Err(e) => return Err(e.into()),
+        };
+        let (mut buf, mut len) = (&data[..], 0);
+        let mut entries = vec![];
+        while !buf.is_empty() {
+            match RaftEntry::decode_length_delimited(&mut buf) {
+                Ok(entry) => entries.push(entry),
+                Err(_) => break,
+            }
+            len = data.len() - buf.len();
+        }
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        // 最后一条日志没写完就崩溃了，截掉之后才能接着写
+        if len < data.len() {
+            warn!(
+                "Truncate {} bytes of broken raft log tail",
+                data.len() - len
+            );
+            file.set_len(len as u64)?;
+        }
+        info!(
+            "Loaded {} raft log entries, term {}",
+            entries.len(),
+            state.term
+        );
+        Ok(Self {
+            dir,
+            state,
+            entries,
+            file,
+        })
+    }
+
+    pub fn term(&self) -> u64 {
+        self.state.term
+    }
+
+    /// 当前任期投票给了谁，0 表示还没有投票
+    pub fn voted_for(&self) -> u64 {
+        self.state.voted_for
+    }
+
+    /// 先写到临时文件再改名，崩溃时不会留下写了一半的状态
+    pub fn save_state(&mut self, term: u64, voted_for: u64) -> Result<(), KvError> {
+        let state = RaftHardState { term, voted_for };
+        let tmp = self.dir.join(format!("{}.tmp", STATE_FILE));
+        let mut file = File::create(&tmp)?;
+        file.write_all(&state.encode_to_vec())?;
+        file.sync_all()?;
+        fs::rename(tmp, self.dir.join(STATE_FILE))?;
+        self.state = state;
+        Ok(())
+    }
+
+    pub fn last_index(&self) -> u64 {
+        self.entries.len() as u64
+    }
+
+    pub fn last_term(&self) -> u64 {
+        self.entries.last().map(|e| e.term).unwrap_or_default()
+    }
+
+    /// index 处日志的任期；index 为 0 时是 0，超出日志时为 None
+    pub fn term_at(&self, index: u64) -> Option<u64> {
+        match index {
+            0 => Some(0),
+            _ => self.entry(index).map(|e| e.term),
+        }
+    }
+
+    pub fn entry(&self, index: u64) -> Option<&RaftEntry> {
+        index
+            .checked_sub(1)
+            .and_then(|i| self.entries.get(i as usize))
+    }
+
+    /// 从 index 开始最多 max 条日志
+    pub fn entries_from(&self, index: u64, max: usize) -> Vec<RaftEntry> {
+        let start = (index.max(1) - 1) as usize;
+        let end = (start + max).min(self.entries.len());
+        self.entries.get(start..end).unwrap_or_default().to_vec()
+    }
+
+    pub fn append(&mut self, entries: &[RaftEntry]) -> Result<(), KvError> {
+        let mut buf = vec![];
+        for entry in entries {
+            entry.encode_length_delimited(&mut buf)?;
+        }
+        self.file.write_all(&buf)?;
+        self.file.sync_data()?;
+        self.entries.extend_from_slice(entries);
+        Ok(())
+    }
+
+    /// 删除 index 以及之后的日志，剩下的日志重新写一遍
+    pub fn truncate(&mut self, index: u64) -> Result<(), KvError> {
+        self.entries.truncate((index.max(1) - 1) as usize);
+        let mut buf = vec![];
+        for entry in &self.entries {
+            entry.encode_length_delimited(&mut buf)?;
+        }
+        let tmp = self.dir.join(format!("{}.tmp", LOG_FILE));
+        let mut file = File::create(&tmp)?;
+        file.write_all(&buf)?;
+        file.sync_all()?;
+        let path = self.dir.join(LOG_FILE);
+        fs::rename(tmp, &path)?;
+        self.file = OpenOptions::new().append(true).open(path)?;
+        Ok(())
+    }
+}
+
+/// 一个节点上的 raft 状态
+pub(super) struct Raft {
+    id: u64,
+    peers: Vec<u64>,
+    election_timeout: Duration,
+    log: RaftLog,
+    role: Role,
+    leader: Option<u64>,
+    commit_index: u64,
+    /// 已经应用到存储的最后一条日志
+    last_applied: u64,
+    /// leader 上记录的每个 peer 下一条要发送的日志，和已经确认一致的最后一条日志
+    next_index: HashMap<u64, u64>,
+    match_index: HashMap<u64, u64>,
+    /// candidate 收到的投票，包括自己
+    votes: HashSet<u64>,
+    election_deadline: Instant,
+}
+
+impl Raft {
+    pub fn new(id: u64, peers: Vec<u64>, election_timeout: Duration, log: RaftLog) -> Self {
+        let mut raft = Self {
+            id,
+            peers,
+            election_timeout,
+            log,
+            role: Role::Follower,
+            leader: None,
+            commit_index: 0,
+            last_applied: 0,
+            next_index: HashMap::new(),
+            match_index: HashMap::new(),
+            votes: HashSet::new(),
+            election_deadline: Instant::now(),
+        };
+        raft.reset_election_timer();
+        raft
+    }
+
+    pub fn role(&self) -> Role {
+        self.role
+    }
+
+    pub fn is_leader(&self) -> bool {
+        self.role == Role::Leader
+    }
+
+    pub fn leader(&self) -> Option<u64> {
+        self.leader
+    }
+
+    pub fn term(&self) -> u64 {
+        self.log.term()
+    }
+
+    pub fn commit_index(&self) -> u64 {
+        self.commit_index
+    }
+
+    pub fn last_applied(&self) -> u64 {
+        self.last_applied
+    }
+
+    /// 包括自己在内的多数节点
+    pub fn quorum(&self) -> usize {
+        let nodes = self.peers.len() + 1;
+        nodes / 2 + 1
+    }
+
+    /// 下一次选举的时刻在 1 到 2 倍的选举超时之间随机选取，避免多个节点同时发起选举
+    fn reset_election_timer(&mut self) {
+        let timeout = self.election_timeout.as_millis().max(1) as u64;
+        let jitter = rand::thread_rng().gen_range(0..timeout);
+        self.election_deadline = Instant::now() + Duration::from_millis(timeout + jitter);
+    }
+
+    /// 到了选举的时刻就发起选举，返回要发给所有 peer 的投票请求；只有一个节点时直接成为 leader
+    pub fn tick(&mut self, now: Instant) -> Result<Option<RaftVote>, KvError> {
+        if self.role == Role::Leader || now < self.election_deadline {
+            return Ok(None);
+        }
+        let term = self.term() + 1;
+        self.log.save_state(term, self.id)?;
+        self.role = Role::Candidate;
+        self.leader = None;
+        self.votes = HashSet::from([self.id]);
+        self.reset_election_timer();
+        info!("Node {} starts election for term {}", self.id, term);
+        if self.votes.len() >= self.quorum() {
+            self.become_leader()?;
+            return Ok(None);
+        }
+        Ok(Some(RaftVote {
+            term,
+            candidate: self.id,
+            last_log_index: self.log.last_index(),
+            last_log_term: self.log.last_term(),
+        }))
+    }
+
+    /// 处理投票请求，返回 (term, 是否投票)；candidate 的日志至少和自己一样新才投票
+    pub fn handle_vote(&mut self, req: &RaftVote) -> Result<(u64, bool), KvError> {
+        if req.term > self.term() {
+            self.become_follower(req.term, None)?;
+        }
+        if req.term < self.term() {
+            return Ok((self.term(), false));
+        }
+        let up_to_date = (req.last_log_term, req.last_log_index)
+            >= (self.log.last_term(), self.log.last_index());
+        let voted_for = self.log.voted_for();
+        let granted = up_to_date && (voted_for == 0 || voted_for == req.candidate);
+        if granted && voted_for == 0 {
+            self.log.save_state(req.term, req.candidate)?;
+            self.reset_election_timer();
+        }
+        Ok((self.term(), granted))
+    }
+
+    /// 处理 peer 对 term 任期投票请求的回复，成为 leader 时返回 true
+    pub fn handle_vote_response(
+        &mut self,
+        peer: u64,
+        req_term: u64,
+        term: u64,
+        granted: bool,
+    ) -> Result<bool, KvError> {
+        if term > self.term() {
+            self.become_follower(term, None)?;
+            return Ok(false);
+        }
+        if self.role != Role::Candidate || req_term != self.term() || !granted {
+            return Ok(false);
+        }
+        self.votes.insert(peer);
+        if self.votes.len() < self.quorum() {
+            return Ok(false);
+        }
+        self.become_leader()?;
+        Ok(true)
+    }
+
+    fn become_follower(&mut self, term: u64, leader: Option<u64>) -> Result<(), KvError> {
+        if term > self.term() {
+            self.log.save_state(term, 0)?;
+        }
+        if self.role == Role::Leader {
+            info!("Node {} steps down in term {}", self.id, self.term());
+        }
+        self.role = Role::Follower;
+        self.leader = leader;
+        self.votes.clear();
+        Ok(())
+    }
+
+    fn become_leader(&mut self) -> Result<(), KvError> {
+        info!("Node {} becomes leader of term {}", self.id, self.term());
+        self.role = Role::Leader;
+        self.leader = Some(self.id);
+        let next = self.log.last_index() + 1;
+        self.next_index = self.peers.iter().map(|&p| (p, next)).collect();
+        self.match_index = self.peers.iter().map(|&p| (p, 0)).collect();
+        // 上任时写入一条空操作，它提交之后，之前任期留下的日志也一起提交
+        self.append_entry(None)?;
+        Ok(())
+    }
+
+    /// leader 把命令追加到日志中，返回日志的 index 和任期
+    pub fn propose(&mut self, command: CommandRequest) -> Result<(u64, u64), KvError> {
+        if self.role != Role::Leader {
+            return Err(KvError::NotLeader(format!(
+                "node {} is not leader",
+                self.id
+            )));
+        }
+        self.append_entry(Some(command))
+    }
+
+    fn append_entry(&mut self, command: Option<CommandRequest>) -> Result<(u64, u64), KvError> {
+        let term = self.term();
+        self.log.append(&[RaftEntry { term, command }])?;
+        // 只有一个节点时写下就提交了
+        self.advance_commit();
+        Ok((self.log.last_index(), term))
+    }
+
+    /// 发给 peer 的 AppendEntries：从 next_index 开始的日志，没有新日志时是心跳
+    pub fn append_request(&self, peer: u64) -> RaftAppend {
+        let next = self
+            .next_index
+            .get(&peer)
+            .copied()
+            .unwrap_or(self.log.last_index() + 1);
+        let prev = next - 1;
+        RaftAppend {
+            term: self.term(),
+            leader: self.id,
+            prev_log_index: prev,
+            prev_log_term: self.log.term_at(prev).unwrap_or_default(),
+            entries: self.log.entries_from(next, MAX_ENTRIES),
+            leader_commit: self.commit_index,
+        }
+    }
+
+    /// leader 上 peer 是否还有日志没有发送
+    pub fn lags(&self, peer: u64) -> bool {
+        self.role == Role::Leader
+            && self.next_index.get(&peer).copied().unwrap_or_default() <= self.log.last_index()
+    }
+
+    /// 处理 AppendEntries，返回 (term, 是否成功, index)：成功时 index 是和 leader 一致的最后一条日志，
+    /// 失败时是 leader 下一次应该从哪里开始发送
+    pub fn handle_append(&mut self, req: &RaftAppend) -> Result<(u64, bool, u64), KvError> {
+        if req.term < self.term() {
+            return Ok((self.term(), false, 0));
+        }
+        self.become_follower(req.term, Some(req.leader))?;
+        self.reset_election_timer();
+
+        let last = self.log.last_index();
+        if req.prev_log_index > last {
+            return Ok((self.term(), false, last + 1));
+        }
+        if let Some(conflict) = self
+            .log
+            .term_at(req.prev_log_index)
+            .filter(|&t| t != req.prev_log_term)
+        {
+            // 跳过整个冲突的任期，不用一条一条往回试
+            let mut index = req.prev_log_index;
+            while index > self.commit_index + 1 && self.log.term_at(index - 1) == Some(conflict) {
+                index -= 1;
+            }
+            return Ok((self.term(), false, index));
+        }
+
+        for (i, entry) in req.entries.iter().enumerate() {
+            let index = req.prev_log_index + 1 + i as u64;
+            match self.log.term_at(index) {
+                Some(term) if term == entry.term => continue,
+                Some(_) => self.log.truncate(index)?,
+                None => {}
+            }
+            self.log.append(&req.entries[i..])?;
+            break;
+        }
+        let matched = req.prev_log_index + req.entries.len() as u64;
+        self.commit_index = self.commit_index.max(req.leader_commit.min(matched));
+        Ok((self.term(), true, matched))
+    }
+
+    /// 处理 peer 对 req_term 任期 AppendEntries 的回复，commit index 前进时返回 true
+    pub fn handle_append_response(
+        &mut self,
+        peer: u64,
+        req_term: u64,
+        term: u64,
+        success: bool,
+        index: u64,
+    ) -> Result<bool, KvError> {
+        if term > self.term() {
+            self.become_follower(term, None)?;
+            return Ok(false);
+        }
+        if self.role != Role::Leader || req_term != self.term() {
+            return Ok(false);
+        }
+        let matched = self.match_index.entry(peer).or_default();
+        let next = self.next_index.entry(peer).or_insert(1);
+        if success {
+            *matched = (*matched).max(index);
+            *next = (*next).max(*matched + 1);
+            return Ok(self.advance_commit());
+        }
+        *next = index.max(*matched + 1).min(*next).max(1);
+        Ok(false)
+    }
+
+    /// 多数节点都有的、当前任期的日志可以提交；之前任期的日志随之提交
+    fn advance_commit(&mut self) -> bool {
+        if self.role != Role::Leader {
+            return false;
+        }
+        for index in (self.commit_index + 1..=self.log.last_index()).rev() {
+            if self.log.term_at(index) != Some(self.term()) {
+                break;
+            }
+            let acks = 1 + self.match_index.values().filter(|&&m| m >= index).count();
+            if acks >= self.quorum() {
+                self.commit_index = index;
+                return true;
+            }
+        }
+        false
+    }
+
+    /// leader 处理读请求之前要等 applied 达到的 index；上任后的空操作还没有提交时为 None
+    pub fn read_index(&self) -> Option<u64> {
+        match self.role == Role::Leader && self.log.term_at(self.commit_index) == Some(self.term())
+        {
+            true => Some(self.commit_index),
+            false => None,
+        }
+    }
+
+    /// 下一条已经提交、还没有应用的日志
+    pub fn next_to_apply(&self) -> Option<(u64, RaftEntry)> {
+        let index = self.last_applied + 1;
+        match index <= self.commit_index {
+            true => self.log.entry(index).map(|e| (index, e.clone())),
+            false => None,
+        }
+    }
+
+    pub fn set_applied(&mut self, index: u64) {
+        self.last_applied = self.last_applied.max(index);
+    }
+}
+
+#[cfg(test)]
+mod raft_tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    const TIMEOUT: Duration = Duration::from_millis(100);
+
+    fn node(id: u64, peers: &[u64], dir: &TempDir) -> Raft {
+        let log = RaftLog::open(dir.path().join(id.to_string())).unwrap();
+        Raft::new(id, peers.to_vec(), TIMEOUT, log)
+    }
+
+    fn entry(term: u64, key: &str) -> RaftEntry {
+        RaftEntry {
+            term,
+            command: Some(CommandRequest::new_hset("t1", key, "v")),
+        }
+    }
+
+    /// node 发起选举，peers 都投票之后成为 leader
+    fn elect(node: &mut Raft, peers: &mut [&mut Raft]) {
+        let vote = node.tick(Instant::now() + TIMEOUT * 2).unwrap().unwrap();
+        for peer in peers.iter_mut() {
+            let (term, granted) = peer.handle_vote(&vote).unwrap();
+            node.handle_vote_response(peer.id, vote.term, term, granted)
+                .unwrap();
+        }
+        assert!(node.is_leader());
+    }
+
+    /// leader 给 peer 发送一次 AppendEntries
+    fn replicate(leader: &mut Raft, peer: &mut Raft) -> bool {
+        let req = leader.append_request(peer.id);
+        let (term, success, index) = peer.handle_append(&req).unwrap();
+        leader
+            .handle_append_response(peer.id, req.term, term, success, index)
+            .unwrap()
+    }
+
+    #[test]
+    fn raft_log_should_be_persisted() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut log = RaftLog::open(dir.path()).unwrap();
+        log.save_state(3, 2).unwrap();
+        log.append(&[entry(1, "k1"), entry(2, "k2"), entry(3, "k3")])
+            .unwrap();
+        log.truncate(3).unwrap();
+        log.append(&[entry(3, "k4")]).unwrap();
+
+        let log = RaftLog::open(dir.path()).unwrap();
+        assert_eq!((log.term(), log.voted_for()), (3, 2));
+        assert_eq!(log.last_index(), 3);
+        assert_eq!(log.term_at(2), Some(2));
+        assert_eq!(log.entry(3), Some(&entry(3, "k4")));
+        assert_eq!(log.term_at(4), None);
+    }
+
+    #[test]
+    fn raft_log_should_drop_broken_tail() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut log = RaftLog::open(dir.path()).unwrap();
+        log.append(&[entry(1, "k1")]).unwrap();
+        let mut file = OpenOptions::new()
+            .append(true)
+            .open(dir.path().join(LOG_FILE))
+            .unwrap();
+        file.write_all(&[42, 1, 2]).unwrap();
+
+        let mut log = RaftLog::open(dir.path()).unwrap();
+        assert_eq!(log.last_index(), 1);
+        log.append(&[entry(1, "k2")]).unwrap();
+        let log = RaftLog::open(dir.path()).unwrap();
+        assert_eq!(log.last_index(), 2);
+    }
+
+    #[test]
+    fn single_node_should_commit_immediately() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut n1 = node(1, &[], &dir);
+        assert!(n1.tick(Instant::now() + TIMEOUT * 2).unwrap().is_none());
+        assert!(n1.is_leader());
+        let (index, term) = n1
+            .propose(CommandRequest::new_hset("t1", "k1", "v1"))
+            .unwrap();
+        assert_eq!((index, term), (2, 1));
+        assert_eq!(n1.commit_index(), 2);
+        assert_eq!(n1.read_index(), Some(2));
+    }
+
+    #[test]
+    fn leader_should_commit_after_majority_replicated() {
+        let dir = tempfile::tempdir().unwrap();
+        let (mut n1, mut n2, mut n3) = (
+            node(1, &[2, 3], &dir),
+            node(2, &[1, 3], &dir),
+            node(3, &[1, 2], &dir),
+        );
+        elect(&mut n1, &mut [&mut n2, &mut n3]);
+        // 空操作还没有提交，不能处理读请求
+        assert_eq!(n1.read_index(), None);
+        let (index, _) = n1
+            .propose(CommandRequest::new_hset("t1", "k1", "v1"))
+            .unwrap();
+        assert_eq!(n1.commit_index(), 0);
+        assert!(n1.lags(2));
+
+        assert!(replicate(&mut n1, &mut n2));
+        assert_eq!(n1.commit_index(), index);
+        assert_eq!(n1.read_index(), Some(index));
+        assert!(!n1.lags(2));
+        // 下一次心跳把 commit index 带给 follower
+        assert_eq!(n2.commit_index(), 0);
+        replicate(&mut n1, &mut n2);
+        assert_eq!(n2.commit_index(), index);
+        assert_eq!(n2.leader(), Some(1));
+        assert_eq!(
+            n2.next_to_apply().unwrap().1.command,
+            None,
+            "the first entry is the no-op of the new leader"
+        );
+
+        // 其它节点拒绝 follower 的 propose
+        assert!(n2.propose(CommandRequest::new_hget("t1", "k1")).is_err());
+    }
+
+    #[test]
+    fn vote_should_be_rejected_for_stale_log() {
+        let dir = tempfile::tempdir().unwrap();
+        let (mut n1, mut n2, mut n3) = (
+            node(1, &[2, 3], &dir),
+            node(2, &[1, 3], &dir),
+            node(3, &[1, 2], &dir),
+        );
+        elect(&mut n1, &mut [&mut n2, &mut n3]);
+        n1.propose(CommandRequest::new_hset("t1", "k1", "v1"))
+            .unwrap();
+        replicate(&mut n1, &mut n2);
+
+        // n3 没有收到日志，不能当选
+        let vote = n3.tick(Instant::now() + TIMEOUT * 2).unwrap().unwrap();
+        let (term, granted) = n2.handle_vote(&vote).unwrap();
+        assert_eq!((term, granted), (2, false));
+        // 同一个任期只投一次票
+        let vote = n2.tick(Instant::now() + TIMEOUT * 2).unwrap().unwrap();
+        assert_eq!(n3.handle_vote(&vote).unwrap(), (3, true));
+        assert_eq!(n1.handle_vote(&vote).unwrap(), (3, true));
+        let other = RaftVote {
+            candidate: 1,
+            ..vote
+        };
+        assert_eq!(n3.handle_vote(&other).unwrap(), (3, false));
+        assert!(!n1.is_leader());
+    }
+
+    #[test]
+    fn follower_should_replace_conflicting_entries() {
+        let dir = tempfile::tempdir().unwrap();
+        let (mut n1, mut n2, mut n3) = (
+            node(1, &[2, 3], &dir),
+            node(2, &[1, 3], &dir),
+            node(3, &[1, 2], &dir),
+        );
+        elect(&mut n1, &mut [&mut n2, &mut n3]);
+        replicate(&mut n1, &mut n2);
+        replicate(&mut n1, &mut n3);
+        // n1 写下的日志没有复制出去
+        n1.propose(CommandRequest::new_hset("t1", "k1", "lost"))
+            .unwrap();
+        n1.propose(CommandRequest::new_hset("t1", "k2", "lost"))
+            .unwrap();
+
+        elect(&mut n2, &mut [&mut n3]);
+        n2.propose(CommandRequest::new_hset("t1", "k1", "v1"))
+            .unwrap();
+        // n1 上没有提交的日志被 n2 的日志替换
+        assert!(replicate(&mut n2, &mut n1));
+        replicate(&mut n2, &mut n1);
+        assert!(!n1.is_leader());
+        assert_eq!(n1.log.last_index(), n2.log.last_index());
+        assert_eq!(n1.log.entry(3), n2.log.entry(3));
+        assert_eq!(n1.commit_index(), n2.commit_index());
+        assert_eq!(n2.commit_index(), 3);
+    }
+}
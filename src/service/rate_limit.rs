@@ -0,0 +1,151 @@
+use std::{sync::Mutex, time::Instant};
+
+use dashmap::DashMap;
+
+use super::{acl, session::Session};
+use crate::{config::LimitsConfig, error::KvError, pb::abi::command_request::RequestData};
+
+/// 令牌桶：每秒补充 rate 个令牌，最多攒 rate 个，每个请求消耗一个
+#[derive(Debug)]
+pub struct TokenBucket {
+    rate: f64,
+    /// 剩余的令牌数和上次补充的时间
+    state: Mutex<(f64, Instant)>,
+}
+
+/// 按连接和按 table 限流；HTTP 和 gRPC 的每个请求都是一个新的 session，只按 table 限流
+#[derive(Debug, Default)]
+pub struct RateLimiter {
+    config: LimitsConfig,
+    tables: DashMap<String, TokenBucket>,
+}
+
+impl TokenBucket {
+    pub fn new(qps: u32) -> Self {
+        Self {
+            rate: qps as f64,
+            state: Mutex::new((qps as f64, Instant::now())),
+        }
+    }
+
+    /// 有令牌时消耗一个并返回 true
+    pub fn try_acquire(&self) -> bool {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let (tokens, last) = &mut *state;
+        let now = Instant::now();
+        *tokens = (*tokens + now.duration_since(*last).as_secs_f64() * self.rate).min(self.rate);
+        *last = now;
+        if *tokens < 1.0 {
+            return false;
+        }
+        *tokens -= 1.0;
+        true
+    }
+}
+
+impl RateLimiter {
+    pub fn new(config: LimitsConfig) -> Self {
+        Self {
+            config,
+            tables: DashMap::new(),
+        }
+    }
+
+    /// 先检查连接的配额，再检查命令访问的每个 table 的配额
+    pub fn acquire(&self, data: &RequestData, session: &Session) -> Result<(), KvError> {
+        if let Some(qps) = self.config.per_conn_qps {
+            if !session.rate_limit(qps).try_acquire() {
+                return Err(KvError::RateLimited(format!(
+                    "connection is limited to {} requests per second",
+                    qps
+                )));
+            }
+        }
+        let Some(qps) = self.config.per_table_qps else {
+            return Ok(());
+        };
+        for access in acl::required_access(data) {
+            let Some(table) = access.table else {
+                continue;
+            };
+            let allowed = self
+                .tables
+                .entry(table.to_string())
+                .or_insert_with(|| TokenBucket::new(qps))
+                .try_acquire();
+            if !allowed {
+                return Err(KvError::RateLimited(format!(
+                    "table {} is limited to {} requests per second",
+                    table, qps
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod rate_limit_tests {
+    use std::{thread, time::Duration};
+
+    use futures::StreamExt;
+
+    use super::*;
+    use crate::{
+        assert_res_error, pb::abi::CommandRequest, service_builder::ServiceBuilder, Service,
+    };
+
+    #[test]
+    fn token_bucket_should_refill_over_time() {
+        let bucket = TokenBucket::new(100);
+        for _ in 0..100 {
+            assert!(bucket.try_acquire());
+        }
+        assert!(!bucket.try_acquire());
+        thread::sleep(Duration::from_millis(50));
+        assert!(bucket.try_acquire());
+    }
+
+    #[test]
+    fn rate_limiter_should_limit_connections_and_tables() {
+        let limiter = RateLimiter::new(LimitsConfig {
+            per_conn_qps: Some(3),
+            per_table_qps: Some(2),
+        });
+        let cmd = |table: &str| CommandRequest::new_hget(table, "k1").request_data.unwrap();
+        let (s1, s2) = (Session::new(), Session::new());
+
+        assert!(limiter.acquire(&cmd("t1"), &s1).is_ok());
+        assert!(limiter.acquire(&cmd("t1"), &s2).is_ok());
+        // 两个连接共享 table 的配额
+        let res = limiter.acquire(&cmd("t1"), &s1);
+        assert!(matches!(res, Err(KvError::RateLimited(m)) if m.contains("table t1")));
+        // 被 table 拒绝的请求也消耗了连接的配额
+        assert!(limiter.acquire(&cmd("t2"), &s1).is_ok());
+        let res = limiter.acquire(&cmd("t2"), &s1);
+        assert!(matches!(res, Err(KvError::RateLimited(m)) if m.contains("connection")));
+        assert!(limiter.acquire(&cmd("t2"), &s2).is_ok());
+    }
+
+    #[tokio::test]
+    async fn over_limit_requests_should_be_rejected_with_429() {
+        let limits = LimitsConfig {
+            per_conn_qps: Some(2),
+            per_table_qps: None,
+        };
+        let service: Service = ServiceBuilder::default().limits(limits).finish();
+        let session = Session::new();
+        for _ in 0..2 {
+            let cmd = CommandRequest::new_hset("t1", "k1", "v1");
+            let res = service.execute_with(cmd, &session).next().await.unwrap();
+            assert_eq!(res.status, 200);
+        }
+        let cmd = CommandRequest::new_hset("t1", "k1", "v1");
+        let res = service.execute_with(cmd, &session).next().await.unwrap();
+        assert_res_error(&res, 429, "Too many requests");
+        // PING 不受限流影响
+        let cmd = CommandRequest::new_ping();
+        let res = service.execute_with(cmd, &session).next().await.unwrap();
+        assert_eq!(res.status, 200);
+    }
+}
@@ -0,0 +1,288 @@
+//! 自定义命令：嵌入 kv-sv 的程序按 type_url 注册 handler，客户端发送 Custom 命令调用。
+//! handler 只读取存储，要做的修改放在 Outcome 里返回，由 Service 和内置命令一样提交，
+//! 所以会产生 keyspace 通知和 MVCC revision，也会复制到 replica 和写入 CDC 的日志
+
+use std::{collections::HashMap, sync::Arc};
+
+use bytes::Bytes;
+use prost::Message;
+
+use super::{session::Session, transaction, Service};
+use crate::{
+    error::KvError,
+    pb::abi::{command_request::RequestData, Batch, CommandRequest, CommandResponse, Custom},
+    Storage,
+};
+
+/// handler 执行的结果
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Outcome {
+    /// 要提交的修改，只能是 HSET、HMSET、HDEL、HMDEL，和 batch 中一样原子地一起提交
+    pub writes: Vec<CommandRequest>,
+    /// 返回给客户端的结果；没有设置时返回 writes 中每个命令的结果
+    pub response: Option<CommandResponse>,
+}
+
+impl Outcome {
+    /// 不修改数据，直接返回 response
+    pub fn reply(response: impl Into<CommandResponse>) -> Self {
+        Self {
+            writes: Vec::new(),
+            response: Some(response.into()),
+        }
+    }
+
+    /// 提交 writes，返回它们的结果
+    pub fn write(writes: impl IntoIterator<Item = CommandRequest>) -> Self {
+        Self {
+            writes: writes.into_iter().collect(),
+            response: None,
+        }
+    }
+
+    /// 提交成功之后返回 response 而不是 writes 的结果
+    pub fn with_response(mut self, response: impl Into<CommandResponse>) -> Self {
+        self.response = Some(response.into());
+        self
+    }
+}
+
+/// 处理一种自定义命令
+pub trait CommandHandler<Store>: Send + Sync + 'static {
+    /// value 是客户端发送的参数
+    fn execute(&self, value: &Bytes, store: &Store, session: &Session) -> Result<Outcome, KvError>;
+}
+
+impl<Store, F> CommandHandler<Store> for F
+where
+    F: Fn(&Bytes, &Store, &Session) -> Result<Outcome, KvError> + Send + Sync + 'static,
+{
+    fn execute(&self, value: &Bytes, store: &Store, session: &Session) -> Result<Outcome, KvError> {
+        self(value, store, session)
+    }
+}
+
+/// 参数是 protobuf 消息 M 的 handler，先解码再交给 f
+pub struct MessageHandler<M, F> {
+    f: F,
+    _message: std::marker::PhantomData<fn(M)>,
+}
+
+impl<M, F> MessageHandler<M, F> {
+    pub fn new(f: F) -> Self {
+        Self {
+            f,
+            _message: Default::default(),
+        }
+    }
+}
+
+impl<Store, M, F> CommandHandler<Store> for MessageHandler<M, F>
+where
+    M: Message + Default + 'static,
+    F: Fn(M, &Store, &Session) -> Result<Outcome, KvError> + Send + Sync + 'static,
+{
+    fn execute(&self, value: &Bytes, store: &Store, session: &Session) -> Result<Outcome, KvError> {
+        let msg = M::decode(value.clone())
+            .map_err(|e| KvError::InvalidCommand(format!("invalid arguments: {}", e)))?;
+        (self.f)(msg, store, session)
+    }
+}
+
+/// type_url -> handler
+pub struct CommandRegistry<Store> {
+    handlers: HashMap<String, Arc<dyn CommandHandler<Store>>>,
+}
+
+impl<Store> Default for CommandRegistry<Store> {
+    fn default() -> Self {
+        Self {
+            handlers: HashMap::new(),
+        }
+    }
+}
+
+impl<Store> CommandRegistry<Store> {
+    /// 注册 handler，同一个 type_url 之前注册的 handler 会被替换
+    pub fn register(&mut self, type_url: impl Into<String>, handler: impl CommandHandler<Store>) {
+        self.handlers.insert(type_url.into(), Arc::new(handler));
+    }
+
+    pub fn get(&self, type_url: &str) -> Option<&Arc<dyn CommandHandler<Store>>> {
+        self.handlers.get(type_url)
+    }
+}
+
+impl<Store: Storage> Service<Store> {
+    /// 执行自定义命令，handler 返回的修改和内置命令一样经过 keyspace、replicator、统计和审计
+    pub(super) fn custom(&self, cmd: &Custom, session: &Session) -> CommandResponse {
+        let Some(handler) = self.commands.get(&cmd.type_url) else {
+            return KvError::InvalidCommand(format!("unknown command {}", cmd.type_url)).into();
+        };
+        match handler
+            .execute(&cmd.value, &self.store, session)
+            .and_then(|outcome| self.commit(outcome, session))
+        {
+            Ok(res) => res,
+            Err(e) => e.into(),
+        }
+    }
+
+    fn commit(&self, outcome: Outcome, session: &Session) -> Result<CommandResponse, KvError> {
+        if outcome.writes.is_empty() {
+            return Ok(outcome.response.unwrap_or_else(CommandResponse::ok));
+        }
+        let mut writes = Vec::with_capacity(outcome.writes.len());
+        for cmd in &outcome.writes {
+            match &cmd.request_data {
+                Some(data) if transaction::is_plain_write(data) => writes.push(data.clone()),
+                Some(data) => {
+                    return Err(KvError::InvalidCommand(format!(
+                        "{} is not supported in custom command",
+                        data.name()
+                    )))
+                }
+                None => return Err(KvError::InvalidCommand("Request has no data".into())),
+            }
+        }
+        // 包成 batch，keyspace 和 replicator 都按 batch 里的每个命令找到修改过的 key
+        let batch = RequestData::Batch(Batch {
+            commands: outcome.writes,
+        });
+        let results = self.keyspace.track(&batch, &self.store, || {
+            transaction::apply_writes(&self.store, &writes)
+        });
+        self.replicator.record(&batch, &self.store)?;
+        // Custom 命令本身看不出修改了哪些 key，按提交的 batch 记录审计日志
+        let batch_res = results.clone().into();
+        self.stats.record(&batch, &batch_res);
+        self.audit
+            .record(&batch, &batch_res, session, &self.broadcaster);
+        // 提交失败时每个命令都是同样的错误
+        if let Some(e) = results.iter().find(|res| res.status != 200) {
+            return Ok(e.clone());
+        }
+        Ok(outcome.response.unwrap_or_else(|| results.into()))
+    }
+}
+
+#[cfg(test)]
+mod registry_tests {
+    use futures::StreamExt;
+    use hyper::StatusCode;
+
+    use super::*;
+    use crate::{
+        audit::AuditLog,
+        config::AuditConfig,
+        memory::MemTable,
+        pb::abi::{value, Kvpair, Value},
+        service_builder::ServiceBuilder,
+        topic::Topic,
+    };
+
+    /// 把 pair 写入 table，返回写入之前 table 中的 key 数
+    fn put_and_count(pair: Kvpair, store: &MemTable, _: &Session) -> Result<Outcome, KvError> {
+        let count = store.get_all("custom")?.len() as i64;
+        let value = pair.value.unwrap_or_default();
+        Ok(
+            Outcome::write([CommandRequest::new_hset("custom", pair.key, value)])
+                .with_response(Value::from(count)),
+        )
+    }
+
+    #[tokio::test]
+    async fn custom_commands_should_be_dispatched_by_type_url() {
+        let service: Service = ServiceBuilder::default()
+            .command(
+                "example.Echo",
+                |value: &Bytes, _: &MemTable, _: &Session| {
+                    Ok(Outcome::reply(Value::from(value.clone())))
+                },
+            )
+            .command("example.Put", MessageHandler::new(put_and_count))
+            .finish();
+
+        let cmd = CommandRequest::new_custom("example.Echo", "hello");
+        let res = service.execute(cmd).next().await.unwrap();
+        assert_eq!(res.values, [Value::from(Bytes::from("hello"))]);
+
+        let pair = Kvpair::new("k1", "v1".into());
+        let cmd = CommandRequest::new_custom_message("example.Put", &pair);
+        let res = service.execute(cmd).next().await.unwrap();
+        assert_eq!(res.values, [Value::from(0)]);
+        assert_eq!(
+            service.store.get("custom", "k1").unwrap(),
+            Some("v1".into())
+        );
+
+        // 参数解码失败
+        let cmd = CommandRequest::new_custom("example.Put", vec![0xff]);
+        let res = service.execute(cmd).next().await.unwrap();
+        assert_eq!(res.status, StatusCode::BAD_REQUEST.as_u16() as u32);
+
+        let cmd = CommandRequest::new_custom("example.Unknown", "");
+        let res = service.execute(cmd).next().await.unwrap();
+        assert_eq!(res.status, StatusCode::BAD_REQUEST.as_u16() as u32);
+    }
+
+    #[tokio::test]
+    async fn custom_writes_should_be_committed_like_builtin_commands() {
+        let service: Service = ServiceBuilder::default()
+            .command("example.Put", MessageHandler::new(put_and_count))
+            .command("example.Incr", |_: &Bytes, _: &MemTable, _: &Session| {
+                Ok(Outcome::write([CommandRequest::new_hincrby(
+                    "custom", "n", 1,
+                )]))
+            })
+            .finish();
+        let mut watch = service.execute(CommandRequest::new_hwatch("custom", "k1", false));
+        watch.next().await.unwrap();
+
+        let pair = Kvpair::new("k1", "v1".into());
+        let cmd = CommandRequest::new_custom_message("example.Put", &pair);
+        service.execute(cmd).next().await.unwrap();
+        let res = watch.next().await.unwrap();
+        assert_eq!(res.events.len(), 1);
+        assert_eq!(res.events[0].new_value, Some("v1".into()));
+
+        // 只能返回可以原子提交的修改
+        let cmd = CommandRequest::new_custom("example.Incr", "");
+        let res = service.execute(cmd).next().await.unwrap();
+        assert_eq!(res.status, StatusCode::BAD_REQUEST.as_u16() as u32);
+        assert_eq!(service.store.get("custom", "n").unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn custom_writes_should_be_audited_and_idempotent() {
+        let config = AuditConfig {
+            topic: Some("audit".into()),
+            ..Default::default()
+        };
+        let service: Service = ServiceBuilder::default()
+            .audit(AuditLog::open(&config).unwrap())
+            .command("example.Put", MessageHandler::new(put_and_count))
+            .finish();
+        let mut rx = service.broadcaster.clone().subscript("audit");
+        rx.recv().await.unwrap();
+
+        let pair = Kvpair::new("k1", "v1".into());
+        let cmd =
+            CommandRequest::new_custom_message("example.Put", &pair).with_idempotency_key("a");
+        // 重试返回第一次的结果，不会再执行一次
+        for _ in 0..2 {
+            let res = service.execute(cmd.clone()).next().await.unwrap();
+            assert_eq!(res.values, [Value::from(0)]);
+        }
+
+        let res = rx.recv().await.unwrap();
+        let Some(value::Value::Json(entry)) = res.values[0].value.clone() else {
+            panic!("audit entry should be json");
+        };
+        let entry: serde_json::Value = serde_json::from_str(&entry).unwrap();
+        assert_eq!(entry["command"], "HSET");
+        assert_eq!(entry["table"], "custom");
+        assert_eq!(entry["key"], "k1");
+        assert!(rx.try_recv().is_err());
+    }
+}
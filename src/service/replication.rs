@@ -0,0 +1,457 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicU32, Ordering},
+        Arc, Mutex, Weak,
+    },
+    time::Duration,
+};
+
+use futures::{stream, StreamExt};
+use hyper::StatusCode;
+use tokio::{
+    sync::broadcast::{self, error::RecvError},
+    task::JoinHandle,
+    time,
+};
+use tracing::{info, warn};
+
+use super::{cdc::Cdc, service_builder::ServiceBuilder, topic_service::StreamingResponse, Service};
+use crate::{
+    config::ClientConfig,
+    error::KvError,
+    pb::abi::{command_request::RequestData, CommandRequest, CommandResponse, WalRecord},
+    start_client_with_config,
+    storage::{now_millis, wal::apply},
+    Storage,
+};
+
+/// 还没有推送给 replica 的修改的上限，超过之后 replica 需要重新全量同步
+const REPLICATION_CAPACITY: usize = 1024;
+
+/// 全量同步时，每个 CommandResponse 里最多放多少条记录
+const SYNC_BATCH_SIZE: usize = 1024;
+
+/// replica 和 primary 断开之后，重连之前等待的时间
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// 下一个 replica id
+static NEXT_ID: AtomicU32 = AtomicU32::new(1);
+
+/// primary 端：把修改过的 key 的最新状态推送给所有的 replica
+pub struct Replicator {
+    /// 持有锁时读取 key 的状态并发送，这样同一个 key 最后发出去的一定是最新的状态
+    sender: Mutex<broadcast::Sender<Arc<CommandResponse>>>,
+    /// replica 端：连接着 primary 并且已经完成了全量同步
+    synced: AtomicBool,
+    /// 配置之后，修改同时写入 CDC 的日志
+    cdc: Option<Arc<Cdc>>,
+}
+
+impl Default for Replicator {
+    fn default() -> Self {
+        Self::new(None)
+    }
+}
+
+impl Replicator {
+    /// 配置了 cdc 时，修改同时写入 CDC 的日志
+    pub fn new(cdc: Option<Arc<Cdc>>) -> Self {
+        let (sender, _) = broadcast::channel(REPLICATION_CAPACITY);
+        Self {
+            sender: Mutex::new(sender),
+            synced: AtomicBool::new(false),
+            cdc,
+        }
+    }
+
+    /// 命令执行之后调用，把它修改过的 key 推送给 replica 并写入 CDC 的日志；
+    /// 写入 CDC 的日志失败时返回错误，这时修改已经生效但是不会被投递
+    pub fn record(&self, cmd: &RequestData, store: &impl Storage) -> Result<(), KvError> {
+        // 锁里只读取修改并确定顺序，写入 CDC 的日志和 fsync 在释放锁之后等待
+        let appended = {
+            let sender = self.sender.lock().unwrap();
+            let replicas = sender.receiver_count() > 0;
+            if !replicas && self.cdc.is_none() {
+                return Ok(());
+            }
+            let records = match changes_of(cmd, store) {
+                Ok(records) if records.is_empty() => return Ok(()),
+                Ok(records) => records,
+                Err(e) => {
+                    warn!("Failed to read changes of {:?}: {:?}", cmd, e);
+                    // 读取失败时 replica 的数据就不完整了，让它重新全量同步
+                    if replicas {
+                        let _ = sender.send(Arc::new(e.into()));
+                    }
+                    return Ok(());
+                }
+            };
+            let appended = self.cdc.as_ref().map(|cdc| cdc.append(&records));
+            if replicas {
+                let _ = sender.send(Arc::new(records.into()));
+            }
+            appended
+        };
+        match appended {
+            Some(appended) => appended.wait(),
+            None => Ok(()),
+        }
+    }
+
+    /// 当前连接的 replica 数量
+    pub fn replicas(&self) -> usize {
+        self.sender.lock().unwrap().receiver_count()
+    }
+
+    /// replica 是否已经追上了 primary：全量同步完成之后只差还在路上的修改
+    pub fn synced(&self) -> bool {
+        self.synced.load(Ordering::Relaxed)
+    }
+
+    /// 处理 REPLICATE：先返回 replica id，然后推送全量数据和一个没有记录的结束标记，之后持续推送修改
+    pub fn replicate(&self, store: &impl Storage) -> StreamingResponse {
+        // 订阅和导出在同一把锁下完成，导出之后的修改一定会推送给这个 replica
+        let (rx, records) = {
+            let sender = self.sender.lock().unwrap();
+            (sender.subscribe(), store.snapshot())
+        };
+        let records = match records {
+            Ok(records) => records,
+            Err(e) => return Box::pin(stream::once(async move { Arc::new(e.into()) })),
+        };
+
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        info!(
+            "Replica {} starts full sync with {} records",
+            id,
+            records.len()
+        );
+        let head = stream::once(async move { Arc::new((id as i64).into()) });
+        let full: Vec<Arc<CommandResponse>> = records
+            .chunks(SYNC_BATCH_SIZE)
+            .map(|chunk| Arc::new(chunk.to_vec().into()))
+            .chain([Arc::new(CommandResponse::ok())])
+            .collect();
+        let incremental = stream::unfold(Some(rx), move |rx| async move {
+            let mut rx = rx?;
+            match rx.recv().await {
+                Ok(res) => Some((res, Some(rx))),
+                Err(RecvError::Lagged(n)) => {
+                    warn!("Replica {} lagged {} updates behind", id, n);
+                    let e = KvError::Internal(format!("replica lagged {} updates behind", n));
+                    Some((Arc::new(e.into()), None))
+                }
+                Err(RecvError::Closed) => None,
+            }
+        });
+        Box::pin(head.chain(stream::iter(full)).chain(incremental))
+    }
+}
+
+impl<Store: Storage> Service<Store> {
+    /// 启动后台任务，从 primary 同步数据到本地 store；断开之后会重连并重新全量同步。
+    /// Service 全部 drop 之后任务自动退出
+    pub fn spawn_replication_task(&self, primary: ClientConfig) -> JoinHandle<()> {
+        let inner = Arc::downgrade(&self.inner);
+        let replicator = self.replicator.clone();
+        tokio::spawn(async move {
+            loop {
+                match sync_from_primary(&primary, &inner, &replicator).await {
+                    Ok(()) => info!("Replication stream from {} ended", primary.general.addr),
+                    Err(e) => warn!("Replication from {} failed: {:?}", primary.general.addr, e),
+                }
+                replicator.synced.store(false, Ordering::Relaxed);
+                if inner.strong_count() == 0 {
+                    break;
+                }
+                time::sleep(RECONNECT_INTERVAL).await;
+            }
+        })
+    }
+}
+
+/// 连接 primary，清空本地数据之后应用 primary 推送过来的记录，直到连接断开
+async fn sync_from_primary<Store: Storage>(
+    primary: &ClientConfig,
+    inner: &Weak<ServiceBuilder<Store>>,
+    replicator: &Replicator,
+) -> Result<(), KvError> {
+    let mut ctrl = start_client_with_config(primary.clone())
+        .await
+        .map_err(|e| KvError::Internal(format!("Failed to connect to primary: {}", e)))?;
+    let stream = ctrl.open_stream().await?;
+    let mut result = stream
+        .execute_streaming(&CommandRequest::new_replicate())
+        .await?;
+    info!(
+        "Replica {} connected to {}",
+        result.id, primary.general.addr
+    );
+
+    match inner.upgrade() {
+        Some(inner) => clear(&inner.store)?,
+        None => return Ok(()),
+    }
+    while let Some(res) = result.next().await {
+        let res = res?;
+        if res.status != StatusCode::OK.as_u16() as u32 {
+            return Err(KvError::Internal(res.message));
+        }
+        let Some(inner) = inner.upgrade() else {
+            return Ok(());
+        };
+        if res.records.is_empty() && !replicator.synced() {
+            info!("Replica finished full sync from {}", primary.general.addr);
+            replicator.synced.store(true, Ordering::Relaxed);
+        }
+        for record in res.records {
+            apply(&inner.store, record)?;
+        }
+    }
+    Ok(())
+}
+
+/// 删除 store 中所有的 table
+fn clear(store: &impl Storage) -> Result<(), KvError> {
+    for table in store.tables()? {
+        store.drop_table(table)?;
+    }
+    Ok(())
+}
+
+/// 命令执行之后它修改过的 key 和 sorted set 的状态，以及它建立的索引和清空、删除的 table
+pub(super) fn changes_of(
+    cmd: &RequestData,
+    store: &impl Storage,
+) -> Result<Vec<WalRecord>, KvError> {
+    let keys = written_keys(cmd);
+    let zsets = written_members(cmd);
+    let mut records = cleared_tables(cmd);
+    records.extend(created_indexes(cmd));
+    records.extend(records_of(store, &keys)?);
+    records.extend(zset_records_of(store, &zsets)?);
+    Ok(records)
+}
+
+/// 命令会修改哪些 key，<table, key>
+pub(super) fn written_keys(cmd: &RequestData) -> Vec<(&str, &[u8])> {
+    match cmd {
+        RequestData::Hset(v) => v
+            .pair
+            .iter()
+            .map(|pair| (v.table.as_str(), &pair.key[..]))
+            .collect(),
+        RequestData::Hmset(v) => v
+            .pairs
+            .iter()
+            .map(|pair| (v.table.as_str(), &pair.key[..]))
+            .collect(),
+        RequestData::Hcas(v) => vec![(&v.table, &v.key[..])],
+        RequestData::Lock(v) => vec![(&v.table, v.name.as_bytes())],
+        RequestData::Unlock(v) => vec![(&v.table, v.name.as_bytes())],
+        RequestData::Hincrby(v) => vec![(&v.table, &v.key[..])],
+        RequestData::Happend(v) => vec![(&v.table, &v.key[..])],
+        RequestData::Hsetbit(v) => vec![(&v.table, &v.key[..])],
+        RequestData::Hpfadd(v) => vec![(&v.table, &v.key[..])],
+        // 脚本可能修改声明的任何一个 key
+        RequestData::Eval(v) => v.keys.iter().map(|k| (v.table.as_str(), &k[..])).collect(),
+        RequestData::Evalsha(v) => v.keys.iter().map(|k| (v.table.as_str(), &k[..])).collect(),
+        RequestData::Fcall(v) => v.keys.iter().map(|k| (v.table.as_str(), &k[..])).collect(),
+        RequestData::Hdel(v) => vec![(&v.table, &v.key[..])],
+        RequestData::Hmdel(v) => v
+            .keys
+            .iter()
+            .map(|key| (v.table.as_str(), &key[..]))
+            .collect(),
+        RequestData::Hexpire(v) => vec![(&v.table, &v.key[..])],
+        RequestData::Lpush(v) => vec![(&v.table, &v.key[..])],
+        RequestData::Sadd(v) => vec![(&v.table, &v.key[..])],
+        RequestData::MapSet(v) => vec![(&v.table, &v.key[..])],
+        RequestData::Hjsonset(v) => vec![(&v.table, &v.key[..])],
+        RequestData::Batch(v) => v
+            .commands
+            .iter()
+            .filter_map(|cmd| cmd.request_data.as_ref())
+            .flat_map(written_keys)
+            .collect(),
+        _ => vec![],
+    }
+}
+
+/// 命令建立的索引，建立索引是幂等的，直接把定义推送给 replica
+fn created_indexes(cmd: &RequestData) -> Vec<WalRecord> {
+    match cmd {
+        RequestData::CreateIndex(v) => v
+            .index
+            .iter()
+            .map(|spec| WalRecord::new_create_index(&v.table, spec.clone()))
+            .collect(),
+        RequestData::Batch(v) => v
+            .commands
+            .iter()
+            .filter_map(|cmd| cmd.request_data.as_ref())
+            .flat_map(created_indexes)
+            .collect(),
+        _ => vec![],
+    }
+}
+
+/// 命令清空或者删除的 table，直接把操作推送给 replica
+fn cleared_tables(cmd: &RequestData) -> Vec<WalRecord> {
+    match cmd {
+        RequestData::Ttruncate(v) => vec![WalRecord::new_truncate_table(&v.table)],
+        RequestData::Tdrop(v) => vec![WalRecord::new_drop_table(&v.table)],
+        _ => vec![],
+    }
+}
+
+/// sorted set 中被修改的 member，<table, key, members>
+type ZsetMembers<'a> = (&'a str, &'a [u8], Vec<&'a [u8]>);
+
+/// 命令修改过哪些 sorted set 的哪些 member
+fn written_members(cmd: &RequestData) -> Vec<ZsetMembers<'_>> {
+    match cmd {
+        RequestData::Zadd(v) => {
+            let members = v.members.iter().map(|m| &m.member[..]).collect();
+            vec![(&v.table, &v.key[..], members)]
+        }
+        RequestData::Batch(v) => v
+            .commands
+            .iter()
+            .filter_map(|cmd| cmd.request_data.as_ref())
+            .flat_map(written_members)
+            .collect(),
+        _ => vec![],
+    }
+}
+
+/// member 当前的 score，作为 ZADD 记录推送；sorted set 只会增加 member，不需要推送删除
+fn zset_records_of(
+    store: &impl Storage,
+    zsets: &[ZsetMembers<'_>],
+) -> Result<Vec<WalRecord>, KvError> {
+    let mut records = Vec::new();
+    for (table, key, members) in zsets {
+        let current = store
+            .zrange(*table, key, 0, -1)?
+            .into_iter()
+            .filter(|m| members.contains(&&m.member[..]))
+            .collect();
+        records.push(WalRecord::new_zadd(*table, key, current));
+    }
+    Ok(records)
+}
+
+/// key 当前的状态：存在时是 SET（有过期时间时再加上 EXPIRE），不存在时是 DEL
+pub(super) fn records_of(
+    store: &impl Storage,
+    keys: &[(&str, &[u8])],
+) -> Result<Vec<WalRecord>, KvError> {
+    let mut records = Vec::new();
+    for &(table, key) in keys {
+        match store.get(table, key)? {
+            Some(value) => {
+                records.push(WalRecord::new_set(table, key, value));
+                if let Some(ttl) = store.ttl(table, key)? {
+                    let deadline = now_millis() + ttl.as_millis() as u64;
+                    records.push(WalRecord::new_expire(table, key, deadline));
+                }
+            }
+            None => records.push(WalRecord::new_del(table, key)),
+        }
+    }
+    Ok(records)
+}
+
+#[cfg(test)]
+mod replication_tests {
+    use std::time::Duration;
+
+    use futures::StreamExt;
+
+    use super::*;
+    use crate::{memory::MemTable, service_builder::ServiceBuilder, session::Session};
+
+    #[tokio::test]
+    async fn replicate_should_send_full_sync_then_updates() {
+        let service: Service = ServiceBuilder::default().finish();
+        let execute = |cmd: CommandRequest| {
+            let service = service.clone();
+            async move { service.execute(cmd).next().await.unwrap() }
+        };
+        execute(CommandRequest::new_hset("t1", "k1", "v1")).await;
+        execute(CommandRequest::new_hset_ex("t1", "k2", "v2", 100)).await;
+
+        let mut stream = service.execute(CommandRequest::new_replicate());
+        let id: i64 = stream.next().await.unwrap().as_ref().try_into().unwrap();
+        assert!(id > 0);
+
+        let replica = MemTable::new();
+        let full = stream.next().await.unwrap();
+        assert_eq!(full.records.len(), 3);
+        for record in full.records.clone() {
+            apply(&replica, record).unwrap();
+        }
+        assert_eq!(replica.get("t1", "k1").unwrap(), Some("v1".into()));
+        assert!(replica.ttl("t1", "k2").unwrap().unwrap() > Duration::from_secs(90));
+        // 全量同步结束的标记
+        let done = stream.next().await.unwrap();
+        assert_eq!((done.status, done.records.len()), (200, 0));
+
+        // 之后的修改会推送过来，读命令不会
+        execute(CommandRequest::new_hget("t1", "k1")).await;
+        execute(CommandRequest::new_batch([
+            CommandRequest::new_hset("t1", "k3", "v3"),
+            CommandRequest::new_hdel("t1", "k1"),
+        ]))
+        .await;
+        let update = stream.next().await.unwrap();
+        assert_eq!(update.records.len(), 2);
+        for record in update.records.clone() {
+            apply(&replica, record).unwrap();
+        }
+        assert_eq!(replica.get("t1", "k1").unwrap(), None);
+        assert_eq!(replica.get("t1", "k3").unwrap(), Some("v3".into()));
+
+        // ZADD 推送的是 member 最新的 score
+        execute(CommandRequest::new_zadd(
+            "t1",
+            "z1",
+            [("a", 1.0), ("b", 2.0)],
+        ))
+        .await;
+        execute(CommandRequest::new_zadd("t1", "z1", [("a", 3.0)])).await;
+        for _ in 0..2 {
+            let update = stream.next().await.unwrap();
+            for record in update.records.clone() {
+                apply(&replica, record).unwrap();
+            }
+        }
+        let members = replica.zrange("t1", "z1", 0, -1).unwrap();
+        assert_eq!(members, vec![("b", 2.0).into(), ("a", 3.0).into()]);
+    }
+
+    #[tokio::test]
+    async fn read_only_service_should_reject_writes() {
+        let service: Service = ServiceBuilder::default().read_only(true).finish();
+        let session = Session::new();
+
+        let cmd = CommandRequest::new_hset("t1", "k1", "v1");
+        let res = service.execute_with(cmd, &session).next().await.unwrap();
+        assert_eq!(res.status, 403);
+
+        let cmd = CommandRequest::new_hget("t1", "k1");
+        let res = service.execute_with(cmd, &session).next().await.unwrap();
+        assert_eq!(res.status, 404);
+    }
+
+    #[test]
+    fn clear_should_remove_all_keys() {
+        let store = MemTable::new();
+        store.set("t1", "k1", "v1".into()).unwrap();
+        store.set("t2", "k1", "v1".into()).unwrap();
+        clear(&store).unwrap();
+        assert!(store.get_all("t1").unwrap().is_empty());
+        assert!(store.get_all("t2").unwrap().is_empty());
+    }
+}
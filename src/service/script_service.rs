@@ -0,0 +1,378 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    sync::{Arc, Mutex, RwLock},
+};
+
+use bytes::Bytes;
+use rhai::{Array, Blob, Dynamic, Engine, EvalAltResult, ImmutableString, Map, AST};
+use ring::digest;
+use tracing::{debug, instrument};
+
+use super::{command_service::CommandService, transaction::version_of};
+use crate::{
+    error::KvError,
+    pb::abi::{
+        value, CommandResponse, Eval, Evalsha, Value, ValueList, ValueMap, ValueSet, WalRecord,
+    },
+    storage::s3::hex,
+    Storage,
+};
+
+/// 脚本读过的 key 被并发修改时，最多重新执行的次数
+const MAX_RETRIES: usize = 16;
+/// 缓存的脚本数超过这个值时清空缓存，客户端收到 NOSCRIPT 之后用 EVAL 重新提交
+const MAX_CACHED_SCRIPTS: usize = 1024;
+/// 一次执行最多的操作数，防止死循环占住工作线程
+const MAX_OPERATIONS: u64 = 1_000_000;
+/// 脚本中字符串、数组和 map 的大小上限
+const MAX_STRING_SIZE: usize = 16 * 1024 * 1024;
+const MAX_COLLECTION_SIZE: usize = 1024 * 1024;
+
+/// 编译过的脚本，<脚本的 SHA1, AST>；所有 Service 共享，相同的脚本 SHA1 相同
+static SCRIPTS: RwLock<BTreeMap<String, Arc<AST>>> = RwLock::new(BTreeMap::new());
+
+/// 脚本的 SHA1，EVALSHA 用它来引用执行过的脚本
+pub fn script_sha(script: &str) -> String {
+    hex(digest::digest(&digest::SHA1_FOR_LEGACY_USE_ONLY, script.as_bytes()).as_ref())
+}
+
+impl CommandService for Eval {
+    #[instrument(name = "storage_eval", skip_all, fields(table = %self.table))]
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        let keys = match Keys::new(&self.keys) {
+            Ok(keys) => keys,
+            Err(e) => return e.into(),
+        };
+        let res = run(store, &self.table, &keys, &self.args, |engine| {
+            let sha = script_sha(&self.script);
+            if let Some(ast) = cached(&sha) {
+                return Ok(ast);
+            }
+            let ast = Arc::new(engine.compile(&self.script).map_err(|e| {
+                KvError::InvalidCommand(format!("failed to compile script: {}", e))
+            })?);
+            let mut scripts = SCRIPTS.write().unwrap_or_else(|e| e.into_inner());
+            if scripts.len() >= MAX_CACHED_SCRIPTS {
+                scripts.clear();
+            }
+            scripts.insert(sha, ast.clone());
+            Ok(ast)
+        });
+        match res {
+            Ok(value) => value.into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Evalsha {
+    #[instrument(name = "storage_evalsha", skip_all, fields(table = %self.table))]
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        let keys = match Keys::new(&self.keys) {
+            Ok(keys) => keys,
+            Err(e) => return e.into(),
+        };
+        let res = run(store, &self.table, &keys, &self.args, |_| {
+            cached(&self.sha.to_ascii_lowercase()).ok_or_else(|| {
+                KvError::NotFound(format!("NOSCRIPT no script matches {}, use EVAL", self.sha))
+            })
+        });
+        match res {
+            Ok(value) => value.into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+fn cached(sha: &str) -> Option<Arc<AST>> {
+    SCRIPTS
+        .read()
+        .unwrap_or_else(|e| e.into_inner())
+        .get(sha)
+        .cloned()
+}
+
+/// 脚本声明的 key；脚本中用字符串表示 key，所以 key 必须是 UTF-8
+struct Keys(BTreeMap<String, Bytes>);
+
+impl Keys {
+    fn new(keys: &[Bytes]) -> Result<Self, KvError> {
+        let keys = keys.iter().map(|k| match std::str::from_utf8(k) {
+            Ok(name) => Ok((name.to_owned(), k.clone())),
+            Err(_) => Err(KvError::InvalidCommand(format!(
+                "script key {:?} is not valid UTF-8",
+                k
+            ))),
+        });
+        Ok(Self(keys.collect::<Result<_, _>>()?))
+    }
+}
+
+/// 脚本执行过程中看到的数据：开始时读入声明的 key，脚本的修改只记在这里，执行成功之后一起写回
+#[derive(Default)]
+struct Overlay {
+    values: BTreeMap<String, Option<Value>>,
+    written: BTreeSet<String>,
+}
+
+/// 在 store 上原子地执行脚本：先记下 key 的版本号并读入数据，执行完之后用 Storage::apply_batch 写回修改；
+/// 执行期间 key 被别人修改了则重新执行，所以脚本除了读写 key 之外不应该有别的副作用
+fn run(
+    store: &impl Storage,
+    table: &str,
+    keys: &Keys,
+    args: &[Value],
+    compile: impl FnOnce(&Engine) -> Result<Arc<AST>, KvError>,
+) -> Result<Value, KvError> {
+    let overlay = Arc::new(Mutex::new(Overlay::default()));
+    let engine = engine(overlay.clone());
+    let ast = compile(&engine)?;
+    for _ in 0..MAX_RETRIES {
+        // 版本号要在读取数据之前获取，这样读到的数据一定不比版本号旧
+        let mut versions = Vec::with_capacity(keys.0.len());
+        let mut values = BTreeMap::new();
+        for (name, key) in &keys.0 {
+            versions.push(version_of(store, table, key)?);
+            values.insert(name.clone(), store.get(table, key)?);
+        }
+        *overlay.lock().unwrap() = Overlay {
+            values,
+            written: BTreeSet::new(),
+        };
+
+        let mut scope = rhai::Scope::new();
+        let names: Array = keys.0.keys().map(|k| Dynamic::from(k.clone())).collect();
+        let argv: Array = args.iter().map(|v| to_dynamic(v.clone())).collect();
+        scope.push_constant("KEYS", names);
+        scope.push_constant("ARGV", argv);
+        let result = engine
+            .eval_ast_with_scope::<Dynamic>(&mut scope, &ast)
+            .map_err(|e| KvError::InvalidCommand(format!("script error: {}", e)))?;
+        let result = from_dynamic(result)
+            .map_err(|e| KvError::InvalidCommand(format!("invalid script result: {}", e)))?;
+
+        let overlay = overlay.lock().unwrap();
+        let records = overlay
+            .written
+            .iter()
+            .map(|name| {
+                let key = &keys.0[name];
+                match overlay.values.get(name).cloned().flatten() {
+                    Some(value) => WalRecord::new_set(table, key, value),
+                    None => WalRecord::new_del(table, key),
+                }
+            })
+            .collect();
+        if store.apply_batch(records, &versions)? {
+            return Ok(result);
+        }
+    }
+    Err(KvError::Conflict(
+        "too many concurrent modifications, script is not executed".into(),
+    ))
+}
+
+/// 脚本只能使用标准库和下面注册的 get/set/del，不能访问文件和网络，执行的操作数和内存都有上限
+fn engine(overlay: Arc<Mutex<Overlay>>) -> Engine {
+    let mut engine = Engine::new();
+    engine
+        .set_max_operations(MAX_OPERATIONS)
+        .set_max_call_levels(32)
+        .set_max_expr_depths(64, 32)
+        .set_max_string_size(MAX_STRING_SIZE)
+        .set_max_array_size(MAX_COLLECTION_SIZE)
+        .set_max_map_size(MAX_COLLECTION_SIZE)
+        .on_print(|s| debug!("script: {}", s))
+        .on_debug(|s, _, _| debug!("script: {}", s));
+
+    let data = overlay.clone();
+    engine.register_fn("get", move |key: &str| -> Result<Dynamic, _> {
+        let data = data.lock().unwrap();
+        match data.values.get(key) {
+            Some(value) => Ok(value.clone().map(to_dynamic).unwrap_or(Dynamic::UNIT)),
+            None => Err(undeclared(key)),
+        }
+    });
+    let data = overlay.clone();
+    engine.register_fn(
+        "set",
+        move |key: &str, value: Dynamic| -> Result<(), Box<EvalAltResult>> {
+            let value = from_dynamic(value)?;
+            let mut data = data.lock().unwrap();
+            match data.values.get_mut(key) {
+                Some(current) => *current = Some(value),
+                None => return Err(undeclared(key)),
+            }
+            data.written.insert(key.to_owned());
+            Ok(())
+        },
+    );
+    engine.register_fn("del", move |key: &str| -> Result<bool, _> {
+        let mut data = overlay.lock().unwrap();
+        let existed = match data.values.get_mut(key) {
+            Some(current) => current.take().is_some(),
+            None => return Err(undeclared(key)),
+        };
+        data.written.insert(key.to_owned());
+        Ok(existed)
+    });
+    engine
+}
+
+/// 和 redis 一样，脚本只能访问 KEYS 中声明的 key，这样执行前就知道脚本会修改哪些 key
+fn undeclared(key: &str) -> Box<EvalAltResult> {
+    format!("key {} is not declared in KEYS", key).into()
+}
+
+fn to_dynamic(value: Value) -> Dynamic {
+    match value.value {
+        None => Dynamic::UNIT,
+        Some(value::Value::String(s)) | Some(value::Value::Json(s)) => s.into(),
+        Some(value::Value::Binary(b)) => Dynamic::from_blob(b.to_vec()),
+        Some(value::Value::Integer(i)) => i.into(),
+        Some(value::Value::Float(f)) => f.into(),
+        Some(value::Value::Bool(b)) => b.into(),
+        Some(value::Value::List(ValueList { values: v }))
+        | Some(value::Value::Set(ValueSet { members: v })) => {
+            Dynamic::from_array(v.into_iter().map(to_dynamic).collect())
+        }
+        Some(value::Value::Map(map)) => Dynamic::from_map(
+            map.entries
+                .into_iter()
+                .map(|(k, v)| (k.into(), to_dynamic(v)))
+                .collect(),
+        ),
+    }
+}
+
+/// 数组转成 list，对象转成 map；函数指针这类没有对应 Value 的类型返回错误
+fn from_dynamic(value: Dynamic) -> Result<Value, Box<EvalAltResult>> {
+    if value.is_unit() {
+        return Ok(Value::default());
+    }
+    let value = match value.type_name() {
+        "string" => Value::from(value.cast::<ImmutableString>().to_string()),
+        "blob" => Bytes::from(value.cast::<Blob>()).into(),
+        "i64" => value.cast::<i64>().into(),
+        "f64" => value.cast::<f64>().into(),
+        "bool" => value.cast::<bool>().into(),
+        "char" => Value::from(value.cast::<char>().to_string()),
+        "array" => ValueList {
+            values: value
+                .cast::<Array>()
+                .into_iter()
+                .map(from_dynamic)
+                .collect::<Result<_, _>>()?,
+        }
+        .into(),
+        "map" => ValueMap {
+            entries: value
+                .cast::<Map>()
+                .into_iter()
+                .map(|(k, v)| Ok((k.to_string(), from_dynamic(v)?)))
+                .collect::<Result<_, Box<EvalAltResult>>>()?,
+        }
+        .into(),
+        name => return Err(format!("{} can not be stored", name).into()),
+    };
+    Ok(value)
+}
+
+#[cfg(test)]
+mod script_tests {
+    use super::*;
+    use crate::memory::MemTable;
+
+    fn eval(store: &MemTable, script: &str, keys: &[&str], args: Vec<Value>) -> CommandResponse {
+        Eval {
+            table: "t1".into(),
+            script: script.into(),
+            keys: keys
+                .iter()
+                .map(|k| Bytes::copy_from_slice(k.as_bytes()))
+                .collect(),
+            args,
+        }
+        .execute(store)
+    }
+
+    #[test]
+    fn eval_should_reject_non_utf8_keys() {
+        let store = MemTable::new();
+        let keys = vec![Bytes::from_static(b"k\xff"), Bytes::from_static(b"k\xfe")];
+        let res = Eval {
+            table: "t1".into(),
+            script: r#"set(KEYS[0], 1); set(KEYS[1], 2)"#.into(),
+            keys,
+            args: vec![],
+        }
+        .execute(&store);
+        assert_eq!(res.status, 400);
+        assert!(res.message.contains("UTF-8"), "{}", res.message);
+        assert!(store.get_all("t1").unwrap().is_empty());
+    }
+
+    #[test]
+    fn eval_should_read_and_write_declared_keys() {
+        let store = MemTable::new();
+        store.set("t1", "balance", 100.into()).unwrap();
+        let script = r#"
+            let balance = get("balance") - ARGV[0];
+            if balance < 0 { throw "insufficient balance"; }
+            set("balance", balance);
+            set("log", `paid ${ARGV[0]}`);
+            balance
+        "#;
+        let res = eval(&store, script, &["balance", "log"], vec![30.into()]);
+        assert_eq!(res.values, vec![70.into()]);
+        assert_eq!(store.get("t1", "balance").unwrap(), Some(70.into()));
+        assert_eq!(store.get("t1", "log").unwrap(), Some("paid 30".into()));
+
+        // 出错时不修改任何数据
+        let res = eval(&store, script, &["balance", "log"], vec![100.into()]);
+        assert_eq!(res.status, 400);
+        assert_eq!(store.get("t1", "balance").unwrap(), Some(70.into()));
+
+        let res = eval(&store, r#"del("log")"#, &["log"], vec![]);
+        assert_eq!(res.values, vec![true.into()]);
+        assert_eq!(store.get("t1", "log").unwrap(), None);
+    }
+
+    #[test]
+    fn eval_should_be_sandboxed() {
+        let store = MemTable::new();
+        let res = eval(&store, r#"set("other", 1)"#, &["k1"], vec![]);
+        assert_eq!(res.status, 400);
+        assert_eq!(store.get("t1", "other").unwrap(), None);
+
+        let res = eval(&store, "loop {}", &[], vec![]);
+        assert_eq!(res.status, 400);
+        let res = eval(&store, "let x = ", &[], vec![]);
+        assert_eq!(res.status, 400);
+    }
+
+    #[test]
+    fn evalsha_should_use_cached_script() {
+        let store = MemTable::new();
+        let script = "[KEYS.len(), ARGV[0] + 1]";
+        let sha = script_sha(script);
+        let evalsha = || {
+            Evalsha {
+                table: "t1".into(),
+                sha: sha.clone(),
+                keys: vec![],
+                args: vec![1.into()],
+            }
+            .execute(&store)
+        };
+        assert_eq!(evalsha().status, 404);
+
+        let res = eval(&store, script, &[], vec![1.into()]);
+        let expected: Value = ValueList {
+            values: vec![0.into(), 2.into()],
+        }
+        .into();
+        assert_eq!(res.values, vec![expected.clone()]);
+        assert_eq!(evalsha().values, vec![expected]);
+    }
+}
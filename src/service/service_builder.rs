@@ -1,8 +1,34 @@
-use std::sync::Arc;
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc, RwLock,
+};
 
 use crate::{
+    acl::Acl,
+    audit::AuditLog,
+    cdc::Cdc,
+    clients::ClientRegistry,
+    config::{
+        AclConfig, AuthConfig, CompressionConfig, IdempotencyConfig, LimitsConfig, MvccConfig,
+        NotificationConfig, SecurityConfig, SlowlogConfig,
+    },
+    conn_limit::ConnectionLimiter,
+    error::KvError,
+    hooks::{Hook, Hooks},
+    idempotency::IdempotencyCache,
+    in_flight::InFlightLimiter,
+    keyspace::Keyspace,
     memory::MemTable,
+    middleware::Middleware,
     pb::abi::{CommandRequest, CommandResponse},
+    plugin::Plugins,
+    proxy::Proxy,
+    rate_limit::RateLimiter,
+    registry::{CommandHandler, CommandRegistry},
+    replication::Replicator,
+    slowlog::SlowLog,
+    tenant::Tenants,
+    topic::BroadCaster,
     Service, Storage,
 };
 
@@ -16,6 +42,48 @@ pub struct ServiceBuilder<Store> {
     pub on_before_send: Vec<fn(&mut CommandResponse)>,
     /// 在服务器发送完 CommandResponse 后触发
     pub on_after_send: Vec<fn()>,
+    /// 命令执行前后调用的中间件，按注册的顺序排列
+    pub middlewares: Vec<Arc<dyn Middleware>>,
+    /// 自定义命令的 handler
+    pub commands: CommandRegistry<Store>,
+    /// 配置之后，连接需要先 AUTH 才能执行其它命令；可以在运行时重新加载
+    pub auth: RwLock<Option<AuthConfig>>,
+    /// 配置之后，命令在执行前需要通过权限检查；可以在运行时重新加载
+    pub acl: RwLock<Option<Acl>>,
+    /// 用户所属的租户，租户的用户访问的 table 加上租户的前缀；可以在运行时重新加载
+    pub tenants: RwLock<Tenants>,
+    /// 只读时拒绝所有修改数据的命令；可以在运行时修改
+    pub read_only: AtomicBool,
+    /// replica 只处理读请求，不能关闭只读
+    pub replica: bool,
+    /// key 被修改时自动发布的 pub/sub 通知
+    pub notifications: NotificationConfig,
+    /// 配置之后，修改数据的命令生成 revision，保留修改的历史
+    pub mvcc: Option<MvccConfig>,
+    /// 按连接和按 table 的限流
+    pub limiter: RateLimiter,
+    /// HELLO 可以协商的压缩算法
+    pub compression: CompressionConfig,
+    /// 所有 listener 共享的连接计数，INFO 中显示当前的连接数
+    pub connections: ConnectionLimiter,
+    /// 所有 listener 上的客户端连接，CLIENT LIST 和 CLIENT KILL 使用
+    pub clients: ClientRegistry,
+    /// 同时执行的命令数的上限，所有 listener 共享
+    pub in_flight: InFlightLimiter,
+    /// 执行时间超过阈值的命令
+    pub slowlog: SlowLog,
+    /// 带幂等 key 的修改命令的结果
+    pub idempotency: IdempotencyCache,
+    /// 修改数据的审计日志
+    pub audit: AuditLog,
+    /// 配置之后，数据命令转发到后端节点，不在本地执行
+    pub proxy: Option<Proxy>,
+    /// FCALL 可以调用的 WASM 插件
+    pub plugins: Plugins,
+    /// 连接和命令的事件 hook
+    pub hooks: Hooks,
+    /// 配置之后，提交的修改投递到外部的 sink
+    pub cdc: Option<Arc<Cdc>>,
 }
 
 impl<Store: Storage> ServiceBuilder<Store> {
@@ -26,9 +94,116 @@ impl<Store: Storage> ServiceBuilder<Store> {
             on_executed: Vec::new(),
             on_before_send: Vec::new(),
             on_after_send: Vec::new(),
+            middlewares: Vec::new(),
+            commands: CommandRegistry::default(),
+            auth: Default::default(),
+            acl: Default::default(),
+            tenants: Default::default(),
+            read_only: AtomicBool::new(false),
+            replica: false,
+            notifications: NotificationConfig::default(),
+            mvcc: None,
+            limiter: RateLimiter::default(),
+            compression: CompressionConfig::default(),
+            connections: ConnectionLimiter::default(),
+            clients: ClientRegistry::default(),
+            in_flight: InFlightLimiter::default(),
+            slowlog: SlowLog::default(),
+            idempotency: IdempotencyCache::default(),
+            audit: AuditLog::default(),
+            proxy: None,
+            plugins: Plugins::default(),
+            hooks: Hooks::default(),
+            cdc: None,
         }
     }
 
+    pub fn auth(self, auth: Option<AuthConfig>) -> Self {
+        *self.auth.write().unwrap() = auth;
+        self
+    }
+
+    pub fn acl(self, acl: Option<AclConfig>) -> Self {
+        *self.acl.write().unwrap() = acl.map(Into::into);
+        self
+    }
+
+    pub fn tenants(self, tenants: Tenants) -> Self {
+        *self.tenants.write().unwrap() = tenants;
+        self
+    }
+
+    pub fn read_only(self, read_only: bool) -> Self {
+        self.read_only.store(read_only, Ordering::Relaxed);
+        self
+    }
+
+    /// replica 总是只读的
+    pub fn replica(mut self, replica: bool) -> Self {
+        self.replica = replica;
+        self
+    }
+
+    pub fn notifications(mut self, notifications: NotificationConfig) -> Self {
+        self.notifications = notifications;
+        self
+    }
+
+    pub fn mvcc(mut self, mvcc: Option<MvccConfig>) -> Self {
+        self.mvcc = mvcc;
+        self
+    }
+
+    pub fn limits(mut self, limits: LimitsConfig) -> Self {
+        self.limiter = RateLimiter::new(limits);
+        self
+    }
+
+    pub fn compression(mut self, compression: CompressionConfig) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    pub fn connections(mut self, connections: ConnectionLimiter) -> Self {
+        self.connections = connections;
+        self
+    }
+
+    pub fn in_flight(mut self, in_flight: InFlightLimiter) -> Self {
+        self.in_flight = in_flight;
+        self
+    }
+
+    pub fn slowlog(mut self, slowlog: SlowlogConfig) -> Self {
+        self.slowlog = SlowLog::new(slowlog);
+        self
+    }
+
+    pub fn idempotency(mut self, idempotency: IdempotencyConfig) -> Self {
+        self.idempotency = IdempotencyCache::new(idempotency);
+        self
+    }
+
+    pub fn audit(mut self, audit: AuditLog) -> Self {
+        self.audit = audit;
+        self
+    }
+
+    pub fn proxy(mut self, proxy: Option<Proxy>) -> Self {
+        self.proxy = proxy;
+        self
+    }
+
+    pub fn plugins(mut self, plugins: Plugins) -> Self {
+        self.plugins = plugins;
+        self
+    }
+
+    pub fn cdc(mut self, cdc: Option<Cdc>) -> Self {
+        self.cdc = cdc.map(Arc::new);
+        self
+    }
+
     pub fn fn_received(mut self, f: fn(&CommandRequest)) -> Self {
         self.on_received.push(f);
         self
@@ -46,10 +221,91 @@ impl<Store: Storage> ServiceBuilder<Store> {
         self
     }
 
+    /// 客户端建立连接时调用
+    pub fn on_connect(mut self, hook: impl Into<Hook>) -> Self {
+        self.hooks.connect.push(hook.into());
+        self
+    }
+
+    /// 收到命令、还没有执行时调用
+    pub fn on_command(mut self, hook: impl Into<Hook>) -> Self {
+        self.hooks.command.push(hook.into());
+        self
+    }
+
+    /// 命令执行完成时调用，事件中有命令、响应和执行的时间
+    pub fn on_executed(mut self, hook: impl Into<Hook>) -> Self {
+        self.hooks.executed.push(hook.into());
+        self
+    }
+
+    /// 命令执行出错时调用
+    pub fn on_error(mut self, hook: impl Into<Hook>) -> Self {
+        self.hooks.error.push(hook.into());
+        self
+    }
+
+    /// 客户端断开连接时调用
+    pub fn on_disconnect(mut self, hook: impl Into<Hook>) -> Self {
+        self.hooks.disconnect.push(hook.into());
+        self
+    }
+
+    /// 添加一个中间件，先添加的中间件先看到命令，后看到响应
+    pub fn middleware(mut self, middleware: impl Middleware) -> Self {
+        self.middlewares.push(Arc::new(middleware));
+        self
+    }
+
+    /// 注册自定义命令，客户端发送 type_url 相同的 Custom 命令时调用 handler
+    pub fn command(
+        mut self,
+        type_url: impl Into<String>,
+        handler: impl CommandHandler<Store>,
+    ) -> Self {
+        self.commands.register(type_url, handler);
+        self
+    }
+
+    /// 重新加载认证和权限的配置；已经认证过的连接不需要重新认证，但之后的命令按新的权限检查。
+    /// 租户的配置有错误时什么都不修改
+    pub fn set_security(&self, security: &SecurityConfig) -> Result<(), KvError> {
+        let tenants = security.tenants.as_slice().try_into()?;
+        *self.auth.write().unwrap() = security.auth.clone();
+        *self.acl.write().unwrap() = security.acl.clone().map(Into::into);
+        *self.tenants.write().unwrap() = tenants;
+        Ok(())
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.replica || self.read_only.load(Ordering::Relaxed)
+    }
+
+    /// 打开或者关闭只读模式；replica 不能关闭只读
+    pub fn set_read_only(&self, read_only: bool) -> Result<(), KvError> {
+        if self.replica && !read_only {
+            return Err(KvError::InvalidCommand(
+                "replica is always read-only".into(),
+            ));
+        }
+        self.read_only.store(read_only, Ordering::Relaxed);
+        Ok(())
+    }
+
     pub fn finish(self) -> Service<Store> {
+        let broadcaster: Arc<BroadCaster> = Default::default();
+        let keyspace = Keyspace::new(self.notifications, self.mvcc, broadcaster.clone());
+        let replicator = Replicator::new(self.cdc.clone());
         Service {
             inner: Arc::new(self),
-            broadcaster: Default::default(),
+            broadcaster,
+            replicator: Arc::new(replicator),
+            keyspace: Arc::new(keyspace),
+            stats: Default::default(),
+            shutdown: Default::default(),
+            reloader: Default::default(),
+            cluster: Default::default(),
+            membership: Default::default(),
         }
     }
 }
@@ -70,6 +326,27 @@ impl Default for ServiceBuilder<MemTable> {
             on_executed: Default::default(),
             on_before_send: Default::default(),
             on_after_send: Default::default(),
+            middlewares: Default::default(),
+            commands: Default::default(),
+            auth: Default::default(),
+            acl: Default::default(),
+            tenants: Default::default(),
+            read_only: AtomicBool::new(false),
+            replica: false,
+            notifications: NotificationConfig::default(),
+            mvcc: None,
+            limiter: RateLimiter::default(),
+            compression: CompressionConfig::default(),
+            connections: ConnectionLimiter::default(),
+            clients: ClientRegistry::default(),
+            in_flight: InFlightLimiter::default(),
+            slowlog: SlowLog::default(),
+            idempotency: IdempotencyCache::default(),
+            audit: AuditLog::default(),
+            proxy: None,
+            plugins: Plugins::default(),
+            hooks: Hooks::default(),
+            cdc: None,
         }
     }
 }
@@ -111,7 +388,7 @@ mod builder_tests {
 
         let mut res = service.execute(CommandRequest::new_hset("t1", "k1", "v1"));
         let res = res.next().await.unwrap().as_ref().to_owned();
-        assert_eq!(res.status, StatusCode::CREATED.as_u16() as _);
+        assert_eq!(res.status, StatusCode::CREATED.as_u16() as u32);
         assert_eq!(res.message, "");
         assert_eq!(res.values, vec![Value::default()]);
     }
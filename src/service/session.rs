@@ -0,0 +1,397 @@
+use std::{
+    collections::HashSet,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex, OnceLock, RwLock,
+    },
+    time::{Duration, Instant},
+};
+
+use tokio::{sync::Semaphore, time};
+use tokio_util::sync::CancellationToken;
+
+use super::rate_limit::TokenBucket;
+use crate::{
+    error::KvError,
+    frame::FrameCompression,
+    pb::abi::{command_request::RequestData, CommandRequest},
+    storage::KeyVersion,
+};
+
+/// 一个客户端连接的状态，同一个连接上的所有 yamux stream 共享同一个 Session
+#[derive(Debug, Default)]
+pub struct Session {
+    /// 认证通过后的用户名
+    user: RwLock<Option<String>>,
+    /// MULTI 之后排队的命令，None 表示不在事务中
+    transaction: Mutex<Option<Transaction>>,
+    /// WATCH 时 key 的版本号
+    watched: Mutex<Vec<KeyVersion>>,
+    /// HWATCH 创建的 watcher 的 id，只能 HUNWATCH 这里的 watcher
+    watchers: Mutex<HashSet<u32>>,
+    /// 连接的令牌桶，第一次限流时创建
+    rate_limit: OnceLock<TokenBucket>,
+    /// 连接上同时执行的命令数的配额，第一次执行命令时创建
+    in_flight: OnceLock<Arc<Semaphore>>,
+    /// HELLO 协商好的压缩算法，发送响应时使用
+    compression: RwLock<FrameCompression>,
+    /// 客户端的地址，记录慢查询时使用
+    peer: Option<String>,
+    /// 执行中的命令数和最后一个命令结束的时间，用来判断连接是否空闲
+    activity: Mutex<Activity>,
+    /// 最近执行的命令，CLIENT LIST 中显示
+    last_command: Mutex<Option<&'static str>>,
+    /// 连接上打开的 stream 数
+    streams: AtomicUsize,
+    /// CLIENT KILL 之后取消，连接随之断开
+    killed: CancellationToken,
+    /// SELECT 和 SESSION SET 设置的选项
+    options: RwLock<Options>,
+}
+
+#[derive(Debug, Default)]
+struct Options {
+    /// table 为空的命令使用的 table
+    table: Option<String>,
+    /// 没有设置超时时间的命令使用的超时（毫秒）
+    timeout: u64,
+    /// HGETALL/HSCAN 的结果分多帧返回
+    stream: bool,
+}
+
+#[derive(Debug)]
+struct Activity {
+    busy: usize,
+    last: Instant,
+}
+
+impl Default for Activity {
+    fn default() -> Self {
+        Self {
+            busy: 0,
+            last: Instant::now(),
+        }
+    }
+}
+
+/// stream 打开期间持有，drop 之后 stream 数减一
+#[derive(Debug)]
+pub struct OpenStream(Arc<Session>);
+
+impl Drop for OpenStream {
+    fn drop(&mut self) {
+        self.0.streams.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// 执行命令期间持有，drop 之后连接开始计算空闲时间
+#[derive(Debug)]
+pub struct Busy(Arc<Session>);
+
+impl Drop for Busy {
+    fn drop(&mut self) {
+        let mut activity = self.0.activity.lock().unwrap();
+        activity.busy -= 1;
+        activity.last = Instant::now();
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Transaction {
+    pub commands: Vec<CommandRequest>,
+    /// 排队时有命令出错，EXEC 会直接失败
+    pub aborted: bool,
+}
+
+impl Session {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 已经认证过的 session，用于进程内的可信调用
+    pub fn authenticated(user: impl Into<String>) -> Self {
+        Self {
+            user: RwLock::new(Some(user.into())),
+            ..Default::default()
+        }
+    }
+
+    pub fn with_peer(mut self, peer: impl ToString) -> Self {
+        self.peer = Some(peer.to_string());
+        self
+    }
+
+    pub fn peer(&self) -> Option<&str> {
+        self.peer.as_deref()
+    }
+
+    pub fn user(&self) -> Option<String> {
+        self.user.read().unwrap().clone()
+    }
+
+    pub fn is_authenticated(&self) -> bool {
+        self.user.read().unwrap().is_some()
+    }
+
+    pub fn set_user(&self, user: impl Into<String>) {
+        *self.user.write().unwrap() = Some(user.into());
+    }
+
+    pub fn compression(&self) -> FrameCompression {
+        *self.compression.read().unwrap()
+    }
+
+    pub fn set_compression(&self, compression: FrameCompression) {
+        *self.compression.write().unwrap() = compression;
+    }
+
+    /// 开始事务，已经在事务中时返回 false
+    pub fn begin_transaction(&self) -> bool {
+        let mut transaction = self.transaction.lock().unwrap();
+        match *transaction {
+            Some(_) => false,
+            None => {
+                *transaction = Some(Transaction::default());
+                true
+            }
+        }
+    }
+
+    pub fn in_transaction(&self) -> bool {
+        self.transaction.lock().unwrap().is_some()
+    }
+
+    pub fn queue(&self, cmd: CommandRequest) {
+        if let Some(transaction) = self.transaction.lock().unwrap().as_mut() {
+            transaction.commands.push(cmd);
+        }
+    }
+
+    pub fn abort_transaction(&self) {
+        if let Some(transaction) = self.transaction.lock().unwrap().as_mut() {
+            transaction.aborted = true;
+        }
+    }
+
+    /// 结束事务，返回排队的命令
+    pub fn take_transaction(&self) -> Option<Transaction> {
+        self.transaction.lock().unwrap().take()
+    }
+
+    pub fn watch(&self, keys: impl IntoIterator<Item = KeyVersion>) {
+        self.watched.lock().unwrap().extend(keys);
+    }
+
+    /// 取消所有的监视，返回之前监视的 key
+    pub fn unwatch(&self) -> Vec<KeyVersion> {
+        std::mem::take(&mut *self.watched.lock().unwrap())
+    }
+
+    pub fn add_watcher(&self, id: u32) {
+        self.watchers.lock().unwrap().insert(id);
+    }
+
+    /// 删除 session 创建的 watcher，不是这个 session 创建的返回 false
+    pub fn remove_watcher(&self, id: u32) -> bool {
+        self.watchers.lock().unwrap().remove(&id)
+    }
+
+    /// 连接的令牌桶；创建之后 qps 不再变化
+    pub(crate) fn rate_limit(&self, qps: u32) -> &TokenBucket {
+        self.rate_limit.get_or_init(|| TokenBucket::new(qps))
+    }
+
+    pub(crate) fn in_flight(&self, max: usize) -> Arc<Semaphore> {
+        let semaphore = self.in_flight.get_or_init(|| Arc::new(Semaphore::new(max)));
+        semaphore.clone()
+    }
+
+    pub fn last_command(&self) -> Option<&'static str> {
+        *self.last_command.lock().unwrap()
+    }
+
+    pub fn set_last_command(&self, name: &'static str) {
+        *self.last_command.lock().unwrap() = Some(name);
+    }
+
+    /// 标记打开了一个 stream；RESP 和 WebSocket 的连接只有一个 stream
+    pub fn open_stream(self: &Arc<Self>) -> OpenStream {
+        self.streams.fetch_add(1, Ordering::Relaxed);
+        OpenStream(self.clone())
+    }
+
+    pub fn streams(&self) -> usize {
+        self.streams.load(Ordering::Relaxed)
+    }
+
+    /// 设置默认 table，table 为空时取消
+    pub fn select(&self, table: impl Into<String>) {
+        let table = table.into();
+        self.options.write().unwrap().table = (!table.is_empty()).then_some(table);
+    }
+
+    pub fn selected(&self) -> Option<String> {
+        self.options.read().unwrap().table.clone()
+    }
+
+    /// 处理 SESSION SET
+    pub fn set_option(&self, name: &str, value: &str) -> Result<(), KvError> {
+        let mut options = self.options.write().unwrap();
+        match name.to_ascii_lowercase().as_str() {
+            "timeout" => {
+                options.timeout = value
+                    .parse()
+                    .map_err(|_| KvError::InvalidCommand(format!("invalid timeout: {}", value)))?;
+            }
+            "stream" => {
+                options.stream = match value.to_ascii_lowercase().as_str() {
+                    "on" => true,
+                    "off" => false,
+                    _ => {
+                        return Err(KvError::InvalidCommand(format!(
+                            "stream should be on or off: {}",
+                            value
+                        )))
+                    }
+                };
+            }
+            _ => {
+                return Err(KvError::InvalidCommand(format!(
+                    "unknown session option: {}",
+                    name
+                )))
+            }
+        }
+        Ok(())
+    }
+
+    /// 把连接的选项应用到命令上：table 为空的命令使用默认 table，没有超时时间的命令使用默认的超时
+    pub fn apply_options(&self, cmd: &mut CommandRequest) {
+        let options = self.options.read().unwrap();
+        if cmd.timeout == 0 {
+            cmd.timeout = options.timeout;
+        }
+        if options.stream
+            && matches!(
+                cmd.request_data,
+                Some(RequestData::Hgetall(_) | RequestData::Hscan(_))
+            )
+        {
+            cmd.stream = true;
+        }
+        if let (Some(table), Some(data)) = (&options.table, cmd.request_data.as_mut()) {
+            select_table(data, table);
+        }
+    }
+
+    /// 断开这个连接，CLIENT KILL 时调用
+    pub fn kill(&self) {
+        self.killed.cancel();
+    }
+
+    /// 等到连接被 CLIENT KILL 断开
+    pub async fn killed(&self) {
+        self.killed.cancelled().await
+    }
+
+    /// 标记开始执行一个命令；SUBSCRIBE 这类命令在推送结束之前一直持有
+    pub fn busy(self: &Arc<Self>) -> Busy {
+        self.activity.lock().unwrap().busy += 1;
+        Busy(self.clone())
+    }
+
+    /// 等到连接上没有执行中的命令，并且已经空闲了 timeout
+    pub async fn idle(&self, timeout: Duration) {
+        loop {
+            let wait = {
+                let activity = self.activity.lock().unwrap();
+                let elapsed = activity.last.elapsed();
+                if activity.busy == 0 && elapsed >= timeout {
+                    return;
+                }
+                match activity.busy {
+                    0 => timeout - elapsed,
+                    _ => timeout,
+                }
+            };
+            time::sleep(wait).await;
+        }
+    }
+}
+
+/// table 为空时使用 table；HSTATS 的 table 为空表示所有的 table，不需要处理
+fn select_table(data: &mut RequestData, table: &str) {
+    match data {
+        RequestData::Batch(batch) => batch
+            .commands
+            .iter_mut()
+            .filter_map(|cmd| cmd.request_data.as_mut())
+            .for_each(|data| select_table(data, table)),
+        RequestData::Hstats(_) => {}
+        data => {
+            if let Some(t) = data.table_mut().filter(|t| t.is_empty()) {
+                *t = table.to_owned();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod session_tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn idle_should_wait_for_running_commands() {
+        let session = Arc::new(Session::new());
+        let timeout = Duration::from_millis(100);
+        let busy = session.busy();
+        let idle = session.idle(timeout);
+        tokio::pin!(idle);
+        assert!(time::timeout(timeout * 3, &mut idle).await.is_err());
+
+        // 命令结束之后重新计时
+        drop(busy);
+        assert!(time::timeout(timeout / 2, &mut idle).await.is_err());
+        assert!(time::timeout(timeout, &mut idle).await.is_ok());
+    }
+
+    #[test]
+    fn options_should_apply_to_commands() {
+        let session = Session::new();
+        session.select("t1");
+        session.set_option("timeout", "500").unwrap();
+        session.set_option("stream", "on").unwrap();
+        assert!(session.set_option("stream", "yes").is_err());
+        assert!(session.set_option("format", "json").is_err());
+
+        let mut cmd = CommandRequest::new_batch([
+            CommandRequest::new_hset("", "k1", "v1"),
+            CommandRequest::new_hset("t2", "k2", "v2"),
+        ]);
+        session.apply_options(&mut cmd);
+        assert_eq!(cmd.timeout, 500);
+        let Some(RequestData::Batch(batch)) = &cmd.request_data else {
+            panic!("expect batch");
+        };
+        let tables: Vec<_> = batch
+            .commands
+            .iter()
+            .map(|cmd| cmd.request_data.clone().unwrap().table_mut().cloned())
+            .collect();
+        assert_eq!(tables, [Some("t1".into()), Some("t2".into())]);
+
+        let mut cmd = CommandRequest::new_hgetall("").with_timeout(Duration::from_millis(100));
+        session.apply_options(&mut cmd);
+        assert_eq!(cmd.timeout, 100);
+        assert!(cmd.stream);
+        assert_eq!(
+            cmd,
+            CommandRequest::new_hgetall("t1")
+                .with_timeout(Duration::from_millis(100))
+                .with_stream()
+        );
+
+        session.select("");
+        assert_eq!(session.selected(), None);
+    }
+}
@@ -0,0 +1,102 @@
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+
+use tokio::sync::Notify;
+use tokio_util::sync::CancellationToken;
+
+/// 优雅退出的信号：触发之后 listener 不再接受新的连接，连接不再读取新的请求；
+/// 同时记录正在执行的请求数，退出前等待它们执行完
+#[derive(Clone, Default)]
+pub struct Shutdown(Arc<Inner>);
+
+#[derive(Default)]
+struct Inner {
+    token: CancellationToken,
+    in_flight: AtomicUsize,
+    /// 正在执行的请求数降到 0 时通知
+    idle: Notify,
+}
+
+/// 正在执行的请求，drop 之后请求数减一
+pub struct InFlight(Arc<Inner>);
+
+impl Shutdown {
+    /// 开始退出
+    pub fn trigger(&self) {
+        self.0.token.cancel();
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        self.0.token.is_cancelled()
+    }
+
+    /// 等待退出信号
+    pub async fn triggered(&self) {
+        self.0.token.cancelled().await
+    }
+
+    /// 开始执行一个请求
+    pub fn start(&self) -> InFlight {
+        self.0.in_flight.fetch_add(1, Ordering::SeqCst);
+        InFlight(self.0.clone())
+    }
+
+    /// 正在执行的请求数
+    pub fn in_flight(&self) -> usize {
+        self.0.in_flight.load(Ordering::SeqCst)
+    }
+
+    /// 等待所有正在执行的请求执行完
+    pub async fn drained(&self) {
+        loop {
+            let notified = self.0.idle.notified();
+            tokio::pin!(notified);
+            // 先注册再检查，避免错过检查之后、等待之前发出的通知
+            notified.as_mut().enable();
+            if self.in_flight() == 0 {
+                return;
+            }
+            notified.await;
+        }
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        if self.0.in_flight.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.0.idle.notify_waiters();
+        }
+    }
+}
+
+#[cfg(test)]
+mod shutdown_tests {
+    use std::time::Duration;
+
+    use tokio::time;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn drained_should_wait_for_in_flight_requests() {
+        let shutdown = Shutdown::default();
+        shutdown.drained().await;
+
+        let request = shutdown.start();
+        shutdown.trigger();
+        assert!(shutdown.is_triggered());
+        let waiting = time::timeout(Duration::from_millis(20), shutdown.drained()).await;
+        assert!(waiting.is_err());
+
+        tokio::spawn(async move {
+            time::sleep(Duration::from_millis(10)).await;
+            drop(request);
+        });
+        time::timeout(Duration::from_secs(1), shutdown.drained())
+            .await
+            .unwrap();
+        assert_eq!(shutdown.in_flight(), 0);
+    }
+}
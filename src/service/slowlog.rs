@@ -0,0 +1,216 @@
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+
+use bytes::Bytes;
+use tracing::warn;
+
+use super::{acl::required_access, session::Session, transaction::touched_keys};
+use crate::{
+    config::SlowlogConfig,
+    pb::abi::{command_request::RequestData, CommandResponse, Value, ValueMap},
+    storage::now_millis,
+};
+
+/// 默认的阈值
+const DEFAULT_THRESHOLD: Duration = Duration::from_millis(10);
+
+/// 默认保留的条数
+const DEFAULT_MAX_LEN: usize = 128;
+
+/// 最近的慢查询，超过 max_len 之后丢弃最早的
+#[derive(Debug)]
+pub struct SlowLog {
+    threshold: Duration,
+    max_len: usize,
+    next_id: AtomicU64,
+    entries: Mutex<VecDeque<SlowEntry>>,
+}
+
+#[derive(Debug, Clone)]
+struct SlowEntry {
+    id: u64,
+    /// 命令执行完的毫秒时间戳
+    timestamp: u64,
+    duration: Duration,
+    command: String,
+    table: String,
+    key: Bytes,
+    peer: String,
+}
+
+impl Default for SlowLog {
+    fn default() -> Self {
+        Self::new(SlowlogConfig::default())
+    }
+}
+
+impl SlowLog {
+    pub fn new(config: SlowlogConfig) -> Self {
+        Self {
+            threshold: config
+                .threshold
+                .map_or(DEFAULT_THRESHOLD, Duration::from_millis),
+            max_len: config.max_len.unwrap_or(DEFAULT_MAX_LEN),
+            next_id: AtomicU64::new(0),
+            entries: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    /// 命令执行完之后调用，超过阈值时写入日志并保留下来
+    pub fn record(&self, cmd: &RequestData, session: &Session, duration: Duration) {
+        if duration < self.threshold {
+            return;
+        }
+        let (table, key) = target_of(cmd);
+        let entry = SlowEntry {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed),
+            timestamp: now_millis(),
+            duration,
+            command: command_name(cmd),
+            table: table.to_owned(),
+            key: Bytes::copy_from_slice(key),
+            peer: session.peer().unwrap_or_default().to_owned(),
+        };
+        warn!(
+            command = %entry.command,
+            table = %entry.table,
+            key = %String::from_utf8_lossy(&entry.key),
+            duration_ms = duration.as_secs_f64() * 1000.0,
+            peer = %entry.peer,
+            "Slow command"
+        );
+        if self.max_len == 0 {
+            return;
+        }
+        let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        if entries.len() >= self.max_len {
+            entries.pop_front();
+        }
+        entries.push_back(entry);
+    }
+
+    /// 处理 SLOWLOG GET：最新的在前面，count 为 0 时返回全部
+    pub fn get(&self, count: usize) -> CommandResponse {
+        let entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        let count = match count {
+            0 => entries.len(),
+            n => n,
+        };
+        let values: Vec<Value> = entries.iter().rev().take(count).map(Value::from).collect();
+        values.into()
+    }
+}
+
+impl From<&SlowEntry> for Value {
+    fn from(entry: &SlowEntry) -> Self {
+        let entries = [
+            ("id", (entry.id as i64).into()),
+            ("timestamp", (entry.timestamp as i64).into()),
+            ("duration_us", (entry.duration.as_micros() as i64).into()),
+            ("command", entry.command.as_str().into()),
+            ("table", entry.table.as_str().into()),
+            ("key", entry.key.clone().into()),
+            ("peer", entry.peer.as_str().into()),
+        ]
+        .into_iter()
+        .map(|(name, v)| (name.to_string(), v))
+        .collect();
+        ValueMap { entries }.into()
+    }
+}
+
+/// 命令的名字，比如 HGET
+pub(super) fn command_name(cmd: &RequestData) -> String {
+    cmd.name().to_owned()
+}
+
+/// 命令访问的第一个 table 和 key，没有 key 时为空
+fn target_of(cmd: &RequestData) -> (&str, &[u8]) {
+    if let Some(&(table, key)) = touched_keys(cmd).as_ref().and_then(|keys| keys.first()) {
+        return (table, key.unwrap_or_default());
+    }
+    let table = required_access(cmd)
+        .into_iter()
+        .find_map(|access| access.table)
+        .unwrap_or_default();
+    (table, &[])
+}
+
+#[cfg(test)]
+mod slowlog_tests {
+    use std::collections::BTreeMap;
+
+    use futures::StreamExt;
+
+    use super::*;
+    use crate::{assert_res_ok, pb::abi::CommandRequest, service_builder::ServiceBuilder, Service};
+
+    #[tokio::test]
+    async fn slowlog_should_keep_recent_slow_commands() {
+        let config = SlowlogConfig {
+            threshold: Some(0),
+            max_len: Some(2),
+        };
+        let service: Service = ServiceBuilder::default().slowlog(config).finish();
+        let session = Session::new().with_peer("127.0.0.1:4000");
+        for cmd in [
+            CommandRequest::new_hset("t1", "k1", "v1"),
+            CommandRequest::new_hget("t1", "k1"),
+            CommandRequest::new_hgetall("t2"),
+        ] {
+            service.execute_with(cmd, &session).next().await.unwrap();
+        }
+
+        let res = service
+            .execute(CommandRequest::new_slowlog_get(0))
+            .next()
+            .await
+            .unwrap();
+        assert_eq!(res.values.len(), 2);
+        let entries: Vec<BTreeMap<String, Value>> = res
+            .values
+            .iter()
+            .map(|v| match &v.value {
+                Some(crate::pb::abi::value::Value::Map(map)) => {
+                    map.entries.clone().into_iter().collect()
+                }
+                _ => panic!("slowlog entry should be a map"),
+            })
+            .collect();
+        assert_eq!(entries[0]["command"], "HGETALL".into());
+        assert_eq!(entries[0]["table"], "t2".into());
+        assert_eq!(entries[0]["key"], Bytes::new().into());
+        assert_eq!(entries[0]["id"], 2.into());
+        assert_eq!(entries[1]["command"], "HGET".into());
+        assert_eq!(entries[1]["key"], Bytes::from("k1").into());
+        assert_eq!(entries[1]["peer"], "127.0.0.1:4000".into());
+
+        // SLOWLOG GET 自己也会被记录
+        let res = service
+            .execute(CommandRequest::new_slowlog_get(1))
+            .next()
+            .await
+            .unwrap();
+        assert_eq!(res.values.len(), 1);
+
+        // 低于阈值的命令不记录
+        let service: Service = ServiceBuilder::default().finish();
+        service
+            .execute(CommandRequest::new_hget("t1", "k1"))
+            .next()
+            .await
+            .unwrap();
+        let res = service
+            .execute(CommandRequest::new_slowlog_get(0))
+            .next()
+            .await
+            .unwrap();
+        assert_res_ok(&res, &[], &[]);
+    }
+}
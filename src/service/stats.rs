@@ -0,0 +1,337 @@
+use std::{
+    collections::BTreeMap,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+use dashmap::DashMap;
+use hyper::StatusCode;
+
+use super::Service;
+use crate::{
+    pb::abi::{command_request::RequestData, CommandResponse, Kvpair, Value, ValueMap},
+    KvError, Storage, TableStats,
+};
+
+/// 服务器启动以来处理过的命令数，以及每个 table 的 HGET/HMGET 命中和未命中次数
+#[derive(Debug)]
+pub struct Stats {
+    started: Instant,
+    commands: AtomicU64,
+    tables: DashMap<String, Counters>,
+}
+
+impl Default for Stats {
+    fn default() -> Self {
+        Self {
+            started: Instant::now(),
+            commands: AtomicU64::new(0),
+            tables: DashMap::new(),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl Stats {
+    /// 收到了一个命令
+    pub fn command(&self) {
+        self.commands.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn commands(&self) -> u64 {
+        self.commands.load(Ordering::Relaxed)
+    }
+
+    pub fn uptime(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    /// 命令执行之后调用，按照结果统计 key 是否找到
+    pub fn record(&self, cmd: &RequestData, res: &CommandResponse) {
+        match cmd {
+            RequestData::Hget(v) => match res.status {
+                s if s == StatusCode::OK.as_u16() as u32 => self.add(&v.table, 1, 0),
+                s if s == StatusCode::NOT_FOUND.as_u16() as u32 => self.add(&v.table, 0, 1),
+                _ => {}
+            },
+            // 不存在的 key 返回的是 Value::default()
+            RequestData::Hmget(v) => {
+                let hits = res.values.iter().filter(|v| v.value.is_some()).count() as u64;
+                self.add(&v.table, hits, res.values.len() as u64 - hits);
+            }
+            RequestData::Batch(batch) => batch
+                .commands
+                .iter()
+                .zip(&res.results)
+                .filter_map(|(cmd, res)| Some((cmd.request_data.as_ref()?, res)))
+                .for_each(|(cmd, res)| self.record(cmd, res)),
+            _ => {}
+        }
+    }
+
+    /// table 的 (hits, misses)
+    pub fn get(&self, table: &str) -> (u64, u64) {
+        self.tables.get(table).map_or((0, 0), |c| {
+            (
+                c.hits.load(Ordering::Relaxed),
+                c.misses.load(Ordering::Relaxed),
+            )
+        })
+    }
+
+    fn add(&self, table: &str, hits: u64, misses: u64) {
+        if hits == 0 && misses == 0 {
+            return;
+        }
+        let counters = match self.tables.get(table) {
+            Some(counters) => counters,
+            None => self.tables.entry(table.to_owned()).or_default().downgrade(),
+        };
+        counters.hits.fetch_add(hits, Ordering::Relaxed);
+        counters.misses.fetch_add(misses, Ordering::Relaxed);
+    }
+}
+
+impl<Store: Storage> Service<Store> {
+    /// 处理 INFO：没有内存上限的存储引擎不返回内存使用量
+    pub(super) fn info(&self) -> CommandResponse {
+        let role = match self.replica {
+            true => "replica",
+            false => "primary",
+        };
+        let mut pairs = vec![
+            Kvpair::new("version", env!("CARGO_PKG_VERSION").into()),
+            Kvpair::new(
+                "uptime_seconds",
+                (self.stats.uptime().as_secs() as i64).into(),
+            ),
+            Kvpair::new(
+                "connected_clients",
+                (self.connections.connections() as i64).into(),
+            ),
+            Kvpair::new("commands_processed", (self.stats.commands() as i64).into()),
+            Kvpair::new("storage_backend", self.store.backend().into()),
+            Kvpair::new("role", role.into()),
+            Kvpair::new("read_only", self.is_read_only().into()),
+            Kvpair::new(
+                "connected_replicas",
+                (self.replicator.replicas() as i64).into(),
+            ),
+        ];
+        if let Some(cluster) = self.cluster() {
+            let status = cluster.status();
+            pairs.push(Kvpair::new("cluster_role", status.role.as_str().into()));
+            pairs.push(Kvpair::new("cluster_term", (status.term as i64).into()));
+            let leader = status.leader.unwrap_or_default() as i64;
+            pairs.push(Kvpair::new("cluster_leader", leader.into()));
+            pairs.push(Kvpair::new(
+                "cluster_commit_index",
+                (status.commit_index as i64).into(),
+            ));
+            pairs.push(Kvpair::new(
+                "cluster_last_applied",
+                (status.last_applied as i64).into(),
+            ));
+        }
+        if let Some(history) = self.keyspace.history() {
+            pairs.push(Kvpair::new(
+                "mvcc_revision",
+                (history.revision() as i64).into(),
+            ));
+            pairs.push(Kvpair::new(
+                "mvcc_oldest_revision",
+                (history.oldest() as i64).into(),
+            ));
+        }
+        if let Ok(Some(used)) = self.store.disk_usage() {
+            pairs.push(Kvpair::new("disk_usage", (used as i64).into()));
+        }
+        if let Some(memory) = self.store.memory_limit() {
+            pairs.push(Kvpair::new("used_memory", (memory.used() as i64).into()));
+            pairs.push(Kvpair::new(
+                "max_memory",
+                (memory.max_memory() as i64).into(),
+            ));
+        }
+        pairs.into()
+    }
+
+    /// 处理 HSTATS：table 为空时返回所有有数据或者被读取过的 table
+    pub(super) fn table_stats(&self, table: &str) -> CommandResponse {
+        let mut tables: BTreeMap<String, TableStats> = match self.store.stats() {
+            Ok(stats) => stats.into_iter().collect(),
+            Err(e) => return e.into(),
+        };
+        match table.is_empty() {
+            true => self.stats.tables.iter().for_each(|c| {
+                tables.entry(c.key().clone()).or_default();
+            }),
+            false => {
+                let stats = tables.remove(table).unwrap_or_default();
+                tables = [(table.to_owned(), stats)].into();
+            }
+        }
+        let pairs: Vec<Kvpair> = tables
+            .into_iter()
+            .map(|(table, stats)| {
+                let (hits, misses) = self.stats.get(&table);
+                let entries = [
+                    ("keys", stats.keys),
+                    ("bytes", stats.bytes),
+                    ("hits", hits),
+                    ("misses", misses),
+                ]
+                .into_iter()
+                .map(|(name, n)| (name.to_string(), Value::from(n as i64)))
+                .collect();
+                Kvpair::new(table, ValueMap { entries }.into())
+            })
+            .collect();
+        pairs.into()
+    }
+
+    /// 处理 QUOTA：table 自己的配额和使用量，以及包含它的租户的配额
+    pub(super) fn quota(&self, table: &str) -> CommandResponse {
+        let Some(quotas) = self.store.quotas() else {
+            return KvError::InvalidCommand("storage doesn't support quotas".into()).into();
+        };
+        let pairs: Vec<Kvpair> = quotas
+            .get(table)
+            .into_iter()
+            .map(|(scope, quota, usage)| {
+                let entries = [
+                    ("keys", usage.keys),
+                    ("bytes", usage.bytes),
+                    ("max_keys", quota.max_keys),
+                    ("max_bytes", quota.max_bytes),
+                ]
+                .into_iter()
+                .map(|(name, n)| (name.to_string(), Value::from(n as i64)))
+                .collect();
+                Kvpair::new(scope.to_string(), ValueMap { entries }.into())
+            })
+            .collect();
+        pairs.into()
+    }
+}
+
+#[cfg(test)]
+mod stats_tests {
+    use super::*;
+    use crate::{
+        assert_res_error, assert_res_ok,
+        config::QuotaConfig,
+        pb::abi::{value, CommandRequest},
+        service_builder::ServiceBuilder,
+    };
+    use futures::StreamExt;
+
+    fn stats_of(keys: i64, bytes: i64, hits: i64, misses: i64) -> Value {
+        let entries = [
+            ("keys", keys),
+            ("bytes", bytes),
+            ("hits", hits),
+            ("misses", misses),
+        ]
+        .into_iter()
+        .map(|(name, n)| (name.to_string(), n.into()))
+        .collect();
+        ValueMap { entries }.into()
+    }
+
+    #[tokio::test]
+    async fn hstats_should_count_keys_and_hits() {
+        let service: Service = ServiceBuilder::default().finish();
+        let run = |cmd: CommandRequest| {
+            let service = service.clone();
+            async move { service.execute(cmd).next().await.unwrap() }
+        };
+        run(CommandRequest::new_hset("t1", "k1", "v1")).await;
+        run(CommandRequest::new_hset("t1", "k2", "v2")).await;
+        run(CommandRequest::new_hget("t1", "k1")).await;
+        run(CommandRequest::new_hget("t1", "k3")).await;
+        run(CommandRequest::new_hmget("t1", ["k1", "k2", "k3"])).await;
+        run(CommandRequest::new_hget("t2", "k1")).await;
+
+        let res = run(CommandRequest::new_dbsize()).await;
+        assert_res_ok(&res, &[2.into()], &[]);
+
+        let res = run(CommandRequest::new_hstats("")).await;
+        let bytes = match &res.pairs[0].value.as_ref().unwrap().value {
+            Some(value::Value::Map(map)) => map.entries["bytes"].clone(),
+            _ => panic!("stats should be a map"),
+        };
+        let bytes = i64::try_from(&bytes).unwrap();
+        assert!(bytes > 0);
+        let pairs = &[
+            Kvpair::new("t1", stats_of(2, bytes, 3, 2)),
+            Kvpair::new("t2", stats_of(0, 0, 0, 1)),
+        ];
+        assert_res_ok(&res, &[], pairs);
+
+        let res = run(CommandRequest::new_hstats("t3")).await;
+        assert_res_ok(&res, &[], &[Kvpair::new("t3", stats_of(0, 0, 0, 0))]);
+    }
+
+    #[tokio::test]
+    async fn quota_should_reject_writes_and_report_usage() {
+        let service: Service = ServiceBuilder::default().finish();
+        let config = [QuotaConfig {
+            table: Some("t1".into()),
+            max_keys: Some(1),
+            ..Default::default()
+        }];
+        service.store.quotas().unwrap().configure(&config).unwrap();
+        let run = |cmd: CommandRequest| {
+            let service = service.clone();
+            async move { service.execute(cmd).next().await.unwrap() }
+        };
+        run(CommandRequest::new_hset("t1", "k1", "v1")).await;
+        let res = run(CommandRequest::new_hset("t1", "k2", "v2")).await;
+        assert_res_error(&res, 507, "Quota exceeded");
+
+        let res = run(CommandRequest::new_quota("t1")).await;
+        let Some(value::Value::Map(map)) = &res.pairs[0].value.as_ref().unwrap().value else {
+            panic!("quota should be a map");
+        };
+        assert_eq!(res.pairs[0].key, "t1");
+        assert_eq!(map.entries["keys"], 1.into());
+        assert_eq!(map.entries["max_keys"], 1.into());
+        assert_eq!(map.entries["max_bytes"], 0.into());
+    }
+
+    #[tokio::test]
+    async fn info_should_report_server_state() {
+        let service: Service = ServiceBuilder::default().finish();
+        let _conn = service.connections.try_acquire(None).unwrap();
+        let cmd = CommandRequest::new_hset("t1", "k1", "v1");
+        service.execute(cmd).next().await.unwrap();
+        let res = service
+            .execute(CommandRequest::new_info())
+            .next()
+            .await
+            .unwrap();
+        let info: BTreeMap<String, Value> = res
+            .pairs
+            .iter()
+            .map(|p| {
+                (
+                    String::from_utf8_lossy(&p.key).into(),
+                    p.value.clone().unwrap(),
+                )
+            })
+            .collect();
+        assert_eq!(info["version"], env!("CARGO_PKG_VERSION").into());
+        assert_eq!(info["connected_clients"], 1.into());
+        assert_eq!(info["commands_processed"], 2.into());
+        assert_eq!(info["storage_backend"], "memory".into());
+        assert_eq!(info["role"], "primary".into());
+        assert_eq!(info["connected_replicas"], 0.into());
+        assert!(i64::try_from(&info["used_memory"]).unwrap() > 0);
+    }
+}
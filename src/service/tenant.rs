@@ -0,0 +1,309 @@
+use std::{collections::HashMap, sync::Arc};
+
+use bytes::Bytes;
+
+use super::keyspace::{KEYEVENT_TOPIC, KEYSPACE_TOPIC};
+use crate::{
+    config::TenantConfig,
+    error::KvError,
+    pb::abi::{
+        command_request::RequestData, value, CommandResponse, Publish, Subscribe, Unsubscribe,
+        Value,
+    },
+};
+
+/// 租户的名字和 table 名之间的分隔符
+pub const SEPARATOR: char = ':';
+
+/// 用户名到租户的映射；不属于任何租户的用户访问的 table 不加前缀
+#[derive(Debug, Default)]
+pub struct Tenants {
+    /// <用户名，table 的前缀>
+    users: HashMap<String, Arc<str>>,
+}
+
+/// 租户的名字不能为空、不能重复，也不能包含分隔符，否则两个租户的 table 可能重名：
+/// 租户 a 的 table `b:t1` 和租户 `a:b` 的 table `t1` 都是 `a:b:t1`。
+/// 前缀以分隔符结尾，所以 app 和 apps 这样一个是另一个前缀的名字不会冲突
+impl TryFrom<&[TenantConfig]> for Tenants {
+    type Error = KvError;
+
+    fn try_from(tenants: &[TenantConfig]) -> Result<Self, KvError> {
+        for tenant in tenants {
+            if tenant.name.is_empty() || tenant.name.contains(SEPARATOR) {
+                return Err(KvError::InvalidCommand(format!(
+                    "tenant name {:?} must be non-empty and can't contain {:?}",
+                    tenant.name, SEPARATOR
+                )));
+            }
+            if tenants.iter().filter(|t| t.name == tenant.name).count() > 1 {
+                return Err(KvError::InvalidCommand(format!(
+                    "tenant name {:?} is configured more than once",
+                    tenant.name
+                )));
+            }
+        }
+        let users = tenants
+            .iter()
+            .flat_map(|tenant| {
+                let prefix: Arc<str> = format!("{}{}", tenant.name, SEPARATOR).into();
+                tenant
+                    .users
+                    .iter()
+                    .map(move |user| (user.clone(), prefix.clone()))
+            })
+            .collect();
+        Ok(Self { users })
+    }
+}
+
+impl Tenants {
+    /// 用户所属租户的 table 前缀
+    pub fn namespace(&self, user: Option<&str>) -> Option<Arc<str>> {
+        self.users.get(user?).cloned()
+    }
+}
+
+/// 给命令访问的 table 加上租户的前缀；HSTATS 的 table 为空时表示所有的 table，在响应中过滤。
+/// DBSIZE 只统计租户的 table，lease 和 pub/sub 的 topic 也限定在租户内
+pub fn prefix_tables(data: &mut RequestData, namespace: &str) {
+    match data {
+        RequestData::Batch(batch) => batch
+            .commands
+            .iter_mut()
+            .filter_map(|cmd| cmd.request_data.as_mut())
+            .for_each(|data| prefix_tables(data, namespace)),
+        RequestData::Hstats(v) if v.table.is_empty() => {}
+        RequestData::Dbsize(v) => v.namespace.insert_str(0, namespace),
+        RequestData::Hset(v) if v.lease != 0 => {
+            v.table.insert_str(0, namespace);
+            v.lease_namespace.insert_str(0, namespace);
+        }
+        RequestData::LeaseGrant(v) => v.namespace.insert_str(0, namespace),
+        RequestData::LeaseKeepAlive(v) => v.namespace.insert_str(0, namespace),
+        RequestData::LeaseRevoke(v) => v.namespace.insert_str(0, namespace),
+        // keyevent 通知的数据里带着 table 名，订阅时不加前缀，推送时再按 table 过滤
+        RequestData::Subscribe(Subscribe { topic })
+        | RequestData::Unsubscribe(Unsubscribe { topic, .. })
+            if topic.starts_with(KEYEVENT_TOPIC) => {}
+        RequestData::Subscribe(Subscribe { topic })
+        | RequestData::Unsubscribe(Unsubscribe { topic, .. })
+        | RequestData::Publish(Publish { topic, .. }) => prefix_topic(topic, namespace),
+        data => {
+            if let Some(table) = data.table_mut() {
+                table.insert_str(0, namespace);
+            }
+        }
+    }
+}
+
+/// keyspace 通知的 topic 中的 table 名加上前缀，和 Keyspace 发布的 topic 一致；其它 topic 直接加上前缀，
+/// 所以租户也不能往 keyevent 的 topic 上发布
+fn prefix_topic(topic: &mut String, namespace: &str) {
+    match topic.starts_with(KEYSPACE_TOPIC) {
+        true => topic.insert_str(KEYSPACE_TOPIC.len(), namespace),
+        false => topic.insert_str(0, namespace),
+    }
+}
+
+/// 租户订阅 keyevent 通知时，推送的通知需要按 table 过滤
+pub fn subscribes_keyevents(data: &RequestData) -> bool {
+    matches!(data, RequestData::Subscribe(v) if v.topic.starts_with(KEYEVENT_TOPIC))
+}
+
+/// 处理推送的 keyevent 通知：数据是 [table, key]，只保留租户自己的 table，并且去掉前缀；
+/// 订阅 id 这类其它的响应原样返回
+pub fn strip_keyevent(res: &CommandResponse, namespace: &str) -> Option<CommandResponse> {
+    let Some(Value {
+        value: Some(value::Value::String(table)),
+    }) = res.values.first()
+    else {
+        return Some(res.clone());
+    };
+    let table = table.strip_prefix(namespace)?;
+    let mut res = res.clone();
+    res.values[0] = table.into();
+    Some(res)
+}
+
+/// 响应中包含 table 名的命令，只保留租户自己的 table，并且去掉前缀
+pub fn lists_tables(data: &RequestData) -> bool {
+    matches!(data, RequestData::Tlist(_) | RequestData::Hstats(_))
+}
+
+/// 处理 TLIST 和 HSTATS 的响应
+pub fn strip_tables(res: &CommandResponse, namespace: &str) -> CommandResponse {
+    let mut res = res.clone();
+    res.values.retain_mut(|v| match &mut v.value {
+        Some(value::Value::String(table)) => match table.strip_prefix(namespace) {
+            Some(name) => {
+                *table = name.to_owned();
+                true
+            }
+            None => false,
+        },
+        _ => true,
+    });
+    res.pairs
+        .retain_mut(|pair| match pair.key.strip_prefix(namespace.as_bytes()) {
+            Some(name) => {
+                pair.key = Bytes::copy_from_slice(name);
+                true
+            }
+            None => false,
+        });
+    res
+}
+
+#[cfg(test)]
+mod tenant_tests {
+    use super::*;
+    use crate::pb::abi::{CommandRequest, Kvpair, Value};
+
+    fn tenants() -> Tenants {
+        let config = [TenantConfig {
+            name: "billing".into(),
+            users: vec!["alice".into(), "bob".into()],
+        }];
+        Tenants::try_from(&config[..]).unwrap()
+    }
+
+    #[test]
+    fn ambiguous_tenant_names_should_be_rejected() {
+        let tenant = |name: &str| TenantConfig {
+            name: name.into(),
+            users: vec![],
+        };
+        for names in [&[""][..], &["a:b"], &["a", "a"]] {
+            let config: Vec<_> = names.iter().map(|name| tenant(name)).collect();
+            assert!(Tenants::try_from(&config[..]).is_err(), "{:?}", names);
+        }
+        for names in [&["billing", "orders"][..], &["app", "apps"]] {
+            let config: Vec<_> = names.iter().map(|name| tenant(name)).collect();
+            assert!(Tenants::try_from(&config[..]).is_ok(), "{:?}", names);
+        }
+    }
+
+    #[test]
+    fn namespace_should_map_users_to_tenants() {
+        let tenants = tenants();
+        assert_eq!(
+            tenants.namespace(Some("alice")).as_deref(),
+            Some("billing:")
+        );
+        assert_eq!(tenants.namespace(Some("carol")), None);
+        assert_eq!(tenants.namespace(None), None);
+    }
+
+    #[test]
+    fn prefix_tables_should_work() {
+        let cmd = CommandRequest::new_batch([
+            CommandRequest::new_hset("t1", "k1", "v1"),
+            CommandRequest::new_hstats(""),
+            CommandRequest::new_ping(),
+        ]);
+        let mut data = cmd.request_data.unwrap();
+        prefix_tables(&mut data, "billing:");
+        let expected = CommandRequest::new_batch([
+            CommandRequest::new_hset("billing:t1", "k1", "v1"),
+            CommandRequest::new_hstats(""),
+            CommandRequest::new_ping(),
+        ]);
+        assert_eq!(Some(data), expected.request_data);
+    }
+
+    #[test]
+    fn prefix_tables_should_namespace_topics_and_leases() {
+        let prefixed = |cmd: CommandRequest| {
+            let mut data = cmd.request_data.unwrap();
+            prefix_tables(&mut data, "billing:");
+            data
+        };
+        let topic = |data| match data {
+            RequestData::Subscribe(v) => v.topic,
+            RequestData::Unsubscribe(v) => v.topic,
+            RequestData::Publish(v) => v.topic,
+            _ => unreachable!(),
+        };
+        assert_eq!(
+            topic(prefixed(CommandRequest::new_subscribe("lobby"))),
+            "billing:lobby"
+        );
+        assert_eq!(
+            topic(prefixed(CommandRequest::new_publish("lobby", vec![]))),
+            "billing:lobby"
+        );
+        assert_eq!(
+            topic(prefixed(CommandRequest::new_subscribe(
+                "__keyspace__:t1:k1"
+            ))),
+            "__keyspace__:billing:t1:k1"
+        );
+        assert_eq!(
+            topic(prefixed(CommandRequest::new_unsubscribe(
+                "__keyevent__:del",
+                1
+            ))),
+            "__keyevent__:del"
+        );
+        assert_eq!(
+            topic(prefixed(CommandRequest::new_publish(
+                "__keyevent__:del",
+                vec![]
+            ))),
+            "billing:__keyevent__:del"
+        );
+
+        let RequestData::Dbsize(v) = prefixed(CommandRequest::new_dbsize()) else {
+            unreachable!()
+        };
+        assert_eq!(v.namespace, "billing:");
+        let RequestData::LeaseRevoke(v) = prefixed(CommandRequest::new_lease_revoke(7)) else {
+            unreachable!()
+        };
+        assert_eq!(v.namespace, "billing:");
+        let cmd = CommandRequest::new_hset_with_lease("t1", "k1", "v1", 7);
+        let RequestData::Hset(v) = prefixed(cmd) else {
+            unreachable!()
+        };
+        assert_eq!(
+            (v.table.as_str(), v.lease_namespace.as_str()),
+            ("billing:t1", "billing:")
+        );
+    }
+
+    #[test]
+    fn strip_keyevent_should_hide_other_tenants() {
+        let id: CommandResponse = Value::from(1).into();
+        assert_eq!(strip_keyevent(&id, "billing:"), Some(id));
+
+        let event = |table: &str| -> CommandResponse {
+            vec![Value::from(table), Bytes::from("k1").into()].into()
+        };
+        assert_eq!(
+            strip_keyevent(&event("billing:t1"), "billing:"),
+            Some(event("t1"))
+        );
+        assert_eq!(strip_keyevent(&event("orders"), "billing:"), None);
+    }
+
+    #[test]
+    fn strip_tables_should_hide_other_tenants() {
+        let res: CommandResponse = vec![
+            Value::from("billing:t1"),
+            Value::from("orders"),
+            Value::from("billing:t2"),
+        ]
+        .into();
+        let res = strip_tables(&res, "billing:");
+        assert_eq!(res.values, [Value::from("t1"), Value::from("t2")]);
+
+        let res: CommandResponse = vec![
+            Kvpair::new("billing:t1", 1.into()),
+            Kvpair::new("orders", 2.into()),
+        ]
+        .into();
+        let res = strip_tables(&res, "billing:");
+        assert_eq!(res.pairs, [Kvpair::new("t1", 1.into())]);
+    }
+}
@@ -158,10 +158,10 @@ mod topic_test {
         let res1 = stream1.recv().await.unwrap();
         let res2 = stream2.recv().await.unwrap();
         assert_eq!(res1, res2);
-        assert_res_ok(&res1, &[value.clone()], &[]);
+        assert_res_ok(&res1, std::slice::from_ref(&value), &[]);
 
         // 如果 subscription 取消订阅，则收不到新数据
-        broad.clone().unsubscribe(lobby, id1 as _);
+        broad.clone().unsubscribe(lobby, id1 as _).unwrap();
 
         // // 再一次发布
         let value: Value = "world".into();
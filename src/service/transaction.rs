@@ -0,0 +1,493 @@
+use std::collections::{BTreeSet, HashMap};
+
+use bytes::Bytes;
+
+use super::{
+    dispatch,
+    replication::{records_of, written_keys},
+    session::Session,
+    Service,
+};
+use crate::{
+    error::KvError,
+    memory::MemTable,
+    pb::{
+        abi::{
+            command_request::RequestData, Batch, CommandRequest, CommandResponse, Value, WalRecord,
+        },
+        to_key,
+    },
+    storage::{wal::apply, KeyVersion},
+    Storage,
+};
+
+/// 事务读过的 key 被并发修改时，最多重新执行的次数
+const MAX_TRANSACTION_RETRIES: usize = 16;
+
+impl<Store: Storage> Service<Store> {
+    /// 处理 MULTI：之后这个 session 上的命令都会排队
+    pub(super) fn multi(&self, cmd: &CommandRequest, session: &Session) -> CommandResponse {
+        if let Err(e) = self.authorize(cmd, session) {
+            return e.into();
+        }
+        match session.begin_transaction() {
+            true => CommandResponse::ok(),
+            false => KvError::InvalidCommand("MULTI calls can not be nested".into()).into(),
+        }
+    }
+
+    /// 事务中的命令只检查权限，然后排队；出错的话整个事务在 EXEC 时失败
+    pub(super) fn queue(&self, cmd: CommandRequest, session: &Session) -> CommandResponse {
+        let checked = match cmd.request_data.as_ref().and_then(touched_keys) {
+            Some(_) => self.authorize(&cmd, session),
+            None => Err(KvError::InvalidCommand(
+                "Command is not allowed in transaction".into(),
+            )),
+        };
+        match checked {
+            Ok(()) => {
+                session.queue(cmd);
+                Value::from("QUEUED").into()
+            }
+            Err(e) => {
+                session.abort_transaction();
+                e.into()
+            }
+        }
+    }
+
+    pub(super) fn discard(&self, session: &Session) -> CommandResponse {
+        match session.take_transaction() {
+            Some(_) => {
+                session.unwatch();
+                CommandResponse::ok()
+            }
+            None => KvError::InvalidCommand("DISCARD without MULTI".into()).into(),
+        }
+    }
+
+    /// 处理 WATCH：记下 key 当前的版本号，EXEC 时用来判断 key 有没有被修改过
+    pub(super) fn watch(&self, cmd: &CommandRequest, session: &Session) -> CommandResponse {
+        let Some(RequestData::Watch(watch)) = &cmd.request_data else {
+            return KvError::Internal("not a WATCH command".into()).into();
+        };
+        if let Err(e) = self.authorize(cmd, session) {
+            return e.into();
+        }
+        let keys: Result<Vec<KeyVersion>, KvError> = watch
+            .keys
+            .iter()
+            .map(|key| version_of(&self.store, &watch.table, key))
+            .collect();
+        match keys {
+            Ok(keys) => {
+                session.watch(keys);
+                CommandResponse::ok()
+            }
+            Err(e) => e.into(),
+        }
+    }
+
+    /// 处理 EXEC：依次执行排队的命令，所有修改通过 Storage::apply_batch 一起生效
+    pub(super) fn exec(&self, cmd: &CommandRequest, session: &Session) -> CommandResponse {
+        if let Err(e) = self.authorize(cmd, session) {
+            return e.into();
+        }
+        let transaction = match session.take_transaction() {
+            Some(transaction) if transaction.aborted => {
+                session.unwatch();
+                return KvError::InvalidCommand(
+                    "EXECABORT Transaction discarded because of previous errors".into(),
+                )
+                .into();
+            }
+            Some(transaction) => transaction,
+            None => return KvError::InvalidCommand("EXEC without MULTI".into()).into(),
+        };
+
+        let watched = session.unwatch();
+        let batch = RequestData::Batch(Batch {
+            commands: transaction.commands,
+        });
+        let results = self.keyspace.track(&batch, &self.store, || {
+            execute_transaction(&self.store, &batch, &watched)
+        });
+        let res: CommandResponse = match results {
+            Ok(results) => results.into(),
+            Err(e) => return e.into(),
+        };
+        // 和单独执行的命令一样，事务中的每个命令都记到统计和审计日志中
+        self.stats.record(&batch, &res);
+        self.audit.record(&batch, &res, session, &self.broadcaster);
+        if let Err(e) = self.replicator.record(&batch, &self.store) {
+            return e.into();
+        }
+        res
+    }
+}
+
+/// 先把命令涉及的数据复制到一个临时的 MemTable 上执行，这样命令可以读到前面命令的修改，
+/// 然后把修改过的 key 的最终状态原子地写回 store。
+/// 复制之后读过的 key 被别人修改了的话重新执行；WATCH 的 key 被修改了则直接失败
+fn execute_transaction(
+    store: &impl Storage,
+    batch: &RequestData,
+    watched: &[KeyVersion],
+) -> Result<Vec<CommandResponse>, KvError> {
+    let RequestData::Batch(Batch { commands }) = batch else {
+        return Err(KvError::Internal("transaction must be a batch".into()));
+    };
+    for _ in 0..MAX_TRANSACTION_RETRIES {
+        if watched_changed(store, watched)? {
+            return Err(KvError::Conflict("watched keys have been modified".into()));
+        }
+
+        let mut keys = BTreeSet::new();
+        for (table, key) in touched_keys(batch).unwrap_or_default() {
+            match key {
+                Some(key) => {
+                    keys.insert((table, to_key(key)));
+                }
+                None => keys.extend(store.get_iter(table)?.map(|pair| (table, pair.key))),
+            }
+        }
+        // 版本号要在读取数据之前获取，这样读到的数据一定不比版本号旧
+        let mut versions = watched.to_vec();
+        for (table, key) in &keys {
+            versions.push(version_of(store, table, key)?);
+        }
+        let keys: Vec<(&str, &[u8])> = keys.iter().map(|(t, k)| (*t, &k[..])).collect();
+        let scratch = MemTable::new();
+        for record in records_of(store, &keys)? {
+            apply(&scratch, record)?;
+        }
+
+        let results = commands
+            .iter()
+            .map(|cmd| dispatch(cmd.clone(), &scratch))
+            .collect();
+        let written = written_keys(batch);
+        if store.apply_batch(records_of(&scratch, &written)?, &versions)? {
+            return Ok(results);
+        }
+    }
+    Err(KvError::Conflict(
+        "too many concurrent modifications, transaction is not executed".into(),
+    ))
+}
+
+/// 只写 key 的命令：除了返回旧的值不需要读取数据，可以直接转换成 WalRecord
+pub(super) fn is_plain_write(cmd: &RequestData) -> bool {
+    match cmd {
+        RequestData::Hset(v) => v.pair.is_some() && !v.nx && !v.xx && v.ttl == 0 && v.lease == 0,
+        RequestData::Hmset(_) | RequestData::Hdel(_) | RequestData::Hmdel(_) => true,
+        _ => false,
+    }
+}
+
+/// 原子地执行一组 is_plain_write 的命令：先获取 key 的版本号再读出旧的值，所有的修改用一次 apply_batch 提交，
+/// 期间 key 被并发修改时重新读取。提交失败时每个命令都返回同样的错误
+pub(super) fn apply_writes(store: &impl Storage, cmds: &[RequestData]) -> Vec<CommandResponse> {
+    match try_apply_writes(store, cmds) {
+        Ok(results) => results,
+        Err(e) => vec![e.into(); cmds.len()],
+    }
+}
+
+fn try_apply_writes(
+    store: &impl Storage,
+    cmds: &[RequestData],
+) -> Result<Vec<CommandResponse>, KvError> {
+    let mut keys = BTreeSet::new();
+    for cmd in cmds {
+        for (table, key) in touched_keys(cmd).unwrap_or_default() {
+            if let Some(key) = key {
+                keys.insert((table, to_key(key)));
+            }
+        }
+    }
+    for _ in 0..MAX_TRANSACTION_RETRIES {
+        let mut versions = Vec::with_capacity(keys.len());
+        for (table, key) in &keys {
+            versions.push(version_of(store, table, key)?);
+        }
+        let mut current = HashMap::with_capacity(keys.len());
+        for (table, key) in &keys {
+            current.insert((*table, key.clone()), store.get(*table, key)?);
+        }
+        let mut records = Vec::new();
+        let results = cmds
+            .iter()
+            .map(|cmd| plain_write(cmd, &mut current, &mut records))
+            .collect();
+        if store.apply_batch(records, &versions)? {
+            return Ok(results);
+        }
+    }
+    Err(KvError::Conflict(
+        "too many concurrent modifications, batch is not executed".into(),
+    ))
+}
+
+/// 把命令的修改加到 records 中，current 是 key 当前的值；返回和直接执行命令一样的结果
+fn plain_write<'a>(
+    cmd: &'a RequestData,
+    current: &mut HashMap<(&'a str, Bytes), Option<Value>>,
+    records: &mut Vec<WalRecord>,
+) -> CommandResponse {
+    let mut write = |table: &'a str, key: &Bytes, value: Option<Value>| {
+        records.push(match &value {
+            Some(value) => WalRecord::new_set(table, key, value.clone()),
+            None => WalRecord::new_del(table, key),
+        });
+        current
+            .insert((table, key.clone()), value)
+            .flatten()
+            .unwrap_or_default()
+    };
+    match cmd {
+        RequestData::Hset(v) => match &v.pair {
+            Some(pair) => write(
+                &v.table,
+                &pair.key,
+                Some(pair.value.clone().unwrap_or_default()),
+            )
+            .into(),
+            None => KvError::InvalidCommand("Hset has no pair".into()).into(),
+        },
+        RequestData::Hmset(v) => {
+            for pair in &v.pairs {
+                write(
+                    &v.table,
+                    &pair.key,
+                    Some(pair.value.clone().unwrap_or_default()),
+                );
+            }
+            CommandResponse::ok()
+        }
+        RequestData::Hdel(v) => write(&v.table, &v.key, None).into(),
+        RequestData::Hmdel(v) => v
+            .keys
+            .iter()
+            .map(|key| write(&v.table, key, None))
+            .collect::<Vec<_>>()
+            .into(),
+        _ => KvError::Internal(format!("{} is not a plain write", cmd.name())).into(),
+    }
+}
+
+pub(super) fn version_of(
+    store: &impl Storage,
+    table: &str,
+    key: &[u8],
+) -> Result<KeyVersion, KvError> {
+    Ok(KeyVersion {
+        table: table.into(),
+        key: to_key(key),
+        version: store.version(table, key)?,
+    })
+}
+
+fn watched_changed(store: &impl Storage, watched: &[KeyVersion]) -> Result<bool, KvError> {
+    for w in watched {
+        if store.version(w.table.as_str(), &w.key)? != w.version {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// 命令读写的 <table, key>，key 为 None 表示整个 table
+type TouchedKey<'a> = (&'a str, Option<&'a [u8]>);
+
+/// 命令会读写哪些 key；返回 None 表示命令不能放在事务里
+pub(super) fn touched_keys(cmd: &RequestData) -> Option<Vec<TouchedKey<'_>>> {
+    let keys = match cmd {
+        RequestData::Hget(v) => vec![(v.table.as_str(), Some(&v.key[..]))],
+        RequestData::Hgetall(v) => vec![(v.table.as_str(), None)],
+        RequestData::Hscan(v) => vec![(v.table.as_str(), None)],
+        RequestData::Hrange(v) => vec![(v.table.as_str(), None)],
+        RequestData::Hscanprefix(v) => vec![(v.table.as_str(), None)],
+        RequestData::Hmget(v) => v
+            .keys
+            .iter()
+            .map(|k| (v.table.as_str(), Some(&k[..])))
+            .collect(),
+        // 关联 lease 时还要修改 lease 的元数据
+        RequestData::Hset(v) if v.lease != 0 => return None,
+        RequestData::Hset(v) => v
+            .pair
+            .iter()
+            .map(|pair| (v.table.as_str(), Some(&pair.key[..])))
+            .collect(),
+        RequestData::Hmset(v) => v
+            .pairs
+            .iter()
+            .map(|pair| (v.table.as_str(), Some(&pair.key[..])))
+            .collect(),
+        RequestData::Hcas(v) => vec![(v.table.as_str(), Some(&v.key[..]))],
+        RequestData::Lock(v) => vec![(v.table.as_str(), Some(v.name.as_bytes()))],
+        RequestData::Unlock(v) => vec![(v.table.as_str(), Some(v.name.as_bytes()))],
+        RequestData::Hincrby(v) => vec![(v.table.as_str(), Some(&v.key[..]))],
+        RequestData::Happend(v) => vec![(v.table.as_str(), Some(&v.key[..]))],
+        RequestData::Hstrlen(v) => vec![(v.table.as_str(), Some(&v.key[..]))],
+        RequestData::Hgetrange(v) => vec![(v.table.as_str(), Some(&v.key[..]))],
+        RequestData::Hsetbit(v) => vec![(v.table.as_str(), Some(&v.key[..]))],
+        RequestData::Hgetbit(v) => vec![(v.table.as_str(), Some(&v.key[..]))],
+        RequestData::Hbitcount(v) => vec![(v.table.as_str(), Some(&v.key[..]))],
+        RequestData::Hpfadd(v) => vec![(v.table.as_str(), Some(&v.key[..]))],
+        RequestData::Eval(v) => v
+            .keys
+            .iter()
+            .map(|k| (v.table.as_str(), Some(&k[..])))
+            .collect(),
+        RequestData::Evalsha(v) => v
+            .keys
+            .iter()
+            .map(|k| (v.table.as_str(), Some(&k[..])))
+            .collect(),
+        RequestData::Hpfcount(v) => v
+            .keys
+            .iter()
+            .map(|k| (v.table.as_str(), Some(&k[..])))
+            .collect(),
+        RequestData::Hdel(v) => vec![(v.table.as_str(), Some(&v.key[..]))],
+        RequestData::Hexist(v) => vec![(v.table.as_str(), Some(&v.key[..]))],
+        RequestData::Hexpire(v) => vec![(v.table.as_str(), Some(&v.key[..]))],
+        RequestData::Httl(v) => vec![(v.table.as_str(), Some(&v.key[..]))],
+        RequestData::Lpush(v) => vec![(v.table.as_str(), Some(&v.key[..]))],
+        RequestData::Lrange(v) => vec![(v.table.as_str(), Some(&v.key[..]))],
+        RequestData::Sadd(v) => vec![(v.table.as_str(), Some(&v.key[..]))],
+        RequestData::Smembers(v) => vec![(v.table.as_str(), Some(&v.key[..]))],
+        RequestData::MapGet(v) => vec![(v.table.as_str(), Some(&v.key[..]))],
+        RequestData::MapSet(v) => vec![(v.table.as_str(), Some(&v.key[..]))],
+        RequestData::Hjsonget(v) => vec![(v.table.as_str(), Some(&v.key[..]))],
+        RequestData::Hjsonset(v) => vec![(v.table.as_str(), Some(&v.key[..]))],
+        RequestData::Batch(v) => {
+            let mut keys = Vec::new();
+            for cmd in &v.commands {
+                keys.extend(cmd.request_data.as_ref().and_then(touched_keys)?);
+            }
+            keys
+        }
+        _ => return None,
+    };
+    Some(keys)
+}
+
+#[cfg(test)]
+mod transaction_tests {
+    use futures::StreamExt;
+
+    use super::*;
+    use crate::{assert_res_error, assert_res_ok, service_builder::ServiceBuilder};
+
+    #[tokio::test]
+    async fn exec_should_apply_queued_commands() {
+        let service: Service = ServiceBuilder::default().finish();
+        let session = Session::new();
+        let execute = |cmd: CommandRequest| {
+            let res = service.execute_with(cmd, &session);
+            async move { res.into_future().await.0.unwrap() }
+        };
+        execute(CommandRequest::new_hset("t1", "k1", 10i64)).await;
+
+        let res = execute(CommandRequest::new_multi()).await;
+        assert_res_ok(&res, &[], &[]);
+        let res = execute(CommandRequest::new_hincrby("t1", "k1", 5)).await;
+        assert_res_ok(&res, &["QUEUED".into()], &[]);
+        execute(CommandRequest::new_hset("t1", "k2", "v2")).await;
+        execute(CommandRequest::new_hget("t1", "k1")).await;
+
+        // EXEC 之前其它连接看不到修改
+        let res = service.execute(CommandRequest::new_hget("t1", "k2"));
+        assert_eq!(res.into_future().await.0.unwrap().status, 404);
+
+        let res = execute(CommandRequest::new_exec()).await;
+        assert_eq!(res.results.len(), 3);
+        assert_res_ok(&res.results[0], &[15i64.into()], &[]);
+        assert_res_ok(&res.results[2], &[15i64.into()], &[]);
+        let res = service.execute(CommandRequest::new_hget("t1", "k2"));
+        assert_res_ok(&res.into_future().await.0.unwrap(), &["v2".into()], &[]);
+
+        // 事务结束之后命令会直接执行
+        let res = execute(CommandRequest::new_hget("t1", "k1")).await;
+        assert_res_ok(&res, &[15i64.into()], &[]);
+    }
+
+    #[tokio::test]
+    async fn discard_and_errors_should_abort_transaction() {
+        let service: Service = ServiceBuilder::default().finish();
+        let session = Session::new();
+        let execute = |cmd: CommandRequest| {
+            let res = service.execute_with(cmd, &session);
+            async move { res.into_future().await.0.unwrap() }
+        };
+
+        let res = execute(CommandRequest::new_exec()).await;
+        assert_res_error(&res, 400, "EXEC without MULTI");
+
+        execute(CommandRequest::new_multi()).await;
+        let res = execute(CommandRequest::new_multi()).await;
+        assert_res_error(&res, 400, "MULTI calls can not be nested");
+        execute(CommandRequest::new_hset("t1", "k1", "v1")).await;
+        let res = execute(CommandRequest::new_discard()).await;
+        assert_res_ok(&res, &[], &[]);
+        let res = execute(CommandRequest::new_hget("t1", "k1")).await;
+        assert_eq!(res.status, 404);
+
+        execute(CommandRequest::new_multi()).await;
+        execute(CommandRequest::new_hset("t1", "k1", "v1")).await;
+        let res = execute(CommandRequest::new_snapshot("/tmp/kv.snapshot")).await;
+        assert_res_error(&res, 400, "not allowed in transaction");
+        let res = execute(CommandRequest::new_exec()).await;
+        assert_res_error(&res, 400, "EXECABORT");
+        let res = execute(CommandRequest::new_hget("t1", "k1")).await;
+        assert_eq!(res.status, 404);
+    }
+
+    #[tokio::test]
+    async fn exec_should_fail_when_watched_key_changed() {
+        let service: Service = ServiceBuilder::default().finish();
+        let session = Session::new();
+        let execute = |cmd: CommandRequest| {
+            let res = service.execute_with(cmd, &session);
+            async move { res.into_future().await.0.unwrap() }
+        };
+        execute(CommandRequest::new_hset("t1", "k1", "v1")).await;
+
+        execute(CommandRequest::new_watch("t1", ["k1", "k2"])).await;
+        // 其它连接修改了被监视的 key
+        service
+            .execute(CommandRequest::new_hset("t1", "k2", "other"))
+            .next()
+            .await;
+        execute(CommandRequest::new_multi()).await;
+        execute(CommandRequest::new_hset("t1", "k1", "v2")).await;
+        let res = execute(CommandRequest::new_exec()).await;
+        assert_res_error(&res, 409, "watched keys have been modified");
+        let res = execute(CommandRequest::new_hget("t1", "k1")).await;
+        assert_res_ok(&res, &["v1".into()], &[]);
+
+        // EXEC 之后监视自动取消，没有被修改的 key 可以正常执行
+        execute(CommandRequest::new_watch("t1", ["k1"])).await;
+        execute(CommandRequest::new_multi()).await;
+        execute(CommandRequest::new_hset("t1", "k1", "v2")).await;
+        let res = execute(CommandRequest::new_exec()).await;
+        assert_eq!(res.results.len(), 1);
+        let res = execute(CommandRequest::new_hget("t1", "k1")).await;
+        assert_res_ok(&res, &["v2".into()], &[]);
+
+        // UNWATCH 之后修改不会影响事务
+        execute(CommandRequest::new_watch("t1", ["k1"])).await;
+        execute(CommandRequest::new_unwatch()).await;
+        service
+            .execute(CommandRequest::new_hdel("t1", "k1"))
+            .next()
+            .await;
+        execute(CommandRequest::new_multi()).await;
+        execute(CommandRequest::new_hget("t1", "k2")).await;
+        let res = execute(CommandRequest::new_exec()).await;
+        assert_res_ok(&res.results[0], &["other".into()], &[]);
+    }
+}
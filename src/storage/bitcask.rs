@@ -0,0 +1,1534 @@
+use super::{
+    incr_value,
+    index::{check_spec, index_term, Index},
+    is_empty_range, now_millis,
+    zset::{check_score, SortedSet},
+    KeyVersion, Storage, TableStats,
+};
+use crate::{
+    config::{parse_size, BitcaskConfig, FsyncPolicy},
+    error::KvError,
+    pb::{
+        abi::{wal_record::Op, HintRecord, IndexSpec, Kvpair, ScoredMember, Value, WalRecord},
+        to_key,
+    },
+};
+use bytes::Bytes;
+use prost::Message;
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::{self, File, OpenOptions},
+    io::{self, BufWriter, Write},
+    ops::{Bound, Deref, RangeBounds},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard, Weak},
+    thread,
+    time::{Duration, Instant},
+};
+use tracing::{info, warn};
+
+/// 默认单个数据文件的大小上限
+const DEFAULT_MAX_FILE_SIZE: usize = 64 << 20;
+
+/// 默认失效的数据超过一半时合并
+const DEFAULT_MERGE_RATIO: f64 = 0.5;
+
+/// 默认每分钟检查一次是否需要合并
+const DEFAULT_MERGE_INTERVAL: u64 = 60;
+
+/// 失效的数据少于这个值时不合并，避免数据很少时反复合并
+const MIN_MERGE_BYTES: u64 = 1 << 20;
+
+/// EverySec 策略下两次 fsync 之间的间隔
+const FSYNC_INTERVAL: Duration = Duration::from_secs(1);
+
+/// 日志结构的存储引擎：所有的修改都以 WalRecord 的形式追加写入数据文件，
+/// 内存中的 keydir 记录每个 key 最新的 value 在数据文件中的位置，读取时直接从文件中读出来。
+/// sorted set 和索引保存在内存中，启动时从数据文件重建。
+/// 被覆盖或删除的 value 仍然占用数据文件，后台定期把仍然有效的数据合并到一个新的数据文件，
+/// 同时写一个 hint 文件，下次启动时只需要读取 hint 文件
+pub struct Bitcask {
+    inner: Arc<Engine>,
+}
+
+pub struct Engine {
+    dir: PathBuf,
+    fsync: FsyncPolicy,
+    max_file_size: u64,
+    merge_ratio: f64,
+    state: RwLock<State>,
+    /// 同一时刻只有一个合并在进行
+    merging: Mutex<()>,
+}
+
+/// 一条 SET 在数据文件中的位置：item 不为 None 时 SET 是 batch 记录中的第 item 条
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Location {
+    file: u64,
+    offset: u64,
+    len: u64,
+    item: Option<u32>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Entry {
+    loc: Location,
+    /// 过期时刻的毫秒时间戳
+    deadline: Option<u64>,
+    /// 这个 key 在数据文件中占用的字节数，被覆盖或删除之后计入失效的数据
+    size: u64,
+}
+
+impl Entry {
+    fn is_live(&self, now: u64) -> bool {
+        self.deadline.is_none_or(|d| d > now)
+    }
+}
+
+#[derive(Debug)]
+struct State {
+    active: File,
+    active_id: u64,
+    active_len: u64,
+    last_sync: Instant,
+    /// 所有数据文件（包括正在写入的）的只读句柄
+    files: BTreeMap<u64, File>,
+    keydir: HashMap<String, BTreeMap<Bytes, Entry>>,
+    /// key 的版本号，每次修改都会加一；删除之后也保留，重启之后从 0 开始
+    versions: HashMap<String, HashMap<Bytes, u64>>,
+    zsets: HashMap<String, BTreeMap<Bytes, SortedSet>>,
+    /// table 上的索引，<table, <索引名, index>>
+    indexes: HashMap<String, BTreeMap<String, Index>>,
+    /// 数据文件的总字节数，以及其中已经失效的字节数
+    total: u64,
+    dead: u64,
+}
+
+impl Bitcask {
+    pub fn open(config: &BitcaskConfig) -> Result<Self, KvError> {
+        let (max_file_size, merge_ratio) = parse_config(config)?;
+        let engine = Engine::open(
+            &config.path,
+            config.fsync,
+            max_file_size as u64,
+            merge_ratio,
+        )?;
+        Ok(Self {
+            inner: Arc::new(engine),
+        })
+    }
+
+    /// 在后台线程中每隔 interval 秒检查一次，失效的数据足够多时合并数据文件，store 被丢弃之后线程退出
+    pub fn spawn(&self, interval: Option<u64>) {
+        let interval = Duration::from_secs(interval.unwrap_or(DEFAULT_MERGE_INTERVAL));
+        let engine: Weak<Engine> = Arc::downgrade(&self.inner);
+        thread::spawn(move || loop {
+            thread::sleep(interval);
+            let Some(engine) = engine.upgrade() else {
+                break;
+            };
+            if !engine.needs_merge() {
+                continue;
+            }
+            if let Err(e) = engine.merge() {
+                warn!("Failed to merge bitcask data files: {:?}", e);
+            }
+        });
+    }
+}
+
+impl Deref for Bitcask {
+    type Target = Engine;
+
+    fn deref(&self) -> &Self::Target {
+        &self.inner
+    }
+}
+
+impl Engine {
+    fn open(
+        dir: impl AsRef<Path>,
+        fsync: FsyncPolicy,
+        max_file_size: u64,
+        merge_ratio: f64,
+    ) -> Result<Self, KvError> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+
+        let mut ids = Vec::new();
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            match path.extension().and_then(|e| e.to_str()) {
+                // 合并到一半时留下的临时文件
+                Some("tmp") => fs::remove_file(&path)?,
+                Some("data") => {
+                    if let Some(id) = file_id(&path) {
+                        ids.push(id);
+                    }
+                }
+                _ => {}
+            }
+        }
+        ids.sort_unstable();
+
+        let mut files = BTreeMap::new();
+        for id in &ids {
+            files.insert(*id, File::open(data_path(&dir, *id))?);
+        }
+        // 空的数据文件（比如上次启动之后没有写入）直接接着写，否则每次启动都换一个新的文件
+        let last = ids.last().copied().unwrap_or_default();
+        let active_id = match files.get(&last) {
+            Some(file) if file.metadata()?.len() == 0 => last,
+            _ => last + 1,
+        };
+        let active = OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(data_path(&dir, active_id))?;
+        files.insert(active_id, File::open(data_path(&dir, active_id))?);
+
+        let mut state = State {
+            active,
+            active_id,
+            active_len: 0,
+            last_sync: Instant::now(),
+            files,
+            keydir: HashMap::new(),
+            versions: HashMap::new(),
+            zsets: HashMap::new(),
+            indexes: HashMap::new(),
+            total: 0,
+            dead: 0,
+        };
+        for id in ids {
+            let hint = hint_path(&dir, id);
+            if hint.exists() {
+                state.load_hints(id, &fs::read(hint)?)?;
+                state.total += state.files[&id].metadata()?.len();
+                continue;
+            }
+            let data = fs::read(data_path(&dir, id))?;
+            let len = state.replay(id, &data)?;
+            if len < data.len() {
+                warn!(
+                    "Ignore {} bytes of broken records in bitcask data file {}",
+                    data.len() - len,
+                    id
+                );
+            }
+            state.total += data.len() as u64;
+        }
+        info!(
+            "Loaded {} keys from {} bitcask data files",
+            state.keydir.values().map(|keys| keys.len()).sum::<usize>(),
+            state.files.len()
+        );
+
+        Ok(Self {
+            dir,
+            fsync,
+            max_file_size,
+            merge_ratio,
+            state: RwLock::new(state),
+            merging: Mutex::new(()),
+        })
+    }
+
+    fn read(&self) -> RwLockReadGuard<'_, State> {
+        self.state.read().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn write(&self) -> RwLockWriteGuard<'_, State> {
+        self.state.write().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// 读取 key 之前先删除已经过期的 key，和 MemTable 的惰性过期一样，版本号会加一
+    fn read_key(&self, table: &str, key: &[u8]) -> RwLockReadGuard<'_, State> {
+        let state = self.read();
+        let now = now_millis();
+        let expired = state
+            .keydir
+            .get(table)
+            .and_then(|keys| keys.get(key))
+            .is_some_and(|entry| !entry.is_live(now));
+        if !expired {
+            return state;
+        }
+        drop(state);
+        self.write().remove_if_expired(table, key);
+        self.read()
+    }
+
+    /// 遍历 table 之前先删除其中已经过期的 key
+    fn read_table(&self, table: &str) -> RwLockReadGuard<'_, State> {
+        let state = self.read();
+        let now = now_millis();
+        let expired = state
+            .keydir
+            .get(table)
+            .is_some_and(|keys| keys.values().any(|entry| !entry.is_live(now)));
+        if !expired {
+            return state;
+        }
+        drop(state);
+        self.write().remove_expired_in(table);
+        self.read()
+    }
+
+    /// 失效的数据是否已经多到需要合并
+    pub fn needs_merge(&self) -> bool {
+        let state = self.read();
+        state.dead >= MIN_MERGE_BYTES && state.dead as f64 >= state.total as f64 * self.merge_ratio
+    }
+
+    /// 把除了正在写入的文件之外的所有数据文件中仍然有效的数据写到一个新的数据文件，同时写一个 hint 文件，
+    /// 然后删除旧的数据文件，返回回收的字节数。合并期间读写照常进行
+    pub fn merge(&self) -> Result<u64, KvError> {
+        let _merging = self.merging.lock().unwrap_or_else(|e| e.into_inner());
+
+        // 换一个新的文件接着写，合并的结果放在旧的文件和新的文件之间，这样重放的顺序仍然是对的
+        let (merged, sealed, live, zsets, indexes) = {
+            let mut state = self.write();
+            let merged = state.active_id + 1;
+            self.rotate(&mut state, merged + 1)?;
+            let sealed: Vec<u64> = state.files.range(..merged).map(|(id, _)| *id).collect();
+            let now = now_millis();
+            let live: Vec<(String, Bytes, Entry)> = state
+                .keydir
+                .iter()
+                .flat_map(|(table, keys)| {
+                    keys.iter()
+                        .filter(|(_, entry)| entry.is_live(now))
+                        .map(|(key, entry)| (table.clone(), key.clone(), *entry))
+                })
+                .collect();
+            let zsets: Vec<WalRecord> = state
+                .zsets
+                .iter()
+                .flat_map(|(table, zsets)| {
+                    zsets.iter().map(|(key, set)| {
+                        WalRecord::new_zadd(table.as_str(), key, set.range(0, -1))
+                    })
+                })
+                .collect();
+            let indexes: Vec<WalRecord> = state
+                .indexes
+                .iter()
+                .flat_map(|(table, indexes)| {
+                    indexes.values().map(|index| {
+                        WalRecord::new_create_index(table.as_str(), index.spec.clone())
+                    })
+                })
+                .collect();
+            (merged, sealed, live, zsets, indexes)
+        };
+
+        let data_tmp = tmp_path(&data_path(&self.dir, merged));
+        let hint_tmp = tmp_path(&hint_path(&self.dir, merged));
+        let mut file = MergeFile {
+            data: BufWriter::new(File::create(&data_tmp)?),
+            hints: BufWriter::new(File::create(&hint_tmp)?),
+            len: 0,
+        };
+        let mut moved = Vec::with_capacity(live.len());
+        for (table, key, entry) in live {
+            // 读取的时候 key 可能已经被覆盖了，这时新的值在新的文件里，重放时会覆盖合并的结果
+            let value = self.read().read(&entry.loc)?;
+            let record = WalRecord::new_set(&table, &key, value);
+            let deadline = entry.deadline.unwrap_or_default();
+            let (offset, len) = file.write(&record, Some((true, deadline)))?;
+            if let Some(deadline) = entry.deadline {
+                // 没有 hint 文件时也能从数据文件恢复过期时间，hint 文件中不需要这条记录
+                file.write(&WalRecord::new_expire(&table, &key, deadline), None)?;
+            }
+            let loc = Location {
+                file: merged,
+                offset,
+                len,
+                item: None,
+            };
+            moved.push((table, key, entry.loc, loc));
+        }
+        // 索引放在最后，重建索引的时候 key 都已经加载了
+        for record in zsets.iter().chain(indexes.iter()) {
+            file.write(record, Some((false, 0)))?;
+        }
+        let written = file.finish()?;
+        // 先有数据文件再有 hint 文件；在删除旧文件之前崩溃的话，重放旧文件再重放合并的结果，结果不变
+        fs::rename(&data_tmp, data_path(&self.dir, merged))?;
+        fs::rename(&hint_tmp, hint_path(&self.dir, merged))?;
+
+        let mut state = self.write();
+        state
+            .files
+            .insert(merged, File::open(data_path(&self.dir, merged))?);
+        for (table, key, old, loc) in moved {
+            let Some(entry) = state
+                .keydir
+                .get_mut(&table)
+                .and_then(|keys| keys.get_mut(&key))
+            else {
+                continue;
+            };
+            if entry.loc == old {
+                entry.loc = loc;
+                entry.size = loc.len;
+            }
+        }
+        // 还指向旧文件的 key 在合并开始时就已经过期了
+        let expired: Vec<(String, Bytes)> = state
+            .keydir
+            .iter()
+            .flat_map(|(table, keys)| {
+                keys.iter()
+                    .filter(|(_, entry)| entry.loc.file < merged)
+                    .map(|(key, _)| (table.clone(), key.clone()))
+            })
+            .collect();
+        for (table, key) in expired {
+            state.remove(&table, &key);
+        }
+        let mut removed = 0;
+        for id in &sealed {
+            if let Some(file) = state.files.remove(id) {
+                removed += file.metadata()?.len();
+            }
+            fs::remove_file(data_path(&self.dir, *id))?;
+            let hint = hint_path(&self.dir, *id);
+            if hint.exists() {
+                fs::remove_file(hint)?;
+            }
+        }
+        // 合并开始之后失效的数据都在新的文件中，这里只能估算
+        state.total = (state.total + written).saturating_sub(removed);
+        state.dead = state.dead.min(state.active_len);
+        let reclaimed = removed.saturating_sub(written);
+        info!(
+            "Merged {} bitcask data files, {} bytes reclaimed",
+            sealed.len(),
+            reclaimed
+        );
+        Ok(reclaimed)
+    }
+
+    /// 把记录追加到正在写入的文件，然后应用到内存中，返回 State::apply 的结果
+    fn append(&self, state: &mut State, record: WalRecord) -> Result<usize, KvError> {
+        let buf = record.encode_length_delimited_to_vec();
+        state.active.write_all(&buf)?;
+        let loc = Location {
+            file: state.active_id,
+            offset: state.active_len,
+            len: buf.len() as u64,
+            item: None,
+        };
+        state.active_len += loc.len;
+        state.total += loc.len;
+
+        let sync = match self.fsync {
+            FsyncPolicy::Always => true,
+            FsyncPolicy::EverySec => state.last_sync.elapsed() >= FSYNC_INTERVAL,
+            FsyncPolicy::No => false,
+        };
+        if sync {
+            state.active.sync_data()?;
+            state.last_sync = Instant::now();
+        }
+        let result = state.apply(record, loc, loc.len)?;
+        if state.active_len >= self.max_file_size {
+            let next = state.active_id + 1;
+            self.rotate(state, next)?;
+        }
+        Ok(result)
+    }
+
+    /// 把正在写入的文件刷到磁盘，换成编号为 id 的新文件
+    fn rotate(&self, state: &mut State, id: u64) -> Result<(), KvError> {
+        state.active.sync_data()?;
+        let path = data_path(&self.dir, id);
+        state.active = OpenOptions::new().append(true).create(true).open(&path)?;
+        state.files.insert(id, File::open(&path)?);
+        state.active_id = id;
+        state.active_len = 0;
+        state.last_sync = Instant::now();
+        Ok(())
+    }
+
+    /// 修改 key 的值，key 有过期时间时再记录一次，SET 会清除过期时间
+    fn put_keeping_ttl(
+        &self,
+        state: &mut State,
+        table: &str,
+        key: &[u8],
+        value: Value,
+    ) -> Result<(), KvError> {
+        let record = WalRecord::new_set(table, key, value);
+        let record = match state.live(table, key).and_then(|entry| entry.deadline) {
+            Some(deadline) => {
+                WalRecord::new_batch(vec![record, WalRecord::new_expire(table, key, deadline)])
+            }
+            None => record,
+        };
+        self.append(state, record).map(|_| ())
+    }
+}
+
+impl State {
+    fn live(&self, table: &str, key: &[u8]) -> Option<&Entry> {
+        self.keydir
+            .get(table)?
+            .get(key)
+            .filter(|entry| entry.is_live(now_millis()))
+    }
+
+    fn get(&self, table: &str, key: &[u8]) -> Result<Option<Value>, KvError> {
+        self.live(table, key)
+            .map(|entry| self.read(&entry.loc))
+            .transpose()
+    }
+
+    /// 从数据文件中读出 loc 位置的 value
+    fn read(&self, loc: &Location) -> Result<Value, KvError> {
+        let record = self.read_record(loc)?;
+        let op = match (loc.item, record.op) {
+            (None, op) => op,
+            (Some(item), Some(Op::Batch(batch))) => batch
+                .records
+                .into_iter()
+                .nth(item as usize)
+                .and_then(|record| record.op),
+            _ => None,
+        };
+        match op {
+            Some(Op::Set(value)) => Ok(value),
+            _ => Err(KvError::Internal(format!(
+                "no value at offset {} of bitcask data file {}",
+                loc.offset, loc.file
+            ))),
+        }
+    }
+
+    fn read_record(&self, loc: &Location) -> Result<WalRecord, KvError> {
+        let file = self.files.get(&loc.file).ok_or_else(|| {
+            KvError::Internal(format!("bitcask data file {} is missing", loc.file))
+        })?;
+        let mut buf = vec![0; loc.len as usize];
+        read_exact_at(file, &mut buf, loc.offset)?;
+        Ok(WalRecord::decode_length_delimited(buf.as_slice())?)
+    }
+
+    /// 按 key 的字节序返回 range 之间最多 limit 个没有过期的 kv pair
+    fn range(
+        &self,
+        table: &str,
+        range: impl RangeBounds<Bytes>,
+        limit: usize,
+    ) -> Result<Vec<Kvpair>, KvError> {
+        let Some(keys) = self.keydir.get(table) else {
+            return Ok(vec![]);
+        };
+        if is_empty_range(&range) {
+            return Ok(vec![]);
+        }
+        let now = now_millis();
+        keys.range(range)
+            .filter(|(_, entry)| entry.is_live(now))
+            .take(limit)
+            .map(|(key, entry)| Ok(Kvpair::new(key.clone(), self.read(&entry.loc)?)))
+            .collect()
+    }
+
+    /// 依次重放数据文件中的记录，返回有效数据的长度
+    fn replay(&mut self, id: u64, data: &[u8]) -> Result<usize, KvError> {
+        let mut buf = data;
+        let mut len = 0;
+        while !buf.is_empty() {
+            let record = match WalRecord::decode_length_delimited(&mut buf) {
+                Ok(record) => record,
+                Err(_) => break,
+            };
+            let loc = Location {
+                file: id,
+                offset: len as u64,
+                len: (data.len() - buf.len() - len) as u64,
+                item: None,
+            };
+            len = data.len() - buf.len();
+            self.apply(record, loc, loc.len)?;
+        }
+        Ok(len)
+    }
+
+    /// 读取合并生成的数据文件对应的 hint 文件
+    fn load_hints(&mut self, id: u64, data: &[u8]) -> Result<(), KvError> {
+        let mut buf = data;
+        while !buf.is_empty() {
+            let hint = HintRecord::decode_length_delimited(&mut buf)?;
+            let loc = Location {
+                file: id,
+                offset: hint.offset,
+                len: hint.len,
+                item: None,
+            };
+            if !hint.set {
+                let record = self.read_record(&loc)?;
+                self.apply(record, loc, hint.len)?;
+                continue;
+            }
+            // 合并之后崩溃没来得及删除旧文件时，重放旧文件可能已经建立了索引
+            let value = if self.indexes.contains_key(&hint.table) {
+                Some(self.read(&loc)?)
+            } else {
+                None
+            };
+            let entry = Entry {
+                loc,
+                deadline: (hint.expire > 0).then_some(hint.expire),
+                size: hint.len,
+            };
+            self.put(&hint.table, hint.key, entry, value.as_ref());
+        }
+        Ok(())
+    }
+
+    /// 把写入数据文件 loc 位置的记录应用到内存中，返回影响的 key 或者 member 的数量；
+    /// size 是这条记录占用的字节数，batch 中的记录各占整条记录的一部分
+    fn apply(&mut self, record: WalRecord, loc: Location, size: u64) -> Result<usize, KvError> {
+        let (table, key) = (record.table, record.key);
+        let count = match record.op {
+            Some(Op::Set(value)) => {
+                let entry = Entry {
+                    loc,
+                    deadline: None,
+                    size,
+                };
+                self.put(&table, key, entry, Some(&value));
+                1
+            }
+            Some(Op::Del(_)) => {
+                self.dead += size;
+                let live = self.live(&table, &key).is_some();
+                self.remove(&table, &key);
+                live as usize
+            }
+            Some(Op::Expire(deadline)) => {
+                let entry = self
+                    .keydir
+                    .get_mut(&table)
+                    .and_then(|keys| keys.get_mut(&key));
+                match entry {
+                    Some(entry) => {
+                        entry.deadline = Some(deadline);
+                        entry.size += size;
+                        self.touch(&table, &key);
+                        1
+                    }
+                    None => {
+                        self.dead += size;
+                        0
+                    }
+                }
+            }
+            Some(Op::Batch(batch)) => {
+                let size = size / batch.records.len().max(1) as u64;
+                let mut count = 0;
+                for (item, record) in batch.records.into_iter().enumerate() {
+                    let loc = Location {
+                        item: Some(item as u32),
+                        ..loc
+                    };
+                    count += self.apply(record, loc, size)?;
+                }
+                count
+            }
+            Some(Op::Zadd(zadd)) => {
+                let set = self.zsets.entry(table).or_default().entry(key).or_default();
+                zadd.members
+                    .into_iter()
+                    .filter(|m| set.insert(m.member.clone(), m.score))
+                    .count()
+            }
+            Some(Op::CreateIndex(spec)) => self.build_index(table, spec)? as usize,
+            Some(Op::TruncateTable(_)) => {
+                self.dead += size;
+                self.clear_table(&table, false)
+            }
+            Some(Op::DropTable(_)) => {
+                self.dead += size;
+                self.clear_table(&table, true)
+            }
+            None => 0,
+        };
+        Ok(count)
+    }
+
+    /// 把 key 指向新的位置，value 是新的值，用于更新索引；table 没有索引时可以为 None
+    fn put(&mut self, table: &str, key: Bytes, entry: Entry, value: Option<&Value>) {
+        let keys = match self.keydir.get_mut(table) {
+            Some(keys) => keys,
+            None => self.keydir.entry(table.to_owned()).or_default(),
+        };
+        if let Some(old) = keys.insert(key.clone(), entry) {
+            self.dead += old.size;
+        }
+        self.touch(table, &key);
+        if let Some(indexes) = self.indexes.get_mut(table) {
+            indexes
+                .values_mut()
+                .for_each(|index| index.update(&key, value));
+        }
+    }
+
+    /// 从 keydir 中删除 key，不管是否已经过期
+    fn remove(&mut self, table: &str, key: &[u8]) -> Option<Entry> {
+        let entry = self.keydir.get_mut(table)?.remove(key)?;
+        self.dead += entry.size;
+        self.touch(table, key);
+        if let Some(indexes) = self.indexes.get_mut(table) {
+            indexes
+                .values_mut()
+                .for_each(|index| index.update(key, None));
+        }
+        Some(entry)
+    }
+
+    /// key 已经过期时从 keydir 中删除，返回 true
+    fn remove_if_expired(&mut self, table: &str, key: &[u8]) -> bool {
+        let expired = self
+            .keydir
+            .get(table)
+            .and_then(|keys| keys.get(key))
+            .is_some_and(|entry| !entry.is_live(now_millis()));
+        if expired {
+            self.remove(table, key);
+        }
+        expired
+    }
+
+    /// 删除 table 中所有已过期的 key
+    fn remove_expired_in(&mut self, table: &str) -> usize {
+        let now = now_millis();
+        let expired: Vec<Bytes> = match self.keydir.get(table) {
+            Some(keys) => keys
+                .iter()
+                .filter(|(_, entry)| !entry.is_live(now))
+                .map(|(key, _)| key.clone())
+                .collect(),
+            None => return 0,
+        };
+        for key in &expired {
+            self.remove(table, key);
+        }
+        expired.len()
+    }
+
+    fn touch(&mut self, table: &str, key: &[u8]) {
+        let versions = match self.versions.get_mut(table) {
+            Some(versions) => versions,
+            None => self.versions.entry(table.to_owned()).or_default(),
+        };
+        *versions.entry(to_key(key)).or_default() += 1;
+    }
+
+    fn version(&self, table: &str, key: &[u8]) -> u64 {
+        self.versions
+            .get(table)
+            .and_then(|versions| versions.get(key).copied())
+            .unwrap_or_default()
+    }
+
+    /// 删除 table 中所有的 key 和 sorted set，drop 时连同 table 上的索引一起删除，返回删除的 key 数
+    fn clear_table(&mut self, table: &str, drop: bool) -> usize {
+        let now = now_millis();
+        let keys = self.keydir.remove(table).unwrap_or_default();
+        let mut count = 0;
+        for (key, entry) in keys {
+            self.dead += entry.size;
+            self.touch(table, &key);
+            count += entry.is_live(now) as usize;
+        }
+        count += self.zsets.remove(table).map_or(0, |zsets| zsets.len());
+        if drop {
+            self.indexes.remove(table);
+        } else if let Some(indexes) = self.indexes.get_mut(table) {
+            indexes
+                .values_mut()
+                .for_each(|index| *index = Index::new(index.spec.clone()));
+        }
+        count
+    }
+
+    /// 从数据文件中读出 table 中所有的 value 建立索引，已经有同名的索引时返回 false
+    fn build_index(&mut self, table: String, spec: IndexSpec) -> Result<bool, KvError> {
+        if self
+            .indexes
+            .get(&table)
+            .is_some_and(|indexes| indexes.contains_key(&spec.name))
+        {
+            return Ok(false);
+        }
+        let name = spec.name.clone();
+        let mut index = Index::new(spec);
+        for pair in self.range(&table, .., usize::MAX)? {
+            index.update(&pair.key, pair.value.as_ref());
+        }
+        self.indexes.entry(table).or_default().insert(name, index);
+        Ok(true)
+    }
+
+    fn with_zset<T>(&self, table: &str, key: &[u8], f: impl FnOnce(&SortedSet) -> T) -> T {
+        match self.zsets.get(table).and_then(|zsets| zsets.get(key)) {
+            Some(set) => f(set),
+            None => f(&SortedSet::default()),
+        }
+    }
+}
+
+impl Storage for Bitcask {
+    fn get(
+        &self,
+        table: impl Into<String>,
+        key: impl AsRef<[u8]>,
+    ) -> Result<Option<Value>, KvError> {
+        let (table, key) = (table.into(), key.as_ref());
+        self.read_key(&table, key).get(&table, key)
+    }
+
+    fn set(
+        &self,
+        table: impl Into<String>,
+        key: impl AsRef<[u8]>,
+        value: Value,
+    ) -> Result<Option<Value>, KvError> {
+        let (table, key) = (table.into(), key.as_ref());
+        let mut state = self.write();
+        let old = state.get(&table, key)?;
+        self.append(&mut state, WalRecord::new_set(&table, key, value))?;
+        Ok(old)
+    }
+
+    /// 整组 kv pair 作为一条 batch 记录写入
+    fn mset(&self, table: impl Into<String>, pairs: Vec<Kvpair>) -> Result<(), KvError> {
+        let table = table.into();
+        let records = pairs
+            .into_iter()
+            .map(|pair| WalRecord::new_set(&table, pair.key, pair.value.unwrap_or_default()))
+            .collect();
+        let mut state = self.write();
+        self.append(&mut state, WalRecord::new_batch(records))
+            .map(|_| ())
+    }
+
+    fn compare_and_swap(
+        &self,
+        table: impl Into<String>,
+        key: impl AsRef<[u8]>,
+        expected: Option<Value>,
+        value: Value,
+    ) -> Result<(bool, Option<Value>), KvError> {
+        let (table, key) = (table.into(), key.as_ref());
+        // 所有的写入都持有写锁，比较和写入之间不会有别的修改
+        let mut state = self.write();
+        let current = state.get(&table, key)?;
+        if current != expected {
+            return Ok((false, current));
+        }
+        self.append(&mut state, WalRecord::new_set(&table, key, value.clone()))?;
+        Ok((true, Some(value)))
+    }
+
+    fn incr(
+        &self,
+        table: impl Into<String>,
+        key: impl AsRef<[u8]>,
+        delta: i64,
+    ) -> Result<i64, KvError> {
+        let key = key.as_ref();
+        self.update(table, key, |current| {
+            let value = incr_value(key, current, delta)?;
+            Ok((value.into(), value))
+        })
+    }
+
+    fn update<T>(
+        &self,
+        table: impl Into<String>,
+        key: impl AsRef<[u8]>,
+        mut f: impl FnMut(Option<&Value>) -> Result<(Value, T), KvError>,
+    ) -> Result<T, KvError> {
+        let (table, key) = (table.into(), key.as_ref());
+        let mut state = self.write();
+        let current = state.get(&table, key)?;
+        let (value, result) = f(current.as_ref())?;
+        self.put_keeping_ttl(&mut state, &table, key, value)?;
+        Ok(result)
+    }
+
+    fn contains(&self, table: impl Into<String>, key: impl AsRef<[u8]>) -> Result<bool, KvError> {
+        let (table, key) = (table.into(), key.as_ref());
+        Ok(self.read_key(&table, key).live(&table, key).is_some())
+    }
+
+    fn del(
+        &self,
+        table: impl Into<String>,
+        key: impl AsRef<[u8]>,
+    ) -> Result<Option<Value>, KvError> {
+        let (table, key) = (table.into(), key.as_ref());
+        let mut state = self.write();
+        let exists = state
+            .keydir
+            .get(&table)
+            .is_some_and(|keys| keys.contains_key(key));
+        // key 不存在时不需要写入删除记录
+        if !exists {
+            return Ok(None);
+        }
+        let old = state.get(&table, key)?;
+        self.append(&mut state, WalRecord::new_del(&table, key))?;
+        Ok(old)
+    }
+
+    fn get_all(&self, table: impl Into<String>) -> Result<Vec<Kvpair>, KvError> {
+        let table = table.into();
+        self.read_table(&table).range(&table, .., usize::MAX)
+    }
+
+    fn get_iter(&self, table: impl Into<String>) -> Result<impl Iterator<Item = Kvpair>, KvError> {
+        Ok(self.get_all(table)?.into_iter())
+    }
+
+    fn scan_prefix(
+        &self,
+        table: impl Into<String>,
+        prefix: impl AsRef<[u8]>,
+    ) -> Result<impl Iterator<Item = Kvpair>, KvError> {
+        let (table, prefix) = (table.into(), to_key(prefix));
+        let pairs = self.read_table(&table).range(
+            &table,
+            (Bound::Included(prefix.clone()), Bound::Unbounded),
+            usize::MAX,
+        )?;
+        Ok(pairs
+            .into_iter()
+            .take_while(move |pair| pair.key.starts_with(&prefix)))
+    }
+
+    fn range(
+        &self,
+        table: impl Into<String>,
+        range: impl RangeBounds<Bytes>,
+    ) -> Result<Vec<Kvpair>, KvError> {
+        let table = table.into();
+        self.read_table(&table).range(&table, range, usize::MAX)
+    }
+
+    fn scan_after(
+        &self,
+        table: impl Into<String>,
+        start: Option<Bytes>,
+        limit: usize,
+    ) -> Result<Vec<Kvpair>, KvError> {
+        let start = start.map_or(Bound::Unbounded, Bound::Excluded);
+        let table = table.into();
+        self.read_table(&table)
+            .range(&table, (start, Bound::Unbounded), limit)
+    }
+
+    fn expire(
+        &self,
+        table: impl Into<String>,
+        key: impl AsRef<[u8]>,
+        ttl: Duration,
+    ) -> Result<bool, KvError> {
+        let (table, key) = (table.into(), key.as_ref());
+        let mut state = self.write();
+        if state.remove_if_expired(&table, key) || state.live(&table, key).is_none() {
+            return Ok(false);
+        }
+        // 记录绝对时间，这样重启之后剩余的存活时间仍然是对的
+        let deadline = now_millis() + ttl.as_millis() as u64;
+        self.append(&mut state, WalRecord::new_expire(&table, key, deadline))?;
+        Ok(true)
+    }
+
+    fn ttl(
+        &self,
+        table: impl Into<String>,
+        key: impl AsRef<[u8]>,
+    ) -> Result<Option<Duration>, KvError> {
+        let (table, key) = (table.into(), key.as_ref());
+        let ttl = self
+            .read_key(&table, key)
+            .live(&table, key)
+            .and_then(|entry| entry.deadline)
+            .map(|deadline| Duration::from_millis(deadline.saturating_sub(now_millis())));
+        Ok(ttl)
+    }
+
+    /// 过期时刻记录在数据文件中，重放时同样会过期，清理时不需要写入删除记录
+    fn purge_expired(&self) -> Result<usize, KvError> {
+        let mut state = self.write();
+        let tables: Vec<String> = state.keydir.keys().cloned().collect();
+        Ok(tables
+            .iter()
+            .map(|table| state.remove_expired_in(table))
+            .sum())
+    }
+
+    fn tables(&self) -> Result<Vec<String>, KvError> {
+        let state = self.read();
+        let now = now_millis();
+        Ok(state
+            .keydir
+            .iter()
+            .filter(|(_, keys)| keys.values().any(|entry| entry.is_live(now)))
+            .map(|(table, _)| table.clone())
+            .collect())
+    }
+
+    fn backend(&self) -> &'static str {
+        "bitcask"
+    }
+
+    /// bytes 是 key 的长度加上 key 在数据文件中占用的字节数
+    fn stats(&self) -> Result<Vec<(String, TableStats)>, KvError> {
+        let state = self.read();
+        let now = now_millis();
+        let mut stats = Vec::new();
+        for (table, keys) in &state.keydir {
+            let mut table_stats = TableStats::default();
+            for (key, entry) in keys.iter().filter(|(_, entry)| entry.is_live(now)) {
+                table_stats.keys += 1;
+                table_stats.bytes += key.len() as u64 + entry.size;
+            }
+            if table_stats.keys > 0 {
+                stats.push((table.clone(), table_stats));
+            }
+        }
+        Ok(stats)
+    }
+
+    fn truncate_table(&self, table: impl Into<String>) -> Result<usize, KvError> {
+        let mut state = self.write();
+        self.append(&mut state, WalRecord::new_truncate_table(table.into()))
+    }
+
+    fn drop_table(&self, table: impl Into<String>) -> Result<usize, KvError> {
+        let mut state = self.write();
+        self.append(&mut state, WalRecord::new_drop_table(table.into()))
+    }
+
+    fn version(&self, table: impl Into<String>, key: impl AsRef<[u8]>) -> Result<u64, KvError> {
+        let (table, key) = (table.into(), key.as_ref());
+        let mut state = self.write();
+        state.remove_if_expired(&table, key);
+        Ok(state.version(&table, key))
+    }
+
+    /// 整组记录作为一条 batch 记录写入，重放时要么全部生效，要么整条被忽略
+    fn apply_batch(
+        &self,
+        records: Vec<WalRecord>,
+        watched: &[KeyVersion],
+    ) -> Result<bool, KvError> {
+        let mut state = self.write();
+        for w in watched {
+            state.remove_if_expired(&w.table, &w.key);
+            if state.version(&w.table, &w.key) != w.version {
+                return Ok(false);
+            }
+        }
+        // batch 中的 SET 用下标定位，嵌套的 batch 先展开
+        let records = flatten(records);
+        self.append(&mut state, WalRecord::new_batch(records))?;
+        Ok(true)
+    }
+
+    /// 不管 fsync 策略是什么，都把正在写入的文件刷到磁盘
+    fn flush(&self) -> Result<(), KvError> {
+        let mut state = self.write();
+        state.active.sync_data()?;
+        state.last_sync = Instant::now();
+        Ok(())
+    }
+
+    fn is_blocking(&self) -> bool {
+        true
+    }
+
+    fn compact(&self) -> Result<u64, KvError> {
+        self.merge()
+    }
+
+    fn disk_usage(&self) -> Result<Option<u64>, KvError> {
+        Ok(Some(self.read().total))
+    }
+
+    fn zadd(
+        &self,
+        table: impl Into<String>,
+        key: impl AsRef<[u8]>,
+        members: Vec<ScoredMember>,
+    ) -> Result<usize, KvError> {
+        // 先检查 score，不合法的记录不写入数据文件
+        members.iter().try_for_each(|m| check_score(m.score))?;
+        let mut state = self.write();
+        self.append(&mut state, WalRecord::new_zadd(table.into(), key, members))
+    }
+
+    fn zrange(
+        &self,
+        table: impl Into<String>,
+        key: impl AsRef<[u8]>,
+        start: i64,
+        stop: i64,
+    ) -> Result<Vec<ScoredMember>, KvError> {
+        let state = self.read();
+        Ok(state.with_zset(&table.into(), key.as_ref(), |set| set.range(start, stop)))
+    }
+
+    fn zrange_by_score(
+        &self,
+        table: impl Into<String>,
+        key: impl AsRef<[u8]>,
+        min: f64,
+        max: f64,
+    ) -> Result<Vec<ScoredMember>, KvError> {
+        let state = self.read();
+        Ok(state.with_zset(&table.into(), key.as_ref(), |set| {
+            set.range_by_score(min, max)
+        }))
+    }
+
+    fn zsets(&self) -> Result<Vec<(String, Bytes)>, KvError> {
+        let state = self.read();
+        Ok(state
+            .zsets
+            .iter()
+            .flat_map(|(table, zsets)| zsets.keys().map(|key| (table.clone(), key.clone())))
+            .collect())
+    }
+
+    fn create_index(&self, table: impl Into<String>, spec: IndexSpec) -> Result<bool, KvError> {
+        check_spec(&spec)?;
+        let table = table.into();
+        let mut state = self.write();
+        if state
+            .indexes
+            .get(&table)
+            .is_some_and(|indexes| indexes.contains_key(&spec.name))
+        {
+            return Ok(false);
+        }
+        self.append(&mut state, WalRecord::new_create_index(table, spec))
+            .map(|created| created > 0)
+    }
+
+    fn query(
+        &self,
+        table: impl Into<String>,
+        index: &str,
+        value: &Value,
+    ) -> Result<Vec<Kvpair>, KvError> {
+        let name = table.into();
+        let state = self.read();
+        let keys = state
+            .indexes
+            .get(&name)
+            .and_then(|indexes| {
+                let index = indexes.get(index)?;
+                Some(index_term(value, &[]).map_or(vec![], |term| index.get(&term)))
+            })
+            .ok_or_else(|| KvError::NotFound(format!("index {}:{}", name, index)))?;
+        let mut pairs = Vec::new();
+        for key in keys {
+            if let Some(value) = state.get(&name, &key)? {
+                pairs.push(Kvpair::new(key, value));
+            }
+        }
+        Ok(pairs)
+    }
+
+    fn indexes(&self) -> Result<Vec<(String, IndexSpec)>, KvError> {
+        let state = self.read();
+        Ok(state
+            .indexes
+            .iter()
+            .flat_map(|(table, indexes)| {
+                indexes
+                    .values()
+                    .map(|index| (table.clone(), index.spec.clone()))
+            })
+            .collect())
+    }
+}
+
+/// 合并生成的数据文件和对应的 hint 文件
+struct MergeFile {
+    data: BufWriter<File>,
+    hints: BufWriter<File>,
+    len: u64,
+}
+
+impl MergeFile {
+    /// 写入一条记录，hint 为 (是否是 SET, 过期时刻)，为 None 时不写入 hint 文件；返回记录的位置和长度
+    fn write(&mut self, record: &WalRecord, hint: Option<(bool, u64)>) -> io::Result<(u64, u64)> {
+        let buf = record.encode_length_delimited_to_vec();
+        self.data.write_all(&buf)?;
+        let (offset, len) = (self.len, buf.len() as u64);
+        if let Some((set, expire)) = hint {
+            let hint = HintRecord {
+                table: record.table.clone(),
+                key: record.key.clone(),
+                offset,
+                len,
+                expire,
+                set,
+            };
+            self.hints
+                .write_all(&hint.encode_length_delimited_to_vec())?;
+        }
+        self.len += len;
+        Ok((offset, len))
+    }
+
+    /// 把两个文件都刷到磁盘，返回数据文件的长度
+    fn finish(self) -> io::Result<u64> {
+        self.data.into_inner()?.sync_all()?;
+        self.hints.into_inner()?.sync_all()?;
+        Ok(self.len)
+    }
+}
+
+/// 检查配置，返回数据文件的大小上限和合并的比例
+pub(crate) fn parse_config(config: &BitcaskConfig) -> Result<(usize, f64), KvError> {
+    let max_file_size = match &config.max_file_size {
+        Some(size) => parse_size(size)?,
+        None => DEFAULT_MAX_FILE_SIZE,
+    };
+    let merge_ratio = config.merge_ratio.unwrap_or(DEFAULT_MERGE_RATIO);
+    if !(merge_ratio > 0.0 && merge_ratio <= 1.0) {
+        return Err(KvError::InvalidCommand(format!(
+            "merge_ratio must be in (0, 1], got {}",
+            merge_ratio
+        )));
+    }
+    Ok((max_file_size, merge_ratio))
+}
+
+fn flatten(records: Vec<WalRecord>) -> Vec<WalRecord> {
+    records
+        .into_iter()
+        .flat_map(|record| match record.op {
+            Some(Op::Batch(batch)) => flatten(batch.records),
+            _ => vec![record],
+        })
+        .collect()
+}
+
+fn data_path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(format!("{:09}.data", id))
+}
+
+fn hint_path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(format!("{:09}.hint", id))
+}
+
+fn tmp_path(path: &Path) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(".tmp");
+    path.into()
+}
+
+fn file_id(path: &Path) -> Option<u64> {
+    path.file_stem()?.to_str()?.parse().ok()
+}
+
+#[cfg(unix)]
+fn read_exact_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<()> {
+    std::os::unix::fs::FileExt::read_exact_at(file, buf, offset)
+}
+
+#[cfg(windows)]
+fn read_exact_at(file: &File, mut buf: &mut [u8], mut offset: u64) -> io::Result<()> {
+    use std::os::windows::fs::FileExt;
+    while !buf.is_empty() {
+        let n = file.seek_read(buf, offset)?;
+        if n == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        buf = &mut buf[n..];
+        offset += n as u64;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod bitcask_test {
+    use super::Bitcask;
+    use crate::{
+        config::{BitcaskConfig, FsyncPolicy},
+        pb::abi::{IndexSpec, Kvpair, ScoredMember},
+        storage::tests::{
+            test_apply_batch, test_basic_interface, test_binary_keys, test_compare_and_swap,
+            test_expiration, test_get_all, test_get_iter, test_incr, test_index, test_mget_mset,
+            test_range, test_scan_prefix, test_set_if, test_stats, test_table_management,
+            test_update, test_version, test_zset,
+        },
+        Storage,
+    };
+    use std::{fs, path::Path, time::Duration};
+    use tempfile::tempdir;
+
+    fn open(path: &Path) -> Bitcask {
+        Bitcask::open(&BitcaskConfig {
+            path: path.to_string_lossy().into(),
+            fsync: FsyncPolicy::No,
+            max_file_size: None,
+            merge_ratio: None,
+            merge_interval: None,
+        })
+        .unwrap()
+    }
+
+    fn data_files(path: &Path) -> Vec<String> {
+        let mut names: Vec<String> = fs::read_dir(path)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into())
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn bitcask_basic_interface_should_work() {
+        let dir = tempdir().unwrap();
+        test_basic_interface(open(dir.path()));
+    }
+
+    #[test]
+    fn bitcask_get_all_should_work() {
+        let dir = tempdir().unwrap();
+        test_get_all(open(dir.path()));
+    }
+
+    #[test]
+    fn bitcask_iter_should_work() {
+        let dir = tempdir().unwrap();
+        test_get_iter(open(dir.path()));
+    }
+
+    #[test]
+    fn bitcask_expiration_should_work() {
+        let dir = tempdir().unwrap();
+        test_expiration(open(dir.path()));
+    }
+
+    #[test]
+    fn bitcask_compare_and_swap_should_work() {
+        let dir = tempdir().unwrap();
+        test_compare_and_swap(open(dir.path()));
+    }
+
+    #[test]
+    fn bitcask_incr_should_work() {
+        let dir = tempdir().unwrap();
+        test_incr(open(dir.path()));
+    }
+
+    #[test]
+    fn bitcask_update_should_work() {
+        let dir = tempdir().unwrap();
+        test_update(open(dir.path()));
+    }
+
+    #[test]
+    fn bitcask_binary_keys_should_work() {
+        let dir = tempdir().unwrap();
+        test_binary_keys(open(dir.path()));
+    }
+
+    #[test]
+    fn bitcask_zset_should_work() {
+        let dir = tempdir().unwrap();
+        test_zset(open(dir.path()));
+    }
+
+    #[test]
+    fn bitcask_index_should_work() {
+        let dir = tempdir().unwrap();
+        test_index(open(dir.path()));
+    }
+
+    #[test]
+    fn bitcask_range_should_work() {
+        let dir = tempdir().unwrap();
+        test_range(open(dir.path()));
+    }
+
+    #[test]
+    fn bitcask_scan_prefix_should_work() {
+        let dir = tempdir().unwrap();
+        test_scan_prefix(open(dir.path()));
+    }
+
+    #[test]
+    fn bitcask_table_management_should_work() {
+        let dir = tempdir().unwrap();
+        test_table_management(open(dir.path()));
+    }
+
+    #[test]
+    fn bitcask_stats_should_work() {
+        let dir = tempdir().unwrap();
+        test_stats(open(dir.path()));
+    }
+
+    #[test]
+    fn bitcask_apply_batch_should_work() {
+        let dir = tempdir().unwrap();
+        test_apply_batch(open(dir.path()));
+    }
+
+    #[test]
+    fn bitcask_mget_mset_should_work() {
+        let dir = tempdir().unwrap();
+        test_mget_mset(open(dir.path()));
+    }
+
+    #[test]
+    fn bitcask_set_if_should_work() {
+        let dir = tempdir().unwrap();
+        test_set_if(open(dir.path()));
+    }
+
+    #[test]
+    fn bitcask_version_should_work() {
+        let dir = tempdir().unwrap();
+        test_version(open(dir.path()));
+    }
+
+    #[test]
+    fn bitcask_should_recover_after_reopen() {
+        let dir = tempdir().unwrap();
+        {
+            let store = open(dir.path());
+            store.set("t1", "k1", "v1".into()).unwrap();
+            store.set("t1", "k2", "v2".into()).unwrap();
+            store.del("t1", "k2").unwrap();
+            store.incr("t1", "n", 3).unwrap();
+            store.expire("t1", "n", Duration::from_secs(100)).unwrap();
+            store.incr("t1", "n", 1).unwrap();
+            store.mset("t2", vec![Kvpair::new("k", 1.into())]).unwrap();
+            store
+                .zadd(
+                    "z",
+                    "k",
+                    vec![ScoredMember {
+                        member: "a".into(),
+                        score: 1.0,
+                    }],
+                )
+                .unwrap();
+            store
+                .create_index(
+                    "t1",
+                    IndexSpec {
+                        name: "idx".into(),
+                        field: vec![],
+                    },
+                )
+                .unwrap();
+        }
+
+        let store = open(dir.path());
+        assert_eq!(store.get("t1", "k1").unwrap(), Some("v1".into()));
+        assert_eq!(store.get("t1", "k2").unwrap(), None);
+        assert_eq!(store.get("t1", "n").unwrap(), Some(4.into()));
+        assert!(store.ttl("t1", "n").unwrap().unwrap() > Duration::from_secs(90));
+        assert_eq!(store.get("t2", "k").unwrap(), Some(1.into()));
+        assert_eq!(store.zrange("z", "k", 0, -1).unwrap().len(), 1);
+        let pairs = store.query("t1", "idx", &"v1".into()).unwrap();
+        assert_eq!(pairs.len(), 1);
+        // 每次启动换一个新的文件，上次启动之后没有写入的空文件会被重用
+        drop(store);
+        let store = open(dir.path());
+        drop(store);
+        assert_eq!(data_files(dir.path()).len(), 2);
+    }
+
+    #[test]
+    fn bitcask_merge_should_reclaim_space_and_write_hints() {
+        let dir = tempdir().unwrap();
+        {
+            let store = open(dir.path());
+            for i in 0..100 {
+                store.set("t1", "k1", format!("v{}", i).into()).unwrap();
+            }
+            store.set("t1", "k2", "v2".into()).unwrap();
+            store.expire("t1", "k2", Duration::from_secs(100)).unwrap();
+            store.set("t1", "gone", "v".into()).unwrap();
+            store.del("t1", "gone").unwrap();
+            store
+                .zadd(
+                    "z",
+                    "k",
+                    vec![ScoredMember {
+                        member: "a".into(),
+                        score: 1.0,
+                    }],
+                )
+                .unwrap();
+            store
+                .create_index(
+                    "t1",
+                    IndexSpec {
+                        name: "idx".into(),
+                        field: vec![],
+                    },
+                )
+                .unwrap();
+
+            let before = store.disk_usage().unwrap().unwrap();
+            let reclaimed = store.merge().unwrap();
+            assert!(reclaimed > 0);
+            assert_eq!(store.disk_usage().unwrap(), Some(before - reclaimed));
+            // 合并之后的读写照常进行
+            assert_eq!(store.get("t1", "k1").unwrap(), Some("v99".into()));
+            store.set("t1", "k3", "v3".into()).unwrap();
+        }
+        assert_eq!(
+            data_files(dir.path()),
+            vec!["000000002.data", "000000002.hint", "000000003.data"]
+        );
+
+        let store = open(dir.path());
+        assert_eq!(store.get("t1", "k1").unwrap(), Some("v99".into()));
+        assert!(store.ttl("t1", "k2").unwrap().unwrap() > Duration::from_secs(90));
+        assert_eq!(store.get("t1", "gone").unwrap(), None);
+        assert_eq!(store.get("t1", "k3").unwrap(), Some("v3".into()));
+        assert_eq!(store.zrange("z", "k", 0, -1).unwrap().len(), 1);
+        let pairs = store.query("t1", "idx", &"v2".into()).unwrap();
+        assert_eq!(pairs.len(), 1);
+    }
+
+    #[test]
+    fn bitcask_should_ignore_broken_tail() {
+        let dir = tempdir().unwrap();
+        {
+            let store = open(dir.path());
+            store.set("t1", "k1", "v1".into()).unwrap();
+        }
+        let path = dir.path().join("000000001.data");
+        let mut data = fs::read(&path).unwrap();
+        data.extend_from_slice(&[0x20, 1, 2]);
+        fs::write(&path, data).unwrap();
+
+        let store = open(dir.path());
+        assert_eq!(store.get("t1", "k1").unwrap(), Some("v1".into()));
+        store.set("t1", "k2", "v2".into()).unwrap();
+        drop(store);
+        let store = open(dir.path());
+        assert_eq!(store.get("t1", "k2").unwrap(), Some("v2".into()));
+    }
+}
@@ -0,0 +1,763 @@
+use super::{
+    eviction::MemoryLimit,
+    now_millis,
+    quota::Quotas,
+    s3::{hex, ObjectStore, S3Store},
+    KeyVersion, Storage, TableStats,
+};
+use crate::{
+    config::{parse_size, TieringConfig},
+    error::KvError,
+    pb::abi::{value, wal_record::Op, IndexSpec, Kvpair, ScoredMember, Value, WalRecord},
+};
+use bytes::{Bytes, BytesMut};
+use dashmap::DashMap;
+use prost::Message;
+use std::{
+    ops::{Deref, RangeBounds},
+    sync::{Arc, Weak},
+    thread,
+    time::Duration,
+};
+use tracing::{debug, info, warn};
+
+/// 转移到对象存储的 value 在本地留下的占位：一个 Binary，MAGIC 后面是 object 的名字
+const MAGIC: &[u8] = b"\0kvc";
+
+/// 默认不转移小于 1KB 的 value，占位本身也要占用空间
+const DEFAULT_MIN_SIZE: usize = 1024;
+
+/// 默认每小时检查一次冷数据
+const DEFAULT_INTERVAL: u64 = 3600;
+
+/// 检查冷数据时每批读取的 key 数
+const OFFLOAD_BATCH: usize = 1000;
+
+/// 把长时间没有读写的 value 转移到对象存储：本地只留下占位，读取时取回来放回本地。
+/// 遍历 table 时会从对象存储读取冷数据，但不会放回本地。
+/// 访问时间只保存在内存中，重启之后所有的 key 都当作在启动时访问过。
+/// 有过期时间的 key 和有索引的 table 不会被转移
+pub struct ColdStorage<S> {
+    inner: Arc<ColdTier<S>>,
+}
+
+pub struct ColdTier<S> {
+    store: S,
+    objects: Box<dyn ObjectStore>,
+    idle: Duration,
+    min_size: usize,
+    /// 启动之后每个 key 最后一次读写的时刻
+    accessed: DashMap<(String, Bytes), u64>,
+    started: u64,
+}
+
+impl<S: Storage> ColdStorage<S> {
+    pub fn open(store: S, config: &TieringConfig) -> Result<Self, KvError> {
+        let min_size = match &config.min_size {
+            Some(size) => parse_size(size)?,
+            None => DEFAULT_MIN_SIZE,
+        };
+        let idle = Duration::from_secs(config.idle_days * 24 * 3600);
+        Ok(Self::new(store, S3Store::new(&config.s3)?, idle, min_size))
+    }
+
+    pub fn new(store: S, objects: impl ObjectStore, idle: Duration, min_size: usize) -> Self {
+        Self {
+            inner: Arc::new(ColdTier {
+                store,
+                objects: Box::new(objects),
+                idle,
+                min_size,
+                accessed: DashMap::new(),
+                started: now_millis(),
+            }),
+        }
+    }
+
+    /// 在后台线程中每隔 interval 秒转移一次冷数据，store 被丢弃之后线程退出
+    pub fn spawn(&self, interval: Option<u64>) {
+        let interval = Duration::from_secs(interval.unwrap_or(DEFAULT_INTERVAL));
+        let tier: Weak<ColdTier<S>> = Arc::downgrade(&self.inner);
+        thread::spawn(move || loop {
+            thread::sleep(interval);
+            let Some(tier) = tier.upgrade() else {
+                break;
+            };
+            match tier.offload() {
+                Ok(0) => {}
+                Ok(n) => info!("Moved {} cold values to object storage", n),
+                Err(e) => warn!("Failed to move cold values to object storage: {:?}", e),
+            }
+        });
+    }
+}
+
+impl<S> Deref for ColdStorage<S> {
+    type Target = ColdTier<S>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.inner
+    }
+}
+
+impl<S: Storage> ColdTier<S> {
+    /// 把超过 idle 没有读写的 value 上传到对象存储，本地替换成占位，返回转移的 key 数量
+    pub fn offload(&self) -> Result<usize, KvError> {
+        let deadline = now_millis().saturating_sub(self.idle.as_millis() as u64);
+        let indexed: Vec<String> = self.store.indexes()?.into_iter().map(|(t, _)| t).collect();
+        let mut count = 0;
+        for table in self.store.tables()? {
+            if indexed.contains(&table) {
+                continue;
+            }
+            let mut start = None;
+            loop {
+                let pairs = self
+                    .store
+                    .scan_after(table.as_str(), start, OFFLOAD_BATCH)?;
+                let Some(last) = pairs.last() else {
+                    break;
+                };
+                start = Some(last.key.clone());
+                for pair in pairs {
+                    let Some(value) = pair.value else {
+                        continue;
+                    };
+                    if self.is_cold(&table, &pair.key, &value, deadline)?
+                        && self.offload_key(&table, &pair.key, value)?
+                    {
+                        count += 1;
+                    }
+                }
+            }
+        }
+        Ok(count)
+    }
+
+    fn is_cold(
+        &self,
+        table: &str,
+        key: &Bytes,
+        value: &Value,
+        deadline: u64,
+    ) -> Result<bool, KvError> {
+        if stub_name(value).is_some() || value.encoded_len() < self.min_size {
+            return Ok(false);
+        }
+        let accessed = self
+            .accessed
+            .get(&(table.to_string(), key.clone()))
+            .map_or(self.started, |t| *t);
+        Ok(accessed <= deadline && self.store.ttl(table, key)?.is_none())
+    }
+
+    /// 上传之后只有本地的 value 没有变化时才替换成占位，否则删掉刚上传的 object
+    fn offload_key(&self, table: &str, key: &[u8], value: Value) -> Result<bool, KvError> {
+        let name = format!(
+            "{}/{}.{:016x}",
+            hex(table.as_bytes()),
+            hex(key),
+            rand::random::<u64>()
+        );
+        self.objects.put(&name, value.encode_to_vec().into())?;
+        let stub = stub(&name);
+        let mut changed = false;
+        let res = self.store.update(table, key, |current| match current {
+            Some(current) if *current == value => Ok((stub.clone(), ())),
+            _ => {
+                changed = true;
+                Err(KvError::Conflict("value changed".into()))
+            }
+        });
+        match res {
+            Ok(()) => Ok(true),
+            Err(e) => {
+                self.delete_object(&name);
+                match changed {
+                    true => Ok(false),
+                    false => Err(e),
+                }
+            }
+        }
+    }
+
+    fn touch(&self, table: &str, key: &[u8]) {
+        self.accessed
+            .insert((table.into(), Bytes::copy_from_slice(key)), now_millis());
+    }
+
+    /// value 是占位时从对象存储读取原来的 value
+    fn fetch(&self, value: Value) -> Result<Value, KvError> {
+        match stub_name(&value) {
+            Some(name) => Ok(Value::decode(self.objects.get(name)?)?),
+            None => Ok(value),
+        }
+    }
+
+    fn fetch_pairs(&self, pairs: Vec<Kvpair>) -> Result<Vec<Kvpair>, KvError> {
+        pairs
+            .into_iter()
+            .map(|pair| {
+                let value = pair.value.map(|v| self.fetch(v)).transpose()?;
+                Ok(Kvpair {
+                    key: pair.key,
+                    value,
+                })
+            })
+            .collect()
+    }
+
+    /// iterator 没法返回错误，读取失败的 kv pair 记录日志之后跳过
+    fn fetch_iter<'a>(
+        &'a self,
+        iter: impl Iterator<Item = Kvpair> + 'a,
+    ) -> impl Iterator<Item = Kvpair> + 'a {
+        iter.filter_map(move |pair| {
+            let value = match pair.value.map(|v| self.fetch(v)).transpose() {
+                Ok(value) => value,
+                Err(e) => {
+                    warn!("Skipped cold value that can't be fetched: {}", e);
+                    return None;
+                }
+            };
+            Some(Kvpair {
+                key: pair.key,
+                value,
+            })
+        })
+    }
+
+    /// 读取 key 时把冷数据取回来放回本地
+    fn load(&self, table: &str, key: &[u8], raw: Option<Value>) -> Result<Option<Value>, KvError> {
+        let Some(raw) = raw else {
+            return Ok(None);
+        };
+        let Some(name) = stub_name(&raw).map(String::from) else {
+            return Ok(Some(raw));
+        };
+        let value = self.fetch(raw.clone())?;
+        let res = self.store.update(table, key, |current| match current {
+            Some(current) if *current == raw => Ok((value.clone(), ())),
+            _ => Err(KvError::Conflict("value changed".into())),
+        });
+        match res {
+            Ok(()) => {
+                debug!("Moved {} back from object storage", name);
+                self.delete_object(&name);
+            }
+            // 读取期间 key 被修改了，新的值会负责删除 object
+            Err(e) => debug!("Failed to move {} back: {}", name, e),
+        }
+        Ok(Some(value))
+    }
+
+    /// 被覆盖或者删除的旧值是占位时，读取原来的值，然后删掉 object
+    fn release(&self, old: Option<Value>) -> Result<Option<Value>, KvError> {
+        let Some(old) = old else {
+            return Ok(None);
+        };
+        let Some(name) = stub_name(&old).map(String::from) else {
+            return Ok(Some(old));
+        };
+        let value = self.fetch(old)?;
+        self.delete_object(&name);
+        Ok(Some(value))
+    }
+
+    /// 删除失败只会在对象存储中留下没用的 object，记录日志即可
+    fn delete_object(&self, name: &str) {
+        if let Err(e) = self.objects.delete(name) {
+            warn!("Failed to delete object {}: {:?}", name, e);
+        }
+    }
+
+    fn delete_objects(&self, values: impl IntoIterator<Item = Option<Value>>) {
+        for value in values.into_iter().flatten() {
+            if let Some(name) = stub_name(&value) {
+                self.delete_object(name);
+            }
+        }
+    }
+
+    /// table 中所有占位，清空 table 之后删掉对应的 object
+    fn table_stubs(&self, table: &str) -> Result<Vec<Option<Value>>, KvError> {
+        Ok(self
+            .store
+            .get_iter(table.to_string())?
+            .filter_map(|pair| pair.value)
+            .filter(|value| stub_name(value).is_some())
+            .map(Some)
+            .collect())
+    }
+
+    fn fetch_record(&self, record: WalRecord) -> Result<WalRecord, KvError> {
+        match record.op {
+            Some(Op::Set(value)) => Ok(WalRecord::new_set(
+                record.table,
+                record.key,
+                self.fetch(value)?,
+            )),
+            op => Ok(WalRecord { op, ..record }),
+        }
+    }
+}
+
+impl<S: Storage> Storage for ColdStorage<S> {
+    fn get(
+        &self,
+        table: impl Into<String>,
+        key: impl AsRef<[u8]>,
+    ) -> Result<Option<Value>, KvError> {
+        let (table, key) = (table.into(), key.as_ref());
+        self.touch(&table, key);
+        let raw = self.store.get(table.as_str(), key)?;
+        self.load(&table, key, raw)
+    }
+
+    fn set(
+        &self,
+        table: impl Into<String>,
+        key: impl AsRef<[u8]>,
+        value: Value,
+    ) -> Result<Option<Value>, KvError> {
+        let (table, key) = (table.into(), key.as_ref());
+        self.touch(&table, key);
+        let old = self.store.set(table.as_str(), key, value)?;
+        self.release(old)
+    }
+
+    /// 和占位比较没有意义，先读出本地的值比较原来的值，再用本地的值做 compare_and_swap
+    fn compare_and_swap(
+        &self,
+        table: impl Into<String>,
+        key: impl AsRef<[u8]>,
+        expected: Option<Value>,
+        value: Value,
+    ) -> Result<(bool, Option<Value>), KvError> {
+        let (table, key) = (table.into(), key.as_ref());
+        self.touch(&table, key);
+        loop {
+            let raw = self.store.get(table.as_str(), key)?;
+            let current = raw.clone().map(|v| self.fetch(v)).transpose()?;
+            if current != expected {
+                return Ok((false, current));
+            }
+            if self
+                .store
+                .compare_and_swap(table.as_str(), key, raw.clone(), value.clone())?
+                .0
+            {
+                self.delete_objects([raw]);
+                return Ok((true, Some(value)));
+            }
+        }
+    }
+
+    fn incr(
+        &self,
+        table: impl Into<String>,
+        key: impl AsRef<[u8]>,
+        delta: i64,
+    ) -> Result<i64, KvError> {
+        let key = key.as_ref();
+        self.update(table, key, |current| {
+            let value = super::incr_value(key, current, delta)?;
+            Ok((value.into(), value))
+        })
+    }
+
+    fn update<T>(
+        &self,
+        table: impl Into<String>,
+        key: impl AsRef<[u8]>,
+        mut f: impl FnMut(Option<&Value>) -> Result<(Value, T), KvError>,
+    ) -> Result<T, KvError> {
+        let (table, key) = (table.into(), key.as_ref());
+        self.touch(&table, key);
+        let mut replaced = None;
+        let result = self.store.update(table.as_str(), key, |current| {
+            replaced = current.cloned();
+            let current = current.cloned().map(|v| self.fetch(v)).transpose()?;
+            f(current.as_ref())
+        })?;
+        self.delete_objects([replaced]);
+        Ok(result)
+    }
+
+    fn contains(&self, table: impl Into<String>, key: impl AsRef<[u8]>) -> Result<bool, KvError> {
+        self.store.contains(table, key)
+    }
+
+    fn del(
+        &self,
+        table: impl Into<String>,
+        key: impl AsRef<[u8]>,
+    ) -> Result<Option<Value>, KvError> {
+        let (table, key) = (table.into(), key.as_ref());
+        self.accessed
+            .remove(&(table.clone(), Bytes::copy_from_slice(key)));
+        let old = self.store.del(table.as_str(), key)?;
+        self.release(old)
+    }
+
+    fn mget(
+        &self,
+        table: impl Into<String>,
+        keys: &[impl AsRef<[u8]>],
+    ) -> Result<Vec<Option<Value>>, KvError> {
+        let table = table.into();
+        let values = self.store.mget(table.as_str(), keys)?;
+        keys.iter()
+            .zip(values)
+            .map(|(key, raw)| {
+                self.touch(&table, key.as_ref());
+                self.load(&table, key.as_ref(), raw)
+            })
+            .collect()
+    }
+
+    fn get_all(&self, table: impl Into<String>) -> Result<Vec<Kvpair>, KvError> {
+        self.fetch_pairs(self.store.get_all(table)?)
+    }
+
+    fn get_iter(&self, table: impl Into<String>) -> Result<impl Iterator<Item = Kvpair>, KvError> {
+        let iter = self.store.get_iter(table.into())?;
+        Ok(self.fetch_iter(iter))
+    }
+
+    fn scan_prefix(
+        &self,
+        table: impl Into<String>,
+        prefix: impl AsRef<[u8]>,
+    ) -> Result<impl Iterator<Item = Kvpair>, KvError> {
+        let prefix = Bytes::copy_from_slice(prefix.as_ref());
+        let iter = self.store.scan_prefix(table.into(), prefix)?;
+        Ok(self.fetch_iter(iter))
+    }
+
+    fn range(
+        &self,
+        table: impl Into<String>,
+        range: impl RangeBounds<Bytes>,
+    ) -> Result<Vec<Kvpair>, KvError> {
+        self.fetch_pairs(self.store.range(table, range)?)
+    }
+
+    fn scan_after(
+        &self,
+        table: impl Into<String>,
+        start: Option<Bytes>,
+        limit: usize,
+    ) -> Result<Vec<Kvpair>, KvError> {
+        self.fetch_pairs(self.store.scan_after(table, start, limit)?)
+    }
+
+    fn expire(
+        &self,
+        table: impl Into<String>,
+        key: impl AsRef<[u8]>,
+        ttl: Duration,
+    ) -> Result<bool, KvError> {
+        self.store.expire(table, key, ttl)
+    }
+
+    fn ttl(
+        &self,
+        table: impl Into<String>,
+        key: impl AsRef<[u8]>,
+    ) -> Result<Option<Duration>, KvError> {
+        self.store.ttl(table, key)
+    }
+
+    /// 有过期时间的 key 不会被转移，过期的 key 不会有 object
+    fn purge_expired(&self) -> Result<usize, KvError> {
+        self.store.purge_expired()
+    }
+
+    fn tables(&self) -> Result<Vec<String>, KvError> {
+        self.store.tables()
+    }
+
+    fn backend(&self) -> &'static str {
+        self.store.backend()
+    }
+
+    fn stats(&self) -> Result<Vec<(String, TableStats)>, KvError> {
+        self.store.stats()
+    }
+
+    fn truncate_table(&self, table: impl Into<String>) -> Result<usize, KvError> {
+        let table = table.into();
+        let stubs = self.table_stubs(&table)?;
+        let count = self.store.truncate_table(table.as_str())?;
+        self.accessed.retain(|(t, _), _| *t != table);
+        self.delete_objects(stubs);
+        Ok(count)
+    }
+
+    fn drop_table(&self, table: impl Into<String>) -> Result<usize, KvError> {
+        let table = table.into();
+        let stubs = self.table_stubs(&table)?;
+        let count = self.store.drop_table(table.as_str())?;
+        self.accessed.retain(|(t, _), _| *t != table);
+        self.delete_objects(stubs);
+        Ok(count)
+    }
+
+    fn version(&self, table: impl Into<String>, key: impl AsRef<[u8]>) -> Result<u64, KvError> {
+        self.store.version(table, key)
+    }
+
+    /// 先读出会被覆盖或者删除的 key，整组记录生效之后删掉其中占位对应的 object
+    fn apply_batch(
+        &self,
+        records: Vec<WalRecord>,
+        watched: &[KeyVersion],
+    ) -> Result<bool, KvError> {
+        let mut replaced = Vec::new();
+        let mut pending: Vec<&WalRecord> = records.iter().collect();
+        while let Some(record) = pending.pop() {
+            match &record.op {
+                Some(Op::Set(_) | Op::Del(_)) => {
+                    self.touch(&record.table, &record.key);
+                    replaced.push(self.store.get(record.table.as_str(), &record.key)?);
+                }
+                Some(Op::Batch(batch)) => pending.extend(&batch.records),
+                _ => {}
+            }
+        }
+        if !self.store.apply_batch(records, watched)? {
+            return Ok(false);
+        }
+        self.delete_objects(replaced);
+        Ok(true)
+    }
+
+    fn zadd(
+        &self,
+        table: impl Into<String>,
+        key: impl AsRef<[u8]>,
+        members: Vec<ScoredMember>,
+    ) -> Result<usize, KvError> {
+        self.store.zadd(table, key, members)
+    }
+
+    fn zrange(
+        &self,
+        table: impl Into<String>,
+        key: impl AsRef<[u8]>,
+        start: i64,
+        stop: i64,
+    ) -> Result<Vec<ScoredMember>, KvError> {
+        self.store.zrange(table, key, start, stop)
+    }
+
+    fn zrange_by_score(
+        &self,
+        table: impl Into<String>,
+        key: impl AsRef<[u8]>,
+        min: f64,
+        max: f64,
+    ) -> Result<Vec<ScoredMember>, KvError> {
+        self.store.zrange_by_score(table, key, min, max)
+    }
+
+    fn zsets(&self) -> Result<Vec<(String, Bytes)>, KvError> {
+        self.store.zsets()
+    }
+
+    fn create_index(&self, table: impl Into<String>, spec: IndexSpec) -> Result<bool, KvError> {
+        self.store.create_index(table, spec)
+    }
+
+    fn query(
+        &self,
+        table: impl Into<String>,
+        index: &str,
+        value: &Value,
+    ) -> Result<Vec<Kvpair>, KvError> {
+        self.store.query(table, index, value)
+    }
+
+    fn indexes(&self) -> Result<Vec<(String, IndexSpec)>, KvError> {
+        self.store.indexes()
+    }
+
+    fn flush(&self) -> Result<(), KvError> {
+        self.store.flush()
+    }
+
+    fn compact(&self) -> Result<u64, KvError> {
+        self.store.compact()
+    }
+
+    /// 只包括本地的存储，不包括转移到对象存储的数据
+    fn disk_usage(&self) -> Result<Option<u64>, KvError> {
+        self.store.disk_usage()
+    }
+
+    /// 读取冷数据需要访问对象存储
+    fn is_blocking(&self) -> bool {
+        true
+    }
+
+    fn memory_limit(&self) -> Option<&MemoryLimit> {
+        self.store.memory_limit()
+    }
+
+    fn quotas(&self) -> Option<&Quotas> {
+        self.store.quotas()
+    }
+
+    /// 快照中是原来的值，导入到别的存储不依赖对象存储
+    fn snapshot(&self) -> Result<Vec<WalRecord>, KvError> {
+        self.store
+            .snapshot()?
+            .into_iter()
+            .map(|r| self.fetch_record(r))
+            .collect()
+    }
+}
+
+fn stub(name: &str) -> Value {
+    let mut buf = BytesMut::with_capacity(MAGIC.len() + name.len());
+    buf.extend_from_slice(MAGIC);
+    buf.extend_from_slice(name.as_bytes());
+    buf.freeze().into()
+}
+
+/// value 是占位时返回 object 的名字
+fn stub_name(value: &Value) -> Option<&str> {
+    match &value.value {
+        Some(value::Value::Binary(data)) if data.starts_with(MAGIC) => {
+            std::str::from_utf8(&data[MAGIC.len()..]).ok()
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod cold_tests {
+    use super::*;
+    use crate::storage::{
+        memory::MemTable,
+        tests::{
+            test_apply_batch, test_basic_interface, test_compare_and_swap, test_expiration,
+            test_get_all, test_incr, test_mget_mset, test_set_if, test_table_management,
+            test_update,
+        },
+    };
+    use std::{collections::HashMap, sync::Mutex};
+
+    #[derive(Default, Clone)]
+    struct MemoryObjects(Arc<Mutex<HashMap<String, Bytes>>>);
+
+    impl MemoryObjects {
+        fn len(&self) -> usize {
+            self.0.lock().unwrap().len()
+        }
+    }
+
+    impl ObjectStore for MemoryObjects {
+        fn put(&self, name: &str, data: Bytes) -> Result<(), KvError> {
+            self.0.lock().unwrap().insert(name.into(), data);
+            Ok(())
+        }
+
+        fn get(&self, name: &str) -> Result<Bytes, KvError> {
+            self.0
+                .lock()
+                .unwrap()
+                .get(name)
+                .cloned()
+                .ok_or_else(|| KvError::NotFound(name.into()))
+        }
+
+        fn delete(&self, name: &str) -> Result<(), KvError> {
+            self.0.lock().unwrap().remove(name);
+            Ok(())
+        }
+    }
+
+    /// 所有 value 都是冷数据
+    fn cold_storage() -> (ColdStorage<MemTable>, MemoryObjects) {
+        let objects = MemoryObjects::default();
+        let store = ColdStorage::new(MemTable::new(), objects.clone(), Duration::ZERO, 0);
+        (store, objects)
+    }
+
+    #[test]
+    fn cold_storage_should_pass_storage_tests() {
+        test_basic_interface(cold_storage().0);
+        test_get_all(cold_storage().0);
+        test_expiration(cold_storage().0);
+        test_compare_and_swap(cold_storage().0);
+        test_incr(cold_storage().0);
+        test_update(cold_storage().0);
+        test_table_management(cold_storage().0);
+        test_apply_batch(cold_storage().0);
+        test_mget_mset(cold_storage().0);
+        test_set_if(cold_storage().0);
+    }
+
+    #[test]
+    fn cold_values_should_be_fetched_back() {
+        let (store, objects) = cold_storage();
+        store.set("t1", "k1", "v1".into()).unwrap();
+        store.set("t1", "k2", "v2".into()).unwrap();
+        store.set("t1", "k3", "v3".into()).unwrap();
+        store.expire("t1", "k3", Duration::from_secs(100)).unwrap();
+        assert_eq!(store.offload().unwrap(), 2);
+        assert_eq!(objects.len(), 2);
+        assert!(stub_name(&store.store.get("t1", "k1").unwrap().unwrap()).is_some());
+
+        // 遍历时读取冷数据，不放回本地
+        let mut pairs = store.get_all("t1").unwrap();
+        pairs.sort_by(|a, b| a.key.cmp(&b.key));
+        assert_eq!(pairs[0], Kvpair::new("k1", "v1".into()));
+        assert_eq!(objects.len(), 2);
+        let snapshot = store.snapshot().unwrap();
+        assert!(snapshot.contains(&WalRecord::new_set("t1", "k1", "v1".into())));
+
+        // 读取时放回本地，删掉 object
+        assert_eq!(store.get("t1", "k1").unwrap(), Some("v1".into()));
+        assert_eq!(store.store.get("t1", "k1").unwrap(), Some("v1".into()));
+        assert_eq!(objects.len(), 1);
+
+        // 覆盖和删除冷数据返回原来的值，并删掉 object
+        store.offload().unwrap();
+        assert_eq!(
+            store.set("t1", "k1", "v4".into()).unwrap(),
+            Some("v1".into())
+        );
+        assert_eq!(store.del("t1", "k2").unwrap(), Some("v2".into()));
+        assert_eq!(objects.len(), 0);
+    }
+
+    #[test]
+    fn recently_accessed_values_should_stay_local() {
+        let objects = MemoryObjects::default();
+        let store = ColdStorage::new(
+            MemTable::new(),
+            objects.clone(),
+            Duration::from_secs(3600),
+            0,
+        );
+        store.set("t1", "k1", "v1".into()).unwrap();
+        assert_eq!(store.offload().unwrap(), 0);
+
+        // 太小的 value 和有索引的 table 都留在本地
+        let store = ColdStorage::new(MemTable::new(), objects.clone(), Duration::ZERO, 100);
+        let spec = IndexSpec {
+            name: "by_value".into(),
+            field: vec![],
+        };
+        store.create_index("t2", spec).unwrap();
+        store.set("t1", "small", "v1".into()).unwrap();
+        store.set("t2", "large", "v".repeat(200).into()).unwrap();
+        assert_eq!(store.offload().unwrap(), 0);
+        store.set("t1", "large", "v".repeat(200).into()).unwrap();
+        assert_eq!(store.offload().unwrap(), 1);
+        assert_eq!(objects.len(), 1);
+    }
+}
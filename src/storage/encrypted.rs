@@ -0,0 +1,745 @@
+use super::{eviction::MemoryLimit, incr_value, quota::Quotas, KeyVersion, Storage, TableStats};
+use crate::{
+    config::{Cipher, EncryptionConfig},
+    error::KvError,
+    pb::abi::{value, wal_record::Op, IndexSpec, Kvpair, ScoredMember, Value, WalRecord},
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use bytes::{BufMut, Bytes, BytesMut};
+use prost::Message;
+use ring::{
+    aead::{
+        Aad, Algorithm, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, CHACHA20_POLY1305, NONCE_LEN,
+    },
+    rand::{SecureRandom, SystemRandom},
+};
+use std::{collections::HashMap, env, ops::RangeBounds, time::Duration};
+use tracing::warn;
+
+/// 加密之后的 value 是一个 Binary：MAGIC、key 的 id（4 字节大端）、nonce，最后是密文和 tag
+const MAGIC: &[u8] = b"\0kve";
+
+const HEADER_LEN: usize = MAGIC.len() + 4 + NONCE_LEN;
+
+/// 重新加密时每批读取的 key 数
+const REENCRYPT_BATCH: usize = 1000;
+
+/// 加密使用的所有 key，新写入的 value 使用 active 的 key
+pub struct Keyring {
+    keys: HashMap<u32, LessSafeKey>,
+    active: u32,
+    rng: SystemRandom,
+}
+
+impl Keyring {
+    pub fn from_config(config: &EncryptionConfig) -> Result<Self, KvError> {
+        let algorithm: &'static Algorithm = match config.cipher {
+            Cipher::Aes256Gcm => &AES_256_GCM,
+            Cipher::ChaCha20Poly1305 => &CHACHA20_POLY1305,
+        };
+        let mut keys = HashMap::new();
+        for key in &config.keys {
+            let encoded = match (&key.key, &key.env) {
+                (Some(encoded), None) => encoded.clone(),
+                (None, Some(name)) => env::var(name).map_err(|_| {
+                    invalid(format!(
+                        "environment variable {} of encryption key {} is not set",
+                        name, key.id
+                    ))
+                })?,
+                _ => {
+                    return Err(invalid(format!(
+                        "encryption key {} must have exactly one of key and env",
+                        key.id
+                    )))
+                }
+            };
+            let bytes = STANDARD
+                .decode(encoded.trim())
+                .map_err(|_| invalid(format!("encryption key {} is not valid base64", key.id)))?;
+            let unbound = UnboundKey::new(algorithm, &bytes).map_err(|_| {
+                invalid(format!(
+                    "encryption key {} must be {} bytes",
+                    key.id,
+                    algorithm.key_len()
+                ))
+            })?;
+            if keys.insert(key.id, LessSafeKey::new(unbound)).is_some() {
+                return Err(invalid(format!("duplicate encryption key {}", key.id)));
+            }
+        }
+        if !keys.contains_key(&config.active) {
+            return Err(invalid(format!(
+                "active encryption key {} is not configured",
+                config.active
+            )));
+        }
+        Ok(Self {
+            keys,
+            active: config.active,
+            rng: SystemRandom::new(),
+        })
+    }
+
+    /// 用 active 的 key 加密 value。已经是这个位置的密文时原样返回，
+    /// 这样导入从加密的存储中直接导出的 snapshot 时不会被加密两次
+    fn encrypt(&self, table: &str, key: &[u8], value: &Value) -> Result<Value, KvError> {
+        if matches!(self.open(table, key, value), Ok(Some(_))) {
+            return Ok(value.clone());
+        }
+        let mut nonce = [0u8; NONCE_LEN];
+        self.rng
+            .fill(&mut nonce)
+            .map_err(|_| KvError::Internal("failed to generate nonce".into()))?;
+        let mut data = value.encode_to_vec();
+        self.keys[&self.active]
+            .seal_in_place_append_tag(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::from(aad(table, key)),
+                &mut data,
+            )
+            .map_err(|_| KvError::Internal("failed to encrypt value".into()))?;
+
+        let mut buf = BytesMut::with_capacity(HEADER_LEN + data.len());
+        buf.put_slice(MAGIC);
+        buf.put_u32(self.active);
+        buf.put_slice(&nonce);
+        buf.put_slice(&data);
+        Ok(buf.freeze().into())
+    }
+
+    /// 解密 value，没有加密过的 value 原样返回
+    fn decrypt(&self, table: &str, key: &[u8], value: Value) -> Result<Value, KvError> {
+        Ok(self.open(table, key, &value)?.unwrap_or(value))
+    }
+
+    /// value 不是密文时返回 None；key 不存在或者密文被修改过时返回错误
+    fn open(&self, table: &str, key: &[u8], value: &Value) -> Result<Option<Value>, KvError> {
+        let (Some(id), Some(data)) = (key_id(value), envelope(value)) else {
+            return Ok(None);
+        };
+        let cipher = self.keys.get(&id).ok_or_else(|| {
+            KvError::Internal(format!(
+                "encryption key {} of {}/{} is not configured",
+                id,
+                table,
+                String::from_utf8_lossy(key)
+            ))
+        })?;
+        let nonce = Nonce::try_assume_unique_for_key(&data[MAGIC.len() + 4..HEADER_LEN])
+            .map_err(|_| KvError::Internal("invalid nonce".into()))?;
+        let mut buf = data[HEADER_LEN..].to_vec();
+        let plain = cipher
+            .open_in_place(nonce, Aad::from(aad(table, key)), &mut buf)
+            .map_err(|_| {
+                KvError::Internal(format!(
+                    "failed to decrypt value of {}/{}",
+                    table,
+                    String::from_utf8_lossy(key)
+                ))
+            })?;
+        Ok(Some(Value::decode(&*plain)?))
+    }
+}
+
+/// 透明地加密 value 的存储：value 加密之后再交给内部的存储，读出来之后再解密。
+/// 没有加密过的 value 原样返回，已有的数据可以在开启加密之后通过 reencrypt 逐步加密。
+/// key、过期时间和 sorted set 不加密：member 要按原样比较，再次 ZADD 同一个 member 时才能只更新 score，
+/// 而每次加密的密文都不一样，所以敏感数据不要放在 key 和 member 里。
+/// 索引建立在密文上没有意义，不支持建立索引
+pub struct EncryptedStorage<S> {
+    inner: S,
+    keyring: Keyring,
+}
+
+impl<S: Storage> EncryptedStorage<S> {
+    pub fn new(inner: S, keyring: Keyring) -> Self {
+        Self { inner, keyring }
+    }
+
+    /// 把没有加密或者不是用 active 的 key 加密的 value 用 active 的 key 重新加密，
+    /// 不改变过期时间，返回重新加密的 key 数量
+    pub fn reencrypt(&self) -> Result<usize, KvError> {
+        let mut count = 0;
+        for table in self.inner.tables()? {
+            let mut start = None;
+            loop {
+                let pairs = self
+                    .inner
+                    .scan_after(table.as_str(), start, REENCRYPT_BATCH)?;
+                let Some(last) = pairs.last() else {
+                    break;
+                };
+                start = Some(last.key.clone());
+                for pair in pairs {
+                    if self.reencrypt_key(&table, &pair.key)? {
+                        count += 1;
+                    }
+                }
+            }
+        }
+        Ok(count)
+    }
+
+    fn reencrypt_key(&self, table: &str, key: &[u8]) -> Result<bool, KvError> {
+        let mut skipped = false;
+        let res = self.inner.update(table, key, |current| match current {
+            Some(value) if key_id(value) != Some(self.keyring.active) => {
+                let plain = self.keyring.decrypt(table, key, value.clone())?;
+                Ok((self.keyring.encrypt(table, key, &plain)?, ()))
+            }
+            // 已经被删掉，或者已经是用 active 的 key 加密的
+            _ => {
+                skipped = true;
+                Err(KvError::Conflict("already encrypted".into()))
+            }
+        });
+        match res {
+            Ok(()) => Ok(true),
+            Err(_) if skipped => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn decrypt_value(
+        &self,
+        table: &str,
+        key: &[u8],
+        value: Option<Value>,
+    ) -> Result<Option<Value>, KvError> {
+        value
+            .map(|v| self.keyring.decrypt(table, key, v))
+            .transpose()
+    }
+
+    fn decrypt_pair(&self, table: &str, pair: Kvpair) -> Result<Kvpair, KvError> {
+        let value = self.decrypt_value(table, &pair.key, pair.value)?;
+        Ok(Kvpair {
+            key: pair.key,
+            value,
+        })
+    }
+
+    fn decrypt_pairs(&self, table: &str, pairs: Vec<Kvpair>) -> Result<Vec<Kvpair>, KvError> {
+        pairs
+            .into_iter()
+            .map(|pair| self.decrypt_pair(table, pair))
+            .collect()
+    }
+
+    /// iterator 没法返回错误，解密失败的 kv pair 记录日志之后跳过
+    fn decrypt_iter<'a>(
+        &'a self,
+        table: String,
+        iter: impl Iterator<Item = Kvpair> + 'a,
+    ) -> impl Iterator<Item = Kvpair> + 'a {
+        iter.filter_map(move |pair| match self.decrypt_pair(&table, pair) {
+            Ok(pair) => Some(pair),
+            Err(e) => {
+                warn!("Skipped value that can't be decrypted: {}", e);
+                None
+            }
+        })
+    }
+
+    fn encrypt_record(&self, record: WalRecord) -> Result<WalRecord, KvError> {
+        match record.op {
+            Some(Op::Set(value)) => {
+                let value = self.keyring.encrypt(&record.table, &record.key, &value)?;
+                Ok(WalRecord::new_set(record.table, record.key, value))
+            }
+            Some(Op::Batch(batch)) => {
+                let records = batch
+                    .records
+                    .into_iter()
+                    .map(|r| self.encrypt_record(r))
+                    .collect::<Result<_, _>>()?;
+                Ok(WalRecord::new_batch(records))
+            }
+            Some(Op::CreateIndex(_)) => Err(index_not_supported()),
+            op => Ok(WalRecord { op, ..record }),
+        }
+    }
+}
+
+impl<S: Storage> Storage for EncryptedStorage<S> {
+    fn get(
+        &self,
+        table: impl Into<String>,
+        key: impl AsRef<[u8]>,
+    ) -> Result<Option<Value>, KvError> {
+        let (table, key) = (table.into(), key.as_ref());
+        let value = self.inner.get(table.as_str(), key)?;
+        self.decrypt_value(&table, key, value)
+    }
+
+    fn set(
+        &self,
+        table: impl Into<String>,
+        key: impl AsRef<[u8]>,
+        value: Value,
+    ) -> Result<Option<Value>, KvError> {
+        let (table, key) = (table.into(), key.as_ref());
+        let value = self.keyring.encrypt(&table, key, &value)?;
+        let old = self.inner.set(table.as_str(), key, value)?;
+        self.decrypt_value(&table, key, old)
+    }
+
+    /// 密文每次都不一样，先读出密文比较明文，再用读到的密文做 compare_and_swap
+    fn compare_and_swap(
+        &self,
+        table: impl Into<String>,
+        key: impl AsRef<[u8]>,
+        expected: Option<Value>,
+        value: Value,
+    ) -> Result<(bool, Option<Value>), KvError> {
+        let (table, key) = (table.into(), key.as_ref());
+        loop {
+            let raw = self.inner.get(table.as_str(), key)?;
+            let current = self.decrypt_value(&table, key, raw.clone())?;
+            if current != expected {
+                return Ok((false, current));
+            }
+            let encrypted = self.keyring.encrypt(&table, key, &value)?;
+            // 读取之后 key 被别人修改了就重新读取
+            if self
+                .inner
+                .compare_and_swap(table.as_str(), key, raw, encrypted)?
+                .0
+            {
+                return Ok((true, Some(value)));
+            }
+        }
+    }
+
+    fn incr(
+        &self,
+        table: impl Into<String>,
+        key: impl AsRef<[u8]>,
+        delta: i64,
+    ) -> Result<i64, KvError> {
+        let key = key.as_ref();
+        self.update(table, key, |current| {
+            let value = incr_value(key, current, delta)?;
+            Ok((value.into(), value))
+        })
+    }
+
+    fn update<T>(
+        &self,
+        table: impl Into<String>,
+        key: impl AsRef<[u8]>,
+        mut f: impl FnMut(Option<&Value>) -> Result<(Value, T), KvError>,
+    ) -> Result<T, KvError> {
+        let (table, key) = (table.into(), key.as_ref());
+        self.inner.update(table.as_str(), key, |current| {
+            let current = self.decrypt_value(&table, key, current.cloned())?;
+            let (value, result) = f(current.as_ref())?;
+            Ok((self.keyring.encrypt(&table, key, &value)?, result))
+        })
+    }
+
+    fn contains(&self, table: impl Into<String>, key: impl AsRef<[u8]>) -> Result<bool, KvError> {
+        self.inner.contains(table, key)
+    }
+
+    fn del(
+        &self,
+        table: impl Into<String>,
+        key: impl AsRef<[u8]>,
+    ) -> Result<Option<Value>, KvError> {
+        let (table, key) = (table.into(), key.as_ref());
+        let old = self.inner.del(table.as_str(), key)?;
+        self.decrypt_value(&table, key, old)
+    }
+
+    fn mget(
+        &self,
+        table: impl Into<String>,
+        keys: &[impl AsRef<[u8]>],
+    ) -> Result<Vec<Option<Value>>, KvError> {
+        let table = table.into();
+        let values = self.inner.mget(table.as_str(), keys)?;
+        keys.iter()
+            .zip(values)
+            .map(|(key, value)| self.decrypt_value(&table, key.as_ref(), value))
+            .collect()
+    }
+
+    fn get_all(&self, table: impl Into<String>) -> Result<Vec<Kvpair>, KvError> {
+        let table = table.into();
+        let pairs = self.inner.get_all(table.as_str())?;
+        self.decrypt_pairs(&table, pairs)
+    }
+
+    fn get_iter(&self, table: impl Into<String>) -> Result<impl Iterator<Item = Kvpair>, KvError> {
+        let table = table.into();
+        let iter = self.inner.get_iter(table.clone())?;
+        Ok(self.decrypt_iter(table, iter))
+    }
+
+    fn scan_prefix(
+        &self,
+        table: impl Into<String>,
+        prefix: impl AsRef<[u8]>,
+    ) -> Result<impl Iterator<Item = Kvpair>, KvError> {
+        let table = table.into();
+        let prefix = Bytes::copy_from_slice(prefix.as_ref());
+        let iter = self.inner.scan_prefix(table.clone(), prefix)?;
+        Ok(self.decrypt_iter(table, iter))
+    }
+
+    fn range(
+        &self,
+        table: impl Into<String>,
+        range: impl RangeBounds<Bytes>,
+    ) -> Result<Vec<Kvpair>, KvError> {
+        let table = table.into();
+        let pairs = self.inner.range(table.as_str(), range)?;
+        self.decrypt_pairs(&table, pairs)
+    }
+
+    fn scan_after(
+        &self,
+        table: impl Into<String>,
+        start: Option<Bytes>,
+        limit: usize,
+    ) -> Result<Vec<Kvpair>, KvError> {
+        let table = table.into();
+        let pairs = self.inner.scan_after(table.as_str(), start, limit)?;
+        self.decrypt_pairs(&table, pairs)
+    }
+
+    fn expire(
+        &self,
+        table: impl Into<String>,
+        key: impl AsRef<[u8]>,
+        ttl: Duration,
+    ) -> Result<bool, KvError> {
+        self.inner.expire(table, key, ttl)
+    }
+
+    fn ttl(
+        &self,
+        table: impl Into<String>,
+        key: impl AsRef<[u8]>,
+    ) -> Result<Option<Duration>, KvError> {
+        self.inner.ttl(table, key)
+    }
+
+    fn purge_expired(&self) -> Result<usize, KvError> {
+        self.inner.purge_expired()
+    }
+
+    fn tables(&self) -> Result<Vec<String>, KvError> {
+        self.inner.tables()
+    }
+
+    fn backend(&self) -> &'static str {
+        self.inner.backend()
+    }
+
+    fn stats(&self) -> Result<Vec<(String, TableStats)>, KvError> {
+        self.inner.stats()
+    }
+
+    fn truncate_table(&self, table: impl Into<String>) -> Result<usize, KvError> {
+        self.inner.truncate_table(table)
+    }
+
+    fn drop_table(&self, table: impl Into<String>) -> Result<usize, KvError> {
+        self.inner.drop_table(table)
+    }
+
+    fn version(&self, table: impl Into<String>, key: impl AsRef<[u8]>) -> Result<u64, KvError> {
+        self.inner.version(table, key)
+    }
+
+    fn apply_batch(
+        &self,
+        records: Vec<WalRecord>,
+        watched: &[KeyVersion],
+    ) -> Result<bool, KvError> {
+        let records = records
+            .into_iter()
+            .map(|r| self.encrypt_record(r))
+            .collect::<Result<_, _>>()?;
+        self.inner.apply_batch(records, watched)
+    }
+
+    fn zadd(
+        &self,
+        table: impl Into<String>,
+        key: impl AsRef<[u8]>,
+        members: Vec<ScoredMember>,
+    ) -> Result<usize, KvError> {
+        self.inner.zadd(table, key, members)
+    }
+
+    fn zrange(
+        &self,
+        table: impl Into<String>,
+        key: impl AsRef<[u8]>,
+        start: i64,
+        stop: i64,
+    ) -> Result<Vec<ScoredMember>, KvError> {
+        self.inner.zrange(table, key, start, stop)
+    }
+
+    fn zrange_by_score(
+        &self,
+        table: impl Into<String>,
+        key: impl AsRef<[u8]>,
+        min: f64,
+        max: f64,
+    ) -> Result<Vec<ScoredMember>, KvError> {
+        self.inner.zrange_by_score(table, key, min, max)
+    }
+
+    fn zsets(&self) -> Result<Vec<(String, Bytes)>, KvError> {
+        self.inner.zsets()
+    }
+
+    fn create_index(&self, _table: impl Into<String>, _spec: IndexSpec) -> Result<bool, KvError> {
+        Err(index_not_supported())
+    }
+
+    fn query(
+        &self,
+        table: impl Into<String>,
+        index: &str,
+        value: &Value,
+    ) -> Result<Vec<Kvpair>, KvError> {
+        self.inner.query(table, index, value)
+    }
+
+    fn indexes(&self) -> Result<Vec<(String, IndexSpec)>, KvError> {
+        self.inner.indexes()
+    }
+
+    fn flush(&self) -> Result<(), KvError> {
+        self.inner.flush()
+    }
+
+    fn compact(&self) -> Result<u64, KvError> {
+        self.inner.compact()
+    }
+
+    fn disk_usage(&self) -> Result<Option<u64>, KvError> {
+        self.inner.disk_usage()
+    }
+
+    fn is_blocking(&self) -> bool {
+        self.inner.is_blocking()
+    }
+
+    fn memory_limit(&self) -> Option<&MemoryLimit> {
+        self.inner.memory_limit()
+    }
+
+    fn quotas(&self) -> Option<&Quotas> {
+        self.inner.quotas()
+    }
+
+    /// SNAPSHOT、备份和复制的全量同步使用 snapshot，会被写到磁盘上，所以导出的是密文：
+    /// 还没有加密的 value 用 active 的 key 加密，已经加密的原样导出。
+    /// 导入到配置了同样的 key 的存储时不会被加密两次，replica 也需要配置同样的 key
+    fn snapshot(&self) -> Result<Vec<WalRecord>, KvError> {
+        self.inner
+            .snapshot()?
+            .into_iter()
+            .map(|r| match r.op {
+                // 开启加密之前建立的索引
+                Some(Op::CreateIndex(_)) => Ok(r),
+                _ => self.encrypt_record(r),
+            })
+            .collect()
+    }
+}
+
+/// 密文绑定 table 和 key，不能被挪到别的 key 下面
+fn aad(table: &str, key: &[u8]) -> Vec<u8> {
+    let mut aad = Vec::with_capacity(table.len() + 1 + key.len());
+    aad.extend_from_slice(table.as_bytes());
+    aad.push(0);
+    aad.extend_from_slice(key);
+    aad
+}
+
+/// value 是加密之后的格式时返回整个 Binary
+fn envelope(value: &Value) -> Option<&Bytes> {
+    match &value.value {
+        Some(value::Value::Binary(data)) if data.len() > HEADER_LEN && data.starts_with(MAGIC) => {
+            Some(data)
+        }
+        _ => None,
+    }
+}
+
+/// 加密 value 使用的 key 的 id，没有加密的 value 返回 None
+fn key_id(value: &Value) -> Option<u32> {
+    let data = envelope(value)?;
+    let id = data[MAGIC.len()..MAGIC.len() + 4].try_into().ok()?;
+    Some(u32::from_be_bytes(id))
+}
+
+fn invalid(msg: String) -> KvError {
+    KvError::InvalidCommand(msg)
+}
+
+fn index_not_supported() -> KvError {
+    KvError::InvalidCommand("indexes are not supported on encrypted storage".into())
+}
+
+#[cfg(test)]
+mod encrypted_tests {
+    use super::*;
+    use crate::{
+        config::EncryptionKey,
+        storage::{
+            memory::MemTable,
+            tests::{
+                test_apply_batch, test_basic_interface, test_compare_and_swap, test_expiration,
+                test_get_all, test_incr, test_mget_mset, test_set_if, test_update,
+            },
+        },
+    };
+
+    fn config(cipher: Cipher, active: u32, ids: &[u32]) -> EncryptionConfig {
+        let keys = ids
+            .iter()
+            .map(|&id| EncryptionKey {
+                id,
+                key: Some(STANDARD.encode([id as u8; 32])),
+                env: None,
+            })
+            .collect();
+        EncryptionConfig {
+            cipher,
+            active,
+            keys,
+            reencrypt: false,
+        }
+    }
+
+    fn encrypted(cipher: Cipher) -> EncryptedStorage<MemTable> {
+        let keyring = Keyring::from_config(&config(cipher, 1, &[1])).unwrap();
+        EncryptedStorage::new(MemTable::new(), keyring)
+    }
+
+    #[test]
+    fn encrypted_storage_should_pass_storage_tests() {
+        for cipher in [Cipher::Aes256Gcm, Cipher::ChaCha20Poly1305] {
+            test_basic_interface(encrypted(cipher));
+            test_get_all(encrypted(cipher));
+            test_expiration(encrypted(cipher));
+            test_compare_and_swap(encrypted(cipher));
+            test_incr(encrypted(cipher));
+            test_update(encrypted(cipher));
+            test_apply_batch(encrypted(cipher));
+            test_mget_mset(encrypted(cipher));
+            test_set_if(encrypted(cipher));
+        }
+    }
+
+    #[test]
+    fn values_should_be_encrypted_in_inner_storage() {
+        let store = encrypted(Cipher::Aes256Gcm);
+        store.set("t1", "k1", "secret".into()).unwrap();
+        let raw = store.inner.get("t1", "k1").unwrap().unwrap();
+        assert_eq!(key_id(&raw), Some(1));
+        assert!(!format!("{:?}", raw).contains("secret"));
+        assert_eq!(store.get("t1", "k1").unwrap(), Some("secret".into()));
+
+        // 密文挪到别的 key 下面不能被解密
+        store.inner.set("t1", "k2", raw.clone()).unwrap();
+        assert!(store.get("t1", "k2").is_err());
+        store.del("t1", "k2").unwrap_err();
+        store.inner.del("t1", "k2").unwrap();
+
+        // 快照中是密文，导入时不会把已经加密的 value 再加密一次
+        store.inner.set("t1", "plain", "v0".into()).unwrap();
+        let snapshot = store.snapshot().unwrap();
+        assert_eq!(snapshot.len(), 2);
+        assert!(snapshot
+            .iter()
+            .all(|r| matches!(&r.op, Some(Op::Set(v)) if key_id(v) == Some(1))));
+        let restored = encrypted(Cipher::Aes256Gcm);
+        restored.apply_batch(snapshot, &[]).unwrap();
+        assert_eq!(restored.inner.get("t1", "k1").unwrap(), Some(raw.clone()));
+        assert_eq!(restored.get("t1", "k1").unwrap(), Some("secret".into()));
+        assert_eq!(restored.get("t1", "plain").unwrap(), Some("v0".into()));
+        store.set("t1", "k1", raw).unwrap();
+        assert_eq!(store.get("t1", "k1").unwrap(), Some("secret".into()));
+
+        assert!(store.create_index("t1", IndexSpec::default()).is_err());
+    }
+
+    #[test]
+    fn zset_members_should_not_be_encrypted() {
+        let store = encrypted(Cipher::Aes256Gcm);
+        store.zadd("t1", "z1", vec![("m1", 1.0).into()]).unwrap();
+        // member 原样保存，再次 ZADD 同一个 member 只更新 score
+        store.zadd("t1", "z1", vec![("m1", 2.0).into()]).unwrap();
+        let members = store.inner.zrange("t1", "z1", 0, -1).unwrap();
+        assert_eq!(members, vec![ScoredMember::from(("m1", 2.0))]);
+        assert_eq!(store.zrange("t1", "z1", 0, -1).unwrap(), members);
+        assert!(format!("{:?}", store.snapshot().unwrap()).contains("m1"));
+    }
+
+    #[test]
+    fn keys_should_be_rotated() {
+        let store = MemTable::new();
+        store.set("t1", "plain", "v0".into()).unwrap();
+        let old = EncryptedStorage::new(
+            store,
+            Keyring::from_config(&config(Cipher::Aes256Gcm, 1, &[1])).unwrap(),
+        );
+        old.set("t1", "k1", "v1".into()).unwrap();
+        old.expire("t1", "k1", Duration::from_secs(100)).unwrap();
+
+        // 换成新的 key 之后，旧的 key 加密的 value 和没有加密的 value 都能读取
+        let store = EncryptedStorage::new(
+            old.inner,
+            Keyring::from_config(&config(Cipher::Aes256Gcm, 2, &[1, 2])).unwrap(),
+        );
+        store.set("t1", "k2", "v2".into()).unwrap();
+        assert_eq!(store.get("t1", "plain").unwrap(), Some("v0".into()));
+        assert_eq!(store.get("t1", "k1").unwrap(), Some("v1".into()));
+        assert_eq!(
+            key_id(&store.inner.get("t1", "k2").unwrap().unwrap()),
+            Some(2)
+        );
+
+        assert_eq!(store.reencrypt().unwrap(), 2);
+        assert_eq!(store.reencrypt().unwrap(), 0);
+        assert!(store.ttl("t1", "k1").unwrap().is_some());
+
+        // 重新加密之后不再需要旧的 key
+        let store = EncryptedStorage::new(
+            store.inner,
+            Keyring::from_config(&config(Cipher::Aes256Gcm, 2, &[2])).unwrap(),
+        );
+        let mut pairs = store.get_all("t1").unwrap();
+        pairs.sort_by(|a, b| a.key.cmp(&b.key));
+        let values: Vec<_> = pairs.into_iter().filter_map(|p| p.value).collect();
+        assert_eq!(values, vec!["v1".into(), "v2".into(), "v0".into()]);
+    }
+
+    #[test]
+    fn invalid_keys_should_be_rejected() {
+        let mut bad = config(Cipher::Aes256Gcm, 1, &[1]);
+        bad.keys[0].key = Some(STANDARD.encode([0u8; 16]));
+        assert!(Keyring::from_config(&bad).is_err());
+
+        assert!(Keyring::from_config(&config(Cipher::Aes256Gcm, 2, &[1])).is_err());
+        assert!(Keyring::from_config(&config(Cipher::Aes256Gcm, 1, &[1, 1])).is_err());
+
+        let mut from_env = config(Cipher::Aes256Gcm, 1, &[1]);
+        from_env.keys[0].key = None;
+        from_env.keys[0].env = Some("KV_SERVER_TEST_ENCRYPTION_KEY".into());
+        assert!(Keyring::from_config(&from_env).is_err());
+        env::set_var("KV_SERVER_TEST_ENCRYPTION_KEY", STANDARD.encode([7u8; 32]));
+        assert!(Keyring::from_config(&from_env).is_ok());
+    }
+}
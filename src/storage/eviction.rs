@@ -0,0 +1,156 @@
+use std::{
+    collections::hash_map::RandomState,
+    hash::BuildHasher,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        RwLock,
+    },
+};
+
+use prost::Message;
+
+use crate::{
+    config::{parse_size, EvictionPolicy, MemTableConfig},
+    error::KvError,
+    pb::abi::Value,
+};
+
+/// 除了 key 和 value 之外，每个 key 额外占用的内存（估算）
+const ENTRY_OVERHEAD: usize = 64;
+
+/// 内存上限和当前的使用量，使用量按照 key 和 value 的大小估算
+#[derive(Debug, Default)]
+pub struct MemoryLimit {
+    /// 0 表示不限制
+    max_memory: AtomicUsize,
+    policy: RwLock<EvictionPolicy>,
+    used: AtomicUsize,
+    /// 每次读写 key 加一，用来比较 key 最后一次访问的先后
+    clock: AtomicU64,
+}
+
+/// 单个 key 占用的内存和访问情况
+#[derive(Debug, Clone)]
+pub(crate) struct Usage {
+    size: usize,
+    last_access: u64,
+    hits: u32,
+}
+
+impl MemoryLimit {
+    pub fn configure(&self, config: &MemTableConfig) -> Result<(), KvError> {
+        let max_memory = match &config.max_memory {
+            Some(size) => parse_size(size)?,
+            None => 0,
+        };
+        self.set_max_memory(max_memory);
+        self.set_policy(config.eviction);
+        Ok(())
+    }
+
+    pub fn max_memory(&self) -> usize {
+        self.max_memory.load(Ordering::Relaxed)
+    }
+
+    /// 设置内存上限，0 表示不限制；超过的部分在下一次写入时淘汰
+    pub fn set_max_memory(&self, max_memory: usize) {
+        self.max_memory.store(max_memory, Ordering::Relaxed);
+    }
+
+    pub fn policy(&self) -> EvictionPolicy {
+        *self.policy.read().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub fn set_policy(&self, policy: EvictionPolicy) {
+        *self.policy.write().unwrap_or_else(|e| e.into_inner()) = policy;
+    }
+
+    /// 当前使用的内存
+    pub fn used(&self) -> usize {
+        self.used.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn is_over(&self) -> bool {
+        let max_memory = self.max_memory();
+        max_memory > 0 && self.used() > max_memory
+    }
+
+    /// 淘汰时一直删到上限的 15/16 以下，避免之后每次写入都要扫描所有的 key
+    pub(crate) fn target(&self) -> usize {
+        let max_memory = self.max_memory();
+        max_memory - max_memory / 16
+    }
+
+    pub(crate) fn out_of_memory(&self) -> KvError {
+        KvError::OutOfMemory(format!(
+            "used memory {} is over max-memory {}",
+            self.used(),
+            self.max_memory()
+        ))
+    }
+
+    /// 新增一个 key
+    pub(crate) fn alloc(&self, size: usize) -> Usage {
+        self.used.fetch_add(size, Ordering::Relaxed);
+        Usage {
+            size,
+            last_access: self.tick(),
+            hits: 1,
+        }
+    }
+
+    /// key 的值被修改了
+    pub(crate) fn resize(&self, usage: &mut Usage, size: usize) {
+        self.used.fetch_add(size, Ordering::Relaxed);
+        self.used.fetch_sub(usage.size, Ordering::Relaxed);
+        usage.size = size;
+        self.access(usage);
+    }
+
+    /// key 被删除了
+    pub(crate) fn free(&self, usage: Usage) {
+        self.used.fetch_sub(usage.size, Ordering::Relaxed);
+    }
+
+    pub(crate) fn access(&self, usage: &mut Usage) {
+        usage.last_access = self.tick();
+        usage.hits = usage.hits.saturating_add(1);
+    }
+
+    fn tick(&self) -> u64 {
+        self.clock.fetch_add(1, Ordering::Relaxed)
+    }
+}
+
+impl Clone for MemoryLimit {
+    fn clone(&self) -> Self {
+        Self {
+            max_memory: self.max_memory().into(),
+            policy: RwLock::new(self.policy()),
+            used: self.used().into(),
+            clock: self.clock.load(Ordering::Relaxed).into(),
+        }
+    }
+}
+
+impl Usage {
+    /// 估算的占用内存
+    pub(crate) fn size(&self) -> usize {
+        self.size
+    }
+
+    /// 淘汰的顺序，越小越先淘汰
+    pub(crate) fn rank(&self, policy: EvictionPolicy, random: &RandomState, key: &[u8]) -> u64 {
+        match policy {
+            EvictionPolicy::Lru => self.last_access,
+            EvictionPolicy::Lfu => self.hits as u64,
+            EvictionPolicy::Random => random.hash_one(key),
+            EvictionPolicy::RejectWrites => 0,
+        }
+    }
+}
+
+/// key 和 value 占用的内存（估算）
+pub(crate) fn entry_size(key: &[u8], value: &Value) -> usize {
+    key.len() + value.encoded_len() + ENTRY_OVERHEAD
+}
@@ -0,0 +1,453 @@
+use super::{now_millis, Storage};
+use crate::{
+    error::KvError,
+    pb::abi::{value, Value, ValueList, ValueMap, ValueSet},
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use bytes::Bytes;
+use serde_json::{json, Map, Value as JsonValue};
+use std::{
+    io::{BufRead, BufReader, BufWriter, Read, Write},
+    str::FromStr,
+    time::Duration,
+};
+
+/// 不是 UTF-8 的 key（以及本身以这个前缀开头的 key）加上前缀之后用 base64 编码
+const BASE64_KEY_PREFIX: &str = "base64:";
+
+/// CSV 的表头
+const CSV_HEADER: [&str; 5] = ["table", "key", "type", "value", "expire_at"];
+
+/// 导出文件的格式
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// 每行一个 JSON 对象：{"table", "key", "value": {"type", "value"}, "expire_at"}
+    Json,
+    /// 表头为 table,key,type,value,expire_at；list/set/map 的 value 是带类型的 JSON
+    Csv,
+}
+
+impl FromStr for Format {
+    type Err = KvError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "json" | "jsonl" => Ok(Self::Json),
+            "csv" => Ok(Self::Csv),
+            _ => Err(KvError::InvalidCommand(format!(
+                "unknown export format {}, expect json or csv",
+                s
+            ))),
+        }
+    }
+}
+
+/// 导出的一个 key
+#[derive(Debug, Clone, PartialEq)]
+struct Entry {
+    table: String,
+    key: Bytes,
+    value: Value,
+    /// 过期时刻的毫秒时间戳
+    expire_at: Option<u64>,
+}
+
+/// 把 store 中所有 table 的 kvpair 按 format 写到 writer，返回导出的 key 数量。
+/// value 带着类型导出，导入之后类型不变；sorted set 和索引不会导出，完整的备份请使用 snapshot
+pub fn export(store: &impl Storage, writer: impl Write, format: Format) -> Result<usize, KvError> {
+    let mut sink = match format {
+        Format::Json => Sink::Json(BufWriter::new(writer)),
+        Format::Csv => {
+            let mut writer = csv::Writer::from_writer(writer);
+            writer.write_record(CSV_HEADER).map_err(csv_error)?;
+            Sink::Csv(Box::new(writer))
+        }
+    };
+    let mut tables = store.tables()?;
+    tables.sort();
+    let mut count = 0;
+    for table in tables {
+        for pair in store.get_iter(table.as_str())? {
+            let ttl = store.ttl(table.as_str(), &pair.key)?;
+            let entry = Entry {
+                table: table.clone(),
+                key: pair.key,
+                value: pair.value.unwrap_or_default(),
+                expire_at: ttl.map(|ttl| now_millis() + ttl.as_millis() as u64),
+            };
+            sink.write(&entry)?;
+            count += 1;
+        }
+    }
+    sink.flush()?;
+    Ok(count)
+}
+
+/// 把 export 导出的数据导入到 store，返回导入的 key 数量；已经过期的 key 会被跳过。
+/// 遇到格式错误时返回带行号的错误，之前的行已经导入了
+pub fn import(store: &impl Storage, reader: impl Read, format: Format) -> Result<usize, KvError> {
+    let mut count = 0;
+    let mut load = |entry: Entry| -> Result<(), KvError> {
+        let now = now_millis();
+        match entry.expire_at {
+            Some(deadline) if deadline <= now => return Ok(()),
+            _ => {}
+        }
+        store.set(entry.table.as_str(), &entry.key, entry.value)?;
+        if let Some(deadline) = entry.expire_at {
+            let ttl = Duration::from_millis(deadline - now);
+            store.expire(entry.table, &entry.key, ttl)?;
+        }
+        count += 1;
+        Ok(())
+    };
+    match format {
+        Format::Json => {
+            for (i, line) in BufReader::new(reader).lines().enumerate() {
+                let line = line?;
+                if line.trim().is_empty() {
+                    continue;
+                }
+                load(from_json(&line).map_err(|e| at_line(i as u64 + 1, e))?)?;
+            }
+        }
+        Format::Csv => {
+            let mut reader = csv::Reader::from_reader(reader);
+            if reader.headers().map_err(csv_error)? != CSV_HEADER.as_slice() {
+                return Err(KvError::InvalidCommand(format!(
+                    "CSV header must be {}",
+                    CSV_HEADER.join(",")
+                )));
+            }
+            for record in reader.records() {
+                let record = record.map_err(csv_error)?;
+                let line = record.position().map_or(0, |p| p.line());
+                load(from_csv(&record).map_err(|e| at_line(line, e))?)?;
+            }
+        }
+    }
+    Ok(count)
+}
+
+/// 导出的目标
+enum Sink<W: Write> {
+    Json(BufWriter<W>),
+    Csv(Box<csv::Writer<W>>),
+}
+
+impl<W: Write> Sink<W> {
+    fn write(&mut self, entry: &Entry) -> Result<(), KvError> {
+        match self {
+            Self::Json(writer) => {
+                let mut doc = Map::new();
+                doc.insert("table".into(), json!(entry.table));
+                doc.insert("key".into(), json!(encode_key(&entry.key)));
+                doc.insert("value".into(), to_tagged(&entry.value));
+                if let Some(deadline) = entry.expire_at {
+                    doc.insert("expire_at".into(), json!(deadline));
+                }
+                writeln!(writer, "{}", JsonValue::Object(doc))?;
+            }
+            Self::Csv(writer) => {
+                let tagged = to_tagged(&entry.value);
+                let value = match &tagged["value"] {
+                    JsonValue::Null => String::new(),
+                    JsonValue::String(s) => s.clone(),
+                    v => v.to_string(),
+                };
+                let expire_at = entry.expire_at.map(|v| v.to_string()).unwrap_or_default();
+                let record = [
+                    entry.table.as_str(),
+                    &encode_key(&entry.key),
+                    tagged["type"].as_str().unwrap_or_default(),
+                    &value,
+                    &expire_at,
+                ];
+                writer.write_record(record).map_err(csv_error)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), KvError> {
+        match self {
+            Self::Json(writer) => writer.flush()?,
+            Self::Csv(writer) => writer.flush()?,
+        }
+        Ok(())
+    }
+}
+
+fn from_json(line: &str) -> Result<Entry, KvError> {
+    let doc: JsonValue = serde_json::from_str(line).map_err(|e| invalid(e.to_string()))?;
+    let field = |name: &str| doc.get(name).filter(|v| !v.is_null());
+    let table = field("table").and_then(JsonValue::as_str);
+    let key = field("key").and_then(JsonValue::as_str);
+    let (Some(table), Some(key), Some(value)) = (table, key, field("value")) else {
+        return Err(invalid("table, key and value are required"));
+    };
+    let expire_at = match field("expire_at") {
+        Some(v) => Some(v.as_u64().ok_or_else(|| invalid("invalid expire_at"))?),
+        None => None,
+    };
+    Ok(Entry {
+        table: table.into(),
+        key: decode_key(key)?,
+        value: from_tagged(value)?,
+        expire_at,
+    })
+}
+
+fn from_csv(record: &csv::StringRecord) -> Result<Entry, KvError> {
+    let [table, key, ty, text, expire_at] = [0, 1, 2, 3, 4].map(|i| record.get(i));
+    let (Some(table), Some(key), Some(ty), Some(text)) = (table, key, ty, text) else {
+        return Err(invalid("table, key, type and value are required"));
+    };
+    // 标量直接是文本，数字、bool 和 list/set/map 按 JSON 解析
+    let value = match ty {
+        "null" => JsonValue::Null,
+        "string" | "binary" | "json" | "float" => json!(text),
+        _ => serde_json::from_str(text).map_err(|_| invalid_value(ty, text))?,
+    };
+    let expire_at = match expire_at.unwrap_or_default() {
+        "" => None,
+        v => Some(v.parse().map_err(|_| invalid("invalid expire_at"))?),
+    };
+    Ok(Entry {
+        table: table.into(),
+        key: decode_key(key)?,
+        value: parse_value(ty, &value)?,
+        expire_at,
+    })
+}
+
+/// 把 value 编码成 {"type": 类型, "value": 值}，list/set/map 中的每个元素也带着类型
+fn to_tagged(value: &Value) -> JsonValue {
+    let tagged = |values: &[Value]| JsonValue::Array(values.iter().map(to_tagged).collect());
+    let (ty, v) = match &value.value {
+        Some(value::Value::String(s)) => ("string", json!(s)),
+        Some(value::Value::Binary(b)) => ("binary", json!(STANDARD.encode(b))),
+        Some(value::Value::Integer(i)) => ("integer", json!(i)),
+        // JSON 没有 NaN 和无穷大，用字符串表示
+        Some(value::Value::Float(f)) => match serde_json::Number::from_f64(*f) {
+            Some(n) => ("float", JsonValue::Number(n)),
+            None => ("float", json!(f.to_string())),
+        },
+        Some(value::Value::Bool(b)) => ("bool", json!(b)),
+        Some(value::Value::Json(text)) => ("json", json!(text)),
+        Some(value::Value::List(list)) => ("list", tagged(&list.values)),
+        Some(value::Value::Set(set)) => ("set", tagged(&set.members)),
+        Some(value::Value::Map(map)) => {
+            let entries = map.entries.iter();
+            let doc = entries.map(|(k, v)| (k.clone(), to_tagged(v))).collect();
+            ("map", JsonValue::Object(doc))
+        }
+        None => return json!({ "type": "null" }),
+    };
+    json!({ "type": ty, "value": v })
+}
+
+fn from_tagged(tagged: &JsonValue) -> Result<Value, KvError> {
+    let ty = tagged
+        .get("type")
+        .and_then(JsonValue::as_str)
+        .ok_or_else(|| invalid("value type is required"))?;
+    parse_value(ty, tagged.get("value").unwrap_or(&JsonValue::Null))
+}
+
+fn parse_value(ty: &str, v: &JsonValue) -> Result<Value, KvError> {
+    let tagged_array = |v: &JsonValue| -> Result<Vec<Value>, KvError> {
+        let values = v.as_array().ok_or_else(|| invalid_value(ty, v))?;
+        values.iter().map(from_tagged).collect()
+    };
+    let value = match ty {
+        "null" => Some(Value::default()),
+        "string" => v.as_str().map(Value::from),
+        "binary" => v
+            .as_str()
+            .and_then(|s| STANDARD.decode(s).ok())
+            .map(|b| Bytes::from(b).into()),
+        "integer" => v.as_i64().map(Value::from),
+        "float" => v
+            .as_f64()
+            .or_else(|| v.as_str()?.parse().ok())
+            .map(Value::from),
+        "bool" => v.as_bool().map(Value::from),
+        "json" => v.as_str().map(|text| Value {
+            value: Some(value::Value::Json(text.into())),
+        }),
+        "list" => Some(
+            ValueList {
+                values: tagged_array(v)?,
+            }
+            .into(),
+        ),
+        "set" => Some(
+            ValueSet {
+                members: tagged_array(v)?,
+            }
+            .into(),
+        ),
+        "map" => {
+            let doc = v.as_object().ok_or_else(|| invalid_value(ty, v))?;
+            let entries = doc
+                .iter()
+                .map(|(k, v)| Ok((k.clone(), from_tagged(v)?)))
+                .collect::<Result<_, KvError>>()?;
+            Some(ValueMap { entries }.into())
+        }
+        _ => return Err(invalid(format!("unknown value type {}", ty))),
+    };
+    value.ok_or_else(|| invalid_value(ty, v))
+}
+
+fn encode_key(key: &[u8]) -> String {
+    match std::str::from_utf8(key) {
+        Ok(s) if !s.starts_with(BASE64_KEY_PREFIX) => s.into(),
+        _ => format!("{}{}", BASE64_KEY_PREFIX, STANDARD.encode(key)),
+    }
+}
+
+fn decode_key(key: &str) -> Result<Bytes, KvError> {
+    match key.strip_prefix(BASE64_KEY_PREFIX) {
+        Some(encoded) => STANDARD
+            .decode(encoded)
+            .map(Bytes::from)
+            .map_err(|_| invalid(format!("invalid base64 key {}", key))),
+        None => Ok(Bytes::from(key.to_owned())),
+    }
+}
+
+fn invalid(msg: impl Into<String>) -> KvError {
+    KvError::InvalidCommand(msg.into())
+}
+
+fn invalid_value(ty: &str, v: impl std::fmt::Display) -> KvError {
+    invalid(format!("invalid {} value {}", ty, v))
+}
+
+fn at_line(line: u64, e: KvError) -> KvError {
+    match e {
+        KvError::InvalidCommand(msg) => invalid(format!("line {}: {}", line, msg)),
+        e => e,
+    }
+}
+
+fn csv_error(e: csv::Error) -> KvError {
+    KvError::InvalidCommand(format!("invalid CSV: {}", e))
+}
+
+#[cfg(test)]
+mod export_tests {
+    use super::*;
+    use crate::memory::MemTable;
+    use std::collections::BTreeMap;
+
+    fn values() -> Vec<(&'static str, Value)> {
+        let map = ValueMap {
+            entries: BTreeMap::from([("a".into(), 1.into()), ("b".into(), "x".into())]),
+        };
+        vec![
+            ("string", "hello, \"world\"\n".into()),
+            ("binary", Bytes::from_static(&[0, 159, 146, 150]).into()),
+            ("integer", (-42).into()),
+            ("float", 1.5.into()),
+            ("infinity", f64::INFINITY.into()),
+            ("bool", true.into()),
+            ("json", serde_json::json!({"name": "kv"}).into()),
+            (
+                "list",
+                ValueList {
+                    values: vec![1.into(), "a".into()],
+                }
+                .into(),
+            ),
+            (
+                "set",
+                ValueSet {
+                    members: vec![true.into()],
+                }
+                .into(),
+            ),
+            ("map", map.into()),
+            ("null", Value::default()),
+        ]
+    }
+
+    fn round_trip(format: Format) {
+        let store = MemTable::new();
+        for (key, value) in values() {
+            store.set("t1", key, value).unwrap();
+        }
+        store.set("t2", b"\xff\xfe", "binary key".into()).unwrap();
+        store.set("t2", "base64:abc", "prefixed".into()).unwrap();
+        store
+            .expire("t2", "base64:abc", Duration::from_secs(100))
+            .unwrap();
+
+        let mut buf = Vec::new();
+        assert_eq!(
+            export(&store, &mut buf, format).unwrap(),
+            values().len() + 2
+        );
+
+        let other = MemTable::new();
+        assert_eq!(
+            import(&other, buf.as_slice(), format).unwrap(),
+            values().len() + 2
+        );
+        for (key, value) in values() {
+            assert_eq!(other.get("t1", key).unwrap(), Some(value), "{}", key);
+        }
+        assert_eq!(
+            other.get("t2", b"\xff\xfe").unwrap(),
+            Some("binary key".into())
+        );
+        assert!(other.ttl("t2", "base64:abc").unwrap().unwrap() > Duration::from_secs(90));
+        assert_eq!(other.ttl("t1", "string").unwrap(), None);
+    }
+
+    #[test]
+    fn json_export_should_round_trip() {
+        round_trip(Format::Json);
+    }
+
+    #[test]
+    fn csv_export_should_round_trip() {
+        round_trip(Format::Csv);
+    }
+
+    #[test]
+    fn import_should_skip_expired_keys() {
+        let data = "{\"table\":\"t1\",\"key\":\"k1\",\"value\":{\"type\":\"integer\",\"value\":1},\"expire_at\":1}\n\n\
+                    {\"table\":\"t1\",\"key\":\"k2\",\"value\":{\"type\":\"integer\",\"value\":2}}\n";
+        let store = MemTable::new();
+        assert_eq!(import(&store, data.as_bytes(), Format::Json).unwrap(), 1);
+        assert_eq!(store.get("t1", "k1").unwrap(), None);
+        assert_eq!(store.get("t1", "k2").unwrap(), Some(2.into()));
+
+        let data = "table,key,type,value,expire_at\nt1,k3,float,NaN,\nt1,k4,bool,false,1\n";
+        assert_eq!(import(&store, data.as_bytes(), Format::Csv).unwrap(), 1);
+        let value = store.get("t1", "k3").unwrap().unwrap().value;
+        assert!(matches!(value, Some(value::Value::Float(f)) if f.is_nan()));
+        assert_eq!(store.get("t1", "k4").unwrap(), None);
+    }
+
+    #[test]
+    fn broken_import_should_report_line() {
+        let store = MemTable::new();
+        let data = "{\"table\":\"t1\",\"key\":\"k1\",\"value\":{\"type\":\"string\",\"value\":\"v\"}}\n\
+                    {\"table\":\"t1\",\"key\":\"k2\",\"value\":{\"type\":\"integer\",\"value\":\"x\"}}\n";
+        let err = import(&store, data.as_bytes(), Format::Json).unwrap_err();
+        assert!(err.to_string().contains("line 2"), "{}", err);
+        assert_eq!(store.get("t1", "k1").unwrap(), Some("v".into()));
+
+        let data = "table,key,type,value,expire_at\nt1,k1,list,[1],\n";
+        let err = import(&store, data.as_bytes(), Format::Csv).unwrap_err();
+        assert!(err.to_string().contains("line 2"), "{}", err);
+
+        let data = "table,key,value\nt1,k1,v\n";
+        assert!(import(&store, data.as_bytes(), Format::Csv).is_err());
+        assert!("xml".parse::<Format>().is_err());
+    }
+}
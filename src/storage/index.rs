@@ -0,0 +1,171 @@
+use std::collections::{BTreeSet, HashMap};
+
+use bytes::Bytes;
+use prost::Message;
+
+use crate::{
+    error::KvError,
+    pb::{
+        abi::{value, IndexSpec, Value},
+        to_key,
+    },
+};
+
+/// 结构化的 value 编码之后的前缀，和文本区分开
+const ENCODED_TERM: u8 = 0xff;
+
+/// 内存中的一个索引：被索引的部分（term）到 key 的映射，以及反过来 key 到 term 的映射
+#[derive(Debug, Default, Clone)]
+pub(crate) struct Index {
+    pub(crate) spec: IndexSpec,
+    keys: HashMap<Vec<u8>, BTreeSet<Bytes>>,
+    terms: HashMap<Bytes, Vec<u8>>,
+}
+
+impl Index {
+    pub(crate) fn new(spec: IndexSpec) -> Self {
+        Self {
+            spec,
+            ..Default::default()
+        }
+    }
+
+    /// key 的值变成了 value，value 为 None 表示 key 被删除了
+    pub(crate) fn update(&mut self, key: &[u8], value: Option<&Value>) {
+        let term = value.and_then(|v| index_term(v, &self.spec.field));
+        if self.terms.get(key) == term.as_ref() {
+            return;
+        }
+        if let Some(old) = self.terms.remove(key) {
+            if let Some(keys) = self.keys.get_mut(&old) {
+                keys.remove(key);
+                if keys.is_empty() {
+                    self.keys.remove(&old);
+                }
+            }
+        }
+        if let Some(term) = term {
+            let key = to_key(key);
+            self.keys
+                .entry(term.clone())
+                .or_default()
+                .insert(key.clone());
+            self.terms.insert(key, term);
+        }
+    }
+
+    /// term 对应的所有 key，按 key 排序
+    pub(crate) fn get(&self, term: &[u8]) -> Vec<Bytes> {
+        self.keys
+            .get(term)
+            .map(|keys| keys.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+}
+
+/// value 中被索引的部分：field 为空时是整个 value，否则沿着 field 进入 map 或者 JSON 文档。
+/// 没有这个 field 时返回 None，不加入索引
+pub(crate) fn index_term(value: &Value, field: &[String]) -> Option<Vec<u8>> {
+    let Some((first, rest)) = field.split_first() else {
+        return value_term(value);
+    };
+    match value.value.as_ref()? {
+        value::Value::Map(map) => index_term(map.entries.get(first)?, rest),
+        value::Value::Json(text) => {
+            let doc: serde_json::Value = serde_json::from_str(text).ok()?;
+            field
+                .iter()
+                .try_fold(&doc, |v, field| v.as_object()?.get(field))
+                .and_then(json_term)
+        }
+        _ => None,
+    }
+}
+
+/// string、integer、float 和 bool 都按文本比较，其它类型按 prost 编码之后的字节比较
+fn value_term(value: &Value) -> Option<Vec<u8>> {
+    let term = match value.value.as_ref()? {
+        value::Value::String(s) => s.as_bytes().to_vec(),
+        value::Value::Binary(b) => b.to_vec(),
+        value::Value::Integer(i) => i.to_string().into_bytes(),
+        value::Value::Float(f) => f.to_string().into_bytes(),
+        value::Value::Bool(b) => b.to_string().into_bytes(),
+        value::Value::Json(text) => {
+            let doc: serde_json::Value = serde_json::from_str(text).ok()?;
+            return json_term(&doc);
+        }
+        _ => encoded(&value.encode_to_vec()),
+    };
+    Some(term)
+}
+
+fn json_term(v: &serde_json::Value) -> Option<Vec<u8>> {
+    let term = match v {
+        serde_json::Value::Null => return None,
+        serde_json::Value::String(s) => s.as_bytes().to_vec(),
+        serde_json::Value::Number(n) => n.to_string().into_bytes(),
+        serde_json::Value::Bool(b) => b.to_string().into_bytes(),
+        _ => encoded(v.to_string().as_bytes()),
+    };
+    Some(term)
+}
+
+fn encoded(data: &[u8]) -> Vec<u8> {
+    let mut term = vec![ENCODED_TERM];
+    term.extend_from_slice(data);
+    term
+}
+
+/// 索引名不能为空
+pub(crate) fn check_spec(spec: &IndexSpec) -> Result<(), KvError> {
+    match spec.name.is_empty() {
+        true => Err(KvError::InvalidCommand("index name is empty".into())),
+        false => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod index_tests {
+    use super::*;
+    use crate::pb::abi::ValueMap;
+
+    #[test]
+    fn index_term_should_follow_field() {
+        let user: Value = ValueMap {
+            entries: [
+                ("age".to_string(), 30.into()),
+                ("name".to_string(), "alice".into()),
+            ]
+            .into(),
+        }
+        .into();
+        let field = |f: &[&str]| -> Vec<String> { f.iter().map(|f| f.to_string()).collect() };
+        assert_eq!(index_term(&user, &field(&["age"])), Some(b"30".to_vec()));
+        assert_eq!(index_term(&user, &field(&["email"])), None);
+        assert!(index_term(&user, &[]).unwrap().starts_with(&[ENCODED_TERM]));
+
+        let doc = Value::from(serde_json::json!({ "address": { "city": "hangzhou" }, "age": 30 }));
+        let term = index_term(&doc, &field(&["address", "city"]));
+        assert_eq!(term, Some(b"hangzhou".to_vec()));
+        assert_eq!(index_term(&doc, &field(&["age"])), Some(b"30".to_vec()));
+        // 不是 map 或者 JSON 文档时没有 field
+        assert_eq!(index_term(&"v".into(), &field(&["age"])), None);
+    }
+
+    #[test]
+    fn index_should_follow_updates() {
+        let mut index = Index::new(IndexSpec {
+            name: "by_value".into(),
+            field: vec![],
+        });
+        index.update(b"k1", Some(&"a".into()));
+        index.update(b"k2", Some(&"a".into()));
+        index.update(b"k3", Some(&"b".into()));
+        assert_eq!(index.get(b"a"), ["k1", "k2"]);
+
+        index.update(b"k1", Some(&"b".into()));
+        index.update(b"k2", None);
+        assert!(index.get(b"a").is_empty());
+        assert_eq!(index.get(b"b"), ["k1", "k3"]);
+    }
+}
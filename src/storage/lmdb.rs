@@ -0,0 +1,395 @@
+//! LMDB 存储：数据放在内存映射的 B+ 树里，读事务不加锁，也不需要把数据复制到缓存，适合读多写少的场景。
+//!
+//! 所有 table 的数据放在同一个 database 里，key 是 `table\0key`；元数据每个 tree 一个 database。
+//! LMDB 的 key 最长 511 字节，table 名字、key 和被索引的 term 加起来超过这个长度时写入失败
+
+use std::{borrow::Cow, ops::Bound, path::Path};
+
+use heed::{types::Bytes, Database, Env, EnvOpenOptions, RoTxn, RwTxn, WithoutTls};
+
+use super::ordered::{
+    borrowed, full_key, prefix_end, split_full_key, table_prefix, Engine, Entries, OrderedStore,
+    ReadTxn, Space, Tree, WriteTxn,
+};
+use crate::{config::parse_size, error::KvError};
+
+/// 没有配置 map_size 时数据库最多增长到 10GB；只占用虚拟地址空间，不会预先分配磁盘
+pub const DEFAULT_MAP_SIZE: usize = 10 << 30;
+
+/// 存放所有 table 数据的 database
+const DATA_DB: &str = "__data__";
+
+type Db = Database<Bytes, Bytes>;
+
+pub struct LmdbEngine {
+    env: Env<WithoutTls>,
+    data: Db,
+    /// 和 Tree::ALL 的顺序一致
+    meta: Vec<Db>,
+}
+
+/// 基于 LMDB 的 Storage
+pub type Lmdb = OrderedStore<LmdbEngine>;
+
+impl Lmdb {
+    /// 打开 path 目录下的数据库，目录不存在时创建；map_size 是数据库文件的大小上限
+    pub fn open(path: impl AsRef<Path>, map_size: usize) -> Result<Self, KvError> {
+        let path = path.as_ref();
+        std::fs::create_dir_all(path)?;
+        let mut options = EnvOpenOptions::new().read_txn_without_tls();
+        options
+            .map_size(map_size)
+            .max_dbs(Tree::ALL.len() as u32 + 1);
+        // SAFETY: 数据库文件只通过 LMDB 读写，同一个进程重复打开时 heed 返回错误
+        let env = unsafe { options.open(path) }
+            .map_err(|e| KvError::Internal(format!("Failed to open {}: {}", path.display(), e)))?;
+        let mut txn = env.write_txn().map_err(lmdb_error)?;
+        let data = env
+            .create_database(&mut txn, Some(DATA_DB))
+            .map_err(lmdb_error)?;
+        let meta = Tree::ALL
+            .iter()
+            .map(|tree| env.create_database(&mut txn, Some(tree.name())))
+            .collect::<Result<_, _>>()
+            .map_err(lmdb_error)?;
+        txn.commit().map_err(lmdb_error)?;
+        Ok(OrderedStore::new(LmdbEngine { env, data, meta }))
+    }
+
+    /// 按 StorageConfig::Lmdb 打开数据库，map_size 例如 "64GB"
+    pub fn open_with_config(path: &str, map_size: Option<&str>) -> Result<Self, KvError> {
+        let map_size = map_size.map(parse_size).transpose()?;
+        Lmdb::open(path, map_size.unwrap_or(DEFAULT_MAP_SIZE))
+    }
+}
+
+fn lmdb_error(e: heed::Error) -> KvError {
+    KvError::Internal(format!("LMDB error: {}", e))
+}
+
+impl LmdbEngine {
+    /// space 中的 key 所在的 database，以及 key 在 database 中的样子
+    fn locate<'k>(&self, space: Space, key: &'k [u8]) -> (Db, Cow<'k, [u8]>) {
+        match space {
+            Space::Data(table) => (self.data, Cow::Owned(full_key(table, key))),
+            Space::Meta(tree) => (self.meta[tree as usize], Cow::Borrowed(key)),
+        }
+    }
+
+    fn get(&self, txn: &RoTxn, space: Space, key: &[u8]) -> Result<Option<Vec<u8>>, KvError> {
+        let (db, key) = self.locate(space, key);
+        let value = db.get(txn, &key).map_err(lmdb_error)?;
+        Ok(value.map(<[u8]>::to_vec))
+    }
+
+    fn range(
+        &self,
+        txn: &RoTxn,
+        space: Space,
+        start: Bound<&[u8]>,
+        end: Bound<&[u8]>,
+        limit: usize,
+    ) -> Result<Entries, KvError> {
+        let (db, start, end, skip) = match space {
+            Space::Data(table) => {
+                let prefix = table_prefix(table);
+                let start = match start {
+                    Bound::Unbounded => Bound::Included(prefix.clone()),
+                    bound => bound.map(|key| full_key(table, key)),
+                };
+                let end = match end {
+                    Bound::Unbounded => prefix_end(&prefix),
+                    bound => bound.map(|key| full_key(table, key)),
+                };
+                (self.data, start, end, prefix.len())
+            }
+            Space::Meta(tree) => (
+                self.meta[tree as usize],
+                start.map(<[u8]>::to_vec),
+                end.map(<[u8]>::to_vec),
+                0,
+            ),
+        };
+        let mut entries = Vec::new();
+        let range = (borrowed(&start), borrowed(&end));
+        for item in db.range(txn, &range).map_err(lmdb_error)?.take(limit) {
+            let (key, value) = item.map_err(lmdb_error)?;
+            entries.push((key[skip..].to_vec(), value.to_vec()));
+        }
+        Ok(entries)
+    }
+
+    /// 每找到一个 table，就跳到这个 table 所有的 key 之后继续找
+    fn tables(&self, txn: &RoTxn) -> Result<Vec<String>, KvError> {
+        let (mut tables, mut start) = (Vec::new(), Bound::Unbounded);
+        loop {
+            let range = (borrowed(&start), Bound::Unbounded);
+            let mut iter = self.data.range(txn, &range).map_err(lmdb_error)?;
+            let Some(item) = iter.next() else {
+                break;
+            };
+            let (key, _) = item.map_err(lmdb_error)?;
+            let Some((table, _)) = split_full_key(key) else {
+                break;
+            };
+            start = match prefix_end(&table_prefix(&table)) {
+                Bound::Excluded(end) => Bound::Included(end),
+                _ => Bound::Unbounded,
+            };
+            tables.push(table);
+        }
+        Ok(tables)
+    }
+}
+
+struct LmdbRead<'a> {
+    engine: &'a LmdbEngine,
+    txn: &'a RoTxn<'a>,
+}
+
+struct LmdbWrite<'a, 'e> {
+    engine: &'a LmdbEngine,
+    txn: &'a mut RwTxn<'e>,
+}
+
+impl ReadTxn for LmdbRead<'_> {
+    fn get(&self, space: Space, key: &[u8]) -> Result<Option<Vec<u8>>, KvError> {
+        self.engine.get(self.txn, space, key)
+    }
+
+    fn range(
+        &self,
+        space: Space,
+        start: Bound<&[u8]>,
+        end: Bound<&[u8]>,
+        limit: usize,
+    ) -> Result<Entries, KvError> {
+        self.engine.range(self.txn, space, start, end, limit)
+    }
+
+    fn tables(&self) -> Result<Vec<String>, KvError> {
+        self.engine.tables(self.txn)
+    }
+}
+
+impl ReadTxn for LmdbWrite<'_, '_> {
+    fn get(&self, space: Space, key: &[u8]) -> Result<Option<Vec<u8>>, KvError> {
+        self.engine.get(self.txn, space, key)
+    }
+
+    fn range(
+        &self,
+        space: Space,
+        start: Bound<&[u8]>,
+        end: Bound<&[u8]>,
+        limit: usize,
+    ) -> Result<Entries, KvError> {
+        self.engine.range(self.txn, space, start, end, limit)
+    }
+
+    fn tables(&self) -> Result<Vec<String>, KvError> {
+        self.engine.tables(self.txn)
+    }
+}
+
+impl WriteTxn for LmdbWrite<'_, '_> {
+    fn put(&mut self, space: Space, key: &[u8], value: &[u8]) -> Result<(), KvError> {
+        let (db, key) = self.engine.locate(space, key);
+        db.put(self.txn, &key, value).map_err(lmdb_error)
+    }
+
+    fn delete(&mut self, space: Space, key: &[u8]) -> Result<bool, KvError> {
+        let (db, key) = self.engine.locate(space, key);
+        db.delete(self.txn, &key).map_err(lmdb_error)
+    }
+}
+
+impl Engine for LmdbEngine {
+    const NAME: &'static str = "lmdb";
+
+    fn read<T>(&self, f: impl FnOnce(&dyn ReadTxn) -> Result<T, KvError>) -> Result<T, KvError> {
+        let txn = self.env.read_txn().map_err(lmdb_error)?;
+        f(&LmdbRead {
+            engine: self,
+            txn: &txn,
+        })
+    }
+
+    fn write<T>(
+        &self,
+        f: impl FnOnce(&mut dyn WriteTxn) -> Result<T, KvError>,
+    ) -> Result<T, KvError> {
+        let mut txn = self.env.write_txn().map_err(lmdb_error)?;
+        let result = f(&mut LmdbWrite {
+            engine: self,
+            txn: &mut txn,
+        });
+        match result {
+            Ok(v) => {
+                txn.commit().map_err(lmdb_error)?;
+                Ok(v)
+            }
+            Err(e) => {
+                txn.abort();
+                Err(e)
+            }
+        }
+    }
+
+    /// 默认每次提交都会 fsync，这里只是保险
+    fn flush(&self) -> Result<(), KvError> {
+        self.env.force_sync().map_err(lmdb_error)
+    }
+
+    fn disk_usage(&self) -> Result<Option<u64>, KvError> {
+        Ok(Some(self.env.real_disk_size().map_err(lmdb_error)?))
+    }
+}
+
+#[cfg(test)]
+mod lmdb_tests {
+    use tempfile::tempdir;
+
+    use super::{Lmdb, DEFAULT_MAP_SIZE};
+    use crate::{
+        storage::tests::{
+            test_apply_batch, test_basic_interface, test_binary_keys, test_compare_and_swap,
+            test_expiration, test_get_all, test_get_iter, test_incr, test_index, test_mget_mset,
+            test_range, test_scan_prefix, test_set_if, test_stats, test_table_management,
+            test_update, test_version, test_zset,
+        },
+        Storage,
+    };
+
+    fn open() -> (tempfile::TempDir, Lmdb) {
+        let dir = tempdir().unwrap();
+        let store = Lmdb::open(dir.path(), DEFAULT_MAP_SIZE).unwrap();
+        (dir, store)
+    }
+
+    #[test]
+    fn lmdb_basic_interface_should_work() {
+        let (_dir, store) = open();
+        test_basic_interface(store);
+    }
+
+    #[test]
+    fn lmdb_get_all_should_work() {
+        let (_dir, store) = open();
+        test_get_all(store);
+    }
+
+    #[test]
+    fn lmdb_iter_should_work() {
+        let (_dir, store) = open();
+        test_get_iter(store);
+    }
+
+    #[test]
+    fn lmdb_expiration_should_work() {
+        let (_dir, store) = open();
+        test_expiration(store);
+    }
+
+    #[test]
+    fn lmdb_compare_and_swap_should_work() {
+        let (_dir, store) = open();
+        test_compare_and_swap(store);
+    }
+
+    #[test]
+    fn lmdb_incr_should_work() {
+        let (_dir, store) = open();
+        test_incr(store);
+    }
+
+    #[test]
+    fn lmdb_update_should_work() {
+        let (_dir, store) = open();
+        test_update(store);
+    }
+
+    #[test]
+    fn lmdb_binary_keys_should_work() {
+        let (_dir, store) = open();
+        test_binary_keys(store);
+    }
+
+    #[test]
+    fn lmdb_zset_should_work() {
+        let (_dir, store) = open();
+        test_zset(store);
+    }
+
+    #[test]
+    fn lmdb_index_should_work() {
+        let (_dir, store) = open();
+        test_index(store);
+    }
+
+    #[test]
+    fn lmdb_range_should_work() {
+        let (_dir, store) = open();
+        test_range(store);
+    }
+
+    #[test]
+    fn lmdb_scan_prefix_should_work() {
+        let (_dir, store) = open();
+        test_scan_prefix(store);
+    }
+
+    #[test]
+    fn lmdb_table_management_should_work() {
+        let (_dir, store) = open();
+        test_table_management(store);
+    }
+
+    #[test]
+    fn lmdb_stats_should_work() {
+        let (_dir, store) = open();
+        test_stats(store);
+    }
+
+    #[test]
+    fn lmdb_apply_batch_should_work() {
+        let (_dir, store) = open();
+        test_apply_batch(store);
+    }
+
+    #[test]
+    fn lmdb_mget_mset_should_work() {
+        let (_dir, store) = open();
+        test_mget_mset(store);
+    }
+
+    #[test]
+    fn lmdb_set_if_should_work() {
+        let (_dir, store) = open();
+        test_set_if(store);
+    }
+
+    #[test]
+    fn lmdb_version_should_work() {
+        let (_dir, store) = open();
+        test_version(store);
+    }
+
+    #[test]
+    fn lmdb_should_keep_data_after_reopen() {
+        let dir = tempdir().unwrap();
+        let store = Lmdb::open(dir.path(), DEFAULT_MAP_SIZE).unwrap();
+        store.set("t1", "k1", "v1".into()).unwrap();
+        // 一个 table 的名字是另一个的前缀，或者带着 `:` 时也不会混在一起
+        store.set("t1:a", "k2", "v2".into()).unwrap();
+        store.zadd("t2", "z1", vec![("m1", 1.0).into()]).unwrap();
+        drop(store);
+
+        let store = Lmdb::open(dir.path(), DEFAULT_MAP_SIZE).unwrap();
+        assert_eq!(store.get("t1", "k1").unwrap(), Some("v1".into()));
+        assert_eq!(store.tables().unwrap(), ["t1", "t1:a"]);
+        assert_eq!(store.zrange("t2", "z1", 0, -1).unwrap().len(), 1);
+        assert_eq!(store.backend(), "lmdb");
+        assert!(store.disk_usage().unwrap().unwrap() > 0);
+    }
+}
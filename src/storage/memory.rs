@@ -1,15 +1,223 @@
-use super::Storage;
+use super::{
+    eviction::{entry_size, MemoryLimit, Usage},
+    incr_value,
+    index::{check_spec, index_term, Index},
+    is_empty_range, now_millis,
+    quota::Quotas,
+    zset::{check_score, SortedSet},
+    KeyVersion, Storage, TableStats,
+};
 use crate::{
+    config::{EvictionPolicy, MemTableConfig},
     error::KvError,
-    pb::abi::{Kvpair, Value},
+    pb::{
+        abi::{wal_record::Op, IndexSpec, Kvpair, ScoredMember, Value, WalRecord},
+        to_key,
+    },
     StorageIter,
 };
-use dashmap::{mapref::one::Ref, DashMap};
+use bytes::Bytes;
+use dashmap::{
+    mapref::{entry::Entry, one::Ref},
+    DashMap,
+};
+use std::{
+    collections::{hash_map::RandomState, BTreeSet, HashMap},
+    hash::BuildHasher,
+    ops::{Bound, RangeBounds},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, RwLock, RwLockReadGuard,
+    },
+    thread,
+    time::{Duration, Instant},
+};
 
 /// Memory DB
 #[derive(Debug, Default, Clone)]
 pub struct MemTable {
-    tables: DashMap<String, DashMap<String, Value>>,
+    tables: DashMap<String, DashMap<Bytes, Value>>,
+    /// key 的过期时刻，<table, <key, deadline>>
+    expirations: DashMap<String, DashMap<Bytes, Instant>>,
+    /// 存在的 key 的版本号，每次修改都从 clock 取一个新的版本号；删除之后就去掉，<table, <key, version>>
+    versions: DashMap<String, DashMap<Bytes, u64>>,
+    clock: VersionClock,
+    /// key 占用的内存和访问情况，<table, <key, usage>>
+    usage: DashMap<String, DashMap<Bytes, Usage>>,
+    /// sorted set 和 HashTable 分开存放，<table, <key, sorted set>>
+    zsets: DashMap<String, DashMap<Bytes, SortedSet>>,
+    /// 按字节序排列的 key，用于 range，<table, keys>
+    ordered: DashMap<String, OrderedKeys>,
+    /// table 上的索引，<table, <索引名, index>>
+    indexes: DashMap<String, DashMap<String, Index>>,
+    memory: MemoryLimit,
+    quotas: Quotas,
+    layout: Layout,
+    /// 单个操作持有读锁，apply_batch 持有写锁，这样一组修改对其它操作来说是原子的
+    batch: Arc<RwLock<()>>,
+}
+
+/// 每个 table 内部的 DashMap 的分片数和初始容量：分片越多，并发写同一个 table 时锁的竞争越少
+#[derive(Debug, Default, Clone, Copy)]
+struct Layout {
+    /// None 时使用 DashMap 默认的分片数（CPU 核数的 4 倍）
+    shards: Option<usize>,
+    capacity: usize,
+}
+
+impl Layout {
+    fn new_map<V>(&self) -> DashMap<Bytes, V> {
+        match self.shards {
+            Some(shards) => DashMap::with_capacity_and_shard_amount(self.capacity, shards),
+            None => DashMap::with_capacity(self.capacity),
+        }
+    }
+
+    /// 和 DashMap 一样，默认的分片数是 CPU 核数的 4 倍
+    fn shard_amount(&self) -> usize {
+        self.shards.unwrap_or_else(|| {
+            let cpus = thread::available_parallelism().map_or(1, usize::from);
+            (cpus * 4).next_power_of_two()
+        })
+    }
+}
+
+/// 被删除的 key 按 hash 分到这么多个槽里
+const RETIRED_SLOTS: usize = 1024;
+
+/// 所有 key 共用一个单调递增的版本号，删除的 key 不用再保存版本号：
+/// 它的版本号是同一个槽里最后被删除的 key 的版本号，重新写入之后也不会回到之前的版本号
+#[derive(Debug)]
+struct VersionClock {
+    last: AtomicU64,
+    hasher: RandomState,
+    retired: Box<[AtomicU64]>,
+}
+
+impl Default for VersionClock {
+    fn default() -> Self {
+        Self {
+            last: AtomicU64::new(0),
+            hasher: RandomState::new(),
+            retired: (0..RETIRED_SLOTS).map(|_| AtomicU64::new(0)).collect(),
+        }
+    }
+}
+
+impl Clone for VersionClock {
+    fn clone(&self) -> Self {
+        let load = |v: &AtomicU64| AtomicU64::new(v.load(Ordering::Relaxed));
+        Self {
+            last: load(&self.last),
+            hasher: self.hasher.clone(),
+            retired: self.retired.iter().map(load).collect(),
+        }
+    }
+}
+
+impl VersionClock {
+    fn next(&self) -> u64 {
+        self.last.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    fn slot(&self, table: &str, key: &[u8]) -> &AtomicU64 {
+        let hash = self.hasher.hash_one((table, key)) as usize;
+        &self.retired[hash % self.retired.len()]
+    }
+
+    /// key 被删除了，之后它的版本号不会小于 version
+    fn retire(&self, table: &str, key: &[u8], version: u64) {
+        self.slot(table, key).fetch_max(version, Ordering::Relaxed);
+    }
+
+    /// 不存在的 key 的版本号
+    fn retired(&self, table: &str, key: &[u8]) -> u64 {
+        self.slot(table, key).load(Ordering::Relaxed)
+    }
+}
+
+/// 一个 table 中按字节序排列的 key；和 table 的 DashMap 一样按 key 的 hash 分片，
+/// 并发写入新的 key 时只竞争各自分片的锁，range 时合并所有分片的结果
+#[derive(Debug)]
+struct OrderedKeys {
+    hasher: RandomState,
+    shards: Box<[RwLock<BTreeSet<Bytes>>]>,
+}
+
+impl Clone for OrderedKeys {
+    fn clone(&self) -> Self {
+        Self {
+            hasher: self.hasher.clone(),
+            shards: self
+                .shards
+                .iter()
+                .map(|shard| RwLock::new(shard.read().unwrap().clone()))
+                .collect(),
+        }
+    }
+}
+
+impl OrderedKeys {
+    fn new(shards: usize) -> Self {
+        Self {
+            hasher: RandomState::new(),
+            shards: (0..shards).map(|_| Default::default()).collect(),
+        }
+    }
+
+    fn shard(&self, key: &[u8]) -> &RwLock<BTreeSet<Bytes>> {
+        let hash = self.hasher.hash_one(key) as usize;
+        &self.shards[hash % self.shards.len()]
+    }
+
+    /// 已经存在的 key 只需要读锁
+    fn insert(&self, key: &[u8]) {
+        let shard = self.shard(key);
+        if !shard.read().unwrap().contains(key) {
+            shard.write().unwrap().insert(to_key(key));
+        }
+    }
+
+    fn remove(&self, key: &[u8]) {
+        self.shard(key).write().unwrap().remove(key);
+    }
+
+    /// 每个分片最多取 limit 个 key，排序之后再取前 limit 个
+    fn range(&self, range: (Bound<Bytes>, Bound<Bytes>), limit: usize) -> Vec<Bytes> {
+        let mut keys: Vec<Bytes> = self
+            .shards
+            .iter()
+            .flat_map(|shard| {
+                let shard = shard.read().unwrap();
+                shard
+                    .range(range.clone())
+                    .take(limit)
+                    .cloned()
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+        keys.sort_unstable();
+        keys.truncate(limit);
+        keys
+    }
+
+    /// 从 prefix 开始在每个分片中查找，遇到第一个不以 prefix 开头的 key 就停下
+    fn prefix(&self, prefix: &Bytes) -> Vec<Bytes> {
+        let mut keys: Vec<Bytes> = self
+            .shards
+            .iter()
+            .flat_map(|shard| {
+                let shard = shard.read().unwrap();
+                shard
+                    .range(prefix.clone()..)
+                    .take_while(|key| key.starts_with(prefix))
+                    .cloned()
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+        keys.sort_unstable();
+        keys
+    }
 }
 
 impl MemTable {
@@ -17,15 +225,451 @@ impl MemTable {
         Self::default()
     }
 
-    fn get_or_create_table(&self, name: impl Into<String>) -> Ref<String, DashMap<String, Value>> {
-        let names: String = name.into();
-        match self.tables.get(&names) {
+    /// 按照配置的分片数和初始容量创建 table；内存上限通过 MemoryLimit::configure 设置
+    pub fn with_config(config: &MemTableConfig) -> Result<Self, KvError> {
+        if let Some(shards) = config.shards {
+            if shards < 2 || !shards.is_power_of_two() {
+                return Err(KvError::InvalidCommand(format!(
+                    "shards must be a power of two greater than 1, got {}",
+                    shards
+                )));
+            }
+        }
+        Ok(Self {
+            layout: Layout {
+                shards: config.shards,
+                capacity: config.capacity.unwrap_or_default(),
+            },
+            ..Default::default()
+        })
+    }
+
+    fn get_or_create_table(
+        &self,
+        name: impl Into<String>,
+    ) -> Ref<'_, String, DashMap<Bytes, Value>> {
+        let name: String = name.into();
+        self.table_of(&self.tables, &name)
+    }
+
+    /// 先用读锁查找 table，不存在时才用写锁创建，这样并发修改同一个 table 时不会在外层的 DashMap 上互相阻塞
+    fn table_of<'a, V>(
+        &self,
+        map: &'a DashMap<String, DashMap<Bytes, V>>,
+        table: &str,
+    ) -> Ref<'a, String, DashMap<Bytes, V>> {
+        match map.get(table) {
             Some(table) => table,
+            None => map
+                .entry(table.to_owned())
+                .or_insert_with(|| self.layout.new_map())
+                .downgrade(),
+        }
+    }
+
+    /// 按 key 的字节序返回 range 之间的最多 limit 个 kv pair
+    fn range_limit(
+        &self,
+        name: String,
+        range: impl RangeBounds<Bytes>,
+        limit: usize,
+    ) -> Result<Vec<Kvpair>, KvError> {
+        let _guard = self.shared();
+        if is_empty_range(&range) {
+            return Ok(vec![]);
+        }
+        let mut range = (range.start_bound().cloned(), range.end_bound().cloned());
+        let Some(ordered) = self.ordered.get(&name) else {
+            return Ok(vec![]);
+        };
+        // 过期的 key 留给后台任务清理，这里跳过它们；跳过之后不够 limit 个时从最后一个 key 之后接着取
+        let mut pairs = Vec::new();
+        while pairs.len() < limit {
+            let wanted = limit - pairs.len();
+            let keys = ordered.range(range.clone(), wanted);
+            let Some(last) = keys.last() else {
+                break;
+            };
+            range.0 = Bound::Excluded(last.clone());
+            let fetched = keys.len();
+            pairs.extend(self.live_pairs(&name, keys));
+            if fetched < wanted {
+                break;
+            }
+        }
+        Ok(pairs)
+    }
+
+    /// 按顺序取出 keys 的值，跳过已经过期或者已经删除的 key
+    fn live_pairs(&self, name: &str, keys: Vec<Bytes>) -> Vec<Kvpair> {
+        let now = Instant::now();
+        let deadlines = self.expirations.get(name);
+        let is_expired = |key: &Bytes| {
+            let deadline = deadlines.as_ref().and_then(|d| d.get(key).map(|d| *d));
+            deadline.is_some_and(|d| d <= now)
+        };
+        let Some(table) = self.tables.get(name) else {
+            return vec![];
+        };
+        keys.into_iter()
+            .filter(|key| !is_expired(key))
+            .filter_map(|key| {
+                let value = table.get(&key)?.value().clone();
+                Some(Kvpair::new(key, value))
+            })
+            .collect()
+    }
+
+    /// 惰性过期：如果 key 已经过期，就把它从 table 中删除，返回 true
+    fn remove_if_expired(&self, table: &str, key: &[u8]) -> bool {
+        let expired = match self.expirations.get(table) {
+            Some(deadlines) => deadlines.remove_if(key, |_, d| *d <= Instant::now()),
+            None => None,
+        };
+
+        match expired {
+            Some(_) => {
+                if let Some(t) = self.tables.get(table) {
+                    t.remove(key);
+                }
+                self.touch(table, key);
+                self.account(table, key, None);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// 删除 table 中所有已过期的 key
+    fn remove_expired_in(&self, table: &str) -> usize {
+        let now = Instant::now();
+        let keys: Vec<Bytes> = match self.expirations.get(table) {
+            Some(deadlines) => deadlines
+                .iter()
+                .filter(|d| *d.value() <= now)
+                .map(|d| d.key().clone())
+                .collect(),
+            None => return 0,
+        };
+
+        keys.iter()
+            .filter(|key| self.remove_if_expired(table, key))
+            .count()
+    }
+
+    /// key 被修改了，换一个新的版本号；key 被删除时 account 会把它去掉
+    fn touch(&self, table: &str, key: &[u8]) {
+        let versions = self.table_of(&self.versions, table);
+        versions.insert(to_key(key), self.clock.next());
+    }
+
+    fn current_version(&self, table: &str, key: &[u8]) -> u64 {
+        self.versions
+            .get(table)
+            .and_then(|versions| versions.get(key).map(|v| *v))
+            .unwrap_or_else(|| self.clock.retired(table, key))
+    }
+
+    /// key 的值变成了 value，更新它占用的内存、key 的顺序和 table 上的索引；value 为 None 表示 key 被删除了
+    fn account(&self, table: &str, key: &[u8], value: Option<&Value>) {
+        match value {
+            // table 已经存在时只需要读锁
+            Some(_) => match self.ordered.get(table) {
+                Some(keys) => keys.insert(key),
+                None => self
+                    .ordered
+                    .entry(table.to_owned())
+                    .or_insert_with(|| OrderedKeys::new(self.layout.shard_amount()))
+                    .insert(key),
+            },
             None => {
-                let entry = self.tables.entry(names).or_default();
-                entry.downgrade()
+                if let Some(keys) = self.ordered.get(table) {
+                    keys.remove(key);
+                }
+                let removed = self
+                    .versions
+                    .get(table)
+                    .and_then(|versions| versions.remove(key));
+                if let Some((_, version)) = removed {
+                    self.clock.retire(table, key, version);
+                }
             }
         }
+        if let Some(indexes) = self.indexes.get(table) {
+            indexes
+                .iter_mut()
+                .for_each(|mut index| index.update(key, value));
+        }
+        match value {
+            Some(value) => {
+                let size = entry_size(key, value);
+                let usage = self.table_of(&self.usage, table);
+                match usage.entry(to_key(key)) {
+                    Entry::Occupied(mut entry) => {
+                        let old = entry.get().size();
+                        self.memory.resize(entry.get_mut(), size);
+                        self.quotas.record(table, 0, size as i64 - old as i64);
+                    }
+                    Entry::Vacant(entry) => {
+                        entry.insert(self.memory.alloc(size));
+                        self.quotas.record(table, 1, size as i64);
+                    }
+                };
+            }
+            None => {
+                let removed = self.usage.get(table).and_then(|usage| usage.remove(key));
+                if let Some((_, usage)) = removed {
+                    self.quotas.record(table, -1, -(usage.size() as i64));
+                    self.memory.free(usage);
+                }
+            }
+        }
+    }
+
+    /// key 的值变成 value 之后 table 增加的 key 数和空间，key 被删除时 value 为 None
+    fn growth(&self, table: &str, key: &[u8], value: Option<&Value>) -> (i64, i64) {
+        let old = match self.usage.get(table) {
+            Some(usage) => usage.get(key).map(|u| u.size() as i64),
+            None => None,
+        };
+        match (old, value) {
+            (Some(old), Some(value)) => (0, entry_size(key, value) as i64 - old),
+            (None, Some(value)) => (1, entry_size(key, value) as i64),
+            (Some(old), None) => (-1, -old),
+            (None, None) => (0, 0),
+        }
+    }
+
+    /// 写入之前调用：key 的值变成 value 之后超过 table 的配额时返回 QuotaExceeded
+    pub(crate) fn check_quota(
+        &self,
+        table: &str,
+        key: &[u8],
+        value: &Value,
+    ) -> Result<(), KvError> {
+        let (keys, bytes) = self.growth(table, key, Some(value));
+        self.quotas.check(table, keys, bytes)
+    }
+
+    /// 按 table 累加一组记录对使用量的影响，再检查配额；同一个 key 写入多次时会重复计算
+    pub(crate) fn check_batch_quota(&self, records: &[WalRecord]) -> Result<(), KvError> {
+        fn collect(
+            table: &MemTable,
+            records: &[WalRecord],
+            growth: &mut HashMap<String, (i64, i64)>,
+        ) {
+            for record in records {
+                let (keys, bytes) = match &record.op {
+                    Some(Op::Set(value)) => table.growth(&record.table, &record.key, Some(value)),
+                    Some(Op::Del(_)) => table.growth(&record.table, &record.key, None),
+                    Some(Op::Batch(batch)) => {
+                        collect(table, &batch.records, growth);
+                        continue;
+                    }
+                    _ => continue,
+                };
+                let total = growth.entry(record.table.clone()).or_default();
+                total.0 += keys;
+                total.1 += bytes;
+            }
+        }
+        let mut growth = HashMap::new();
+        collect(self, records, &mut growth);
+        growth
+            .into_iter()
+            .try_for_each(|(table, (keys, bytes))| self.quotas.check(&table, keys, bytes))
+    }
+
+    /// 读取了 key，更新 LRU/LFU 用到的访问记录
+    fn accessed(&self, table: &str, key: &[u8]) {
+        if let Some(usage) = self.usage.get(table) {
+            if let Some(mut usage) = usage.get_mut(key) {
+                self.memory.access(&mut usage);
+            }
+        }
+    }
+
+    /// 写入之前调用：超过内存上限时按照淘汰策略删除 key，返回被淘汰的 <table, key>；
+    /// 策略是 RejectWrites 时返回 OutOfMemory
+    pub(crate) fn make_room(&self) -> Result<Vec<(String, Bytes)>, KvError> {
+        let _guard = self.shared();
+        self.evict()
+    }
+
+    fn evict(&self) -> Result<Vec<(String, Bytes)>, KvError> {
+        if !self.memory.is_over() {
+            return Ok(vec![]);
+        }
+        // 先清理已过期的 key
+        let tables: Vec<String> = self.expirations.iter().map(|t| t.key().clone()).collect();
+        tables.iter().for_each(|t| {
+            self.remove_expired_in(t);
+        });
+        if !self.memory.is_over() {
+            return Ok(vec![]);
+        }
+        let policy = self.memory.policy();
+        if policy == EvictionPolicy::RejectWrites {
+            return Err(self.memory.out_of_memory());
+        }
+
+        let random = RandomState::new();
+        let mut candidates: Vec<(u64, String, Bytes)> = self
+            .usage
+            .iter()
+            .flat_map(|usage| {
+                let table = usage.key();
+                usage
+                    .iter()
+                    .map(|u| {
+                        (
+                            u.rank(policy, &random, u.key()),
+                            table.clone(),
+                            u.key().clone(),
+                        )
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+        candidates.sort_unstable();
+
+        let target = self.memory.target();
+        let mut evicted = vec![];
+        for (_, table, key) in candidates {
+            if self.memory.used() <= target {
+                break;
+            }
+            self.clear_expiration(&table, &key);
+            if let Some(t) = self.tables.get(&table) {
+                t.remove(&key);
+            }
+            self.touch(&table, &key);
+            self.account(&table, &key, None);
+            evicted.push((table, key));
+        }
+        Ok(evicted)
+    }
+
+    fn shared(&self) -> RwLockReadGuard<'_, ()> {
+        self.batch.read().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// 调用者需要持有 batch 的写锁
+    fn apply_record(&self, record: WalRecord) {
+        let (name, key) = (record.table, record.key);
+        match record.op {
+            Some(Op::Set(value)) => {
+                self.clear_expiration(&name, &key);
+                self.touch(&name, &key);
+                self.account(&name, &key, Some(&value));
+                self.get_or_create_table(name).insert(key, value);
+            }
+            Some(Op::Del(_)) => {
+                self.clear_expiration(&name, &key);
+                self.touch(&name, &key);
+                self.account(&name, &key, None);
+                if let Some(table) = self.tables.get(&name) {
+                    table.remove(&key);
+                }
+            }
+            Some(Op::Expire(deadline)) => {
+                let exists = self
+                    .tables
+                    .get(&name)
+                    .is_some_and(|table| table.contains_key(&key));
+                if exists {
+                    self.touch(&name, &key);
+                    let ttl = Duration::from_millis(deadline.saturating_sub(now_millis()));
+                    let deadlines = self.table_of(&self.expirations, &name);
+                    deadlines.insert(key, Instant::now() + ttl);
+                }
+            }
+            Some(Op::Batch(batch)) => batch
+                .records
+                .into_iter()
+                .for_each(|record| self.apply_record(record)),
+            Some(Op::Zadd(zadd)) => {
+                self.add_members(name, key, zadd.members);
+            }
+            Some(Op::CreateIndex(spec)) => {
+                self.build_index(name, spec);
+            }
+            Some(Op::TruncateTable(_)) => {
+                self.clear_table(&name, false);
+            }
+            Some(Op::DropTable(_)) => {
+                self.clear_table(&name, true);
+            }
+            None => {}
+        }
+    }
+
+    /// 删除 table 中所有的 key 和 sorted set，drop 时连同 table 上的索引一起删除，返回删除的 key 数。
+    /// 调用者需要持有 batch 的写锁
+    fn clear_table(&self, table: &str, drop: bool) -> usize {
+        self.remove_expired_in(table);
+        let keys: Vec<Bytes> = match self.tables.get(table) {
+            Some(data) => data.iter().map(|pair| pair.key().clone()).collect(),
+            None => vec![],
+        };
+        for key in &keys {
+            self.touch(table, key);
+            self.account(table, key, None);
+        }
+        self.expirations.remove(table);
+        let zsets = self.zsets.remove(table).map_or(0, |(_, zsets)| zsets.len());
+        if drop {
+            self.tables.remove(table);
+            self.ordered.remove(table);
+            self.indexes.remove(table);
+        } else if let Some(data) = self.tables.get(table) {
+            data.clear();
+        }
+        keys.len() + zsets
+    }
+
+    /// 调用者需要持有 batch 的写锁，这样建立索引期间不会有修改
+    fn build_index(&self, table: String, spec: IndexSpec) -> bool {
+        let indexes = self.indexes.entry(table.clone()).or_default();
+        if indexes.contains_key(&spec.name) {
+            return false;
+        }
+        let name = spec.name.clone();
+        let mut index = Index::new(spec);
+        if let Some(data) = self.tables.get(&table) {
+            data.iter()
+                .for_each(|pair| index.update(pair.key(), Some(pair.value())));
+        }
+        indexes.insert(name, index);
+        true
+    }
+
+    /// 调用者需要先检查 score
+    fn add_members(&self, table: String, key: Bytes, members: Vec<ScoredMember>) -> usize {
+        let zsets = self.table_of(&self.zsets, &table);
+        let mut set = zsets.entry(key).or_default();
+        members
+            .into_iter()
+            .filter(|m| set.insert(m.member.clone(), m.score))
+            .count()
+    }
+
+    /// 读取 sorted set，不存在时 f 拿到空的 sorted set
+    fn with_zset<T>(&self, table: &str, key: &[u8], f: impl FnOnce(&SortedSet) -> T) -> T {
+        let Some(zsets) = self.zsets.get(table) else {
+            return f(&SortedSet::default());
+        };
+        let result = match zsets.get(key) {
+            Some(set) => f(&set),
+            None => f(&SortedSet::default()),
+        };
+        result
+    }
+
+    fn clear_expiration(&self, table: &str, key: &[u8]) {
+        if let Some(deadlines) = self.expirations.get(table) {
+            deadlines.remove(key);
+        }
     }
 }
 
@@ -33,38 +677,202 @@ impl Storage for MemTable {
     fn get(
         &self,
         table: impl Into<String>,
-        key: impl Into<String>,
+        key: impl AsRef<[u8]>,
     ) -> Result<Option<Value>, KvError> {
-        let table = self.get_or_create_table(table);
-        Ok(table.get(&key.into()).map(|v| v.value().clone()))
+        let _guard = self.shared();
+        let (name, key) = (table.into(), to_key(key));
+        self.remove_if_expired(&name, &key);
+        self.accessed(&name, &key);
+        let table = self.get_or_create_table(name);
+        Ok(table.get(&key).map(|v| v.value().clone()))
     }
 
     fn set(
         &self,
         table: impl Into<String>,
-        key: impl Into<String>,
+        key: impl AsRef<[u8]>,
         value: Value,
     ) -> Result<Option<Value>, KvError> {
-        let table = self.get_or_create_table(table);
-        Ok(table.insert(key.into(), value))
+        let _guard = self.shared();
+        self.evict()?;
+        let (name, key) = (table.into(), to_key(key));
+        self.remove_if_expired(&name, &key);
+        self.check_quota(&name, &key, &value)?;
+        self.clear_expiration(&name, &key);
+        self.touch(&name, &key);
+        self.account(&name, &key, Some(&value));
+        let table = self.get_or_create_table(name);
+        Ok(table.insert(key, value))
+    }
+
+    /// 每个 key 检查过期之后，只查找一次 table
+    fn mget(
+        &self,
+        table: impl Into<String>,
+        keys: &[impl AsRef<[u8]>],
+    ) -> Result<Vec<Option<Value>>, KvError> {
+        let _guard = self.shared();
+        let name = table.into();
+        for key in keys {
+            self.remove_if_expired(&name, key.as_ref());
+            self.accessed(&name, key.as_ref());
+        }
+        let table = self.get_or_create_table(name);
+        Ok(keys
+            .iter()
+            .map(|key| table.get(key.as_ref()).map(|v| v.value().clone()))
+            .collect())
     }
 
-    fn contains(&self, table: impl Into<String>, key: impl Into<String>) -> Result<bool, KvError> {
-        let table = self.get_or_create_table(table);
-        Ok(table.contains_key(&key.into()))
+    /// 和 set 一样维护过期时间、版本号和内存占用，最后一次性写入 table；
+    /// 只在开始时检查一次内存上限和配额。不会阻塞其它命令，其它命令可能看到只写入了一部分
+    fn mset(&self, table: impl Into<String>, pairs: Vec<Kvpair>) -> Result<(), KvError> {
+        let _guard = self.shared();
+        self.evict()?;
+        let name = table.into();
+        let pairs: Vec<(Bytes, Value)> = pairs
+            .into_iter()
+            .map(|pair| (pair.key, pair.value.unwrap_or_default()))
+            .collect();
+        let (mut keys, mut bytes) = (0, 0);
+        for (key, value) in &pairs {
+            self.remove_if_expired(&name, key);
+            let growth = self.growth(&name, key, Some(value));
+            keys += growth.0;
+            bytes += growth.1;
+        }
+        self.quotas.check(&name, keys, bytes)?;
+        for (key, value) in &pairs {
+            self.clear_expiration(&name, key);
+            self.touch(&name, key);
+            self.account(&name, key, Some(value));
+        }
+        let table = self.get_or_create_table(name);
+        for (key, value) in pairs {
+            table.insert(key, value);
+        }
+        Ok(())
+    }
+
+    fn compare_and_swap(
+        &self,
+        table: impl Into<String>,
+        key: impl AsRef<[u8]>,
+        expected: Option<Value>,
+        value: Value,
+    ) -> Result<(bool, Option<Value>), KvError> {
+        let _guard = self.shared();
+        self.evict()?;
+        let (name, key) = (table.into(), to_key(key));
+        self.remove_if_expired(&name, &key);
+        let table = self.get_or_create_table(name.as_str());
+        // entry 持有 key 所在分片的写锁，比较和写入之间不会有别的修改
+        match table.entry(key.clone()) {
+            Entry::Occupied(mut entry) if Some(entry.get()) == expected.as_ref() => {
+                self.check_quota(&name, &key, &value)?;
+                entry.insert(value.clone());
+            }
+            Entry::Vacant(entry) if expected.is_none() => {
+                self.check_quota(&name, &key, &value)?;
+                entry.insert(value.clone());
+            }
+            Entry::Occupied(entry) => return Ok((false, Some(entry.get().clone()))),
+            Entry::Vacant(_) => return Ok((false, None)),
+        }
+        self.clear_expiration(&name, &key);
+        self.touch(&name, &key);
+        self.account(&name, &key, Some(&value));
+        Ok((true, Some(value)))
+    }
+
+    fn incr(
+        &self,
+        table: impl Into<String>,
+        key: impl AsRef<[u8]>,
+        delta: i64,
+    ) -> Result<i64, KvError> {
+        let _guard = self.shared();
+        self.evict()?;
+        let (name, key) = (table.into(), to_key(key));
+        self.remove_if_expired(&name, &key);
+        let table = self.get_or_create_table(name.as_str());
+        let value = match table.entry(key.clone()) {
+            Entry::Occupied(mut entry) => {
+                let value = incr_value(entry.key(), Some(entry.get()), delta)?;
+                self.check_quota(&name, &key, &value.into())?;
+                entry.insert(value.into());
+                value
+            }
+            Entry::Vacant(entry) => {
+                self.check_quota(&name, &key, &delta.into())?;
+                entry.insert(delta.into());
+                delta
+            }
+        };
+        self.touch(&name, &key);
+        self.account(&name, &key, Some(&value.into()));
+        Ok(value)
+    }
+
+    fn update<T>(
+        &self,
+        table: impl Into<String>,
+        key: impl AsRef<[u8]>,
+        mut f: impl FnMut(Option<&Value>) -> Result<(Value, T), KvError>,
+    ) -> Result<T, KvError> {
+        let _guard = self.shared();
+        self.evict()?;
+        let (name, key) = (table.into(), to_key(key));
+        self.remove_if_expired(&name, &key);
+        let table = self.get_or_create_table(name.as_str());
+        let (value, result) = match table.entry(key.clone()) {
+            Entry::Occupied(mut entry) => {
+                let (value, result) = f(Some(entry.get()))?;
+                self.check_quota(&name, &key, &value)?;
+                entry.insert(value.clone());
+                (value, result)
+            }
+            Entry::Vacant(entry) => {
+                let (value, result) = f(None)?;
+                self.check_quota(&name, &key, &value)?;
+                entry.insert(value.clone());
+                (value, result)
+            }
+        };
+        self.touch(&name, &key);
+        self.account(&name, &key, Some(&value));
+        Ok(result)
+    }
+
+    fn contains(&self, table: impl Into<String>, key: impl AsRef<[u8]>) -> Result<bool, KvError> {
+        let _guard = self.shared();
+        let (name, key) = (table.into(), to_key(key));
+        self.remove_if_expired(&name, &key);
+        self.accessed(&name, &key);
+        let table = self.get_or_create_table(name);
+        Ok(table.contains_key(&key))
     }
 
     fn del(
         &self,
         table: impl Into<String>,
-        key: impl Into<String>,
+        key: impl AsRef<[u8]>,
     ) -> Result<Option<Value>, KvError> {
-        let table = self.get_or_create_table(table);
-        Ok(table.remove(&key.into()).map(|(_, v)| v))
+        let _guard = self.shared();
+        let (name, key) = (table.into(), to_key(key));
+        self.remove_if_expired(&name, &key);
+        self.clear_expiration(&name, &key);
+        self.touch(&name, &key);
+        self.account(&name, &key, None);
+        let table = self.get_or_create_table(name);
+        Ok(table.remove(&key).map(|(_, v)| v))
     }
 
     fn get_all(&self, table: impl Into<String>) -> Result<Vec<Kvpair>, KvError> {
-        let table = self.get_or_create_table(table);
+        let _guard = self.shared();
+        let name = table.into();
+        self.remove_expired_in(&name);
+        let table = self.get_or_create_table(name);
         let result: Vec<Kvpair> = table
             .iter()
             .map(|m| Kvpair::new(m.key(), m.value().clone()))
@@ -73,8 +881,321 @@ impl Storage for MemTable {
     }
 
     fn get_iter(&self, table: impl Into<String>) -> Result<impl Iterator<Item = Kvpair>, KvError> {
-        let table = self.get_or_create_table(table).clone();
+        let _guard = self.shared();
+        let name = table.into();
+        self.remove_expired_in(&name);
+        let table = self.get_or_create_table(name).clone();
         let iter = StorageIter::new(table.into_iter());
         Ok(iter)
     }
+
+    fn scan_prefix(
+        &self,
+        table: impl Into<String>,
+        prefix: impl AsRef<[u8]>,
+    ) -> Result<impl Iterator<Item = Kvpair>, KvError> {
+        let _guard = self.shared();
+        let name = table.into();
+        let keys = match self.ordered.get(&name) {
+            Some(keys) => keys.prefix(&to_key(prefix)),
+            None => vec![],
+        };
+        Ok(self.live_pairs(&name, keys).into_iter())
+    }
+
+    fn range(
+        &self,
+        table: impl Into<String>,
+        range: impl RangeBounds<Bytes>,
+    ) -> Result<Vec<Kvpair>, KvError> {
+        self.range_limit(table.into(), range, usize::MAX)
+    }
+
+    fn scan_after(
+        &self,
+        table: impl Into<String>,
+        start: Option<Bytes>,
+        limit: usize,
+    ) -> Result<Vec<Kvpair>, KvError> {
+        let start = start.map_or(Bound::Unbounded, Bound::Excluded);
+        self.range_limit(table.into(), (start, Bound::Unbounded), limit)
+    }
+
+    fn expire(
+        &self,
+        table: impl Into<String>,
+        key: impl AsRef<[u8]>,
+        ttl: Duration,
+    ) -> Result<bool, KvError> {
+        let _guard = self.shared();
+        let (name, key) = (table.into(), to_key(key));
+        self.remove_if_expired(&name, &key);
+        if !self.get_or_create_table(name.as_str()).contains_key(&key) {
+            return Ok(false);
+        }
+        self.touch(&name, &key);
+        let deadlines = self.table_of(&self.expirations, &name);
+        deadlines.insert(key, Instant::now() + ttl);
+        Ok(true)
+    }
+
+    fn ttl(
+        &self,
+        table: impl Into<String>,
+        key: impl AsRef<[u8]>,
+    ) -> Result<Option<Duration>, KvError> {
+        let _guard = self.shared();
+        let (name, key) = (table.into(), to_key(key));
+        self.remove_if_expired(&name, &key);
+        let ttl = self.expirations.get(&name).and_then(|deadlines| {
+            deadlines
+                .get(&key)
+                .map(|d| d.saturating_duration_since(Instant::now()))
+        });
+        Ok(ttl)
+    }
+
+    fn purge_expired(&self) -> Result<usize, KvError> {
+        let _guard = self.shared();
+        let tables: Vec<String> = self.expirations.iter().map(|t| t.key().clone()).collect();
+        Ok(tables.iter().map(|t| self.remove_expired_in(t)).sum())
+    }
+
+    fn tables(&self) -> Result<Vec<String>, KvError> {
+        let _guard = self.shared();
+        // 读取不存在的 table 时也会创建一个空的 table，这里跳过它们
+        Ok(self
+            .tables
+            .iter()
+            .filter(|t| !t.value().is_empty())
+            .map(|t| t.key().clone())
+            .collect())
+    }
+
+    fn backend(&self) -> &'static str {
+        "memory"
+    }
+
+    fn stats(&self) -> Result<Vec<(String, TableStats)>, KvError> {
+        let _guard = self.shared();
+        let names: Vec<String> = self.tables.iter().map(|t| t.key().clone()).collect();
+        let mut stats = Vec::new();
+        for name in names {
+            self.remove_expired_in(&name);
+            let keys = self.tables.get(&name).map_or(0, |t| t.len() as u64);
+            if keys == 0 {
+                continue;
+            }
+            let bytes = self
+                .usage
+                .get(&name)
+                .map_or(0, |usage| usage.iter().map(|u| u.size() as u64).sum());
+            stats.push((name, TableStats { keys, bytes }));
+        }
+        Ok(stats)
+    }
+
+    fn truncate_table(&self, table: impl Into<String>) -> Result<usize, KvError> {
+        let _guard = self.batch.write().unwrap_or_else(|e| e.into_inner());
+        Ok(self.clear_table(&table.into(), false))
+    }
+
+    fn drop_table(&self, table: impl Into<String>) -> Result<usize, KvError> {
+        let _guard = self.batch.write().unwrap_or_else(|e| e.into_inner());
+        Ok(self.clear_table(&table.into(), true))
+    }
+
+    fn version(&self, table: impl Into<String>, key: impl AsRef<[u8]>) -> Result<u64, KvError> {
+        let _guard = self.shared();
+        let (name, key) = (table.into(), to_key(key));
+        self.remove_if_expired(&name, &key);
+        Ok(self.current_version(&name, &key))
+    }
+
+    fn apply_batch(
+        &self,
+        records: Vec<WalRecord>,
+        watched: &[KeyVersion],
+    ) -> Result<bool, KvError> {
+        let _guard = self.batch.write().unwrap_or_else(|e| e.into_inner());
+        if records.iter().any(|r| matches!(r.op, Some(Op::Set(_)))) {
+            self.evict()?;
+        }
+        let changed = watched.iter().any(|w| {
+            self.remove_if_expired(&w.table, &w.key);
+            self.current_version(&w.table, &w.key) != w.version
+        });
+        if changed {
+            return Ok(false);
+        }
+        self.check_batch_quota(&records)?;
+        records
+            .into_iter()
+            .for_each(|record| self.apply_record(record));
+        Ok(true)
+    }
+
+    fn zadd(
+        &self,
+        table: impl Into<String>,
+        key: impl AsRef<[u8]>,
+        members: Vec<ScoredMember>,
+    ) -> Result<usize, KvError> {
+        let _guard = self.shared();
+        members.iter().try_for_each(|m| check_score(m.score))?;
+        Ok(self.add_members(table.into(), to_key(key), members))
+    }
+
+    fn zrange(
+        &self,
+        table: impl Into<String>,
+        key: impl AsRef<[u8]>,
+        start: i64,
+        stop: i64,
+    ) -> Result<Vec<ScoredMember>, KvError> {
+        let _guard = self.shared();
+        let range = |set: &SortedSet| set.range(start, stop);
+        Ok(self.with_zset(&table.into(), key.as_ref(), range))
+    }
+
+    fn zrange_by_score(
+        &self,
+        table: impl Into<String>,
+        key: impl AsRef<[u8]>,
+        min: f64,
+        max: f64,
+    ) -> Result<Vec<ScoredMember>, KvError> {
+        let _guard = self.shared();
+        let range = |set: &SortedSet| set.range_by_score(min, max);
+        Ok(self.with_zset(&table.into(), key.as_ref(), range))
+    }
+
+    fn zsets(&self) -> Result<Vec<(String, Bytes)>, KvError> {
+        let _guard = self.shared();
+        let zsets = self.zsets.iter().flat_map(|zsets| {
+            let table = zsets.key().clone();
+            zsets
+                .iter()
+                .map(|set| (table.clone(), set.key().clone()))
+                .collect::<Vec<_>>()
+        });
+        Ok(zsets.collect())
+    }
+
+    fn create_index(&self, table: impl Into<String>, spec: IndexSpec) -> Result<bool, KvError> {
+        check_spec(&spec)?;
+        let _guard = self.batch.write().unwrap_or_else(|e| e.into_inner());
+        Ok(self.build_index(table.into(), spec))
+    }
+
+    fn query(
+        &self,
+        table: impl Into<String>,
+        index: &str,
+        value: &Value,
+    ) -> Result<Vec<Kvpair>, KvError> {
+        let _guard = self.shared();
+        let name = table.into();
+        let keys = self
+            .indexes
+            .get(&name)
+            .and_then(|indexes| {
+                let index = indexes.get(index)?;
+                Some(index_term(value, &[]).map_or(vec![], |term| index.get(&term)))
+            })
+            .ok_or_else(|| KvError::NotFound(format!("index {}:{}", name, index)))?;
+        let mut pairs = Vec::new();
+        for key in keys {
+            // 过期的 key 在这里被删除，同时也会从索引中删除
+            if self.remove_if_expired(&name, &key) {
+                continue;
+            }
+            let value = self
+                .tables
+                .get(&name)
+                .and_then(|t| t.get(&key).map(|v| v.clone()));
+            if let Some(value) = value {
+                pairs.push(Kvpair::new(key, value));
+            }
+        }
+        Ok(pairs)
+    }
+
+    fn indexes(&self) -> Result<Vec<(String, IndexSpec)>, KvError> {
+        let _guard = self.shared();
+        let indexes = self.indexes.iter().flat_map(|indexes| {
+            let table = indexes.key().clone();
+            indexes
+                .iter()
+                .map(|index| (table.clone(), index.spec.clone()))
+                .collect::<Vec<_>>()
+        });
+        Ok(indexes.collect())
+    }
+
+    fn memory_limit(&self) -> Option<&MemoryLimit> {
+        Some(&self.memory)
+    }
+
+    fn quotas(&self) -> Option<&Quotas> {
+        Some(&self.quotas)
+    }
+}
+
+#[cfg(test)]
+mod memtable_tests {
+    use std::{thread, time::Duration};
+
+    use super::MemTable;
+    use crate::Storage;
+
+    #[test]
+    fn deleted_keys_should_not_keep_versions() {
+        let store = MemTable::new();
+        for i in 0..100 {
+            store.set("t1", format!("k{}", i), "v".into()).unwrap();
+        }
+        let v1 = store.version("t1", "k1").unwrap();
+        for i in 0..100 {
+            store.del("t1", format!("k{}", i)).unwrap();
+        }
+        assert!(store.versions.get("t1").unwrap().is_empty());
+        assert!(store.version("t1", "k1").unwrap() > v1);
+    }
+
+    #[test]
+    fn scan_prefix_should_return_sorted_live_keys() {
+        let store = MemTable::new();
+        for key in ["b:1", "a:2", "a:1", "a:3", "c"] {
+            store.set("t1", key, "v".into()).unwrap();
+        }
+        store.expire("t1", "a:3", Duration::from_millis(1)).unwrap();
+        thread::sleep(Duration::from_millis(10));
+
+        let keys: Vec<_> = store
+            .scan_prefix("t1", "a:")
+            .unwrap()
+            .map(|pair| pair.key)
+            .collect();
+        assert_eq!(keys, ["a:1", "a:2"]);
+    }
+    #[test]
+    fn scan_after_should_skip_expired_keys_without_sweeping() {
+        let store = MemTable::new();
+        for i in 0..5 {
+            store.set("t1", format!("k{}", i), "v".into()).unwrap();
+        }
+        for key in ["k0", "k1", "k3"] {
+            store.expire("t1", key, Duration::from_millis(1)).unwrap();
+        }
+        thread::sleep(Duration::from_millis(10));
+
+        // 跳过过期的 key 之后仍然返回一整页
+        let pairs = store.scan_after("t1", None, 2).unwrap();
+        let keys: Vec<_> = pairs.into_iter().map(|pair| pair.key).collect();
+        assert_eq!(keys, ["k2", "k4"]);
+        // 过期的 key 留给后台任务删除
+        assert_eq!(store.tables.get("t1").unwrap().len(), 5);
+        assert_eq!(store.purge_expired().unwrap(), 3);
+    }
 }
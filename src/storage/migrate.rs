@@ -0,0 +1,205 @@
+use super::{now_millis, Storage};
+use crate::{
+    error::KvError,
+    pb::abi::{value, Value},
+};
+use std::time::Duration;
+
+/// 每复制这么多个 key 报告一次进度
+const PROGRESS_INTERVAL: usize = 10_000;
+
+/// 校验时最多列出的不一致的 key 数量
+const MAX_REPORTED_MISMATCHES: usize = 10;
+
+/// 迁移的进度和结果
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MigrateStats {
+    /// 正在复制（或者最后复制）的 table
+    pub table: String,
+    pub tables: usize,
+    pub keys: usize,
+    pub zsets: usize,
+    pub indexes: usize,
+}
+
+/// 把 from 中所有的索引、kvpair（包括过期时间）和 sorted set 复制到 to，逐个 table 用
+/// get_iter 遍历，不会把整个 table 读到内存里。每复制 PROGRESS_INTERVAL 个 key
+/// 以及每个 table 复制完成时调用 progress。迁移期间 from 不应该再有写入
+pub fn migrate(
+    from: &impl Storage,
+    to: &impl Storage,
+    mut progress: impl FnMut(&MigrateStats),
+) -> Result<MigrateStats, KvError> {
+    let mut stats = MigrateStats::default();
+    // 先建索引，复制的数据会直接加入索引
+    for (table, spec) in from.indexes()? {
+        to.create_index(table, spec)?;
+        stats.indexes += 1;
+    }
+    let mut tables = from.tables()?;
+    tables.sort();
+    for table in tables {
+        stats.table = table.clone();
+        for pair in from.get_iter(table.as_str())? {
+            // 遍历期间过期的 key 就不再复制了
+            let ttl = from.ttl(table.as_str(), &pair.key)?;
+            let deadline = ttl.map(|ttl| now_millis() + ttl.as_millis() as u64);
+            to.set(table.as_str(), &pair.key, pair.value.unwrap_or_default())?;
+            if let Some(deadline) = deadline {
+                let ttl = Duration::from_millis(deadline.saturating_sub(now_millis()).max(1));
+                to.expire(table.as_str(), &pair.key, ttl)?;
+            }
+            stats.keys += 1;
+            if stats.keys % PROGRESS_INTERVAL == 0 {
+                progress(&stats);
+            }
+        }
+        stats.tables += 1;
+        progress(&stats);
+    }
+    for (table, key) in from.zsets()? {
+        let members = from.zrange(table.as_str(), &key, 0, -1)?;
+        to.zadd(table, key, members)?;
+        stats.zsets += 1;
+    }
+    to.flush()?;
+    Ok(stats)
+}
+
+/// 检查 from 中的每个 key 在 to 中都存在、值相同、都设置了（或者都没有设置）过期时间，
+/// 以及每个 sorted set 的 member 和 score 都相同。to 中多出来的数据不算不一致。
+/// sled 把 integer 按字符串存放，所以 string、binary 和 integer 按文本比较
+pub fn verify(from: &impl Storage, to: &impl Storage) -> Result<(), KvError> {
+    let mut mismatches = Vec::new();
+    let mut count = 0;
+    let mut mismatch = |table: &str, key: &[u8], reason: &str| {
+        count += 1;
+        if mismatches.len() < MAX_REPORTED_MISMATCHES {
+            let key = String::from_utf8_lossy(key);
+            mismatches.push(format!("{}/{}: {}", table, key, reason));
+        }
+    };
+    for table in from.tables()? {
+        for pair in from.get_iter(table.as_str())? {
+            let expected = pair.value.unwrap_or_default();
+            match to.get(table.as_str(), &pair.key)? {
+                Some(actual) if same_value(&expected, &actual) => {}
+                Some(_) => mismatch(&table, &pair.key, "value differs"),
+                None => {
+                    // 迁移之后过期的 key 不算丢失
+                    if from.contains(table.as_str(), &pair.key)? {
+                        mismatch(&table, &pair.key, "missing");
+                    }
+                    continue;
+                }
+            }
+            let from_ttl = from.ttl(table.as_str(), &pair.key)?;
+            let to_ttl = to.ttl(table.as_str(), &pair.key)?;
+            if from_ttl.is_some() != to_ttl.is_some() {
+                mismatch(&table, &pair.key, "ttl differs");
+            }
+        }
+    }
+    for (table, key) in from.zsets()? {
+        let expected = from.zrange(table.as_str(), &key, 0, -1)?;
+        if to.zrange(table.as_str(), &key, 0, -1)? != expected {
+            mismatch(&table, &key, "sorted set differs");
+        }
+    }
+    match count {
+        0 => Ok(()),
+        n => Err(KvError::Conflict(format!(
+            "{} keys are different after migration: {}",
+            n,
+            mismatches.join(", ")
+        ))),
+    }
+}
+
+fn same_value(expected: &Value, actual: &Value) -> bool {
+    expected == actual
+        || matches!((as_text(expected), as_text(actual)), (Some(a), Some(b)) if a == b)
+}
+
+fn as_text(v: &Value) -> Option<Vec<u8>> {
+    match &v.value {
+        Some(value::Value::String(s)) => Some(s.as_bytes().to_vec()),
+        Some(value::Value::Binary(b)) => Some(b.to_vec()),
+        Some(value::Value::Integer(i)) => Some(i.to_string().into_bytes()),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod migrate_tests {
+    use super::*;
+    use crate::{
+        config::FsyncPolicy,
+        memory::MemTable,
+        pb::abi::{IndexSpec, ScoredMember},
+        sled_db::SledDB,
+        wal::WalMemTable,
+    };
+    use tempfile::tempdir;
+
+    #[test]
+    fn migrate_should_copy_everything() {
+        let dir = tempdir().unwrap();
+        let from = WalMemTable::open(dir.path().join("kv.wal"), FsyncPolicy::No).unwrap();
+        for i in 0..PROGRESS_INTERVAL + 5 {
+            from.set("t1", format!("k{}", i), (i as i64).into())
+                .unwrap();
+        }
+        from.set("t2", "doc", serde_json::json!({"city": "sh"}).into())
+            .unwrap();
+        from.set("t2", "bin", b"\x00\xff".into()).unwrap();
+        from.expire("t2", "doc", Duration::from_secs(100)).unwrap();
+        let member = ScoredMember {
+            member: "m1".into(),
+            score: 1.5,
+        };
+        from.zadd("t3", "z1", vec![member.clone()]).unwrap();
+        let spec = IndexSpec {
+            name: "city".into(),
+            field: vec!["city".into()],
+        };
+        from.create_index("t2", spec).unwrap();
+
+        let to = SledDB::new(dir.path().join("sled"));
+        let mut reports = Vec::new();
+        let stats = migrate(&from, &to, |s| reports.push(s.clone())).unwrap();
+        assert_eq!(
+            (stats.tables, stats.keys, stats.zsets, stats.indexes),
+            (2, PROGRESS_INTERVAL + 7, 1, 1)
+        );
+        // 复制了 PROGRESS_INTERVAL 个 key 时一次，每个 table 结束时一次
+        let tables: Vec<&str> = reports.iter().map(|s| s.table.as_str()).collect();
+        assert_eq!(tables, ["t1", "t1", "t2"]);
+
+        verify(&from, &to).unwrap();
+        assert_eq!(to.get("t1", "k7").unwrap(), Some("7".into()));
+        assert!(to.ttl("t2", "doc").unwrap().unwrap() > Duration::from_secs(90));
+        assert_eq!(to.zrange("t3", "z1", 0, -1).unwrap(), vec![member]);
+        let docs = to.query("t2", "city", &"sh".into()).unwrap();
+        assert_eq!(docs.len(), 1);
+    }
+
+    #[test]
+    fn verify_should_report_differences() {
+        let from = MemTable::new();
+        let to = MemTable::new();
+        from.set("t1", "k1", "v1".into()).unwrap();
+        from.set("t1", "k2", "v2".into()).unwrap();
+        from.expire("t1", "k2", Duration::from_secs(100)).unwrap();
+        migrate(&from, &to, |_| {}).unwrap();
+        verify(&from, &to).unwrap();
+
+        to.set("t1", "k1", "other".into()).unwrap();
+        to.del("t1", "k2").unwrap();
+        to.set("t1", "k3", "extra".into()).unwrap();
+        let err = verify(&from, &to).unwrap_err().to_string();
+        assert!(err.contains("2 keys"), "{}", err);
+        assert!(err.contains("t1/k1: value differs"), "{}", err);
+        assert!(err.contains("t1/k2: missing"), "{}", err);
+    }
+}
@@ -1,9 +1,37 @@
+pub mod bitcask;
+pub mod cold;
+pub mod encrypted;
+pub mod eviction;
+pub mod export;
+pub mod index;
+#[cfg(feature = "lmdb")]
+pub mod lmdb;
 pub mod memory;
+pub mod migrate;
+#[cfg(any(feature = "lmdb", feature = "redb", feature = "sqlite"))]
+pub mod ordered;
+pub mod quota;
+#[cfg(feature = "redb")]
+pub mod redb_db;
+pub mod s3;
 pub mod sled_db;
+pub mod snapshot;
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
+pub mod tiered;
+pub mod wal;
+pub mod zset;
 
 use crate::{
     error::KvError,
-    pb::abi::{Kvpair, Value},
+    pb::abi::{IndexSpec, Kvpair, ScoredMember, Value, WalRecord},
+};
+use bytes::Bytes;
+use eviction::MemoryLimit;
+use quota::Quotas;
+use std::{
+    ops::{Bound, RangeBounds},
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
 /// 对存储的抽象，我们不关心数据存在哪儿，但需要定义外界如何和存储打交道
@@ -12,32 +40,347 @@ pub trait Storage: Send + Sync + 'static {
     fn get(
         &self,
         table: impl Into<String>,
-        key: impl Into<String>,
+        key: impl AsRef<[u8]>,
     ) -> Result<Option<Value>, KvError>;
 
     /// 从一个 HashTable 里设置一个 key 的 value，返回旧的 value
     fn set(
         &self,
         table: impl Into<String>,
-        key: impl Into<String>,
+        key: impl AsRef<[u8]>,
         value: Value,
     ) -> Result<Option<Value>, KvError>;
 
+    /// key 当前的值等于 expected 时才设置为 value，expected 为 None 表示 key 必须不存在。
+    /// 返回是否设置成功，以及 key 当前的值
+    fn compare_and_swap(
+        &self,
+        table: impl Into<String>,
+        key: impl AsRef<[u8]>,
+        expected: Option<Value>,
+        value: Value,
+    ) -> Result<(bool, Option<Value>), KvError>;
+
+    /// 按 condition 设置 key 的值，返回是否设置成功，以及 key 之前的值。
+    /// 设置成功时和 set 一样清掉过期时间；默认实现基于 compare_and_swap，比较和写入之间不会有别的修改
+    fn set_if(
+        &self,
+        table: impl Into<String>,
+        key: impl AsRef<[u8]>,
+        value: Value,
+        condition: SetCondition,
+    ) -> Result<(bool, Option<Value>), KvError> {
+        let (table, key) = (table.into(), key.as_ref());
+        loop {
+            let current = self.get(table.as_str(), key)?;
+            let allowed = match condition {
+                SetCondition::IfAbsent => current.is_none(),
+                SetCondition::IfExists => current.is_some(),
+            };
+            if !allowed {
+                return Ok((false, current));
+            }
+            // 读取之后 key 被别人修改了就重新读取
+            let expected = current.clone();
+            if self
+                .compare_and_swap(table.as_str(), key, expected, value.clone())?
+                .0
+            {
+                return Ok((true, current));
+            }
+        }
+    }
+
+    /// 把 key 的值当成整数加上 delta，key 不存在时从 0 开始，返回新的值；不会改变 key 的过期时间
+    fn incr(
+        &self,
+        table: impl Into<String>,
+        key: impl AsRef<[u8]>,
+        delta: i64,
+    ) -> Result<i64, KvError>;
+
+    /// 原子地修改 key 的值：f 拿到当前的值（key 不存在时为 None），返回新的值和交给调用者的结果，
+    /// f 返回错误时不做任何修改；不会改变 key 的过期时间。f 可能被调用多次
+    fn update<T>(
+        &self,
+        table: impl Into<String>,
+        key: impl AsRef<[u8]>,
+        f: impl FnMut(Option<&Value>) -> Result<(Value, T), KvError>,
+    ) -> Result<T, KvError>;
+
     /// 查看 HashTable 中是否有 key
-    fn contains(&self, table: impl Into<String>, key: impl Into<String>) -> Result<bool, KvError>;
+    fn contains(&self, table: impl Into<String>, key: impl AsRef<[u8]>) -> Result<bool, KvError>;
 
     /// 从 HashTable 中删除一个 key
     fn del(
         &self,
         table: impl Into<String>,
-        key: impl Into<String>,
+        key: impl AsRef<[u8]>,
     ) -> Result<Option<Value>, KvError>;
 
+    /// 一次读取 HashTable 中的多个 key，结果和 keys 的顺序一致，不存在的 key 为 None
+    fn mget(
+        &self,
+        table: impl Into<String>,
+        keys: &[impl AsRef<[u8]>],
+    ) -> Result<Vec<Option<Value>>, KvError> {
+        let table = table.into();
+        keys.iter()
+            .map(|key| self.get(table.as_str(), key))
+            .collect()
+    }
+
+    /// 一次写入一组 kv pair，并清除这些 key 的过期时间。
+    /// 默认实现通过 apply_batch 写入，其它操作要么看到全部修改，要么一个都看不到
+    fn mset(&self, table: impl Into<String>, pairs: Vec<Kvpair>) -> Result<(), KvError> {
+        let table = table.into();
+        let records = pairs
+            .into_iter()
+            .map(|pair| WalRecord::new_set(&table, pair.key, pair.value.unwrap_or_default()))
+            .collect();
+        self.apply_batch(records, &[]).map(|_| ())
+    }
+
     /// 遍历 HashTable，返回所有 kv pair（这个接口不好）
     fn get_all(&self, table: impl Into<String>) -> Result<Vec<Kvpair>, KvError>;
 
     /// 遍历 HashTable，返回 kv pair 的 Iterator
     fn get_iter(&self, table: impl Into<String>) -> Result<impl Iterator<Item = Kvpair>, KvError>;
+
+    /// 遍历 HashTable 中以 prefix 开头的 key，返回 kv pair 的 Iterator
+    fn scan_prefix(
+        &self,
+        table: impl Into<String>,
+        prefix: impl AsRef<[u8]>,
+    ) -> Result<impl Iterator<Item = Kvpair>, KvError>;
+
+    /// 按 key 的字节序返回 key 在 range 之间的 kv pair
+    fn range(
+        &self,
+        table: impl Into<String>,
+        range: impl RangeBounds<Bytes>,
+    ) -> Result<Vec<Kvpair>, KvError>;
+
+    /// 按 key 的字节序返回 start 之后（不含 start）的最多 limit 个 kv pair，start 为 None 时从头开始。
+    /// 用来分批读取很大的 table；默认实现会先读出 start 之后的所有 kv pair
+    fn scan_after(
+        &self,
+        table: impl Into<String>,
+        start: Option<Bytes>,
+        limit: usize,
+    ) -> Result<Vec<Kvpair>, KvError> {
+        let start = start.map_or(Bound::Unbounded, Bound::Excluded);
+        let mut pairs = self.range(table, (start, Bound::Unbounded))?;
+        pairs.truncate(limit);
+        Ok(pairs)
+    }
+
+    /// 给 HashTable 中的 key 设置存活时间，key 不存在时返回 false
+    fn expire(
+        &self,
+        table: impl Into<String>,
+        key: impl AsRef<[u8]>,
+        ttl: Duration,
+    ) -> Result<bool, KvError>;
+
+    /// 查看 key 剩余的存活时间，没有设置过期时间则返回 None
+    fn ttl(
+        &self,
+        table: impl Into<String>,
+        key: impl AsRef<[u8]>,
+    ) -> Result<Option<Duration>, KvError>;
+
+    /// 清理所有已过期的 key，返回清理掉的数量
+    fn purge_expired(&self) -> Result<usize, KvError>;
+
+    /// 列出所有有数据的 table
+    fn tables(&self) -> Result<Vec<String>, KvError>;
+
+    /// 存储引擎的名字，INFO 中显示
+    fn backend(&self) -> &'static str;
+
+    /// 每个有数据的 table 的 key 数量和占用的空间
+    fn stats(&self) -> Result<Vec<(String, TableStats)>, KvError>;
+
+    /// 删除 table 中所有的 key 和 sorted set，保留 table 上的索引，返回删除的 key 数
+    fn truncate_table(&self, table: impl Into<String>) -> Result<usize, KvError>;
+
+    /// 删除 table 中所有的 key 和 sorted set，以及 table 上的索引，返回删除的 key 数
+    fn drop_table(&self, table: impl Into<String>) -> Result<usize, KvError>;
+
+    /// key 的版本号，每次修改（包括删除和过期）都会变大，删除之后再写入也不会回到之前的版本号；
+    /// 空的数据库中没有修改过的 key 为 0。不存在的 key 可能和其它被删除的 key 共用一个版本号
+    fn version(&self, table: impl Into<String>, key: impl AsRef<[u8]>) -> Result<u64, KvError>;
+
+    /// 原子地应用一组 SET/DEL/EXPIRE 记录：其它操作要么看到全部修改，要么一个都看不到。
+    /// watched 中有 key 的版本号变了时什么都不做，返回 false
+    fn apply_batch(&self, records: Vec<WalRecord>, watched: &[KeyVersion])
+        -> Result<bool, KvError>;
+
+    /// 把 member 加入 key 对应的 sorted set，已经存在的 member 更新 score，返回新加入的 member 数。
+    /// sorted set 和 HashTable 中同名的 key 互不影响，也不会过期
+    fn zadd(
+        &self,
+        table: impl Into<String>,
+        key: impl AsRef<[u8]>,
+        members: Vec<ScoredMember>,
+    ) -> Result<usize, KvError>;
+
+    /// 按 score 从小到大排序，返回排名在 [start, stop] 之间的 member，负数从尾部开始数
+    fn zrange(
+        &self,
+        table: impl Into<String>,
+        key: impl AsRef<[u8]>,
+        start: i64,
+        stop: i64,
+    ) -> Result<Vec<ScoredMember>, KvError>;
+
+    /// 按 score 从小到大排序，返回 score 在 [min, max] 之间的 member
+    fn zrange_by_score(
+        &self,
+        table: impl Into<String>,
+        key: impl AsRef<[u8]>,
+        min: f64,
+        max: f64,
+    ) -> Result<Vec<ScoredMember>, KvError>;
+
+    /// 列出所有的 sorted set，<table, key>
+    fn zsets(&self) -> Result<Vec<(String, Bytes)>, KvError>;
+
+    /// 在 table 上建立索引，已有的数据也会加入索引，之后的每次修改都会更新索引。
+    /// 同名的索引已经存在时什么都不做，返回 false
+    fn create_index(&self, table: impl Into<String>, spec: IndexSpec) -> Result<bool, KvError>;
+
+    /// 通过索引查找被索引的部分等于 value 的 key，按 key 排序返回它们的 kvpair；
+    /// 索引不存在时返回 NotFound
+    fn query(
+        &self,
+        table: impl Into<String>,
+        index: &str,
+        value: &Value,
+    ) -> Result<Vec<Kvpair>, KvError>;
+
+    /// 列出所有的索引，<table, 索引>
+    fn indexes(&self) -> Result<Vec<(String, IndexSpec)>, KvError>;
+
+    /// 把还在缓冲区里的修改写到磁盘，退出之前调用
+    fn flush(&self) -> Result<(), KvError> {
+        Ok(())
+    }
+
+    /// 压缩存储占用的磁盘空间（比如合并数据文件、重写 WAL），返回回收的字节数；不在磁盘上的存储什么都不做
+    fn compact(&self) -> Result<u64, KvError> {
+        Ok(0)
+    }
+
+    /// 存储占用的磁盘空间（字节），不在磁盘上的存储返回 None
+    fn disk_usage(&self) -> Result<Option<u64>, KvError> {
+        Ok(None)
+    }
+
+    /// 读写会阻塞线程（比如磁盘 I/O）的存储引擎返回 true，服务器会把命令放到阻塞线程上执行
+    fn is_blocking(&self) -> bool {
+        false
+    }
+
+    /// 内存上限，不支持的 store 返回 None
+    fn memory_limit(&self) -> Option<&MemoryLimit> {
+        None
+    }
+
+    /// 存储配额和每个 table 的使用量，不支持的 store 返回 None
+    fn quotas(&self) -> Option<&Quotas> {
+        None
+    }
+
+    /// 把所有数据导出成一组 SET/EXPIRE/ZADD 记录和索引的定义，重放这些记录就能恢复数据。
+    /// 默认实现逐个 table 遍历，遍历期间的写入可能只有一部分被导出
+    fn snapshot(&self) -> Result<Vec<WalRecord>, KvError> {
+        let mut records = Vec::new();
+        for (table, spec) in self.indexes()? {
+            records.push(WalRecord::new_create_index(table, spec));
+        }
+        for table in self.tables()? {
+            for pair in self.get_iter(table.as_str())? {
+                let ttl = self.ttl(table.as_str(), &pair.key)?;
+                let value = pair.value.unwrap_or_default();
+                records.push(WalRecord::new_set(&table, &pair.key, value));
+                if let Some(ttl) = ttl {
+                    let deadline = now_millis() + ttl.as_millis() as u64;
+                    records.push(WalRecord::new_expire(&table, pair.key, deadline));
+                }
+            }
+        }
+        for (table, key) in self.zsets()? {
+            let members = self.zrange(table.as_str(), &key, 0, -1)?;
+            records.push(WalRecord::new_zadd(table, key, members));
+        }
+        Ok(records)
+    }
+}
+
+/// set_if 的条件
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SetCondition {
+    /// key 不存在时才设置（SETNX）
+    IfAbsent,
+    /// key 已经存在时才设置（SETXX）
+    IfExists,
+}
+
+/// 某个时刻 key 的版本号，用于 WATCH
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyVersion {
+    pub table: String,
+    pub key: Bytes,
+    pub version: u64,
+}
+
+/// table 的统计信息
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TableStats {
+    /// key 的数量，不包括 sorted set
+    pub keys: u64,
+    /// 大致占用的空间：MemTable 是估算的内存，SledDB 是 key 和 value 的字节数
+    pub bytes: u64,
+}
+
+/// 计算 incr 之后的值，key 不存在时 current 为 None
+fn incr_value(key: &[u8], current: Option<&Value>, delta: i64) -> Result<i64, KvError> {
+    let current = match current {
+        Some(v) => v.to_integer().ok_or_else(|| {
+            KvError::InvalidCommand(format!(
+                "value of {} is not an integer",
+                String::from_utf8_lossy(key)
+            ))
+        })?,
+        None => 0,
+    };
+    current.checked_add(delta).ok_or_else(|| {
+        KvError::InvalidCommand(format!(
+            "increment of {} would overflow",
+            String::from_utf8_lossy(key)
+        ))
+    })
+}
+
+/// range 的起点在终点之后，或者两端相同但不包含端点时，range 是空的。
+/// BTreeSet::range 遇到这样的 range 会 panic，需要先检查
+pub(crate) fn is_empty_range(range: &impl RangeBounds<Bytes>) -> bool {
+    match (range.start_bound(), range.end_bound()) {
+        (Bound::Included(start), Bound::Included(end)) => start > end,
+        (Bound::Included(start) | Bound::Excluded(start), Bound::Excluded(end))
+        | (Bound::Excluded(start), Bound::Included(end)) => start >= end,
+        _ => false,
+    }
+}
+
+/// 当前时刻的毫秒时间戳
+pub(crate) fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
 }
 
 /// 提供 Storage iterator，这样 trait 的实现者只需要
@@ -65,22 +408,13 @@ where
     }
 }
 
-trait U8toString<T> {
-    fn u8_to_string(self) -> String;
-}
-
-impl<T> U8toString<T> for T
-where
-    T: AsRef<[u8]>,
-{
-    fn u8_to_string(self) -> String {
-        String::from_utf8_lossy(self.as_ref()).to_string()
-    }
-}
-
 #[cfg(test)]
 mod tests {
-    use super::{memory::MemTable, *};
+    use super::{memory::MemTable, quota::QuotaScope, *};
+    use crate::{
+        config::{EvictionPolicy, MemTableConfig, QuotaConfig},
+        pb::abi::{value, ValueList, ValueMap},
+    };
     use pretty_assertions::assert_eq;
 
     #[test]
@@ -101,6 +435,679 @@ mod tests {
         test_get_iter(store);
     }
 
+    #[test]
+    pub fn memtable_expiration_should_work() {
+        let store = MemTable::new();
+        test_expiration(store);
+    }
+
+    #[test]
+    pub fn memtable_compare_and_swap_should_work() {
+        let store = MemTable::new();
+        test_compare_and_swap(store);
+    }
+
+    #[test]
+    pub fn memtable_incr_should_work() {
+        let store = MemTable::new();
+        test_incr(store);
+    }
+
+    #[test]
+    pub fn memtable_apply_batch_should_work() {
+        let store = MemTable::new();
+        test_apply_batch(store);
+    }
+
+    #[test]
+    pub fn memtable_update_should_work() {
+        let store = MemTable::new();
+        test_update(store);
+    }
+
+    #[test]
+    pub fn memtable_binary_keys_should_work() {
+        let store = MemTable::new();
+        test_binary_keys(store);
+    }
+
+    #[test]
+    pub fn memtable_zset_should_work() {
+        let store = MemTable::new();
+        test_zset(store);
+    }
+
+    #[test]
+    pub fn memtable_index_should_work() {
+        let store = MemTable::new();
+        test_index(store);
+    }
+
+    #[test]
+    pub fn memtable_range_should_work() {
+        let store = MemTable::new();
+        test_range(store);
+    }
+
+    #[test]
+    pub fn memtable_scan_prefix_should_work() {
+        let store = MemTable::new();
+        test_scan_prefix(store);
+    }
+
+    #[test]
+    pub fn memtable_table_management_should_work() {
+        let store = MemTable::new();
+        test_table_management(store);
+    }
+
+    #[test]
+    pub fn memtable_stats_should_work() {
+        let store = MemTable::new();
+        test_stats(store);
+    }
+
+    #[test]
+    pub fn memtable_eviction_should_work() {
+        let store = MemTable::new();
+        test_eviction(store);
+    }
+
+    #[test]
+    pub fn memtable_quota_should_work() {
+        let store = MemTable::new();
+        test_quota(store);
+    }
+
+    #[test]
+    pub fn memtable_with_shards_should_work() {
+        let config = MemTableConfig {
+            shards: Some(64),
+            capacity: Some(1024),
+            ..Default::default()
+        };
+        test_basic_interface(MemTable::with_config(&config).unwrap());
+        test_expiration(MemTable::with_config(&config).unwrap());
+        test_zset(MemTable::with_config(&config).unwrap());
+        test_range(MemTable::with_config(&config).unwrap());
+        test_scan_prefix(MemTable::with_config(&config).unwrap());
+
+        for shards in [0, 1, 3] {
+            let config = MemTableConfig {
+                shards: Some(shards),
+                ..Default::default()
+            };
+            assert!(MemTable::with_config(&config).is_err());
+        }
+    }
+
+    pub fn test_update(store: impl Storage) {
+        let list = |values: &[&str]| -> Value {
+            ValueList {
+                values: values.iter().map(|&v| v.into()).collect(),
+            }
+            .into()
+        };
+        let push = |current: Option<&Value>, v: &str| {
+            let mut values = match current.and_then(|c| c.value.as_ref()) {
+                Some(value::Value::List(list)) => list.values.clone(),
+                Some(_) => return Err(KvError::InvalidCommand("not a list".into())),
+                None => vec![],
+            };
+            values.push(v.into());
+            let len = values.len();
+            Ok((ValueList { values }.into(), len))
+        };
+        assert_eq!(store.update("t9", "k1", |c| push(c, "a")).unwrap(), 1);
+        assert_eq!(store.update("t9", "k1", |c| push(c, "b")).unwrap(), 2);
+        // 结构化的 value 读出来和写进去的一样
+        assert_eq!(store.get("t9", "k1").unwrap(), Some(list(&["a", "b"])));
+        assert_eq!(
+            store.get_all("t9").unwrap(),
+            vec![Kvpair::new("k1", list(&["a", "b"]))]
+        );
+
+        // 出错时不修改，也不改变过期时间
+        store.set("t9", "k2", "v".into()).unwrap();
+        store.expire("t9", "k2", Duration::from_secs(100)).unwrap();
+        assert!(store.update("t9", "k2", |c| push(c, "a")).is_err());
+        assert_eq!(store.get("t9", "k2").unwrap(), Some("v".into()));
+        store.set("t9", "k2", list(&[])).unwrap();
+        store.expire("t9", "k2", Duration::from_secs(100)).unwrap();
+        store.update("t9", "k2", |c| push(c, "a")).unwrap();
+        assert!(store.ttl("t9", "k2").unwrap().is_some());
+    }
+
+    pub fn test_binary_keys(store: impl Storage) {
+        // 不是合法 utf8 的 key 不会被替换成 U+FFFD，两个不同的 key 不会混在一起
+        let (k1, k2) = (b"\xff\x00k".as_slice(), b"\xfe\x00k".as_slice());
+        store.set("t8", k1, "v1".into()).unwrap();
+        store.set("t8", k2, "v2".into()).unwrap();
+        assert_eq!(store.get("t8", k1).unwrap(), Some("v1".into()));
+        assert_eq!(store.get("t8", k2).unwrap(), Some("v2".into()));
+
+        let mut data = store.get_all("t8").unwrap();
+        data.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        assert_eq!(
+            data,
+            vec![Kvpair::new(k2, "v2".into()), Kvpair::new(k1, "v1".into())]
+        );
+        assert!(store.expire("t8", k1, Duration::from_secs(100)).unwrap());
+        let records = store.snapshot().unwrap();
+        assert!(records.iter().all(|r| r.key == k1 || r.key == k2));
+        assert_eq!(store.del("t8", k1).unwrap(), Some("v1".into()));
+        assert!(!store.contains("t8", k1).unwrap());
+    }
+
+    pub fn test_zset(store: impl Storage) {
+        let members = |v: &[(&str, f64)]| -> Vec<ScoredMember> {
+            v.iter().map(|&(m, score)| (m, score).into()).collect()
+        };
+        let added = store
+            .zadd("t6", "z1", members(&[("a", 3.0), ("b", -1.5), ("c", 2.0)]))
+            .unwrap();
+        assert_eq!(added, 3);
+        // 已有的 member 只更新 score
+        let added = store
+            .zadd("t6", "z1", members(&[("b", 5.0), ("d", 0.0)]))
+            .unwrap();
+        assert_eq!(added, 1);
+        let all = members(&[("d", 0.0), ("c", 2.0), ("a", 3.0), ("b", 5.0)]);
+        assert_eq!(store.zrange("t6", "z1", 0, -1).unwrap(), all);
+        assert_eq!(store.zrange("t6", "z1", -2, 10).unwrap(), all[2..]);
+        assert!(store.zrange("t6", "z1", 3, 1).unwrap().is_empty());
+        assert_eq!(
+            store.zrange_by_score("t6", "z1", 1.0, 3.0).unwrap(),
+            all[1..3]
+        );
+        let (inf, neg_inf) = (f64::INFINITY, f64::NEG_INFINITY);
+        assert_eq!(
+            store.zrange_by_score("t6", "z1", neg_inf, inf).unwrap(),
+            all
+        );
+        assert!(store.zrange("t6", "z2", 0, -1).unwrap().is_empty());
+
+        // NaN 不能作为 score，整个 ZADD 都不生效
+        let res = store.zadd("t6", "z1", members(&[("e", 1.0), ("f", f64::NAN)]));
+        assert!(res.is_err());
+        assert_eq!(store.zrange("t6", "z1", 0, -1).unwrap(), all);
+
+        // sorted set 和 HashTable 中的 key 互不影响
+        assert_eq!(store.get("t6", "z1").unwrap(), None);
+        assert_eq!(
+            store.zsets().unwrap(),
+            vec![("t6".to_string(), "z1".into())]
+        );
+        let records = store.snapshot().unwrap();
+        assert_eq!(records, vec![WalRecord::new_zadd("t6", "z1", all)]);
+    }
+
+    pub fn test_index(store: impl Storage) {
+        let user = |name: &str, city: &str| -> Value {
+            ValueMap {
+                entries: [
+                    ("city".to_string(), city.into()),
+                    ("name".to_string(), name.into()),
+                ]
+                .into(),
+            }
+            .into()
+        };
+        let spec = |name: &str, field: &[&str]| IndexSpec {
+            name: name.into(),
+            field: field.iter().map(|f| f.to_string()).collect(),
+        };
+        let keys =
+            |pairs: Vec<Kvpair>| -> Vec<Bytes> { pairs.into_iter().map(|p| p.key).collect() };
+        let query = |index: &str, value: Value| keys(store.query("t4", index, &value).unwrap());
+
+        // 已有的数据也会加入索引
+        store.set("t4", "k1", user("alice", "hangzhou")).unwrap();
+        store.set("t4", "k2", user("bob", "beijing")).unwrap();
+        assert!(store
+            .create_index("t4", spec("by_city", &["city"]))
+            .unwrap());
+        assert!(!store.create_index("t4", spec("by_city", &[])).unwrap());
+        assert!(store.create_index("t4", spec("by_value", &[])).unwrap());
+        assert!(store.create_index("t4", spec("", &[])).is_err());
+        assert_eq!(query("by_city", "hangzhou".into()), ["k1"]);
+
+        // 之后的修改都会更新索引
+        store.set("t4", "k3", user("carol", "hangzhou")).unwrap();
+        assert_eq!(query("by_city", "hangzhou".into()), ["k1", "k3"]);
+        let pairs = store.query("t4", "by_city", &"beijing".into()).unwrap();
+        assert_eq!(pairs, vec![Kvpair::new("k2", user("bob", "beijing"))]);
+        store.set("t4", "k1", user("alice", "beijing")).unwrap();
+        store.del("t4", "k3").unwrap();
+        assert!(query("by_city", "hangzhou".into()).is_empty());
+        assert_eq!(query("by_city", "beijing".into()), ["k1", "k2"]);
+
+        // 整个 value 作为索引时，integer 和 string 按文本比较
+        store.set("t4", "k4", "plain".into()).unwrap();
+        store.incr("t4", "k5", 30).unwrap();
+        assert_eq!(query("by_value", "plain".into()), ["k4"]);
+        assert_eq!(query("by_value", 30.into()), ["k5"]);
+        assert_eq!(query("by_value", "30".into()), ["k5"]);
+        store.expire("t4", "k4", Duration::ZERO).unwrap();
+        assert!(query("by_value", "plain".into()).is_empty());
+
+        let records = vec![WalRecord::new_set("t4", "k6", user("dave", "hangzhou"))];
+        assert!(store.apply_batch(records, &[]).unwrap());
+        assert_eq!(query("by_city", "hangzhou".into()), ["k6"]);
+
+        assert!(store.query("t4", "by_name", &"alice".into()).is_err());
+        let mut indexes = store.indexes().unwrap();
+        indexes.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        let expected = vec![
+            ("t4".to_string(), spec("by_city", &["city"])),
+            ("t4".to_string(), spec("by_value", &[])),
+        ];
+        assert_eq!(indexes, expected);
+    }
+
+    pub fn test_range(store: impl Storage) {
+        for key in ["b", "d", "a", "c", "e"] {
+            store.set("t3", key, key.into()).unwrap();
+        }
+        // 相邻的 table 不会混进来
+        store.set("t3a", "a", "v".into()).unwrap();
+        store.set("t2", "z", "v".into()).unwrap();
+        store.del("t3", "d").unwrap();
+        store.expire("t3", "e", Duration::ZERO).unwrap();
+        let keys =
+            |pairs: Vec<Kvpair>| -> Vec<Bytes> { pairs.into_iter().map(|p| p.key).collect() };
+        let key = |k: &str| Bytes::copy_from_slice(k.as_bytes());
+
+        let pairs = store.range("t3", key("b")..key("d")).unwrap();
+        assert_eq!(
+            pairs,
+            vec![Kvpair::new("b", "b".into()), Kvpair::new("c", "c".into())]
+        );
+        assert_eq!(keys(store.range("t3", key("b")..).unwrap()), ["b", "c"]);
+        assert_eq!(keys(store.range("t3", ..=key("b")).unwrap()), ["a", "b"]);
+        assert_eq!(keys(store.range("t3", ..).unwrap()), ["a", "b", "c"]);
+        assert!(store.range("t3", key("c")..key("a")).unwrap().is_empty());
+        assert!(store.range("t3", key("b")..key("b")).unwrap().is_empty());
+        assert!(store.range("t4", ..).unwrap().is_empty());
+
+        // 分批读取
+        assert_eq!(keys(store.scan_after("t3", None, 2).unwrap()), ["a", "b"]);
+        assert_eq!(
+            keys(store.scan_after("t3", Some(key("b")), 2).unwrap()),
+            ["c"]
+        );
+        assert!(store
+            .scan_after("t3", Some(key("c")), 2)
+            .unwrap()
+            .is_empty());
+        assert!(store.scan_after("t4", None, 2).unwrap().is_empty());
+    }
+
+    pub fn test_scan_prefix(store: impl Storage) {
+        for key in ["user:1:name", "user:1:age", "user:2:name", "user:3:name"] {
+            store.set("t5", key, key.into()).unwrap();
+        }
+        // 其它 table 中同样前缀的 key 不会混进来
+        store.set("t5x", "user:1:name", "v".into()).unwrap();
+        store.set("t5", b"user:\xff", "v".into()).unwrap();
+        store.expire("t5", "user:3:name", Duration::ZERO).unwrap();
+        let mut keys: Vec<Bytes> = store
+            .scan_prefix("t5", "user:")
+            .unwrap()
+            .map(|p| p.key)
+            .collect();
+        keys.sort();
+        let expected: [&[u8]; 4] = [b"user:1:age", b"user:1:name", b"user:2:name", b"user:\xff"];
+        assert_eq!(keys, expected);
+
+        let pairs: Vec<Kvpair> = store.scan_prefix("t5", "user:1:n").unwrap().collect();
+        assert_eq!(
+            pairs,
+            vec![Kvpair::new("user:1:name", "user:1:name".into())]
+        );
+        assert_eq!(store.scan_prefix("t5", "").unwrap().count(), 4);
+        assert_eq!(store.scan_prefix("t5", "order:").unwrap().count(), 0);
+    }
+
+    pub fn test_table_management(store: impl Storage) {
+        let spec = IndexSpec {
+            name: "by_value".into(),
+            field: vec![],
+        };
+        for table in ["t7", "t8"] {
+            store.set(table, "k1", "v1".into()).unwrap();
+            store.set(table, "k2", "v2".into()).unwrap();
+            store.expire(table, "k2", Duration::from_secs(100)).unwrap();
+            store.zadd(table, "z1", vec![("m1", 1.0).into()]).unwrap();
+            store.create_index(table, spec.clone()).unwrap();
+        }
+        store.get("t9", "k1").unwrap();
+        let mut tables = store.tables().unwrap();
+        tables.sort();
+        assert_eq!(tables, ["t7", "t8"]);
+
+        // truncate 之后 table 是空的，但是索引还在，并且跟着之后的写入更新
+        let version = store.version("t7", "k1").unwrap();
+        assert_eq!(store.truncate_table("t7").unwrap(), 3);
+        assert!(store.version("t7", "k1").unwrap() > version);
+        assert!(store.get_all("t7").unwrap().is_empty());
+        assert!(store.zrange("t7", "z1", 0, -1).unwrap().is_empty());
+        assert!(store
+            .query("t7", "by_value", &"v1".into())
+            .unwrap()
+            .is_empty());
+        store.set("t7", "k2", "v1".into()).unwrap();
+        assert_eq!(store.ttl("t7", "k2").unwrap(), None);
+        let pairs = store.query("t7", "by_value", &"v1".into()).unwrap();
+        assert_eq!(pairs, vec![Kvpair::new("k2", "v1".into())]);
+
+        // drop 连同索引一起删除
+        assert_eq!(store.drop_table("t8").unwrap(), 3);
+        assert_eq!(store.tables().unwrap(), ["t7"]);
+        assert!(store.query("t8", "by_value", &"v1".into()).is_err());
+        assert!(store.zsets().unwrap().is_empty());
+        let indexes = store.indexes().unwrap();
+        assert_eq!(indexes, vec![("t7".to_string(), spec)]);
+        assert_eq!(store.drop_table("t8").unwrap(), 0);
+    }
+
+    pub fn test_stats(store: impl Storage) {
+        store.set("t1", "k1", "v1".into()).unwrap();
+        store.set("t1", "k2", "v2".into()).unwrap();
+        store.set("t2", "k1", "v1".into()).unwrap();
+        store.set("t2", "k2", "v2".into()).unwrap();
+        store.expire("t2", "k2", Duration::ZERO).unwrap();
+        store.zadd("t3", "z1", vec![("m1", 1.0).into()]).unwrap();
+        store.get("t4", "k1").unwrap();
+
+        let mut stats = store.stats().unwrap();
+        stats.sort_by(|a, b| a.0.cmp(&b.0));
+        let tables: Vec<(&str, u64)> = stats.iter().map(|(t, s)| (t.as_str(), s.keys)).collect();
+        assert_eq!(tables, [("t1", 2), ("t2", 1)]);
+        assert!(stats[0].1.bytes > stats[1].1.bytes);
+    }
+
+    pub fn test_eviction(store: impl Storage) {
+        let memory = store.memory_limit().unwrap();
+        store.set("t7", "k1", "v".into()).unwrap();
+        // 每个 key 占用的内存都一样，上限可以放下 4 个
+        let size = memory.used();
+        memory.set_max_memory(size * 4);
+        memory.set_policy(EvictionPolicy::Lfu);
+        for key in ["k2", "k3", "k4", "k5"] {
+            store.set("t7", key, "v".into()).unwrap();
+        }
+        for key in ["k1", "k3", "k4"] {
+            store.get("t7", key).unwrap();
+        }
+
+        // 超过上限之后，淘汰访问次数最少的 key，直到低于上限的 15/16
+        store.set("t7", "k6", "v".into()).unwrap();
+        assert_eq!(store.get("t7", "k2").unwrap(), None);
+        assert_eq!(store.get("t7", "k5").unwrap(), None);
+        for key in ["k1", "k3", "k4", "k6"] {
+            assert!(store.contains("t7", key).unwrap());
+        }
+        assert_eq!(memory.used(), size * 4);
+
+        // 不允许淘汰时拒绝写入，删除不受影响
+        memory.set_policy(EvictionPolicy::RejectWrites);
+        memory.set_max_memory(size);
+        let res = store.set("t7", "k7", "v".into());
+        assert!(matches!(res, Err(KvError::OutOfMemory(_))));
+        assert!(matches!(
+            store.incr("t7", "k8", 1),
+            Err(KvError::OutOfMemory(_))
+        ));
+        store.del("t7", "k1").unwrap();
+        assert_eq!(memory.used(), size * 3);
+    }
+
+    pub fn test_quota(store: impl Storage) {
+        let quotas = store.quotas().unwrap();
+        let config = [QuotaConfig {
+            table: Some("t10".into()),
+            max_keys: Some(2),
+            ..Default::default()
+        }];
+        quotas.configure(&config).unwrap();
+        store.set("t10", "k1", "v1".into()).unwrap();
+        store.set("t10", "k2", "v2".into()).unwrap();
+        // 覆盖已有的 key 不增加 key 的数量
+        store.set("t10", "k1", "v3".into()).unwrap();
+        assert!(matches!(
+            store.set("t10", "k4", "v".into()),
+            Err(KvError::QuotaExceeded(_))
+        ));
+        assert!(matches!(
+            store.incr("t10", "k4", 1),
+            Err(KvError::QuotaExceeded(_))
+        ));
+        let pairs = vec![Kvpair::new("k1", "v".into()), Kvpair::new("k4", "v".into())];
+        assert!(matches!(
+            store.mset("t10", pairs),
+            Err(KvError::QuotaExceeded(_))
+        ));
+        assert!(!store.contains("t10", "k4").unwrap());
+        store.set("t11", "k4", "v".into()).unwrap();
+
+        // 同一个 batch 中先删除再写入不会超过配额
+        let records = vec![
+            WalRecord::new_del("t10", "k2"),
+            WalRecord::new_set("t10", "k3", "v".into()),
+        ];
+        assert!(store.apply_batch(records, &[]).unwrap());
+        let usage = quotas.usage(&QuotaScope::Table("t10".into()));
+        let stats = store.stats().unwrap();
+        assert_eq!(
+            Some(&usage),
+            stats.iter().find(|s| s.0 == "t10").map(|s| &s.1)
+        );
+        assert_eq!(usage.keys, 2);
+    }
+
+    pub fn test_apply_batch(store: impl Storage) {
+        store.set("t6", "k1", "v1".into()).unwrap();
+        store.set("t6", "k2", "v2".into()).unwrap();
+        store.expire("t6", "k2", Duration::from_secs(100)).unwrap();
+
+        let deadline = now_millis() + 100_000;
+        let records = vec![
+            WalRecord::new_del("t6", "k1"),
+            WalRecord::new_set("t6", "k2", "v3".into()),
+            WalRecord::new_set("t6", "k3", "v4".into()),
+            WalRecord::new_expire("t6", "k3", deadline),
+            // 不存在的 key 设置过期时间会被忽略
+            WalRecord::new_expire("t6", "k4", deadline),
+        ];
+        assert!(store.apply_batch(records, &[]).unwrap());
+
+        assert_eq!(store.get("t6", "k1").unwrap(), None);
+        // SET 会清掉之前的过期时间
+        assert_eq!(store.get("t6", "k2").unwrap(), Some("v3".into()));
+        assert_eq!(store.ttl("t6", "k2").unwrap(), None);
+        assert!(store.ttl("t6", "k3").unwrap().unwrap() > Duration::from_secs(90));
+        assert!(!store.contains("t6", "k4").unwrap());
+
+        // 版本号变了之后，整组记录都不会生效
+        let watched = KeyVersion {
+            table: "t6".into(),
+            key: "k2".into(),
+            version: store.version("t6", "k2").unwrap(),
+        };
+        store.set("t6", "k2", "v5".into()).unwrap();
+        let records = vec![WalRecord::new_set("t6", "k2", "v6".into())];
+        assert!(!store.apply_batch(records.clone(), &[watched]).unwrap());
+        assert_eq!(store.get("t6", "k2").unwrap(), Some("v5".into()));
+        let watched = KeyVersion {
+            table: "t6".into(),
+            key: "k2".into(),
+            version: store.version("t6", "k2").unwrap(),
+        };
+        assert!(store.apply_batch(records, &[watched]).unwrap());
+        assert_eq!(store.get("t6", "k2").unwrap(), Some("v6".into()));
+    }
+
+    #[test]
+    pub fn memtable_mget_mset_should_work() {
+        let store = MemTable::new();
+        test_mget_mset(store);
+    }
+
+    pub fn test_mget_mset(store: impl Storage) {
+        store.set("t7", "k1", "v1".into()).unwrap();
+        store.expire("t7", "k1", Duration::from_secs(100)).unwrap();
+        let version = store.version("t7", "k1").unwrap();
+
+        let pairs = vec![
+            Kvpair::new("k1", "v2".into()),
+            Kvpair::new("k2", 2.into()),
+            Kvpair::new([0xff, 0], "v3".into()),
+        ];
+        store.mset("t7", pairs).unwrap();
+        // MSET 和 SET 一样会清掉过期时间，修改版本号
+        assert_eq!(store.ttl("t7", "k1").unwrap(), None);
+        assert!(store.version("t7", "k1").unwrap() > version);
+
+        let keys: [&[u8]; 4] = [b"k2", b"k0", b"k1", &[0xff, 0]];
+        let values = store.mget("t7", &keys).unwrap();
+        assert_eq!(values.len(), 4);
+        assert!(values[0].as_ref().and_then(Value::to_integer) == Some(2));
+        assert_eq!(values[1..], [None, Some("v2".into()), Some("v3".into())]);
+        assert!(store.mget("t7", &[] as &[&str]).unwrap().is_empty());
+        store.mset("t7", vec![]).unwrap();
+    }
+
+    #[test]
+    pub fn memtable_set_if_should_work() {
+        let store = MemTable::new();
+        test_set_if(store);
+    }
+
+    pub fn test_set_if(store: impl Storage) {
+        let (set, old) = store
+            .set_if("t8", "k1", "v1".into(), SetCondition::IfExists)
+            .unwrap();
+        assert!(!set && old.is_none());
+        assert!(!store.contains("t8", "k1").unwrap());
+
+        let (set, old) = store
+            .set_if("t8", "k1", "v1".into(), SetCondition::IfAbsent)
+            .unwrap();
+        assert!(set && old.is_none());
+        let (set, old) = store
+            .set_if("t8", "k1", "v2".into(), SetCondition::IfAbsent)
+            .unwrap();
+        assert!(!set);
+        assert_eq!(old, Some("v1".into()));
+        assert_eq!(store.get("t8", "k1").unwrap(), Some("v1".into()));
+
+        store.expire("t8", "k1", Duration::from_secs(100)).unwrap();
+        let (set, old) = store
+            .set_if("t8", "k1", "v3".into(), SetCondition::IfExists)
+            .unwrap();
+        assert!(set);
+        assert_eq!(old, Some("v1".into()));
+        assert_eq!(store.get("t8", "k1").unwrap(), Some("v3".into()));
+        assert_eq!(store.ttl("t8", "k1").unwrap(), None);
+
+        // 过期的 key 当作不存在
+        store.expire("t8", "k1", Duration::from_millis(1)).unwrap();
+        std::thread::sleep(Duration::from_millis(5));
+        let (set, _) = store
+            .set_if("t8", "k1", "v4".into(), SetCondition::IfAbsent)
+            .unwrap();
+        assert!(set);
+        assert_eq!(store.get("t8", "k1").unwrap(), Some("v4".into()));
+    }
+
+    #[test]
+    pub fn memtable_version_should_work() {
+        let store = MemTable::new();
+        test_version(store);
+    }
+
+    pub fn test_version(store: impl Storage) {
+        assert_eq!(store.version("t7", "k1").unwrap(), 0);
+        store.set("t7", "k1", "v".into()).unwrap();
+        let v1 = store.version("t7", "k1").unwrap();
+        assert!(v1 > 0);
+        // 读不改变版本号
+        store.get("t7", "k1").unwrap();
+        assert_eq!(store.version("t7", "k1").unwrap(), v1);
+
+        // 各种修改都会改变版本号，删除之后再写入也不会回到之前的版本号
+        store.incr("t7", "k2", 1).unwrap();
+        store.set("t7", "k1", "v2".into()).unwrap();
+        let v2 = store.version("t7", "k1").unwrap();
+        assert!(v2 > v1);
+        store.del("t7", "k1").unwrap();
+        let deleted = store.version("t7", "k1").unwrap();
+        assert!(deleted > v2);
+        // 删除之后没有修改，版本号不变
+        assert_eq!(store.version("t7", "k1").unwrap(), deleted);
+        store.set("t7", "k1", "v2".into()).unwrap();
+        assert!(store.version("t7", "k1").unwrap() > deleted);
+
+        // 过期也算修改
+        let v3 = store.version("t7", "k2").unwrap();
+        store.expire("t7", "k2", Duration::from_millis(10)).unwrap();
+        let v4 = store.version("t7", "k2").unwrap();
+        assert!(v4 > v3);
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(store.version("t7", "k2").unwrap() > v4);
+    }
+
+    pub fn test_incr(store: impl Storage) {
+        // key 不存在时从 0 开始
+        assert_eq!(store.incr("t5", "k1", 5).unwrap(), 5);
+        assert_eq!(store.incr("t5", "k1", -7).unwrap(), -2);
+        assert_eq!(
+            store.get("t5", "k1").unwrap().unwrap().to_integer(),
+            Some(-2)
+        );
+
+        // 字符串形式的数字也可以累加
+        store.set("t5", "k2", "10".into()).unwrap();
+        assert_eq!(store.incr("t5", "k2", 1).unwrap(), 11);
+
+        // 不是数字，或者溢出时报错，值保持不变
+        store.set("t5", "k3", "abc".into()).unwrap();
+        assert!(store.incr("t5", "k3", 1).is_err());
+        assert_eq!(store.get("t5", "k3").unwrap(), Some("abc".into()));
+        assert!(store.incr("t5", "k1", i64::MIN).is_err());
+        assert_eq!(store.incr("t5", "k1", 0).unwrap(), -2);
+
+        // 过期时间保持不变
+        store.expire("t5", "k2", Duration::from_secs(100)).unwrap();
+        assert_eq!(store.incr("t5", "k2", 1).unwrap(), 12);
+        assert!(store.ttl("t5", "k2").unwrap().is_some());
+    }
+
+    pub fn test_compare_and_swap(store: impl Storage) {
+        // key 不存在时，只有 expected 为 None 才能写入
+        let v = store.compare_and_swap("t4", "k1", Some("v0".into()), "v1".into());
+        assert_eq!(v.unwrap(), (false, None));
+        let v = store.compare_and_swap("t4", "k1", None, "v1".into());
+        assert_eq!(v.unwrap(), (true, Some("v1".into())));
+
+        // 当前值不匹配时不写入，返回当前值
+        let v = store.compare_and_swap("t4", "k1", None, "v2".into());
+        assert_eq!(v.unwrap(), (false, Some("v1".into())));
+        let v = store.compare_and_swap("t4", "k1", Some("v0".into()), "v2".into());
+        assert_eq!(v.unwrap(), (false, Some("v1".into())));
+
+        let v = store.compare_and_swap("t4", "k1", Some("v1".into()), "v2".into());
+        assert_eq!(v.unwrap(), (true, Some("v2".into())));
+        assert_eq!(store.get("t4", "k1").unwrap(), Some("v2".into()));
+
+        // 已过期的 key 当作不存在，写入之后过期时间被清除
+        store.expire("t4", "k1", Duration::ZERO).unwrap();
+        let v = store.compare_and_swap("t4", "k1", None, "v3".into());
+        assert_eq!(v.unwrap(), (true, Some("v3".into())));
+        assert_eq!(store.ttl("t4", "k1").unwrap(), None);
+    }
+
     pub fn test_get_all(store: impl Storage) {
         store.set("t2", "k1", "v1".into()).unwrap();
         store.set("t2", "k2", "v2".into()).unwrap();
@@ -129,6 +1136,41 @@ mod tests {
         )
     }
 
+    pub fn test_expiration(store: impl Storage) {
+        store.set("t3", "k1", "v1".into()).unwrap();
+        store.set("t3", "k2", "v2".into()).unwrap();
+        store.set("t3", "k3", "v3".into()).unwrap();
+
+        // 不存在的 key 不能设置过期时间
+        assert!(!store.expire("t3", "k4", Duration::from_secs(1)).unwrap());
+
+        // 没有设置过期时间的 key，ttl 为 None
+        assert_eq!(store.ttl("t3", "k1").unwrap(), None);
+
+        assert!(store.expire("t3", "k1", Duration::ZERO).unwrap());
+        assert!(store.expire("t3", "k2", Duration::from_secs(100)).unwrap());
+        assert!(store.expire("t3", "k3", Duration::ZERO).unwrap());
+        assert!(store.ttl("t3", "k2").unwrap().unwrap() > Duration::from_secs(90));
+
+        // 惰性过期：过期的 key 读不到
+        assert_eq!(store.get("t3", "k1").unwrap(), None);
+        assert!(!store.contains("t3", "k1").unwrap());
+        assert_eq!(
+            store.get_all("t3").unwrap(),
+            vec![Kvpair::new("k2", "v2".into())]
+        );
+
+        // 重新 set 会清除过期时间
+        store.set("t3", "k2", "v2".into()).unwrap();
+        assert_eq!(store.ttl("t3", "k2").unwrap(), None);
+
+        // 后台清理
+        store.set("t3", "k4", "v4".into()).unwrap();
+        store.expire("t3", "k4", Duration::ZERO).unwrap();
+        assert_eq!(store.purge_expired().unwrap(), 1);
+        assert_eq!(store.purge_expired().unwrap(), 0);
+    }
+
     pub fn test_basic_interface(store: impl Storage) {
         // 第一次 set 会创建 table，插入 key 并返回 None（之前没值）
         let v = store.set("t1", "hello", "world".into());
@@ -0,0 +1,972 @@
+//! 基于有序 kv 引擎（LMDB、redb、SQLite）的 Storage 实现。
+//!
+//! 引擎只需要提供读事务、写事务，以及按 key 的字节序读取一个 key 空间。数据的布局和 SledDB 一样：
+//! 过期时间、版本号、sorted set 和索引各自放在一个 key 空间里，但是和数据在同一个写事务里修改，
+//! 不会出现 SledDB 中数据已经写入、版本号和索引还没有更新的窗口
+
+use std::{
+    collections::BTreeSet,
+    iter,
+    ops::{Bound, RangeBounds},
+    time::Duration,
+};
+
+use bytes::Bytes;
+use prost::Message;
+use sled::IVec;
+use tracing::warn;
+
+use super::{
+    incr_value,
+    index::{check_spec, index_term},
+    is_empty_range, now_millis,
+    sled_db::{
+        as_u32, as_u64, get_term_prefix, index_entry_key, parse_index_key, to_value, zset_index_key,
+    },
+    zset::{check_score, from_score_key, rank_range, score_key},
+    KeyVersion, Storage, TableStats,
+};
+use crate::{
+    error::KvError,
+    pb::abi::{wal_record::Op, IndexSpec, Kvpair, ScoredMember, Value, WalRecord},
+};
+
+/// get_iter 和 scan_prefix 每次从引擎中读取的 kv pair 数量，读完一批再开始下一个读事务
+const PAGE_SIZE: usize = 1024;
+
+/// 引擎中的一个 key 空间
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Space<'a> {
+    /// table 中的数据，key 就是 table 里的 key
+    Data(&'a str),
+    /// 元数据，key 以 table 的名字和一个 0 开头
+    Meta(Tree),
+}
+
+/// 存放元数据的 key 空间
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Tree {
+    /// <full key, 过期时刻的毫秒时间戳>
+    Expirations,
+    /// <full key, 版本号>
+    Versions,
+    /// sorted set 中 member 的 score，<zset prefix + member, score>
+    ZScores,
+    /// 按 score 排序的 sorted set，<zset prefix + score + member, 空>
+    ZSets,
+    /// 索引的定义，<`table\0` + 索引名, prost 编码的 IndexSpec>
+    IndexSpecs,
+    /// 索引的数据，<index prefix + term 的长度 + term + key, 空>
+    Index,
+    /// key 当前被索引的 term，<index prefix + key, term>
+    IndexTerms,
+}
+
+impl Tree {
+    pub const ALL: [Tree; 7] = [
+        Tree::Expirations,
+        Tree::Versions,
+        Tree::ZScores,
+        Tree::ZSets,
+        Tree::IndexSpecs,
+        Tree::Index,
+        Tree::IndexTerms,
+    ];
+
+    /// 和 SledDB 中对应的 tree 同名
+    pub fn name(self) -> &'static str {
+        match self {
+            Tree::Expirations => "__expirations__",
+            Tree::Versions => "__versions__",
+            Tree::ZScores => "__zscores__",
+            Tree::ZSets => "__zsets__",
+            Tree::IndexSpecs => "__index_specs__",
+            Tree::Index => "__index__",
+            Tree::IndexTerms => "__index_terms__",
+        }
+    }
+}
+
+/// 按 key 的字节序排列的一组 <key, value>
+pub type Entries = Vec<(Vec<u8>, Vec<u8>)>;
+
+/// 读事务，看到的是事务开始时的一致的快照
+pub trait ReadTxn {
+    fn get(&self, space: Space, key: &[u8]) -> Result<Option<Vec<u8>>, KvError>;
+
+    /// 按 key 的字节序返回 space 中 key 在 start 和 end 之间的最多 limit 个 kv pair；start 不会大于 end
+    fn range(
+        &self,
+        space: Space,
+        start: Bound<&[u8]>,
+        end: Bound<&[u8]>,
+        limit: usize,
+    ) -> Result<Entries, KvError>;
+
+    /// 所有有数据的 table
+    fn tables(&self) -> Result<Vec<String>, KvError>;
+}
+
+/// 写事务，同一时刻只有一个；写事务里也可以读到之前写入的数据
+pub trait WriteTxn: ReadTxn {
+    fn put(&mut self, space: Space, key: &[u8], value: &[u8]) -> Result<(), KvError>;
+
+    /// 删除 key，返回 key 之前是否存在
+    fn delete(&mut self, space: Space, key: &[u8]) -> Result<bool, KvError>;
+}
+
+/// 支持事务的有序 kv 引擎
+pub trait Engine: Send + Sync + 'static {
+    /// 存储引擎的名字，INFO 中显示
+    const NAME: &'static str;
+
+    fn read<T>(&self, f: impl FnOnce(&dyn ReadTxn) -> Result<T, KvError>) -> Result<T, KvError>;
+
+    /// f 返回 Ok 时提交事务，返回错误时回滚，什么都不修改
+    fn write<T>(
+        &self,
+        f: impl FnOnce(&mut dyn WriteTxn) -> Result<T, KvError>,
+    ) -> Result<T, KvError>;
+
+    fn flush(&self) -> Result<(), KvError> {
+        Ok(())
+    }
+
+    fn compact(&self) -> Result<u64, KvError> {
+        Ok(0)
+    }
+
+    fn disk_usage(&self) -> Result<Option<u64>, KvError> {
+        Ok(None)
+    }
+}
+
+/// 在 Engine 上实现 Storage，每个操作都在一个事务里完成
+pub struct OrderedStore<E> {
+    engine: E,
+}
+
+impl<E: Engine> OrderedStore<E> {
+    pub fn new(engine: E) -> Self {
+        Self { engine }
+    }
+
+    /// 读取 key 的值；key 已经过期时在写事务里把它删除，返回 None
+    fn get_live(&self, table: &str, key: &[u8]) -> Result<Option<Vec<u8>>, KvError> {
+        let full_key = full_key(table, key);
+        let (value, expired) = self.engine.read(|tx| {
+            let value = tx.get(Space::Data(table), key)?;
+            Ok((value, is_expired(tx, &full_key)?))
+        })?;
+        if expired {
+            self.engine.write(|tx| remove_if_expired(tx, table, key))?;
+            return Ok(None);
+        }
+        Ok(value)
+    }
+
+    /// 删除这些 full key 中已经过期的 key，返回删除的数量
+    fn remove_expired(&self, full_keys: Vec<Vec<u8>>) -> Result<usize, KvError> {
+        if full_keys.is_empty() {
+            return Ok(0);
+        }
+        self.engine.write(|tx| {
+            let mut count = 0;
+            for full_key in &full_keys {
+                let Some((table, key)) = split_full_key(full_key) else {
+                    continue;
+                };
+                if remove_if_expired(tx, &table, key)? {
+                    count += 1;
+                }
+            }
+            Ok(count)
+        })
+    }
+
+    /// 以 prefix 开头的 full key 中已经过期的 key
+    fn expired_in(&self, prefix: &[u8]) -> Result<Vec<Vec<u8>>, KvError> {
+        let now = now_millis();
+        let (start, end) = prefix_range(prefix);
+        let entries = self.engine.read(|tx| {
+            let space = Space::Meta(Tree::Expirations);
+            tx.range(space, borrowed(&start), borrowed(&end), usize::MAX)
+        })?;
+        let expired = entries
+            .into_iter()
+            .filter(|(_, deadline)| as_u64(deadline) <= now)
+            .map(|(key, _)| key)
+            .collect();
+        Ok(expired)
+    }
+
+    /// 遍历 table 前先清理掉其中已经过期的 key
+    fn remove_expired_in(&self, table: &str) -> Result<usize, KvError> {
+        self.remove_expired(self.expired_in(&table_prefix(table))?)
+    }
+
+    /// 一个读事务里读出 table 中 key 在 start 和 end 之间的最多 limit 个 kv pair
+    fn range_limit(
+        &self,
+        table: &str,
+        start: &Bound<Vec<u8>>,
+        end: &Bound<Vec<u8>>,
+        limit: usize,
+    ) -> Result<Vec<Kvpair>, KvError> {
+        let entries = self
+            .engine
+            .read(|tx| tx.range(Space::Data(table), borrowed(start), borrowed(end), limit))?;
+        Ok(entries
+            .into_iter()
+            .map(|(key, value)| Kvpair::new(key, to_value(&value)))
+            .collect())
+    }
+
+    /// 按 key 的顺序分批读取 table，每一批在一个读事务里，不会长时间占着事务
+    fn pages(
+        &self,
+        table: String,
+        start: Bound<Vec<u8>>,
+        end: Bound<Vec<u8>>,
+    ) -> impl Iterator<Item = Kvpair> + '_ {
+        let (mut start, mut done) = (start, false);
+        let mut page = Vec::new().into_iter();
+        iter::from_fn(move || loop {
+            if let Some(pair) = page.next() {
+                return Some(pair);
+            }
+            if done {
+                return None;
+            }
+            let pairs = match self.range_limit(&table, &start, &end, PAGE_SIZE) {
+                Ok(pairs) => pairs,
+                Err(e) => {
+                    warn!("Failed to read table {}: {:?}", table, e);
+                    return None;
+                }
+            };
+            done = pairs.len() < PAGE_SIZE;
+            if let Some(last) = pairs.last() {
+                start = Bound::Excluded(last.key.to_vec());
+            }
+            page = pairs.into_iter();
+        })
+    }
+
+    /// 删除 table 中所有的 key 和 sorted set，drop 时连同 table 上的索引一起删除，返回删除的 key 数
+    fn clear_table(&self, table: &str, drop: bool) -> Result<usize, KvError> {
+        self.engine.write(|tx| {
+            let prefix = table_prefix(table);
+            if drop {
+                // 先删除索引的定义，这样之后删除 key 时不需要再逐个更新索引
+                for tree in [Tree::IndexSpecs, Tree::Index, Tree::IndexTerms] {
+                    remove_prefix(tx, Space::Meta(tree), &prefix)?;
+                }
+            }
+            let keys = tx.range(
+                Space::Data(table),
+                Bound::Unbounded,
+                Bound::Unbounded,
+                usize::MAX,
+            )?;
+            let mut count = 0;
+            for (key, _) in keys {
+                if !remove_if_expired(tx, table, &key)? && remove(tx, table, &key)?.is_some() {
+                    count += 1;
+                }
+            }
+            let zsets = zsets_of(tx)?.iter().filter(|(t, _)| t == table).count();
+            remove_prefix(tx, Space::Meta(Tree::ZScores), &prefix)?;
+            remove_prefix(tx, Space::Meta(Tree::ZSets), &prefix)?;
+            Ok(count + zsets)
+        })
+    }
+}
+
+/// 元数据中 key 的前缀是 table 的名字加上一个 0；table 的名字里没有 0，
+/// 一个 table 的名字是另一个的前缀时也不会混在一起
+pub(super) fn table_prefix(table: &str) -> Vec<u8> {
+    let mut prefix = table.as_bytes().to_vec();
+    prefix.push(0);
+    prefix
+}
+
+/// 元数据中 key 对应的 full key，key 原样拼在 table prefix 后面
+pub(super) fn full_key(table: &str, key: &[u8]) -> Vec<u8> {
+    let mut full_key = table_prefix(table);
+    full_key.extend_from_slice(key);
+    full_key
+}
+
+/// full key 的逆操作，返回 (table, key)
+pub(super) fn split_full_key(full_key: &[u8]) -> Option<(String, &[u8])> {
+    let end = full_key.iter().position(|c| *c == 0)?;
+    let table = String::from_utf8_lossy(&full_key[..end]).into_owned();
+    Some((table, &full_key[end + 1..]))
+}
+
+/// sorted set 和索引的前缀是 table prefix 加上 name 的长度和 name，
+/// 这样一个 name 是另一个 name 的前缀时也不会混在一起
+fn length_prefixed(table: &str, name: &[u8]) -> Vec<u8> {
+    let mut prefix = table_prefix(table);
+    prefix.extend_from_slice(&(name.len() as u32).to_be_bytes());
+    prefix.extend_from_slice(name);
+    prefix
+}
+
+/// 以 prefix 开头的所有 key 之后的第一个 key，prefix 全是 0xff 时没有上界
+pub(super) fn prefix_end(prefix: &[u8]) -> Bound<Vec<u8>> {
+    let mut end = prefix.to_vec();
+    while let Some(last) = end.pop() {
+        if last < 0xff {
+            end.push(last + 1);
+            return Bound::Excluded(end);
+        }
+    }
+    Bound::Unbounded
+}
+
+/// 以 prefix 开头的所有 key；LMDB 不接受空的 key，prefix 为空时两端都没有边界
+fn prefix_range(prefix: &[u8]) -> (Bound<Vec<u8>>, Bound<Vec<u8>>) {
+    match prefix.is_empty() {
+        true => (Bound::Unbounded, Bound::Unbounded),
+        false => (Bound::Included(prefix.to_vec()), prefix_end(prefix)),
+    }
+}
+
+pub(super) fn borrowed(bound: &Bound<Vec<u8>>) -> Bound<&[u8]> {
+    bound.as_ref().map(Vec::as_slice)
+}
+
+fn is_expired(tx: &dyn ReadTxn, full_key: &[u8]) -> Result<bool, KvError> {
+    let deadline = tx.get(Space::Meta(Tree::Expirations), full_key)?;
+    Ok(deadline.is_some_and(|deadline| as_u64(&deadline) <= now_millis()))
+}
+
+/// 惰性过期：如果 key 已经过期，就把它删除，返回 true
+fn remove_if_expired(tx: &mut dyn WriteTxn, table: &str, key: &[u8]) -> Result<bool, KvError> {
+    let full_key = full_key(table, key);
+    if !is_expired(tx, &full_key)? {
+        return Ok(false);
+    }
+    tx.delete(Space::Meta(Tree::Expirations), &full_key)?;
+    tx.delete(Space::Data(table), key)?;
+    modified(tx, table, key, None)?;
+    Ok(true)
+}
+
+/// 写入 key 的值，不改变过期时间
+fn put_value(tx: &mut dyn WriteTxn, table: &str, key: &[u8], value: &Value) -> Result<(), KvError> {
+    tx.put(Space::Data(table), key, &IVec::from(value.clone()))?;
+    modified(tx, table, key, Some(value))
+}
+
+/// 删除 key 和它的过期时间，返回之前的值
+fn remove(tx: &mut dyn WriteTxn, table: &str, key: &[u8]) -> Result<Option<Vec<u8>>, KvError> {
+    tx.delete(Space::Meta(Tree::Expirations), &full_key(table, key))?;
+    let old = tx.get(Space::Data(table), key)?;
+    if old.is_some() {
+        tx.delete(Space::Data(table), key)?;
+        modified(tx, table, key, None)?;
+    }
+    Ok(old)
+}
+
+/// key 被修改了，版本号加一，并且更新索引；value 为 None 表示 key 被删除了
+fn modified(
+    tx: &mut dyn WriteTxn,
+    table: &str,
+    key: &[u8],
+    value: Option<&Value>,
+) -> Result<(), KvError> {
+    bump_version(tx, &full_key(table, key))?;
+    reindex(tx, table, key, value)
+}
+
+fn bump_version(tx: &mut dyn WriteTxn, full_key: &[u8]) -> Result<(), KvError> {
+    let space = Space::Meta(Tree::Versions);
+    let version = tx.get(space, full_key)?.map_or(0, |v| as_u64(&v));
+    tx.put(space, full_key, &(version + 1).to_be_bytes())
+}
+
+/// table 上所有的索引
+fn specs_of(tx: &dyn ReadTxn, table: &str) -> Result<Vec<IndexSpec>, KvError> {
+    let (start, end) = prefix_range(&table_prefix(table));
+    let entries = tx.range(
+        Space::Meta(Tree::IndexSpecs),
+        borrowed(&start),
+        borrowed(&end),
+        usize::MAX,
+    )?;
+    entries
+        .iter()
+        .map(|(_, spec)| Ok(IndexSpec::decode(&spec[..])?))
+        .collect()
+}
+
+/// 按 key 新的值更新 table 上所有的索引
+fn reindex(
+    tx: &mut dyn WriteTxn,
+    table: &str,
+    key: &[u8],
+    value: Option<&Value>,
+) -> Result<(), KvError> {
+    for spec in specs_of(tx, table)? {
+        let prefix = length_prefixed(table, spec.name.as_bytes());
+        let term = value.and_then(|v| index_term(v, &spec.field));
+        let term_key = [&prefix[..], key].concat();
+        let old = tx.get(Space::Meta(Tree::IndexTerms), &term_key)?;
+        if old == term {
+            continue;
+        }
+        if let Some(old) = old {
+            tx.delete(
+                Space::Meta(Tree::Index),
+                &index_entry_key(&prefix, &old, key),
+            )?;
+        }
+        match term {
+            Some(term) => {
+                let entry = index_entry_key(&prefix, &term, key);
+                tx.put(Space::Meta(Tree::Index), &entry, &[])?;
+                tx.put(Space::Meta(Tree::IndexTerms), &term_key, &term)?;
+            }
+            None => {
+                tx.delete(Space::Meta(Tree::IndexTerms), &term_key)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// 删除 space 中以 prefix 开头的所有 key
+fn remove_prefix(tx: &mut dyn WriteTxn, space: Space, prefix: &[u8]) -> Result<(), KvError> {
+    let (start, end) = prefix_range(prefix);
+    for (key, _) in tx.range(space, borrowed(&start), borrowed(&end), usize::MAX)? {
+        tx.delete(space, &key)?;
+    }
+    Ok(())
+}
+
+/// 从 zset prefix 中解析出所有 sorted set 的 table 和 key
+fn zsets_of(tx: &dyn ReadTxn) -> Result<BTreeSet<(String, Bytes)>, KvError> {
+    let space = Space::Meta(Tree::ZScores);
+    let mut zsets = BTreeSet::new();
+    for (key, _) in tx.range(space, Bound::Unbounded, Bound::Unbounded, usize::MAX)? {
+        let Some((table, rest)) = split_full_key(&key) else {
+            continue;
+        };
+        let len = as_u32(rest) as usize;
+        if let Some(zset) = rest.get(4..4 + len) {
+            zsets.insert((table, Bytes::copy_from_slice(zset)));
+        }
+    }
+    Ok(zsets)
+}
+
+/// 在写事务里应用 SET/DEL/EXPIRE 记录
+fn apply_records(tx: &mut dyn WriteTxn, records: &[WalRecord]) -> Result<(), KvError> {
+    for record in records {
+        let (table, key) = (record.table.as_str(), &record.key[..]);
+        match &record.op {
+            Some(Op::Set(value)) => {
+                tx.delete(Space::Meta(Tree::Expirations), &full_key(table, key))?;
+                put_value(tx, table, key, value)?;
+            }
+            Some(Op::Del(_)) => {
+                remove(tx, table, key)?;
+            }
+            Some(Op::Expire(deadline)) => {
+                // 和 expire 一样，不存在的 key 不设置过期时间
+                if remove_if_expired(tx, table, key)? || tx.get(Space::Data(table), key)?.is_none()
+                {
+                    continue;
+                }
+                let full_key = full_key(table, key);
+                let space = Space::Meta(Tree::Expirations);
+                tx.put(space, &full_key, &deadline.to_be_bytes())?;
+                bump_version(tx, &full_key)?;
+            }
+            Some(Op::Batch(batch)) => apply_records(tx, &batch.records)?,
+            // 和 SledDB 一样，batch 里不会有 ZADD 和 CREATEINDEX 这些记录
+            Some(Op::Zadd(_) | Op::CreateIndex(_) | Op::TruncateTable(_) | Op::DropTable(_))
+            | None => {}
+        }
+    }
+    Ok(())
+}
+
+impl<E: Engine> Storage for OrderedStore<E> {
+    fn get(
+        &self,
+        table: impl Into<String>,
+        key: impl AsRef<[u8]>,
+    ) -> Result<Option<Value>, KvError> {
+        let value = self.get_live(&table.into(), key.as_ref())?;
+        Ok(value.map(|v| to_value(&v)))
+    }
+
+    fn set(
+        &self,
+        table: impl Into<String>,
+        key: impl AsRef<[u8]>,
+        value: Value,
+    ) -> Result<Option<Value>, KvError> {
+        let (table, key) = (table.into(), key.as_ref());
+        self.engine.write(|tx| {
+            remove_if_expired(tx, &table, key)?;
+            let old = tx.get(Space::Data(&table), key)?;
+            tx.delete(Space::Meta(Tree::Expirations), &full_key(&table, key))?;
+            put_value(tx, &table, key, &value)?;
+            Ok(old.map(|v| to_value(&v)))
+        })
+    }
+
+    fn compare_and_swap(
+        &self,
+        table: impl Into<String>,
+        key: impl AsRef<[u8]>,
+        expected: Option<Value>,
+        value: Value,
+    ) -> Result<(bool, Option<Value>), KvError> {
+        let (table, key) = (table.into(), key.as_ref());
+        let expected = expected.map(IVec::from);
+        self.engine.write(|tx| {
+            remove_if_expired(tx, &table, key)?;
+            let current = tx.get(Space::Data(&table), key)?;
+            if current.as_deref() != expected.as_deref() {
+                return Ok((false, current.map(|v| to_value(&v))));
+            }
+            tx.delete(Space::Meta(Tree::Expirations), &full_key(&table, key))?;
+            put_value(tx, &table, key, &value)?;
+            Ok((true, Some(value)))
+        })
+    }
+
+    fn incr(
+        &self,
+        table: impl Into<String>,
+        key: impl AsRef<[u8]>,
+        delta: i64,
+    ) -> Result<i64, KvError> {
+        let (table, key) = (table.into(), key.as_ref());
+        self.engine.write(|tx| {
+            remove_if_expired(tx, &table, key)?;
+            let current = tx.get(Space::Data(&table), key)?.map(|v| to_value(&v));
+            let value = incr_value(key, current.as_ref(), delta)?;
+            put_value(tx, &table, key, &value.into())?;
+            Ok(value)
+        })
+    }
+
+    /// 读取和写入在同一个写事务里，f 只会被调用一次
+    fn update<T>(
+        &self,
+        table: impl Into<String>,
+        key: impl AsRef<[u8]>,
+        mut f: impl FnMut(Option<&Value>) -> Result<(Value, T), KvError>,
+    ) -> Result<T, KvError> {
+        let (table, key) = (table.into(), key.as_ref());
+        self.engine.write(|tx| {
+            remove_if_expired(tx, &table, key)?;
+            let current = tx.get(Space::Data(&table), key)?.map(|v| to_value(&v));
+            let (value, result) = f(current.as_ref())?;
+            put_value(tx, &table, key, &value)?;
+            Ok(result)
+        })
+    }
+
+    fn contains(&self, table: impl Into<String>, key: impl AsRef<[u8]>) -> Result<bool, KvError> {
+        Ok(self.get_live(&table.into(), key.as_ref())?.is_some())
+    }
+
+    fn del(
+        &self,
+        table: impl Into<String>,
+        key: impl AsRef<[u8]>,
+    ) -> Result<Option<Value>, KvError> {
+        let (table, key) = (table.into(), key.as_ref());
+        self.engine.write(|tx| {
+            remove_if_expired(tx, &table, key)?;
+            let old = remove(tx, &table, key)?;
+            Ok(old.map(|v| to_value(&v)))
+        })
+    }
+
+    fn get_all(&self, table: impl Into<String>) -> Result<Vec<Kvpair>, KvError> {
+        Ok(self.get_iter(table)?.collect())
+    }
+
+    fn get_iter(&self, table: impl Into<String>) -> Result<impl Iterator<Item = Kvpair>, KvError> {
+        let table = table.into();
+        self.remove_expired_in(&table)?;
+        Ok(self.pages(table, Bound::Unbounded, Bound::Unbounded))
+    }
+
+    fn scan_prefix(
+        &self,
+        table: impl Into<String>,
+        prefix: impl AsRef<[u8]>,
+    ) -> Result<impl Iterator<Item = Kvpair>, KvError> {
+        let table = table.into();
+        self.remove_expired_in(&table)?;
+        let (start, end) = prefix_range(prefix.as_ref());
+        Ok(self.pages(table, start, end))
+    }
+
+    fn range(
+        &self,
+        table: impl Into<String>,
+        range: impl RangeBounds<Bytes>,
+    ) -> Result<Vec<Kvpair>, KvError> {
+        let table = table.into();
+        self.remove_expired_in(&table)?;
+        if is_empty_range(&range) {
+            return Ok(vec![]);
+        }
+        let start = range.start_bound().map(|key| key.to_vec());
+        let end = range.end_bound().map(|key| key.to_vec());
+        self.range_limit(&table, &start, &end, usize::MAX)
+    }
+
+    fn scan_after(
+        &self,
+        table: impl Into<String>,
+        start: Option<Bytes>,
+        limit: usize,
+    ) -> Result<Vec<Kvpair>, KvError> {
+        let table = table.into();
+        self.remove_expired_in(&table)?;
+        let start = start.map_or(Bound::Unbounded, |key| Bound::Excluded(key.to_vec()));
+        self.range_limit(&table, &start, &Bound::Unbounded, limit)
+    }
+
+    fn expire(
+        &self,
+        table: impl Into<String>,
+        key: impl AsRef<[u8]>,
+        ttl: Duration,
+    ) -> Result<bool, KvError> {
+        let (table, key) = (table.into(), key.as_ref());
+        self.engine.write(|tx| {
+            if remove_if_expired(tx, &table, key)? || tx.get(Space::Data(&table), key)?.is_none() {
+                return Ok(false);
+            }
+            let deadline = now_millis() + ttl.as_millis() as u64;
+            let full_key = full_key(&table, key);
+            let space = Space::Meta(Tree::Expirations);
+            tx.put(space, &full_key, &deadline.to_be_bytes())?;
+            bump_version(tx, &full_key)?;
+            Ok(true)
+        })
+    }
+
+    fn ttl(
+        &self,
+        table: impl Into<String>,
+        key: impl AsRef<[u8]>,
+    ) -> Result<Option<Duration>, KvError> {
+        let (table, key) = (table.into(), key.as_ref());
+        let full_key = full_key(&table, key);
+        let deadline = self
+            .engine
+            .read(|tx| tx.get(Space::Meta(Tree::Expirations), &full_key))?;
+        let now = now_millis();
+        match deadline.map(|deadline| as_u64(&deadline)) {
+            Some(deadline) if deadline <= now => {
+                self.engine.write(|tx| remove_if_expired(tx, &table, key))?;
+                Ok(None)
+            }
+            deadline => Ok(deadline.map(|deadline| Duration::from_millis(deadline - now))),
+        }
+    }
+
+    fn purge_expired(&self) -> Result<usize, KvError> {
+        self.remove_expired(self.expired_in(&[])?)
+    }
+
+    fn tables(&self) -> Result<Vec<String>, KvError> {
+        self.engine.read(|tx| tx.tables())
+    }
+
+    fn backend(&self) -> &'static str {
+        E::NAME
+    }
+
+    fn stats(&self) -> Result<Vec<(String, TableStats)>, KvError> {
+        self.purge_expired()?;
+        let mut stats = Vec::new();
+        for table in self.tables()? {
+            let (mut entry, mut start) = (TableStats::default(), Bound::Unbounded);
+            loop {
+                let entries = self.engine.read(|tx| {
+                    let space = Space::Data(&table);
+                    tx.range(space, borrowed(&start), Bound::Unbounded, PAGE_SIZE)
+                })?;
+                for (key, value) in &entries {
+                    entry.keys += 1;
+                    entry.bytes += (key.len() + value.len()) as u64;
+                }
+                match entries.last() {
+                    Some((key, _)) if entries.len() == PAGE_SIZE => {
+                        start = Bound::Excluded(key.clone())
+                    }
+                    _ => break,
+                }
+            }
+            if entry.keys > 0 {
+                stats.push((table, entry));
+            }
+        }
+        Ok(stats)
+    }
+
+    fn truncate_table(&self, table: impl Into<String>) -> Result<usize, KvError> {
+        self.clear_table(&table.into(), false)
+    }
+
+    fn drop_table(&self, table: impl Into<String>) -> Result<usize, KvError> {
+        self.clear_table(&table.into(), true)
+    }
+
+    fn version(&self, table: impl Into<String>, key: impl AsRef<[u8]>) -> Result<u64, KvError> {
+        let (table, key) = (table.into(), key.as_ref());
+        let full_key = full_key(&table, key);
+        let get_version = |tx: &dyn ReadTxn| {
+            let version = tx.get(Space::Meta(Tree::Versions), &full_key)?;
+            Ok(version.map_or(0, |v| as_u64(&v)))
+        };
+        let (version, expired) = self
+            .engine
+            .read(|tx| Ok((get_version(tx)?, is_expired(tx, &full_key)?)))?;
+        if !expired {
+            return Ok(version);
+        }
+        self.engine.write(|tx| {
+            remove_if_expired(tx, &table, key)?;
+            get_version(tx)
+        })
+    }
+
+    /// 检查版本号和修改数据、过期时间、版本号、索引都在同一个写事务里
+    fn apply_batch(
+        &self,
+        records: Vec<WalRecord>,
+        watched: &[KeyVersion],
+    ) -> Result<bool, KvError> {
+        self.engine.write(|tx| {
+            for w in watched {
+                remove_if_expired(tx, &w.table, &w.key)?;
+                let version = tx.get(Space::Meta(Tree::Versions), &full_key(&w.table, &w.key))?;
+                if version.map_or(0, |v| as_u64(&v)) != w.version {
+                    return Ok(false);
+                }
+            }
+            apply_records(tx, &records)?;
+            Ok(true)
+        })
+    }
+
+    fn zadd(
+        &self,
+        table: impl Into<String>,
+        key: impl AsRef<[u8]>,
+        members: Vec<ScoredMember>,
+    ) -> Result<usize, KvError> {
+        members.iter().try_for_each(|m| check_score(m.score))?;
+        let prefix = length_prefixed(&table.into(), key.as_ref());
+        self.engine.write(|tx| {
+            let mut added = 0;
+            for m in &members {
+                let member_key = [&prefix[..], &m.member[..]].concat();
+                match tx.get(Space::Meta(Tree::ZScores), &member_key)? {
+                    Some(old) => {
+                        let old = f64::from_bits(as_u64(&old));
+                        let index_key = zset_index_key(&prefix, old, &m.member);
+                        tx.delete(Space::Meta(Tree::ZSets), &index_key)?;
+                    }
+                    None => added += 1,
+                }
+                let score = m.score.to_bits().to_be_bytes();
+                tx.put(Space::Meta(Tree::ZScores), &member_key, &score)?;
+                let index_key = zset_index_key(&prefix, m.score, &m.member);
+                tx.put(Space::Meta(Tree::ZSets), &index_key, &[])?;
+            }
+            Ok(added)
+        })
+    }
+
+    fn zrange(
+        &self,
+        table: impl Into<String>,
+        key: impl AsRef<[u8]>,
+        start: i64,
+        stop: i64,
+    ) -> Result<Vec<ScoredMember>, KvError> {
+        let prefix = length_prefixed(&table.into(), key.as_ref());
+        let (lower, upper) = prefix_range(&prefix);
+        let (lower, upper) = (borrowed(&lower), borrowed(&upper));
+        self.engine.read(|tx| {
+            let len = tx
+                .range(Space::Meta(Tree::ZScores), lower, upper, usize::MAX)?
+                .len();
+            let (start, end) = rank_range(len, start, stop);
+            if start >= end {
+                return Ok(vec![]);
+            }
+            let entries = tx.range(Space::Meta(Tree::ZSets), lower, upper, end)?;
+            Ok(entries
+                .into_iter()
+                .skip(start)
+                .map(|(key, _)| {
+                    let (score, member) = parse_index_key(prefix.len(), &key);
+                    (member, from_score_key(score)).into()
+                })
+                .collect())
+        })
+    }
+
+    fn zrange_by_score(
+        &self,
+        table: impl Into<String>,
+        key: impl AsRef<[u8]>,
+        min: f64,
+        max: f64,
+    ) -> Result<Vec<ScoredMember>, KvError> {
+        if score_key(min) > score_key(max) {
+            return Ok(vec![]);
+        }
+        let prefix = length_prefixed(&table.into(), key.as_ref());
+        let start = Bound::Included(zset_index_key(&prefix, min, b""));
+        // score 等于 max 的 member 都在下一个 score 之前
+        let end = match score_key(max).checked_add(1) {
+            Some(next) => Bound::Excluded([&prefix[..], &next.to_be_bytes()].concat()),
+            None => prefix_end(&prefix),
+        };
+        let entries = self.engine.read(|tx| {
+            let space = Space::Meta(Tree::ZSets);
+            tx.range(space, borrowed(&start), borrowed(&end), usize::MAX)
+        })?;
+        Ok(entries
+            .into_iter()
+            .map(|(key, _)| {
+                let (score, member) = parse_index_key(prefix.len(), &key);
+                (member, from_score_key(score)).into()
+            })
+            .collect())
+    }
+
+    fn zsets(&self) -> Result<Vec<(String, Bytes)>, KvError> {
+        let zsets = self.engine.read(zsets_of)?;
+        Ok(zsets.into_iter().collect())
+    }
+
+    /// 写入索引的定义和索引已有的 key 在同一个写事务里
+    fn create_index(&self, table: impl Into<String>, spec: IndexSpec) -> Result<bool, KvError> {
+        check_spec(&spec)?;
+        let table = table.into();
+        let spec_key = full_key(&table, spec.name.as_bytes());
+        self.engine.write(|tx| {
+            let space = Space::Meta(Tree::IndexSpecs);
+            if tx.get(space, &spec_key)?.is_some() {
+                return Ok(false);
+            }
+            tx.put(space, &spec_key, &spec.encode_to_vec())?;
+            let data = Space::Data(&table);
+            for (key, value) in tx.range(data, Bound::Unbounded, Bound::Unbounded, usize::MAX)? {
+                reindex(tx, &table, &key, Some(&to_value(&value)))?;
+            }
+            Ok(true)
+        })
+    }
+
+    fn query(
+        &self,
+        table: impl Into<String>,
+        index: &str,
+        value: &Value,
+    ) -> Result<Vec<Kvpair>, KvError> {
+        let table = table.into();
+        let keys = self.engine.read(|tx| {
+            if !specs_of(tx, &table)?.iter().any(|spec| spec.name == index) {
+                return Err(KvError::NotFound(format!("index {}:{}", table, index)));
+            }
+            let Some(term) = index_term(value, &[]) else {
+                return Ok(vec![]);
+            };
+            let prefix = get_term_prefix(&length_prefixed(&table, index.as_bytes()), &term);
+            let (start, end) = prefix_range(&prefix);
+            let entries = tx.range(
+                Space::Meta(Tree::Index),
+                borrowed(&start),
+                borrowed(&end),
+                usize::MAX,
+            )?;
+            Ok(entries
+                .into_iter()
+                .map(|(entry, _)| entry[prefix.len()..].to_vec())
+                .collect::<Vec<_>>())
+        })?;
+        let mut pairs = Vec::new();
+        for key in keys {
+            // 过期的 key 在这里被删除，同时也会从索引中删除
+            if let Some(value) = self.get_live(&table, &key)? {
+                pairs.push(Kvpair::new(key, to_value(&value)));
+            }
+        }
+        Ok(pairs)
+    }
+
+    fn indexes(&self) -> Result<Vec<(String, IndexSpec)>, KvError> {
+        let entries = self.engine.read(|tx| {
+            let space = Space::Meta(Tree::IndexSpecs);
+            tx.range(space, Bound::Unbounded, Bound::Unbounded, usize::MAX)
+        })?;
+        let mut indexes = Vec::new();
+        for (key, spec) in entries {
+            if let Some((table, _)) = split_full_key(&key) {
+                indexes.push((table, IndexSpec::decode(&spec[..])?));
+            }
+        }
+        Ok(indexes)
+    }
+
+    fn is_blocking(&self) -> bool {
+        true
+    }
+
+    fn flush(&self) -> Result<(), KvError> {
+        self.engine.flush()
+    }
+
+    fn compact(&self) -> Result<u64, KvError> {
+        self.engine.compact()
+    }
+
+    fn disk_usage(&self) -> Result<Option<u64>, KvError> {
+        self.engine.disk_usage()
+    }
+}
+
+#[cfg(test)]
+mod ordered_tests {
+    use super::*;
+
+    #[test]
+    fn prefix_end_should_skip_trailing_ff() {
+        assert_eq!(prefix_end(b"ab"), Bound::Excluded(b"ac".to_vec()));
+        assert_eq!(prefix_end(b"a\xff"), Bound::Excluded(b"b".to_vec()));
+        assert_eq!(prefix_end(b"\xff\xff"), Bound::Unbounded);
+        assert_eq!(prefix_end(b""), Bound::Unbounded);
+    }
+
+    #[test]
+    fn full_key_should_keep_tables_apart() {
+        assert_ne!(full_key("a:b", b"c"), full_key("a", b"b:c"));
+        let key = full_key("t1", b"\x00k");
+        assert_eq!(
+            split_full_key(&key),
+            Some(("t1".to_string(), &b"\x00k"[..]))
+        );
+    }
+}
@@ -0,0 +1,226 @@
+use std::{collections::BTreeMap, fmt, sync::RwLock};
+
+use dashmap::DashMap;
+
+use super::TableStats;
+use crate::{
+    config::{parse_size, QuotaConfig},
+    error::KvError,
+    service::tenant::SEPARATOR,
+};
+
+/// 配额作用的范围
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum QuotaScope {
+    Table(String),
+    /// 名字以这个前缀开头的所有 table；租户的 table 都以 `<name>:` 开头
+    Prefix(String),
+}
+
+/// key 的数量和占用空间的上限，0 表示不限制
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Quota {
+    pub max_keys: u64,
+    pub max_bytes: u64,
+}
+
+/// 配额和每个 table 当前的使用量；使用量由存储在每次修改 key 时维护，不需要遍历 table
+#[derive(Debug, Default)]
+pub struct Quotas {
+    limits: RwLock<BTreeMap<QuotaScope, Quota>>,
+    /// <table, key 的数量和占用的空间>
+    usage: DashMap<String, TableStats>,
+}
+
+impl QuotaScope {
+    pub fn contains(&self, table: &str) -> bool {
+        match self {
+            Self::Table(name) => name == table,
+            Self::Prefix(prefix) => table.starts_with(prefix.as_str()),
+        }
+    }
+}
+
+impl fmt::Display for QuotaScope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Table(name) => write!(f, "{}", name),
+            Self::Prefix(prefix) => write!(f, "{}*", prefix),
+        }
+    }
+}
+
+impl TryFrom<&QuotaConfig> for (QuotaScope, Quota) {
+    type Error = KvError;
+
+    fn try_from(config: &QuotaConfig) -> Result<Self, Self::Error> {
+        let scope = match (&config.table, &config.tenant) {
+            (Some(table), None) => QuotaScope::Table(table.clone()),
+            (None, Some(tenant)) => QuotaScope::Prefix(format!("{}{}", tenant, SEPARATOR)),
+            _ => {
+                return Err(KvError::InvalidCommand(
+                    "quota must have exactly one of table and tenant".into(),
+                ))
+            }
+        };
+        let max_bytes = match &config.max_bytes {
+            Some(size) => parse_size(size)? as u64,
+            None => 0,
+        };
+        let quota = Quota {
+            max_keys: config.max_keys.unwrap_or_default(),
+            max_bytes,
+        };
+        Ok((scope, quota))
+    }
+}
+
+/// 检查所有的配额配置，出错时不返回任何配额，这样重新加载配置时不会只应用一部分
+pub fn parse_quotas(config: &[QuotaConfig]) -> Result<BTreeMap<QuotaScope, Quota>, KvError> {
+    config.iter().map(TryFrom::try_from).collect()
+}
+
+impl Quotas {
+    pub fn configure(&self, config: &[QuotaConfig]) -> Result<(), KvError> {
+        self.set_limits(parse_quotas(config)?);
+        Ok(())
+    }
+
+    /// 替换所有的配额；已经超过新配额的 table 不会删除数据，只是拒绝之后增加使用量的写入
+    pub fn set_limits(&self, limits: BTreeMap<QuotaScope, Quota>) {
+        *self.limits.write().unwrap_or_else(|e| e.into_inner()) = limits;
+    }
+
+    /// 范围内所有 table 的使用量
+    pub fn usage(&self, scope: &QuotaScope) -> TableStats {
+        match scope {
+            QuotaScope::Table(table) => self
+                .usage
+                .get(table)
+                .map(|usage| usage.clone())
+                .unwrap_or_default(),
+            QuotaScope::Prefix(_) => self
+                .usage
+                .iter()
+                .filter(|usage| scope.contains(usage.key()))
+                .fold(TableStats::default(), |total, usage| TableStats {
+                    keys: total.keys + usage.keys,
+                    bytes: total.bytes + usage.bytes,
+                }),
+        }
+    }
+
+    /// table 的使用量，以及包含这个 table 的其它配额；table 自己的配额没有配置时为 0
+    pub fn get(&self, table: &str) -> Vec<(QuotaScope, Quota, TableStats)> {
+        let limits = self.limits.read().unwrap_or_else(|e| e.into_inner());
+        let scope = QuotaScope::Table(table.to_owned());
+        let quota = limits.get(&scope).copied().unwrap_or_default();
+        let mut quotas = vec![(scope.clone(), quota, self.usage(&scope))];
+        quotas.extend(
+            limits
+                .iter()
+                .filter(|(s, _)| matches!(s, QuotaScope::Prefix(_)) && s.contains(table))
+                .map(|(s, quota)| (s.clone(), *quota, self.usage(s))),
+        );
+        quotas
+    }
+
+    /// table 增加 keys 个 key 和 bytes 字节之后是否会超过配额；减少使用量的修改总是允许
+    pub fn check(&self, table: &str, keys: i64, bytes: i64) -> Result<(), KvError> {
+        if keys <= 0 && bytes <= 0 {
+            return Ok(());
+        }
+        let limits = self.limits.read().unwrap_or_else(|e| e.into_inner());
+        for (scope, quota) in limits.iter().filter(|(s, _)| s.contains(table)) {
+            let used = self.usage(scope);
+            if quota.max_keys > 0 && keys > 0 && used.keys + keys as u64 > quota.max_keys {
+                return Err(KvError::QuotaExceeded(format!(
+                    "{} has {} keys, max-keys is {}",
+                    scope, used.keys, quota.max_keys
+                )));
+            }
+            if quota.max_bytes > 0 && bytes > 0 && used.bytes + bytes as u64 > quota.max_bytes {
+                return Err(KvError::QuotaExceeded(format!(
+                    "{} uses {} bytes, max-bytes is {}",
+                    scope, used.bytes, quota.max_bytes
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// table 中的 key 被修改了，keys 和 bytes 是使用量的变化
+    pub(crate) fn record(&self, table: &str, keys: i64, bytes: i64) {
+        let mut usage = self.usage.entry(table.to_owned()).or_default();
+        usage.keys = usage.keys.saturating_add_signed(keys);
+        usage.bytes = usage.bytes.saturating_add_signed(bytes);
+    }
+}
+
+impl Clone for Quotas {
+    fn clone(&self) -> Self {
+        let limits = self.limits.read().unwrap_or_else(|e| e.into_inner());
+        Self {
+            limits: RwLock::new(limits.clone()),
+            usage: self.usage.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod quota_tests {
+    use super::*;
+
+    #[test]
+    fn quotas_should_be_checked_against_usage() {
+        let quotas = Quotas::default();
+        let config = [
+            QuotaConfig {
+                table: Some("orders".into()),
+                max_keys: Some(2),
+                ..Default::default()
+            },
+            QuotaConfig {
+                tenant: Some("billing".into()),
+                max_bytes: Some("100B".into()),
+                ..Default::default()
+            },
+        ];
+        quotas.configure(&config).unwrap();
+
+        quotas.record("orders", 2, 50);
+        assert!(matches!(
+            quotas.check("orders", 1, 10),
+            Err(KvError::QuotaExceeded(_))
+        ));
+        // 覆盖已有的 key 不增加 key 的数量
+        assert!(quotas.check("orders", 0, 10).is_ok());
+        assert!(quotas.check("t1", 1, 10).is_ok());
+
+        // 租户的所有 table 一起计算
+        quotas.record("billing:t1", 1, 60);
+        quotas.record("billing:t2", 1, 30);
+        assert!(quotas.check("billing:t2", 0, 20).is_err());
+        assert!(quotas.check("billing:t2", 0, -20).is_ok());
+        quotas.record("billing:t1", -1, -60);
+        assert!(quotas.check("billing:t2", 1, 20).is_ok());
+
+        let res = quotas.get("billing:t2");
+        assert_eq!(res.len(), 2);
+        assert_eq!(res[0].0, QuotaScope::Table("billing:t2".into()));
+        assert_eq!(res[1].0.to_string(), "billing:*");
+        assert_eq!(res[1].1.max_bytes, 100);
+        assert_eq!(res[1].2, TableStats { keys: 1, bytes: 30 });
+    }
+
+    #[test]
+    fn quota_config_should_have_one_scope() {
+        let config = QuotaConfig {
+            table: Some("orders".into()),
+            tenant: Some("billing".into()),
+            ..Default::default()
+        };
+        assert!(parse_quotas(&[config]).is_err());
+        assert!(parse_quotas(&[QuotaConfig::default()]).is_err());
+    }
+}
@@ -0,0 +1,365 @@
+//! redb 存储：纯 Rust 实现的 B 树，写事务提交时落盘，不需要 sled 那样的后台线程。
+//!
+//! 每个 table 对应一个名字为 `kv:table` 的 redb table，元数据每个 tree 一个名字为 `meta:tree` 的 table
+
+use std::{ops::Bound, path::Path};
+
+use redb::{
+    Database, ReadTransaction, ReadableDatabase, ReadableTable, ReadableTableMetadata,
+    TableDefinition, TableError, TableHandle, WriteTransaction,
+};
+
+use super::ordered::{Engine, Entries, OrderedStore, ReadTxn, Space, WriteTxn};
+use crate::error::KvError;
+
+/// 存放 table 数据的 redb table 的名字前缀
+const DATA_PREFIX: &str = "kv:";
+
+type Definition<'a> = TableDefinition<'a, &'static [u8], &'static [u8]>;
+
+pub struct RedbEngine {
+    db: Database,
+    path: String,
+}
+
+/// 基于 redb 的 Storage
+pub type Redb = OrderedStore<RedbEngine>;
+
+impl Redb {
+    /// 打开 path 处的数据库文件，不存在时创建
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, KvError> {
+        let path = path.as_ref();
+        let db = Database::create(path)
+            .map_err(|e| KvError::Internal(format!("Failed to open {}: {}", path.display(), e)))?;
+        Ok(OrderedStore::new(RedbEngine {
+            db,
+            path: path.display().to_string(),
+        }))
+    }
+}
+
+fn redb_error(e: impl Into<redb::Error>) -> KvError {
+    KvError::Internal(format!("redb error: {}", e.into()))
+}
+
+/// space 对应的 redb table 的名字
+fn table_name(space: Space) -> String {
+    match space {
+        Space::Data(table) => format!("{}{}", DATA_PREFIX, table),
+        Space::Meta(tree) => format!("meta:{}", tree.name()),
+    }
+}
+
+fn read_range(
+    table: &impl ReadableTable<&'static [u8], &'static [u8]>,
+    start: Bound<&[u8]>,
+    end: Bound<&[u8]>,
+    limit: usize,
+) -> Result<Entries, KvError> {
+    let mut entries = Vec::new();
+    for item in table
+        .range::<&[u8]>((start, end))
+        .map_err(redb_error)?
+        .take(limit)
+    {
+        let (key, value) = item.map_err(redb_error)?;
+        entries.push((key.value().to_vec(), value.value().to_vec()));
+    }
+    Ok(entries)
+}
+
+struct RedbRead(ReadTransaction);
+
+struct RedbWrite<'a>(&'a WriteTransaction);
+
+impl ReadTxn for RedbRead {
+    fn get(&self, space: Space, key: &[u8]) -> Result<Option<Vec<u8>>, KvError> {
+        let name = table_name(space);
+        let table = match self.0.open_table(Definition::new(&name)) {
+            Ok(table) => table,
+            Err(TableError::TableDoesNotExist(_)) => return Ok(None),
+            Err(e) => return Err(redb_error(e)),
+        };
+        let value = table.get(key).map_err(redb_error)?;
+        Ok(value.map(|v| v.value().to_vec()))
+    }
+
+    fn range(
+        &self,
+        space: Space,
+        start: Bound<&[u8]>,
+        end: Bound<&[u8]>,
+        limit: usize,
+    ) -> Result<Entries, KvError> {
+        let name = table_name(space);
+        match self.0.open_table(Definition::new(&name)) {
+            Ok(table) => read_range(&table, start, end, limit),
+            Err(TableError::TableDoesNotExist(_)) => Ok(vec![]),
+            Err(e) => Err(redb_error(e)),
+        }
+    }
+
+    fn tables(&self) -> Result<Vec<String>, KvError> {
+        let mut tables = Vec::new();
+        for handle in self.0.list_tables().map_err(redb_error)? {
+            let Some(table) = handle.name().strip_prefix(DATA_PREFIX) else {
+                continue;
+            };
+            let data = self
+                .0
+                .open_table(Definition::new(handle.name()))
+                .map_err(redb_error)?;
+            // 删光了数据的 table 还留在 redb 中
+            if !data.is_empty().map_err(redb_error)? {
+                tables.push(table.to_string());
+            }
+        }
+        Ok(tables)
+    }
+}
+
+/// 写事务中打开 table 时，不存在的 table 会被创建出来
+impl ReadTxn for RedbWrite<'_> {
+    fn get(&self, space: Space, key: &[u8]) -> Result<Option<Vec<u8>>, KvError> {
+        let name = table_name(space);
+        let table = self
+            .0
+            .open_table(Definition::new(&name))
+            .map_err(redb_error)?;
+        let value = table.get(key).map_err(redb_error)?;
+        Ok(value.map(|v| v.value().to_vec()))
+    }
+
+    fn range(
+        &self,
+        space: Space,
+        start: Bound<&[u8]>,
+        end: Bound<&[u8]>,
+        limit: usize,
+    ) -> Result<Entries, KvError> {
+        let name = table_name(space);
+        let table = self
+            .0
+            .open_table(Definition::new(&name))
+            .map_err(redb_error)?;
+        read_range(&table, start, end, limit)
+    }
+
+    fn tables(&self) -> Result<Vec<String>, KvError> {
+        let mut tables = Vec::new();
+        for handle in self.0.list_tables().map_err(redb_error)? {
+            let Some(table) = handle.name().strip_prefix(DATA_PREFIX) else {
+                continue;
+            };
+            let data = self
+                .0
+                .open_table(Definition::new(handle.name()))
+                .map_err(redb_error)?;
+            if !data.is_empty().map_err(redb_error)? {
+                tables.push(table.to_string());
+            }
+        }
+        Ok(tables)
+    }
+}
+
+impl WriteTxn for RedbWrite<'_> {
+    fn put(&mut self, space: Space, key: &[u8], value: &[u8]) -> Result<(), KvError> {
+        let name = table_name(space);
+        let mut table = self
+            .0
+            .open_table(Definition::new(&name))
+            .map_err(redb_error)?;
+        table.insert(key, value).map_err(redb_error)?;
+        Ok(())
+    }
+
+    fn delete(&mut self, space: Space, key: &[u8]) -> Result<bool, KvError> {
+        let name = table_name(space);
+        let mut table = self
+            .0
+            .open_table(Definition::new(&name))
+            .map_err(redb_error)?;
+        let old = table.remove(key).map_err(redb_error)?;
+        Ok(old.is_some())
+    }
+}
+
+impl Engine for RedbEngine {
+    const NAME: &'static str = "redb";
+
+    fn read<T>(&self, f: impl FnOnce(&dyn ReadTxn) -> Result<T, KvError>) -> Result<T, KvError> {
+        let txn = self.db.begin_read().map_err(redb_error)?;
+        f(&RedbRead(txn))
+    }
+
+    fn write<T>(
+        &self,
+        f: impl FnOnce(&mut dyn WriteTxn) -> Result<T, KvError>,
+    ) -> Result<T, KvError> {
+        let txn = self.db.begin_write().map_err(redb_error)?;
+        match f(&mut RedbWrite(&txn)) {
+            Ok(v) => {
+                txn.commit().map_err(redb_error)?;
+                Ok(v)
+            }
+            Err(e) => {
+                txn.abort().map_err(redb_error)?;
+                Err(e)
+            }
+        }
+    }
+
+    fn disk_usage(&self) -> Result<Option<u64>, KvError> {
+        Ok(Some(std::fs::metadata(&self.path)?.len()))
+    }
+}
+
+#[cfg(test)]
+mod redb_tests {
+    use tempfile::tempdir;
+
+    use super::Redb;
+    use crate::{
+        storage::tests::{
+            test_apply_batch, test_basic_interface, test_binary_keys, test_compare_and_swap,
+            test_expiration, test_get_all, test_get_iter, test_incr, test_index, test_mget_mset,
+            test_range, test_scan_prefix, test_set_if, test_stats, test_table_management,
+            test_update, test_version, test_zset,
+        },
+        Storage,
+    };
+
+    fn open() -> (tempfile::TempDir, Redb) {
+        let dir = tempdir().unwrap();
+        let store = Redb::open(dir.path().join("kv.redb")).unwrap();
+        (dir, store)
+    }
+
+    #[test]
+    fn redb_basic_interface_should_work() {
+        let (_dir, store) = open();
+        test_basic_interface(store);
+    }
+
+    #[test]
+    fn redb_get_all_should_work() {
+        let (_dir, store) = open();
+        test_get_all(store);
+    }
+
+    #[test]
+    fn redb_iter_should_work() {
+        let (_dir, store) = open();
+        test_get_iter(store);
+    }
+
+    #[test]
+    fn redb_expiration_should_work() {
+        let (_dir, store) = open();
+        test_expiration(store);
+    }
+
+    #[test]
+    fn redb_compare_and_swap_should_work() {
+        let (_dir, store) = open();
+        test_compare_and_swap(store);
+    }
+
+    #[test]
+    fn redb_incr_should_work() {
+        let (_dir, store) = open();
+        test_incr(store);
+    }
+
+    #[test]
+    fn redb_update_should_work() {
+        let (_dir, store) = open();
+        test_update(store);
+    }
+
+    #[test]
+    fn redb_binary_keys_should_work() {
+        let (_dir, store) = open();
+        test_binary_keys(store);
+    }
+
+    #[test]
+    fn redb_zset_should_work() {
+        let (_dir, store) = open();
+        test_zset(store);
+    }
+
+    #[test]
+    fn redb_index_should_work() {
+        let (_dir, store) = open();
+        test_index(store);
+    }
+
+    #[test]
+    fn redb_range_should_work() {
+        let (_dir, store) = open();
+        test_range(store);
+    }
+
+    #[test]
+    fn redb_scan_prefix_should_work() {
+        let (_dir, store) = open();
+        test_scan_prefix(store);
+    }
+
+    #[test]
+    fn redb_table_management_should_work() {
+        let (_dir, store) = open();
+        test_table_management(store);
+    }
+
+    #[test]
+    fn redb_stats_should_work() {
+        let (_dir, store) = open();
+        test_stats(store);
+    }
+
+    #[test]
+    fn redb_apply_batch_should_work() {
+        let (_dir, store) = open();
+        test_apply_batch(store);
+    }
+
+    #[test]
+    fn redb_mget_mset_should_work() {
+        let (_dir, store) = open();
+        test_mget_mset(store);
+    }
+
+    #[test]
+    fn redb_set_if_should_work() {
+        let (_dir, store) = open();
+        test_set_if(store);
+    }
+
+    #[test]
+    fn redb_version_should_work() {
+        let (_dir, store) = open();
+        test_version(store);
+    }
+
+    #[test]
+    fn redb_should_keep_data_after_reopen() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("kv.redb");
+        let store = Redb::open(&path).unwrap();
+        store.set("t1", "k1", "v1".into()).unwrap();
+        store.set("t2", "k1", "v1".into()).unwrap();
+        store.del("t2", "k1").unwrap();
+        store.zadd("t3", "z1", vec![("m1", 1.0).into()]).unwrap();
+        drop(store);
+
+        let store = Redb::open(&path).unwrap();
+        assert_eq!(store.get("t1", "k1").unwrap(), Some("v1".into()));
+        // 删空了的 table 不再列出来
+        assert_eq!(store.tables().unwrap(), ["t1"]);
+        assert_eq!(store.zrange("t3", "z1", 0, -1).unwrap().len(), 1);
+        assert_eq!(store.backend(), "redb");
+    }
+}
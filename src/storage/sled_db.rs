@@ -1,11 +1,61 @@
-use super::U8toString;
+use super::{
+    incr_value,
+    index::{check_spec, index_term},
+    is_empty_range, now_millis,
+    zset::{check_score, from_score_key, rank_range, score_key},
+    KeyVersion, TableStats,
+};
 use crate::{
     error::KvError,
-    pb::abi::{value, Kvpair, Value},
+    pb::abi::{value, wal_record::Op, IndexSpec, Kvpair, ScoredMember, Value, WalRecord},
     Storage, StorageIter,
 };
-use sled::{Db, Error, IVec};
-use std::{fmt::Debug, ops::Deref, path::Path};
+use bytes::Bytes;
+use prost::Message;
+use sled::{
+    transaction::{
+        ConflictableTransactionError, ConflictableTransactionResult, TransactionError,
+        TransactionalTree,
+    },
+    Db, Error, IVec, Transactional, Tree,
+};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt::Debug,
+    ops::{Bound, Deref, RangeBounds},
+    path::Path,
+    time::Duration,
+};
+
+/// 存放 key 过期时间的 tree 名字
+const EXPIRATION_TREE: &str = "__expirations__";
+
+/// 存放 key 版本号的 tree 名字，只保存存在的 key
+const VERSION_TREE: &str = "__versions__";
+
+/// 被删除的 key 按 hash 分槽，保存每个槽里最后被删除的 key 的版本号，<槽号, version>
+const RETIRED_VERSION_TREE: &str = "__retired_versions__";
+
+/// 被删除的 key 分到这么多个槽里
+const RETIRED_SLOTS: u64 = 1024;
+
+/// sorted set 中 member 的 score，<zset prefix + member, score>
+const ZSCORE_TREE: &str = "__zscores__";
+
+/// 按 score 排序的 sorted set，<zset prefix + score + member, 空>
+const ZSET_TREE: &str = "__zsets__";
+
+/// 索引的定义，<`table:` + 索引名, prost 编码的 IndexSpec>
+const INDEX_SPEC_TREE: &str = "__index_specs__";
+
+/// 索引的数据，<index prefix + term 的长度 + term + key, 空>
+const INDEX_TREE: &str = "__index__";
+
+/// key 当前被索引的 term，用来在 key 修改之后删除旧的索引，<index prefix + key, term>
+const INDEX_TERM_TREE: &str = "__index_terms__";
+
+/// prost 编码的 value 的前缀
+const ENCODED_VALUE: u8 = 0xff;
 
 pub struct SledDB(Db);
 
@@ -15,84 +65,891 @@ impl SledDB {
         Self(sled::open(path).unwrap())
     }
 
+    /// 和 new 一样，但是打开失败（比如数据库被另一个进程锁住）时返回错误
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, KvError> {
+        let path = path.as_ref();
+        let db = sled::open(path)
+            .map_err(|e| KvError::Internal(format!("Failed to open {}: {}", path.display(), e)))?;
+        Ok(Self(db))
+    }
+
     /// 在 sleddb 里，因为它可以 scan_prefix，我们用 prefix
     /// 来模拟一个 table。当然，还可以用其它方案。
-    fn get_full_key(table: &str, key: &str) -> String {
-        // format!("{}:{}", table, key)
-        format!("{}:{}", table, key)
+    /// key 可以是任意的字节，原样拼在 prefix 后面
+    fn get_full_key(table: &str, key: &[u8]) -> Vec<u8> {
+        let mut full_key = SledDB::get_table_prefix(table).into_bytes();
+        full_key.extend_from_slice(key);
+        full_key
     }
 
     /// 遍历 table 的 key 时，我们直接把 prefix: 当成 table
     fn get_table_prefix(table: &str) -> String {
         format!("{}:", table)
     }
+
+    /// 过期时间单独存放在一个 tree 里，<full key, 过期时刻的毫秒时间戳>
+    fn expirations(&self) -> Result<Tree, KvError> {
+        self.open_tree(EXPIRATION_TREE).sled_error()
+    }
+
+    /// 惰性过期：如果 key 已经过期，就把它删除，返回 true
+    fn remove_if_expired(&self, full_key: &[u8]) -> Result<bool, KvError> {
+        let expirations = self.expirations()?;
+        match expirations.get(full_key).sled_error()? {
+            Some(deadline) if as_u64(&deadline) <= now_millis() => {
+                expirations.remove(full_key).sled_error()?;
+                self.remove(full_key).sled_error()?;
+                self.touch(full_key)?;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    /// 版本号单独存放在一个 tree 里，<full key, 版本号>
+    fn versions(&self) -> Result<Tree, KvError> {
+        self.open_tree(VERSION_TREE).sled_error()
+    }
+
+    /// key 被修改了，版本号加一，并且更新索引。和数据不在同一个事务里修改，
+    /// 和 apply_batch 并发时仍然有很小的窗口检测不到冲突
+    fn retired_versions(&self) -> Result<Tree, KvError> {
+        self.open_tree(RETIRED_VERSION_TREE).sled_error()
+    }
+
+    /// key 被修改了，从数据库全局的计数器取一个新的版本号；
+    /// 被删除的 key 不再保存版本号，而是抬高它所在的槽的版本号，重新写入之后也不会回到之前的版本号
+    fn touch(&self, full_key: &[u8]) -> Result<(), KvError> {
+        let version = self.generate_id().sled_error()? + 1;
+        let versions = self.versions()?;
+        if self.contains_key(full_key).sled_error()? {
+            versions
+                .insert(full_key, &version.to_be_bytes())
+                .sled_error()?;
+        } else {
+            // 先抬高槽的版本号再去掉 key 的版本号，中间读到的版本号也和之前的不一样
+            self.retired_versions()?
+                .fetch_and_update(retired_slot(full_key), |v| {
+                    let retired = v.map(as_u64).unwrap_or_default().max(version);
+                    Some(retired.to_be_bytes().to_vec())
+                })
+                .sled_error()?;
+            versions.remove(full_key).sled_error()?;
+        }
+        self.reindex(full_key)
+    }
+
+    fn index_specs(&self) -> Result<Tree, KvError> {
+        self.open_tree(INDEX_SPEC_TREE).sled_error()
+    }
+
+    fn index_tree(&self) -> Result<Tree, KvError> {
+        self.open_tree(INDEX_TREE).sled_error()
+    }
+
+    fn index_terms(&self) -> Result<Tree, KvError> {
+        self.open_tree(INDEX_TERM_TREE).sled_error()
+    }
+
+    /// table 上所有的索引
+    fn specs_of(&self, table: &str) -> Result<Vec<IndexSpec>, KvError> {
+        let mut specs = Vec::new();
+        for item in self
+            .index_specs()?
+            .scan_prefix(SledDB::get_table_prefix(table))
+        {
+            let (_, spec) = item.sled_error()?;
+            specs.push(IndexSpec::decode(&spec[..])?);
+        }
+        Ok(specs)
+    }
+
+    /// 更新 full key 所在 table 上的所有索引。在事务里读取 key 当前的值，
+    /// 并发修改同一个 key 时，索引最后也和 key 最后的值一致
+    fn reindex(&self, full_key: &[u8]) -> Result<(), KvError> {
+        let Some(end) = full_key.iter().position(|c| *c == b':') else {
+            return Ok(());
+        };
+        let table = String::from_utf8_lossy(&full_key[..end]);
+        let specs = self.specs_of(&table)?;
+        if specs.is_empty() {
+            return Ok(());
+        }
+        let key = &full_key[end + 1..];
+        let (index, terms) = (self.index_tree()?, self.index_terms()?);
+        let data: &Tree = self;
+        let result = (data, &index, &terms).transaction(|(data, index, terms)| {
+            let value = data.get(full_key)?.map(|v| to_value(&v));
+            for spec in &specs {
+                let prefix = get_index_prefix(&table, &spec.name);
+                let term = value.as_ref().and_then(|v| index_term(v, &spec.field));
+                let term_key = [&prefix[..], key].concat();
+                let old = terms.get(&term_key[..])?;
+                if old.as_deref() == term.as_deref() {
+                    continue;
+                }
+                if let Some(old) = old {
+                    index.remove(index_entry_key(&prefix, &old, key))?;
+                }
+                match term {
+                    Some(term) => {
+                        index.insert(index_entry_key(&prefix, &term, key), &[][..])?;
+                        terms.insert(term_key, term)?;
+                    }
+                    None => {
+                        terms.remove(term_key)?;
+                    }
+                }
+            }
+            Ok::<_, ConflictableTransactionError<()>>(())
+        });
+        result
+            .map_err(|e| KvError::StorageError("reindex", "".into(), "".into(), format!("{:?}", e)))
+    }
+
+    /// apply_batch 在一个事务里修改数据，之后再逐个更新索引
+    fn reindex_records(&self, records: &[WalRecord]) -> Result<(), KvError> {
+        for record in records {
+            match &record.op {
+                Some(Op::Batch(batch)) => self.reindex_records(&batch.records)?,
+                _ => self.reindex(&SledDB::get_full_key(&record.table, &record.key))?,
+            }
+        }
+        Ok(())
+    }
+
+    fn clear_expiration(&self, full_key: &[u8]) -> Result<(), KvError> {
+        self.expirations()?.remove(full_key).sled_error()?;
+        Ok(())
+    }
+
+    /// 在 range 的两端加上 table 的前缀，最多返回 limit 个 kv pair；
+    /// 没有终点时一直到 `table;`，也就是 `table:` 开头的最后一个 key 之后
+    fn range_limit(
+        &self,
+        table: String,
+        range: impl RangeBounds<Bytes>,
+        limit: usize,
+    ) -> Result<Vec<Kvpair>, KvError> {
+        let prefix = SledDB::get_table_prefix(&table);
+        self.remove_expired_in(&prefix)?;
+        if is_empty_range(&range) {
+            return Ok(vec![]);
+        }
+        let full_key = |key: &Bytes| SledDB::get_full_key(&table, key);
+        let start = match range.start_bound() {
+            Bound::Included(key) => Bound::Included(full_key(key)),
+            Bound::Excluded(key) => Bound::Excluded(full_key(key)),
+            Bound::Unbounded => Bound::Included(prefix.clone().into_bytes()),
+        };
+        let end = match range.end_bound() {
+            Bound::Included(key) => Bound::Included(full_key(key)),
+            Bound::Excluded(key) => Bound::Excluded(full_key(key)),
+            Bound::Unbounded => Bound::Excluded(format!("{};", table).into_bytes()),
+        };
+        let pairs = self
+            .deref()
+            .range::<Vec<u8>, _>((start, end))
+            .take(limit)
+            .map(|v| SledDB::into_kvpair(&prefix, v))
+            .collect();
+        Ok(pairs)
+    }
+
+    /// 遍历 table 前先清理掉其中已经过期的 key
+    fn remove_expired_in(&self, prefix: &str) -> Result<usize, KvError> {
+        let now = now_millis();
+        let expired: Vec<IVec> = self
+            .expirations()?
+            .scan_prefix(prefix)
+            .filter_map(|v| v.ok())
+            .filter(|(_, deadline)| as_u64(deadline) <= now)
+            .map(|(k, _)| k)
+            .collect();
+
+        let mut count = 0;
+        for key in expired {
+            if self.remove_if_expired(&key)? {
+                count += 1;
+            }
+        }
+        Ok(count)
+    }
+
+    /// 删除 table 中所有的 key 和 sorted set，drop 时连同 table 上的索引一起删除，返回删除的 key 数
+    fn clear_table(&self, table: &str, drop: bool) -> Result<usize, KvError> {
+        let prefix = SledDB::get_table_prefix(table);
+        if drop {
+            // 先删除索引的定义，这样之后删除 key 时不需要再逐个更新索引
+            remove_prefix(&self.index_specs()?, &prefix)?;
+            remove_prefix(&self.index_tree()?, &prefix)?;
+            remove_prefix(&self.index_terms()?, &prefix)?;
+        }
+        self.remove_expired_in(&prefix)?;
+        let mut count = 0;
+        for key in self.deref().scan_prefix(&prefix).keys() {
+            let key = key.sled_error()?;
+            self.clear_expiration(&key)?;
+            if self.remove(&key).sled_error()?.is_some() {
+                self.touch(&key)?;
+                count += 1;
+            }
+        }
+        let zsets = self.zsets()?.iter().filter(|(t, _)| t == table).count();
+        remove_prefix(&self.zscores()?, &prefix)?;
+        remove_prefix(&self.zset_index()?, &prefix)?;
+        Ok(count + zsets)
+    }
+
+    /// sorted set 的前缀是 `table:` 加上 key 的长度和 key，
+    /// 这样一个 key 是另一个 key 的前缀时，两个 sorted set 也不会混在一起
+    fn get_zset_prefix(table: &str, key: &[u8]) -> Vec<u8> {
+        let mut prefix = SledDB::get_table_prefix(table).into_bytes();
+        prefix.extend_from_slice(&(key.len() as u32).to_be_bytes());
+        prefix.extend_from_slice(key);
+        prefix
+    }
+
+    fn zscores(&self) -> Result<Tree, KvError> {
+        self.open_tree(ZSCORE_TREE).sled_error()
+    }
+
+    fn zset_index(&self) -> Result<Tree, KvError> {
+        self.open_tree(ZSET_TREE).sled_error()
+    }
+
+    /// 去掉 full key 中的 table 前缀
+    fn into_kvpair(prefix: &str, v: Result<(IVec, IVec), Error>) -> Kvpair {
+        let mut pair: Kvpair = v.into();
+        if pair.key.starts_with(prefix.as_bytes()) {
+            pair.key = pair.key.slice(prefix.len()..);
+        }
+        pair
+    }
+}
+
+/// 过期时刻和版本号都按大端序的 u64 存放
+pub(super) fn as_u64(v: &[u8]) -> u64 {
+    v.try_into().map(u64::from_be_bytes).unwrap_or_default()
+}
+
+/// 索引的前缀是 `table:` 加上索引名的长度和索引名
+/// 删除 tree 中以 prefix 开头的所有 key
+fn remove_prefix(tree: &Tree, prefix: &str) -> Result<(), KvError> {
+    for key in tree.scan_prefix(prefix).keys() {
+        tree.remove(key.sled_error()?).sled_error()?;
+    }
+    Ok(())
+}
+
+fn get_index_prefix(table: &str, name: &str) -> Vec<u8> {
+    let mut prefix = SledDB::get_table_prefix(table).into_bytes();
+    prefix.extend_from_slice(&(name.len() as u32).to_be_bytes());
+    prefix.extend_from_slice(name.as_bytes());
+    prefix
+}
+
+/// 同一个 term 的 key 放在一起，term 前面加上长度，这样一个 term 是另一个 term 的前缀时也不会混在一起
+pub(super) fn get_term_prefix(index_prefix: &[u8], term: &[u8]) -> Vec<u8> {
+    let mut prefix = index_prefix.to_vec();
+    prefix.extend_from_slice(&(term.len() as u32).to_be_bytes());
+    prefix.extend_from_slice(term);
+    prefix
+}
+
+pub(super) fn index_entry_key(index_prefix: &[u8], term: &[u8], key: &[u8]) -> Vec<u8> {
+    let mut entry = get_term_prefix(index_prefix, term);
+    entry.extend_from_slice(key);
+    entry
+}
+
+/// 排序用的 key：score 转换成保持顺序的大端序 u64，放在 member 前面
+pub(super) fn zset_index_key(prefix: &[u8], score: f64, member: &[u8]) -> Vec<u8> {
+    let mut key = prefix.to_vec();
+    key.extend_from_slice(&score_key(score).to_be_bytes());
+    key.extend_from_slice(member);
+    key
+}
+
+/// zset_index_key 的逆操作，返回 (score_key, member)
+pub(super) fn parse_index_key(prefix_len: usize, key: &[u8]) -> (u64, Bytes) {
+    let score = as_u64(&key[prefix_len..prefix_len + 8]);
+    (score, Bytes::copy_from_slice(&key[prefix_len + 8..]))
 }
 
 impl Storage for SledDB {
     fn get(
         &self,
         table: impl Into<String>,
-        key: impl Into<String>,
+        key: impl AsRef<[u8]>,
     ) -> Result<Option<Value>, KvError> {
-        let key = SledDB::get_full_key(&table.into(), &key.into());
+        let key = SledDB::get_full_key(&table.into(), key.as_ref());
+        self.remove_if_expired(&key)?;
         self.deref().get(key).flip()
     }
 
     fn set(
         &self,
         table: impl Into<String>,
-        key: impl Into<String>,
+        key: impl AsRef<[u8]>,
         value: Value,
     ) -> Result<Option<Value>, KvError> {
-        let key = SledDB::get_full_key(&table.into(), &key.into());
-        self.insert(key, value).flip()
+        let key = SledDB::get_full_key(&table.into(), key.as_ref());
+        self.remove_if_expired(&key)?;
+        self.clear_expiration(&key)?;
+        let old = self.insert(&key, value).flip()?;
+        self.touch(&key)?;
+        Ok(old)
+    }
+
+    fn compare_and_swap(
+        &self,
+        table: impl Into<String>,
+        key: impl AsRef<[u8]>,
+        expected: Option<Value>,
+        value: Value,
+    ) -> Result<(bool, Option<Value>), KvError> {
+        let key = SledDB::get_full_key(&table.into(), key.as_ref());
+        self.remove_if_expired(&key)?;
+        let expected: Option<IVec> = expected.map(Into::into);
+        let new: IVec = value.clone().into();
+        match self
+            .deref()
+            .compare_and_swap(&key, expected, Some(new))
+            .sled_error()?
+        {
+            Ok(()) => {
+                self.clear_expiration(&key)?;
+                self.touch(&key)?;
+                Ok((true, Some(value)))
+            }
+            Err(e) => Ok((false, e.current.map(|v| to_value(&v)))),
+        }
     }
 
-    fn contains(&self, table: impl Into<String>, key: impl Into<String>) -> Result<bool, KvError> {
-        let key = SledDB::get_full_key(&table.into(), &key.into());
+    fn incr(
+        &self,
+        table: impl Into<String>,
+        key: impl AsRef<[u8]>,
+        delta: i64,
+    ) -> Result<i64, KvError> {
+        let key = key.as_ref();
+        let full_key = SledDB::get_full_key(&table.into(), key);
+        self.remove_if_expired(&full_key)?;
+        // 读出来算好之后用 compare_and_swap 写回去，期间被别人改过就重试
+        loop {
+            let current = self.deref().get(&full_key).sled_error()?;
+            let value = incr_value(key, current.as_deref().map(to_value).as_ref(), delta)?;
+            let new: IVec = Value::from(value).into();
+            if self
+                .deref()
+                .compare_and_swap(&full_key, current, Some(new))
+                .sled_error()?
+                .is_ok()
+            {
+                self.touch(&full_key)?;
+                return Ok(value);
+            }
+        }
+    }
+
+    fn update<T>(
+        &self,
+        table: impl Into<String>,
+        key: impl AsRef<[u8]>,
+        mut f: impl FnMut(Option<&Value>) -> Result<(Value, T), KvError>,
+    ) -> Result<T, KvError> {
+        let key = SledDB::get_full_key(&table.into(), key.as_ref());
+        self.remove_if_expired(&key)?;
+        // 和 incr 一样，期间被别人改过就用新的值重新计算
+        loop {
+            let current = self.deref().get(&key).sled_error()?;
+            let (value, result) = f(current.as_deref().map(to_value).as_ref())?;
+            let new: IVec = value.into();
+            if self
+                .deref()
+                .compare_and_swap(&key, current, Some(new))
+                .sled_error()?
+                .is_ok()
+            {
+                self.touch(&key)?;
+                return Ok(result);
+            }
+        }
+    }
+
+    fn contains(&self, table: impl Into<String>, key: impl AsRef<[u8]>) -> Result<bool, KvError> {
+        let key = SledDB::get_full_key(&table.into(), key.as_ref());
+        self.remove_if_expired(&key)?;
         self.contains_key(key).sled_error()
     }
 
     fn del(
         &self,
         table: impl Into<String>,
-        key: impl Into<String>,
+        key: impl AsRef<[u8]>,
     ) -> Result<Option<Value>, KvError> {
-        let key = SledDB::get_full_key(&table.into(), &key.into());
-        self.remove(key).flip()
+        let key = SledDB::get_full_key(&table.into(), key.as_ref());
+        self.remove_if_expired(&key)?;
+        self.clear_expiration(&key)?;
+        let old = self.remove(&key).flip()?;
+        self.touch(&key)?;
+        Ok(old)
     }
 
     fn get_all(&self, table: impl Into<String>) -> Result<Vec<Kvpair>, KvError> {
-        let data = self
-            .scan_prefix(table.into())
-            .map(|v: Result<(IVec, IVec), Error>| v.into())
-            .collect();
-        Ok(data)
+        Ok(self.get_iter(table)?.collect())
     }
 
     fn get_iter(&self, table: impl Into<String>) -> Result<impl Iterator<Item = Kvpair>, KvError> {
-        let iter = self.scan_prefix(table.into()).into_iter();
-        let iter = StorageIter::new(iter);
-        Ok(iter)
+        let prefix = SledDB::get_table_prefix(&table.into());
+        self.remove_expired_in(&prefix)?;
+        let iter = self
+            .deref()
+            .scan_prefix(&prefix)
+            .map(move |v| SledDB::into_kvpair(&prefix, v));
+        Ok(StorageIter::new(iter))
+    }
+
+    fn scan_prefix(
+        &self,
+        table: impl Into<String>,
+        prefix: impl AsRef<[u8]>,
+    ) -> Result<impl Iterator<Item = Kvpair>, KvError> {
+        let table = table.into();
+        let table_prefix = SledDB::get_table_prefix(&table);
+        self.remove_expired_in(&table_prefix)?;
+        let iter = self
+            .deref()
+            .scan_prefix(SledDB::get_full_key(&table, prefix.as_ref()))
+            .map(move |v| SledDB::into_kvpair(&table_prefix, v));
+        Ok(StorageIter::new(iter))
+    }
+
+    fn range(
+        &self,
+        table: impl Into<String>,
+        range: impl RangeBounds<Bytes>,
+    ) -> Result<Vec<Kvpair>, KvError> {
+        self.range_limit(table.into(), range, usize::MAX)
+    }
+
+    fn scan_after(
+        &self,
+        table: impl Into<String>,
+        start: Option<Bytes>,
+        limit: usize,
+    ) -> Result<Vec<Kvpair>, KvError> {
+        let start = start.map_or(Bound::Unbounded, Bound::Excluded);
+        self.range_limit(table.into(), (start, Bound::Unbounded), limit)
+    }
+
+    fn expire(
+        &self,
+        table: impl Into<String>,
+        key: impl AsRef<[u8]>,
+        ttl: Duration,
+    ) -> Result<bool, KvError> {
+        let key = SledDB::get_full_key(&table.into(), key.as_ref());
+        if self.remove_if_expired(&key)? || !self.contains_key(&key).sled_error()? {
+            return Ok(false);
+        }
+        let deadline = now_millis() + ttl.as_millis() as u64;
+        self.expirations()?
+            .insert(&key, &deadline.to_be_bytes())
+            .sled_error()?;
+        self.touch(&key)?;
+        Ok(true)
+    }
+
+    fn ttl(
+        &self,
+        table: impl Into<String>,
+        key: impl AsRef<[u8]>,
+    ) -> Result<Option<Duration>, KvError> {
+        let key = SledDB::get_full_key(&table.into(), key.as_ref());
+        self.remove_if_expired(&key)?;
+        let ttl =
+            self.expirations()?.get(key).sled_error()?.map(|deadline| {
+                Duration::from_millis(as_u64(&deadline).saturating_sub(now_millis()))
+            });
+        Ok(ttl)
+    }
+
+    fn purge_expired(&self) -> Result<usize, KvError> {
+        self.remove_expired_in("")
+    }
+
+    /// full key 中第一个 `:` 之前的部分就是 table
+    fn tables(&self) -> Result<Vec<String>, KvError> {
+        let mut tables = BTreeSet::new();
+        for item in self.iter() {
+            let (key, _) = item.sled_error()?;
+            if let Some(end) = key.iter().position(|c| *c == b':') {
+                tables.insert(String::from_utf8_lossy(&key[..end]).into_owned());
+            }
+        }
+        Ok(tables.into_iter().collect())
+    }
+
+    fn backend(&self) -> &'static str {
+        "sled"
+    }
+
+    fn stats(&self) -> Result<Vec<(String, TableStats)>, KvError> {
+        self.purge_expired()?;
+        let mut stats: BTreeMap<String, TableStats> = BTreeMap::new();
+        for item in self.iter() {
+            let (key, value) = item.sled_error()?;
+            let Some(end) = key.iter().position(|c| *c == b':') else {
+                continue;
+            };
+            let table = String::from_utf8_lossy(&key[..end]).into_owned();
+            let entry = stats.entry(table).or_default();
+            entry.keys += 1;
+            entry.bytes += (key.len() - end - 1 + value.len()) as u64;
+        }
+        Ok(stats.into_iter().collect())
+    }
+
+    fn truncate_table(&self, table: impl Into<String>) -> Result<usize, KvError> {
+        self.clear_table(&table.into(), false)
+    }
+
+    fn drop_table(&self, table: impl Into<String>) -> Result<usize, KvError> {
+        self.clear_table(&table.into(), true)
+    }
+
+    fn version(&self, table: impl Into<String>, key: impl AsRef<[u8]>) -> Result<u64, KvError> {
+        let key = SledDB::get_full_key(&table.into(), key.as_ref());
+        self.remove_if_expired(&key)?;
+        let version = match self.versions()?.get(&key).sled_error()? {
+            Some(version) => Some(version),
+            None => self
+                .retired_versions()?
+                .get(retired_slot(&key))
+                .sled_error()?,
+        };
+        Ok(version.map(|v| as_u64(&v)).unwrap_or_default())
+    }
+
+    /// 数据、过期时间和版本号在同一个 sled 事务里修改
+    fn apply_batch(
+        &self,
+        records: Vec<WalRecord>,
+        watched: &[KeyVersion],
+    ) -> Result<bool, KvError> {
+        for w in watched {
+            self.remove_if_expired(&SledDB::get_full_key(&w.table, &w.key))?;
+        }
+        let (expirations, versions) = (self.expirations()?, self.versions()?);
+        let retired = self.retired_versions()?;
+        let data: &Tree = self;
+        let result = (data, &expirations, &versions, &retired).transaction(
+            |(data, expirations, versions, retired)| {
+                for w in watched {
+                    let key = SledDB::get_full_key(&w.table, &w.key);
+                    let version = match versions.get(&key[..])? {
+                        Some(version) => Some(version),
+                        None => retired.get(retired_slot(&key))?,
+                    };
+                    if version.map(|v| as_u64(&v)).unwrap_or_default() != w.version {
+                        return Err(ConflictableTransactionError::Abort(()));
+                    }
+                }
+                let trees = VersionTrees { versions, retired };
+                apply_in_transaction(data, expirations, &trees, &records)?;
+                Ok(())
+            },
+        );
+        match result {
+            Ok(()) => {
+                self.reindex_records(&records)?;
+                Ok(true)
+            }
+            Err(TransactionError::Abort(())) => Ok(false),
+            Err(e) => Err(KvError::StorageError(
+                "apply_batch",
+                "".into(),
+                "".into(),
+                format!("{:?}", e),
+            )),
+        }
+    }
+
+    /// 两个 tree 在同一个 sled 事务里修改
+    fn zadd(
+        &self,
+        table: impl Into<String>,
+        key: impl AsRef<[u8]>,
+        members: Vec<ScoredMember>,
+    ) -> Result<usize, KvError> {
+        members.iter().try_for_each(|m| check_score(m.score))?;
+        let prefix = SledDB::get_zset_prefix(&table.into(), key.as_ref());
+        let (scores, index) = (self.zscores()?, self.zset_index()?);
+        let result = (&scores, &index).transaction(|(scores, index)| {
+            let mut added = 0;
+            for m in &members {
+                let member_key = [&prefix[..], &m.member[..]].concat();
+                let score = m.score.to_bits().to_be_bytes();
+                match scores.insert(member_key, &score[..])? {
+                    Some(old) => {
+                        let old = f64::from_bits(as_u64(&old));
+                        index.remove(zset_index_key(&prefix, old, &m.member))?;
+                    }
+                    None => added += 1,
+                }
+                index.insert(zset_index_key(&prefix, m.score, &m.member), &[][..])?;
+            }
+            Ok::<_, ConflictableTransactionError<()>>(added)
+        });
+        result.map_err(|e| KvError::StorageError("zadd", "".into(), "".into(), format!("{:?}", e)))
+    }
+
+    fn zrange(
+        &self,
+        table: impl Into<String>,
+        key: impl AsRef<[u8]>,
+        start: i64,
+        stop: i64,
+    ) -> Result<Vec<ScoredMember>, KvError> {
+        let prefix = SledDB::get_zset_prefix(&table.into(), key.as_ref());
+        let len = self.zscores()?.scan_prefix(&prefix).count();
+        let (start, end) = rank_range(len, start, stop);
+        let mut members = Vec::new();
+        for item in self.zset_index()?.scan_prefix(&prefix).skip(start) {
+            if members.len() >= end - start {
+                break;
+            }
+            let (key, _) = item.sled_error()?;
+            let (score, member) = parse_index_key(prefix.len(), &key);
+            members.push((member, from_score_key(score)).into());
+        }
+        Ok(members)
+    }
+
+    fn zrange_by_score(
+        &self,
+        table: impl Into<String>,
+        key: impl AsRef<[u8]>,
+        min: f64,
+        max: f64,
+    ) -> Result<Vec<ScoredMember>, KvError> {
+        let prefix = SledDB::get_zset_prefix(&table.into(), key.as_ref());
+        let max = score_key(max);
+        let mut members = Vec::new();
+        for item in self
+            .zset_index()?
+            .range(zset_index_key(&prefix, min, b"")..)
+        {
+            let (key, _) = item.sled_error()?;
+            if !key.starts_with(&prefix) {
+                break;
+            }
+            let (score, member) = parse_index_key(prefix.len(), &key);
+            if score > max {
+                break;
+            }
+            members.push((member, from_score_key(score)).into());
+        }
+        Ok(members)
+    }
+
+    /// 从 zset prefix 中解析出 table 和 key
+    fn zsets(&self) -> Result<Vec<(String, Bytes)>, KvError> {
+        let mut zsets = BTreeSet::new();
+        for item in self.zscores()?.iter() {
+            let (key, _) = item.sled_error()?;
+            let Some(end) = key.iter().position(|c| *c == b':') else {
+                continue;
+            };
+            let len = as_u32(&key[end + 1..]) as usize;
+            let start = end + 1 + 4;
+            let Some(zset) = key.get(start..start + len) else {
+                continue;
+            };
+            let table = String::from_utf8_lossy(&key[..end]).into_owned();
+            zsets.insert((table, Bytes::copy_from_slice(zset)));
+        }
+        Ok(zsets.into_iter().collect())
+    }
+
+    /// 先写入索引的定义，再逐个更新已有的 key；期间的修改同样会更新索引
+    fn create_index(&self, table: impl Into<String>, spec: IndexSpec) -> Result<bool, KvError> {
+        check_spec(&spec)?;
+        let table = table.into();
+        let prefix = SledDB::get_table_prefix(&table);
+        let spec_key = [prefix.as_bytes(), spec.name.as_bytes()].concat();
+        let created = self
+            .index_specs()?
+            .compare_and_swap(spec_key, None as Option<&[u8]>, Some(spec.encode_to_vec()))
+            .sled_error()?;
+        if created.is_err() {
+            return Ok(false);
+        }
+        for item in self.deref().scan_prefix(&prefix) {
+            let (key, _) = item.sled_error()?;
+            self.reindex(&key)?;
+        }
+        Ok(true)
+    }
+
+    fn query(
+        &self,
+        table: impl Into<String>,
+        index: &str,
+        value: &Value,
+    ) -> Result<Vec<Kvpair>, KvError> {
+        let table = table.into();
+        if !self.specs_of(&table)?.iter().any(|spec| spec.name == index) {
+            return Err(KvError::NotFound(format!("index {}:{}", table, index)));
+        }
+        let Some(term) = index_term(value, &[]) else {
+            return Ok(vec![]);
+        };
+        let prefix = get_term_prefix(&get_index_prefix(&table, index), &term);
+        let mut pairs = Vec::new();
+        for item in self.index_tree()?.scan_prefix(&prefix) {
+            let (entry, _) = item.sled_error()?;
+            let key = &entry[prefix.len()..];
+            // 过期的 key 在这里被删除，同时也会从索引中删除
+            if let Some(value) = self.get(table.as_str(), key)? {
+                pairs.push(Kvpair::new(key, value));
+            }
+        }
+        Ok(pairs)
+    }
+
+    fn indexes(&self) -> Result<Vec<(String, IndexSpec)>, KvError> {
+        let mut indexes = Vec::new();
+        for item in self.index_specs()?.iter() {
+            let (key, spec) = item.sled_error()?;
+            let Some(end) = key.iter().position(|c| *c == b':') else {
+                continue;
+            };
+            let table = String::from_utf8_lossy(&key[..end]).into_owned();
+            indexes.push((table, IndexSpec::decode(&spec[..])?));
+        }
+        Ok(indexes)
+    }
+
+    fn is_blocking(&self) -> bool {
+        true
+    }
+
+    fn flush(&self) -> Result<(), KvError> {
+        self.0.flush().sled_error().map(|_| ())
+    }
+
+    /// sled 在后台自己清理旧的 segment，这里把缓冲区刷到磁盘，让已经写满的 segment 可以被回收
+    fn compact(&self) -> Result<u64, KvError> {
+        let before = self.0.size_on_disk().sled_error()?;
+        self.0.flush().sled_error()?;
+        let after = self.0.size_on_disk().sled_error()?;
+        Ok(before.saturating_sub(after))
+    }
+
+    fn disk_usage(&self) -> Result<Option<u64>, KvError> {
+        Ok(Some(self.0.size_on_disk().sled_error()?))
+    }
+}
+
+/// zset prefix 中 key 的长度按大端序的 u32 存放
+pub(super) fn as_u32(v: &[u8]) -> u32 {
+    v.get(..4)
+        .and_then(|v| v.try_into().ok())
+        .map(u32::from_be_bytes)
+        .unwrap_or_default()
+}
+
+/// 被删除的 key 所在的槽；槽号写进数据库，所以用固定的 FNV-1a 而不是每次启动都不一样的 hash
+fn retired_slot(full_key: &[u8]) -> [u8; 8] {
+    let hash = full_key.iter().fold(0xcbf29ce484222325_u64, |hash, b| {
+        (hash ^ *b as u64).wrapping_mul(0x100000001b3)
+    });
+    (hash % RETIRED_SLOTS).to_be_bytes()
+}
+
+/// 事务里的存在的 key 的版本号和被删除的 key 的槽的版本号
+struct VersionTrees<'a> {
+    versions: &'a TransactionalTree,
+    retired: &'a TransactionalTree,
+}
+
+fn apply_in_transaction(
+    data: &TransactionalTree,
+    expirations: &TransactionalTree,
+    trees: &VersionTrees,
+    records: &[WalRecord],
+) -> ConflictableTransactionResult<(), ()> {
+    for record in records {
+        let key = SledDB::get_full_key(&record.table, &record.key);
+        match &record.op {
+            Some(Op::Set(value)) => {
+                expirations.remove(&key[..])?;
+                data.insert(&key[..], IVec::from(value.clone()))?;
+            }
+            Some(Op::Del(_)) => {
+                expirations.remove(&key[..])?;
+                data.remove(&key[..])?;
+            }
+            Some(Op::Expire(deadline)) => {
+                // 和 expire 一样，不存在的 key 不设置过期时间
+                if data.get(&key[..])?.is_none() {
+                    continue;
+                }
+                expirations.insert(&key[..], &deadline.to_be_bytes())?;
+            }
+            Some(Op::Batch(batch)) => {
+                apply_in_transaction(data, expirations, trees, &batch.records)?;
+                continue;
+            }
+            // ZADD 和 CREATEINDEX 不能在事务里执行，batch 里不会有这些记录
+            Some(Op::Zadd(_) | Op::CreateIndex(_) | Op::TruncateTable(_) | Op::DropTable(_))
+            | None => continue,
+        }
+        let version = trees.versions.generate_id()? + 1;
+        if data.get(&key[..])?.is_some() {
+            trees.versions.insert(&key[..], &version.to_be_bytes())?;
+        } else {
+            let slot = retired_slot(&key);
+            let retired = trees.retired.get(slot)?.map(|v| as_u64(&v));
+            let retired = retired.unwrap_or_default().max(version);
+            trees.retired.insert(&slot, &retired.to_be_bytes())?;
+            trees.versions.remove(&key[..])?;
+        }
     }
+    Ok(())
 }
 
+/// string 和 integer 直接按字符串存放，其它类型的 value 用 prost 编码，
+/// 前面加上一个不可能出现在合法 utf8 开头的字节，和字符串区分开
 impl From<Value> for IVec {
     fn from(value: Value) -> Self {
-        match value.value {
-            Some(v) => match v {
-                value::Value::String(s) => s.as_str().into(),
-                _ => todo!(),
-            },
-            None => todo!(),
+        match &value.value {
+            Some(value::Value::String(s)) => s.as_str().into(),
+            Some(value::Value::Integer(i)) => i.to_string().as_str().into(),
+            _ => {
+                let mut buf = vec![ENCODED_VALUE];
+                buf.extend(value.encode_to_vec());
+                buf.into()
+            }
         }
     }
 }
 
+/// IVec 转换回 Value，和 `From<Value> for IVec` 对应
+pub(super) fn to_value(data: &[u8]) -> Value {
+    match data.split_first() {
+        Some((&ENCODED_VALUE, encoded)) => Value::decode(encoded).unwrap_or_default(),
+        _ => data.into(),
+    }
+}
+
 impl From<Result<(IVec, IVec), Error>> for Kvpair {
     fn from(value: Result<(IVec, IVec), Error>) -> Self {
         match value {
-            Ok(v) => Kvpair::new(v.0.u8_to_string(), v.1.as_ref().into()),
+            Ok(v) => Kvpair::new(v.0, to_value(&v.1)),
             Err(_) => Kvpair::default(),
         }
     }
@@ -117,7 +974,7 @@ where
 {
     fn flip(self) -> Result<Option<Value>, KvError> {
         match self {
-            Ok(value) => Ok(value.map(|m| m.deref().into())),
+            Ok(value) => Ok(value.map(|m| to_value(&m))),
             Err(e) => Err(KvError::Internal(
                 format!("error flipr: {:?}", e).to_owned(),
             )),
@@ -147,7 +1004,13 @@ mod sled_test {
 
     use tempfile::tempdir;
 
-    use crate::storage::tests::{test_basic_interface, test_get_all, test_get_iter};
+    use crate::storage::tests::{
+        test_apply_batch, test_basic_interface, test_binary_keys, test_compare_and_swap,
+        test_expiration, test_get_all, test_get_iter, test_incr, test_index, test_mget_mset,
+        test_range, test_scan_prefix, test_set_if, test_stats, test_table_management, test_update,
+        test_version, test_zset,
+    };
+    use crate::Storage;
 
     use super::SledDB;
 
@@ -170,4 +1033,132 @@ mod sled_test {
         let store = SledDB::new(dir);
         test_get_iter(store);
     }
+
+    #[test]
+    fn sleddb_expiration_should_work() {
+        let dir = tempdir().unwrap();
+        let store = SledDB::new(dir);
+        test_expiration(store);
+    }
+
+    #[test]
+    fn sleddb_compare_and_swap_should_work() {
+        let dir = tempdir().unwrap();
+        let store = SledDB::new(dir);
+        test_compare_and_swap(store);
+    }
+
+    #[test]
+    fn sleddb_incr_should_work() {
+        let dir = tempdir().unwrap();
+        let store = SledDB::new(dir);
+        test_incr(store);
+    }
+
+    #[test]
+    fn sleddb_update_should_work() {
+        let dir = tempdir().unwrap();
+        let store = SledDB::new(dir);
+        test_update(store);
+    }
+
+    #[test]
+    fn sleddb_binary_keys_should_work() {
+        let dir = tempdir().unwrap();
+        let store = SledDB::new(dir);
+        test_binary_keys(store);
+    }
+
+    #[test]
+    fn sleddb_zset_should_work() {
+        let dir = tempdir().unwrap();
+        let store = SledDB::new(dir);
+        test_zset(store);
+    }
+
+    #[test]
+    fn sleddb_index_should_work() {
+        let dir = tempdir().unwrap();
+        let store = SledDB::new(dir);
+        test_index(store);
+    }
+
+    #[test]
+    fn sleddb_range_should_work() {
+        let dir = tempdir().unwrap();
+        let store = SledDB::new(dir);
+        test_range(store);
+    }
+
+    #[test]
+    fn sleddb_scan_prefix_should_work() {
+        let dir = tempdir().unwrap();
+        let store = SledDB::new(dir);
+        test_scan_prefix(store);
+    }
+
+    #[test]
+    fn sleddb_table_management_should_work() {
+        let dir = tempdir().unwrap();
+        let store = SledDB::new(dir);
+        test_table_management(store);
+    }
+
+    #[test]
+    fn sleddb_stats_should_work() {
+        let dir = tempdir().unwrap();
+        let store = SledDB::new(dir);
+        test_stats(store);
+    }
+
+    #[test]
+    fn sleddb_apply_batch_should_work() {
+        let dir = tempdir().unwrap();
+        let store = SledDB::new(dir);
+        test_apply_batch(store);
+    }
+
+    #[test]
+    fn sleddb_mget_mset_should_work() {
+        let dir = tempdir().unwrap();
+        let store = SledDB::new(dir);
+        test_mget_mset(store);
+    }
+
+    #[test]
+    fn sleddb_set_if_should_work() {
+        let dir = tempdir().unwrap();
+        let store = SledDB::new(dir);
+        test_set_if(store);
+    }
+
+    #[test]
+    fn sleddb_version_should_work() {
+        let dir = tempdir().unwrap();
+        let store = SledDB::new(dir);
+        test_version(store);
+    }
+
+    #[test]
+    fn sleddb_deleted_keys_should_not_keep_versions() {
+        let dir = tempdir().unwrap();
+        let store = SledDB::new(&dir);
+        for i in 0..100 {
+            store.set("t1", format!("k{}", i), "v".into()).unwrap();
+        }
+        let v1 = store.version("t1", "k1").unwrap();
+        for i in 0..100 {
+            store.del("t1", format!("k{}", i)).unwrap();
+        }
+        assert!(store.versions().unwrap().is_empty());
+        let v2 = store.version("t1", "k1").unwrap();
+        assert!(v2 > v1);
+
+        // 重新打开之后版本号也不会回到之前的值
+        drop(store);
+        let store = SledDB::new(&dir);
+        assert_eq!(store.version("t1", "k1").unwrap(), v2);
+        store.set("t1", "k1", "v".into()).unwrap();
+        assert!(store.version("t1", "k1").unwrap() > v2);
+    }
 }
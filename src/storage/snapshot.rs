@@ -0,0 +1,88 @@
+use super::{wal, Storage};
+use crate::{error::KvError, pb::abi::wal_record::Op};
+use prost::Message;
+use std::{fs, path::Path};
+
+/// 把 store 中所有的数据导出到 path，返回导出的 key 数量（包括 sorted set）。
+/// 文件的格式和 WAL 一样，先写到临时文件再改名，不会留下写了一半的 snapshot
+pub fn dump(store: &impl Storage, path: impl AsRef<Path>) -> Result<usize, KvError> {
+    let path = path.as_ref();
+    let records = store.snapshot()?;
+    let keys = records
+        .iter()
+        .filter(|r| matches!(r.op, Some(Op::Set(_) | Op::Zadd(_))))
+        .count();
+
+    let mut buf = Vec::new();
+    for record in records {
+        record.encode_length_delimited(&mut buf)?;
+    }
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, buf)?;
+    fs::rename(tmp, path)?;
+    Ok(keys)
+}
+
+/// 把 path 中的 snapshot 导入到 store，返回导入的记录数
+pub fn restore(store: &impl Storage, path: impl AsRef<Path>) -> Result<usize, KvError> {
+    let path = path.as_ref();
+    let data = fs::read(path)?;
+    let (count, len) = wal::replay(store, &data);
+    if len < data.len() {
+        return Err(KvError::Internal(format!(
+            "broken snapshot {}: only {} of {} bytes are valid",
+            path.display(),
+            len,
+            data.len()
+        )));
+    }
+    Ok(count)
+}
+
+#[cfg(test)]
+mod snapshot_test {
+    use super::{dump, restore};
+    use crate::{
+        config::FsyncPolicy, memory::MemTable, sled_db::SledDB, wal::WalMemTable, Storage,
+    };
+    use std::time::Duration;
+    use tempfile::tempdir;
+
+    #[test]
+    fn snapshot_should_be_restored_into_another_backend() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("kv.snapshot");
+
+        let store = MemTable::new();
+        store.set("t1", "k1", "v1".into()).unwrap();
+        store.set("t1", "k2", "v2".into()).unwrap();
+        store.set("t2", "k1", "v3".into()).unwrap();
+        store.expire("t2", "k1", Duration::from_secs(100)).unwrap();
+        assert_eq!(dump(&store, &path).unwrap(), 3);
+
+        let sled = SledDB::new(dir.path().join("sled"));
+        assert_eq!(restore(&sled, &path).unwrap(), 4);
+        let mut tables = sled.tables().unwrap();
+        tables.sort();
+        assert_eq!(tables, vec!["t1", "t2"]);
+        assert_eq!(sled.get("t1", "k2").unwrap(), Some("v2".into()));
+        assert_eq!(sled.get("t2", "k1").unwrap(), Some("v3".into()));
+        assert!(sled.ttl("t2", "k1").unwrap().unwrap() > Duration::from_secs(90));
+        assert_eq!(sled.ttl("t1", "k1").unwrap(), None);
+
+        // 反过来再导出到 WalMemTable
+        assert_eq!(dump(&sled, &path).unwrap(), 3);
+        let wal = WalMemTable::open(dir.path().join("kv.wal"), FsyncPolicy::No).unwrap();
+        restore(&wal, &path).unwrap();
+        assert_eq!(wal.get("t1", "k1").unwrap(), Some("v1".into()));
+        assert!(wal.ttl("t2", "k1").unwrap().is_some());
+    }
+
+    #[test]
+    fn broken_snapshot_should_be_rejected() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("kv.snapshot");
+        std::fs::write(&path, [10, 1, 2, 3]).unwrap();
+        assert!(restore(&MemTable::new(), &path).is_err());
+    }
+}
@@ -0,0 +1,348 @@
+//! SQLite 存储：所有 table 的数据放在一张 `(tbl, key, value)` 表里，元数据放在同样结构的另一张表里。
+//!
+//! 数据库使用 WAL 模式，写事务用 `BEGIN IMMEDIATE` 开始；语句通过 prepare_cached 只编译一次。
+//! 同一个连接由 Mutex 保护，读写都是串行的
+
+use std::{
+    ops::Bound,
+    path::Path,
+    sync::{Mutex, MutexGuard, PoisonError},
+    time::Duration,
+};
+
+use rusqlite::{Connection, ToSql, TransactionBehavior};
+
+use super::ordered::{Engine, Entries, OrderedStore, ReadTxn, Space, WriteTxn};
+use crate::error::KvError;
+
+/// 另一个进程持有写锁时最多等待的时间
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS kv (tbl TEXT NOT NULL, key BLOB NOT NULL, value BLOB NOT NULL, PRIMARY KEY (tbl, key)) WITHOUT ROWID;
+CREATE TABLE IF NOT EXISTS meta (tbl TEXT NOT NULL, key BLOB NOT NULL, value BLOB NOT NULL, PRIMARY KEY (tbl, key)) WITHOUT ROWID;
+";
+
+pub struct SqliteEngine {
+    conn: Mutex<Connection>,
+    path: String,
+}
+
+/// 基于 SQLite 的 Storage
+pub type Sqlite = OrderedStore<SqliteEngine>;
+
+impl Sqlite {
+    /// 打开 path 处的数据库文件，不存在时创建
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, KvError> {
+        let path = path.as_ref();
+        let conn = Connection::open(path)
+            .map_err(|e| KvError::Internal(format!("Failed to open {}: {}", path.display(), e)))?;
+        conn.pragma_update_and_check(None, "journal_mode", "WAL", |row| row.get::<_, String>(0))
+            .map_err(sqlite_error)?;
+        conn.busy_timeout(BUSY_TIMEOUT).map_err(sqlite_error)?;
+        conn.execute_batch(SCHEMA).map_err(sqlite_error)?;
+        Ok(OrderedStore::new(SqliteEngine {
+            conn: Mutex::new(conn),
+            path: path.display().to_string(),
+        }))
+    }
+}
+
+fn sqlite_error(e: rusqlite::Error) -> KvError {
+    KvError::Internal(format!("SQLite error: {}", e))
+}
+
+/// space 所在的 SQL 表，以及 tbl 列的值
+fn locate(space: Space<'_>) -> (&'static str, &str) {
+    match space {
+        Space::Data(table) => ("kv", table),
+        Space::Meta(tree) => ("meta", tree.name()),
+    }
+}
+
+/// 在事务里执行的语句；写语句只会在 Engine::write 开始的事务里执行
+struct SqliteTxn<'a>(&'a Connection);
+
+impl ReadTxn for SqliteTxn<'_> {
+    fn get(&self, space: Space, key: &[u8]) -> Result<Option<Vec<u8>>, KvError> {
+        let (table, name) = locate(space);
+        let sql = format!("SELECT value FROM {} WHERE tbl = ?1 AND key = ?2", table);
+        let mut stmt = self.0.prepare_cached(&sql).map_err(sqlite_error)?;
+        let mut rows = stmt.query((name, key)).map_err(sqlite_error)?;
+        match rows.next().map_err(sqlite_error)? {
+            Some(row) => Ok(Some(row.get(0).map_err(sqlite_error)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn range(
+        &self,
+        space: Space,
+        start: Bound<&[u8]>,
+        end: Bound<&[u8]>,
+        limit: usize,
+    ) -> Result<Entries, KvError> {
+        let (table, name) = locate(space);
+        let limit = i64::try_from(limit).unwrap_or(i64::MAX);
+        let mut sql = format!("SELECT key, value FROM {} WHERE tbl = ?", table);
+        let mut args: Vec<&dyn ToSql> = vec![&name];
+        for (bound, inclusive, exclusive) in [(&start, ">=", ">"), (&end, "<=", "<")] {
+            let (op, key) = match bound {
+                Bound::Included(key) => (inclusive, key),
+                Bound::Excluded(key) => (exclusive, key),
+                Bound::Unbounded => continue,
+            };
+            sql.push_str(&format!(" AND key {} ?", op));
+            args.push(key);
+        }
+        sql.push_str(" ORDER BY key LIMIT ?");
+        args.push(&limit);
+
+        let mut stmt = self.0.prepare_cached(&sql).map_err(sqlite_error)?;
+        let rows = stmt
+            .query_map(args.as_slice(), |row| Ok((row.get(0)?, row.get(1)?)))
+            .map_err(sqlite_error)?;
+        rows.collect::<Result<_, _>>().map_err(sqlite_error)
+    }
+
+    fn tables(&self) -> Result<Vec<String>, KvError> {
+        let mut stmt = self
+            .0
+            .prepare_cached("SELECT DISTINCT tbl FROM kv ORDER BY tbl")
+            .map_err(sqlite_error)?;
+        let rows = stmt.query_map([], |row| row.get(0)).map_err(sqlite_error)?;
+        rows.collect::<Result<_, _>>().map_err(sqlite_error)
+    }
+}
+
+impl WriteTxn for SqliteTxn<'_> {
+    fn put(&mut self, space: Space, key: &[u8], value: &[u8]) -> Result<(), KvError> {
+        let (table, name) = locate(space);
+        let sql = format!(
+            "INSERT OR REPLACE INTO {} (tbl, key, value) VALUES (?1, ?2, ?3)",
+            table
+        );
+        let mut stmt = self.0.prepare_cached(&sql).map_err(sqlite_error)?;
+        stmt.execute((name, key, value)).map_err(sqlite_error)?;
+        Ok(())
+    }
+
+    fn delete(&mut self, space: Space, key: &[u8]) -> Result<bool, KvError> {
+        let (table, name) = locate(space);
+        let sql = format!("DELETE FROM {} WHERE tbl = ?1 AND key = ?2", table);
+        let mut stmt = self.0.prepare_cached(&sql).map_err(sqlite_error)?;
+        let deleted = stmt.execute((name, key)).map_err(sqlite_error)?;
+        Ok(deleted > 0)
+    }
+}
+
+impl SqliteEngine {
+    /// 事务失败时 SQLite 会回滚，锁被 poison 之后连接仍然可以继续使用
+    fn conn(&self) -> MutexGuard<'_, Connection> {
+        self.conn.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl Engine for SqliteEngine {
+    const NAME: &'static str = "sqlite";
+
+    fn read<T>(&self, f: impl FnOnce(&dyn ReadTxn) -> Result<T, KvError>) -> Result<T, KvError> {
+        let mut conn = self.conn();
+        let txn = conn.transaction().map_err(sqlite_error)?;
+        let result = f(&SqliteTxn(&txn));
+        txn.finish().map_err(sqlite_error)?;
+        result
+    }
+
+    fn write<T>(
+        &self,
+        f: impl FnOnce(&mut dyn WriteTxn) -> Result<T, KvError>,
+    ) -> Result<T, KvError> {
+        let mut conn = self.conn();
+        let txn = conn
+            .transaction_with_behavior(TransactionBehavior::Immediate)
+            .map_err(sqlite_error)?;
+        match f(&mut SqliteTxn(&txn)) {
+            Ok(v) => {
+                txn.commit().map_err(sqlite_error)?;
+                Ok(v)
+            }
+            Err(e) => {
+                txn.rollback().map_err(sqlite_error)?;
+                Err(e)
+            }
+        }
+    }
+
+    /// 把 WAL 中的修改写回数据库文件
+    fn flush(&self) -> Result<(), KvError> {
+        self.conn()
+            .query_row("PRAGMA wal_checkpoint(TRUNCATE)", [], |_| Ok(()))
+            .map_err(sqlite_error)
+    }
+
+    /// VACUUM 重写数据库文件，回收删除的数据占用的页
+    fn compact(&self) -> Result<u64, KvError> {
+        let before = self.disk_usage()?.unwrap_or(0);
+        self.conn().execute_batch("VACUUM").map_err(sqlite_error)?;
+        self.flush()?;
+        let after = self.disk_usage()?.unwrap_or(0);
+        Ok(before.saturating_sub(after))
+    }
+
+    /// 数据库文件加上 WAL 文件的大小
+    fn disk_usage(&self) -> Result<Option<u64>, KvError> {
+        let wal = std::fs::metadata(format!("{}-wal", self.path)).map_or(0, |m| m.len());
+        Ok(Some(std::fs::metadata(&self.path)?.len() + wal))
+    }
+}
+
+#[cfg(test)]
+mod sqlite_tests {
+    use tempfile::tempdir;
+
+    use super::Sqlite;
+    use crate::{
+        storage::tests::{
+            test_apply_batch, test_basic_interface, test_binary_keys, test_compare_and_swap,
+            test_expiration, test_get_all, test_get_iter, test_incr, test_index, test_mget_mset,
+            test_range, test_scan_prefix, test_set_if, test_stats, test_table_management,
+            test_update, test_version, test_zset,
+        },
+        Storage,
+    };
+
+    fn open() -> (tempfile::TempDir, Sqlite) {
+        let dir = tempdir().unwrap();
+        let store = Sqlite::open(dir.path().join("kv.db")).unwrap();
+        (dir, store)
+    }
+
+    #[test]
+    fn sqlite_basic_interface_should_work() {
+        let (_dir, store) = open();
+        test_basic_interface(store);
+    }
+
+    #[test]
+    fn sqlite_get_all_should_work() {
+        let (_dir, store) = open();
+        test_get_all(store);
+    }
+
+    #[test]
+    fn sqlite_iter_should_work() {
+        let (_dir, store) = open();
+        test_get_iter(store);
+    }
+
+    #[test]
+    fn sqlite_expiration_should_work() {
+        let (_dir, store) = open();
+        test_expiration(store);
+    }
+
+    #[test]
+    fn sqlite_compare_and_swap_should_work() {
+        let (_dir, store) = open();
+        test_compare_and_swap(store);
+    }
+
+    #[test]
+    fn sqlite_incr_should_work() {
+        let (_dir, store) = open();
+        test_incr(store);
+    }
+
+    #[test]
+    fn sqlite_update_should_work() {
+        let (_dir, store) = open();
+        test_update(store);
+    }
+
+    #[test]
+    fn sqlite_binary_keys_should_work() {
+        let (_dir, store) = open();
+        test_binary_keys(store);
+    }
+
+    #[test]
+    fn sqlite_zset_should_work() {
+        let (_dir, store) = open();
+        test_zset(store);
+    }
+
+    #[test]
+    fn sqlite_index_should_work() {
+        let (_dir, store) = open();
+        test_index(store);
+    }
+
+    #[test]
+    fn sqlite_range_should_work() {
+        let (_dir, store) = open();
+        test_range(store);
+    }
+
+    #[test]
+    fn sqlite_scan_prefix_should_work() {
+        let (_dir, store) = open();
+        test_scan_prefix(store);
+    }
+
+    #[test]
+    fn sqlite_table_management_should_work() {
+        let (_dir, store) = open();
+        test_table_management(store);
+    }
+
+    #[test]
+    fn sqlite_stats_should_work() {
+        let (_dir, store) = open();
+        test_stats(store);
+    }
+
+    #[test]
+    fn sqlite_apply_batch_should_work() {
+        let (_dir, store) = open();
+        test_apply_batch(store);
+    }
+
+    #[test]
+    fn sqlite_mget_mset_should_work() {
+        let (_dir, store) = open();
+        test_mget_mset(store);
+    }
+
+    #[test]
+    fn sqlite_set_if_should_work() {
+        let (_dir, store) = open();
+        test_set_if(store);
+    }
+
+    #[test]
+    fn sqlite_version_should_work() {
+        let (_dir, store) = open();
+        test_version(store);
+    }
+
+    #[test]
+    fn sqlite_should_keep_data_after_reopen() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("kv.db");
+        let store = Sqlite::open(&path).unwrap();
+        store.set("t1", "k1", "v1".into()).unwrap();
+        store.set("t2", "k1", "v1".into()).unwrap();
+        store.del("t2", "k1").unwrap();
+        store.zadd("t3", "z1", vec![("m1", 1.0).into()]).unwrap();
+        drop(store);
+
+        let store = Sqlite::open(&path).unwrap();
+        assert_eq!(store.get("t1", "k1").unwrap(), Some("v1".into()));
+        assert_eq!(store.tables().unwrap(), ["t1"]);
+        assert_eq!(store.zrange("t3", "z1", 0, -1).unwrap().len(), 1);
+        assert_eq!(store.backend(), "sqlite");
+        assert!(store.compact().is_ok());
+        assert!(store.disk_usage().unwrap().unwrap() > 0);
+    }
+}
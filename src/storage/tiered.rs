@@ -0,0 +1,494 @@
+use super::{memory::MemTable, sled_db::SledDB, KeyVersion, Storage, TableStats};
+use crate::{
+    config::{parse_size, CacheInvalidation, EvictionPolicy, TieredConfig},
+    error::KvError,
+    pb::abi::{IndexSpec, Kvpair, ScoredMember, Value, WalRecord},
+};
+use bytes::Bytes;
+use std::{
+    collections::hash_map::RandomState,
+    hash::BuildHasher,
+    ops::RangeBounds,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard,
+    },
+    time::Duration,
+};
+
+/// 默认的缓存大小
+const DEFAULT_CACHE_SIZE: usize = 64 * 1024 * 1024;
+
+/// 分段锁的数量
+const LOCK_STRIPES: usize = 64;
+
+/// sled 前面加一层 MemTable 缓存：读取先查缓存，没有命中时从 sled 加载到缓存；
+/// 写入先写 sled，再按 invalidation 更新或者删除缓存中的 key。缓存超过上限之后淘汰最久没有访问的 key
+pub struct Tiered {
+    cache: MemTable,
+    store: SledDB,
+    invalidation: CacheInvalidation,
+    /// 修改整个 table 或者多个 key 时持有写锁，其它操作持有读锁
+    table_lock: RwLock<()>,
+    /// 从 sled 加载一个 key 和修改这个 key 持有同一个分段锁，加载到缓存中的不会是已经被覆盖的旧值
+    stripes: Vec<Mutex<()>>,
+    hasher: RandomState,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl Tiered {
+    pub fn open(config: &TieredConfig) -> Result<Self, KvError> {
+        let cache_size = match &config.cache_size {
+            Some(size) => parse_size(size)?,
+            None => DEFAULT_CACHE_SIZE,
+        };
+        Ok(Self::new(
+            SledDB::open(&config.path)?,
+            cache_size,
+            config.invalidation,
+        ))
+    }
+
+    /// cache_size 是缓存的内存上限（字节）
+    pub fn new(store: SledDB, cache_size: usize, invalidation: CacheInvalidation) -> Self {
+        let cache = MemTable::new();
+        if let Some(memory) = cache.memory_limit() {
+            memory.set_max_memory(cache_size.max(1));
+            memory.set_policy(EvictionPolicy::Lru);
+        }
+        Self {
+            cache,
+            store,
+            invalidation,
+            table_lock: RwLock::new(()),
+            stripes: (0..LOCK_STRIPES).map(|_| Mutex::new(())).collect(),
+            hasher: RandomState::new(),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// 读取命中和没有命中缓存的次数
+    pub fn cache_stats(&self) -> (u64, u64) {
+        (
+            self.hits.load(Ordering::Relaxed),
+            self.misses.load(Ordering::Relaxed),
+        )
+    }
+
+    fn lock_key(&self, table: &str, key: &[u8]) -> (RwLockReadGuard<'_, ()>, MutexGuard<'_, ()>) {
+        let shared = self.table_lock.read().unwrap_or_else(|e| e.into_inner());
+        let stripe = self.hasher.hash_one((table, key)) as usize % LOCK_STRIPES;
+        let guard = self.stripes[stripe]
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        (shared, guard)
+    }
+
+    fn lock_all(&self) -> RwLockWriteGuard<'_, ()> {
+        self.table_lock.write().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// 从缓存读取，没有命中时从 sled 加载，连同剩余的存活时间一起放入缓存
+    fn load(&self, table: &str, key: &[u8]) -> Result<Option<Value>, KvError> {
+        if let Some(value) = self.cache.get(table, key)? {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            return Ok(Some(value));
+        }
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        let _guard = self.lock_key(table, key);
+        // 等锁的时候可能已经有别的线程加载过了
+        if let Some(value) = self.cache.get(table, key)? {
+            return Ok(Some(value));
+        }
+        let Some(value) = self.store.get(table, key)? else {
+            return Ok(None);
+        };
+        let ttl = self.store.ttl(table, key)?;
+        self.cache_value(table, key, &value, ttl);
+        Ok(Some(value))
+    }
+
+    /// 放入缓存失败时删掉缓存中的 key，不能留下旧的值
+    fn cache_value(&self, table: &str, key: &[u8], value: &Value, ttl: Option<Duration>) {
+        let res = self
+            .cache
+            .set(table, key, value.clone())
+            .and_then(|_| match ttl {
+                Some(ttl) => self.cache.expire(table, key, ttl).map(|_| ()),
+                None => Ok(()),
+            });
+        if res.is_err() {
+            self.cache.del(table, key).ok();
+        }
+    }
+
+    /// key 写入 sled 之后调用：value 是写入之后的值并且没有过期时间时按策略更新缓存，否则删掉缓存中的 key
+    fn written(&self, table: &str, key: &[u8], value: Option<&Value>) {
+        match (self.invalidation, value) {
+            (CacheInvalidation::Update, Some(value)) => self.cache_value(table, key, value, None),
+            _ => {
+                self.cache.del(table, key).ok();
+            }
+        }
+    }
+}
+
+impl Storage for Tiered {
+    fn get(
+        &self,
+        table: impl Into<String>,
+        key: impl AsRef<[u8]>,
+    ) -> Result<Option<Value>, KvError> {
+        self.load(&table.into(), key.as_ref())
+    }
+
+    fn set(
+        &self,
+        table: impl Into<String>,
+        key: impl AsRef<[u8]>,
+        value: Value,
+    ) -> Result<Option<Value>, KvError> {
+        let (table, key) = (table.into(), key.as_ref());
+        let _guard = self.lock_key(&table, key);
+        let old = self.store.set(table.as_str(), key, value.clone())?;
+        self.written(&table, key, Some(&value));
+        Ok(old)
+    }
+
+    fn compare_and_swap(
+        &self,
+        table: impl Into<String>,
+        key: impl AsRef<[u8]>,
+        expected: Option<Value>,
+        value: Value,
+    ) -> Result<(bool, Option<Value>), KvError> {
+        let (table, key) = (table.into(), key.as_ref());
+        let _guard = self.lock_key(&table, key);
+        let (swapped, current) =
+            self.store
+                .compare_and_swap(table.as_str(), key, expected, value)?;
+        if swapped {
+            self.written(&table, key, current.as_ref());
+        }
+        Ok((swapped, current))
+    }
+
+    /// 不改变过期时间，缓存中的 key 直接删掉
+    fn incr(
+        &self,
+        table: impl Into<String>,
+        key: impl AsRef<[u8]>,
+        delta: i64,
+    ) -> Result<i64, KvError> {
+        let (table, key) = (table.into(), key.as_ref());
+        let _guard = self.lock_key(&table, key);
+        let value = self.store.incr(table.as_str(), key, delta)?;
+        self.written(&table, key, None);
+        Ok(value)
+    }
+
+    fn update<T>(
+        &self,
+        table: impl Into<String>,
+        key: impl AsRef<[u8]>,
+        f: impl FnMut(Option<&Value>) -> Result<(Value, T), KvError>,
+    ) -> Result<T, KvError> {
+        let (table, key) = (table.into(), key.as_ref());
+        let _guard = self.lock_key(&table, key);
+        let result = self.store.update(table.as_str(), key, f)?;
+        self.written(&table, key, None);
+        Ok(result)
+    }
+
+    fn contains(&self, table: impl Into<String>, key: impl AsRef<[u8]>) -> Result<bool, KvError> {
+        Ok(self.load(&table.into(), key.as_ref())?.is_some())
+    }
+
+    fn del(
+        &self,
+        table: impl Into<String>,
+        key: impl AsRef<[u8]>,
+    ) -> Result<Option<Value>, KvError> {
+        let (table, key) = (table.into(), key.as_ref());
+        let _guard = self.lock_key(&table, key);
+        let old = self.store.del(table.as_str(), key)?;
+        self.written(&table, key, None);
+        Ok(old)
+    }
+
+    fn mset(&self, table: impl Into<String>, pairs: Vec<Kvpair>) -> Result<(), KvError> {
+        let table = table.into();
+        let _guard = self.lock_all();
+        self.store.mset(table.as_str(), pairs.clone())?;
+        for pair in &pairs {
+            self.written(&table, &pair.key, pair.value.as_ref());
+        }
+        Ok(())
+    }
+
+    fn get_all(&self, table: impl Into<String>) -> Result<Vec<Kvpair>, KvError> {
+        self.store.get_all(table)
+    }
+
+    fn get_iter(&self, table: impl Into<String>) -> Result<impl Iterator<Item = Kvpair>, KvError> {
+        self.store.get_iter(table)
+    }
+
+    fn scan_prefix(
+        &self,
+        table: impl Into<String>,
+        prefix: impl AsRef<[u8]>,
+    ) -> Result<impl Iterator<Item = Kvpair>, KvError> {
+        self.store.scan_prefix(table, prefix)
+    }
+
+    fn range(
+        &self,
+        table: impl Into<String>,
+        range: impl RangeBounds<Bytes>,
+    ) -> Result<Vec<Kvpair>, KvError> {
+        self.store.range(table, range)
+    }
+
+    fn scan_after(
+        &self,
+        table: impl Into<String>,
+        start: Option<Bytes>,
+        limit: usize,
+    ) -> Result<Vec<Kvpair>, KvError> {
+        self.store.scan_after(table, start, limit)
+    }
+
+    fn expire(
+        &self,
+        table: impl Into<String>,
+        key: impl AsRef<[u8]>,
+        ttl: Duration,
+    ) -> Result<bool, KvError> {
+        let (table, key) = (table.into(), key.as_ref());
+        let _guard = self.lock_key(&table, key);
+        let expired = self.store.expire(table.as_str(), key, ttl)?;
+        self.written(&table, key, None);
+        Ok(expired)
+    }
+
+    fn ttl(
+        &self,
+        table: impl Into<String>,
+        key: impl AsRef<[u8]>,
+    ) -> Result<Option<Duration>, KvError> {
+        self.store.ttl(table, key)
+    }
+
+    /// 缓存中的 key 带着同样的过期时间，会自己过期
+    fn purge_expired(&self) -> Result<usize, KvError> {
+        self.cache.purge_expired()?;
+        self.store.purge_expired()
+    }
+
+    fn tables(&self) -> Result<Vec<String>, KvError> {
+        self.store.tables()
+    }
+
+    fn backend(&self) -> &'static str {
+        "tiered"
+    }
+
+    fn stats(&self) -> Result<Vec<(String, TableStats)>, KvError> {
+        self.store.stats()
+    }
+
+    fn truncate_table(&self, table: impl Into<String>) -> Result<usize, KvError> {
+        let table = table.into();
+        let _guard = self.lock_all();
+        let count = self.store.truncate_table(table.as_str())?;
+        self.cache.drop_table(table)?;
+        Ok(count)
+    }
+
+    fn drop_table(&self, table: impl Into<String>) -> Result<usize, KvError> {
+        let table = table.into();
+        let _guard = self.lock_all();
+        let count = self.store.drop_table(table.as_str())?;
+        self.cache.drop_table(table)?;
+        Ok(count)
+    }
+
+    fn version(&self, table: impl Into<String>, key: impl AsRef<[u8]>) -> Result<u64, KvError> {
+        self.store.version(table, key)
+    }
+
+    /// 整组记录写入 sled 之后，删掉缓存中涉及的 key
+    fn apply_batch(
+        &self,
+        records: Vec<WalRecord>,
+        watched: &[KeyVersion],
+    ) -> Result<bool, KvError> {
+        let _guard = self.lock_all();
+        let keys: Vec<_> = records
+            .iter()
+            .map(|r| (r.table.clone(), r.key.clone()))
+            .collect();
+        if !self.store.apply_batch(records, watched)? {
+            return Ok(false);
+        }
+        for (table, key) in keys {
+            self.cache.del(table, key)?;
+        }
+        Ok(true)
+    }
+
+    fn flush(&self) -> Result<(), KvError> {
+        self.store.flush()
+    }
+
+    fn compact(&self) -> Result<u64, KvError> {
+        self.store.compact()
+    }
+
+    fn disk_usage(&self) -> Result<Option<u64>, KvError> {
+        self.store.disk_usage()
+    }
+
+    fn is_blocking(&self) -> bool {
+        true
+    }
+
+    fn snapshot(&self) -> Result<Vec<WalRecord>, KvError> {
+        self.store.snapshot()
+    }
+
+    /// sorted set 和索引不经过缓存
+    fn zadd(
+        &self,
+        table: impl Into<String>,
+        key: impl AsRef<[u8]>,
+        members: Vec<ScoredMember>,
+    ) -> Result<usize, KvError> {
+        self.store.zadd(table, key, members)
+    }
+
+    fn zrange(
+        &self,
+        table: impl Into<String>,
+        key: impl AsRef<[u8]>,
+        start: i64,
+        stop: i64,
+    ) -> Result<Vec<ScoredMember>, KvError> {
+        self.store.zrange(table, key, start, stop)
+    }
+
+    fn zrange_by_score(
+        &self,
+        table: impl Into<String>,
+        key: impl AsRef<[u8]>,
+        min: f64,
+        max: f64,
+    ) -> Result<Vec<ScoredMember>, KvError> {
+        self.store.zrange_by_score(table, key, min, max)
+    }
+
+    fn zsets(&self) -> Result<Vec<(String, Bytes)>, KvError> {
+        self.store.zsets()
+    }
+
+    fn create_index(&self, table: impl Into<String>, spec: IndexSpec) -> Result<bool, KvError> {
+        self.store.create_index(table, spec)
+    }
+
+    fn query(
+        &self,
+        table: impl Into<String>,
+        index: &str,
+        value: &Value,
+    ) -> Result<Vec<Kvpair>, KvError> {
+        self.store.query(table, index, value)
+    }
+
+    fn indexes(&self) -> Result<Vec<(String, IndexSpec)>, KvError> {
+        self.store.indexes()
+    }
+}
+
+#[cfg(test)]
+mod tiered_tests {
+    use tempfile::{tempdir, TempDir};
+
+    use super::*;
+    use crate::storage::tests::{
+        test_apply_batch, test_basic_interface, test_compare_and_swap, test_expiration,
+        test_get_all, test_incr, test_mget_mset, test_set_if, test_table_management, test_update,
+    };
+
+    fn tiered(invalidation: CacheInvalidation) -> (Tiered, TempDir) {
+        let dir = tempdir().unwrap();
+        let store = Tiered::new(SledDB::new(dir.path()), DEFAULT_CACHE_SIZE, invalidation);
+        (store, dir)
+    }
+
+    #[test]
+    fn tiered_should_pass_storage_tests() {
+        for invalidation in [CacheInvalidation::Update, CacheInvalidation::Evict] {
+            test_basic_interface(tiered(invalidation).0);
+            test_get_all(tiered(invalidation).0);
+            test_expiration(tiered(invalidation).0);
+            test_compare_and_swap(tiered(invalidation).0);
+            test_incr(tiered(invalidation).0);
+            test_update(tiered(invalidation).0);
+            test_table_management(tiered(invalidation).0);
+            test_apply_batch(tiered(invalidation).0);
+            test_mget_mset(tiered(invalidation).0);
+            test_set_if(tiered(invalidation).0);
+        }
+    }
+
+    #[test]
+    fn reads_should_be_served_from_cache() {
+        let (store, _dir) = tiered(CacheInvalidation::Update);
+        store.set("t1", "k1", "v1".into()).unwrap();
+        // 写入时已经放入了缓存
+        assert_eq!(store.get("t1", "k1").unwrap(), Some("v1".into()));
+        assert_eq!(store.cache_stats(), (1, 0));
+
+        let (store, _dir) = tiered(CacheInvalidation::Evict);
+        store.set("t1", "k1", "v1".into()).unwrap();
+        assert_eq!(store.get("t1", "k1").unwrap(), Some("v1".into()));
+        assert_eq!(store.get("t1", "k1").unwrap(), Some("v1".into()));
+        assert_eq!(store.cache_stats(), (1, 1));
+        // 写入之后缓存中的 key 被删掉，下次从 sled 读到新的值
+        store.incr("t1", "n", 1).unwrap();
+        store.set("t1", "k1", "v2".into()).unwrap();
+        assert_eq!(store.get("t1", "k1").unwrap(), Some("v2".into()));
+        assert_eq!(store.cache_stats(), (1, 2));
+    }
+
+    #[test]
+    fn cached_keys_should_keep_ttl() {
+        let (store, _dir) = tiered(CacheInvalidation::Update);
+        store.set("t1", "k1", "v1".into()).unwrap();
+        store.expire("t1", "k1", Duration::from_millis(50)).unwrap();
+        assert_eq!(store.get("t1", "k1").unwrap(), Some("v1".into()));
+        std::thread::sleep(Duration::from_millis(100));
+        assert_eq!(store.get("t1", "k1").unwrap(), None);
+    }
+
+    #[test]
+    fn cache_should_evict_keys_over_size() {
+        let dir = tempdir().unwrap();
+        let store = Tiered::new(SledDB::new(dir.path()), 4096, CacheInvalidation::Update);
+        for i in 0..200 {
+            store
+                .set("t1", format!("k{}", i), "v".repeat(64).into())
+                .unwrap();
+        }
+        let cached = store.cache.get_all("t1").unwrap().len();
+        assert!(cached > 0 && cached < 200, "{} keys cached", cached);
+        // 被淘汰的 key 仍然可以从 sled 读到
+        for i in 0..200 {
+            let value = store.get("t1", format!("k{}", i)).unwrap();
+            assert_eq!(value, Some("v".repeat(64).into()));
+        }
+    }
+}
@@ -0,0 +1,786 @@
+use super::{
+    eviction::MemoryLimit, incr_value, index::check_spec, memory::MemTable, now_millis,
+    quota::Quotas, zset::check_score, KeyVersion, Storage, TableStats,
+};
+use crate::{
+    config::FsyncPolicy,
+    error::KvError,
+    pb::abi::{wal_record::Op, IndexSpec, Kvpair, ScoredMember, Value, WalRecord},
+};
+use bytes::Bytes;
+use prost::Message;
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{BufWriter, Read, Write},
+    ops::RangeBounds,
+    path::{Path, PathBuf},
+    sync::{Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
+use tracing::{info, warn};
+
+/// EverySec 策略下两次 fsync 之间的间隔
+const FSYNC_INTERVAL: Duration = Duration::from_secs(1);
+
+/// 带 WAL 的 MemTable：读写都在内存里完成，修改操作先追加写入 WAL，
+/// 重启时重放 WAL 恢复数据
+#[derive(Debug)]
+pub struct WalMemTable {
+    table: MemTable,
+    wal: Mutex<WalWriter>,
+    path: PathBuf,
+}
+
+#[derive(Debug)]
+struct WalWriter {
+    file: File,
+    fsync: FsyncPolicy,
+    last_sync: Instant,
+}
+
+impl WalMemTable {
+    /// 打开（或创建）WAL 文件，并重放其中的记录
+    pub fn open(path: impl AsRef<Path>, fsync: FsyncPolicy) -> Result<Self, KvError> {
+        Self::open_with(path, fsync, MemTable::new())
+    }
+
+    /// 和 open 一样，记录重放到给定的（空的）MemTable 中
+    pub fn open_with(
+        path: impl AsRef<Path>,
+        fsync: FsyncPolicy,
+        table: MemTable,
+    ) -> Result<Self, KvError> {
+        let path = path.as_ref().to_path_buf();
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(&path)?;
+        let mut data = Vec::new();
+        file.read_to_end(&mut data)?;
+
+        let (count, len) = replay(&table, &data);
+        // 最后一条记录没写完（比如写到一半进程崩溃），把它截掉，之后的记录才能接着写
+        if len < data.len() {
+            warn!("Truncate {} bytes of broken WAL tail", data.len() - len);
+            file.set_len(len as u64)?;
+        }
+        info!("Replayed {} WAL records", count);
+
+        Ok(Self {
+            table,
+            wal: Mutex::new(WalWriter {
+                file,
+                fsync,
+                last_sync: Instant::now(),
+            }),
+            path,
+        })
+    }
+
+    fn lock(&self) -> MutexGuard<'_, WalWriter> {
+        self.wal.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// 写入之前腾出内存，被淘汰的 key 作为 DEL 写入 WAL，这样重启之后它们不会再出现
+    fn make_room(&self, wal: &mut WalWriter) -> Result<(), KvError> {
+        for (table, key) in self.table.make_room()? {
+            wal.append(WalRecord::new_del(&table, key))?;
+        }
+        Ok(())
+    }
+}
+
+impl WalWriter {
+    fn append(&mut self, record: WalRecord) -> Result<(), KvError> {
+        // 一条记录一次写完，减少崩溃时留下半条记录的可能
+        let buf = record.encode_length_delimited_to_vec();
+        self.file.write_all(&buf)?;
+
+        let sync = match self.fsync {
+            FsyncPolicy::Always => true,
+            FsyncPolicy::EverySec => self.last_sync.elapsed() >= FSYNC_INTERVAL,
+            FsyncPolicy::No => false,
+        };
+        if sync {
+            self.file.sync_data()?;
+            self.last_sync = Instant::now();
+        }
+        Ok(())
+    }
+}
+
+/// 按顺序重放 WAL 记录，返回重放的记录数和有效数据的长度
+pub(crate) fn replay(store: &impl Storage, data: &[u8]) -> (usize, usize) {
+    let mut buf = data;
+    let (mut count, mut len) = (0, 0);
+    while !buf.is_empty() {
+        let record = match WalRecord::decode_length_delimited(&mut buf) {
+            Ok(record) => record,
+            Err(_) => break,
+        };
+        len = data.len() - buf.len();
+        if let Err(e) = apply(store, record) {
+            warn!("Failed to replay WAL record: {:?}", e);
+        }
+        count += 1;
+    }
+    (count, len)
+}
+
+/// 把一条记录应用到 store 上
+pub(crate) fn apply(store: &impl Storage, record: WalRecord) -> Result<(), KvError> {
+    match record.op {
+        Some(Op::Set(value)) => store.set(record.table, record.key, value).map(|_| ()),
+        Some(Op::Del(_)) => store.del(record.table, record.key).map(|_| ()),
+        Some(Op::Expire(deadline)) => {
+            let ttl = Duration::from_millis(deadline.saturating_sub(now_millis()));
+            store.expire(record.table, record.key, ttl).map(|_| ())
+        }
+        Some(Op::Batch(batch)) => store.apply_batch(batch.records, &[]).map(|_| ()),
+        Some(Op::Zadd(zadd)) => store
+            .zadd(record.table, record.key, zadd.members)
+            .map(|_| ()),
+        Some(Op::CreateIndex(spec)) => store.create_index(record.table, spec).map(|_| ()),
+        Some(Op::TruncateTable(_)) => store.truncate_table(record.table).map(|_| ()),
+        Some(Op::DropTable(_)) => store.drop_table(record.table).map(|_| ()),
+        None => Ok(()),
+    }
+}
+
+impl Storage for WalMemTable {
+    fn get(
+        &self,
+        table: impl Into<String>,
+        key: impl AsRef<[u8]>,
+    ) -> Result<Option<Value>, KvError> {
+        self.table.get(table, key)
+    }
+
+    fn set(
+        &self,
+        table: impl Into<String>,
+        key: impl AsRef<[u8]>,
+        value: Value,
+    ) -> Result<Option<Value>, KvError> {
+        let (table, key) = (table.into(), key.as_ref());
+        // 持有锁直到修改完内存，保证 WAL 中的顺序和内存中的一致
+        let mut wal = self.lock();
+        self.make_room(&mut wal)?;
+        // 超过配额的写入不能写到 WAL 里，否则重启之后会出现
+        self.table.check_quota(&table, key, &value)?;
+        wal.append(WalRecord::new_set(&table, key, value.clone()))?;
+        self.table.set(table, key, value)
+    }
+
+    fn mget(
+        &self,
+        table: impl Into<String>,
+        keys: &[impl AsRef<[u8]>],
+    ) -> Result<Vec<Option<Value>>, KvError> {
+        self.table.mget(table, keys)
+    }
+
+    /// 整组 kv pair 作为一条 batch 记录写入 WAL
+    fn mset(&self, table: impl Into<String>, pairs: Vec<Kvpair>) -> Result<(), KvError> {
+        let table = table.into();
+        let records: Vec<WalRecord> = pairs
+            .iter()
+            .map(|pair| {
+                let value = pair.value.clone().unwrap_or_default();
+                WalRecord::new_set(&table, &pair.key, value)
+            })
+            .collect();
+        let mut wal = self.lock();
+        self.make_room(&mut wal)?;
+        self.table.check_batch_quota(&records)?;
+        wal.append(WalRecord::new_batch(records))?;
+        self.table.mset(table, pairs)
+    }
+
+    fn compare_and_swap(
+        &self,
+        table: impl Into<String>,
+        key: impl AsRef<[u8]>,
+        expected: Option<Value>,
+        value: Value,
+    ) -> Result<(bool, Option<Value>), KvError> {
+        let (table, key) = (table.into(), key.as_ref());
+        // 所有的写入都要先拿到锁，比较和写入之间不会有别的修改
+        let mut wal = self.lock();
+        self.make_room(&mut wal)?;
+        let current = self.table.get(table.as_str(), key)?;
+        if current != expected {
+            return Ok((false, current));
+        }
+        self.table.check_quota(&table, key, &value)?;
+        wal.append(WalRecord::new_set(&table, key, value.clone()))?;
+        self.table.set(table, key, value.clone())?;
+        Ok((true, Some(value)))
+    }
+
+    fn incr(
+        &self,
+        table: impl Into<String>,
+        key: impl AsRef<[u8]>,
+        delta: i64,
+    ) -> Result<i64, KvError> {
+        let (table, key) = (table.into(), key.as_ref());
+        let mut wal = self.lock();
+        self.make_room(&mut wal)?;
+        let current = self.table.get(table.as_str(), key)?;
+        let value = incr_value(key, current.as_ref(), delta)?;
+        self.table.check_quota(&table, key, &value.into())?;
+        // 重放时 SET 会清除过期时间，需要再记录一次
+        wal.append(WalRecord::new_set(&table, key, value.into()))?;
+        if let Some(ttl) = self.table.ttl(table.as_str(), key)? {
+            let deadline = now_millis() + ttl.as_millis() as u64;
+            wal.append(WalRecord::new_expire(&table, key, deadline))?;
+        }
+        self.table.incr(table, key, delta)
+    }
+
+    fn update<T>(
+        &self,
+        table: impl Into<String>,
+        key: impl AsRef<[u8]>,
+        mut f: impl FnMut(Option<&Value>) -> Result<(Value, T), KvError>,
+    ) -> Result<T, KvError> {
+        let (table, key) = (table.into(), key.as_ref());
+        let mut wal = self.lock();
+        self.make_room(&mut wal)?;
+        let current = self.table.get(table.as_str(), key)?;
+        let (value, result) = f(current.as_ref())?;
+        self.table.check_quota(&table, key, &value)?;
+        // 和 incr 一样，重放时 SET 会清除过期时间，需要再记录一次
+        wal.append(WalRecord::new_set(&table, key, value.clone()))?;
+        if let Some(ttl) = self.table.ttl(table.as_str(), key)? {
+            let deadline = now_millis() + ttl.as_millis() as u64;
+            wal.append(WalRecord::new_expire(&table, key, deadline))?;
+        }
+        self.table.update(table, key, |_| Ok((value.clone(), ())))?;
+        Ok(result)
+    }
+
+    fn contains(&self, table: impl Into<String>, key: impl AsRef<[u8]>) -> Result<bool, KvError> {
+        self.table.contains(table, key)
+    }
+
+    fn del(
+        &self,
+        table: impl Into<String>,
+        key: impl AsRef<[u8]>,
+    ) -> Result<Option<Value>, KvError> {
+        let (table, key) = (table.into(), key.as_ref());
+        let mut wal = self.lock();
+        wal.append(WalRecord::new_del(&table, key))?;
+        self.table.del(table, key)
+    }
+
+    fn get_all(&self, table: impl Into<String>) -> Result<Vec<Kvpair>, KvError> {
+        self.table.get_all(table)
+    }
+
+    fn get_iter(&self, table: impl Into<String>) -> Result<impl Iterator<Item = Kvpair>, KvError> {
+        self.table.get_iter(table)
+    }
+
+    fn scan_prefix(
+        &self,
+        table: impl Into<String>,
+        prefix: impl AsRef<[u8]>,
+    ) -> Result<impl Iterator<Item = Kvpair>, KvError> {
+        self.table.scan_prefix(table, prefix)
+    }
+
+    fn range(
+        &self,
+        table: impl Into<String>,
+        range: impl RangeBounds<Bytes>,
+    ) -> Result<Vec<Kvpair>, KvError> {
+        self.table.range(table, range)
+    }
+
+    fn scan_after(
+        &self,
+        table: impl Into<String>,
+        start: Option<Bytes>,
+        limit: usize,
+    ) -> Result<Vec<Kvpair>, KvError> {
+        self.table.scan_after(table, start, limit)
+    }
+
+    fn expire(
+        &self,
+        table: impl Into<String>,
+        key: impl AsRef<[u8]>,
+        ttl: Duration,
+    ) -> Result<bool, KvError> {
+        let (table, key) = (table.into(), key.as_ref());
+        let mut wal = self.lock();
+        if !self.table.contains(table.as_str(), key)? {
+            return Ok(false);
+        }
+        // 记录绝对时间，这样重启之后剩余的存活时间仍然是对的
+        let deadline = now_millis() + ttl.as_millis() as u64;
+        wal.append(WalRecord::new_expire(&table, key, deadline))?;
+        self.table.expire(table, key, ttl)
+    }
+
+    fn ttl(
+        &self,
+        table: impl Into<String>,
+        key: impl AsRef<[u8]>,
+    ) -> Result<Option<Duration>, KvError> {
+        self.table.ttl(table, key)
+    }
+
+    /// 过期的 key 重放时同样会过期，清理操作不需要写入 WAL
+    fn purge_expired(&self) -> Result<usize, KvError> {
+        self.table.purge_expired()
+    }
+
+    fn tables(&self) -> Result<Vec<String>, KvError> {
+        self.table.tables()
+    }
+
+    fn backend(&self) -> &'static str {
+        "wal"
+    }
+
+    fn stats(&self) -> Result<Vec<(String, TableStats)>, KvError> {
+        self.table.stats()
+    }
+
+    fn truncate_table(&self, table: impl Into<String>) -> Result<usize, KvError> {
+        let table = table.into();
+        let mut wal = self.lock();
+        wal.append(WalRecord::new_truncate_table(&table))?;
+        self.table.truncate_table(table)
+    }
+
+    fn drop_table(&self, table: impl Into<String>) -> Result<usize, KvError> {
+        let table = table.into();
+        let mut wal = self.lock();
+        wal.append(WalRecord::new_drop_table(&table))?;
+        self.table.drop_table(table)
+    }
+
+    /// 整组记录作为一条 WAL 记录写入，重放时要么全部生效，要么整条被截掉
+    fn version(&self, table: impl Into<String>, key: impl AsRef<[u8]>) -> Result<u64, KvError> {
+        self.table.version(table, key)
+    }
+
+    /// 写入都持有 WAL 的锁，检查完版本号之后不会再有别的修改
+    fn apply_batch(
+        &self,
+        records: Vec<WalRecord>,
+        watched: &[KeyVersion],
+    ) -> Result<bool, KvError> {
+        let mut wal = self.lock();
+        for w in watched {
+            if self.table.version(w.table.as_str(), &w.key)? != w.version {
+                return Ok(false);
+            }
+        }
+        if records.iter().any(|r| matches!(r.op, Some(Op::Set(_)))) {
+            self.make_room(&mut wal)?;
+        }
+        self.table.check_batch_quota(&records)?;
+        wal.append(WalRecord::new_batch(records.clone()))?;
+        self.table.apply_batch(records, &[])
+    }
+
+    /// 不管 fsync 策略是什么，都把 WAL 刷到磁盘
+    fn flush(&self) -> Result<(), KvError> {
+        let mut wal = self.lock();
+        wal.file.sync_data()?;
+        wal.last_sync = Instant::now();
+        Ok(())
+    }
+
+    /// 用当前的数据重写 WAL：先写到临时文件，再替换原来的文件。重写期间持有 WAL 的锁，修改会等待重写完成
+    fn compact(&self) -> Result<u64, KvError> {
+        let mut wal = self.lock();
+        let before = wal.file.metadata()?.len();
+        let mut tmp = self.path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        let mut writer = BufWriter::new(File::create(&tmp)?);
+        for record in self.table.snapshot()? {
+            writer.write_all(&record.encode_length_delimited_to_vec())?;
+        }
+        writer
+            .into_inner()
+            .map_err(|e| e.into_error())?
+            .sync_all()?;
+        fs::rename(&tmp, &self.path)?;
+        wal.file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .open(&self.path)?;
+        wal.last_sync = Instant::now();
+        let after = wal.file.metadata()?.len();
+        Ok(before.saturating_sub(after))
+    }
+
+    fn disk_usage(&self) -> Result<Option<u64>, KvError> {
+        Ok(Some(self.lock().file.metadata()?.len()))
+    }
+
+    fn zadd(
+        &self,
+        table: impl Into<String>,
+        key: impl AsRef<[u8]>,
+        members: Vec<ScoredMember>,
+    ) -> Result<usize, KvError> {
+        let (table, key) = (table.into(), key.as_ref());
+        // 先检查 score，不合法的记录不写入 WAL
+        members.iter().try_for_each(|m| check_score(m.score))?;
+        let mut wal = self.lock();
+        wal.append(WalRecord::new_zadd(&table, key, members.clone()))?;
+        self.table.zadd(table, key, members)
+    }
+
+    fn zrange(
+        &self,
+        table: impl Into<String>,
+        key: impl AsRef<[u8]>,
+        start: i64,
+        stop: i64,
+    ) -> Result<Vec<ScoredMember>, KvError> {
+        self.table.zrange(table, key, start, stop)
+    }
+
+    fn zrange_by_score(
+        &self,
+        table: impl Into<String>,
+        key: impl AsRef<[u8]>,
+        min: f64,
+        max: f64,
+    ) -> Result<Vec<ScoredMember>, KvError> {
+        self.table.zrange_by_score(table, key, min, max)
+    }
+
+    fn zsets(&self) -> Result<Vec<(String, Bytes)>, KvError> {
+        self.table.zsets()
+    }
+
+    /// 只有新建了索引时才写入 WAL
+    fn create_index(&self, table: impl Into<String>, spec: IndexSpec) -> Result<bool, KvError> {
+        let table = table.into();
+        check_spec(&spec)?;
+        let mut wal = self.lock();
+        let exists = self
+            .table
+            .indexes()?
+            .iter()
+            .any(|(t, s)| *t == table && s.name == spec.name);
+        if exists {
+            return Ok(false);
+        }
+        wal.append(WalRecord::new_create_index(&table, spec.clone()))?;
+        self.table.create_index(table, spec)
+    }
+
+    fn query(
+        &self,
+        table: impl Into<String>,
+        index: &str,
+        value: &Value,
+    ) -> Result<Vec<Kvpair>, KvError> {
+        self.table.query(table, index, value)
+    }
+
+    fn indexes(&self) -> Result<Vec<(String, IndexSpec)>, KvError> {
+        self.table.indexes()
+    }
+
+    /// 写入时要追加 WAL 文件
+    fn is_blocking(&self) -> bool {
+        true
+    }
+
+    fn memory_limit(&self) -> Option<&MemoryLimit> {
+        self.table.memory_limit()
+    }
+
+    fn quotas(&self) -> Option<&Quotas> {
+        self.table.quotas()
+    }
+
+    /// 导出期间持有 WAL 的锁，不会有写入，导出的数据是一致的
+    fn snapshot(&self) -> Result<Vec<WalRecord>, KvError> {
+        let _wal = self.lock();
+        self.table.snapshot()
+    }
+}
+
+#[cfg(test)]
+mod wal_test {
+    use super::WalMemTable;
+    use crate::{
+        config::FsyncPolicy,
+        pb::abi::Kvpair,
+        storage::tests::{
+            test_apply_batch, test_basic_interface, test_compare_and_swap, test_eviction,
+            test_expiration, test_get_all, test_get_iter, test_incr, test_index, test_mget_mset,
+            test_quota, test_range, test_scan_prefix, test_set_if, test_stats,
+            test_table_management, test_zset,
+        },
+        Storage,
+    };
+    use std::{fs::OpenOptions, io::Write, time::Duration};
+    use tempfile::tempdir;
+
+    #[test]
+    fn wal_memtable_basic_interface_should_work() {
+        let dir = tempdir().unwrap();
+        let store = WalMemTable::open(dir.path().join("kv.wal"), FsyncPolicy::Always).unwrap();
+        test_basic_interface(store);
+    }
+
+    #[test]
+    fn wal_memtable_get_all_should_work() {
+        let dir = tempdir().unwrap();
+        let store = WalMemTable::open(dir.path().join("kv.wal"), FsyncPolicy::No).unwrap();
+        test_get_all(store);
+    }
+
+    #[test]
+    fn wal_memtable_iter_should_work() {
+        let dir = tempdir().unwrap();
+        let store = WalMemTable::open(dir.path().join("kv.wal"), FsyncPolicy::No).unwrap();
+        test_get_iter(store);
+    }
+
+    #[test]
+    fn wal_memtable_expiration_should_work() {
+        let dir = tempdir().unwrap();
+        let store = WalMemTable::open(dir.path().join("kv.wal"), FsyncPolicy::EverySec).unwrap();
+        test_expiration(store);
+    }
+
+    #[test]
+    fn wal_memtable_compare_and_swap_should_work() {
+        let dir = tempdir().unwrap();
+        let store = WalMemTable::open(dir.path().join("kv.wal"), FsyncPolicy::No).unwrap();
+        test_compare_and_swap(store);
+    }
+
+    #[test]
+    fn wal_memtable_range_should_work() {
+        let dir = tempdir().unwrap();
+        let store = WalMemTable::open(dir.path().join("kv.wal"), FsyncPolicy::No).unwrap();
+        test_range(store);
+    }
+
+    #[test]
+    fn wal_memtable_scan_prefix_should_work() {
+        let dir = tempdir().unwrap();
+        let store = WalMemTable::open(dir.path().join("kv.wal"), FsyncPolicy::No).unwrap();
+        test_scan_prefix(store);
+    }
+
+    #[test]
+    fn wal_memtable_table_management_should_work() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("kv.wal");
+        let store = WalMemTable::open(&path, FsyncPolicy::No).unwrap();
+        test_table_management(store);
+
+        // 重放之后被清空和删除的 table 仍然是空的
+        let store = WalMemTable::open(&path, FsyncPolicy::No).unwrap();
+        assert_eq!(store.tables().unwrap(), ["t7"]);
+        assert_eq!(
+            store.get_all("t7").unwrap(),
+            vec![Kvpair::new("k2", "v1".into())]
+        );
+        assert_eq!(store.indexes().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn wal_memtable_stats_should_work() {
+        let dir = tempdir().unwrap();
+        let store = WalMemTable::open(dir.path().join("kv.wal"), FsyncPolicy::No).unwrap();
+        test_stats(store);
+    }
+
+    #[test]
+    fn wal_memtable_apply_batch_should_work() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("kv.wal");
+        let store = WalMemTable::open(&path, FsyncPolicy::No).unwrap();
+        test_apply_batch(store);
+
+        // 整组记录重放之后仍然生效，版本号冲突没有生效的不会写入 WAL
+        let store = WalMemTable::open(&path, FsyncPolicy::No).unwrap();
+        assert_eq!(store.get("t6", "k1").unwrap(), None);
+        assert_eq!(store.get("t6", "k3").unwrap(), Some("v4".into()));
+        assert_eq!(store.get("t6", "k2").unwrap(), Some("v6".into()));
+        assert!(store.ttl("t6", "k3").unwrap().is_some());
+    }
+
+    #[test]
+    fn wal_memtable_set_if_should_work() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("kv.wal");
+        let store = WalMemTable::open(&path, FsyncPolicy::No).unwrap();
+        test_set_if(store);
+    }
+
+    #[test]
+    fn wal_memtable_mget_mset_should_work() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("kv.wal");
+        let store = WalMemTable::open(&path, FsyncPolicy::No).unwrap();
+        test_mget_mset(store);
+
+        // 重放之后整组 kv pair 都还在
+        let store = WalMemTable::open(&path, FsyncPolicy::No).unwrap();
+        let values = store.mget("t7", &["k1", "k2"]).unwrap();
+        assert_eq!(values, [Some("v2".into()), Some(2.into())]);
+        assert_eq!(store.ttl("t7", "k1").unwrap(), None);
+    }
+
+    #[test]
+    fn wal_memtable_incr_should_work() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("kv.wal");
+        let store = WalMemTable::open(&path, FsyncPolicy::No).unwrap();
+        test_incr(store);
+
+        // 重放之后值和过期时间都还在
+        let store = WalMemTable::open(&path, FsyncPolicy::No).unwrap();
+        assert_eq!(store.incr("t5", "k2", 0).unwrap(), 12);
+        assert!(store.ttl("t5", "k2").unwrap().is_some());
+    }
+
+    #[test]
+    fn wal_memtable_zset_should_work() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("kv.wal");
+        let store = WalMemTable::open(&path, FsyncPolicy::No).unwrap();
+        test_zset(store);
+
+        // 重放之后 member 和更新过的 score 都还在
+        let store = WalMemTable::open(&path, FsyncPolicy::No).unwrap();
+        let members = store.zrange("t6", "z1", -1, -1).unwrap();
+        assert_eq!(members, vec![("b", 5.0).into()]);
+        assert_eq!(store.zrange("t6", "z1", 0, -1).unwrap().len(), 4);
+    }
+
+    #[test]
+    fn wal_memtable_index_should_work() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("kv.wal");
+        let store = WalMemTable::open(&path, FsyncPolicy::No).unwrap();
+        test_index(store);
+
+        // 重放之后索引还在，并且和数据一致
+        let store = WalMemTable::open(&path, FsyncPolicy::No).unwrap();
+        assert_eq!(store.indexes().unwrap().len(), 2);
+        let pairs = store.query("t4", "by_city", &"beijing".into()).unwrap();
+        let keys: Vec<_> = pairs.into_iter().map(|p| p.key).collect();
+        assert_eq!(keys, ["k1", "k2"]);
+    }
+
+    #[test]
+    fn wal_memtable_quota_should_work() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("kv.wal");
+        let store = WalMemTable::open(&path, FsyncPolicy::No).unwrap();
+        test_quota(store);
+
+        // 被拒绝的写入没有写到 WAL 里
+        let store = WalMemTable::open(&path, FsyncPolicy::No).unwrap();
+        assert!(!store.contains("t10", "k4").unwrap());
+        assert!(!store.contains("t10", "k2").unwrap());
+        assert!(store.contains("t10", "k3").unwrap());
+    }
+
+    #[test]
+    fn wal_memtable_eviction_should_work() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("kv.wal");
+        let store = WalMemTable::open(&path, FsyncPolicy::No).unwrap();
+        test_eviction(store);
+
+        // 被淘汰的 key 重放之后也不存在
+        let store = WalMemTable::open(&path, FsyncPolicy::No).unwrap();
+        assert_eq!(store.get("t7", "k2").unwrap(), None);
+        assert_eq!(store.get("t7", "k3").unwrap(), Some("v".into()));
+    }
+
+    #[test]
+    fn wal_memtable_should_be_replayed_on_open() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("kv.wal");
+        let store = WalMemTable::open(&path, FsyncPolicy::Always).unwrap();
+        store.set("t1", "k1", "v1".into()).unwrap();
+        store.set("t1", "k2", "v2".into()).unwrap();
+        store.set("t1", "k1", "v3".into()).unwrap();
+        store.del("t1", "k2").unwrap();
+        store.set("t1", "k3", "v4".into()).unwrap();
+        store.expire("t1", "k3", Duration::from_secs(100)).unwrap();
+        store.set("t1", "k4", "v5".into()).unwrap();
+        store.expire("t1", "k4", Duration::ZERO).unwrap();
+        drop(store);
+
+        let store = WalMemTable::open(&path, FsyncPolicy::Always).unwrap();
+        assert_eq!(store.get("t1", "k1").unwrap(), Some("v3".into()));
+        assert_eq!(store.get("t1", "k2").unwrap(), None);
+        assert_eq!(store.get("t1", "k3").unwrap(), Some("v4".into()));
+        assert!(store.ttl("t1", "k3").unwrap().unwrap() > Duration::from_secs(90));
+        assert_eq!(store.get("t1", "k4").unwrap(), None);
+    }
+
+    #[test]
+    fn wal_memtable_should_truncate_broken_tail() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("kv.wal");
+        let store = WalMemTable::open(&path, FsyncPolicy::Always).unwrap();
+        store.set("t1", "k1", "v1".into()).unwrap();
+        drop(store);
+
+        // 模拟写到一半崩溃：长度是 10，但只有 3 个字节
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&[10, 1, 2, 3]).unwrap();
+        drop(file);
+
+        let store = WalMemTable::open(&path, FsyncPolicy::Always).unwrap();
+        assert_eq!(store.get("t1", "k1").unwrap(), Some("v1".into()));
+        store.set("t1", "k2", "v2".into()).unwrap();
+        drop(store);
+
+        let store = WalMemTable::open(&path, FsyncPolicy::Always).unwrap();
+        assert_eq!(store.get("t1", "k1").unwrap(), Some("v1".into()));
+        assert_eq!(store.get("t1", "k2").unwrap(), Some("v2".into()));
+    }
+
+    #[test]
+    fn wal_memtable_compact_should_rewrite_wal() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("kv.wal");
+        let store = WalMemTable::open(&path, FsyncPolicy::No).unwrap();
+        for i in 0..100 {
+            store.set("t1", "k1", i.into()).unwrap();
+        }
+        store.set("t1", "k2", "v2".into()).unwrap();
+        store.expire("t1", "k2", Duration::from_secs(100)).unwrap();
+        store.del("t1", "k1").unwrap();
+
+        let before = store.disk_usage().unwrap().unwrap();
+        let reclaimed = store.compact().unwrap();
+        assert!(reclaimed > 0);
+        assert_eq!(store.disk_usage().unwrap(), Some(before - reclaimed));
+
+        // 重写之后的写入追加到新的 WAL
+        store.set("t1", "k3", "v3".into()).unwrap();
+        drop(store);
+        let store = WalMemTable::open(&path, FsyncPolicy::No).unwrap();
+        assert_eq!(store.get("t1", "k1").unwrap(), None);
+        assert_eq!(store.get("t1", "k2").unwrap(), Some("v2".into()));
+        assert!(store.ttl("t1", "k2").unwrap().unwrap() > Duration::from_secs(90));
+        assert_eq!(store.get("t1", "k3").unwrap(), Some("v3".into()));
+    }
+}
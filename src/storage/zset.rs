@@ -0,0 +1,149 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    ops::Bound,
+};
+
+use bytes::Bytes;
+
+use crate::{error::KvError, pb::abi::ScoredMember};
+
+/// 一个 sorted set：member 到 score 的映射，加上按 (score, member) 排序的索引
+#[derive(Debug, Default, Clone)]
+pub(crate) struct SortedSet {
+    scores: HashMap<Bytes, f64>,
+    ordered: BTreeSet<(u64, Bytes)>,
+}
+
+impl SortedSet {
+    /// 加入 member，已经存在时更新 score；返回是否是新加入的
+    pub(crate) fn insert(&mut self, member: Bytes, score: f64) -> bool {
+        let old = self.scores.insert(member.clone(), score);
+        if let Some(old) = old {
+            self.ordered.remove(&(score_key(old), member.clone()));
+        }
+        self.ordered.insert((score_key(score), member));
+        old.is_none()
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.scores.len()
+    }
+
+    /// 排名在 [start, stop] 之间的 member
+    pub(crate) fn range(&self, start: i64, stop: i64) -> Vec<ScoredMember> {
+        let (start, end) = rank_range(self.len(), start, stop);
+        self.ordered
+            .iter()
+            .skip(start)
+            .take(end - start)
+            .map(to_member)
+            .collect()
+    }
+
+    /// score 在 [min, max] 之间的 member
+    pub(crate) fn range_by_score(&self, min: f64, max: f64) -> Vec<ScoredMember> {
+        let (min, max) = (score_key(min), score_key(max));
+        self.ordered
+            .range((Bound::Included((min, Bytes::new())), Bound::Unbounded))
+            .take_while(|(score, _)| *score <= max)
+            .map(to_member)
+            .collect()
+    }
+}
+
+fn to_member((score, member): &(u64, Bytes)) -> ScoredMember {
+    ScoredMember {
+        member: member.clone(),
+        score: from_score_key(*score),
+    }
+}
+
+/// NaN 没法排序，不能作为 score
+pub(crate) fn check_score(score: f64) -> Result<(), KvError> {
+    match score.is_nan() {
+        true => Err(KvError::InvalidCommand("score is not a number".into())),
+        false => Ok(()),
+    }
+}
+
+/// 把 score 转换成一个 u64，u64 的大小顺序和 score 一致，大端序的字节也可以直接比较
+pub(crate) fn score_key(score: f64) -> u64 {
+    // -0.0 和 0.0 当成同一个 score
+    let bits = (score + 0.0).to_bits();
+    match bits >> 63 {
+        1 => !bits,
+        _ => bits | 1 << 63,
+    }
+}
+
+pub(crate) fn from_score_key(key: u64) -> f64 {
+    let bits = match key >> 63 {
+        1 => key & !(1 << 63),
+        _ => !key,
+    };
+    f64::from_bits(bits)
+}
+
+/// 和 redis 的 LRANGE/ZRANGE 一样：负数从尾部开始数，stop 包含在内，超出范围的部分被截掉；
+/// 返回 [start, end) 形式的下标
+pub(crate) fn rank_range(len: usize, start: i64, stop: i64) -> (usize, usize) {
+    let len = len as i64;
+    let index = |i: i64| if i < 0 { len + i } else { i };
+    let (start, end) = (index(start).max(0), (index(stop) + 1).min(len));
+    match start < end {
+        true => (start as usize, end as usize),
+        false => (0, 0),
+    }
+}
+
+#[cfg(test)]
+mod zset_tests {
+    use super::*;
+
+    #[test]
+    fn score_key_should_keep_order() {
+        let scores = [
+            f64::NEG_INFINITY,
+            -10.5,
+            -1.0,
+            0.0,
+            1e-9,
+            1.0,
+            42.0,
+            f64::INFINITY,
+        ];
+        for w in scores.windows(2) {
+            assert!(score_key(w[0]) < score_key(w[1]), "{} < {}", w[0], w[1]);
+        }
+        for score in scores {
+            assert_eq!(from_score_key(score_key(score)), score);
+        }
+        assert_eq!(score_key(-0.0), score_key(0.0));
+    }
+
+    #[test]
+    fn sorted_set_should_order_by_score() {
+        let mut set = SortedSet::default();
+        assert!(set.insert("a".into(), 3.0));
+        assert!(set.insert("b".into(), 1.0));
+        assert!(set.insert("c".into(), 2.0));
+        // 更新 score 之后位置也跟着变
+        assert!(!set.insert("b".into(), 5.0));
+        let names = |members: Vec<ScoredMember>| -> Vec<Bytes> {
+            members.into_iter().map(|m| m.member).collect()
+        };
+        assert_eq!(names(set.range(0, -1)), ["c", "a", "b"]);
+        assert_eq!(names(set.range(-2, -1)), ["a", "b"]);
+        assert_eq!(names(set.range_by_score(2.0, 3.0)), ["c", "a"]);
+        assert!(set.range_by_score(4.0, 3.0).is_empty());
+    }
+
+    #[test]
+    fn rank_range_should_work() {
+        assert_eq!(rank_range(3, 0, -1), (0, 3));
+        assert_eq!(rank_range(3, -2, 10), (1, 3));
+        assert_eq!(rank_range(3, 2, 1), (0, 0));
+        assert_eq!(rank_range(3, 0, -100), (0, 0));
+        assert_eq!(rank_range(0, 0, -1), (0, 0));
+    }
+}
@@ -0,0 +1,83 @@
+use std::sync::OnceLock;
+
+use opentelemetry::KeyValue;
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::{runtime, trace, Resource};
+use tracing_subscriber::{
+    fmt, layer::SubscriberExt, reload, util::SubscriberInitExt, EnvFilter, Layer, Registry,
+};
+
+use crate::{config::TelemetryConfig, error::KvError};
+
+const DEFAULT_SERVICE_NAME: &str = "kv-server";
+
+/// init 之后可以通过它修改日志级别
+static LOG_FILTER: OnceLock<reload::Handle<EnvFilter, Registry>> = OnceLock::new();
+
+/// 初始化日志；配置了 telemetry 时，同时把 span 通过 OTLP 导出。需要在 tokio runtime 中调用
+pub fn init(config: Option<&TelemetryConfig>) -> Result<(), KvError> {
+    let otel = match config {
+        Some(config) => {
+            let name = config
+                .service_name
+                .as_deref()
+                .unwrap_or(DEFAULT_SERVICE_NAME);
+            let tracer = opentelemetry_otlp::new_pipeline()
+                .tracing()
+                .with_exporter(
+                    opentelemetry_otlp::new_exporter()
+                        .tonic()
+                        .with_endpoint(&config.endpoint),
+                )
+                .with_trace_config(trace::config().with_resource(Resource::new(vec![
+                    KeyValue::new("service.name", name.to_owned()),
+                ])))
+                .install_batch(runtime::Tokio)
+                .map_err(|e| {
+                    KvError::Internal(format!("Failed to install OTLP exporter: {}", e))
+                })?;
+            Some(tracing_opentelemetry::layer().with_tracer(tracer).boxed())
+        }
+        None => None,
+    };
+
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    let (filter, handle) = reload::Layer::new(filter);
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(fmt::layer())
+        .with(otel)
+        .try_init()
+        .map_err(|e| KvError::Internal(format!("Failed to init tracing: {}", e)))?;
+    let _ = LOG_FILTER.set(handle);
+    Ok(())
+}
+
+/// 当前的日志级别；没有调用 init 时返回 None
+pub fn log_level() -> Option<String> {
+    let handle = LOG_FILTER.get()?;
+    handle.with_current(|filter| filter.to_string()).ok()
+}
+
+/// 修改日志级别，格式和 RUST_LOG 一样，例如 `info,kv_db=debug`
+pub fn set_log_level(level: &str) -> Result<(), KvError> {
+    let filter = parse_log_level(level)?;
+    match LOG_FILTER.get() {
+        Some(handle) => handle
+            .reload(filter)
+            .map_err(|e| KvError::Internal(format!("Failed to set log level: {}", e))),
+        // 没有调用 init 时（例如测试中）日志不由我们管理
+        None => Ok(()),
+    }
+}
+
+/// 检查日志级别的格式
+pub fn parse_log_level(level: &str) -> Result<EnvFilter, KvError> {
+    EnvFilter::try_new(level)
+        .map_err(|e| KvError::InvalidCommand(format!("invalid log level {}: {}", level, e)))
+}
+
+/// 把还没有导出的 span 发送出去，服务退出前调用
+pub fn shutdown() {
+    opentelemetry::global::shutdown_tracer_provider();
+}
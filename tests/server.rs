@@ -1,12 +1,30 @@
 use std::time::Duration;
 
 use anyhow::Result;
+use futures::{SinkExt, StreamExt};
 use kv_db::{
-    config::{ClientConfig, ServerConfig, StorageConfig},
-    pb::abi::CommandRequest,
-    start_client_with_config, start_server_with_config, ProstClientStream,
+    backup_with_config, check_config,
+    cluster_client::ClusterClient,
+    config::{
+        AclConfig, AuthConfig, CdcConfig, CdcSinkConfig, ClientAuthConfig, ClientConfig,
+        ClusterConfig, CompressionConfig, HttpConfig, MembershipConfig, NetworkConfig, PeerConfig,
+        ProxyConfig, ReplicationConfig, RespConfig, RoleConfig, ServerConfig, StorageConfig, Verb,
+        WebSocketConfig,
+    },
+    error::KvError,
+    frame::Compression,
+    pb::abi::{value, CommandRequest, CommandResponse},
+    restore_with_config,
+    sled_db::SledDB,
+    start_client_with_config, start_server_with_config, Storage,
 };
-use tokio::time;
+use prost::Message as _;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    time,
+};
+use tokio_tungstenite::tungstenite::Message;
 
 #[tokio::test]
 async fn yamux_server_client_full_tests() -> Result<()> {
@@ -41,3 +59,1008 @@ async fn yamux_server_client_full_tests() -> Result<()> {
 
     Ok(())
 }
+
+#[tokio::test]
+async fn yamux_server_client_auth_tests() -> Result<()> {
+    let addr = "127.0.0.1:10087";
+
+    let mut config: ServerConfig = toml::from_str(include_str!("../fixtures/server.conf"))?;
+    config.general.addr = addr.into();
+    config.storage = StorageConfig::MemTable;
+    config.security.auth = Some(AuthConfig {
+        password: Some("secret".into()),
+        users: vec![],
+    });
+
+    tokio::spawn(async move {
+        start_server_with_config(config).await.unwrap();
+    });
+    time::sleep(Duration::from_millis(10)).await;
+
+    // 没有带凭证的客户端只能收到 401
+    let mut config: ClientConfig = toml::from_str(include_str!("../fixtures/client.conf"))?;
+    config.general.addr = addr.into();
+    let mut ctrl = start_client_with_config(config.clone()).await?;
+    let mut client = ctrl.open_stream().await?;
+    let cmd = CommandRequest::new_hset("table1", "hello", "world");
+    let data = client.execute(&cmd).await?;
+    assert_eq!(data.status, 401);
+
+    // 密码错误，连接时就会失败
+    config.auth = Some(ClientAuthConfig {
+        username: None,
+        password: "wrong".into(),
+    });
+    assert!(start_client_with_config(config.clone()).await.is_err());
+
+    // 认证之后，同一个连接上新开的 stream 都可以正常使用
+    config.auth = Some(ClientAuthConfig {
+        username: None,
+        password: "secret".into(),
+    });
+    let mut ctrl = start_client_with_config(config).await?;
+    let mut client = ctrl.open_stream().await?;
+    let data = client.execute(&cmd).await?;
+    assert_eq!(data.status, 200);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn replica_should_follow_primary() -> Result<()> {
+    let primary_addr = "127.0.0.1:10088";
+    let replica_addr = "127.0.0.1:10089";
+
+    let mut config: ServerConfig = toml::from_str(include_str!("../fixtures/server.conf"))?;
+    config.general.addr = primary_addr.into();
+    config.storage = StorageConfig::MemTable;
+    tokio::spawn(async move {
+        start_server_with_config(config).await.unwrap();
+    });
+    time::sleep(Duration::from_millis(10)).await;
+
+    let mut client_config: ClientConfig = toml::from_str(include_str!("../fixtures/client.conf"))?;
+    client_config.general.addr = primary_addr.into();
+    let mut ctrl = start_client_with_config(client_config.clone()).await?;
+    let mut primary = ctrl.open_stream().await?;
+    // 连接之前写入的数据通过全量同步复制过去
+    let cmd = CommandRequest::new_hset("table1", "k1", "v1");
+    primary.execute(&cmd).await?;
+
+    let mut config: ServerConfig = toml::from_str(include_str!("../fixtures/server.conf"))?;
+    config.general.addr = replica_addr.into();
+    config.storage = StorageConfig::MemTable;
+    config.replication = Some(ReplicationConfig {
+        primary: client_config.clone(),
+    });
+    let replica_http_addr = "127.0.0.1:10124";
+    config.http = Some(HttpConfig {
+        addr: replica_http_addr.into(),
+    });
+    tokio::spawn(async move {
+        start_server_with_config(config).await.unwrap();
+    });
+    time::sleep(Duration::from_millis(100)).await;
+
+    // 连接之后的修改通过增量同步复制过去
+    let cmd = CommandRequest::new_hset("table1", "k2", "v2");
+    primary.execute(&cmd).await?;
+    time::sleep(Duration::from_millis(50)).await;
+
+    client_config.general.addr = replica_addr.into();
+    let mut ctrl = start_client_with_config(client_config).await?;
+    let mut replica = ctrl.open_stream().await?;
+    let data = replica
+        .execute(&CommandRequest::new_hget("table1", "k1"))
+        .await?;
+    assert_eq!(data.values, &["v1".into()]);
+    let data = replica
+        .execute(&CommandRequest::new_hget("table1", "k2"))
+        .await?;
+    assert_eq!(data.values, &["v2".into()]);
+
+    // replica 只读
+    let data = replica
+        .execute(&CommandRequest::new_hset("table1", "k3", "v3"))
+        .await?;
+    assert_eq!(data.status, 403);
+
+    // 完成全量同步之后 replica 可以接收流量
+    let get = "GET /readyz HTTP/1.1\r\nHost: kv\r\nConnection: close\r\n\r\n";
+    let res = http_request(replica_http_addr, get).await?;
+    assert!(res.starts_with("HTTP/1.1 200"));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn cdc_should_mirror_writes_to_downstream() -> Result<()> {
+    let upstream_addr = "127.0.0.1:10128";
+    let downstream_addr = "127.0.0.1:10129";
+    let dir = tempfile::tempdir()?;
+
+    let mut config: ServerConfig = toml::from_str(include_str!("../fixtures/server.conf"))?;
+    config.general.addr = downstream_addr.into();
+    config.storage = StorageConfig::MemTable;
+    tokio::spawn(async move {
+        start_server_with_config(config).await.unwrap();
+    });
+
+    let mut client_config: ClientConfig = toml::from_str(include_str!("../fixtures/client.conf"))?;
+    client_config.general.addr = downstream_addr.into();
+    let mut config: ServerConfig = toml::from_str(include_str!("../fixtures/server.conf"))?;
+    config.general.addr = upstream_addr.into();
+    config.storage = StorageConfig::MemTable;
+    config.cdc = Some(CdcConfig {
+        dir: dir.path().to_string_lossy().into(),
+        sink: CdcSinkConfig::Kv(Box::new(client_config.clone())),
+        tables: vec![],
+        fsync: Default::default(),
+    });
+    tokio::spawn(async move {
+        start_server_with_config(config).await.unwrap();
+    });
+    time::sleep(Duration::from_millis(50)).await;
+
+    let mut upstream_config = client_config.clone();
+    upstream_config.general.addr = upstream_addr.into();
+    let mut ctrl = start_client_with_config(upstream_config).await?;
+    let mut upstream = ctrl.open_stream().await?;
+    upstream
+        .execute(&CommandRequest::new_hset("table1", "k1", "v1"))
+        .await?;
+    upstream
+        .execute(&CommandRequest::new_hset("table1", "k2", "v2"))
+        .await?;
+    upstream
+        .execute(&CommandRequest::new_hdel("table1", "k2"))
+        .await?;
+    time::sleep(Duration::from_millis(200)).await;
+
+    let mut ctrl = start_client_with_config(client_config).await?;
+    let mut downstream = ctrl.open_stream().await?;
+    let data = downstream
+        .execute(&CommandRequest::new_hget("table1", "k1"))
+        .await?;
+    assert_eq!(data.values, &["v1".into()]);
+    let data = downstream
+        .execute(&CommandRequest::new_hget("table1", "k2"))
+        .await?;
+    assert_eq!(data.status, 404);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn redis_client_should_talk_resp() -> Result<()> {
+    let addr = "127.0.0.1:10090";
+    let resp_addr = "127.0.0.1:10091";
+
+    let mut config: ServerConfig = toml::from_str(include_str!("../fixtures/server.conf"))?;
+    config.general.addr = addr.into();
+    config.storage = StorageConfig::MemTable;
+    config.resp = Some(RespConfig {
+        addr: resp_addr.into(),
+    });
+    tokio::spawn(async move {
+        start_server_with_config(config).await.unwrap();
+    });
+    time::sleep(Duration::from_millis(10)).await;
+
+    // 和 redis-cli 一样发送 bulk string 数组
+    let mut stream = TcpStream::connect(resp_addr).await?;
+    stream
+        .write_all(b"*4\r\n$4\r\nHSET\r\n$6\r\ntable1\r\n$5\r\nhello\r\n$5\r\nworld\r\n")
+        .await?;
+    stream
+        .write_all(b"*3\r\n$4\r\nHGET\r\n$6\r\ntable1\r\n$5\r\nhello\r\n")
+        .await?;
+    let expected = b":1\r\n$5\r\nworld\r\n";
+    let mut buf = vec![0; expected.len()];
+    stream.read_exact(&mut buf).await?;
+    assert_eq!(&buf, expected);
+
+    // 通过 RESP 写入的数据，prost 客户端也能读到
+    let mut config: ClientConfig = toml::from_str(include_str!("../fixtures/client.conf"))?;
+    config.general.addr = addr.into();
+    let mut ctrl = start_client_with_config(config).await?;
+    let mut client = ctrl.open_stream().await?;
+    let data = client
+        .execute(&CommandRequest::new_hget("table1", "hello"))
+        .await?;
+    assert_eq!(data.values, &["world".into()]);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn http_client_should_use_rest_api() -> Result<()> {
+    let addr = "127.0.0.1:10092";
+    let http_addr = "127.0.0.1:10093";
+
+    let mut config: ServerConfig = toml::from_str(include_str!("../fixtures/server.conf"))?;
+    config.general.addr = addr.into();
+    config.storage = StorageConfig::MemTable;
+    config.http = Some(HttpConfig {
+        addr: http_addr.into(),
+    });
+    tokio::spawn(async move {
+        start_server_with_config(config).await.unwrap();
+    });
+    time::sleep(Duration::from_millis(10)).await;
+
+    let put = "PUT /v1/table1/hello HTTP/1.1\r\nHost: kv\r\nConnection: close\r\nContent-Length: 5\r\n\r\nworld";
+    let res = http_request(http_addr, put).await?;
+    assert!(res.starts_with("HTTP/1.1 201"));
+
+    // 通过 REST 写入的数据，prost 客户端也能读到
+    let mut config: ClientConfig = toml::from_str(include_str!("../fixtures/client.conf"))?;
+    config.general.addr = addr.into();
+    let mut ctrl = start_client_with_config(config).await?;
+    let mut client = ctrl.open_stream().await?;
+    let data = client
+        .execute(&CommandRequest::new_hget("table1", "hello"))
+        .await?;
+    assert_eq!(data.values, &["world".into()]);
+
+    let get = "GET /v1/table1/hello HTTP/1.1\r\nHost: kv\r\nConnection: close\r\n\r\n";
+    let res = http_request(http_addr, get).await?;
+    assert!(res.starts_with("HTTP/1.1 200"));
+    assert!(res.ends_with("\r\n\r\nworld"));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn server_should_shutdown_gracefully() -> Result<()> {
+    let addr = "127.0.0.1:10094";
+    let resp_addr = "127.0.0.1:10095";
+
+    let mut config: ServerConfig = toml::from_str(include_str!("../fixtures/server.conf"))?;
+    config.general.addr = addr.into();
+    config.storage = StorageConfig::MemTable;
+    config.resp = Some(RespConfig {
+        addr: resp_addr.into(),
+    });
+    config.grace_period = Some(1);
+    let server = start_server_with_config(config).await?;
+
+    let mut client_config: ClientConfig = toml::from_str(include_str!("../fixtures/client.conf"))?;
+    client_config.general.addr = addr.into();
+    let mut ctrl = start_client_with_config(client_config.clone()).await?;
+    let mut client = ctrl.open_stream().await?;
+    let cmd = CommandRequest::new_hset("table1", "k1", "v1");
+    client.execute(&cmd).await?;
+    let mut redis = TcpStream::connect(resp_addr).await?;
+
+    time::timeout(Duration::from_secs(1), server.shutdown()).await??;
+
+    // 退出之后不再接受新的连接，已有的连接也不再处理请求
+    assert!(start_client_with_config(client_config).await.is_err());
+    assert!(TcpStream::connect(resp_addr).await.is_err());
+    let cmd = CommandRequest::new_hget("table1", "k1");
+    assert!(client.execute(&cmd).await.is_err());
+    // 连接已经被关闭，写入可能直接失败，也可能读到 EOF
+    let _ = redis.write_all(b"*1\r\n$4\r\nPING\r\n").await;
+    let mut buf = [0u8; 16];
+    assert_eq!(redis.read(&mut buf).await.unwrap_or_default(), 0);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn config_reload_should_apply_security_to_existing_connections() -> Result<()> {
+    let addr = "127.0.0.1:10096";
+
+    let mut config: ServerConfig = toml::from_str(include_str!("../fixtures/server.conf"))?;
+    config.general.addr = addr.into();
+    config.storage = StorageConfig::MemTable;
+    let dir = tempfile::tempdir()?;
+    let path = dir.path().join("server.conf");
+    std::fs::write(&path, toml::to_string(&config)?)?;
+    let server = start_server_with_config(ServerConfig::load(path.to_str().unwrap())?).await?;
+
+    let mut client_config: ClientConfig = toml::from_str(include_str!("../fixtures/client.conf"))?;
+    client_config.general.addr = addr.into();
+    let mut ctrl = start_client_with_config(client_config).await?;
+    let mut client = ctrl.open_stream().await?;
+    let cmd = CommandRequest::new_hset("table1", "k1", "v1");
+    assert_eq!(client.execute(&cmd).await?.status, 200);
+
+    // 修改配置文件之后重新加载，连接不会断开，但之后的命令需要认证
+    config.security.auth = Some(AuthConfig {
+        password: Some("secret".into()),
+        users: vec![],
+    });
+    std::fs::write(&path, toml::to_string(&config)?)?;
+    let res = client.execute(&CommandRequest::new_config_reload()).await?;
+    assert_eq!(res.status, 200);
+    let cmd = CommandRequest::new_hget("table1", "k1");
+    assert_eq!(client.execute(&cmd).await?.status, 401);
+    client
+        .execute(&CommandRequest::new_auth("", "secret"))
+        .await?;
+    assert_eq!(client.execute(&cmd).await?.status, 200);
+
+    // 配置有错误时不会应用
+    std::fs::write(&path, "not a config")?;
+    assert!(server.reload().is_err());
+    assert_eq!(client.execute(&cmd).await?.status, 200);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn server_should_reject_connections_over_limit() -> Result<()> {
+    let addr = "127.0.0.1:10099";
+    let resp_addr = "127.0.0.1:10100";
+
+    let mut config: ServerConfig = toml::from_str(include_str!("../fixtures/server.conf"))?;
+    config.general.addr = addr.into();
+    config.storage = StorageConfig::MemTable;
+    config.resp = Some(RespConfig {
+        addr: resp_addr.into(),
+    });
+    config.max_connections_per_ip = Some(1);
+    let _server = start_server_with_config(config).await?;
+
+    let mut client_config: ClientConfig = toml::from_str(include_str!("../fixtures/client.conf"))?;
+    client_config.general.addr = addr.into();
+    let mut ctrl = start_client_with_config(client_config.clone()).await?;
+    let mut client = ctrl.open_stream().await?;
+    let cmd = CommandRequest::new_hset("table1", "k1", "v1");
+    assert_eq!(client.execute(&cmd).await?.status, 200);
+
+    // 第二个连接在第一个命令上收到 503，之后连接被关闭
+    let mut rejected = start_client_with_config(client_config.clone()).await?;
+    let res = rejected.open_stream().await?.execute(&cmd).await?;
+    assert_eq!(res.status, 503);
+    assert!(res.message.contains("Too many connections"));
+
+    // RESP 的连接一起计数
+    let mut redis = TcpStream::connect(resp_addr).await?;
+    let mut buf = Vec::new();
+    redis.read_to_end(&mut buf).await?;
+    assert!(String::from_utf8(buf)?.starts_with("-ERR Too many connections"));
+
+    // 第一个连接关闭之后可以建立新的连接
+    ctrl.close().await?;
+    drop(client);
+    time::sleep(Duration::from_millis(50)).await;
+    let mut ctrl = start_client_with_config(client_config).await?;
+    let mut client = ctrl.open_stream().await?;
+    assert_eq!(client.execute(&cmd).await?.status, 200);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn server_should_close_idle_connections() -> Result<()> {
+    let addr = "127.0.0.1:10125";
+    let resp_addr = "127.0.0.1:10126";
+
+    let mut config: ServerConfig = toml::from_str(include_str!("../fixtures/server.conf"))?;
+    config.general.addr = addr.into();
+    config.storage = StorageConfig::MemTable;
+    config.resp = Some(RespConfig {
+        addr: resp_addr.into(),
+    });
+    config.network = NetworkConfig {
+        idle_timeout: Some(1),
+        tcp_keepalive: Some(60),
+        tcp_nodelay: true,
+    };
+    let _server = start_server_with_config(config).await?;
+
+    let mut client_config: ClientConfig = toml::from_str(include_str!("../fixtures/client.conf"))?;
+    client_config.general.addr = addr.into();
+    let mut ctrl = start_client_with_config(client_config).await?;
+    let cmd = CommandRequest::new_hset("table1", "k1", "v1");
+    assert_eq!(ctrl.open_stream().await?.execute(&cmd).await?.status, 200);
+
+    let mut redis = TcpStream::connect(resp_addr).await?;
+    redis.write_all(b"*1\r\n$4\r\nPING\r\n").await?;
+    let mut buf = [0; 7];
+    redis.read_exact(&mut buf).await?;
+    assert_eq!(&buf, b"+PONG\r\n");
+
+    // 空闲超过 1 秒之后服务器关闭连接
+    let mut buf = Vec::new();
+    time::timeout(Duration::from_secs(3), redis.read_to_end(&mut buf)).await??;
+    assert!(buf.is_empty());
+    let res = match ctrl.open_stream().await {
+        Ok(mut stream) => stream.execute(&cmd).await.map(|_| ()),
+        Err(e) => Err(e.into()),
+    };
+    assert!(res.is_err());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn admin_should_list_and_kill_clients() -> Result<()> {
+    let addr = "127.0.0.1:10127";
+
+    let mut config: ServerConfig = toml::from_str(include_str!("../fixtures/server.conf"))?;
+    config.general.addr = addr.into();
+    config.storage = StorageConfig::MemTable;
+    let _server = start_server_with_config(config).await?;
+
+    let mut client_config: ClientConfig = toml::from_str(include_str!("../fixtures/client.conf"))?;
+    client_config.general.addr = addr.into();
+    let mut victim = start_client_with_config(client_config.clone()).await?;
+    let cmd = CommandRequest::new_hset("table1", "k1", "v1");
+    assert_eq!(victim.open_stream().await?.execute(&cmd).await?.status, 200);
+
+    let mut admin = start_client_with_config(client_config).await?;
+    let mut stream = admin.open_stream().await?;
+    let res = stream.execute(&CommandRequest::new_client_list()).await?;
+    assert_eq!(res.values.len(), 2);
+    // 执行过 HSET 的是 victim 的连接
+    let id = res
+        .values
+        .iter()
+        .find_map(|v| match &v.value {
+            Some(value::Value::Map(map))
+                if map.entries.get("last_command") == Some(&"HSET".into()) =>
+            {
+                i64::try_from(map.entries.get("id")?).ok()
+            }
+            _ => None,
+        })
+        .unwrap();
+
+    let res = stream
+        .execute(&CommandRequest::new_client_kill(id as u64))
+        .await?;
+    assert_eq!(res.values, [1.into()]);
+    time::sleep(Duration::from_millis(50)).await;
+    let res = match victim.open_stream().await {
+        Ok(mut stream) => stream.execute(&cmd).await.map(|_| ()),
+        Err(e) => Err(e.into()),
+    };
+    assert!(res.is_err());
+
+    let res = stream.execute(&CommandRequest::new_client_list()).await?;
+    assert_eq!(res.values.len(), 1);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn client_should_talk_over_unix_socket() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let addr = format!("unix:{}", dir.path().join("kv.sock").display());
+
+    let mut config: ServerConfig = toml::from_str(include_str!("../fixtures/server.conf"))?;
+    config.general.addr = addr.clone();
+    config.storage = StorageConfig::MemTable;
+    let _server = start_server_with_config(config).await?;
+
+    // unix socket 不使用 TLS，客户端的 TLS 配置被忽略
+    let mut config: ClientConfig = toml::from_str(include_str!("../fixtures/client.conf"))?;
+    config.general.addr = addr;
+    config.tls.domain = "no.such.domain".into();
+    let mut ctrl = start_client_with_config(config).await?;
+    let mut client = ctrl.open_stream().await?;
+    let cmd = CommandRequest::new_hset("table1", "k1", "v1");
+    assert_eq!(client.execute(&cmd).await?.status, 200);
+    let res = client
+        .execute(&CommandRequest::new_hget("table1", "k1"))
+        .await?;
+    assert_eq!(res.values, &["v1".into()]);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn browser_should_talk_websocket() -> Result<()> {
+    let addr = "127.0.0.1:10105";
+    let ws_addr = "127.0.0.1:10106";
+
+    let mut config: ServerConfig = toml::from_str(include_str!("../fixtures/server.conf"))?;
+    config.general.addr = addr.into();
+    config.storage = StorageConfig::MemTable;
+    config.websocket = Some(WebSocketConfig {
+        addr: ws_addr.into(),
+    });
+    let _server = start_server_with_config(config).await?;
+
+    let (mut ws, _) = tokio_tungstenite::connect_async(format!("ws://{}/", ws_addr)).await?;
+    let mut cmd = CommandRequest::new_hset("table1", "k1", "v1");
+    cmd.id = 7;
+    ws.send(Message::Binary(cmd.encode_to_vec().into())).await?;
+    let Some(Ok(Message::Binary(data))) = ws.next().await else {
+        panic!("expected a binary message");
+    };
+    let res = CommandResponse::decode(data)?;
+    assert_eq!((res.id, res.status), (7, 200));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn client_cert_should_authenticate_user() -> Result<()> {
+    let addr = "127.0.0.1:10107";
+
+    let mut config: ServerConfig = toml::from_str(include_str!("../fixtures/server.conf"))?;
+    config.general.addr = addr.into();
+    config.storage = StorageConfig::MemTable;
+    config.security.auth = Some(AuthConfig {
+        password: Some("secret".into()),
+        users: vec![],
+    });
+    config.security.acl = Some(AclConfig {
+        roles: vec![RoleConfig {
+            name: "writer".into(),
+            tables: vec!["*".into()],
+            verbs: vec![Verb::Read, Verb::Write],
+        }],
+        users: [("awesome-device-id".into(), vec!["writer".into()])].into(),
+    });
+    config.security.client_ca = Some("fixtures/ca.cert".into());
+    config.security.require_client_cert = true;
+    let _server = start_server_with_config(config).await?;
+
+    // 证书的 CN 就是用户名，不需要 AUTH
+    let mut config: ClientConfig = toml::from_str(include_str!("../fixtures/client.conf"))?;
+    config.general.addr = addr.into();
+    config.tls.cert_path = Some("fixtures/client.cert".into());
+    config.tls.key_path = Some("fixtures/client.key".into());
+    let mut ctrl = start_client_with_config(config.clone()).await?;
+    let mut client = ctrl.open_stream().await?;
+    let cmd = CommandRequest::new_hset("table1", "k1", "v1");
+    assert_eq!(client.execute(&cmd).await?.status, 200);
+
+    // 没有证书的客户端不能连接
+    config.tls.cert_path = None;
+    config.tls.key_path = None;
+    let res = async {
+        let mut ctrl = start_client_with_config(config).await?;
+        let res = ctrl.open_stream().await?.execute(&cmd).await?;
+        anyhow::Ok(res)
+    };
+    assert!(res.await.is_err());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn server_should_rotate_tls_certificate() -> Result<()> {
+    let addr = "127.0.0.1:10108";
+    let dir = tempfile::tempdir()?;
+    let cert = dir.path().join("server.cert");
+    let key = dir.path().join("server.key");
+    // 先用一个域名不匹配的证书，客户端不能连接
+    std::fs::copy("fixtures/client.cert", &cert)?;
+    std::fs::copy("fixtures/client.key", &key)?;
+
+    let mut config: ServerConfig = toml::from_str(include_str!("../fixtures/server.conf"))?;
+    config.general.addr = addr.into();
+    config.storage = StorageConfig::MemTable;
+    config.tls.cert = cert.to_str().unwrap().into();
+    config.tls.key = key.to_str().unwrap().into();
+    config.tls.watch_interval = Some(1);
+    let _server = start_server_with_config(config).await?;
+
+    let mut config: ClientConfig = toml::from_str(include_str!("../fixtures/client.conf"))?;
+    config.general.addr = addr.into();
+    assert!(start_client_with_config(config.clone()).await.is_err());
+
+    // 证书文件更新之后，新的连接使用新的证书
+    time::sleep(Duration::from_millis(100)).await;
+    std::fs::copy("fixtures/server.cert", &cert)?;
+    std::fs::copy("fixtures/server.key", &key)?;
+    time::sleep(Duration::from_millis(1500)).await;
+    let mut ctrl = start_client_with_config(config).await?;
+    let res = ctrl
+        .open_stream()
+        .await?
+        .execute(&CommandRequest::new_ping())
+        .await?;
+    assert_eq!(res.status, 200);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn client_should_negotiate_compression() -> Result<()> {
+    let addr = "127.0.0.1:10109";
+
+    let mut config: ServerConfig = toml::from_str(include_str!("../fixtures/server.conf"))?;
+    config.general.addr = addr.into();
+    config.storage = StorageConfig::MemTable;
+    config.compression.codecs = Some(vec![Compression::Lz4, Compression::Gzip]);
+    let _server = start_server_with_config(config).await?;
+
+    let mut config: ClientConfig = toml::from_str(include_str!("../fixtures/client.conf"))?;
+    config.general.addr = addr.into();
+    config.compression = Some(CompressionConfig {
+        codecs: Some(vec![Compression::Zstd, Compression::Lz4]),
+        threshold: Some(64),
+    });
+    let mut ctrl = start_client_with_config(config).await?;
+    let mut client = ctrl.open_stream().await?;
+    let res = client
+        .execute(&CommandRequest::new_hello(["zstd", "lz4"]))
+        .await?;
+    assert_eq!(res.values, &["lz4".into()]);
+
+    // 请求和响应都用 lz4 压缩
+    let value = "v".repeat(100_000);
+    let cmd = CommandRequest::new_hset("table1", "k1", value.as_str());
+    assert_eq!(client.execute(&cmd).await?.status, 200);
+    let res = client
+        .execute(&CommandRequest::new_hget("table1", "k1"))
+        .await?;
+    assert_eq!(res.values, &[value.into()]);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn large_values_should_be_sent_in_chunks() -> Result<()> {
+    let addr = "127.0.0.1:10110";
+
+    let mut config: ServerConfig = toml::from_str(include_str!("../fixtures/server.conf"))?;
+    config.general.addr = addr.into();
+    config.storage = StorageConfig::MemTable;
+    let _server = start_server_with_config(config).await?;
+
+    let mut config: ClientConfig = toml::from_str(include_str!("../fixtures/client.conf"))?;
+    config.general.addr = addr.into();
+    config.compression = Some(CompressionConfig {
+        codecs: Some(vec![Compression::None]),
+        threshold: None,
+    });
+    config.max_frame_size = Some(16 << 20);
+    let mut ctrl = start_client_with_config(config).await?;
+
+    // 10MB 的 value 拆成多个 frame 发送和返回
+    let value = "v".repeat(10 << 20);
+    let mut client = ctrl.open_stream().await?;
+    let cmd = CommandRequest::new_hset("table1", "k1", value.as_str());
+    assert_eq!(client.execute(&cmd).await?.status, 200);
+    let res = client
+        .execute(&CommandRequest::new_hget("table1", "k1"))
+        .await?;
+    assert_eq!(res.values, &[value.into()]);
+
+    // 超过客户端的限制时不会发送
+    let cmd = CommandRequest::new_hset("table1", "k2", "v".repeat(16 << 20));
+    let res = client.execute(&cmd).await;
+    assert!(matches!(res, Err(KvError::FrameTooLarge(_, max)) if max == 16 << 20));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn cluster_should_replicate_writes_between_servers() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let nodes = [
+        (1, "127.0.0.1:10111"),
+        (2, "127.0.0.1:10112"),
+        (3, "127.0.0.1:10113"),
+    ];
+    let client_config = |addr: &str| -> Result<ClientConfig> {
+        let mut config: ClientConfig = toml::from_str(include_str!("../fixtures/client.conf"))?;
+        config.general.addr = addr.into();
+        Ok(config)
+    };
+    let mut servers = vec![];
+    for (id, addr) in nodes {
+        let mut config: ServerConfig = toml::from_str(include_str!("../fixtures/server.conf"))?;
+        config.general.addr = addr.into();
+        config.storage = StorageConfig::MemTable;
+        let mut peers = vec![];
+        for (peer, addr) in nodes.into_iter().filter(|(peer, _)| *peer != id) {
+            let client = client_config(addr)?;
+            peers.push(PeerConfig { id: peer, client });
+        }
+        config.cluster = Some(ClusterConfig {
+            id,
+            dir: dir.path().join(id.to_string()).to_string_lossy().into(),
+            advertise_addr: None,
+            peers,
+            election_timeout: Some(150),
+            heartbeat_interval: Some(30),
+        });
+        servers.push(start_server_with_config(config).await?);
+    }
+
+    // 等待选出 leader：follower 返回 421，leader 返回 200
+    let cmd = CommandRequest::new_hset("table1", "k1", "v1");
+    let mut leader = None;
+    for _ in 0..100 {
+        for (_, addr) in nodes {
+            let mut ctrl = start_client_with_config(client_config(addr)?).await?;
+            let res = ctrl.open_stream().await?.execute(&cmd).await?;
+            if res.status == 200 {
+                leader = Some(addr);
+            }
+        }
+        if leader.is_some() {
+            break;
+        }
+        time::sleep(Duration::from_millis(50)).await;
+    }
+    let leader = leader.expect("no leader is elected");
+
+    let mut ctrl = start_client_with_config(client_config(leader)?).await?;
+    let res = ctrl
+        .open_stream()
+        .await?
+        .execute(&CommandRequest::new_hget("table1", "k1"))
+        .await?;
+    assert_eq!(res.values, &["v1".into()]);
+
+    let (_, follower) = nodes.into_iter().find(|(_, addr)| *addr != leader).unwrap();
+    let mut ctrl = start_client_with_config(client_config(follower)?).await?;
+    let res = ctrl
+        .open_stream()
+        .await?
+        .execute(&CommandRequest::new_hget("table1", "k1"))
+        .await?;
+    assert_eq!(res.status, 421);
+    assert!(res.message.contains(leader));
+
+    // 感知集群的客户端从 follower 获取拓扑，直接把命令发给 leader
+    let client = ClusterClient::connect(client_config(follower)?).await?;
+    let res = client
+        .execute(&CommandRequest::new_hset("table1", "k2", "v2"))
+        .await?;
+    assert_eq!(res.status, 200);
+    let res = client
+        .execute(&CommandRequest::new_hget("table1", "k2"))
+        .await?;
+    assert_eq!(res.values, &["v2".into()]);
+    Ok(())
+}
+
+#[tokio::test]
+async fn proxy_should_shard_keys_over_backends() -> Result<()> {
+    let client_config = |addr: &str| -> Result<ClientConfig> {
+        let mut config: ClientConfig = toml::from_str(include_str!("../fixtures/client.conf"))?;
+        config.general.addr = addr.into();
+        Ok(config)
+    };
+    let backends = ["127.0.0.1:10114", "127.0.0.1:10115"];
+    let mut servers = vec![];
+    for addr in backends {
+        let mut config: ServerConfig = toml::from_str(include_str!("../fixtures/server.conf"))?;
+        config.general.addr = addr.into();
+        config.storage = StorageConfig::MemTable;
+        servers.push(start_server_with_config(config).await?);
+    }
+    let addr = "127.0.0.1:10116";
+    let mut config: ServerConfig = toml::from_str(include_str!("../fixtures/server.conf"))?;
+    config.general.addr = addr.into();
+    config.storage = StorageConfig::MemTable;
+    config.proxy = Some(ProxyConfig {
+        backends: vec![client_config(backends[0])?, client_config(backends[1])?],
+        virtual_nodes: None,
+    });
+    servers.push(start_server_with_config(config).await?);
+
+    let mut ctrl = start_client_with_config(client_config(addr)?).await?;
+    let mut client = ctrl.open_stream().await?;
+    for i in 0..20 {
+        let cmd = CommandRequest::new_hset("table1", format!("k{:02}", i), i);
+        assert_eq!(client.execute(&cmd).await?.status, 200);
+    }
+    let res = client
+        .execute(&CommandRequest::new_hget("table1", "k07"))
+        .await?;
+    assert_eq!(res.values, &[7.into()]);
+
+    // 每个后端只保存一部分 key
+    let mut sizes = vec![];
+    for backend in backends {
+        let mut ctrl = start_client_with_config(client_config(backend)?).await?;
+        let res = ctrl
+            .open_stream()
+            .await?
+            .execute(&CommandRequest::new_dbsize())
+            .await?;
+        sizes.push(res.values[0].to_integer().unwrap());
+    }
+    assert!(sizes.iter().all(|&n| n > 0 && n < 20), "{:?}", sizes);
+
+    // 遍历 table 的命令合并所有后端的结果
+    let res = client.execute(&CommandRequest::new_dbsize()).await?;
+    assert_eq!(res.values, &[20.into()]);
+    let res = client
+        .execute(&CommandRequest::new_hgetall("table1"))
+        .await?;
+    let keys: Vec<_> = res.pairs.iter().map(|p| p.key.clone()).collect();
+    let expected: Vec<_> = (0..20).map(|i| format!("k{:02}", i)).collect();
+    assert_eq!(keys, expected);
+
+    let mut cursor = 0;
+    let mut scanned = 0;
+    loop {
+        let res = client
+            .execute(&CommandRequest::new_hscan("table1", cursor, 3))
+            .await?;
+        scanned += res.pairs.len();
+        cursor = res.values[0].to_integer().unwrap() as u64;
+        if cursor == 0 {
+            break;
+        }
+    }
+    assert_eq!(scanned, 20);
+
+    // 事务需要在一个节点上执行，代理不支持
+    let res = client.execute(&CommandRequest::new_multi()).await?;
+    assert_eq!(res.status, 400);
+
+    // 感知集群的客户端从代理获取拓扑，绕过代理直接访问后端
+    let client = ClusterClient::connect(client_config(addr)?).await?;
+    let res = client
+        .execute(&CommandRequest::new_hget("table1", "k07"))
+        .await?;
+    assert_eq!(res.values, &[7.into()]);
+    for i in 20..40 {
+        let cmd = CommandRequest::new_hset("table1", format!("k{:02}", i), i);
+        assert_eq!(client.execute(&cmd).await?.status, 200);
+    }
+    let res = client.execute(&CommandRequest::new_dbsize()).await?;
+    assert_eq!(res.values, &[40.into()]);
+    // 写入的 key 和经过代理写入的一样分布在各个后端
+    let mut proxied = ctrl.open_stream().await?;
+    let res = proxied
+        .execute(&CommandRequest::new_hget("table1", "k33"))
+        .await?;
+    assert_eq!(res.values, &[33.into()]);
+    Ok(())
+}
+
+#[tokio::test]
+async fn membership_should_discover_nodes_and_detect_failures() -> Result<()> {
+    let client_config = |addr: &str| -> Result<ClientConfig> {
+        let mut config: ClientConfig = toml::from_str(include_str!("../fixtures/client.conf"))?;
+        config.general.addr = addr.into();
+        Ok(config)
+    };
+    let addrs = ["127.0.0.1:10117", "127.0.0.1:10118", "127.0.0.1:10119"];
+    let mut servers = vec![];
+    for (i, addr) in addrs.into_iter().enumerate() {
+        let mut config: ServerConfig = toml::from_str(include_str!("../fixtures/server.conf"))?;
+        config.general.addr = addr.into();
+        config.storage = StorageConfig::MemTable;
+        // 第一个节点没有 seed，只被动地回复其它节点，其它节点都从它开始发现
+        let seeds = match i {
+            0 => vec![],
+            _ => vec![client_config(addrs[0])?],
+        };
+        config.membership = Some(MembershipConfig {
+            seeds,
+            advertise_addr: None,
+            interval: Some(50),
+            failure_timeout: Some(300),
+        });
+        servers.push(start_server_with_config(config).await?);
+    }
+
+    let members = |addr: &'static str| async move {
+        let mut ctrl = start_client_with_config(client_config(addr)?).await?;
+        let res = ctrl
+            .open_stream()
+            .await?
+            .execute(&CommandRequest::new_members())
+            .await?;
+        let members: Vec<_> = res
+            .pairs
+            .into_iter()
+            .map(|p| (p.key, p.value.unwrap()))
+            .collect();
+        anyhow::Ok(members)
+    };
+    let all_alive: Vec<_> = addrs
+        .iter()
+        .map(|addr| (addr.as_bytes().to_vec().into(), "alive".into()))
+        .collect();
+    for addr in addrs {
+        let mut discovered = false;
+        for _ in 0..100 {
+            if members(addr).await? == all_alive {
+                discovered = true;
+                break;
+            }
+            time::sleep(Duration::from_millis(50)).await;
+        }
+        assert!(discovered, "{} didn't discover all nodes", addr);
+    }
+
+    // 停止的节点心跳不再增加，其它节点发现它下线了
+    servers.pop().unwrap().shutdown().await?;
+    let mut detected = false;
+    for _ in 0..100 {
+        let members = members(addrs[1]).await?;
+        if members[2].1 != "alive".into() {
+            detected = true;
+            break;
+        }
+        time::sleep(Duration::from_millis(50)).await;
+    }
+    assert!(detected, "failure of {} isn't detected", addrs[2]);
+    Ok(())
+}
+
+#[test]
+fn sled_storage_should_be_backed_up_and_restored() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let path = dir.path().join("dump.kv");
+    let path = path.to_str().unwrap();
+    let mut config: ServerConfig = toml::from_str(include_str!("../fixtures/server.conf"))?;
+
+    let src = dir.path().join("src").to_string_lossy().to_string();
+    config.storage = StorageConfig::SledDB(src.clone());
+    let store = SledDB::open(&src)?;
+    store.set("table1", "k1", "v1".into())?;
+    store.set("table2", "k2", "v2".into())?;
+    // 服务器或者其它进程打开着存储时无法备份
+    assert!(backup_with_config(&config, path).is_err());
+    store.flush()?;
+    drop(store);
+    // sled 的后台 flush 线程退出之后才会释放文件锁
+    let mut backup = backup_with_config(&config, path);
+    for _ in 0..20 {
+        if backup.is_ok() {
+            break;
+        }
+        std::thread::sleep(Duration::from_millis(100));
+        backup = backup_with_config(&config, path);
+    }
+    assert_eq!(backup?, 2);
+
+    let dst = dir.path().join("dst").to_string_lossy().to_string();
+    config.storage = StorageConfig::SledDB(dst.clone());
+    assert_eq!(restore_with_config(&config, path)?, 2);
+    let store = SledDB::open(&dst)?;
+    assert_eq!(store.get("table1", "k1")?, Some("v1".into()));
+    assert_eq!(store.get("table2", "k2")?, Some("v2".into()));
+
+    // MemTable 没有可以备份的数据
+    config.storage = StorageConfig::MemTable;
+    assert!(backup_with_config(&config, path).is_err());
+    Ok(())
+}
+
+#[test]
+fn check_config_should_validate_without_starting_server() -> Result<()> {
+    let mut config: ServerConfig = toml::from_str(include_str!("../fixtures/server.conf"))?;
+    config.storage = StorageConfig::MemTable;
+    assert!(check_config(&config)?.is_empty());
+
+    config.storage = StorageConfig::SledDB("/tmp/kvserver".into());
+    config.memtable.max_memory = Some("1GB".into());
+    assert_eq!(check_config(&config)?.len(), 1);
+
+    config.log_level = Some("info,[[".into());
+    assert!(check_config(&config).is_err());
+
+    config.log_level = None;
+    config.memtable.max_memory = Some("lots".into());
+    assert!(check_config(&config).is_err());
+
+    config.memtable.max_memory = None;
+    config.cluster = Some(ClusterConfig {
+        id: 0,
+        dir: "/tmp/kvserver-raft".into(),
+        advertise_addr: None,
+        peers: vec![],
+        election_timeout: None,
+        heartbeat_interval: None,
+    });
+    assert!(check_config(&config).is_err());
+    Ok(())
+}
+
+async fn http_request(addr: &str, req: &str) -> Result<String> {
+    let mut stream = TcpStream::connect(addr).await?;
+    stream.write_all(req.as_bytes()).await?;
+    let mut buf = Vec::new();
+    stream.read_to_end(&mut buf).await?;
+    Ok(String::from_utf8(buf)?)
+}